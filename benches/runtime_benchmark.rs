@@ -1,8 +1,12 @@
 use bytes::Bytes;
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use rsync_rust::signature;
-use rsync_rust::{delta, patch};
+use std::sync::Arc;
+
+use rsync_rust::domain::{
+    delta, patch, signature, BlockCache, BlockSize, CachedBasis, StrongHash, Token, TokenBuffer,
+};
+use rsync_rust::test_utils::REALISTIC_WORKLOADS;
 
 pub fn signature_benchmark(c: &mut Criterion) {
     let chunk_size = 100;
@@ -14,6 +18,34 @@ pub fn signature_benchmark(c: &mut Criterion) {
     });
 }
 
+pub fn strong_hash_benchmark(c: &mut Criterion) {
+    let chunk_size = 100;
+
+    let basis_file: Bytes = include_bytes!("test_files/file1").to_vec().into();
+
+    // Every block is hashed on its own, as Signatures and Deltas hash them.
+    let mut group = c.benchmark_group("strong hash of every block [1_000_000 bytes]");
+    for strong_hash in [StrongHash::SipHash, StrongHash::Xxh3] {
+        group.bench_function(strong_hash.to_string(), |b| {
+            b.iter(|| {
+                basis_file
+                    .chunks(chunk_size)
+                    .map(|block| strong_hash.hash(block))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+    group.bench_function("blake3", |b| {
+        b.iter(|| {
+            basis_file
+                .chunks(chunk_size)
+                .map(blake3::hash)
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+}
+
 pub fn delta_benchmark(c: &mut Criterion) {
     let chunk_size = 100;
 
@@ -29,6 +61,69 @@ pub fn delta_benchmark(c: &mut Criterion) {
     });
 }
 
+pub fn unchanged_delta_benchmark(c: &mut Criterion) {
+    let chunk_size = 100;
+
+    let basis_file: Bytes = include_bytes!("test_files/file1").to_vec().into();
+    let signature = signature::compute_signature(basis_file.clone(), chunk_size);
+
+    c.bench_function("delta of an unchanged file [1_000_000 bytes]", |b| {
+        b.iter(|| {
+            delta::compute_delta_to_our_file(signature.clone(), basis_file.clone(), chunk_size)
+        })
+    });
+}
+
+pub fn literal_delta_benchmark(c: &mut Criterion) {
+    let chunk_size = 100;
+
+    // Nothing matches, so every byte of the updated file becomes a literal token.
+    let basis_file: Bytes = include_bytes!("test_files/file1").to_vec().into();
+    let signature = signature::compute_signature(basis_file, chunk_size);
+    let updated_file: Bytes = include_bytes!("test_files/file2")
+        .iter()
+        .map(|byte| byte.wrapping_add(1))
+        .collect::<Vec<u8>>()
+        .into();
+
+    c.bench_function("delta with only literals [1_000_000 bytes]", |b| {
+        b.iter(|| {
+            let delta = delta::compute_delta_to_our_file(
+                signature.clone(),
+                updated_file.clone(),
+                chunk_size,
+            );
+            Bytes::try_from(delta).unwrap()
+        })
+    });
+}
+
+pub fn token_storage_benchmark(c: &mut Criterion) {
+    let updated_file: Bytes = include_bytes!("test_files/file2").to_vec().into();
+
+    // Tokens are pushed one at a time, as matchers do.
+    let mut group = c.benchmark_group("pushing literal tokens [1_000_000 bytes]");
+    group.bench_function("Vec<Token>", |b| {
+        b.iter(|| {
+            let mut tokens = Vec::new();
+            for &byte in updated_file.iter() {
+                tokens.push(Token::ByteLiteral(byte));
+            }
+            tokens
+        })
+    });
+    group.bench_function("TokenBuffer", |b| {
+        b.iter(|| {
+            let mut tokens = TokenBuffer::default();
+            for &byte in updated_file.iter() {
+                tokens.push(Token::ByteLiteral(byte));
+            }
+            tokens
+        })
+    });
+    group.finish();
+}
+
 pub fn patch_benchmark(c: &mut Criterion) {
     let chunk_size = 100;
 
@@ -38,14 +133,92 @@ pub fn patch_benchmark(c: &mut Criterion) {
     let delta = delta::compute_delta_to_our_file(signature, updated_file, chunk_size);
 
     c.bench_function("applying delta to basis file [1_000_000 bytes]", |b| {
-        b.iter(|| patch::apply_delta(basis_file.clone(), delta.clone(), chunk_size))
+        b.iter(|| patch::apply_delta(basis_file.clone(), delta.clone(), chunk_size).unwrap())
     });
 }
 
+pub fn power_of_two_chunk_size_benchmark(c: &mut Criterion) {
+    // Block index math divides by 100, but only shifts and masks with 128.
+    let mut group = c.benchmark_group("block of every offset [1_000_000 offsets]");
+    for chunk_size in [100, 128] {
+        let block_size = BlockSize::new(chunk_size);
+        group.bench_function(chunk_size.to_string(), |b| {
+            b.iter(|| {
+                (0..1_000_000)
+                    .map(|offset| {
+                        block_size.block_of(criterion::black_box(offset))
+                            + block_size.offset_in_block(offset)
+                    })
+                    .sum::<u64>()
+            })
+        });
+    }
+    group.finish();
+
+    let basis_file: Bytes = include_bytes!("test_files/file1").to_vec().into();
+    let updated_file: Bytes = include_bytes!("test_files/file2").to_vec().into();
+    let mut group = c.benchmark_group("patching through a block cache [1_000_000 bytes]");
+    for chunk_size in [100, 128] {
+        let signature = signature::compute_signature(basis_file.clone(), chunk_size);
+        let delta = delta::compute_delta_to_our_file(signature, updated_file.clone(), chunk_size);
+        group.bench_function(chunk_size.to_string(), |b| {
+            b.iter(|| {
+                let cache = Arc::new(BlockCache::new(chunk_size as u64, 1 << 20));
+                let mut basis = CachedBasis::new(basis_file.clone(), cache);
+                let mut recreated = Vec::new();
+                patch::apply_delta_from_reader(
+                    &mut basis,
+                    &delta,
+                    chunk_size,
+                    patch::DEFAULT_MAX_OUTPUT_SIZE,
+                    &mut recreated,
+                )
+                .unwrap();
+                recreated
+            })
+        });
+    }
+    group.finish();
+}
+
+pub fn workload_delta_benchmark(c: &mut Criterion) {
+    let chunk_size = 100;
+
+    let mut group = c.benchmark_group("delta of a realistic workload");
+    for (name, workload) in REALISTIC_WORKLOADS {
+        let files: Vec<_> = workload
+            .generate(42)
+            .into_iter()
+            .map(|file| {
+                let signature = signature::compute_signature(file.basis_file.into(), chunk_size);
+                (signature, Bytes::from(file.updated_file))
+            })
+            .collect();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for (signature, updated_file) in &files {
+                    delta::compute_delta_to_our_file(
+                        signature.clone(),
+                        updated_file.clone(),
+                        chunk_size,
+                    );
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     signature_benchmark,
+    strong_hash_benchmark,
     delta_benchmark,
-    patch_benchmark
+    unchanged_delta_benchmark,
+    literal_delta_benchmark,
+    token_storage_benchmark,
+    patch_benchmark,
+    power_of_two_chunk_size_benchmark,
+    workload_delta_benchmark
 );
 criterion_main!(benches);
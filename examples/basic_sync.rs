@@ -0,0 +1,58 @@
+//! Syncs a file in memory, playing both sides of the algorithm:
+//! 1 - The side with the basis file computes its Signature, and sends it.
+//! 2 - The side with the updated file computes the Delta against it, and sends it back.
+//! 3 - The side with the basis file applies the Delta, recreating the updated file.
+//!
+//! Signatures and Deltas are serialized as they would be to be sent or saved.
+//!
+//! `cargo run --example basic_sync`
+use bytes::Bytes;
+
+use rsync_rust::domain::{
+    Delta, FileSignature, MatchStrategy, MatchingOptions, SyncOptions, SyncSession,
+};
+
+fn main() -> eyre::Result<()> {
+    let basis_file: Bytes = (0..2000)
+        .map(|line| format!("Line {line} of the file, as it was.\n"))
+        .collect::<String>()
+        .into();
+    let updated_file: Bytes = basis_file
+        .split_inclusive(|&byte| byte == b'\n')
+        .enumerate()
+        .flat_map(|(line, content)| match line % 500 {
+            7 => b"This line was edited.\n".as_slice(),
+            _ => content,
+        })
+        .copied()
+        .collect::<Vec<_>>()
+        .into();
+
+    // Both sides must divide files into blocks the same way.
+    let options = SyncOptions::default()
+        .with_chunk_size(64)
+        .with_matching(MatchingOptions {
+            strategy: MatchStrategy::Lazy,
+            ..Default::default()
+        });
+
+    // 1 - The basis file's side.
+    let session = SyncSession::new(basis_file.clone(), options)?;
+    let signature_bytes = Bytes::try_from(session.signature().clone())?;
+
+    // 2 - The updated file's side, which only has the Signature.
+    let signature = FileSignature::try_from(signature_bytes.clone())?;
+    let delta_bytes = Bytes::try_from(options.compute_delta(&signature, &updated_file)?)?;
+
+    // 3 - The basis file's side again.
+    let recreated = session.apply(Delta::try_from(delta_bytes.clone())?)?;
+    assert_eq!(recreated, updated_file);
+
+    println!(
+        "Recreated {} bytes from a Signature of {} bytes and a Delta of {} bytes",
+        recreated.len(),
+        signature_bytes.len(),
+        delta_bytes.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Syncs a file over TCP, with a receiver and a sender in the same process: the receiver serves
+//! a single sync of its basis file, and the sender pushes its updated file to it.
+//!
+//! This is what `rsync_rust serve` and `rsync_rust push` do, on two hosts.
+//!
+//! `cargo run --example network_pair`
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use bytes::Bytes;
+
+use rsync_rust::domain::SyncOptions;
+
+fn main() -> eyre::Result<()> {
+    let basis_file = "The receiver's file, which is out of date.\n".repeat(5000);
+    let updated_file = Bytes::from(basis_file.replacen("out of date", "up to date", 3));
+    let basis_filename = std::env::temp_dir().join("rsync_rust_network_pair_example");
+    std::fs::write(&basis_filename, basis_file)?;
+
+    let options = SyncOptions::default().with_chunk_size(256);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+
+    let receiver = thread::spawn({
+        let basis_filename = basis_filename.clone();
+        move || -> eyre::Result<_> {
+            let (mut connection, _) = listener.accept()?;
+            options.serve(&mut connection, &basis_filename)
+        }
+    });
+
+    let mut connection = TcpStream::connect(address)?;
+    let outcome = options.push(&mut connection, &updated_file)?;
+    receiver.join().expect("Receiver panicked")?;
+
+    assert_eq!(std::fs::read(&basis_filename)?, updated_file);
+    println!("Receiver answered: {outcome:?}");
+
+    std::fs::remove_file(&basis_filename)?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Streams a Delta through a file while it is being computed, and applies it as it is read, so
+//! neither side holds the whole Delta in memory.
+//!
+//! Then keeps the session going: the recreated file is the basis file of the next sync.
+//!
+//! `cargo run --example streaming`
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use bytes::Bytes;
+
+use rsync_rust::domain::{SyncOptions, SyncSession};
+
+// A file of `length` pseudo-random bytes, the same on every run.
+fn generate_file(length: usize, seed: u64) -> Bytes {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 56) as u8
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn main() -> eyre::Result<()> {
+    let options = SyncOptions::default().with_chunk_size(1024);
+    let mut session = SyncSession::new(generate_file(1024 * 1024, 1), options)?;
+    let delta_filename = std::env::temp_dir().join("rsync_rust_streaming_example.delta");
+
+    for generation in 1..=3 {
+        // Some bytes are inserted in the middle, and the end is rewritten.
+        let basis_file = session.basis_file().clone();
+        let middle = basis_file.len() / 2;
+        let updated_file: Bytes = [
+            &basis_file[..middle],
+            &generate_file(100, generation)[..],
+            &basis_file[middle..basis_file.len() - 4096],
+            &generate_file(4096, generation + 100)[..],
+        ]
+        .concat()
+        .into();
+
+        let delta_file = BufWriter::new(File::create(&delta_filename)?);
+        // Flushing the buffer surfaces the errors of the last writes.
+        options
+            .stream_delta(session.signature(), &updated_file, delta_file)?
+            .into_inner()?;
+
+        let mut delta_file = BufReader::new(File::open(&delta_filename)?);
+        let recreated = session.apply_streamed(&mut delta_file)?;
+        assert_eq!(recreated, updated_file);
+        println!(
+            "Generation {generation}: recreated {} bytes from a Delta of {} bytes",
+            recreated.len(),
+            std::fs::metadata(&delta_filename)?.len()
+        );
+
+        session.advance(recreated)?;
+    }
+
+    std::fs::remove_file(&delta_filename)?;
+    Ok(())
+}
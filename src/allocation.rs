@@ -0,0 +1,126 @@
+//! Counts the heap allocations of the process, for the end-of-run statistics.
+//!
+//! Computing a Delta pushes millions of small tokens, so it is often bound by the allocator rather
+//! than by hashing. Built with the `counting-allocator` feature, the `rsync_rust` program installs
+//! a CountingAllocator over its allocator (the system's, or mimalloc or jemalloc with their
+//! features), and its summaries tell how many allocations a command made.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// A global allocator counting the allocations it passes on to `A`.
+///
+/// Every allocation updates a few shared counters, which slows allocation-heavy work down a little.
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+    allocations: AtomicU64,
+    bytes_allocated: AtomicU64,
+    bytes_in_use: AtomicU64,
+    peak_bytes_in_use: AtomicU64,
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocations: AtomicU64::new(0),
+            bytes_allocated: AtomicU64::new(0),
+            bytes_in_use: AtomicU64::new(0),
+            peak_bytes_in_use: AtomicU64::new(0),
+        }
+    }
+
+    /// What this allocator counted so far. The `rsync_rust` program passes those of its global
+    /// allocator to the summaries of its commands.
+    pub fn counts(&self) -> AllocationCounts {
+        AllocationCounts {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+        }
+    }
+
+    // Counts an allocation of `size` bytes, made while `freed` bytes were released.
+    fn count_allocation(&self, size: usize, freed: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_allocated
+            .fetch_add(size as u64, Ordering::Relaxed);
+        let in_use = self.bytes_in_use.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+        self.bytes_in_use.fetch_sub(freed as u64, Ordering::Relaxed);
+        self.peak_bytes_in_use
+            .fetch_max(in_use - freed as u64, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = self.inner.alloc(layout);
+        if !pointer.is_null() {
+            self.count_allocation(layout.size(), 0);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        self.inner.dealloc(pointer, layout);
+        self.bytes_in_use
+            .fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let pointer = self.inner.alloc_zeroed(layout);
+        if !pointer.is_null() {
+            self.count_allocation(layout.size(), 0);
+        }
+        pointer
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_pointer = self.inner.realloc(pointer, layout, new_size);
+        if !new_pointer.is_null() {
+            self.count_allocation(new_size, layout.size());
+        }
+        new_pointer
+    }
+}
+
+/// How much a CountingAllocator allocated since the process started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AllocationCounts {
+    /// Reallocations included.
+    pub allocations: u64,
+    pub bytes_allocated: u64,
+    /// Most bytes allocated and not yet freed at once.
+    pub peak_bytes_in_use: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_counted_with_the_bytes_in_use() {
+        // Each allocator has its own counts, so only these allocations are counted.
+        let allocator = CountingAllocator::new(System);
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+
+        unsafe {
+            let pointer = allocator.alloc(layout);
+            let pointer = allocator.realloc(pointer, layout, 3000);
+            allocator.dealloc(pointer, Layout::from_size_align(3000, 8).unwrap());
+        }
+
+        assert_eq!(
+            allocator.counts(),
+            AllocationCounts {
+                allocations: 2,
+                bytes_allocated: 4000,
+                peak_bytes_in_use: 3000,
+            }
+        );
+        assert_eq!(allocator.bytes_in_use.load(Ordering::Relaxed), 0);
+    }
+}
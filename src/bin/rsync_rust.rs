@@ -0,0 +1,2448 @@
+//! rsync main idea:
+//! User A has a initial file, let's call it `basis_file`.
+//! We (User B) have made some changes to this file, and have our own version of it, `updated_file`.
+//! Now we want to send our changes to user A, so they can update their `basis_file` to be equal
+//! to our `updated_file`.
+//!
+//! One way of accomplishing that is sending the `updated_file` directly.
+//! 1 - User B sends `updated_file`
+//! 2 - User A replaces `basis_file` with `updated_file`
+//!
+//! This of course works, but we are not leveraging the facts that both `basis_file` and `updated_file`
+//! are bound to have mostly the same content. (Picture `basis_file` as a Git repository,
+//! and `updated_file` as the repository after you've made some commits).
+//!
+//! The rsync algorithm:
+//! 1 - User A computes a `signature` for `basis_file`.
+//!         This `signature` "represents" the contents of `basis_file`, approximately, and is much smaller.
+//!
+//! 2 - User A sends the `signature` to User B.
+//!
+//! 3 - User B uses `signature` to compute `delta` from `updated_file` to `basis_file`.
+//!         This `delta` has exactly what needs to change from `basis_file` to become `updated_file`.
+//!
+//! 4 - User B sends `delta` to User A.
+//!         In an approximate way, the `delta` encompasses only what needs to be changed between the two files
+//!         which is generally much smaller than the whole file.
+//!
+//! 5 - User A uses `delta` to update `basis_file`.
+//!
+//! In the end, we have sent two files throughout the algorithm: `signature` and `delta`.
+//! As long as `size(signature)` + `size(delta)` is smaller than `size(updated_file)` we have made
+//! improvements regarding network resources.
+//!
+//! Note that we have traded computation time for memory.
+//! We are sending smaller files through the network, but both User A and User B need to
+//! compute information based on that.
+
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Help;
+
+use rsync_rust::allocation::AllocationCounts;
+#[cfg(feature = "counting-allocator")]
+use rsync_rust::allocation::CountingAllocator;
+use rsync_rust::commands::{
+    self, read_signature, ArtifactProtection, BlockOptions, DeltaOptions, FlashOptions,
+    InspectOptions, MapFormat, PatchJob, PatchOptions, Preprocessing, ProvenanceMapOutput,
+    RunSummary, SignatureOptions,
+};
+use rsync_rust::domain::analysis::{
+    analyze, DEFAULT_ANALYSIS_CHUNK_SIZES, DEFAULT_ANALYSIS_COMPRESSION_LEVELS,
+};
+use rsync_rust::domain::annotation::DeltaMetadata;
+use rsync_rust::domain::audit::{append_audit_record, AuditRecord};
+use rsync_rust::domain::basis_reader::BasisIo;
+use rsync_rust::domain::block_index::BlockIndex;
+use rsync_rust::domain::blocks::compute_block_list;
+use rsync_rust::domain::chunking::{check_chunk_size, ChunkingMode};
+use rsync_rust::domain::compare::{compare_signatures, SignatureComparison};
+use rsync_rust::domain::compressibility::container_transform;
+use rsync_rust::domain::delta::{MatchPreference, MatchStrategy, MatchingOptions};
+use rsync_rust::domain::download::{answer_block_request, BlockRequest, DownloadPlan};
+use rsync_rust::domain::encryption::ArtifactKeys;
+use rsync_rust::domain::extents::align_chunk_size_to_file;
+use rsync_rust::domain::format::ArtifactKind;
+use rsync_rust::domain::generations::{
+    back_up_generation, prune_generations, restore_generation, GenerationChain, RetentionPolicy,
+};
+use rsync_rust::domain::hierarchy::align_coarse_chunk_size;
+use rsync_rust::domain::inspect::{DiffStyle, MAX_INLINE_DIFF_SIZE};
+use rsync_rust::domain::journal::{default_journal_filename, recover_from_journal};
+use rsync_rust::domain::manifest::{check_manifest, compute_manifest, Manifest};
+use rsync_rust::domain::parallel::Parallelism;
+use rsync_rust::domain::patch::DEFAULT_MAX_OUTPUT_SIZE;
+use rsync_rust::domain::policy::FilePolicies;
+use rsync_rust::domain::signature::compute_signature_with_mode;
+use rsync_rust::domain::signing::{generate_signing_key, ArtifactSigner};
+use rsync_rust::domain::similarity::compute_similarity;
+use rsync_rust::domain::strong_hash::StrongHash;
+use rsync_rust::domain::transfer::{transfer_directory, TransferOptions};
+use rsync_rust::domain::weak_hash::WeakHash;
+use rsync_rust::io_utils;
+use rsync_rust::network::{
+    push_file_with_retries, serve_connections, ConflictPolicy, RetryPolicy, ServeOptions,
+    SyncOutcome, SyncRequest, TrafficMeter,
+};
+use rsync_rust::selftest::run_selftest;
+use rsync_rust::{profiling, progress};
+
+#[cfg(all(feature = "mimalloc", feature = "jemalloc"))]
+compile_error!("Features `mimalloc` and `jemalloc` both pick the allocator: enable one of them.");
+
+#[cfg(feature = "mimalloc")]
+type Allocator = mimalloc::MiMalloc;
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+type Allocator = tikv_jemallocator::Jemalloc;
+#[cfg(not(any(
+    feature = "mimalloc",
+    all(feature = "jemalloc", not(target_env = "msvc"))
+)))]
+type Allocator = std::alloc::System;
+
+// Allocations are counted for the summary of each command, then passed on.
+#[cfg(feature = "counting-allocator")]
+#[global_allocator]
+static GLOBAL: CountingAllocator<Allocator> = CountingAllocator::new(Allocator {});
+#[cfg(not(feature = "counting-allocator"))]
+#[global_allocator]
+static GLOBAL: Allocator = Allocator {};
+
+// What the global allocator counted so far, for the summary of a command.
+#[cfg(feature = "counting-allocator")]
+fn allocation_counts() -> Option<AllocationCounts> {
+    Some(GLOBAL.counts())
+}
+#[cfg(not(feature = "counting-allocator"))]
+fn allocation_counts() -> Option<AllocationCounts> {
+    None
+}
+
+#[derive(Parser)]
+struct Arguments {
+    #[command(subcommand)]
+    command: Commands,
+    #[arg(long, global = true)]
+    threads: Option<NonZeroUsize>,
+    // Threads used to hash files. Defaults to `RSYNC_RUST_THREADS`, or to every core.
+    #[arg(long, global = true)]
+    progress_json: bool,
+    // Write progress events as lines of JSON on the standard error, for front-ends to render.
+    #[arg(long, global = true, default_value_t = NonZeroU64::new(500).unwrap())]
+    progress_interval_ms: NonZeroU64,
+    // Milliseconds between progress events while a phase runs.
+    #[arg(long, global = true)]
+    profile_report: bool,
+    // Print how often the hot loops of matching ran, and how long they took, on the standard error.
+}
+
+#[derive(Subcommand)]
+// Artifacts given as arguments are checked while parsing them (see `parse_signature_filename`).
+enum Commands {
+    Signature(SignatureArguments),
+    Delta(DeltaArguments),
+    Index(IndexArguments),
+    RequestBlocks(RequestBlocksArguments),
+    SendBlocks(SendBlocksArguments),
+    Assemble(AssembleArguments),
+    DeltaBestBasis(DeltaBestBasisArguments),
+    Patch(PatchArguments),
+    Flash(FlashArguments),
+    Recover(RecoverArguments),
+    Inspect(InspectArguments),
+    MergeDeltas(MergeDeltasArguments),
+    Cmp(CmpArguments),
+    CmpSig(CmpSigArguments),
+    Blocks(BlocksArguments),
+    Similarity(SimilarityArguments),
+    Analyze(AnalyzeArguments),
+    Manifest(ManifestArguments),
+    Check(CheckArguments),
+    Serve(ServeArguments),
+    Push(PushArguments),
+    Transfer(TransferArguments),
+    Bisync(BisyncArguments),
+    Resign(ResignArguments),
+    Backup(BackupArguments),
+    Restore(RestoreArguments),
+    Gc(GcArguments),
+    Selftest(SelftestArguments),
+    GenerateSigningKey(GenerateSigningKeyArguments),
+}
+
+#[derive(Args)]
+struct SignatureArguments {
+    basis_filename: PathBuf,
+    // The basis file to compute Signature from.
+    signature_output_filename: PathBuf,
+    // Where to save the Signature file.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    preprocessing: PreprocessingArguments,
+    #[arg(long)]
+    verify_deterministic: bool,
+    // Compute the Signature twice, and fail if the results differ.
+    #[arg(long)]
+    dedup: bool,
+    // Store identical blocks only once, to save a smaller Signature file.
+    #[arg(long)]
+    align_to_extents: bool,
+    // Round the chunk size down so blocks start on the pages and extents of the basis file.
+    #[arg(long, default_value_t = WeakHash::Polynomial)]
+    weak_hash: WeakHash,
+    // Rolling hash used to find blocks: `polynomial`, `adler32` or `gear`.
+    #[arg(long, default_value_t = StrongHash::Xxh3)]
+    strong_hash: StrongHash,
+    // Hash confirming the blocks found: `xxh3`, `siphash` as before it could be chosen, or the
+    // wider `xxh128` and `blake3`.
+    #[arg(long, conflicts_with = "strong_hash")]
+    weak_only: bool,
+    // Store no strong hashes, so blocks are reused on their rolling hash alone. Faster, and fine
+    // for estimates, but a collision silently reuses the wrong block.
+    #[arg(long)]
+    timings: bool,
+    // Print the time spent reading, hashing, serializing and writing.
+    #[command(flatten)]
+    summary: SummaryArguments,
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct DeltaArguments {
+    #[arg(value_parser = parse_signature_filename)]
+    signature_filename: PathBuf,
+    // Signature file computed by `Signature` command.
+    updated_filename: PathBuf,
+    // File to compute `Delta` from `Signature`.
+    delta_filename: PathBuf,
+    // Where to save the `Delta` file.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    preprocessing: PreprocessingArguments,
+    #[command(flatten)]
+    provenance_map: ProvenanceMapArguments,
+    #[command(flatten)]
+    matching: MatchingArguments,
+    #[arg(long)]
+    verify_deterministic: bool,
+    // Compute the Delta twice, and fail if the results differ.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "verify_deterministic",
+            "filename",
+            "passphrase_file",
+            "recipient",
+            "signing_key"
+        ]
+    )]
+    stream: bool,
+    // Write the Delta while it is being computed, instead of all at once.
+    #[arg(long, value_name = "FILE")]
+    updated_signature: Option<PathBuf>,
+    // Also save the Signature of the updated file, for the next sync, without reading it again.
+    #[arg(long, value_name = "FILE")]
+    block_index: Option<PathBuf>,
+    // Block Index of the updated file computed by `index` command, instead of rolling it again.
+    #[command(flatten)]
+    annotation: AnnotationArguments,
+    #[arg(long)]
+    timings: bool,
+    // Print the time spent reading, hashing, matching, serializing and writing.
+    #[command(flatten)]
+    summary: SummaryArguments,
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct IndexArguments {
+    updated_filename: PathBuf,
+    // The file which Deltas will be computed of, against many Signatures.
+    index_output_filename: PathBuf,
+    // Where to save the Block Index file.
+    #[arg(short, long, default_value_t = 10, value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    // Size for each block, which must be the one of the Signatures.
+    #[arg(long, default_value_t = WeakHash::Polynomial)]
+    weak_hash: WeakHash, // Rolling hash of the Signatures: `polynomial`, `adler32` or `gear`.
+}
+
+#[derive(Args)]
+struct RequestBlocksArguments {
+    #[arg(value_parser = parse_signature_filename)]
+    signature_filename: PathBuf,
+    // Signature of the updated file, published by the server.
+    basis_filename: PathBuf,
+    // Our older version of the file, whose blocks are not requested.
+    #[arg(required_unless_present = "estimate")]
+    request_output_filename: Option<PathBuf>,
+    // Where to save the Block Request, to send to the server.
+    #[arg(short, long, default_value_t = 10, value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    // Size for each block used in the Signature.
+    #[arg(long, conflicts_with = "request_output_filename")]
+    estimate: bool,
+    // Only report how many bytes would be requested, without saving the request.
+    #[command(flatten)]
+    matching: MatchingArguments,
+}
+
+#[derive(Args)]
+struct SendBlocksArguments {
+    updated_filename: PathBuf,
+    // The file whose Signature was published.
+    request_filename: PathBuf,
+    // Block Request computed by `request-blocks` command.
+    blocks_output_filename: PathBuf, // Where to save the blocks requested, one after the other.
+}
+
+#[derive(Args)]
+struct AssembleArguments {
+    #[arg(value_parser = parse_signature_filename)]
+    signature_filename: PathBuf,
+    // Signature of the updated file, which the request was computed from.
+    basis_filename: PathBuf,
+    // Our older version of the file, as it was when the request was computed.
+    blocks_filename: PathBuf,
+    // Blocks sent by `send-blocks` command.
+    recreated_filename: PathBuf,
+    // Where to save the updated file.
+    #[arg(short, long, default_value_t = 10, value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    // Size for each block used in the Signature.
+    #[command(flatten)]
+    matching: MatchingArguments,
+    #[command(flatten)]
+    audit: AuditArguments,
+}
+
+#[derive(Args)]
+struct DeltaBestBasisArguments {
+    candidates_directory: PathBuf,
+    // Directory of candidate basis files, e.g. previous releases.
+    updated_filename: PathBuf,
+    // File to compute the Delta of.
+    delta_filename: PathBuf,
+    // Where to save the Delta, computed against the closest candidate.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    preprocessing: PreprocessingArguments,
+    #[command(flatten)]
+    matching: MatchingArguments,
+    #[command(flatten)]
+    annotation: AnnotationArguments,
+    #[arg(long)]
+    timings: bool,
+    // Print the time spent reading, scoring candidates, hashing, matching and writing.
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("patch_mode").args(["inplace", "batch", "simulate", "verify_only"])))]
+// At most one way of patching, rather than some silently taking precedence over the others.
+struct PatchArguments {
+    basis_filename: PathBuf,
+    // File to apply changes.
+    #[arg(required_unless_present = "batch", value_parser = parse_delta_filename)]
+    delta_filename: Option<PathBuf>,
+    // Delta file computed by `Delta` command, or `-` to read it from the standard input.
+    #[arg(required_unless_present_any = ["simulate", "verify_only", "batch", "inplace"])]
+    recreated_filename: Option<PathBuf>,
+    // Where to save the updated file, or `-` for the standard output.
+    #[arg(long, conflicts_with_all = ["recreated_filename", "range"])]
+    inplace: bool,
+    // Update the basis file itself, journaling what is overwritten to `recover` after a crash.
+    #[arg(long, requires = "inplace")]
+    journal: Option<PathBuf>,
+    // Where to write the journal. Defaults to the basis file's name, with `.rsync-journal`.
+    #[arg(
+        long,
+        value_name = "JOBS",
+        conflicts_with_all = ["delta_filename", "recreated_filename", "range"]
+    )]
+    batch: Option<PathBuf>,
+    // Apply the Deltas listed in this file (`DELTA<TAB>RECREATED` per line) concurrently.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[arg(long)]
+    simulate: bool,
+    // Only report what applying the Delta would do, without writing anything.
+    #[arg(long, conflicts_with = "recreated_filename")]
+    verify_only: bool,
+    // Recreate the file in memory and check it is the updated file, without writing anything.
+    #[arg(long, value_parser = parse_byte_range, conflicts_with_all = ["simulate", "verify_only"])]
+    range: Option<Range<usize>>,
+    // Only reconstruct this byte window (`START..END`) of the updated file.
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+    // Abort if the recreated file would be larger than this many bytes.
+    #[arg(
+        long,
+        default_value_t = BasisIo::Memory,
+        conflicts_with_all = ["simulate", "verify_only", "range"]
+    )]
+    basis_io: BasisIo,
+    // How blocks of the Basis file are read: `memory`, `positioned` or `io-uring`.
+    #[arg(long, value_name = "BYTES")]
+    read_ahead: Option<u64>,
+    // Read the blocks needed by this many bytes of the recreated file at once, in file order.
+    #[arg(long, conflicts_with_all = ["simulate", "verify_only"])]
+    no_lock: bool,
+    // Write the recreated file without holding an advisory lock on it.
+    #[arg(long, conflicts_with = "simulate")]
+    assume_basis_ok: bool,
+    // Patch even if the Basis file's hash differs from the one the Signature recorded.
+    #[arg(long, conflicts_with = "simulate")]
+    timings: bool,
+    // Print the time spent reading, deserializing, applying the Delta and writing.
+    #[command(flatten)]
+    summary: SummaryArguments,
+    #[command(flatten)]
+    provenance_map: ProvenanceMapArguments,
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct FlashArguments {
+    basis_partition: PathBuf,
+    // Partition (or image file) holding the current image.
+    #[arg(value_parser = parse_delta_filename)]
+    delta_filename: PathBuf,
+    // Delta file computed by `Delta` command against the current image.
+    target_partition: PathBuf,
+    // Partition (or image file) to write the updated image to. Never truncated.
+    #[arg(long)]
+    basis_size: Option<u64>,
+    // Length of the current image. Defaults to the one recorded in the Delta, or the partition's.
+    #[arg(long)]
+    capacity: Option<u64>,
+    // Bytes the target partition holds. Defaults to its size, or creates an image file this large.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct RecoverArguments {
+    filename: PathBuf,
+    // File whose update with `patch --inplace` was interrupted.
+    journal_filename: PathBuf, // Journal written by `patch --inplace`.
+}
+
+#[derive(Args)]
+struct BisyncArguments {
+    left_filename: PathBuf,
+    // One copy of the file.
+    right_filename: PathBuf,
+    // The other copy of the file. Either copy, or both, may have changed since the last sync.
+    #[arg(long)]
+    state: Option<PathBuf>,
+    // Where the last synced version is recorded. Defaults to the left file's name, with `.rsync-state`.
+    #[command(flatten)]
+    blocks: BlockArguments,
+}
+
+#[derive(Args)]
+struct InspectArguments {
+    #[arg(value_parser = parse_delta_filename)]
+    delta_filename: PathBuf,
+    // Delta file computed by `Delta` command.
+    #[arg(long = "basis")]
+    basis_filename: Option<PathBuf>,
+    // Basis file the Delta was computed against. Shows the changed lines, or small text files.
+    #[arg(long, default_value_t = MAX_INLINE_DIFF_SIZE)]
+    max_diff_size: usize,
+    // Largest updated file shown in fixed mode, with changes highlighted.
+    #[arg(short, long, default_value_t = ChunkingMode::Fixed)]
+    mode: ChunkingMode,
+    // How files are divided into blocks: `fixed` or `lines`.
+    #[arg(long)]
+    html: Option<PathBuf>,
+    // Also save a page showing where each part of the updated file comes from.
+    #[arg(short, long, value_parser = parse_chunk_size)]
+    chunk_size: Option<usize>,
+    // Size of the blocks, for Deltas which do not record their basis file.
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct MergeDeltasArguments {
+    #[arg(value_parser = parse_delta_filename)]
+    left_delta_filename: PathBuf,
+    // Delta file computed by `Delta` command.
+    #[arg(value_parser = parse_delta_filename)]
+    right_delta_filename: PathBuf,
+    // Another Delta file, computed against the same Signature.
+    merged_delta_filename: PathBuf,
+    // Where to save the Delta holding the changes of both, unless they conflict.
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct CmpArguments {
+    filename: PathBuf,
+    // Our copy of the file.
+    #[arg(value_parser = parse_signature_filename)]
+    signature_filename: PathBuf,
+    // Signature of the other copy, computed by `Signature` command.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct CmpSigArguments {
+    #[arg(value_parser = parse_signature_filename)]
+    first_signature_filename: PathBuf,
+    // Signature of one copy of the file.
+    #[arg(value_parser = parse_signature_filename)]
+    second_signature_filename: PathBuf,
+    // Signature of the other copy of the file.
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct BlocksArguments {
+    filename: PathBuf,
+    // The file to list the blocks of.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[arg(long, default_value_t = MapFormat::Csv)]
+    format: MapFormat, // Format of the list: `csv` or `json`.
+}
+
+#[derive(Args)]
+struct SimilarityArguments {
+    basis_filename: PathBuf,
+    // The candidate basis file.
+    updated_filename: PathBuf,
+    // The file which would be sent.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[arg(long)]
+    heat_map: Option<PathBuf>,
+    // Where to save, as JSON, how much of each part of the updated file is in the basis file.
+    #[arg(long, default_value_t = 100, requires = "heat_map")]
+    heat_map_buckets: usize, // Number of parts the heat map splits the updated file into.
+}
+
+#[derive(Args)]
+struct AnalyzeArguments {
+    basis_filename: PathBuf,
+    // The file the receiver has.
+    updated_filename: PathBuf,
+    // The file which would be sent.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_chunk_size,
+        default_values_t = DEFAULT_ANALYSIS_CHUNK_SIZES
+    )]
+    chunk_sizes: Vec<usize>,
+    // Sizes for each block to try, separated by commas.
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_ANALYSIS_COMPRESSION_LEVELS)]
+    compression_levels: Vec<u32>,
+    // Deflate levels to try on the Delta, from 0 (none) to 9, separated by commas.
+    #[command(flatten)]
+    matching: MatchingArguments,
+}
+
+#[derive(Args)]
+struct ManifestArguments {
+    directory: PathBuf,
+    // The directory tree to describe.
+    manifest_output_filename: PathBuf, // Where to save the Manifest file.
+}
+
+#[derive(Args)]
+struct CheckArguments {
+    directory: PathBuf,
+    // The directory tree to verify.
+    manifest_filename: PathBuf, // Manifest file computed by `Manifest` command.
+}
+
+#[derive(Args)]
+struct ServeArguments {
+    basis_filename: PathBuf,
+    // The file kept up to date by the senders. Created on the first sync if it does not exist.
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    listen: String,
+    // Address to accept connections on.
+    #[arg(long, conflicts_with = "listen")]
+    listen_unix: Option<PathBuf>,
+    // Accept connections on a Unix domain socket at this path instead, only usable by our user.
+    #[arg(long)]
+    once: bool,
+    // Exit after handling a single sync.
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+    // Syncs handled at the same time.
+    #[arg(long, default_value_t = 2)]
+    connections_per_client: usize,
+    // Syncs from a single IP address at the same time. Further connections are closed.
+    #[arg(long, default_value_t = 30)]
+    timeout_secs: u64,
+    // Abandon a sync when the sender sends or receives nothing for this long. 0 waits forever.
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+    // Refuse Deltas which would recreate a file larger than this many bytes.
+    #[arg(long, default_value_t = ConflictPolicy::Abort)]
+    on_conflict: ConflictPolicy,
+    // If the file changed during a sync: `abort` (the default), `overwrite`, `keep-both` or
+    // `prompt`.
+    #[arg(long)]
+    no_lock: bool, // Write the basis file without holding an advisory lock on it.
+}
+
+#[derive(Args)]
+struct PushArguments {
+    updated_filename: PathBuf,
+    // The file to send.
+    address: String,
+    // Address of a receiver started with the `serve` command, or `unix:<path>` for a Unix socket.
+    #[arg(long, conflicts_with = "single_level", value_parser = parse_chunk_size)]
+    coarse_chunk_size: Option<usize>,
+    // Find changed regions with blocks of this size first. Chosen from the file size by default.
+    #[arg(long)]
+    single_level: bool,
+    // Never use coarse blocks, even for large files.
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    // Try the sync again this many times if the connection fails, resuming it if it can.
+    #[arg(long, default_value_t = 200)]
+    retry_backoff_ms: u64,
+    // Wait before the first retry, doubled on each retry after that.
+    #[arg(long)]
+    compress_whole_file: bool,
+    // Deflate the file when it is sent whole, because its Signature and Delta would be larger.
+    #[arg(long)]
+    estimate: bool,
+    // Only report how many bytes the sync would send, leaving the receiver's file untouched.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    audit: AuditArguments,
+}
+
+#[derive(Args)]
+struct TransferArguments {
+    sender_directory: PathBuf,
+    // The directory tree with the updated files.
+    receiver_directory: PathBuf,
+    // The directory tree to bring up to date, in place.
+    #[arg(long)]
+    compress_whole_files: bool,
+    // Deflate the files sent whole because their Signature and Delta would be larger.
+    #[arg(long)]
+    share_blocks: bool,
+    // Send a single Signature of every receiver file, so content moved between files is matched.
+    #[arg(long)]
+    match_compressed: bool,
+    // Look for blocks even in files which look already compressed, instead of sending them whole.
+    #[arg(long)]
+    policy: Vec<String>,
+    // How files matching a pattern are synced, e.g. "*.csv mode=lines". Can be repeated.
+    #[arg(long)]
+    policy_file: Option<PathBuf>,
+    // File with a policy on each line, tried after those given with `--policy`.
+    #[command(flatten)]
+    blocks: BlockArguments,
+    #[command(flatten)]
+    matching: MatchingArguments,
+    #[command(flatten)]
+    audit: AuditArguments,
+}
+
+#[derive(Args)]
+struct AuditArguments {
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>, // Append a line of JSON describing the sync to this file.
+}
+
+#[derive(Args)]
+struct BackupArguments {
+    chain_directory: PathBuf,
+    // Directory holding the generations of the file, created by the first backup.
+    filename: PathBuf,
+    // The file to store as a new generation.
+    #[arg(short, long, default_value_t = 1024, value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    // Size for each block, when the chain is created. Later backups use the chain's.
+    #[command(flatten)]
+    matching: MatchingArguments,
+}
+
+#[derive(Args)]
+struct RestoreArguments {
+    chain_directory: PathBuf,
+    // Directory holding the generations of the file.
+    #[arg(required_unless_present = "list")]
+    restored_filename: Option<PathBuf>,
+    // Where to save the restored file, or `-` for the standard output.
+    #[arg(short, long)]
+    generation: Option<u64>,
+    // The generation to restore. Defaults to the latest one.
+    #[arg(long, conflicts_with_all = ["restored_filename", "generation"])]
+    list: bool,
+    // Only list the generations.
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64, // Abort if a generation would be larger than this many bytes.
+}
+
+#[derive(Args)]
+struct GcArguments {
+    chain_directory: PathBuf,
+    // Directory holding the generations of the file.
+    #[arg(long, required_unless_present = "keep_within")]
+    keep_last: Option<usize>,
+    // Keep this many of the latest generations.
+    #[arg(long, value_parser = parse_duration)]
+    keep_within: Option<Duration>,
+    // Keep the generations backed up this recently, e.g. `30d`, `12h`, `90m` or `45s`.
+    #[arg(long)]
+    dry_run: bool,
+    // Only list what would be deleted.
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64, // Abort if a generation would be larger than this many bytes.
+}
+
+#[derive(Args)]
+struct SelftestArguments {
+    #[arg(long)]
+    seed: Option<u64>,
+    // Generates the random files. Printed by every run, to reproduce a failure.
+    #[arg(long)]
+    keep: bool, // Keep the generated files and artifacts, in a temporary directory.
+}
+
+#[derive(Args)]
+struct ResignArguments {
+    #[arg(value_parser = parse_signature_filename)]
+    old_signature_filename: PathBuf,
+    // Signature file computed by `Signature` command.
+    basis_filename: PathBuf,
+    // The basis file the Signature was computed from.
+    #[arg(short, long, value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    // Size for each block of the new Signature.
+    #[arg(long)]
+    output: Option<PathBuf>,
+    // Where to save the new Signature file. The old one is replaced by default.
+    #[command(flatten)]
+    encryption: EncryptionArguments,
+    #[command(flatten)]
+    signing: SigningArguments,
+}
+
+#[derive(Args)]
+struct GenerateSigningKeyArguments {
+    signing_key: PathBuf,
+    // Where the private key is written, for `--signing-key`. Keep it secret.
+    verifying_key: PathBuf, // Where the public key is written, for `--verifying-key`.
+}
+
+#[derive(Args)]
+// Must be the same for all the commands of a single run of the algorithm.
+struct BlockArguments {
+    #[arg(short, long, default_value_t = 10, value_parser = parse_chunk_size)]
+    chunk_size: usize, // Size for each block.
+    #[arg(short, long, default_value_t = ChunkingMode::Fixed)]
+    mode: ChunkingMode, // How files are divided into blocks: `fixed` or `lines`.
+}
+
+#[derive(Args)]
+// Only affect how the Delta is computed, so they can differ between runs.
+struct MatchingArguments {
+    #[arg(long)]
+    resync_after: Option<usize>,
+    // After this many literal bytes, look for blocks at the expected boundaries first.
+    #[arg(long, default_value_t = MatchPreference::Any)]
+    match_preference: MatchPreference,
+    // Block referenced among equal ones: `closest`, `first` or `any`.
+    #[arg(long, default_value_t = MatchStrategy::Greedy)]
+    strategy: MatchStrategy,
+    // Blocks referenced among those found: `greedy`, `lazy` or `optimal`.
+    #[arg(long, value_name = "MIN_LENGTH")]
+    zero_runs: Option<usize>, // Send runs of at least this many zero bytes as their length.
+}
+
+#[derive(Args)]
+// Encrypt the Signatures and Deltas written, and decrypt the ones read, with age.
+struct EncryptionArguments {
+    #[arg(long, conflicts_with = "recipient")]
+    passphrase_file: Option<PathBuf>,
+    // Encrypt and decrypt with the passphrase on the first line of this file.
+    #[arg(long)]
+    recipient: Vec<String>,
+    // Encrypt for this age public key (`age1...`). Can be repeated.
+    #[arg(long)]
+    identity: Vec<PathBuf>, // Decrypt with the age private keys in this file. Can be repeated.
+}
+
+#[derive(Args)]
+// Sign the Signatures and Deltas written, and verify the ones read, with ed25519.
+struct SigningArguments {
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+    // Sign with the private key in this file, created by `generate-signing-key`.
+    #[arg(long)]
+    verifying_key: Vec<PathBuf>,
+    // Accept artifacts signed with the public key in this file. Can be repeated.
+    #[arg(long, requires = "verifying_key")]
+    require_signed: bool, // Refuse artifacts which are not signed with one of the verifying keys.
+}
+
+#[derive(Args)]
+// Must be the same for the `signature` and `delta` commands. `patch` reads it from the Delta.
+struct PreprocessingArguments {
+    #[arg(long)]
+    normalize_text: bool,
+    // Convert CRLF line endings to LF before computing blocks.
+    #[arg(long, requires = "normalize_text")]
+    strip_bom: bool,
+    // Also ignore a leading UTF-8 byte order mark.
+    #[arg(long)]
+    transform: Option<String>, // Transform applied to the file before computing blocks.
+}
+
+#[derive(Args)]
+struct AnnotationArguments {
+    #[arg(long)]
+    annotate: bool,
+    // Record when the Delta was computed, and by which version, in its header.
+    #[arg(long, value_name = "NAME")]
+    creator: Option<String>,
+    // Also record who computed the Delta, e.g. a user or a build job.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_property)]
+    property: Vec<(String, String)>,
+    // Also record a free-form property, e.g. `--property commit=4f2a9c1`. May be repeated.
+}
+
+impl From<AnnotationArguments> for Option<DeltaMetadata> {
+    fn from(arguments: AnnotationArguments) -> Self {
+        let AnnotationArguments {
+            annotate,
+            creator,
+            property,
+        } = arguments;
+        if !annotate && creator.is_none() && property.is_empty() {
+            return None;
+        }
+        let mut metadata = DeltaMetadata::now(creator);
+        metadata.properties = property.into_iter().collect();
+        Some(metadata)
+    }
+}
+
+#[derive(Args)]
+struct SummaryArguments {
+    #[arg(long)]
+    quiet: bool,
+    // Do not print what the command read and wrote once it completes.
+    #[arg(long, conflicts_with = "quiet")]
+    stats_json: bool,
+    // Print what the command read and wrote as a line of JSON instead.
+}
+
+#[derive(Args)]
+struct ProvenanceMapArguments {
+    #[arg(long = "provenance-map")]
+    filename: Option<PathBuf>,
+    // Where to save a map of which parts of the updated file are reused from the basis file.
+    #[arg(long = "map-format", default_value_t = MapFormat::Csv)]
+    format: MapFormat, // Format of the provenance map.
+}
+
+fn main() -> color_eyre::Result<(), color_eyre::Report> {
+    // For prettier errors.
+    color_eyre::install().expect("Could not install color_eyre");
+
+    let args = Arguments::parse();
+    let parallelism = Parallelism::from_environment(args.threads)?;
+    if args.progress_json {
+        progress::enable(Duration::from_millis(args.progress_interval_ms.get()));
+    }
+    if args.profile_report && !profiling::ENABLED {
+        return Err(eyre!("This rsync_rust was built without profiling"))
+            .suggestion("Build it with `cargo build --release --features profiling`.");
+    }
+
+    let result = match args.command {
+        Commands::Signature(arguments) => handle_signature_command(arguments, parallelism),
+        Commands::Delta(arguments) => handle_delta_command(arguments, parallelism),
+        Commands::Index(arguments) => handle_index_command(arguments, parallelism),
+        Commands::RequestBlocks(arguments) => handle_request_blocks_command(arguments),
+        Commands::SendBlocks(arguments) => handle_send_blocks_command(arguments),
+        Commands::Assemble(arguments) => handle_assemble_command(arguments),
+        Commands::DeltaBestBasis(arguments) => {
+            handle_delta_best_basis_command(arguments, parallelism)
+        }
+        Commands::Patch(arguments) if arguments.batch.is_some() => {
+            handle_patch_batch(arguments, parallelism)
+        }
+        Commands::Patch(arguments) if arguments.simulate => handle_patch_simulation(arguments),
+        Commands::Patch(arguments) if arguments.verify_only => handle_patch_verification(arguments),
+        Commands::Patch(arguments) if arguments.inplace => handle_patch_in_place(arguments),
+        Commands::Patch(arguments) => handle_patch_command(arguments),
+        Commands::Flash(arguments) => handle_flash_command(arguments),
+        Commands::Recover(arguments) => handle_recover_command(arguments),
+        Commands::Inspect(arguments) => handle_inspect_command(arguments),
+        Commands::MergeDeltas(arguments) => handle_merge_deltas_command(arguments),
+        Commands::Cmp(arguments) => handle_cmp_command(arguments),
+        Commands::CmpSig(arguments) => handle_cmp_sig_command(arguments),
+        Commands::Blocks(arguments) => handle_blocks_command(arguments, parallelism),
+        Commands::Similarity(arguments) => handle_similarity_command(arguments, parallelism),
+        Commands::Analyze(arguments) => handle_analyze_command(arguments, parallelism),
+        Commands::Manifest(arguments) => handle_manifest_command(arguments),
+        Commands::Check(arguments) => handle_check_command(arguments),
+        Commands::Serve(arguments) => handle_serve_command(arguments),
+        Commands::Push(arguments) => handle_push_command(arguments, parallelism),
+        Commands::Transfer(arguments) => handle_transfer_command(arguments),
+        Commands::Bisync(arguments) => handle_bisync_command(arguments),
+        Commands::Resign(arguments) => handle_resign_command(arguments),
+        Commands::Backup(arguments) => handle_backup_command(arguments),
+        Commands::Restore(arguments) => handle_restore_command(arguments),
+        Commands::Gc(arguments) => handle_gc_command(arguments),
+        Commands::Selftest(arguments) => handle_selftest_command(arguments),
+        Commands::GenerateSigningKey(arguments) => {
+            generate_signing_key(&arguments.signing_key, &arguments.verifying_key)
+        }
+    };
+    if args.profile_report {
+        eprintln!("{}", profiling::report());
+    }
+
+    result
+}
+
+fn handle_signature_command(
+    arguments: SignatureArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let SignatureArguments {
+        basis_filename,
+        signature_output_filename,
+        blocks,
+        preprocessing,
+        verify_deterministic,
+        dedup,
+        align_to_extents,
+        weak_hash,
+        strong_hash,
+        weak_only,
+        timings,
+        summary,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let mut blocks: BlockOptions = blocks.into();
+    if align_to_extents {
+        if blocks.mode != ChunkingMode::Fixed {
+            return Err(eyre!("Blocks are only aligned in `fixed` mode"));
+        }
+        let chunk_size = align_chunk_size_to_file(&basis_filename, blocks.chunk_size);
+        if chunk_size != blocks.chunk_size {
+            eprintln!(
+                "Aligned the chunk size to {chunk_size} bytes: use `--chunk-size {chunk_size}` for \
+                 the Delta"
+            );
+            blocks.chunk_size = chunk_size;
+        }
+    }
+    let options = SignatureOptions {
+        blocks,
+        preprocessing: preprocessing.into(),
+        verify_deterministic,
+        dedup,
+        weak_hash,
+        strong_hash: match weak_only {
+            true => StrongHash::Omitted,
+            false => strong_hash,
+        },
+        parallelism,
+    };
+
+    let report = commands::signature(
+        &basis_filename,
+        &signature_output_filename,
+        &options,
+        &protection,
+    )?;
+    if timings {
+        println!("{}", report.timings);
+    }
+    print_summary(&report.summary(allocation_counts()), &summary, false)
+}
+
+fn handle_delta_command(
+    arguments: DeltaArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let DeltaArguments {
+        signature_filename,
+        updated_filename,
+        delta_filename,
+        blocks,
+        preprocessing,
+        provenance_map,
+        matching,
+        verify_deterministic,
+        stream,
+        updated_signature,
+        block_index,
+        annotation,
+        timings,
+        summary,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let block_index = match block_index {
+        Some(filename) => {
+            let index_bytes = io_utils::attempt_to_read_file(&filename).context(
+                "Error while reading Block Index file provided as argument to `delta` command",
+            )?;
+            Some(Arc::new(BlockIndex::try_from(index_bytes)?))
+        }
+        None => None,
+    };
+    let options = DeltaOptions {
+        blocks: blocks.into(),
+        preprocessing: preprocessing.into(),
+        matching: matching.into(),
+        verify_deterministic,
+        stream,
+        provenance_map: provenance_map.into(),
+        updated_signature,
+        metadata: annotation.into(),
+        block_index,
+        parallelism,
+    };
+
+    let report = commands::delta(
+        &signature_filename,
+        &updated_filename,
+        &delta_filename,
+        &options,
+        &protection,
+    )?;
+    if timings {
+        println!("{}", report.timings);
+    }
+    print_summary(&report.summary(allocation_counts()), &summary, false)
+}
+
+fn handle_index_command(
+    arguments: IndexArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let IndexArguments {
+        updated_filename,
+        index_output_filename,
+        chunk_size,
+        weak_hash,
+    } = arguments;
+
+    let updated_file_bytes = io_utils::attempt_to_read_file(&updated_filename)
+        .context("Error while reading Updated file provided as argument to `index` command")?;
+    let index = BlockIndex::compute(&updated_file_bytes, chunk_size, weak_hash, &parallelism)?;
+    let index_bytes: Bytes = index.try_into()?;
+    io_utils::write_to_file(&index_output_filename, index_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        &index_output_filename.display()
+    ))
+}
+
+fn handle_request_blocks_command(
+    arguments: RequestBlocksArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let RequestBlocksArguments {
+        signature_filename,
+        basis_filename,
+        request_output_filename,
+        chunk_size,
+        estimate,
+        matching,
+    } = arguments;
+
+    let signature = read_signature(
+        &signature_filename,
+        "request-blocks",
+        &ArtifactProtection::default(),
+    )?;
+    let basis_file_bytes = io_utils::attempt_to_read_file(&basis_filename).context(
+        "Error while reading Basis file provided as argument to `request-blocks` command",
+    )?;
+    let plan = DownloadPlan::compute(&signature, &basis_file_bytes, chunk_size, &matching.into())?;
+    let request = plan.request();
+    println!(
+        "Found {} of {} bytes locally, requesting {} bytes in {} ranges of blocks",
+        plan.local_size(),
+        plan.layout.length,
+        request.size(),
+        request.missing.len()
+    );
+    if estimate {
+        return Ok(());
+    }
+    let request_output_filename = request_output_filename.expect("Required unless estimating");
+    let request_bytes: Bytes = request.try_into()?;
+    io_utils::write_to_file(&request_output_filename, request_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        &request_output_filename.display()
+    ))
+}
+
+fn handle_send_blocks_command(
+    arguments: SendBlocksArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let SendBlocksArguments {
+        updated_filename,
+        request_filename,
+        blocks_output_filename,
+    } = arguments;
+
+    let updated_file_bytes = io_utils::attempt_to_read_file(&updated_filename).context(
+        "Error while reading Updated file provided as argument to `send-blocks` command",
+    )?;
+    let request_bytes = io_utils::attempt_to_read_file(&request_filename).context(
+        "Error while reading Block Request file provided as argument to `send-blocks` command",
+    )?;
+    let request = BlockRequest::try_from(request_bytes)?;
+    let blocks = answer_block_request(&updated_file_bytes, &request)?;
+    io_utils::write_to_file(&blocks_output_filename, blocks).wrap_err(format!(
+        "Unable to write to file: {}",
+        &blocks_output_filename.display()
+    ))
+}
+
+fn handle_assemble_command(
+    arguments: AssembleArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let AssembleArguments {
+        signature_filename,
+        basis_filename,
+        blocks_filename,
+        recreated_filename,
+        chunk_size,
+        matching,
+        audit,
+    } = arguments;
+    let started = SystemTime::now();
+
+    let signature = read_signature(
+        &signature_filename,
+        "assemble",
+        &ArtifactProtection::default(),
+    )?;
+    let basis_file_bytes = io_utils::attempt_to_read_file(&basis_filename)
+        .context("Error while reading Basis file provided as argument to `assemble` command")?;
+    let blocks = io_utils::attempt_to_read_file(&blocks_filename)
+        .context("Error while reading blocks file provided as argument to `assemble` command")?;
+    // The same plan as the request's, as long as the basis file did not change.
+    let plan = DownloadPlan::compute(&signature, &basis_file_bytes, chunk_size, &matching.into())?;
+    let assembled = plan.assemble(&signature, &basis_file_bytes, &blocks);
+    append_to_audit_log(&audit, || {
+        let record = AuditRecord {
+            files: vec![recreated_filename.display().to_string()],
+            ..AuditRecord::new("assemble", started)
+        };
+        // Blocks are checked against the Signature as the file is assembled.
+        match &assembled {
+            Ok(_) => AuditRecord {
+                verified: true,
+                ..record.with_sizes(plan.layout.length, blocks.len() as u64)
+            },
+            Err(error) => AuditRecord {
+                error: Some(format!("{error:#}")),
+                ..record
+            },
+        }
+    })?;
+    let updated_file =
+        assembled.suggestion("Request the blocks again: the basis file may have changed since.")?;
+    io_utils::write_to_file(&recreated_filename, updated_file).wrap_err(format!(
+        "Unable to write to file: {}",
+        &recreated_filename.display()
+    ))
+}
+
+// Appends the record built by `record` to the audit log, if one was given.
+fn append_to_audit_log(
+    audit: &AuditArguments,
+    record: impl FnOnce() -> AuditRecord,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    match &audit.log_file {
+        Some(log_filename) => append_audit_record(log_filename, &record()),
+        None => Ok(()),
+    }
+}
+
+fn handle_delta_best_basis_command(
+    arguments: DeltaBestBasisArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let DeltaBestBasisArguments {
+        candidates_directory,
+        updated_filename,
+        delta_filename,
+        blocks,
+        preprocessing,
+        matching,
+        annotation,
+        timings,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let options = DeltaOptions {
+        blocks: blocks.into(),
+        preprocessing: preprocessing.into(),
+        matching: matching.into(),
+        metadata: annotation.into(),
+        parallelism,
+        ..Default::default()
+    };
+
+    let report = commands::delta_against_best_basis(
+        &candidates_directory,
+        &updated_filename,
+        &delta_filename,
+        &options,
+        &protection,
+    )?;
+    for candidate in &report.candidates {
+        println!(
+            "{:>6.2}% {}",
+            candidate.score * 100.0,
+            candidate.filename.display()
+        );
+    }
+    println!(
+        "Delta computed against: {}",
+        report.chosen_candidate().filename.display()
+    );
+    if timings {
+        println!("{}", report.delta.timings);
+    }
+    Ok(())
+}
+
+fn handle_cmp_command(arguments: CmpArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let CmpArguments {
+        filename,
+        signature_filename,
+        blocks,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+
+    let file_bytes = io_utils::attempt_to_read_file(filename)
+        .context("Error while reading file provided as argument to `cmp` command")?;
+    let signature = read_signature(&signature_filename, "cmp", &protection)?;
+
+    let our_signature = compute_signature_with_mode(file_bytes, blocks.chunk_size, blocks.mode);
+    let comparison = compare_signatures(&our_signature, &signature);
+    report_comparison(&comparison, blocks)
+}
+
+fn handle_cmp_sig_command(
+    arguments: CmpSigArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let CmpSigArguments {
+        first_signature_filename,
+        second_signature_filename,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+
+    let first_signature = read_signature(&first_signature_filename, "cmp-sig", &protection)?;
+    let second_signature = read_signature(&second_signature_filename, "cmp-sig", &protection)?;
+
+    let comparison = compare_signatures(&first_signature, &second_signature);
+    println!("{comparison}");
+    if comparison.is_equal() {
+        Ok(())
+    } else {
+        Err(eyre!("Signatures differ"))
+    }
+}
+
+fn handle_blocks_command(
+    arguments: BlocksArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let BlocksArguments {
+        filename,
+        blocks,
+        format,
+    } = arguments;
+
+    let file_bytes = io_utils::attempt_to_read_file(&filename)
+        .context("Error while reading file provided as argument to `blocks` command")?;
+
+    let list = compute_block_list(&file_bytes, blocks.chunk_size, blocks.mode, &parallelism);
+    let list_bytes = match format {
+        MapFormat::Csv => list.to_csv()?,
+        MapFormat::Json => list.to_json()?,
+    };
+    std::io::stdout().write_all(&list_bytes)?;
+    Ok(())
+}
+
+fn handle_similarity_command(
+    arguments: SimilarityArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let SimilarityArguments {
+        basis_filename,
+        updated_filename,
+        blocks,
+        heat_map,
+        heat_map_buckets,
+    } = arguments;
+
+    let basis_file_bytes = io_utils::attempt_to_read_file(&basis_filename)
+        .context("Error while reading Basis file provided as argument to `similarity` command")?;
+    let updated_file_bytes = io_utils::attempt_to_read_file(&updated_filename)
+        .context("Error while reading Updated file provided as argument to `similarity` command")?;
+
+    let similarity = compute_similarity(
+        &basis_file_bytes,
+        &updated_file_bytes,
+        blocks.chunk_size,
+        blocks.mode,
+        &parallelism,
+    );
+    println!("{similarity}");
+    if let Some(heat_map_filename) = heat_map {
+        io_utils::write_to_file(
+            &heat_map_filename,
+            similarity.heat_map_to_json(heat_map_buckets)?,
+        )
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            heat_map_filename.display()
+        ))?;
+    }
+    Ok(())
+}
+
+fn handle_analyze_command(
+    arguments: AnalyzeArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let AnalyzeArguments {
+        basis_filename,
+        updated_filename,
+        chunk_sizes,
+        compression_levels,
+        matching,
+    } = arguments;
+    if let Some(level) = compression_levels.iter().find(|&&level| level > 9) {
+        return Err(eyre!("{level} is not a compression level"))
+            .suggestion("Compression levels go from 0 (none) to 9 (smallest).");
+    }
+
+    let basis_file_bytes = io_utils::attempt_to_read_file(&basis_filename)
+        .context("Error while reading Basis file provided as argument to `analyze` command")?;
+    let updated_file_bytes = io_utils::attempt_to_read_file(&updated_filename)
+        .context("Error while reading Updated file provided as argument to `analyze` command")?;
+
+    let analysis = analyze(
+        &basis_file_bytes,
+        &updated_file_bytes,
+        &chunk_sizes,
+        &compression_levels,
+        &matching.into(),
+        &parallelism,
+    )?;
+    std::io::stdout().write_all(&analysis.to_csv()?)?;
+    Ok(())
+}
+
+fn handle_manifest_command(
+    arguments: ManifestArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let ManifestArguments {
+        directory,
+        manifest_output_filename,
+    } = arguments;
+
+    let manifest = compute_manifest(&directory)
+        .context("Error while reading directory provided as argument to `manifest` command")?;
+    let manifest_bytes: Bytes = manifest.try_into()?;
+    io_utils::write_to_file(&manifest_output_filename, manifest_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        &manifest_output_filename.display()
+    ))
+}
+
+fn handle_check_command(arguments: CheckArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let CheckArguments {
+        directory,
+        manifest_filename,
+    } = arguments;
+
+    let manifest_file_bytes = io_utils::attempt_to_read_file(&manifest_filename)
+        .context("Error while reading Manifest file provided as argument to `check` command")?;
+    let manifest: Manifest = manifest_file_bytes.try_into().context(format!(
+        r#"Manifest file path provided was "{}"."#,
+        &manifest_filename.display()
+    ))?;
+
+    let check = check_manifest(&directory, &manifest)
+        .context("Error while reading directory provided as argument to `check` command")?;
+    println!("{check}");
+    if check.is_intact() {
+        Ok(())
+    } else {
+        Err(eyre!("Directory does not match the Manifest"))
+    }
+}
+
+fn handle_serve_command(arguments: ServeArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let ServeArguments {
+        basis_filename,
+        listen,
+        listen_unix,
+        once,
+        workers,
+        connections_per_client,
+        timeout_secs,
+        max_output_size,
+        on_conflict,
+        no_lock,
+    } = arguments;
+    let options = ServeOptions {
+        workers,
+        connections_per_client,
+        timeout: (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs)),
+        max_output_size,
+        on_conflict,
+        lock_basis_file: !no_lock,
+    };
+    let connections_to_serve = if once { 1 } else { usize::MAX };
+
+    if let Some(socket_path) = listen_unix {
+        return serve_unix_socket(
+            &socket_path,
+            &basis_filename,
+            connections_to_serve,
+            &options,
+        );
+    }
+    let listener =
+        TcpListener::bind(&listen).wrap_err(format!("Unable to listen on address: {listen}"))?;
+    println!("Listening on {}", listener.local_addr()?);
+    serve_connections(
+        listener.incoming().take(connections_to_serve),
+        &basis_filename,
+        &options,
+        |result| report_sync(&basis_filename, result),
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn serve_unix_socket(
+    socket_path: &Path,
+    basis_filename: &Path,
+    connections_to_serve: usize,
+    options: &ServeOptions,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+
+    // A socket left behind by a previous run would make binding fail.
+    if std::fs::symlink_metadata(socket_path).is_ok_and(|metadata| metadata.file_type().is_socket())
+    {
+        std::fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path).wrap_err(format!(
+        "Unable to listen on socket: {}",
+        socket_path.display()
+    ))?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+    println!("Listening on {}", socket_path.display());
+    serve_connections(
+        listener.incoming().take(connections_to_serve),
+        basis_filename,
+        options,
+        |result| report_sync(basis_filename, result),
+    );
+    std::fs::remove_file(socket_path)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_unix_socket(
+    _socket_path: &Path,
+    _basis_filename: &Path,
+    _connections_to_serve: usize,
+    _options: &ServeOptions,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    Err(eyre!("`--listen-unix` is only available on Unix"))
+}
+
+// A failed sync only concerns its sender, so it is reported and the others are still served.
+fn report_sync(basis_filename: &Path, result: color_eyre::Result<SyncOutcome>) {
+    match result {
+        Ok(SyncOutcome::Updated {
+            recreated_size,
+            method,
+        }) => {
+            println!(
+                "Updated {} ({recreated_size} bytes, from the {method})",
+                basis_filename.display()
+            )
+        }
+        Ok(SyncOutcome::KeptBoth {
+            recreated_size,
+            method,
+            filename,
+        }) => {
+            println!(
+                "{} changed during the sync, wrote {} instead ({recreated_size} bytes, from the \
+                 {method})",
+                basis_filename.display(),
+                filename.display()
+            )
+        }
+        Ok(SyncOutcome::Failed { reason }) => eprintln!("Sync failed: {reason}"),
+        Ok(SyncOutcome::Estimated(estimate)) => {
+            println!(
+                "Sender estimated a sync of {}: {estimate}",
+                basis_filename.display()
+            )
+        }
+        Err(error) => eprintln!("Sync failed: {error:#}"),
+    }
+}
+
+fn handle_push_command(
+    arguments: PushArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let PushArguments {
+        updated_filename,
+        address,
+        coarse_chunk_size,
+        single_level,
+        retries,
+        retry_backoff_ms,
+        compress_whole_file,
+        estimate,
+        blocks,
+        audit,
+    } = arguments;
+    let started = SystemTime::now();
+
+    let updated_file_bytes = io_utils::attempt_to_read_file(&updated_filename)
+        .context("Error while reading Updated file provided as argument to `push` command")?;
+
+    let mut request = SyncRequest::for_file(&updated_file_bytes, blocks.chunk_size, blocks.mode);
+    request.compress_whole_file = compress_whole_file;
+    request.estimate_only = estimate;
+    if single_level {
+        request.coarse_chunk_size = None;
+    } else if let Some(coarse_chunk_size) = coarse_chunk_size {
+        if blocks.mode != ChunkingMode::Fixed {
+            return Err(eyre!("Coarse blocks are only available in `fixed` mode"));
+        }
+        request.coarse_chunk_size = Some(align_coarse_chunk_size(
+            coarse_chunk_size,
+            blocks.chunk_size,
+        ));
+    }
+    let policy = RetryPolicy {
+        retries,
+        initial_backoff: Duration::from_millis(retry_backoff_ms),
+        ..Default::default()
+    };
+    let report_retry = |error: &color_eyre::Report, backoff: Duration| {
+        eprintln!("Sync failed: {error:#}. Retrying in {backoff:.1?}")
+    };
+    // Counts Signatures, Deltas and whole files alike, over every attempt.
+    let meter = TrafficMeter::default();
+    let outcome = match address.strip_prefix("unix:") {
+        Some(socket_path) => push_over_unix_socket(
+            Path::new(socket_path),
+            &meter,
+            &updated_file_bytes,
+            request,
+            &parallelism,
+            &policy,
+            report_retry,
+        ),
+        None => push_file_with_retries(
+            || TcpStream::connect(&address).map(|connection| meter.meter(connection)),
+            &updated_file_bytes,
+            request,
+            &parallelism,
+            &policy,
+            report_retry,
+        ),
+    }
+    .wrap_err(format!("Unable to sync with: {address}"));
+    append_to_audit_log(&audit, || {
+        let record = AuditRecord {
+            files: vec![updated_filename.display().to_string()],
+            ..AuditRecord::new(if estimate { "push --estimate" } else { "push" }, started)
+                .with_sizes(updated_file_bytes.len() as u64, meter.total())
+        };
+        // The receiver does not hash the file it recreates, so pushes are never verified.
+        match &outcome {
+            Ok(
+                SyncOutcome::Updated { .. }
+                | SyncOutcome::KeptBoth { .. }
+                | SyncOutcome::Estimated(_),
+            ) => record,
+            Ok(SyncOutcome::Failed { reason }) => AuditRecord {
+                error: Some(reason.clone()),
+                ..record
+            },
+            Err(error) => AuditRecord {
+                error: Some(format!("{error:#}")),
+                ..record
+            },
+        }
+    })?;
+    match outcome? {
+        SyncOutcome::Updated {
+            recreated_size,
+            method,
+        } => {
+            println!("Receiver updated its file ({recreated_size} bytes, sent as {method})");
+            Ok(())
+        }
+        SyncOutcome::KeptBoth {
+            recreated_size,
+            method,
+            filename,
+        } => {
+            println!(
+                "Receiver's file changed during the sync, so it kept both and wrote {} \
+                 ({recreated_size} bytes, sent as {method})",
+                filename.display()
+            );
+            Ok(())
+        }
+        SyncOutcome::Failed { reason } => {
+            Err(eyre!("Receiver could not apply the Delta: {reason}"))
+        }
+        SyncOutcome::Estimated(estimate) => {
+            println!("{estimate}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn push_over_unix_socket(
+    socket_path: &Path,
+    meter: &TrafficMeter,
+    updated_file_bytes: &Bytes,
+    request: SyncRequest,
+    parallelism: &Parallelism,
+    policy: &RetryPolicy,
+    on_retry: impl FnMut(&color_eyre::Report, Duration),
+) -> color_eyre::Result<SyncOutcome, color_eyre::Report> {
+    push_file_with_retries(
+        || std::os::unix::net::UnixStream::connect(socket_path).map(|stream| meter.meter(stream)),
+        updated_file_bytes,
+        request,
+        parallelism,
+        policy,
+        on_retry,
+    )
+}
+
+#[cfg(not(unix))]
+fn push_over_unix_socket(
+    _socket_path: &Path,
+    _meter: &TrafficMeter,
+    _updated_file_bytes: &Bytes,
+    _request: SyncRequest,
+    _parallelism: &Parallelism,
+    _policy: &RetryPolicy,
+    _on_retry: impl FnMut(&color_eyre::Report, Duration),
+) -> color_eyre::Result<SyncOutcome, color_eyre::Report> {
+    Err(eyre!("Unix sockets are only available on Unix"))
+}
+
+fn handle_transfer_command(
+    arguments: TransferArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let TransferArguments {
+        sender_directory,
+        receiver_directory,
+        compress_whole_files,
+        share_blocks,
+        match_compressed,
+        policy,
+        policy_file,
+        blocks,
+        matching,
+        audit,
+    } = arguments;
+    let started = SystemTime::now();
+    let mut policies: FilePolicies = policy
+        .join("\n")
+        .parse()
+        .context("Error while reading the policies provided to `transfer` command")?;
+    if let Some(policy_file) = policy_file {
+        let file = io_utils::attempt_to_read_file(&policy_file)
+            .context("Error while reading the policy file provided to `transfer` command")?;
+        policies.extend(
+            String::from_utf8_lossy(&file)
+                .parse()
+                .context("Error while reading the policy file provided to `transfer` command")?,
+        );
+    }
+
+    let options = TransferOptions {
+        chunk_size: blocks.chunk_size,
+        mode: blocks.mode,
+        matching: matching.into(),
+        compress_whole_files,
+        share_blocks,
+        match_compressed,
+        policies,
+    };
+    let transferred = transfer_directory(&sender_directory, &receiver_directory, &options);
+    append_to_audit_log(&audit, || {
+        let record = AuditRecord::new("transfer", started);
+        // Every file recreated is checked against the sender's before it is written.
+        match &transferred {
+            Ok(report) => AuditRecord {
+                files: report.files.iter().map(|file| file.path.clone()).collect(),
+                verified: true,
+                ..record.with_sizes(report.total_file_size(), report.total_sent_size())
+            },
+            Err(error) => AuditRecord {
+                error: Some(format!("{error:#}")),
+                ..record
+            },
+        }
+    })?;
+    let report = transferred.context(
+        "Error while transferring the directory provided as argument to `transfer` command",
+    )?;
+    for file in report.files.iter().filter(|file| file.looked_compressed) {
+        warn_compressed_file(&file.path);
+    }
+    println!("{report}");
+
+    Ok(())
+}
+
+fn handle_backup_command(arguments: BackupArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let BackupArguments {
+        chain_directory,
+        filename,
+        chunk_size,
+        matching,
+    } = arguments;
+
+    let file = io_utils::attempt_to_read_file(&filename)
+        .context("Error while reading the file provided as argument to `backup` command")?;
+    let generation = back_up_generation(&chain_directory, file, chunk_size, &matching.into())?;
+    println!(
+        "Stored generation {} ({}) in {} bytes",
+        generation.id, generation.file, generation.stored_size
+    );
+
+    Ok(())
+}
+
+fn handle_restore_command(
+    arguments: RestoreArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let RestoreArguments {
+        chain_directory,
+        restored_filename,
+        generation,
+        list,
+        max_output_size,
+    } = arguments;
+    if list {
+        print!("{}", GenerationChain::read(&chain_directory)?);
+        return Ok(());
+    }
+
+    let restored = restore_generation(&chain_directory, generation, max_output_size)?;
+    let restored_filename = restored_filename.expect("Required unless listing");
+    io_utils::write_buf_to_file_or_stdout(&restored_filename, restored, true).wrap_err(format!(
+        "Unable to write to file: {}",
+        restored_filename.display()
+    ))?;
+
+    Ok(())
+}
+
+fn handle_gc_command(arguments: GcArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let GcArguments {
+        chain_directory,
+        keep_last,
+        keep_within,
+        dry_run,
+        max_output_size,
+    } = arguments;
+    let policy = RetentionPolicy {
+        keep_last,
+        keep_within,
+    };
+
+    let report = prune_generations(&chain_directory, &policy, dry_run, max_output_size)?;
+    println!("{report}");
+
+    Ok(())
+}
+
+fn handle_selftest_command(
+    arguments: SelftestArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let SelftestArguments { seed, keep } = arguments;
+    let seed = seed.unwrap_or_else(rand::random);
+    let directory =
+        std::env::temp_dir().join(format!("rsync_rust_selftest_{}", std::process::id()));
+
+    println!("Seed {seed}, files in {}", directory.display());
+    let report = run_selftest(&directory, seed);
+    if !keep {
+        let _ = fs::remove_dir_all(&directory);
+    }
+    let report = report?;
+    println!("{report}");
+    match report.failures() {
+        0 => Ok(()),
+        failures => Err(eyre!("{failures} self-test cases failed")).suggestion(format!(
+            "Run `selftest --seed {seed} --keep` to inspect them."
+        )),
+    }
+}
+
+fn handle_resign_command(arguments: ResignArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let ResignArguments {
+        old_signature_filename,
+        basis_filename,
+        chunk_size,
+        output,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+
+    let signature_filename = output.as_deref().unwrap_or(&old_signature_filename);
+    let resigned = commands::resign(
+        &old_signature_filename,
+        &basis_filename,
+        signature_filename,
+        chunk_size,
+        &protection,
+    )?;
+    if resigned.breaks_cached_deltas() {
+        match resigned.old_chunk_size {
+            Some(old_chunk_size) => eprintln!(
+                "Warning: Deltas computed against the old Signature must still be patched with \
+                 `--chunk-size {old_chunk_size}`, not {chunk_size}."
+            ),
+            None => eprintln!(
+                "Warning: the chunk size of the old Signature is unknown. Deltas computed against \
+                 it must still be patched with it, not {chunk_size}."
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn load_protection(
+    encryption: &EncryptionArguments,
+    signing: &SigningArguments,
+) -> color_eyre::Result<ArtifactProtection, color_eyre::Report> {
+    let keys = ArtifactKeys::load(
+        encryption.passphrase_file.as_deref(),
+        &encryption.recipient,
+        &encryption.identity,
+    )
+    .context("Error while loading the encryption keys")?;
+    let signer = ArtifactSigner::load(
+        signing.signing_key.as_deref(),
+        &signing.verifying_key,
+        signing.require_signed,
+    )
+    .context("Error while loading the signing keys")?;
+
+    Ok(ArtifactProtection { keys, signer })
+}
+
+fn report_comparison(
+    comparison: &SignatureComparison,
+    blocks: BlockArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    println!("{comparison}");
+    if comparison.is_equal() {
+        return Ok(());
+    }
+
+    // Blocks only map to byte offsets when they all have the same size.
+    if blocks.mode == ChunkingMode::Fixed {
+        let differing_bytes: Vec<_> = comparison
+            .differing_byte_ranges(blocks.chunk_size)
+            .iter()
+            .map(|bytes| format!("{}..{}", bytes.start, bytes.end))
+            .collect();
+        println!("Differing bytes: [{}]", differing_bytes.join(", "));
+    }
+    Err(eyre!("Files differ")).suggestion(
+        "Make sure the Signature was computed with the same chunk size and mode as this comparison.",
+    )
+}
+
+fn handle_patch_command(arguments: PatchArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let PatchArguments {
+        basis_filename,
+        delta_filename,
+        recreated_filename,
+        blocks,
+        range,
+        max_output_size,
+        basis_io,
+        read_ahead,
+        no_lock,
+        assume_basis_ok,
+        timings,
+        summary,
+        provenance_map,
+        encryption,
+        signing,
+        ..
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let delta_filename = delta_filename.expect("Required unless batching");
+    let recreated_filename = recreated_filename.expect("Required unless simulating or verifying");
+    let options = PatchOptions {
+        blocks: blocks.into(),
+        range,
+        max_output_size,
+        provenance_map: provenance_map.into(),
+        basis_io,
+        read_ahead,
+        block_cache: None,
+        lock: !no_lock,
+        assume_basis_ok,
+    };
+
+    let report = commands::patch(
+        &basis_filename,
+        &delta_filename,
+        &recreated_filename,
+        &options,
+        &protection,
+    )?;
+    // The recreated file may be on the standard output already.
+    let to_stderr = io_utils::is_stdio(&recreated_filename);
+    if report.unconfirmed {
+        warn_unconfirmed_blocks(&delta_filename);
+    }
+    if timings && to_stderr {
+        eprintln!("{}", report.timings);
+    } else if timings {
+        println!("{}", report.timings);
+    }
+    print_summary(&report.summary(allocation_counts()), &summary, to_stderr)
+}
+
+// A collision of weak hashes may have put the wrong block in the recreated file. It is only
+// caught if the Delta recorded the updated file.
+fn warn_unconfirmed_blocks(delta_filename: &Path) {
+    eprintln!(
+        "Warning: {} was computed against a `--weak-only` Signature. Its blocks were reused on \
+         their rolling hash alone, and may not be the blocks of the updated file.",
+        delta_filename.display()
+    );
+}
+
+fn warn_compressed_file(path: &str) {
+    eprintln!(
+        "Warning: {path} looks already compressed, so it was sent whole without looking for blocks \
+         of the basis file. Use `--match-compressed` to look for them anyway."
+    );
+    if let Some(transform) = container_transform(path) {
+        eprintln!(
+            "Warning: {path} is an archive, so syncing it with `signature`, `delta` and `patch` \
+             with `--transform {transform}` may find blocks of its members."
+        );
+    }
+}
+
+// Prints what a command read and wrote, unless asked not to, on the standard error when the
+// standard output holds the command's output.
+fn print_summary(
+    summary: &RunSummary,
+    arguments: &SummaryArguments,
+    to_stderr: bool,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let line = match (arguments.quiet, arguments.stats_json) {
+        (_, true) => summary.to_json()?,
+        (true, false) => return Ok(()),
+        (false, false) => summary.to_string(),
+    };
+    if to_stderr {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+fn handle_patch_in_place(arguments: PatchArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let PatchArguments {
+        basis_filename,
+        delta_filename,
+        journal,
+        blocks,
+        max_output_size,
+        assume_basis_ok,
+        timings,
+        provenance_map,
+        encryption,
+        signing,
+        ..
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let delta_filename = delta_filename.expect("Required unless batching");
+    let journal_filename = journal.unwrap_or_else(|| default_journal_filename(&basis_filename));
+    let options = PatchOptions {
+        blocks: blocks.into(),
+        range: None,
+        max_output_size,
+        provenance_map: provenance_map.into(),
+        basis_io: BasisIo::Memory,
+        assume_basis_ok,
+        ..Default::default()
+    };
+
+    let report = commands::patch_in_place(
+        &basis_filename,
+        &delta_filename,
+        &journal_filename,
+        &options,
+        &protection,
+    )?;
+    if timings {
+        println!("{}", report.timings);
+    }
+    Ok(())
+}
+
+fn handle_recover_command(
+    arguments: RecoverArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let RecoverArguments {
+        filename,
+        journal_filename,
+    } = arguments;
+
+    let recovery = recover_from_journal(&filename, &journal_filename)?;
+
+    println!("{recovery}");
+    Ok(())
+}
+
+fn handle_bisync_command(arguments: BisyncArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let BisyncArguments {
+        left_filename,
+        right_filename,
+        state,
+        blocks,
+    } = arguments;
+    let state_filename =
+        state.unwrap_or_else(|| commands::default_bisync_state_filename(&left_filename));
+
+    let report = commands::bisync(
+        &left_filename,
+        &right_filename,
+        &state_filename,
+        &blocks.into(),
+    )?;
+
+    println!("{report}");
+    Ok(())
+}
+
+fn handle_flash_command(arguments: FlashArguments) -> color_eyre::Result<(), color_eyre::Report> {
+    let FlashArguments {
+        basis_partition,
+        delta_filename,
+        target_partition,
+        basis_size,
+        capacity,
+        blocks,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let options = FlashOptions {
+        blocks: blocks.into(),
+        basis_size,
+        capacity,
+    };
+
+    let update = commands::flash(
+        &basis_partition,
+        &delta_filename,
+        &target_partition,
+        &options,
+        &protection,
+    )?;
+
+    println!("{update}");
+    Ok(())
+}
+
+fn handle_patch_batch(
+    arguments: PatchArguments,
+    parallelism: Parallelism,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let PatchArguments {
+        basis_filename,
+        batch,
+        blocks,
+        max_output_size,
+        basis_io,
+        read_ahead,
+        no_lock,
+        assume_basis_ok,
+        timings,
+        provenance_map,
+        encryption,
+        signing,
+        ..
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let jobs = PatchJob::read_list(&batch.expect("Only called for batches"))?;
+    let options = PatchOptions {
+        blocks: blocks.into(),
+        range: None,
+        max_output_size,
+        provenance_map: provenance_map.into(),
+        basis_io,
+        read_ahead,
+        block_cache: None,
+        lock: !no_lock,
+        assume_basis_ok,
+    };
+
+    let report =
+        commands::patch_batch(&basis_filename, &jobs, &options, &parallelism, &protection)?;
+    for (job, patch) in jobs.iter().zip(&report.patches) {
+        match patch {
+            Ok(patch) => {
+                println!(
+                    "Recreated {} ({} bytes)",
+                    job.recreated_filename.display(),
+                    patch.recreated_size
+                );
+                if patch.unconfirmed {
+                    warn_unconfirmed_blocks(&job.delta_filename);
+                }
+                if timings {
+                    println!("{}", patch.timings);
+                }
+            }
+            Err(error) => eprintln!("{error:?}"),
+        }
+    }
+    println!(
+        "Blocks of the Basis file found in the cache: {}, read from the disk: {}",
+        report.cache.hits, report.cache.misses
+    );
+    report.check()
+}
+
+fn handle_patch_verification(
+    arguments: PatchArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let PatchArguments {
+        basis_filename,
+        delta_filename,
+        blocks,
+        max_output_size,
+        timings,
+        provenance_map,
+        encryption,
+        signing,
+        ..
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let delta_filename = delta_filename.expect("Required unless batching");
+    let options = PatchOptions {
+        blocks: blocks.into(),
+        range: None,
+        max_output_size,
+        provenance_map: provenance_map.into(),
+        basis_io: BasisIo::Memory,
+        ..Default::default()
+    };
+
+    let verification =
+        commands::verify_patch(&basis_filename, &delta_filename, &options, &protection)?;
+
+    println!("{verification}");
+    if timings {
+        println!("{}", verification.timings);
+    }
+    if verification.matches() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Applying the Delta does not recreate the updated file"
+        ))
+        .suggestion("Are you sure the Delta was computed from this Basis file's Signature?")
+    }
+}
+
+fn handle_patch_simulation(
+    arguments: PatchArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let PatchArguments {
+        basis_filename,
+        delta_filename,
+        blocks,
+        encryption,
+        signing,
+        ..
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let delta_filename = delta_filename.expect("Required unless batching");
+
+    let simulation = commands::simulate_patch(
+        &basis_filename,
+        &delta_filename,
+        &blocks.into(),
+        &protection,
+    )?;
+
+    println!("{simulation}");
+    if simulation.is_valid() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Delta references blocks which are not in the Basis file"
+        ))
+        .suggestion("Are you sure the Delta was computed from this Basis file's Signature?")
+    }
+}
+
+fn handle_inspect_command(
+    arguments: InspectArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let InspectArguments {
+        delta_filename,
+        basis_filename,
+        max_diff_size,
+        mode,
+        html,
+        chunk_size,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+    let options = InspectOptions {
+        basis_filename,
+        max_diff_size,
+        mode,
+        html_filename: html,
+        chunk_size,
+        diff_style: match std::io::stdout().is_terminal() {
+            true => DiffStyle::Colored,
+            false => DiffStyle::Plain,
+        },
+    };
+
+    let inspection = commands::inspect(&delta_filename, &options, &protection)?;
+
+    println!("{inspection}");
+    Ok(())
+}
+
+fn handle_merge_deltas_command(
+    arguments: MergeDeltasArguments,
+) -> color_eyre::Result<(), color_eyre::Report> {
+    let MergeDeltasArguments {
+        left_delta_filename,
+        right_delta_filename,
+        merged_delta_filename,
+        encryption,
+        signing,
+    } = arguments;
+    let protection = load_protection(&encryption, &signing)?;
+
+    commands::merge_delta_files(
+        &left_delta_filename,
+        &right_delta_filename,
+        &merged_delta_filename,
+        &protection,
+    )?;
+    Ok(())
+}
+
+impl From<BlockArguments> for BlockOptions {
+    fn from(arguments: BlockArguments) -> Self {
+        Self {
+            chunk_size: arguments.chunk_size,
+            mode: arguments.mode,
+        }
+    }
+}
+
+impl From<PreprocessingArguments> for Preprocessing {
+    fn from(arguments: PreprocessingArguments) -> Self {
+        Self {
+            normalize_text: arguments.normalize_text,
+            strip_bom: arguments.strip_bom,
+            transform: arguments.transform,
+        }
+    }
+}
+
+impl From<MatchingArguments> for MatchingOptions {
+    fn from(arguments: MatchingArguments) -> Self {
+        Self {
+            resync_after: arguments.resync_after,
+            preference: arguments.match_preference,
+            strategy: arguments.strategy,
+            zero_runs: arguments.zero_runs,
+        }
+    }
+}
+
+impl From<ProvenanceMapArguments> for Option<ProvenanceMapOutput> {
+    fn from(arguments: ProvenanceMapArguments) -> Self {
+        let format = arguments.format;
+        arguments
+            .filename
+            .map(|filename| ProvenanceMapOutput { filename, format })
+    }
+}
+
+// Parses a duration written as a number and a unit: `s`, `m`, `h`, `d` or `w`.
+fn parse_duration(argument: &str) -> Result<Duration, String> {
+    let unit_start = argument
+        .find(|character: char| !character.is_ascii_digit())
+        .ok_or_else(|| format!(r#""{argument}" has no unit, e.g. `30d`"#))?;
+    let (count, unit) = argument.split_at(unit_start);
+    let count: u64 = count
+        .parse()
+        .map_err(|_| format!(r#""{argument}" does not start with a number"#))?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!(r#""{unit}" is not `s`, `m`, `h`, `d` or `w`"#)),
+    };
+
+    Ok(Duration::from_secs(count.saturating_mul(unit_seconds)))
+}
+
+// Parses a byte window written as `START..END` (END is exclusive).
+// Refuses files which can not be Signatures before any work starts, see `check_artifact_file`.
+fn parse_signature_filename(argument: &str) -> Result<PathBuf, String> {
+    let filename = PathBuf::from(argument);
+    let kinds = [ArtifactKind::Signature, ArtifactKind::DeduplicatedSignature];
+    commands::check_artifact_file(&filename, &kinds).map_err(|error| format!("{error:#}"))?;
+
+    Ok(filename)
+}
+
+fn parse_delta_filename(argument: &str) -> Result<PathBuf, String> {
+    let filename = PathBuf::from(argument);
+    commands::check_artifact_file(&filename, &[ArtifactKind::Delta])
+        .map_err(|error| format!("{error:#}"))?;
+
+    Ok(filename)
+}
+
+fn parse_chunk_size(argument: &str) -> Result<usize, String> {
+    let chunk_size: usize = argument
+        .parse()
+        .map_err(|_| format!(r#""{argument}" is not a valid chunk size"#))?;
+    check_chunk_size(chunk_size).map_err(|error| error.to_string())?;
+
+    Ok(chunk_size)
+}
+
+fn parse_property(argument: &str) -> Result<(String, String), String> {
+    match argument.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(r#""{argument}" is not in the format KEY=VALUE"#)),
+    }
+}
+
+fn parse_byte_range(argument: &str) -> Result<Range<usize>, String> {
+    let (start, end) = argument
+        .split_once("..")
+        .ok_or_else(|| format!(r#""{argument}" is not in the format START..END"#))?;
+    let start: usize = start
+        .parse()
+        .map_err(|_| format!(r#""{start}" is not a valid byte offset"#))?;
+    let end: usize = end
+        .parse()
+        .map_err(|_| format!(r#""{end}" is not a valid byte offset"#))?;
+    if start > end {
+        return Err(format!(
+            "START ({start}) must not be bigger than END ({end})"
+        ));
+    }
+
+    Ok(start..end)
+}
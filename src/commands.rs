@@ -0,0 +1,2715 @@
+//! The `signature`, `delta` and `patch` commands, exactly as the command line runs them.
+//!
+//! Other programs can drive them without spawning processes: each command reads and writes the
+//! files it is given, and returns what it did instead of printing it.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use bytes::{Buf, Bytes};
+use eyre::{eyre, Context};
+use serde::Serialize;
+
+use crate::allocation::AllocationCounts;
+use crate::domain::annotation::DeltaMetadata;
+use crate::domain::basis_reader::{
+    open_basis_reader, BasisIo, BasisReader, BlockCache, BlockCacheStats, CachedBasis,
+    ReadAheadBasis,
+};
+use crate::domain::block_index::BlockIndex;
+use crate::domain::chunking::{check_chunk_size, ChunkingMode};
+use crate::domain::delta::{
+    compute_delta_to_our_file, compute_delta_with_mode, compute_fixed_delta,
+    compute_sliding_rolling_hashes_in_parallel, stream_delta_with_mode, Delta, DeltaHeader,
+    FileDigest, MatchStrategy, MatchingOptions,
+};
+use crate::domain::encryption::{is_encrypted, ArtifactKeys};
+use crate::domain::format::{artifact_kind, ArtifactKind};
+use crate::domain::inspect::{
+    render_html, render_inline_diff, render_line_diff, summarize_delta, DeltaSummary, DiffStyle,
+};
+use crate::domain::journal::{rewrite_in_place, InPlaceUpdate};
+use crate::domain::merge::{compute_edits, merge_deltas, merge_edits, DeltaMerge};
+use crate::domain::normalization::{
+    normalize_basis_file, normalize_updated_file, TextNormalization,
+};
+use crate::domain::parallel::Parallelism;
+use crate::domain::partition::{apply_delta_to_partition, PartitionUpdate};
+use crate::domain::patch::{
+    apply_delta_from_reader, apply_delta_range, apply_delta_zero_copy, check_basis_file,
+    check_basis_reader, simulate_delta, BytesRope, PatchOutput, PatchSimulation,
+    DEFAULT_MAX_OUTPUT_SIZE,
+};
+use crate::domain::provenance::compute_provenance_map;
+use crate::domain::resign::{resign_from_reader, Resigned};
+use crate::domain::signature::{
+    compute_signature, compute_signature_from_sliding_hashes, compute_signature_in_parallel,
+    compute_signature_with_hashes, format_count, format_size, DeduplicatedSignature, FileSignature,
+};
+use crate::domain::signing::{is_signed, ArtifactSigner};
+use crate::domain::similarity::score_basis_candidates;
+use crate::domain::streaming::DeltaWriter;
+use crate::domain::strong_hash::StrongHash;
+use crate::domain::transform::TransformRegistry;
+use crate::domain::weak_hash::WeakHash;
+use crate::help::Help;
+use crate::timings::{Phase, Timings};
+use crate::{io_utils, progress};
+
+/// How files are divided into blocks. Must be the same for all the commands of a single run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockOptions {
+    /// Size for each block.
+    pub chunk_size: usize,
+    /// How files are divided into blocks.
+    pub mode: ChunkingMode,
+}
+
+impl Default for BlockOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 10,
+            mode: ChunkingMode::Fixed,
+        }
+    }
+}
+
+/// How files are prepared before computing blocks. Must be the same for `signature` and `delta`,
+/// `patch` reads it from the Delta.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preprocessing {
+    /// Convert CRLF line endings to LF before computing blocks.
+    pub normalize_text: bool,
+    /// Also ignore a leading UTF-8 byte order mark.
+    pub strip_bom: bool,
+    /// Transform applied to the file before computing blocks.
+    pub transform: Option<String>,
+}
+
+/// Format of a provenance map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapFormat {
+    Csv,
+    Json,
+}
+
+impl fmt::Display for MapFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFormat::Csv => write!(f, "csv"),
+            MapFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for MapFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(MapFormat::Csv),
+            "json" => Ok(MapFormat::Json),
+            _ => Err(format!(
+                r#""{s}" is not a map format. Expected "csv" or "json""#
+            )),
+        }
+    }
+}
+
+/// Where to save a map of which parts of the updated file are reused from the basis file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvenanceMapOutput {
+    pub filename: PathBuf,
+    pub format: MapFormat,
+}
+
+/// Encryption and signing of the Signatures and Deltas a command writes and reads.
+#[derive(Default)]
+pub struct ArtifactProtection {
+    pub keys: ArtifactKeys,
+    pub signer: ArtifactSigner,
+}
+
+impl ArtifactProtection {
+    /// Encrypts `artifact`, then signs it, so the signature can be verified without the
+    /// encryption keys.
+    pub fn protect(&self, artifact: Bytes) -> eyre::Result<Bytes> {
+        Ok(self.signer.sign(self.keys.encrypt(artifact)?))
+    }
+
+    /// Verifies `artifact`, then decrypts it.
+    pub fn unprotect(&self, artifact: Bytes) -> eyre::Result<Bytes> {
+        self.keys.decrypt(self.signer.verify(artifact)?)
+    }
+}
+
+/// How the `signature` command computes the Signature.
+#[derive(Debug, Clone, Default)]
+pub struct SignatureOptions {
+    pub blocks: BlockOptions,
+    pub preprocessing: Preprocessing,
+    /// Compute the Signature twice, and fail if the results differ.
+    pub verify_deterministic: bool,
+    /// Store identical blocks only once, to save a smaller Signature file.
+    pub dedup: bool,
+    /// Rolling hash `delta` uses to find the blocks, recorded in the Signature.
+    pub weak_hash: WeakHash,
+    /// Hash `delta` uses to confirm the blocks found, recorded in the Signature.
+    pub strong_hash: StrongHash,
+    /// How many threads hash the blocks.
+    pub parallelism: Parallelism,
+}
+
+/// What the `signature` command did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureReport {
+    /// Size of the basis file, once preprocessed.
+    pub basis_size: u64,
+    pub block_count: u64,
+    /// Size of the Signature file written.
+    pub signature_size: u64,
+    /// Time spent in each phase.
+    pub timings: Timings,
+}
+
+/// How the `delta` command computes the Delta.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaOptions {
+    pub blocks: BlockOptions,
+    pub preprocessing: Preprocessing,
+    pub matching: MatchingOptions,
+    /// Compute the Delta twice, and fail if the results differ.
+    pub verify_deterministic: bool,
+    /// Write the Delta while it is being computed, instead of all at once.
+    pub stream: bool,
+    pub provenance_map: Option<ProvenanceMapOutput>,
+    /// Also write the Signature of the updated file here, for the next sync.
+    pub updated_signature: Option<PathBuf>,
+    /// Recorded in the header of the Delta, to trace where it comes from.
+    pub metadata: Option<DeltaMetadata>,
+    /// The rolling hashes of our blocks computed beforehand, in fixed mode.
+    pub block_index: Option<Arc<BlockIndex>>,
+    /// How many threads compute the rolling hashes of our blocks.
+    pub parallelism: Parallelism,
+}
+
+/// What the `delta` command did.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeltaReport {
+    /// Size of the updated file, once preprocessed.
+    pub updated_size: u64,
+    /// Size of the Delta file written.
+    pub delta_size: u64,
+    /// What the Delta holds. Streamed Deltas are not summarized.
+    pub summary: Option<DeltaSummary>,
+    /// Size of the Signature of the updated file written, if requested.
+    pub updated_signature_size: Option<u64>,
+    /// Time spent in each phase.
+    pub timings: Timings,
+}
+
+/// How the `patch` command recreates the updated file.
+#[derive(Debug, Clone)]
+pub struct PatchOptions {
+    pub blocks: BlockOptions,
+    /// Only reconstruct this byte window of the updated file.
+    pub range: Option<Range<usize>>,
+    /// Abort if the recreated file would be larger than this many bytes.
+    pub max_output_size: u64,
+    pub provenance_map: Option<ProvenanceMapOutput>,
+    /// How blocks of the basis file are read.
+    pub basis_io: BasisIo,
+    /// Read the blocks needed by this many bytes of the Delta ahead, in order.
+    pub read_ahead: Option<u64>,
+    /// Blocks of the basis file kept across patches, e.g. by a server patching the same file.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Hold an advisory lock on the recreated file while writing it.
+    pub lock: bool,
+    /// Skip checking the basis file hash recorded in the Delta.
+    pub assume_basis_ok: bool,
+}
+
+impl Default for PatchOptions {
+    fn default() -> Self {
+        Self {
+            blocks: BlockOptions::default(),
+            range: None,
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+            provenance_map: None,
+            basis_io: BasisIo::default(),
+            read_ahead: None,
+            block_cache: None,
+            lock: true,
+            assume_basis_ok: false,
+        }
+    }
+}
+
+/// What the `patch` command did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchReport {
+    /// Size of the basis file, once transformed.
+    pub basis_size: u64,
+    /// Size of the file written.
+    pub recreated_size: u64,
+    /// Blocks of the basis file copied to the recreated file, counting repeated ones.
+    pub blocks_copied: u64,
+    /// Whether the Delta reused blocks on their weak hash alone, from a `--weak-only` Signature.
+    pub unconfirmed: bool,
+    /// Time spent in each phase.
+    pub timings: Timings,
+}
+
+/// What `signature`, `delta` or `patch` read and wrote, printed once they complete like rsync's
+/// end-of-run statistics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub command: &'static str,
+    /// Bytes read: the basis file for `signature` and `patch`, the updated file for `delta`.
+    pub input_size: u64,
+    /// Bytes written: the Signature, the Delta, or the recreated file.
+    pub output_size: u64,
+    /// Blocks hashed, referenced by the Delta, or copied. Streamed Deltas are not counted.
+    pub blocks: Option<u64>,
+    /// Wall time spent in every phase.
+    pub elapsed_seconds: f64,
+    /// How many times smaller the Delta is than the updated file.
+    pub speedup: Option<f64>,
+    /// With the `counting-allocator` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocations: Option<AllocationCounts>,
+}
+
+impl RunSummary {
+    pub fn to_json(&self) -> eyre::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// E.g. "delta: read 1.2 GiB, wrote 3.4 MiB, 1,258,000 blocks in 2.100s, speedup is 361.41", with
+/// ", 2,516,032 allocations, peaking at 1.3 GiB" if they are counted.
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: read {}, wrote {}",
+            self.command,
+            format_size(self.input_size),
+            format_size(self.output_size)
+        )?;
+        if let Some(blocks) = self.blocks {
+            write!(f, ", {} blocks", format_count(blocks))?;
+        }
+        write!(f, " in {:.3}s", self.elapsed_seconds)?;
+        if let Some(speedup) = self.speedup {
+            write!(f, ", speedup is {speedup:.2}")?;
+        }
+        match self.allocations {
+            Some(counts) => write!(
+                f,
+                ", {} allocations, peaking at {}",
+                format_count(counts.allocations),
+                format_size(counts.peak_bytes_in_use)
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SignatureReport {
+    /// The end-of-run statistics, with the `allocations` counted while the command ran, if any.
+    pub fn summary(&self, allocations: Option<AllocationCounts>) -> RunSummary {
+        RunSummary {
+            command: "signature",
+            input_size: self.basis_size,
+            output_size: self.signature_size,
+            blocks: Some(self.block_count),
+            elapsed_seconds: self.timings.total_wall().as_secs_f64(),
+            speedup: None,
+            allocations,
+        }
+    }
+}
+
+impl DeltaReport {
+    /// The end-of-run statistics, with the `allocations` counted while the command ran, if any.
+    pub fn summary(&self, allocations: Option<AllocationCounts>) -> RunSummary {
+        RunSummary {
+            command: "delta",
+            input_size: self.updated_size,
+            output_size: self.delta_size,
+            blocks: self
+                .summary
+                .as_ref()
+                .map(|summary| summary.block_references as u64),
+            elapsed_seconds: self.timings.total_wall().as_secs_f64(),
+            speedup: (self.delta_size > 0)
+                .then(|| self.updated_size as f64 / self.delta_size as f64),
+            allocations,
+        }
+    }
+}
+
+impl PatchReport {
+    /// The end-of-run statistics, with the `allocations` counted while the command ran, if any.
+    pub fn summary(&self, allocations: Option<AllocationCounts>) -> RunSummary {
+        RunSummary {
+            command: "patch",
+            input_size: self.basis_size,
+            output_size: self.recreated_size,
+            blocks: Some(self.blocks_copied),
+            elapsed_seconds: self.timings.total_wall().as_secs_f64(),
+            speedup: None,
+            allocations,
+        }
+    }
+}
+
+/// What `patch_in_place` did.
+pub struct InPlacePatchReport {
+    /// Bytes of the basis file overwritten, and saved in the journal beforehand.
+    pub update: InPlaceUpdate,
+    /// Time spent in each phase.
+    pub timings: Timings,
+}
+
+/// What `bisync` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BisyncReport {
+    /// Regions of the last synced version the left file changed, now in the right file too.
+    pub left_edits: usize,
+    /// Regions of the last synced version the right file changed, now in the left file too.
+    pub right_edits: usize,
+    /// Size of both files, once synced.
+    pub merged_size: u64,
+}
+
+impl fmt::Display for BisyncReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Merged {} changes of the left file and {} of the right file, into {}",
+            self.left_edits,
+            self.right_edits,
+            format_size(self.merged_size)
+        )
+    }
+}
+
+/// Bytes of the basis file a batch of patches keeps in memory, when no BlockCache is given.
+pub const DEFAULT_BATCH_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A Delta to apply in a batch, and where to write the file it recreates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchJob {
+    pub delta_filename: PathBuf,
+    pub recreated_filename: PathBuf,
+}
+
+impl PatchJob {
+    /// Reads a list of jobs, one `DELTA<TAB>RECREATED` per line. Empty lines are skipped.
+    pub fn parse_list(list: &str) -> eyre::Result<Vec<PatchJob>> {
+        list.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| match line.split_once('\t') {
+                Some((delta, recreated)) if !delta.is_empty() && !recreated.is_empty() => {
+                    Ok(PatchJob {
+                        delta_filename: delta.into(),
+                        recreated_filename: recreated.into(),
+                    })
+                }
+                _ => Err(eyre!(r#"Line {} of the batch is "{line}""#, number + 1)).suggestion(
+                    "Write a Delta and the file it recreates on each line, separated by a tab.",
+                ),
+            })
+            .collect()
+    }
+
+    /// Reads the list of jobs saved in `batch_filename`, as `parse_list` does.
+    pub fn read_list(batch_filename: &Path) -> eyre::Result<Vec<PatchJob>> {
+        let list = std::fs::read_to_string(batch_filename).wrap_err(format!(
+            "Unable to read the batch: {}",
+            batch_filename.display()
+        ))?;
+        Self::parse_list(&list)
+    }
+}
+
+/// What a batch of patches did.
+#[derive(Debug)]
+pub struct PatchBatchReport {
+    /// What each job did, in the order they were given.
+    pub patches: Vec<eyre::Result<PatchReport>>,
+    /// How often blocks of the basis file were found in the cache.
+    pub cache: BlockCacheStats,
+}
+
+impl PatchBatchReport {
+    pub fn failures(&self) -> usize {
+        self.patches.iter().filter(|patch| patch.is_err()).count()
+    }
+
+    /// Fails if any of the jobs did, once all of them ran.
+    pub fn check(&self) -> eyre::Result<()> {
+        match self.failures() {
+            0 => Ok(()),
+            failures => Err(eyre!(
+                "{failures} of the {} Deltas could not be applied",
+                self.patches.len()
+            )),
+        }
+    }
+}
+
+/// What `patch --verify-only` found. Nothing is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchVerification {
+    /// The updated file the Delta was computed from.
+    pub expected: FileDigest,
+    /// The file the Delta recreates from the basis file.
+    pub recreated: FileDigest,
+    /// Time spent in each phase.
+    pub timings: Timings,
+}
+
+impl PatchVerification {
+    pub fn matches(&self) -> bool {
+        self.expected == self.recreated
+    }
+}
+
+impl fmt::Display for PatchVerification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Expected updated file: {}", self.expected)?;
+        writeln!(f, "Recreated file: {}", self.recreated)?;
+        if self.matches() {
+            write!(f, "Recreated file matches the updated file.")
+        } else {
+            write!(f, "Recreated file does not match the updated file.")
+        }
+    }
+}
+
+/// How the `flash` command writes the updated image to a partition.
+#[derive(Debug, Clone, Default)]
+pub struct FlashOptions {
+    pub blocks: BlockOptions,
+    /// Length of the current image. Defaults to the one recorded in the Delta, or the partition's.
+    pub basis_size: Option<u64>,
+    /// Bytes the target partition holds. Defaults to its size, or creates an image file this large.
+    pub capacity: Option<u64>,
+}
+
+/// How the `inspect` command shows a Delta.
+#[derive(Debug, Clone)]
+pub struct InspectOptions {
+    /// Basis file the Delta was computed against, to show the changes to it.
+    pub basis_filename: Option<PathBuf>,
+    /// Largest updated file shown in fixed mode, with changes highlighted.
+    pub max_diff_size: usize,
+    pub mode: ChunkingMode,
+    /// Also save a page showing where each part of the updated file comes from.
+    pub html_filename: Option<PathBuf>,
+    /// Size of the blocks, for Deltas which do not record their basis file.
+    pub chunk_size: Option<usize>,
+    /// How the changes are marked in fixed mode.
+    pub diff_style: DiffStyle,
+}
+
+/// What the `inspect` command found in a Delta.
+#[derive(Debug)]
+pub struct DeltaInspection {
+    pub header: DeltaHeader,
+    pub summary: DeltaSummary,
+    /// The changes to the basis file, if it was given.
+    pub diff: Option<String>,
+}
+
+impl fmt::Display for DeltaInspection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(basis) = self.header.basis {
+            writeln!(f, "Computed against {basis}")?;
+        }
+        if let Some(metadata) = &self.header.metadata {
+            writeln!(f, "{metadata}")?;
+        }
+        if self.header.unconfirmed {
+            writeln!(
+                f,
+                "Blocks reused on their rolling hash alone (`--weak-only` Signature)"
+            )?;
+        }
+        write!(f, "{}", self.summary)?;
+        if let Some(diff) = &self.diff {
+            write!(f, "\n\n{diff}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes the Signature of a basis file, and writes it to `signature_filename`.
+///
+/// # Arguments
+/// * `basis_filename` - The basis file to compute the Signature from.
+/// * `signature_filename` - Where to save the Signature file.
+/// * `options` - How the Signature is computed.
+/// * `protection` - How the Signature is encrypted and signed.
+///
+pub fn signature(
+    basis_filename: &Path,
+    signature_filename: &Path,
+    options: &SignatureOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<SignatureReport> {
+    let SignatureOptions {
+        blocks,
+        preprocessing,
+        verify_deterministic,
+        dedup,
+        weak_hash,
+        strong_hash,
+        parallelism,
+    } = options;
+
+    let mut timings = Timings::default();
+
+    let basis_file_bytes = timings.measure(Phase::Read, || {
+        let basis_file_bytes = io_utils::attempt_to_read_file(basis_filename).context(
+            "Error while reading Basis file provided as argument for `signature` command",
+        )?;
+        preprocess_basis_file(basis_file_bytes, preprocessing)
+    })?;
+
+    let compute_signature = || {
+        compute_signature_with_hashes(
+            basis_file_bytes.clone(),
+            blocks.chunk_size,
+            blocks.mode,
+            *weak_hash,
+            *strong_hash,
+            parallelism,
+        )
+    };
+    let (basis, signature_bytes) = if *dedup {
+        let (signature, signature_bytes) = compute_artifact(
+            *verify_deterministic,
+            "Signature",
+            (Phase::Hash, basis_file_bytes.len()),
+            &mut timings,
+            || DeduplicatedSignature::from(&compute_signature()),
+        )?;
+        (signature.basis, signature_bytes)
+    } else {
+        let (signature, signature_bytes) = compute_artifact(
+            *verify_deterministic,
+            "Signature",
+            (Phase::Hash, basis_file_bytes.len()),
+            &mut timings,
+            compute_signature,
+        )?;
+        (signature.basis, signature_bytes)
+    };
+    let signature_bytes = timings
+        .measure(Phase::Serialize, || protection.protect(signature_bytes))
+        .context("Error while encrypting Signature")?;
+    let signature_size = signature_bytes.len() as u64;
+    timings
+        .measure(Phase::Write, || {
+            io_utils::write_to_file(signature_filename, signature_bytes)
+        })
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            signature_filename.display()
+        ))?;
+
+    Ok(SignatureReport {
+        basis_size: basis_file_bytes.len() as u64,
+        block_count: basis.map_or(0, |basis| basis.block_count),
+        signature_size,
+        timings,
+    })
+}
+
+/// Recomputes a Signature with another chunk size, and writes it to `signature_filename`.
+///
+/// The basis file is read once, and checked against the old Signature on the way.
+///
+/// # Arguments
+/// * `old_signature_filename` - Signature file computed by `signature`.
+/// * `basis_filename` - The basis file the old Signature was computed from.
+/// * `signature_filename` - Where to save the new Signature file. May be the old one.
+/// * `chunk_size` - The size for each block of the new Signature.
+/// * `protection` - How the old Signature is verified and decrypted, and the new one encrypted
+///   and signed.
+///
+pub fn resign(
+    old_signature_filename: &Path,
+    basis_filename: &Path,
+    signature_filename: &Path,
+    chunk_size: usize,
+    protection: &ArtifactProtection,
+) -> eyre::Result<Resigned> {
+    let old_signature = read_signature(old_signature_filename, "resign", protection)?;
+    let basis_file = File::open(basis_filename)
+        .wrap_err(format!(r#"Path provided: "{}""#, basis_filename.display()))
+        .context("Error while reading Basis file provided as argument for `resign` command")?;
+
+    let resigned = resign_from_reader(&old_signature, basis_file, chunk_size)?;
+    let signature_bytes = protection
+        .protect(Bytes::try_from(resigned.signature.clone())?)
+        .context("Error while encrypting Signature")?;
+    io_utils::write_to_file(signature_filename, signature_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        signature_filename.display()
+    ))?;
+
+    Ok(resigned)
+}
+
+/// Merges two Deltas computed against the same Signature, and writes the merged Delta to
+/// `merged_filename`. Fails, listing the conflicts, if they replace the same blocks differently.
+///
+/// # Arguments
+/// * `left_filename` - Delta file computed by `delta`.
+/// * `right_filename` - Another Delta file computed by `delta`, against the same Signature.
+/// * `merged_filename` - Where to save the merged Delta file. Nothing is written on conflicts.
+/// * `protection` - How the Deltas are verified and decrypted, and the merged one encrypted and
+///   signed.
+///
+pub fn merge_delta_files(
+    left_filename: &Path,
+    right_filename: &Path,
+    merged_filename: &Path,
+    protection: &ArtifactProtection,
+) -> eyre::Result<Delta> {
+    let left = read_delta(left_filename, "merge-deltas", protection)?;
+    let right = read_delta(right_filename, "merge-deltas", protection)?;
+
+    let merged = match merge_deltas(&left, &right)? {
+        DeltaMerge::Merged(merged) => *merged,
+        DeltaMerge::Conflicts(conflicts) => {
+            let conflicts: String = conflicts
+                .iter()
+                .map(|conflict| format!("\nConflict: {conflict}"))
+                .collect();
+            return Err(eyre!(
+                "Deltas replace the same blocks differently:{conflicts}"
+            ))
+            .suggestion(
+                "Nothing was written. Compute a single Delta from the files, merged by hand.",
+            );
+        }
+    };
+    let delta_bytes = protection
+        .protect(Bytes::try_from(merged.clone())?)
+        .context("Error while encrypting Delta")?;
+    io_utils::write_to_file(merged_filename, delta_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        merged_filename.display()
+    ))?;
+
+    Ok(merged)
+}
+
+/// Describes a Delta, and shows the changes it makes to the basis file when given one.
+///
+/// # Arguments
+/// * `delta_filename` - Delta file computed by `delta`.
+/// * `options` - What is shown, and how.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn inspect(
+    delta_filename: &Path,
+    options: &InspectOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<DeltaInspection> {
+    let InspectOptions {
+        basis_filename,
+        max_diff_size,
+        mode,
+        html_filename,
+        chunk_size,
+        diff_style,
+    } = options;
+    let delta = read_delta(delta_filename, "inspect", protection)?;
+    let delta_chunk_size = || {
+        chunk_size
+            .or_else(|| {
+                let basis = delta.header.basis?;
+                basis.chunk_size().map(|chunk_size| chunk_size as usize)
+            })
+            .ok_or_else(|| eyre!("The Delta does not record the size of its blocks"))
+            .suggestion("Give the chunk size the Delta was computed with, with `--chunk-size`.")
+    };
+
+    if let Some(html_filename) = html_filename {
+        if *mode != ChunkingMode::Fixed {
+            return Err(eyre!("--html is only supported in fixed mode"));
+        }
+        let chunk_size = delta_chunk_size()?;
+        let title = format!("Delta {}", delta_filename.display());
+        io_utils::write_to_file(
+            html_filename,
+            render_html(&title, &delta, chunk_size).into(),
+        )
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            html_filename.display()
+        ))?;
+    }
+
+    let diff = match basis_filename {
+        Some(basis_filename) => {
+            let basis_file_bytes = io_utils::attempt_to_read_file(basis_filename).context(
+                "Error while reading Basis file provided as argument to `inspect` command",
+            )?;
+            if *mode == ChunkingMode::Lines {
+                let diff = render_line_diff(&basis_file_bytes, &delta);
+                Some(diff.strip_suffix('\n').unwrap_or(&diff).to_owned())
+            } else {
+                let updated_size = delta.header.updated.map_or(0, |updated| updated.length);
+                if updated_size > *max_diff_size as u64 {
+                    return Err(eyre!(
+                        "The updated file is {updated_size} bytes, too large to show inline"
+                    ))
+                    .suggestion(
+                        "Raise `--max-diff-size`, or compute the Delta with `--mode lines`.",
+                    );
+                }
+                let diff =
+                    render_inline_diff(&basis_file_bytes, &delta, delta_chunk_size()?, *diff_style)
+                        .ok_or_else(|| eyre!("Only text files can be shown inline"))
+                        .suggestion(
+                            "Did you provide the basis file the Delta was computed against?",
+                        )?;
+                Some(diff)
+            }
+        }
+        None => None,
+    };
+
+    Ok(DeltaInspection {
+        summary: summarize_delta(&delta),
+        header: delta.header,
+        diff,
+    })
+}
+
+/// Computes the Delta of an updated file against a Signature, and writes it to `delta_filename`.
+///
+/// # Arguments
+/// * `signature_filename` - Signature file computed by `signature`.
+/// * `updated_filename` - File to compute the Delta from.
+/// * `delta_filename` - Where to save the Delta file.
+/// * `options` - How the Delta is computed.
+/// * `protection` - How the Signature is verified and decrypted, and the Delta encrypted and signed.
+///
+pub fn delta(
+    signature_filename: &Path,
+    updated_filename: &Path,
+    delta_filename: &Path,
+    options: &DeltaOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<DeltaReport> {
+    check_delta_options(options, protection)?;
+    let mut timings = Timings::default();
+
+    let signature: FileSignature = read_artifact(
+        signature_filename,
+        "Signature",
+        "delta",
+        protection,
+        &mut timings,
+    )?;
+    check_signature_chunk_size(&signature, &options.blocks)?;
+    let updated_file = read_updated_file(updated_filename, &options.preprocessing, &mut timings)?;
+
+    write_delta(
+        signature,
+        updated_file,
+        delta_filename,
+        options,
+        protection,
+        timings,
+    )
+}
+
+/// A candidate basis file, and how much of the updated file it holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasisCandidate {
+    pub filename: PathBuf,
+    /// Fraction of the updated file found in the candidate, between 0 and 1.
+    pub score: f64,
+}
+
+/// What `delta_against_best_basis` did.
+#[derive(Debug, PartialEq)]
+pub struct BasisSelectionReport {
+    /// Every candidate, sorted by filename.
+    pub candidates: Vec<BasisCandidate>,
+    /// Index of the candidate the Delta was computed against.
+    pub chosen: usize,
+    /// What computing the Delta did.
+    pub delta: DeltaReport,
+}
+
+impl BasisSelectionReport {
+    pub fn chosen_candidate(&self) -> &BasisCandidate {
+        &self.candidates[self.chosen]
+    }
+}
+
+/// Picks the candidate basis file closest to the updated file, and writes a Delta against it.
+///
+/// The receiver must patch the chosen candidate, so it is reported. Candidates are scored with
+/// coarse blocks first, and only the chosen one has its Signature computed.
+///
+/// # Arguments
+/// * `candidates_directory` - Directory holding the candidate basis files, e.g. previous releases.
+/// * `updated_filename` - File to compute the Delta of.
+/// * `delta_filename` - Where to save the Delta.
+/// * `options` - How the Delta is computed.
+/// * `protection` - How the Delta is encrypted and signed.
+///
+pub fn delta_against_best_basis(
+    candidates_directory: &Path,
+    updated_filename: &Path,
+    delta_filename: &Path,
+    options: &DeltaOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<BasisSelectionReport> {
+    check_delta_options(options, protection)?;
+    let mut timings = Timings::default();
+
+    let filenames = list_candidates(candidates_directory)?;
+    let candidates = timings.measure(Phase::Read, || {
+        filenames
+            .iter()
+            .map(|filename| {
+                let candidate = io_utils::attempt_to_read_file(filename).wrap_err(format!(
+                    "Error while reading candidate Basis file: {}",
+                    filename.display()
+                ))?;
+                preprocess_basis_file(candidate, &options.preprocessing)
+            })
+            .collect::<eyre::Result<Vec<_>>>()
+    })?;
+    let updated_file = read_updated_file(updated_filename, &options.preprocessing, &mut timings)?;
+
+    let scores = timings.measure(Phase::Match, || {
+        score_basis_candidates(
+            &candidates,
+            &updated_file.bytes,
+            options.blocks.chunk_size,
+            &options.parallelism,
+        )
+    });
+    // On equal scores, the first candidate by filename is chosen, so the choice is reproducible.
+    let chosen =
+        scores.iter().enumerate().fold(
+            0,
+            |best, (index, score)| if *score > scores[best] { index } else { best },
+        );
+
+    let signature = timings.measure(Phase::Hash, || {
+        compute_signature_in_parallel(
+            candidates[chosen].clone(),
+            options.blocks.chunk_size,
+            options.blocks.mode,
+            &options.parallelism,
+        )
+    });
+    // Unlike `delta`, the basis file is at hand, so annotated Deltas record it.
+    let mut options = options.clone();
+    if let Some(metadata) = &mut options.metadata {
+        metadata.basis = Some(FileDigest::of(&candidates[chosen]));
+    }
+    let delta = write_delta(
+        signature,
+        updated_file,
+        delta_filename,
+        &options,
+        protection,
+        timings,
+    )?;
+
+    Ok(BasisSelectionReport {
+        candidates: filenames
+            .into_iter()
+            .zip(scores)
+            .map(|(filename, score)| BasisCandidate { filename, score })
+            .collect(),
+        chosen,
+        delta,
+    })
+}
+
+// Regular files directly inside `directory`, sorted by filename.
+fn list_candidates(directory: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let mut filenames = Vec::new();
+    let entries = std::fs::read_dir(directory).wrap_err(format!(
+        "Unable to read directory of candidate Basis files: {}",
+        directory.display()
+    ))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            filenames.push(entry.path());
+        }
+    }
+    filenames.sort();
+
+    if filenames.is_empty() {
+        return Err(eyre!(
+            "No candidate Basis file in directory: {}",
+            directory.display()
+        ));
+    }
+    Ok(filenames)
+}
+
+fn check_delta_options(
+    options: &DeltaOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<()> {
+    if options.provenance_map.is_some() {
+        ensure_fixed_mode(options.blocks.mode, "--provenance-map")?;
+    }
+    if options.block_index.is_some() {
+        ensure_fixed_mode(options.blocks.mode, "--block-index")?;
+        if options.stream {
+            return Err(eyre!("A streamed Delta rolls the updated file as it goes"))
+                .suggestion("Do not use --block-index with --stream.");
+        }
+    }
+    let protects = protection.keys.encrypts() || protection.signer.signing_key.is_some();
+    let checks = options.verify_deterministic || options.provenance_map.is_some() || protects;
+    if options.stream && checks {
+        return Err(eyre!(
+            "A streamed Delta cannot be verified, mapped, encrypted or signed"
+        ));
+    }
+
+    Ok(())
+}
+
+// A Delta computed with another chunk size than its Signature matches nothing, or the wrong blocks.
+fn check_signature_chunk_size(
+    signature: &FileSignature,
+    blocks: &BlockOptions,
+) -> eyre::Result<()> {
+    if blocks.mode != ChunkingMode::Fixed {
+        return Ok(());
+    }
+    let Some(layout) = signature.basis else {
+        return Ok(());
+    };
+    match layout.chunk_size() {
+        Some(chunk_size) if chunk_size != blocks.chunk_size as u64 => Err(eyre!(
+            "Signature describes {layout}, but `--chunk-size` is {}",
+            blocks.chunk_size
+        ))
+        .suggestion("Use the same `--chunk-size` used to compute the Signature."),
+        _ => Ok(()),
+    }
+}
+
+// A Block Index is only used for the very file it indexes, with the blocks of the Signature.
+fn check_block_index(
+    index: &BlockIndex,
+    signature: &FileSignature,
+    updated_file: &[u8],
+    blocks: &BlockOptions,
+) -> eyre::Result<()> {
+    if index.chunk_size != blocks.chunk_size {
+        return Err(eyre!(
+            "Block Index has blocks of {} bytes, but `--chunk-size` is {}",
+            index.chunk_size,
+            blocks.chunk_size
+        ))
+        .suggestion("Use the same `--chunk-size` used to compute the Block Index.");
+    }
+    index.check_signature(signature)?;
+    if !index.indexes(updated_file) {
+        return Err(eyre!("Block Index was computed from another updated file")).suggestion(
+            "Compute it again with `index` from the updated file, without preprocessing it.",
+        );
+    }
+
+    Ok(())
+}
+
+// The updated file once read and preprocessed.
+struct UpdatedFile {
+    /// Content once preprocessed.
+    bytes: Bytes,
+    normalization: Option<TextNormalization>,
+    /// Recorded before preprocessing, as `patch` recreates the file as it was read.
+    digest: FileDigest,
+}
+
+fn read_updated_file(
+    updated_filename: &Path,
+    preprocessing: &Preprocessing,
+    timings: &mut Timings,
+) -> eyre::Result<UpdatedFile> {
+    let updated_file_bytes = timings.measure(Phase::Read, || {
+        io_utils::attempt_to_read_file(updated_filename)
+            .context("Error while reading Updated file provided as argument to `delta` command")
+    })?;
+    let digest = timings.measure(Phase::Hash, || FileDigest::of(&updated_file_bytes));
+    let (bytes, normalization) = timings.measure(Phase::Read, || {
+        preprocess_updated_file(updated_file_bytes, preprocessing)
+    })?;
+
+    Ok(UpdatedFile {
+        bytes,
+        normalization,
+        digest,
+    })
+}
+
+// Computes the Delta of the updated file against the Signature, and writes it.
+fn write_delta(
+    signature: FileSignature,
+    updated_file: UpdatedFile,
+    delta_filename: &Path,
+    options: &DeltaOptions,
+    protection: &ArtifactProtection,
+    mut timings: Timings,
+) -> eyre::Result<DeltaReport> {
+    let DeltaOptions {
+        blocks,
+        preprocessing,
+        matching,
+        verify_deterministic,
+        stream,
+        provenance_map,
+        updated_signature,
+        metadata,
+        block_index,
+        parallelism,
+    } = options;
+    let UpdatedFile {
+        bytes: updated_file_bytes,
+        normalization,
+        digest: updated,
+    } = updated_file;
+    let updated_size = updated_file_bytes.len() as u64;
+
+    if *stream {
+        let header = DeltaHeader {
+            normalization,
+            transform: preprocessing.transform.clone(),
+            basis: signature.basis,
+            updated: Some(updated),
+            metadata: metadata.clone(),
+            unconfirmed: !signature.strong_hash.confirms_blocks(),
+            output_length: Some(updated_size),
+        };
+        let delta_size = timings.measure(Phase::Match, || {
+            stream_delta_to_file(
+                &signature,
+                &updated_file_bytes,
+                blocks,
+                matching,
+                &header,
+                delta_filename,
+            )
+        })?;
+        let updated_signature_size = match updated_signature {
+            Some(filename) => {
+                let signature = timings.measure(Phase::Hash, || {
+                    compute_signature_with_hashes(
+                        updated_file_bytes.clone(),
+                        blocks.chunk_size,
+                        blocks.mode,
+                        signature.weak_hash,
+                        signature.strong_hash,
+                        parallelism,
+                    )
+                });
+                Some(write_signature(
+                    signature,
+                    filename,
+                    protection,
+                    &mut timings,
+                )?)
+            }
+            None => None,
+        };
+        return Ok(DeltaReport {
+            updated_size,
+            delta_size,
+            summary: None,
+            updated_signature_size,
+            timings,
+        });
+    }
+
+    // Lines are hashed while they are matched.
+    let updated_size = updated_file_bytes.len() as u64;
+    let our_rolling_hashes = match block_index {
+        Some(index) => {
+            check_block_index(index, &signature, &updated_file_bytes, blocks)?;
+            Cow::Borrowed(index.rolling_hashes())
+        }
+        None => {
+            Cow::Owned(
+                timings.measure_bytes(Phase::Hash, updated_size, || match blocks.mode {
+                    ChunkingMode::Fixed => compute_sliding_rolling_hashes_in_parallel(
+                        &updated_file_bytes,
+                        blocks.chunk_size,
+                        signature.weak_hash,
+                        parallelism,
+                    ),
+                    ChunkingMode::Lines => Vec::new(),
+                }),
+            )
+        }
+    };
+    let (delta, delta_bytes) = compute_artifact(
+        *verify_deterministic,
+        "Delta",
+        (Phase::Match, updated_file_bytes.len()),
+        &mut timings,
+        || {
+            let mut delta = match blocks.mode {
+                ChunkingMode::Fixed => compute_fixed_delta(
+                    &signature,
+                    &updated_file_bytes,
+                    blocks.chunk_size,
+                    &our_rolling_hashes,
+                    matching,
+                ),
+                ChunkingMode::Lines => compute_delta_with_mode(
+                    signature.clone(),
+                    updated_file_bytes.clone(),
+                    blocks.chunk_size,
+                    blocks.mode,
+                    matching,
+                ),
+            };
+            delta.header.normalization = normalization;
+            delta.header.transform = preprocessing.transform.clone();
+            delta.header.updated = Some(updated);
+            delta.header.metadata = metadata.clone();
+            delta.optimize_against(&signature);
+            // Strategies minding the size of the Delta check their estimates against the tokens.
+            if matching.strategy != MatchStrategy::Greedy && blocks.mode == ChunkingMode::Fixed {
+                delta.drop_costly_references(&updated_file_bytes, blocks.chunk_size);
+            }
+            delta
+        },
+    )?;
+    // The basis file is not available here, but matched blocks always have `chunk_size` bytes.
+    if let Some(output) = provenance_map {
+        timings.measure(Phase::Write, || {
+            write_provenance_map(output, &delta, blocks.chunk_size, None)
+        })?;
+    }
+    let delta_bytes = timings
+        .measure(Phase::Serialize, || protection.protect(delta_bytes))
+        .context("Error while encrypting Delta")?;
+    let delta_size = delta_bytes.len() as u64;
+    timings
+        .measure(Phase::Write, || {
+            io_utils::write_to_file(delta_filename, delta_bytes)
+        })
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            delta_filename.display()
+        ))?;
+    // Our blocks were hashed to be matched, the same hashes make our Signature.
+    let updated_signature_size = match updated_signature {
+        Some(filename) => {
+            let signature = timings.measure(Phase::Hash, || match blocks.mode {
+                ChunkingMode::Fixed => compute_signature_from_sliding_hashes(
+                    &updated_file_bytes,
+                    blocks.chunk_size,
+                    &our_rolling_hashes,
+                    signature.weak_hash,
+                    signature.strong_hash,
+                    parallelism,
+                ),
+                ChunkingMode::Lines => compute_signature_with_hashes(
+                    updated_file_bytes.clone(),
+                    blocks.chunk_size,
+                    blocks.mode,
+                    signature.weak_hash,
+                    signature.strong_hash,
+                    parallelism,
+                ),
+            });
+            Some(write_signature(
+                signature,
+                filename,
+                protection,
+                &mut timings,
+            )?)
+        }
+        None => None,
+    };
+
+    Ok(DeltaReport {
+        updated_size,
+        delta_size,
+        summary: Some(summarize_delta(&delta)),
+        updated_signature_size,
+        timings,
+    })
+}
+
+// Writes the Delta while it is being computed, and returns how many bytes were written.
+fn stream_delta_to_file(
+    signature: &FileSignature,
+    updated_file_bytes: &Bytes,
+    blocks: &BlockOptions,
+    options: &MatchingOptions,
+    header: &DeltaHeader,
+    delta_filename: &Path,
+) -> eyre::Result<u64> {
+    let write_delta = || -> eyre::Result<u64> {
+        let file = BufWriter::new(File::create(delta_filename)?);
+        let mut writer = DeltaWriter::new(file, header)?;
+        stream_delta_with_mode(
+            signature,
+            updated_file_bytes,
+            blocks.chunk_size,
+            blocks.mode,
+            options,
+            &mut writer,
+        )?;
+        let file = writer.finish()?.into_inner()?;
+        Ok(file.metadata()?.len())
+    };
+
+    write_delta().wrap_err(format!(
+        "Unable to write to file: {}",
+        delta_filename.display()
+    ))
+}
+
+/// Applies a Delta to a basis file, and writes the recreated file to `recreated_filename`.
+///
+/// # Arguments
+/// * `basis_filename` - File to apply the changes to.
+/// * `delta_filename` - Delta file computed by `delta`, or `-` to read it from the standard input.
+/// * `recreated_filename` - Where to save the updated file, or `-` for the standard output.
+/// * `options` - How the file is recreated.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn patch(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    recreated_filename: &Path,
+    options: &PatchOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PatchReport> {
+    if options.read_ahead.is_some() && options.basis_io == BasisIo::Memory {
+        return Err(eyre!(
+            "--read-ahead needs a `--basis-io` reading blocks from the disk"
+        ))
+        .suggestion("Use `--basis-io positioned`.");
+    }
+    if options.block_cache.is_some() && options.basis_io == BasisIo::Memory {
+        return Err(eyre!(
+            "A block cache needs a `basis_io` reading blocks from the disk"
+        ));
+    }
+    if options.basis_io != BasisIo::Memory {
+        return patch_from_reader(
+            basis_filename,
+            delta_filename,
+            recreated_filename,
+            options,
+            protection,
+        );
+    }
+    let mut timings = Timings::default();
+
+    let RecreatedFile {
+        recreated,
+        expected,
+        basis_size,
+        blocks_copied,
+        unconfirmed,
+    } = recreate_file(
+        basis_filename,
+        delta_filename,
+        options,
+        protection,
+        &mut timings,
+    )?;
+    // A damaged Delta may still be applied, but not recreate the file it was computed from.
+    if let (Some(expected), None) = (expected, &options.range) {
+        let recreated = timings.measure(Phase::Hash, || recreated.digest());
+        expected.check_recreated(recreated)?;
+    }
+    let recreated_size = recreated.remaining() as u64;
+
+    timings
+        .measure(Phase::Write, || {
+            io_utils::write_buf_to_file_or_stdout(recreated_filename, recreated, options.lock)
+        })
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            recreated_filename.display()
+        ))?;
+
+    Ok(PatchReport {
+        basis_size,
+        recreated_size,
+        blocks_copied,
+        unconfirmed,
+        timings,
+    })
+}
+
+/// Applies a Delta over the basis file itself, journaling what is overwritten.
+///
+/// The recreated file is checked against the Delta before the basis file is touched. If the
+/// update is interrupted, `journal::recover_from_journal` rolls the basis file back.
+pub fn patch_in_place(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    journal_filename: &Path,
+    options: &PatchOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<InPlacePatchReport> {
+    if options.range.is_some() || options.basis_io != BasisIo::Memory {
+        return Err(eyre!(
+            "--inplace recreates the whole file in memory, without --range or --basis-io"
+        ));
+    }
+    io_utils::ensure_regular_file(basis_filename)?;
+    let mut timings = Timings::default();
+
+    let RecreatedFile {
+        mut recreated,
+        expected,
+        ..
+    } = recreate_file(
+        basis_filename,
+        delta_filename,
+        options,
+        protection,
+        &mut timings,
+    )?;
+    if let Some(expected) = expected {
+        let recreated = timings.measure(Phase::Hash, || recreated.digest());
+        expected.check_recreated(recreated)?;
+    }
+    let recreated = recreated.copy_to_bytes(recreated.remaining());
+
+    let update = timings
+        .measure(Phase::Write, || {
+            rewrite_in_place(basis_filename, &recreated, journal_filename)
+        })
+        .wrap_err(format!(
+            "Unable to update file in place: {}",
+            basis_filename.display()
+        ))?;
+
+    Ok(InPlacePatchReport { update, timings })
+}
+
+/// Writes the image a Delta recreates from the basis partition to the target partition, in place.
+///
+/// Both may be block devices or image files. The target partition is never truncated, and is only
+/// created as an image file when its capacity is given.
+///
+/// # Arguments
+/// * `basis_partition` - Partition (or image file) holding the current image.
+/// * `delta_filename` - Delta file computed by `delta` against the current image.
+/// * `target_partition` - Partition (or image file) to write the updated image to.
+/// * `options` - How large both partitions are.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn flash(
+    basis_partition: &Path,
+    delta_filename: &Path,
+    target_partition: &Path,
+    options: &FlashOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PartitionUpdate> {
+    let FlashOptions {
+        blocks,
+        basis_size,
+        capacity,
+    } = options;
+    ensure_fixed_mode(blocks.mode, "Flashing")?;
+    let delta = read_delta(delta_filename, "flash", protection)?;
+
+    let mut basis = File::open(basis_partition).wrap_err(format!(
+        "Unable to open the basis partition: {}",
+        basis_partition.display()
+    ))?;
+    let basis_size = match basis_size.or(delta.header.basis.map(|basis| basis.length)) {
+        Some(basis_size) => basis_size,
+        None => partition_size(&mut basis)?,
+    };
+    // Only an image file may be created, and only if told how large the partition is.
+    let mut target = OpenOptions::new()
+        .write(true)
+        .create(capacity.is_some())
+        .open(target_partition)
+        .wrap_err(format!(
+            "Unable to open the target partition: {}",
+            target_partition.display()
+        ))
+        .suggestion("Pass its size with --capacity to create an image file.")?;
+    let capacity = match capacity {
+        Some(capacity) => *capacity,
+        None => partition_size(&mut target)?,
+    };
+
+    let expected_size = delta.header.updated.map_or(0, |updated| updated.length);
+    progress::begin(Phase::Apply, expected_size);
+    let mut reported = 0;
+    let update = apply_delta_to_partition(
+        basis,
+        basis_size,
+        &delta,
+        blocks.chunk_size,
+        &mut target,
+        capacity,
+        |written, _| {
+            progress::advance(written - reported);
+            reported = written;
+        },
+    );
+    progress::end();
+    let update = update?;
+    target
+        .sync_all()
+        .wrap_err("Unable to flush the updated image to the target partition")?;
+
+    Ok(update)
+}
+
+// Block devices report no length in their metadata, but can be seeked to their end.
+fn partition_size(partition: &mut File) -> eyre::Result<u64> {
+    let size = partition
+        .seek(SeekFrom::End(0))
+        .wrap_err("Unable to find the size of a partition")?;
+    partition.rewind()?;
+    Ok(size)
+}
+
+/// Where `bisync` records the version `left_filename` was last synced at, unless told otherwise.
+pub fn default_bisync_state_filename(left_filename: &Path) -> PathBuf {
+    let mut state_filename = left_filename.as_os_str().to_owned();
+    state_filename.push(".rsync-state");
+    PathBuf::from(state_filename)
+}
+
+/// Syncs two copies of a file both ways, when either of them, or both, may have changed.
+///
+/// The Signature of the version they were last synced at is kept in `state_filename`. The Delta
+/// of each file against it tells which regions of that version the file changed: when the regions
+/// do not overlap, both files are rewritten with the changes of both, otherwise neither is, and
+/// the conflicting regions are reported. The first sync only records the state, and needs both
+/// files to be equal.
+///
+/// # Arguments
+/// * `left_filename` - One copy of the file.
+/// * `right_filename` - The other copy of the file.
+/// * `state_filename` - Where the Signature of the last synced version is kept.
+/// * `blocks` - How files are divided into blocks. Only fixed blocks are supported.
+///
+pub fn bisync(
+    left_filename: &Path,
+    right_filename: &Path,
+    state_filename: &Path,
+    blocks: &BlockOptions,
+) -> eyre::Result<BisyncReport> {
+    ensure_fixed_mode(blocks.mode, "bisync")?;
+    check_chunk_size(blocks.chunk_size)?;
+    let read_file = |filename: &Path| {
+        io_utils::attempt_to_read_file(filename).wrap_err(format!(
+            "Error while reading file provided as argument to `bisync` command: {}",
+            filename.display()
+        ))
+    };
+    let left = read_file(left_filename)?;
+    let right = read_file(right_filename)?;
+
+    if !state_filename.exists() {
+        if left != right {
+            return Err(eyre!("Files differ, and were never synced")).suggestion(
+                "Copy one over the other first (e.g. with `push`), then `bisync` records the state.",
+            );
+        }
+        write_bisync_state(state_filename, &left, blocks.chunk_size)?;
+        return Ok(BisyncReport {
+            left_edits: 0,
+            right_edits: 0,
+            merged_size: left.len() as u64,
+        });
+    }
+
+    let state = read_signature(state_filename, "bisync", &ArtifactProtection::default())?;
+    let basis = state
+        .basis
+        .ok_or_else(|| eyre!("The state does not record the file it was computed from"))?;
+    if let Some(chunk_size) = basis.chunk_size() {
+        if chunk_size != blocks.chunk_size as u64 {
+            return Err(eyre!(
+                "The state was recorded with a chunk size of {chunk_size}, not {}",
+                blocks.chunk_size
+            ))
+            .suggestion(format!("Use `--chunk-size {chunk_size}`."));
+        }
+    }
+    let compute_file_edits = |file: &Bytes| {
+        let delta = compute_delta_to_our_file(state.clone(), file.clone(), blocks.chunk_size);
+        compute_edits(&delta, file, blocks.chunk_size, basis.length as usize)
+    };
+    let left_edits = compute_file_edits(&left);
+    let right_edits = compute_file_edits(&right);
+
+    let merged = merge_edits(&left, &left_edits, &right_edits)
+        .map_err(|conflicts| {
+            let conflicts: Vec<_> = conflicts.iter().map(ToString::to_string).collect();
+            eyre!(
+                "Both files changed the same regions since they were last synced: {}",
+                conflicts.join("; ")
+            )
+        })
+        .suggestion(
+            "Neither file was changed. Merge them by hand, or `push` one over the other.",
+        )?;
+    let merged = Bytes::from(merged);
+    for (filename, file) in [(left_filename, &left), (right_filename, &right)] {
+        if *file != merged {
+            io_utils::write_to_file(filename, merged.clone())
+                .wrap_err(format!("Unable to write to file: {}", filename.display()))?;
+        }
+    }
+    write_bisync_state(state_filename, &merged, blocks.chunk_size)?;
+
+    Ok(BisyncReport {
+        left_edits: left_edits.len(),
+        right_edits: right_edits.len(),
+        merged_size: merged.len() as u64,
+    })
+}
+
+// Records `file` as the version both copies were last synced at.
+fn write_bisync_state(state_filename: &Path, file: &Bytes, chunk_size: usize) -> eyre::Result<()> {
+    let signature = Bytes::try_from(compute_signature(file.clone(), chunk_size))?;
+    io_utils::write_to_file(state_filename, signature).wrap_err(format!(
+        "Unable to write to file: {}",
+        state_filename.display()
+    ))
+}
+
+// Only reads the blocks the Delta references, and writes the recreated file as it goes.
+fn patch_from_reader(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    recreated_filename: &Path,
+    options: &PatchOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PatchReport> {
+    check_reader_options(options)?;
+    let mut timings = Timings::default();
+
+    let delta: Delta = read_artifact(delta_filename, "Delta", "patch", protection, &mut timings)?;
+    patch_delta_from_reader(basis_filename, &delta, recreated_filename, options, timings)
+}
+
+fn check_reader_options(options: &PatchOptions) -> eyre::Result<()> {
+    ensure_fixed_mode(options.blocks.mode, "--basis-io")?;
+    if options.range.is_some() {
+        return Err(eyre!("--range is only supported with `--basis-io memory`"));
+    }
+    Ok(())
+}
+
+// Applies a Delta which was already read, reading the blocks of the basis file it references.
+fn patch_delta_from_reader(
+    basis_filename: &Path,
+    delta: &Delta,
+    recreated_filename: &Path,
+    options: &PatchOptions,
+    mut timings: Timings,
+) -> eyre::Result<PatchReport> {
+    let PatchOptions {
+        blocks,
+        max_output_size,
+        provenance_map,
+        basis_io,
+        read_ahead,
+        block_cache,
+        lock,
+        assume_basis_ok,
+        ..
+    } = options;
+    let mut basis = open_basis_reader(basis_filename, *basis_io)
+        .context("Error while opening Basis file provided as argument to `patch` command")?;
+    if !assume_basis_ok {
+        timings
+            .measure(Phase::Hash, || check_basis_reader(delta, basis.as_mut()))
+            .suggestion(ASSUME_BASIS_OK)?;
+    }
+    if let Some(cache_size) = read_ahead {
+        basis = Box::new(ReadAheadBasis::new(basis, *cache_size));
+    }
+    if let Some(cache) = block_cache {
+        basis = Box::new(CachedBasis::new(basis, cache.clone()));
+    }
+    if let Some(output) = provenance_map {
+        timings.measure(Phase::Write, || {
+            write_provenance_map(output, delta, blocks.chunk_size, Some(basis.len() as usize))
+        })?;
+    }
+
+    let basis_size = basis.len();
+    let blocks_copied = count_blocks_copied(delta);
+
+    // Reading the basis file and writing the recreated file are interleaved.
+    let expected_size = delta.header.updated.map_or(0, |updated| updated.length);
+    let recreated_size = timings.measure_bytes(Phase::Apply, expected_size, || {
+        let chunk_size = blocks.chunk_size;
+        if io_utils::is_stdio(recreated_filename) {
+            let output = BufWriter::new(std::io::stdout().lock());
+            return apply_to_output(basis.as_mut(), delta, chunk_size, *max_output_size, output);
+        }
+        let output = match lock {
+            true => io_utils::create_locked_file(recreated_filename),
+            false => File::create(recreated_filename).map_err(eyre::Report::from),
+        };
+        let output = BufWriter::new(output.wrap_err(format!(
+            "Unable to write to file: {}",
+            recreated_filename.display()
+        ))?);
+        apply_to_output(basis.as_mut(), delta, chunk_size, *max_output_size, output)
+    })?;
+
+    Ok(PatchReport {
+        basis_size,
+        recreated_size,
+        blocks_copied,
+        unconfirmed: delta.header.unconfirmed,
+        timings,
+    })
+}
+
+// Applies the Delta, writing the recreated file to `output` as it goes. It is checked against the
+// one the Delta was computed from while it is written.
+fn apply_to_output(
+    basis: &mut dyn BasisReader,
+    delta: &Delta,
+    chunk_size: usize,
+    max_output_size: u64,
+    mut output: impl PatchOutput,
+) -> eyre::Result<u64> {
+    let recreated = apply_delta_from_reader(basis, delta, chunk_size, max_output_size, &mut output)
+        .context("Error while applying the Delta to the Basis file")?;
+    output.flush()?;
+
+    Ok(recreated.length)
+}
+
+/// Applies several Deltas to the same basis file concurrently, e.g. to write a variant of a
+/// master file for each client.
+///
+/// Each job is patched as `patch` would, by up to `parallelism` threads at once, all reading the
+/// basis file through the same BlockCache (`options.block_cache`, or one of
+/// `DEFAULT_BATCH_CACHE_SIZE` bytes), so blocks most Deltas reuse are read once. A failed job does
+/// not stop the others.
+///
+/// # Arguments
+/// * `basis_filename` - File to apply the changes to.
+/// * `jobs` - The Deltas to apply, and where to save each recreated file.
+/// * `options` - How the files are recreated. The basis file must be read from the disk.
+/// * `parallelism` - How many jobs are patched at once.
+/// * `protection` - How the Deltas are verified and decrypted.
+///
+pub fn patch_batch(
+    basis_filename: &Path,
+    jobs: &[PatchJob],
+    options: &PatchOptions,
+    parallelism: &Parallelism,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PatchBatchReport> {
+    check_reader_options(options)?;
+    if options.basis_io == BasisIo::Memory {
+        return Err(eyre!(
+            "A batch shares blocks of the Basis file, which needs a `--basis-io` reading them from the disk"
+        ))
+        .suggestion("Use `--basis-io positioned`.");
+    }
+    if options.provenance_map.is_some() {
+        return Err(eyre!("--provenance-map is not supported for batches"));
+    }
+    if let Some(job) = jobs
+        .iter()
+        .find(|job| io_utils::is_stdio(&job.recreated_filename))
+    {
+        return Err(eyre!(
+            "{} of a batch can not be written to the standard output",
+            job.delta_filename.display()
+        ));
+    }
+    let cache = options.block_cache.clone().unwrap_or_else(|| {
+        Arc::new(BlockCache::new(
+            options.blocks.chunk_size as u64,
+            DEFAULT_BATCH_CACHE_SIZE,
+        ))
+    });
+    let options = PatchOptions {
+        block_cache: Some(cache.clone()),
+        ..options.clone()
+    };
+
+    let patches = Mutex::new(Vec::from_iter(jobs.iter().map(|_| None)));
+    let fail = |index: usize, error: eyre::Report| {
+        patches.lock().unwrap()[index] = Some(Err(error.wrap_err(format!(
+            "Unable to apply {} to the Basis file",
+            jobs[index].delta_filename.display()
+        ))));
+    };
+    let (deltas, received_deltas) =
+        mpsc::sync_channel::<(usize, Delta, Timings)>(parallelism.threads());
+    let received_deltas = Mutex::new(received_deltas);
+    thread::scope(|scope| {
+        for _ in 0..parallelism.threads().min(jobs.len()) {
+            scope.spawn(|| loop {
+                let Ok((index, delta, timings)) = received_deltas.lock().unwrap().recv() else {
+                    break;
+                };
+                let recreated_filename = &jobs[index].recreated_filename;
+                match patch_delta_from_reader(
+                    basis_filename,
+                    &delta,
+                    recreated_filename,
+                    &options,
+                    timings,
+                ) {
+                    Ok(report) => patches.lock().unwrap()[index] = Some(Ok(report)),
+                    Err(error) => fail(index, error),
+                }
+            });
+        }
+
+        // Keys can not be shared between threads, so Deltas are read and decrypted here.
+        for (index, job) in jobs.iter().enumerate() {
+            let mut timings = Timings::default();
+            let delta = read_artifact(
+                &job.delta_filename,
+                "Delta",
+                "patch",
+                protection,
+                &mut timings,
+            );
+            match delta {
+                Ok(delta) => {
+                    if deltas.send((index, delta, timings)).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => fail(index, error),
+            }
+        }
+        drop(deltas);
+    });
+
+    Ok(PatchBatchReport {
+        patches: patches
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|patch| patch.expect("Every job is patched"))
+            .collect(),
+        cache: cache.stats(),
+    })
+}
+
+/// Recreates the updated file in memory, and checks it against the one the Delta was computed
+/// from, without writing it.
+///
+/// Useful to validate a Delta on the receiving host before replacing anything. The basis file is
+/// not refused if its hash differs from the one the Signature recorded: the recreated file then
+/// does not match, and the verification tells so.
+///
+/// # Arguments
+/// * `basis_filename` - File to apply the changes to.
+/// * `delta_filename` - Delta file computed by `delta`.
+/// * `options` - How the file is recreated. The whole file is always recreated.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn verify_patch(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    options: &PatchOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PatchVerification> {
+    if options.range.is_some() {
+        return Err(eyre!("--range cannot be verified, only whole files are"));
+    }
+    let mut timings = Timings::default();
+    let options = PatchOptions {
+        assume_basis_ok: true,
+        ..options.clone()
+    };
+
+    let RecreatedFile {
+        recreated,
+        expected,
+        ..
+    } = recreate_file(
+        basis_filename,
+        delta_filename,
+        &options,
+        protection,
+        &mut timings,
+    )?;
+    let expected = expected
+        .ok_or_else(|| eyre!("Delta does not record the updated file it was computed from"))
+        .suggestion("Compute the Delta again with a newer `delta` command.")?;
+    let recreated = timings.measure(Phase::Hash, || recreated.digest());
+
+    Ok(PatchVerification {
+        expected,
+        recreated,
+        timings,
+    })
+}
+
+// A file recreated in memory, with the digest the Delta expects, if any.
+struct RecreatedFile {
+    recreated: BytesRope,
+    expected: Option<FileDigest>,
+    basis_size: u64,
+    blocks_copied: u64,
+    unconfirmed: bool,
+}
+
+// Applies the Delta, and returns the recreated file with the digest the Delta expects, if any.
+fn recreate_file(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    options: &PatchOptions,
+    protection: &ArtifactProtection,
+    timings: &mut Timings,
+) -> eyre::Result<RecreatedFile> {
+    let PatchOptions {
+        blocks,
+        range,
+        max_output_size,
+        provenance_map,
+        assume_basis_ok,
+        ..
+    } = options;
+    if range.is_some() {
+        ensure_fixed_mode(blocks.mode, "--range")?;
+    }
+    if provenance_map.is_some() {
+        ensure_fixed_mode(blocks.mode, "--provenance-map")?;
+    }
+
+    let basis_file_bytes = timings.measure(Phase::Read, || {
+        io_utils::attempt_to_read_file(basis_filename)
+            .context("Error while reading Basis file provided as argument to `patch` command")
+    })?;
+    let delta: Delta = read_artifact(delta_filename, "Delta", "patch", protection, timings)?;
+    let expected = delta.header.updated;
+    let unconfirmed = delta.header.unconfirmed;
+    let blocks_copied = count_blocks_copied(&delta);
+    // The Delta references blocks of the transformed basis file, and recreates a transformed file.
+    let transforms = TransformRegistry::with_builtin_transforms();
+    let transform = delta.header.transform.clone();
+    if range.is_some() && transform.is_some() {
+        return Err(eyre!(
+            "--range is not supported for Deltas computed with a transform"
+        ));
+    }
+    let basis_file_bytes = timings
+        .measure(Phase::Read, || {
+            transforms.encode(transform.as_deref(), basis_file_bytes)
+        })
+        .context("Error while transforming Basis file")?;
+    let basis_size = basis_file_bytes.len() as u64;
+    if !assume_basis_ok {
+        timings
+            .measure(Phase::Hash, || check_basis_file(&delta, &basis_file_bytes))
+            .suggestion(ASSUME_BASIS_OK)?;
+    }
+
+    if let Some(output) = provenance_map {
+        timings.measure(Phase::Write, || {
+            write_provenance_map(
+                output,
+                &delta,
+                blocks.chunk_size,
+                Some(basis_file_bytes.len()),
+            )
+        })?;
+    }
+    let expected_size = expected.map_or(0, |updated| updated.length);
+    let recreated = timings.measure_bytes(Phase::Apply, expected_size, || {
+        let recreated = match range {
+            Some(range) => apply_delta_range(
+                basis_file_bytes,
+                delta,
+                blocks.chunk_size,
+                range.clone(),
+                *max_output_size,
+            )
+            .map(BytesRope::from),
+            // Reused blocks stay in the buffer of the basis file, only literals are copied.
+            None => apply_delta_zero_copy(
+                basis_file_bytes,
+                delta,
+                blocks.chunk_size,
+                blocks.mode,
+                *max_output_size,
+            ),
+        }
+        .context("Error while applying the Delta to the Basis file")?;
+        if transform.is_none() {
+            return Ok(recreated);
+        }
+        let mut recreated = recreated;
+        let recreated = recreated.copy_to_bytes(recreated.remaining());
+        transforms
+            .decode(transform.as_deref(), recreated)
+            .map(BytesRope::from)
+            .context("Error while reversing the transform of the recreated file")
+    })?;
+
+    Ok(RecreatedFile {
+        recreated,
+        expected,
+        basis_size,
+        blocks_copied,
+        unconfirmed,
+    })
+}
+
+// Blocks of the basis file a Delta copies, counting a block again each time it is referenced.
+fn count_blocks_copied(delta: &Delta) -> u64 {
+    delta
+        .tokens()
+        .map(|token| token.block_indexes().len() as u64)
+        .sum()
+}
+
+/// Reports what applying a Delta to a basis file would do, without reading the basis file's
+/// content or writing anything.
+///
+/// # Arguments
+/// * `basis_filename` - File the Delta would be applied to.
+/// * `delta_filename` - Delta file computed by `delta`.
+/// * `blocks` - How the files were divided into blocks. Only `fixed` mode is supported.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn simulate_patch(
+    basis_filename: &Path,
+    delta_filename: &Path,
+    blocks: &BlockOptions,
+    protection: &ArtifactProtection,
+) -> eyre::Result<PatchSimulation> {
+    ensure_fixed_mode(blocks.mode, "--simulate")?;
+
+    // The size of a pipe or a device is not known without reading it.
+    io_utils::ensure_regular_file(basis_filename)
+        .suggestion("Save the Basis file to a regular file first.")?;
+    let basis_file_size = std::fs::metadata(basis_filename)
+        .wrap_err(format!(
+            r#"Could not read metadata of Basis file "{}""#,
+            basis_filename.display()
+        ))?
+        .len() as usize;
+    let delta = read_delta(delta_filename, "patch", protection)?;
+
+    simulate_delta(basis_file_size, &delta, blocks.chunk_size)
+}
+
+// Enough bytes to tell artifacts, signed artifacts and age files apart.
+const PEEKED_LENGTH: u64 = 32;
+
+// Suggested when the Basis file does not match the hash recorded in the Delta.
+const ASSUME_BASIS_OK: &str = "If it is the right one, patch it anyway with `--assume-basis-ok`.";
+
+/// Checks `filename` can be read, and starts like an artifact of one of `kinds` (the first one
+/// naming what was expected), so mistakes in the arguments are reported before any work starts.
+///
+/// Signed and encrypted artifacts only tell their kind once unwrapped, and the standard input can
+/// not be looked at twice, so they are accepted as they are.
+pub fn check_artifact_file(filename: &Path, kinds: &[ArtifactKind]) -> eyre::Result<()> {
+    if io_utils::is_stdio(filename) {
+        return Ok(());
+    }
+    let expected = kinds.first().map_or("artifact", |kind| kind.name());
+    let mut head = Vec::new();
+    File::open(filename)
+        .and_then(|file| file.take(PEEKED_LENGTH).read_to_end(&mut head))
+        .wrap_err(format!(r#"Unable to read "{}""#, filename.display()))?;
+
+    if is_signed(&head)
+        || is_encrypted(&head)
+        || kinds.iter().any(|kind| head.starts_with(kind.magic()))
+    {
+        return Ok(());
+    }
+    let error = match artifact_kind(&head) {
+        Some(kind) => eyre!(
+            r#""{}" holds a {}, not a {expected}"#,
+            filename.display(),
+            kind.name()
+        ),
+        None => eyre!(r#""{}" is not a {expected} file"#, filename.display()),
+    };
+    Err(error).suggestion("Are the arguments in the right order? See `--help`.")
+}
+
+/// Reads a Signature file, verifying and decrypting it.
+///
+/// # Arguments
+/// * `signature_filename` - Signature file computed by `signature`.
+/// * `command` - The command reading it, for error messages.
+/// * `protection` - How the Signature is verified and decrypted.
+///
+pub fn read_signature(
+    signature_filename: &Path,
+    command: &str,
+    protection: &ArtifactProtection,
+) -> eyre::Result<FileSignature> {
+    read_artifact(
+        signature_filename,
+        "Signature",
+        command,
+        protection,
+        &mut Timings::default(),
+    )
+}
+
+/// Reads a Delta file, verifying and decrypting it.
+///
+/// # Arguments
+/// * `delta_filename` - Delta file computed by `delta`.
+/// * `command` - The command reading it, for error messages.
+/// * `protection` - How the Delta is verified and decrypted.
+///
+pub fn read_delta(
+    delta_filename: &Path,
+    command: &str,
+    protection: &ArtifactProtection,
+) -> eyre::Result<Delta> {
+    read_artifact(
+        delta_filename,
+        "Delta",
+        command,
+        protection,
+        &mut Timings::default(),
+    )
+}
+
+fn read_artifact<T>(
+    filename: &Path,
+    artifact_name: &str,
+    command: &str,
+    protection: &ArtifactProtection,
+    timings: &mut Timings,
+) -> eyre::Result<T>
+where
+    T: TryFrom<Bytes, Error = eyre::Report>,
+{
+    let file_bytes = timings
+        .measure(Phase::Read, || {
+            io_utils::attempt_to_read_file_or_stdin(filename)
+        })
+        .context(format!(
+            "Error while reading {artifact_name} file provided as argument to `{command}` command"
+        ))?;
+
+    timings
+        .measure(Phase::Serialize, || {
+            protection.unprotect(file_bytes).and_then(T::try_from)
+        })
+        .context(format!(
+            r#"{artifact_name} file path provided was "{}"."#,
+            filename.display()
+        ))
+}
+
+// Computes and serializes an artifact, timing the computation as `phase`, which goes through
+// `input_size` bytes. When verifying determinism, everything is done twice from the same inputs,
+// and the serialized results must be byte-identical.
+fn compute_artifact<T>(
+    verify_deterministic: bool,
+    artifact_name: &str,
+    (phase, input_size): (Phase, usize),
+    timings: &mut Timings,
+    compute: impl Fn() -> T,
+) -> eyre::Result<(T, Bytes)>
+where
+    T: Clone,
+    Bytes: TryFrom<T, Error = eyre::Report>,
+{
+    let artifact = timings.measure_bytes(phase, input_size as u64, &compute);
+    let artifact_bytes = timings.measure(Phase::Serialize, || Bytes::try_from(artifact.clone()))?;
+
+    if verify_deterministic {
+        let recomputed = timings.measure_bytes(phase, input_size as u64, &compute);
+        let recomputed_bytes = timings.measure(Phase::Serialize, || Bytes::try_from(recomputed))?;
+        if recomputed_bytes != artifact_bytes {
+            return Err(eyre!(
+                "{artifact_name} is not deterministic: computing it twice gave different results"
+            ));
+        }
+    }
+
+    Ok((artifact, artifact_bytes))
+}
+
+// Transforms and normalizes the basis file, as requested.
+fn preprocess_basis_file(
+    basis_file_bytes: Bytes,
+    preprocessing: &Preprocessing,
+) -> eyre::Result<Bytes> {
+    let basis_file_bytes = TransformRegistry::with_builtin_transforms()
+        .encode(preprocessing.transform.as_deref(), basis_file_bytes)
+        .context("Error while transforming Basis file")?;
+
+    if preprocessing.normalize_text {
+        Ok(normalize_basis_file(
+            basis_file_bytes,
+            preprocessing.strip_bom,
+        ))
+    } else {
+        Ok(basis_file_bytes)
+    }
+}
+
+// Serializes, protects and writes a Signature, returning its size.
+fn write_signature(
+    signature: FileSignature,
+    signature_filename: &Path,
+    protection: &ArtifactProtection,
+    timings: &mut Timings,
+) -> eyre::Result<u64> {
+    let signature_bytes = timings
+        .measure(Phase::Serialize, || {
+            Bytes::try_from(signature).and_then(|bytes| protection.protect(bytes))
+        })
+        .context("Error while encrypting Signature")?;
+    let signature_size = signature_bytes.len() as u64;
+    timings
+        .measure(Phase::Write, || {
+            io_utils::write_to_file(signature_filename, signature_bytes)
+        })
+        .wrap_err(format!(
+            "Unable to write to file: {}",
+            signature_filename.display()
+        ))?;
+
+    Ok(signature_size)
+}
+
+// Transforms and normalizes the updated file, as requested.
+fn preprocess_updated_file(
+    updated_file_bytes: Bytes,
+    preprocessing: &Preprocessing,
+) -> eyre::Result<(Bytes, Option<TextNormalization>)> {
+    let updated_file_bytes = TransformRegistry::with_builtin_transforms()
+        .encode(preprocessing.transform.as_deref(), updated_file_bytes)
+        .context("Error while transforming Updated file")?;
+
+    if preprocessing.normalize_text {
+        let (normalized, normalization) =
+            normalize_updated_file(updated_file_bytes, preprocessing.strip_bom);
+        Ok((normalized, Some(normalization)))
+    } else {
+        Ok((updated_file_bytes, None))
+    }
+}
+
+// Some features depend on blocks having exactly `chunk_size` bytes.
+fn ensure_fixed_mode(mode: ChunkingMode, feature: &str) -> eyre::Result<()> {
+    match mode {
+        ChunkingMode::Fixed => Ok(()),
+        _ => Err(eyre!("{feature} is not supported in {mode} mode")),
+    }
+}
+
+fn write_provenance_map(
+    output: &ProvenanceMapOutput,
+    delta: &Delta,
+    chunk_size: usize,
+    basis_file_size: Option<usize>,
+) -> eyre::Result<()> {
+    let map = compute_provenance_map(delta, chunk_size, basis_file_size);
+    let map_bytes = match output.format {
+        MapFormat::Csv => map.to_csv()?,
+        MapFormat::Json => map.to_json()?,
+    };
+    io_utils::write_to_file(&output.filename, map_bytes).wrap_err(format!(
+        "Unable to write to file: {}",
+        output.filename.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn commands_recreate_the_updated_file_and_report_sizes() {
+        let root = scratch_directory("commands_roundtrip");
+        let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+        let updated_file = basis_file.replace("lazy", "sleepy");
+        fs::write(root.join("basis"), &basis_file).unwrap();
+        fs::write(root.join("updated"), &updated_file).unwrap();
+        let blocks = BlockOptions {
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let protection = ArtifactProtection::default();
+
+        let signature_report = signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        let delta_report = delta(
+            &root.join("signature"),
+            &root.join("updated"),
+            &root.join("delta"),
+            &DeltaOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        let patch_report = patch(
+            &root.join("basis"),
+            &root.join("delta"),
+            &root.join("recreated"),
+            &PatchOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+
+        assert_eq!(signature_report.basis_size, basis_file.len() as u64);
+        assert_eq!(
+            signature_report.block_count,
+            basis_file.len().div_ceil(8) as u64
+        );
+        assert_eq!(
+            signature_report.signature_size,
+            fs::metadata(root.join("signature")).unwrap().len()
+        );
+        assert_eq!(
+            delta_report.delta_size,
+            fs::metadata(root.join("delta")).unwrap().len()
+        );
+        assert!(delta_report.summary.unwrap().block_references > 0);
+        assert_eq!(patch_report.basis_size, basis_file.len() as u64);
+        assert!(patch_report.blocks_copied > 0);
+        assert_eq!(patch_report.recreated_size, updated_file.len() as u64);
+        assert_eq!(
+            fs::read(root.join("recreated")).unwrap(),
+            updated_file.as_bytes()
+        );
+    }
+
+    #[test]
+    fn delta_summaries_tell_how_much_smaller_the_delta_is() {
+        let report = DeltaReport {
+            updated_size: 4096,
+            delta_size: 512,
+            summary: None,
+            updated_signature_size: None,
+            timings: Timings::default(),
+        };
+
+        let summary = report.summary(None);
+
+        assert_eq!(summary.speedup, Some(8.0));
+        assert_eq!(
+            summary.to_string(),
+            "delta: read 4 KiB, wrote 512 bytes in 0.000s, speedup is 8.00"
+        );
+        assert!(summary.to_json().unwrap().contains("\"speedup\":8.0"));
+    }
+
+    #[test]
+    fn verify_only_tells_whether_the_updated_file_is_recreated() {
+        let root = scratch_directory("commands_verify");
+        let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+        let updated_file = basis_file.replace("fox", "cat");
+        fs::write(root.join("basis"), &basis_file).unwrap();
+        fs::write(root.join("updated"), updated_file).unwrap();
+        let blocks = BlockOptions {
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let options = PatchOptions {
+            blocks,
+            ..Default::default()
+        };
+        let protection = ArtifactProtection::default();
+        signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        delta(
+            &root.join("signature"),
+            &root.join("updated"),
+            &root.join("delta"),
+            &DeltaOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+
+        let verification = verify_patch(
+            &root.join("basis"),
+            &root.join("delta"),
+            &options,
+            &protection,
+        )
+        .unwrap();
+        assert!(verification.matches());
+
+        // Same length and blocks, but different content: the Delta applies, to the wrong result.
+        fs::write(root.join("basis"), basis_file.replace("dog", "cow")).unwrap();
+        let verification = verify_patch(
+            &root.join("basis"),
+            &root.join("delta"),
+            &options,
+            &protection,
+        )
+        .unwrap();
+        assert!(!verification.matches());
+    }
+
+    #[test]
+    fn patch_refuses_a_basis_file_with_another_hash() {
+        let root = scratch_directory("commands_basis_hash");
+        let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+        fs::write(root.join("basis"), &basis_file).unwrap();
+        fs::write(root.join("updated"), basis_file.replace("fox", "cat")).unwrap();
+        let blocks = BlockOptions {
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let protection = ArtifactProtection::default();
+        signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        delta(
+            &root.join("signature"),
+            &root.join("updated"),
+            &root.join("delta"),
+            &DeltaOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        // Same length and blocks, but different content.
+        fs::write(root.join("basis"), basis_file.replace("dog", "cow")).unwrap();
+
+        for basis_io in [BasisIo::Memory, BasisIo::Positioned] {
+            let options = PatchOptions {
+                blocks,
+                basis_io,
+                ..Default::default()
+            };
+            let result = patch(
+                &root.join("basis"),
+                &root.join("delta"),
+                &root.join("recreated"),
+                &options,
+                &protection,
+            );
+            assert!(result.is_err());
+            assert!(!root.join("recreated").exists());
+        }
+
+        // Assuming the basis file is right, the wrong file is recreated, and only then refused.
+        let options = PatchOptions {
+            blocks,
+            assume_basis_ok: true,
+            ..Default::default()
+        };
+        let result = patch(
+            &root.join("basis"),
+            &root.join("delta"),
+            &root.join("recreated"),
+            &options,
+            &protection,
+        );
+        assert!(result.unwrap_err().to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn delta_writes_the_signature_of_the_updated_file() {
+        let root = scratch_directory("commands_updated_signature");
+        let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+        fs::write(root.join("basis"), &basis_file).unwrap();
+        fs::write(root.join("updated"), basis_file.replace("fox", "cat")).unwrap();
+        let protection = ArtifactProtection::default();
+        for mode in [ChunkingMode::Fixed, ChunkingMode::Lines] {
+            let blocks = BlockOptions {
+                chunk_size: 8,
+                mode,
+            };
+            // The Signature of the updated file keeps the hashes of the basis file's.
+            let options = SignatureOptions {
+                blocks,
+                weak_hash: WeakHash::Adler32,
+                strong_hash: StrongHash::SipHash,
+                ..Default::default()
+            };
+            signature(
+                &root.join("basis"),
+                &root.join("signature"),
+                &options,
+                &protection,
+            )
+            .unwrap();
+            signature(
+                &root.join("updated"),
+                &root.join("expected"),
+                &options,
+                &protection,
+            )
+            .unwrap();
+
+            let report = delta(
+                &root.join("signature"),
+                &root.join("updated"),
+                &root.join("delta"),
+                &DeltaOptions {
+                    blocks,
+                    updated_signature: Some(root.join("next")),
+                    ..Default::default()
+                },
+                &protection,
+            )
+            .unwrap();
+
+            let next = fs::read(root.join("next")).unwrap();
+            assert_eq!(next, fs::read(root.join("expected")).unwrap());
+            assert_eq!(report.updated_signature_size, Some(next.len() as u64));
+        }
+    }
+
+    #[test]
+    fn batch_recreates_each_variant_from_one_basis_file() {
+        let root = scratch_directory("commands_batch");
+        let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+        fs::write(root.join("basis"), &basis_file).unwrap();
+        let blocks = BlockOptions {
+            chunk_size: 8,
+            ..Default::default()
+        };
+        let protection = ArtifactProtection::default();
+        signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions {
+                blocks,
+                ..Default::default()
+            },
+            &protection,
+        )
+        .unwrap();
+        let variants = ["cat", "owl", "elk"];
+        let mut list = String::new();
+        for variant in variants {
+            fs::write(root.join(variant), basis_file.replace("fox", variant)).unwrap();
+            delta(
+                &root.join("signature"),
+                &root.join(variant),
+                &root.join(format!("{variant}.delta")),
+                &DeltaOptions {
+                    blocks,
+                    ..Default::default()
+                },
+                &protection,
+            )
+            .unwrap();
+            let delta = root.join(format!("{variant}.delta"));
+            let recreated = root.join(format!("{variant}.recreated"));
+            list += &format!("{}\t{}\n", delta.display(), recreated.display());
+        }
+        list += "missing.delta\tmissing.recreated\n";
+        let jobs = PatchJob::parse_list(&list).unwrap();
+
+        let report = patch_batch(
+            &root.join("basis"),
+            &jobs,
+            &PatchOptions {
+                blocks,
+                basis_io: BasisIo::Positioned,
+                ..Default::default()
+            },
+            &Parallelism::with_threads(std::num::NonZeroUsize::new(2).unwrap()),
+            &protection,
+        )
+        .unwrap();
+
+        assert_eq!(report.failures(), 1);
+        assert!(report.patches[3].is_err());
+        for variant in variants {
+            assert_eq!(
+                fs::read_to_string(root.join(format!("{variant}.recreated"))).unwrap(),
+                basis_file.replace("fox", variant)
+            );
+        }
+        assert!(report.cache.hits > 0);
+        assert!(PatchJob::parse_list("no tab here").is_err());
+    }
+
+    #[test]
+    fn streamed_delta_cannot_be_verified_for_determinism() {
+        let root = scratch_directory("commands_stream");
+        let options = DeltaOptions {
+            stream: true,
+            verify_deterministic: true,
+            ..Default::default()
+        };
+
+        let result = delta(
+            &root.join("signature"),
+            &root.join("updated"),
+            &root.join("delta"),
+            &options,
+            &ArtifactProtection::default(),
+        );
+
+        assert!(result.is_err());
+        assert!(!root.join("delta").exists());
+    }
+
+    #[test]
+    fn locked_recreated_file_is_not_written() {
+        let root = scratch_directory("commands_lock");
+        fs::write(
+            root.join("basis"),
+            "the quick brown fox jumps over the lazy dog",
+        )
+        .unwrap();
+        fs::write(
+            root.join("updated"),
+            "the quick brown cat jumps over the lazy dog",
+        )
+        .unwrap();
+        let protection = ArtifactProtection::default();
+        signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions::default(),
+            &protection,
+        )
+        .unwrap();
+        delta(
+            &root.join("signature"),
+            &root.join("updated"),
+            &root.join("delta"),
+            &DeltaOptions::default(),
+            &protection,
+        )
+        .unwrap();
+        fs::write(root.join("recreated"), "being written by another process").unwrap();
+        let holder = fs::File::open(root.join("recreated")).unwrap();
+        holder.lock().unwrap();
+
+        let patch_with = |options: &PatchOptions| {
+            patch(
+                &root.join("basis"),
+                &root.join("delta"),
+                &root.join("recreated"),
+                options,
+                &protection,
+            )
+        };
+        for basis_io in [BasisIo::Memory, BasisIo::Positioned] {
+            let options = PatchOptions {
+                basis_io,
+                ..Default::default()
+            };
+            assert!(patch_with(&options).is_err());
+            assert_eq!(
+                fs::read(root.join("recreated")).unwrap(),
+                b"being written by another process"
+            );
+        }
+
+        let options = PatchOptions {
+            lock: false,
+            ..Default::default()
+        };
+        patch_with(&options).unwrap();
+        assert_eq!(
+            fs::read(root.join("recreated")).unwrap(),
+            fs::read(root.join("updated")).unwrap()
+        );
+    }
+
+    #[test]
+    fn artifact_files_are_checked_by_their_magic() {
+        let root = scratch_directory("commands_check_artifact");
+        fs::write(root.join("basis"), "not an artifact").unwrap();
+        signature(
+            &root.join("basis"),
+            &root.join("signature"),
+            &SignatureOptions::default(),
+            &ArtifactProtection::default(),
+        )
+        .unwrap();
+        let signatures = [ArtifactKind::Signature, ArtifactKind::DeduplicatedSignature];
+
+        assert!(check_artifact_file(&root.join("signature"), &signatures).is_ok());
+        assert!(check_artifact_file(&root.join("signature"), &[ArtifactKind::Delta]).is_err());
+        assert!(check_artifact_file(&root.join("basis"), &signatures).is_err());
+        assert!(check_artifact_file(&root.join("missing"), &signatures).is_err());
+        assert!(check_artifact_file(Path::new("-"), &signatures).is_ok());
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::Serialize;
+
+use crate::domain::{
+    compute_delta_with_mode, compute_signature_in_parallel, summarize_delta, ChunkingMode,
+    MatchingOptions, Parallelism,
+};
+
+/// Chunk sizes tried when none are given: every power of 4 from 64 bytes to 64 KiB.
+pub const DEFAULT_ANALYSIS_CHUNK_SIZES: [usize; 6] = [64, 256, 1024, 4096, 16384, 65536];
+
+/// Deflate levels tried when none are given. 0 leaves the Delta uncompressed.
+pub const DEFAULT_ANALYSIS_COMPRESSION_LEVELS: [u32; 4] = [0, 1, 6, 9];
+
+/// What syncing a file costs with a given chunk size, and its Delta deflated at a given level.
+#[derive(Debug, PartialEq, Serialize, Clone, Copy)]
+pub struct AnalysisRow {
+    pub chunk_size: usize,
+    /// Deflate level of the Delta, 0 for none.
+    pub compression_level: u32,
+    /// Bytes of the updated file sent as they are.
+    pub literal_bytes: u64,
+    /// Bytes of the updated file recreated from blocks of the basis file.
+    pub matched_bytes: u64,
+    pub signature_size: u64,
+    pub delta_size: u64,
+    /// Same as `delta_size` when the Delta is not compressed.
+    pub compressed_delta_size: u64,
+}
+
+impl AnalysisRow {
+    /// Fraction of the updated file sent as literals, between 0 and 1.
+    /// An empty updated file sends no literals.
+    pub fn literal_share(&self) -> f64 {
+        let updated_size = self.literal_bytes + self.matched_bytes;
+        if updated_size == 0 {
+            return 0.0;
+        }
+        self.literal_bytes as f64 / updated_size as f64
+    }
+}
+
+/// A row for each chunk size and compression level tried, to choose how to configure syncs.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Analysis {
+    pub rows: Vec<AnalysisRow>,
+}
+
+impl Analysis {
+    /// Serializes the analysis as CSV, with one row per chunk size and compression level.
+    #[cfg(feature = "csv")]
+    pub fn to_csv(&self) -> eyre::Result<Bytes> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record([
+            "chunk_size",
+            "compression_level",
+            "literal_bytes",
+            "matched_bytes",
+            "literal_share",
+            "signature_size",
+            "delta_size",
+            "compressed_delta_size",
+        ])?;
+        for row in &self.rows {
+            writer.write_record([
+                row.chunk_size.to_string(),
+                row.compression_level.to_string(),
+                row.literal_bytes.to_string(),
+                row.matched_bytes.to_string(),
+                format!("{:.4}", row.literal_share()),
+                row.signature_size.to_string(),
+                row.delta_size.to_string(),
+                row.compressed_delta_size.to_string(),
+            ])?;
+        }
+
+        Ok(writer.into_inner()?.into())
+    }
+}
+
+/// Computes the Signature and the Delta of `updated_file` for each chunk size, in fixed mode,
+/// and how large the Delta is once deflated at each level.
+///
+/// # Arguments
+/// * `basis_file` - The file the receiver has.
+/// * `updated_file` - The file which would be sent.
+/// * `chunk_sizes` - The sizes for each block to try.
+/// * `compression_levels` - The deflate levels to try, from 0 (none) to 9.
+/// * `options` - How the matcher is tuned.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn analyze(
+    basis_file: &Bytes,
+    updated_file: &Bytes,
+    chunk_sizes: &[usize],
+    compression_levels: &[u32],
+    options: &MatchingOptions,
+    parallelism: &Parallelism,
+) -> eyre::Result<Analysis> {
+    let mut analysis = Analysis::default();
+    for &chunk_size in chunk_sizes {
+        let mode = ChunkingMode::Fixed;
+        let signature =
+            compute_signature_in_parallel(basis_file.clone(), chunk_size, mode, parallelism);
+        let signature_size = Bytes::try_from(signature.clone())?.len() as u64;
+        let mut delta = compute_delta_with_mode(
+            signature.clone(),
+            updated_file.clone(),
+            chunk_size,
+            mode,
+            options,
+        );
+        delta.optimize_against(&signature);
+        let literal_bytes = summarize_delta(&delta).literal_bytes as u64;
+        let delta_bytes = Bytes::try_from(delta)?;
+
+        for &compression_level in compression_levels {
+            let compressed_delta_size = match compression_level {
+                0 => delta_bytes.len(),
+                level => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+                    encoder.write_all(&delta_bytes)?;
+                    encoder.finish()?.len()
+                }
+            };
+            analysis.rows.push(AnalysisRow {
+                chunk_size,
+                compression_level,
+                literal_bytes,
+                matched_bytes: updated_file.len() as u64 - literal_bytes,
+                signature_size,
+                delta_size: delta_bytes.len() as u64,
+                compressed_delta_size: compressed_delta_size as u64,
+            });
+        }
+    }
+
+    Ok(analysis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "csv")]
+    fn analysis_has_a_row_per_chunk_size_and_level() {
+        let basis_file = Bytes::from("0123456789abcdef".repeat(64));
+        let updated_file = Bytes::from(format!("{}{}", "0123456789abcdef".repeat(64), "x"));
+
+        let analysis = analyze(
+            &basis_file,
+            &updated_file,
+            &[16, 64],
+            &[0, 9],
+            &MatchingOptions::default(),
+            &Parallelism::serial(),
+        )
+        .unwrap();
+
+        assert_eq!(analysis.rows.len(), 4);
+        for row in &analysis.rows {
+            assert_eq!(row.literal_bytes, 1);
+            assert_eq!(row.matched_bytes, updated_file.len() as u64 - 1);
+        }
+        assert_eq!(
+            analysis.rows[0].compressed_delta_size,
+            analysis.rows[0].delta_size
+        );
+        let csv = analysis.to_csv().unwrap();
+        assert_eq!(csv.iter().filter(|&&byte| byte == b'\n').count(), 5);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::domain::delta::FileDigest;
+
+/// Where a Delta comes from, recorded in its header so artifacts can be traced in pipelines.
+///
+/// None of it is needed to apply the Delta: `patch` ignores it, and `inspect` shows it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[non_exhaustive]
+pub struct DeltaMetadata {
+    /// Who computed the Delta, e.g. a user or a build job.
+    pub creator: Option<String>,
+    /// When the Delta was computed, in seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    /// The basis file the Delta was computed against, when it was at hand.
+    pub basis: Option<FileDigest>,
+    /// Version of rsync_rust which computed the Delta.
+    pub tool_version: Option<String>,
+    /// Free-form keys and values, e.g. a commit hash.
+    pub properties: BTreeMap<String, String>,
+}
+
+impl DeltaMetadata {
+    /// Metadata of a Delta computed now, by this version of rsync_rust.
+    pub fn now(creator: Option<String>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .ok();
+        Self {
+            creator,
+            created_at,
+            tool_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// One line per field which is known, e.g. "Created at: 2024-01-31 12:00:00 UTC".
+impl fmt::Display for DeltaMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        if let Some(creator) = &self.creator {
+            lines.push(format!("Created by: {creator}"));
+        }
+        if let Some(created_at) = self.created_at {
+            lines.push(format!("Created at: {}", format_utc(created_at)));
+        }
+        if let Some(basis) = self.basis {
+            lines.push(format!("Basis file: {basis}"));
+        }
+        if let Some(tool_version) = &self.tool_version {
+            lines.push(format!("Tool version: {tool_version}"));
+        }
+        for (key, value) in &self.properties {
+            lines.push(format!("{key}: {value}"));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+// Formats seconds since the Unix epoch as a UTC date and time, without a calendar library.
+fn format_utc(seconds: u64) -> String {
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Days to a civil date, counting in 400-year eras from March 1st, 0000 (Howard Hinnant's).
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::domain::delta::{Delta, DeltaHeader};
+
+    #[test]
+    fn metadata_is_shown_one_field_per_line() {
+        let metadata = DeltaMetadata {
+            creator: Some(String::from("release-bot")),
+            created_at: Some(1_706_702_400),
+            properties: BTreeMap::from([(String::from("commit"), String::from("4f2a9c1"))]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata.to_string(),
+            "Created by: release-bot\nCreated at: 2024-01-31 12:00:00 UTC\ncommit: 4f2a9c1"
+        );
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn metadata_is_read_back_from_the_delta_header() {
+        let delta = Delta {
+            header: DeltaHeader {
+                metadata: Some(DeltaMetadata::now(Some(String::from("ci")))),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let read_back = Delta::try_from(Bytes::try_from(delta.clone()).unwrap()).unwrap();
+
+        assert_eq!(read_back, delta);
+    }
+}
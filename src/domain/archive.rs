@@ -0,0 +1,353 @@
+use std::io::{Cursor, Read, Write};
+use std::ops::Range;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use eyre::{eyre, Context};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::ContentTransform;
+
+// First byte of an encoded file, telling whether it was recognized as an archive.
+const PASSTHROUGH_TAG: u8 = 0;
+const ARCHIVE_TAG: u8 = 1;
+
+// Compression levels tried when checking if a member can be recompressed exactly.
+const DEFLATE_LEVELS: [u32; 3] = [6, 9, 1];
+
+/// Unpacks zip and tar archives into a canonical stream before chunking.
+///
+/// Archive members are stored one after the other, sorted by name and decompressed, so an archive
+/// whose members barely changed still shares most blocks with its previous version.
+/// Everything else (headers, directories, padding) is recorded in a layout at the start
+/// of the stream, so `decode` rebuilds the original archive byte by byte.
+///
+/// Compressed members are only stored decompressed if compressing them again gives back the exact
+/// same bytes. Otherwise, they are kept as they are. Files which are not archives are passed through.
+pub struct ArchiveTransform;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct ArchiveLayout {
+    /// The archive, in its original order.
+    segments: Vec<Segment>,
+    /// Length of each member in the stream, in sorted order.
+    member_lengths: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum Segment {
+    /// Bytes which are not part of a member, copied as they are.
+    Raw(Vec<u8>),
+    /// A member, whose content is in the stream at `stream_index`.
+    Member {
+        stream_index: usize,
+        encoding: MemberEncoding,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+enum MemberEncoding {
+    Verbatim,
+    Deflate { level: u32 },
+}
+
+// The part of an archive which holds the content of a member.
+struct MemberRegion {
+    name: String,
+    range: Range<usize>,
+    deflated: bool,
+}
+
+impl ContentTransform for ArchiveTransform {
+    fn name(&self) -> &str {
+        "archive"
+    }
+
+    fn encode(&self, content: Bytes) -> eyre::Result<Bytes> {
+        let mut encoded = BytesMut::new();
+        match find_member_regions(&content) {
+            Some(regions) => {
+                encoded.put_u8(ARCHIVE_TAG);
+                let (layout, stream) = split_archive(&content, regions)?;
+                let layout = rmp_serde::to_vec(&layout)?;
+                encoded.put_u64_le(layout.len() as u64);
+                encoded.put_slice(&layout);
+                encoded.put_slice(&stream);
+            }
+            None => {
+                encoded.put_u8(PASSTHROUGH_TAG);
+                encoded.put_slice(&content);
+            }
+        }
+
+        Ok(encoded.freeze())
+    }
+
+    fn decode(&self, mut content: Bytes) -> eyre::Result<Bytes> {
+        if content.is_empty() {
+            return Err(eyre!("Content was not encoded by the archive transform"));
+        }
+        match content.get_u8() {
+            PASSTHROUGH_TAG => Ok(content),
+            ARCHIVE_TAG => {
+                if content.len() < 8 {
+                    return Err(eyre!("Archive layout is truncated"));
+                }
+                let layout_length = content.get_u64_le() as usize;
+                if content.len() < layout_length {
+                    return Err(eyre!("Archive layout is truncated"));
+                }
+                let layout = content.split_to(layout_length);
+                let layout = rmp_serde::from_slice(&layout).wrap_err("Invalid archive layout")?;
+                join_archive(layout, &content)
+            }
+            tag => Err(eyre!("Unknown archive transform tag: {tag}")),
+        }
+    }
+}
+
+fn find_member_regions(content: &[u8]) -> Option<Vec<MemberRegion>> {
+    let mut regions = if content.starts_with(b"PK\x03\x04") {
+        find_zip_member_regions(content)?
+    } else if content.get(257..262) == Some(b"ustar") {
+        find_tar_member_regions(content)?
+    } else {
+        return None;
+    };
+
+    // Regions must not overlap, or the archive can not be split into segments.
+    regions.sort_by_key(|region| region.range.start);
+    let well_formed = regions
+        .windows(2)
+        .all(|w| w[0].range.end <= w[1].range.start)
+        && regions
+            .iter()
+            .all(|region| region.range.end <= content.len());
+
+    well_formed.then_some(regions)
+}
+
+fn find_zip_member_regions(content: &[u8]) -> Option<Vec<MemberRegion>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content)).ok()?;
+    let mut regions = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index).ok()?;
+        let start = file.data_start() as usize;
+        regions.push(MemberRegion {
+            name: file.name().to_string(),
+            range: start..start + file.compressed_size() as usize,
+            deflated: file.compression() == zip::CompressionMethod::Deflated,
+        });
+    }
+
+    Some(regions)
+}
+
+fn find_tar_member_regions(content: &[u8]) -> Option<Vec<MemberRegion>> {
+    let mut archive = tar::Archive::new(content);
+    let mut regions = Vec::new();
+    for entry in archive.entries().ok()? {
+        let entry = entry.ok()?;
+        let start = entry.raw_file_position() as usize;
+        regions.push(MemberRegion {
+            name: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+            range: start..start + entry.size() as usize,
+            deflated: false,
+        });
+    }
+
+    Some(regions)
+}
+
+// Splits the archive into its layout and the stream of member contents, sorted by name.
+fn split_archive(
+    content: &[u8],
+    regions: Vec<MemberRegion>,
+) -> eyre::Result<(ArchiveLayout, Vec<u8>)> {
+    let mut members = Vec::with_capacity(regions.len());
+    for region in &regions {
+        let data = &content[region.range.clone()];
+        let member = if region.deflated {
+            inflate_if_reproducible(data)?
+        } else {
+            None
+        };
+        members.push(member.unwrap_or((data.to_vec(), MemberEncoding::Verbatim)));
+    }
+
+    let mut sorted_order: Vec<usize> = (0..regions.len()).collect();
+    sorted_order.sort_by(|&a, &b| regions[a].name.cmp(&regions[b].name));
+    let mut stream_indexes = vec![0; regions.len()];
+    sorted_order
+        .iter()
+        .enumerate()
+        .for_each(|(stream_index, &member)| stream_indexes[member] = stream_index);
+
+    let mut segments = Vec::new();
+    let mut position = 0;
+    for (member, region) in regions.iter().enumerate() {
+        if position < region.range.start {
+            segments.push(Segment::Raw(content[position..region.range.start].to_vec()));
+        }
+        segments.push(Segment::Member {
+            stream_index: stream_indexes[member],
+            encoding: members[member].1,
+        });
+        position = region.range.end;
+    }
+    if position < content.len() {
+        segments.push(Segment::Raw(content[position..].to_vec()));
+    }
+
+    let mut stream = Vec::new();
+    let mut member_lengths = Vec::with_capacity(regions.len());
+    for &member in &sorted_order {
+        stream.extend_from_slice(&members[member].0);
+        member_lengths.push(members[member].0.len());
+    }
+
+    Ok((
+        ArchiveLayout {
+            segments,
+            member_lengths,
+        },
+        stream,
+    ))
+}
+
+fn join_archive(layout: ArchiveLayout, stream: &[u8]) -> eyre::Result<Bytes> {
+    let mut member_ranges = Vec::with_capacity(layout.member_lengths.len());
+    let mut position = 0;
+    for length in layout.member_lengths {
+        member_ranges.push(position..position + length);
+        position += length;
+    }
+    if position != stream.len() {
+        return Err(eyre!("Archive stream does not match its layout"));
+    }
+
+    let mut archive = Vec::new();
+    for segment in layout.segments {
+        match segment {
+            Segment::Raw(bytes) => archive.extend_from_slice(&bytes),
+            Segment::Member {
+                stream_index,
+                encoding,
+            } => {
+                let range = member_ranges
+                    .get(stream_index)
+                    .ok_or_else(|| eyre!("Archive layout references a missing member"))?;
+                let data = &stream[range.clone()];
+                match encoding {
+                    MemberEncoding::Verbatim => archive.extend_from_slice(data),
+                    MemberEncoding::Deflate { level } => archive.extend(deflate(data, level)?),
+                }
+            }
+        }
+    }
+
+    Ok(archive.into())
+}
+
+// Decompresses a deflated member, but only if it can be compressed back to the exact same bytes.
+fn inflate_if_reproducible(data: &[u8]) -> eyre::Result<Option<(Vec<u8>, MemberEncoding)>> {
+    let mut inflated = Vec::new();
+    if DeflateDecoder::new(data)
+        .read_to_end(&mut inflated)
+        .is_err()
+    {
+        return Ok(None);
+    }
+
+    for level in DEFLATE_LEVELS {
+        if deflate(&inflated, level)? == data {
+            return Ok(Some((inflated, MemberEncoding::Deflate { level })));
+        }
+    }
+
+    Ok(None)
+}
+
+fn deflate(data: &[u8], level: u32) -> eyre::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_tar(members: &[(&str, &[u8])]) -> Bytes {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.into_inner().unwrap().into()
+    }
+
+    fn create_zip(members: &[(&str, &[u8])], method: zip::CompressionMethod) -> Bytes {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().compression_method(method);
+        for (name, content) in members {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner().into()
+    }
+
+    #[test]
+    fn tar_archives_are_restored_exactly() {
+        let archive = create_tar(&[("b.txt", b"second member"), ("a.txt", b"first member")]);
+
+        let encoded = ArchiveTransform.encode(archive.clone()).unwrap();
+
+        assert_eq!(encoded[0], ARCHIVE_TAG);
+        assert_eq!(ArchiveTransform.decode(encoded).unwrap(), archive);
+    }
+
+    #[test]
+    fn members_are_stored_sorted_regardless_of_archive_order() {
+        let archive1 = create_tar(&[("a.txt", b"first member"), ("b.txt", b"second member")]);
+        let archive2 = create_tar(&[("b.txt", b"second member"), ("a.txt", b"first member")]);
+
+        let encoded1 = ArchiveTransform.encode(archive1).unwrap();
+        let encoded2 = ArchiveTransform.encode(archive2).unwrap();
+
+        let stream = b"first membersecond member";
+        assert!(encoded1.ends_with(stream));
+        assert!(encoded2.ends_with(stream));
+    }
+
+    #[test]
+    fn zip_archives_are_restored_exactly() {
+        let content: &[u8] = b"a member which is repeated, repeated, repeated, repeated";
+        for method in [
+            zip::CompressionMethod::Stored,
+            zip::CompressionMethod::Deflated,
+        ] {
+            let archive = create_zip(&[("z.txt", content), ("a.txt", b"other")], method);
+
+            let encoded = ArchiveTransform.encode(archive.clone()).unwrap();
+
+            assert_eq!(encoded[0], ARCHIVE_TAG);
+            assert_eq!(ArchiveTransform.decode(encoded).unwrap(), archive);
+        }
+    }
+
+    #[test]
+    fn other_files_are_passed_through() {
+        let file = Bytes::from("not an archive");
+
+        let encoded = ArchiveTransform.encode(file.clone()).unwrap();
+
+        assert_eq!(encoded[0], PASSTHROUGH_TAG);
+        assert_eq!(ArchiveTransform.decode(encoded).unwrap(), file);
+    }
+}
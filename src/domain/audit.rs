@@ -0,0 +1,129 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+/// What a sync did, appended to an audit log as a line of JSON, so savings can be audited and
+/// added up over time.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct AuditRecord {
+    /// When the sync started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub command: String,
+    /// The files brought up to date.
+    pub files: Vec<String>,
+    /// Their total size, which is what sending them directly would cost.
+    pub file_size: u64,
+    /// Bytes sent and received, Signatures included.
+    pub bytes_transferred: u64,
+    /// Bytes of the files which were not transferred, as the other side had them.
+    pub bytes_reused: u64,
+    pub duration_ms: u64,
+    /// Whether the files were checked to be the sender's once written.
+    pub verified: bool,
+    /// Why the sync failed, if it did.
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// A record of a sync by `command` which started at `started` and just ended, without files.
+    pub fn new(command: &str, started: SystemTime) -> Self {
+        Self {
+            timestamp: started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            command: command.to_string(),
+            files: Vec::new(),
+            file_size: 0,
+            bytes_transferred: 0,
+            bytes_reused: 0,
+            duration_ms: started
+                .elapsed()
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            verified: false,
+            error: None,
+        }
+    }
+
+    /// The same record, with the sizes of the sync. The bytes reused are those of the files less
+    /// the bytes transferred.
+    pub fn with_sizes(self, file_size: u64, bytes_transferred: u64) -> Self {
+        Self {
+            file_size,
+            bytes_transferred,
+            bytes_reused: file_size.saturating_sub(bytes_transferred),
+            ..self
+        }
+    }
+}
+
+/// Appends `record` to the audit log at `log_filename` as a single line, creating the log if
+/// needed. The line is written at once, so syncs logging to the same file do not mix their lines.
+pub fn append_audit_record(log_filename: &Path, record: &AuditRecord) -> eyre::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_filename)
+        .and_then(|mut log| log.write_all(&line))
+        .wrap_err(format!(
+            "Unable to append to the audit log: {}",
+            log_filename.display()
+        ))
+}
+
+/// Reads every record of the audit log at `log_filename`, in the order they were appended.
+pub fn read_audit_log(log_filename: &Path) -> eyre::Result<Vec<AuditRecord>> {
+    let log = std::fs::File::open(log_filename).wrap_err(format!(
+        "Unable to read the audit log: {}",
+        log_filename.display()
+    ))?;
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(log).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).wrap_err(format!(
+            "Line {} of the audit log is not a record",
+            number + 1
+        ))?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn records_are_appended_as_lines() {
+        let log_filename = scratch_directory("audit").join("audit.jsonl");
+        let started = SystemTime::now();
+        let transfer = AuditRecord {
+            files: vec![String::from("a.txt"), String::from("nested/b.txt")],
+            verified: true,
+            ..AuditRecord::new("transfer", started).with_sizes(1000, 150)
+        };
+        let push = AuditRecord {
+            error: Some(String::from("Connection refused")),
+            ..AuditRecord::new("push", started)
+        };
+
+        append_audit_record(&log_filename, &transfer).unwrap();
+        append_audit_record(&log_filename, &push).unwrap();
+
+        assert_eq!(transfer.bytes_reused, 850);
+        assert_eq!(read_audit_log(&log_filename).unwrap(), vec![transfer, push]);
+        let log = std::fs::read_to_string(&log_filename).unwrap();
+        assert_eq!(log.lines().count(), 2);
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use bytes::Bytes;
+
+use crate::domain::BlockSize;
+use crate::help::Help;
+use crate::io_utils;
+
+/// Bytes of blocks read together, unless the reader asks for another amount: 4 MiB.
+pub const DEFAULT_BATCH_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Gap between two reads below which `ReadAheadBasis` reads the gap too, in a single read: 256 KiB.
+///
+/// Reading a few unneeded bytes is much cheaper than seeking on spinning disks, or than another
+/// request on network filesystems.
+pub const READ_AHEAD_MAX_GAP: u64 = 256 * 1024;
+
+/// How `patch` reads the blocks of the basis file.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum BasisIo {
+    /// The whole basis file is read in memory first.
+    #[default]
+    Memory,
+    /// Only referenced blocks are read, each at its offset (`pread`).
+    Positioned,
+    /// Only referenced blocks are read, in batches of asynchronous reads. Linux only.
+    IoUring,
+}
+
+impl fmt::Display for BasisIo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BasisIo::Memory => write!(f, "memory"),
+            BasisIo::Positioned => write!(f, "positioned"),
+            BasisIo::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+impl FromStr for BasisIo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(BasisIo::Memory),
+            "positioned" => Ok(BasisIo::Positioned),
+            "io-uring" => Ok(BasisIo::IoUring),
+            _ => Err(format!(
+                r#""{s}" is not a basis IO backend. Expected "memory", "positioned" or "io-uring""#
+            )),
+        }
+    }
+}
+
+/// Reads byte ranges of the basis file where they are, without reading the whole file.
+pub trait BasisReader {
+    /// Size of the basis file, in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of blocks `patch` asks for in a single `read_ranges`, at most.
+    fn batch_size(&self) -> u64 {
+        DEFAULT_BATCH_SIZE
+    }
+
+    /// Reads every range, returning their bytes in the same order. Ranges are within the file.
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>>;
+
+    /// The basis file on disk, if `patch` may copy ranges of it to the output inside the kernel.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl<R: BasisReader + ?Sized> BasisReader for Box<R> {
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn batch_size(&self) -> u64 {
+        (**self).batch_size()
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        (**self).read_ranges(ranges)
+    }
+
+    fn file(&self) -> Option<&File> {
+        (**self).file()
+    }
+}
+
+// The whole basis file, already in memory.
+impl BasisReader for Bytes {
+    fn len(&self) -> u64 {
+        Bytes::len(self) as u64
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        Ok(ranges
+            .iter()
+            .map(|range| self[range.start as usize..range.end as usize].to_vec())
+            .collect())
+    }
+}
+
+/// Reads each range with a positioned read, one after the other.
+pub struct PositionedBasis {
+    file: File,
+    length: u64,
+}
+
+impl PositionedBasis {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(Self { file, length })
+    }
+}
+
+impl BasisReader for PositionedBasis {
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        ranges
+            .iter()
+            .map(|range| {
+                let mut buffer = vec![0; (range.end - range.start) as usize];
+                read_exact_at(&self.file, &mut buffer, range.start)?;
+                Ok(buffer)
+            })
+            .collect()
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads ranges in batches of asynchronous reads, submitted to the kernel all at once.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub struct UringBasis {
+    file: File,
+    length: u64,
+    ring: io_uring::IoUring,
+}
+
+// Reads in flight at once.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_QUEUE_DEPTH: usize = 64;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl UringBasis {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let ring = io_uring::IoUring::new(URING_QUEUE_DEPTH as u32)?;
+        Ok(Self { file, length, ring })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl BasisReader for UringBasis {
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        use std::os::unix::io::AsRawFd;
+
+        use io_uring::{opcode, types};
+
+        let fd = types::Fd(self.file.as_raw_fd());
+        let mut buffers: Vec<Vec<u8>> = ranges
+            .iter()
+            .map(|range| vec![0; (range.end - range.start) as usize])
+            .collect();
+
+        for first in (0..ranges.len()).step_by(URING_QUEUE_DEPTH) {
+            let batch = first..ranges.len().min(first + URING_QUEUE_DEPTH);
+            for index in batch.clone() {
+                let buffer = &mut buffers[index];
+                let read = opcode::Read::new(fd, buffer.as_mut_ptr(), buffer.len() as u32)
+                    .offset(ranges[index].start)
+                    .build()
+                    .user_data(index as u64);
+                // SAFETY: the buffer is neither moved nor dropped before its read completes,
+                // as every read of the batch is waited for below.
+                unsafe { self.ring.submission().push(&read) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+
+            // Every read must complete before returning, even after an error, as the kernel
+            // writes to the buffers until then.
+            let mut completed = 0;
+            let mut error = None;
+            while completed < batch.len() {
+                self.ring.submit_and_wait(batch.len() - completed)?;
+                let results: Vec<_> = self
+                    .ring
+                    .completion()
+                    .map(|entry| (entry.user_data() as usize, entry.result()))
+                    .collect();
+                for (index, result) in results {
+                    completed += 1;
+                    if result < 0 {
+                        error.get_or_insert(io::Error::from_raw_os_error(-result));
+                        continue;
+                    }
+                    // Reads may be short, in which case the rest is read synchronously.
+                    let read = result as usize;
+                    if read < buffers[index].len() {
+                        let offset = ranges[index].start + read as u64;
+                        if let Err(short_read_error) =
+                            read_exact_at(&self.file, &mut buffers[index][read..], offset)
+                        {
+                            error.get_or_insert(short_read_error);
+                        }
+                    }
+                }
+            }
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+
+        Ok(buffers)
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Reads ahead the blocks a whole batch of the Delta needs, in file order.
+///
+/// The blocks needed by the next `cache_size` bytes of the recreated file are sorted, blocks used
+/// several times are read once, and close blocks are read together. Random reads in Delta order
+/// become a few sequential reads, which spinning disks and network filesystems serve much faster.
+pub struct ReadAheadBasis<R> {
+    inner: R,
+    cache_size: u64,
+}
+
+impl<R: BasisReader> ReadAheadBasis<R> {
+    /// Reads through `inner`, with at most about `cache_size` bytes of blocks read ahead.
+    pub fn new(inner: R, cache_size: u64) -> Self {
+        Self {
+            inner,
+            cache_size: cache_size.max(1),
+        }
+    }
+}
+
+impl<R: BasisReader> BasisReader for ReadAheadBasis<R> {
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn batch_size(&self) -> u64 {
+        self.cache_size
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        let mut sorted = ranges.to_vec();
+        sorted.sort_by_key(|range| range.start);
+        let mut spans: Vec<Range<u64>> = Vec::new();
+        for range in sorted {
+            match spans.last_mut() {
+                Some(last) if range.start <= last.end + READ_AHEAD_MAX_GAP => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => spans.push(range),
+            }
+        }
+
+        let cache = self.inner.read_ranges(&spans)?;
+        Ok(ranges
+            .iter()
+            .map(|range| {
+                let span = spans.partition_point(|span| span.start <= range.start) - 1;
+                let start = (range.start - spans[span].start) as usize;
+                let end = (range.end - spans[span].start) as usize;
+                cache[span][start..end].to_vec()
+            })
+            .collect())
+    }
+
+    fn file(&self) -> Option<&File> {
+        self.inner.file()
+    }
+}
+
+/// Blocks of a basis file kept in memory across patches, the least recently used dropped first.
+///
+/// A program applying many Deltas to the same large basis file (e.g. a server) shares one cache
+/// between them through `CachedBasis`, so the regions most Deltas reuse are only read once.
+/// Cached blocks are aligned regions of `block_size` bytes of the basis file, whatever the chunk
+/// size of the Deltas. The cache does not know when the basis file changes: `clear` it then.
+#[derive(Debug)]
+pub struct BlockCache {
+    block_size: u64,
+    /// Blocks kept at most.
+    capacity: usize,
+    state: Mutex<CachedBlocks>,
+}
+
+#[derive(Debug, Default)]
+struct CachedBlocks {
+    /// Content of each block kept, and when it was last used.
+    blocks: HashMap<u64, (Bytes, u64)>,
+    /// The block last used at each time, the oldest use first.
+    last_uses: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: BlockCacheStats,
+}
+
+/// How many blocks were found in a BlockCache, and how many had to be read.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BlockCache {
+    /// Creates a cache of about `capacity` bytes, in blocks of `block_size` bytes.
+    /// At least one block is kept.
+    pub fn new(block_size: u64, capacity: u64) -> Self {
+        let block_size = block_size.max(1);
+        Self {
+            block_size,
+            capacity: (capacity / block_size).max(1) as usize,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        self.lock().stats
+    }
+
+    /// Drops every block, e.g. because the basis file changed.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.blocks.clear();
+        state.last_uses.clear();
+    }
+
+    // Blocks hold no invariant a panic could break, so a poisoned lock is still usable.
+    fn lock(&self) -> std::sync::MutexGuard<'_, CachedBlocks> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get(&self, index: u64) -> Option<Bytes> {
+        let mut state = self.lock();
+        state.clock += 1;
+        let now = state.clock;
+        let Some((block, last_use)) = state.blocks.get_mut(&index) else {
+            state.stats.misses += 1;
+            return None;
+        };
+        let (block, previous_use) = (block.clone(), std::mem::replace(last_use, now));
+        state.last_uses.remove(&previous_use);
+        state.last_uses.insert(now, index);
+        state.stats.hits += 1;
+        Some(block)
+    }
+
+    fn insert(&self, index: u64, block: Bytes) {
+        let mut state = self.lock();
+        state.clock += 1;
+        let now = state.clock;
+        if let Some((_, previous_use)) = state.blocks.insert(index, (block, now)) {
+            state.last_uses.remove(&previous_use);
+        }
+        state.last_uses.insert(now, index);
+        while state.blocks.len() > self.capacity {
+            let Some((_, oldest)) = state.last_uses.pop_first() else {
+                break;
+            };
+            state.blocks.remove(&oldest);
+        }
+    }
+}
+
+/// Reads blocks of the basis file through a BlockCache, reading only those it does not have.
+pub struct CachedBasis<R> {
+    inner: R,
+    cache: Arc<BlockCache>,
+}
+
+impl<R: BasisReader> CachedBasis<R> {
+    pub fn new(inner: R, cache: Arc<BlockCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+impl<R: BasisReader> BasisReader for CachedBasis<R> {
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn batch_size(&self) -> u64 {
+        self.inner.batch_size()
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        let block_size = BlockSize::new(self.cache.block_size as usize);
+        let length = self.inner.len();
+        let mut blocks = HashMap::new();
+        let mut missing = BTreeSet::new();
+        for range in ranges {
+            for index in block_size.block_of(range.start)..block_size.block_count(range.end) {
+                if blocks.contains_key(&index) || missing.contains(&index) {
+                    continue;
+                }
+                match self.cache.get(index) {
+                    Some(block) => {
+                        blocks.insert(index, block);
+                    }
+                    None => {
+                        missing.insert(index);
+                    }
+                }
+            }
+        }
+
+        // Consecutive missing blocks are read together.
+        let mut spans: Vec<Range<u64>> = Vec::new();
+        for &index in &missing {
+            match spans.last_mut() {
+                Some(last) if last.end == index => last.end += 1,
+                _ => spans.push(index..index + 1),
+            }
+        }
+        let reads: Vec<_> = spans
+            .iter()
+            .map(|span| block_size.start_of(span.start)..length.min(block_size.start_of(span.end)))
+            .collect();
+        for (span, content) in spans.into_iter().zip(self.inner.read_ranges(&reads)?) {
+            let content = Bytes::from(content);
+            for index in span.clone() {
+                let start = block_size.start_of(index - span.start) as usize;
+                let end = content.len().min(start + block_size.get() as usize);
+                let block = content.slice(start..end);
+                self.cache.insert(index, block.clone());
+                blocks.insert(index, block);
+            }
+        }
+
+        Ok(ranges
+            .iter()
+            .map(|range| {
+                let mut bytes = Vec::with_capacity((range.end - range.start) as usize);
+                let mut offset = range.start;
+                while offset < range.end {
+                    let index = block_size.block_of(offset);
+                    let block_start = offset - block_size.offset_in_block(offset);
+                    let end = range.end.min(block_start + block_size.get());
+                    bytes.extend_from_slice(
+                        &blocks[&index]
+                            [(offset - block_start) as usize..(end - block_start) as usize],
+                    );
+                    offset = end;
+                }
+                bytes
+            })
+            .collect())
+    }
+
+    fn file(&self) -> Option<&File> {
+        self.inner.file()
+    }
+}
+
+/// Opens the basis file with the given backend.
+///
+/// # Arguments
+/// * `path` - The basis file.
+/// * `io` - How its blocks are read.
+///
+pub fn open_basis_reader(path: &Path, io: BasisIo) -> eyre::Result<Box<dyn BasisReader>> {
+    if io != BasisIo::Memory {
+        io_utils::ensure_regular_file(path).suggestion(
+            "Use `--basis-io memory`, which reads the basis file once, from start to end.",
+        )?;
+    }
+    match io {
+        BasisIo::Memory => Ok(Box::new(io_utils::attempt_to_read_file(path)?)),
+        BasisIo::Positioned => Ok(Box::new(PositionedBasis::open(path)?)),
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        BasisIo::IoUring => Ok(Box::new(UringBasis::open(path)?)),
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        BasisIo::IoUring => Err(eyre::eyre!(
+            "The io-uring backend is only available on Linux, when built with the `io-uring` feature"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn positioned_reads_return_ranges_in_order() {
+        let path = scratch_directory("positioned_basis").join("basis_file");
+        std::fs::write(&path, "ABCDEFGHIJ").unwrap();
+
+        let mut basis = PositionedBasis::open(&path).unwrap();
+        let blocks = basis.read_ranges(&[6..9, 0..2, 9..10]).unwrap();
+
+        assert_eq!(basis.len(), 10);
+        assert_eq!(blocks, vec![b"GHI".to_vec(), b"AB".to_vec(), b"J".to_vec()]);
+    }
+
+    // Remembers the reads it was asked for.
+    struct RecordingReader {
+        content: Bytes,
+        reads: Vec<Range<u64>>,
+    }
+
+    impl BasisReader for RecordingReader {
+        fn len(&self) -> u64 {
+            self.content.len() as u64
+        }
+
+        fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+            self.reads.extend_from_slice(ranges);
+            self.content.read_ranges(ranges)
+        }
+    }
+
+    #[test]
+    fn read_ahead_coalesces_reads_in_file_order() {
+        let far = READ_AHEAD_MAX_GAP + 100;
+        let mut content = vec![b'.'; far as usize + 10];
+        content[..8].copy_from_slice(b"AAAABBBB");
+        content[far as usize..far as usize + 4].copy_from_slice(b"CCCC");
+        let inner = RecordingReader {
+            content: Bytes::from(content),
+            reads: Vec::new(),
+        };
+
+        let mut basis = ReadAheadBasis::new(inner, 1024);
+        let blocks = basis
+            .read_ranges(&[4..8, far..far + 4, 0..4, 4..8])
+            .unwrap();
+
+        assert_eq!(
+            blocks,
+            vec![
+                b"BBBB".to_vec(),
+                b"CCCC".to_vec(),
+                b"AAAA".to_vec(),
+                b"BBBB".to_vec()
+            ]
+        );
+        assert_eq!(basis.inner.reads, vec![0..8, far..far + 4]);
+        assert_eq!(basis.batch_size(), 1024);
+    }
+
+    #[test]
+    fn cached_blocks_are_only_read_once_across_readers() {
+        let cache = Arc::new(BlockCache::new(4, 8));
+        let reader = || RecordingReader {
+            content: Bytes::from("AAAABBBBCCCCDD"),
+            reads: Vec::new(),
+        };
+
+        let mut first = CachedBasis::new(reader(), cache.clone());
+        let blocks = first.read_ranges(&[2..6, 12..14]).unwrap();
+        assert_eq!(blocks, vec![b"AABB".to_vec(), b"DD".to_vec()]);
+        assert_eq!(first.inner.reads, vec![0..8, 12..14]);
+
+        // Only two blocks are kept, and the first one was used least recently.
+        let mut second = CachedBasis::new(reader(), cache.clone());
+        let blocks = second.read_ranges(&[12..14, 4..12]).unwrap();
+        assert_eq!(blocks, vec![b"DD".to_vec(), b"BBBBCCCC".to_vec()]);
+        assert_eq!(second.inner.reads, vec![8..12]);
+        assert_eq!(cache.stats(), BlockCacheStats { hits: 2, misses: 4 });
+    }
+}
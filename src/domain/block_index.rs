@@ -0,0 +1,185 @@
+use bytes::Bytes;
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    artifact_version, calculate_strong_hash, check_chunk_size, compute_fixed_delta,
+    compute_sliding_rolling_hashes_in_parallel, decode_artifact, encode_artifact,
+    hashes_files_with_siphash, ArtifactKind, Delta, FileSignature, MatchingOptions, Parallelism,
+    WeakHash,
+};
+use crate::help::Help;
+
+/// The rolling hash of every window of an updated file, computed once and saved, so Deltas of
+/// that file are computed against many Signatures without hashing it again.
+///
+/// A server distributing a new release to clients holding many different older versions receives
+/// a Signature from each of them. Rolling every window of the release is most of the work of each
+/// Delta in fixed mode, and only depends on the chunk size and the weak hash, which the clients'
+/// Signatures mostly share: matching is all that is left per client.
+///
+/// The index takes 8 bytes per byte of the file, so it is meant for files sent many times.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BlockIndex {
+    pub chunk_size: usize,
+    pub weak_hash: WeakHash,
+    pub file_size: u64,
+    /// Whole-file strong hash of the file indexed, to refuse any other.
+    pub file_hash: u64,
+    /// One per window of `chunk_size` bytes, by starting byte.
+    rolling_hashes: Vec<u64>,
+}
+
+impl BlockIndex {
+    /// Indexes `updated_file`, for Signatures with blocks of `chunk_size` bytes rolled with
+    /// `weak_hash`.
+    pub fn compute(
+        updated_file: &[u8],
+        chunk_size: usize,
+        weak_hash: WeakHash,
+        parallelism: &Parallelism,
+    ) -> eyre::Result<Self> {
+        check_chunk_size(chunk_size)?;
+
+        Ok(Self {
+            chunk_size,
+            weak_hash,
+            file_size: updated_file.len() as u64,
+            file_hash: calculate_strong_hash(updated_file),
+            rolling_hashes: compute_sliding_rolling_hashes_in_parallel(
+                updated_file,
+                chunk_size,
+                weak_hash,
+                parallelism,
+            ),
+        })
+    }
+
+    /// Whether this indexes `updated_file`, and not another version of it.
+    pub fn indexes(&self, updated_file: &[u8]) -> bool {
+        updated_file.len() as u64 == self.file_size
+            && calculate_strong_hash(updated_file) == self.file_hash
+    }
+
+    /// The rolling hash of each window of the file, as `compute_sliding_rolling_hashes` computes
+    /// them.
+    pub fn rolling_hashes(&self) -> &[u64] {
+        &self.rolling_hashes
+    }
+
+    /// Fails unless Deltas against `signature` can be computed from this index: its blocks must
+    /// be rolled with the same weak hash, and have the same size if it records it.
+    pub fn check_signature(&self, signature: &FileSignature) -> eyre::Result<()> {
+        if signature.weak_hash != self.weak_hash {
+            return Err(eyre!(
+                "Signature rolls blocks with {}, but the Block Index with {}",
+                signature.weak_hash,
+                self.weak_hash
+            ))
+            .suggestion("Index the updated file with the weak hash of the Signature.");
+        }
+        let signature_chunk_size = signature.basis.and_then(|layout| layout.chunk_size());
+        if signature_chunk_size.is_some_and(|chunk_size| chunk_size != self.chunk_size as u64) {
+            return Err(eyre!(
+                "Signature has blocks of {} bytes, but the Block Index of {} bytes",
+                signature_chunk_size.unwrap_or_default(),
+                self.chunk_size
+            ))
+            .suggestion("Index the updated file with the chunk size of the Signature.");
+        }
+
+        Ok(())
+    }
+
+    /// Computes the Delta of the indexed file against `signature`, in fixed mode.
+    ///
+    /// `updated_file` must be the file indexed, as `indexes` tells: it is not checked again for
+    /// each Signature. Fails if `check_signature` does.
+    pub fn compute_delta(
+        &self,
+        signature: &FileSignature,
+        updated_file: &[u8],
+        options: &MatchingOptions,
+    ) -> eyre::Result<Delta> {
+        self.check_signature(signature)?;
+
+        Ok(compute_fixed_delta(
+            signature,
+            updated_file,
+            self.chunk_size,
+            &self.rolling_hashes,
+            options,
+        ))
+    }
+}
+
+impl TryFrom<BlockIndex> for Bytes {
+    type Error = eyre::Report;
+
+    fn try_from(index: BlockIndex) -> Result<Self, Self::Error> {
+        encode_artifact(ArtifactKind::BlockIndex, &index)
+    }
+}
+
+impl TryFrom<Bytes> for BlockIndex {
+    type Error = eyre::Report;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        if artifact_version(&bytes).is_some_and(hashes_files_with_siphash) {
+            return Err(eyre!(
+                "Block Index of format version 1 hashes the updated file with SipHash, which this version does not check"
+            ))
+            .suggestion("Compute it again with `index` from the updated file.");
+        }
+        let index = decode_artifact(ArtifactKind::BlockIndex, bytes)
+            .wrap_err("Could not read Block Index from file provided.")
+            .suggestion(
+                "Did you provide the correct path for the Block Index file?\n\
+                         It must have been generated as an output from a previous `index` command.",
+            )?;
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{apply_delta, compute_delta_to_our_file, compute_signature};
+
+    use super::*;
+
+    #[test]
+    fn deltas_from_the_index_are_the_deltas_of_the_file() {
+        let updated_file = Bytes::from("the new release, with some new content in the middle");
+        let index = BlockIndex::compute(
+            &updated_file,
+            4,
+            WeakHash::Polynomial,
+            &Parallelism::serial(),
+        )
+        .unwrap();
+        let index = BlockIndex::try_from(Bytes::try_from(index).unwrap()).unwrap();
+        assert!(index.indexes(&updated_file));
+        assert!(!index.indexes(b"another release"));
+
+        for basis_file in ["the old release", "the new release, with some content"] {
+            let basis_file = Bytes::from(basis_file);
+            let signature = compute_signature(basis_file.clone(), 4);
+            let delta = index
+                .compute_delta(&signature, &updated_file, &MatchingOptions::default())
+                .unwrap();
+
+            assert_eq!(
+                delta,
+                compute_delta_to_our_file(signature.clone(), updated_file.clone(), 4)
+            );
+            assert_eq!(apply_delta(basis_file, delta, 4).unwrap(), updated_file);
+        }
+        assert!(index
+            .compute_delta(
+                &compute_signature(Bytes::from("the old release"), 8),
+                &updated_file,
+                &MatchingOptions::default()
+            )
+            .is_err());
+    }
+}
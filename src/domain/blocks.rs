@@ -0,0 +1,129 @@
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::domain::{compute_signature_in_parallel, ChunkingMode, Parallelism};
+
+/// A block of a file, with the hashes a Signature holds for it.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Copy)]
+pub struct BlockEntry {
+    pub index: usize,
+    /// Where the block starts in the file.
+    pub offset: usize,
+    pub length: usize,
+    /// Rolling hash of the block, used to find candidate matches.
+    pub rolling: u64,
+    /// Strong hash of the block (its first 64 bits), used to confirm them.
+    pub strong: u64,
+}
+
+/// Every block of a file, in order, so other tools can use our hashes without linking against us.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Default)]
+pub struct BlockList {
+    pub entries: Vec<BlockEntry>,
+}
+
+impl BlockList {
+    /// Serializes the list as CSV, with one row per block.
+    #[cfg(feature = "csv")]
+    pub fn to_csv(&self) -> eyre::Result<Bytes> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(["index", "offset", "length", "rolling", "strong"])?;
+        for entry in &self.entries {
+            writer.write_record([
+                entry.index.to_string(),
+                entry.offset.to_string(),
+                entry.length.to_string(),
+                entry.rolling.to_string(),
+                entry.strong.to_string(),
+            ])?;
+        }
+
+        Ok(writer.into_inner()?.into())
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> eyre::Result<Bytes> {
+        let serialized = serde_json::to_vec_pretty(&self.entries)?;
+        Ok(serialized.into())
+    }
+}
+
+/// Lists the blocks of a file, with the same hashes `compute_signature_with_mode` computes.
+///
+/// # Arguments
+/// * `file` - The file to divide into blocks.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How the file is divided into blocks.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn compute_block_list(
+    file: &Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    parallelism: &Parallelism,
+) -> BlockList {
+    let signature = compute_signature_in_parallel(file.clone(), chunk_size, mode, parallelism);
+    let hashes = signature
+        .rolling_hashes
+        .into_iter()
+        .zip(signature.strong_hashes);
+
+    let mut offset = 0;
+    let entries = mode
+        .split(file, chunk_size)
+        .into_iter()
+        .zip(hashes)
+        .enumerate()
+        .map(|(index, (block, (rolling, strong)))| {
+            let entry = BlockEntry {
+                index,
+                offset,
+                length: block.len(),
+                rolling,
+                strong,
+            };
+            offset += block.len();
+            entry
+        })
+        .collect();
+
+    BlockList { entries }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::compute_signature;
+
+    use super::*;
+
+    #[test]
+    fn blocks_have_the_hashes_of_the_signature() {
+        let file = Bytes::from("ABCDEFGHIJ");
+
+        let list = compute_block_list(&file, 4, ChunkingMode::Fixed, &Parallelism::serial());
+
+        let signature = compute_signature(file, 4);
+        let offsets: Vec<_> = list.entries.iter().map(|entry| entry.offset).collect();
+        let lengths: Vec<_> = list.entries.iter().map(|entry| entry.length).collect();
+        let strong: Vec<_> = list.entries.iter().map(|entry| entry.strong).collect();
+        let rolling: Vec<_> = list.entries.iter().map(|entry| entry.rolling).collect();
+        assert_eq!(offsets, vec![0, 4, 8]);
+        assert_eq!(lengths, vec![4, 4, 2]);
+        assert_eq!(strong, signature.strong_hashes);
+        assert_eq!(rolling, signature.rolling_hashes);
+    }
+
+    #[test]
+    #[cfg(feature = "csv")]
+    fn csv_has_a_row_per_block() {
+        let file = Bytes::from("one\ntwo\n");
+
+        let list = compute_block_list(&file, 0, ChunkingMode::Lines, &Parallelism::serial());
+        let csv = list.to_csv().unwrap();
+
+        let rows: Vec<_> = std::str::from_utf8(&csv).unwrap().lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], "index,offset,length,rolling,strong");
+        assert!(rows[2].starts_with("1,4,4,"));
+    }
+}
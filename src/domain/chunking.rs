@@ -0,0 +1,220 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The largest `chunk_size` accepted for fixed blocks: 1 GiB.
+///
+/// A file held in memory is at most `isize::MAX` bytes long, so adding a chunk size to any offset
+/// in it (as the matcher does to find where a block ends) cannot overflow a `usize`, even on
+/// 32-bit targets. Block lengths also fit in a `u32`.
+pub const MAX_CHUNK_SIZE: usize = 1 << 30;
+
+/// Why a `chunk_size` is refused.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChunkSizeError {
+    /// Fixed blocks must not be empty.
+    Zero,
+    /// Larger than `MAX_CHUNK_SIZE`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for ChunkSizeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkSizeError::Zero => write!(f, "Fixed blocks must not be empty"),
+            ChunkSizeError::TooLarge(chunk_size) => write!(
+                f,
+                "Blocks of {chunk_size} bytes are larger than the largest supported, \
+                 {MAX_CHUNK_SIZE} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChunkSizeError {}
+
+/// Checks `chunk_size` can be used for fixed blocks.
+pub fn check_chunk_size(chunk_size: usize) -> Result<(), ChunkSizeError> {
+    match chunk_size {
+        0 => Err(ChunkSizeError::Zero),
+        chunk_size if chunk_size > MAX_CHUNK_SIZE => Err(ChunkSizeError::TooLarge(chunk_size)),
+        _ => Ok(()),
+    }
+}
+
+/// Index math on fixed blocks of a given size: which block an offset is in, where a block starts.
+///
+/// Divisions and remainders are what this math costs most. When the size is a power of two (128
+/// bytes rather than 100, say), they are shifts and masks instead, picked once when the BlockSize
+/// is made, so any size works and powers of two are simply faster.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BlockSize {
+    size: u64,
+    /// log2 of the size, if it is a power of two.
+    shift: Option<u32>,
+}
+
+impl BlockSize {
+    /// Blocks of `chunk_size` bytes, which `check_chunk_size` must accept.
+    pub fn new(chunk_size: usize) -> Self {
+        debug_assert!(chunk_size > 0, "Fixed blocks must not be empty");
+        let size = chunk_size as u64;
+        Self {
+            size,
+            shift: size.is_power_of_two().then(|| size.trailing_zeros()),
+        }
+    }
+
+    pub fn get(self) -> u64 {
+        self.size
+    }
+
+    pub fn is_power_of_two(self) -> bool {
+        self.shift.is_some()
+    }
+
+    /// Index of the block holding the byte at `offset`.
+    #[inline]
+    pub fn block_of(self, offset: u64) -> u64 {
+        match self.shift {
+            Some(shift) => offset >> shift,
+            None => offset / self.size,
+        }
+    }
+
+    /// Offset of the byte at `offset` within its block.
+    #[inline]
+    pub fn offset_in_block(self, offset: u64) -> u64 {
+        match self.shift {
+            Some(_) => offset & (self.size - 1),
+            None => offset % self.size,
+        }
+    }
+
+    /// Offset of the first byte of block `block`.
+    #[inline]
+    pub fn start_of(self, block: u64) -> u64 {
+        match self.shift {
+            Some(shift) => block << shift,
+            None => block * self.size,
+        }
+    }
+
+    /// Blocks a file of `length` bytes has, the last one possibly shorter.
+    #[inline]
+    pub fn block_count(self, length: u64) -> u64 {
+        self.block_of(length + self.size - 1)
+    }
+
+    /// The first block boundary at or after `offset`.
+    #[inline]
+    pub fn align_up(self, offset: u64) -> u64 {
+        self.start_of(self.block_count(offset))
+    }
+}
+
+/// How a file is divided into blocks.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ChunkingMode {
+    /// Blocks of exactly `chunk_size` bytes (except possibly the last one).
+    #[default]
+    Fixed,
+    /// Each line (including its trailing newline) is a block. `chunk_size` is not used.
+    Lines,
+}
+
+impl ChunkingMode {
+    /// Splits `file` into blocks, in order.
+    pub fn split<'a>(&self, file: &'a [u8], chunk_size: usize) -> Vec<&'a [u8]> {
+        match self {
+            ChunkingMode::Fixed => file.chunks(chunk_size).collect(),
+            ChunkingMode::Lines => file.split_inclusive(|&byte| byte == b'\n').collect(),
+        }
+    }
+
+    /// Checks `chunk_size` can be used in this mode. Lines do not use it.
+    pub fn check_chunk_size(&self, chunk_size: usize) -> Result<(), ChunkSizeError> {
+        match self {
+            ChunkingMode::Fixed => check_chunk_size(chunk_size),
+            ChunkingMode::Lines => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for ChunkingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkingMode::Fixed => write!(f, "fixed"),
+            ChunkingMode::Lines => write!(f, "lines"),
+        }
+    }
+}
+
+impl FromStr for ChunkingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(ChunkingMode::Fixed),
+            "lines" => Ok(ChunkingMode::Lines),
+            _ => Err(format!(
+                r#""{s}" is not a chunking mode. Expected "fixed" or "lines""#
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_mode_keeps_newlines_in_blocks() {
+        let file = b"first\nsecond\n\nlast";
+
+        let blocks = ChunkingMode::Lines.split(file, 10);
+
+        let expected: Vec<&[u8]> = vec![b"first\n", b"second\n", b"\n", b"last"];
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn fixed_mode_uses_chunk_size() {
+        let file = b"first\nsecond\n";
+
+        let blocks = ChunkingMode::Fixed.split(file, 5);
+
+        let expected: Vec<&[u8]> = vec![b"first", b"\nseco", b"nd\n"];
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn powers_of_two_do_the_same_math() {
+        for size in [1, 3, 100, 128, 4096] {
+            let blocks = BlockSize::new(size);
+            assert_eq!(blocks.is_power_of_two(), size != 3 && size != 100);
+            for offset in [0, 1, 99, 100, 127, 128, 5000, 1 << 40] {
+                let size = size as u64;
+                assert_eq!(blocks.block_of(offset), offset / size);
+                assert_eq!(blocks.offset_in_block(offset), offset % size);
+                assert_eq!(blocks.start_of(offset), offset * size);
+                assert_eq!(blocks.block_count(offset), offset.div_ceil(size));
+                assert_eq!(blocks.align_up(offset), offset.div_ceil(size) * size);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_are_checked_in_fixed_mode_only() {
+        assert_eq!(check_chunk_size(0), Err(ChunkSizeError::Zero));
+        assert_eq!(check_chunk_size(1), Ok(()));
+        assert_eq!(check_chunk_size(MAX_CHUNK_SIZE), Ok(()));
+        assert_eq!(
+            check_chunk_size(usize::MAX),
+            Err(ChunkSizeError::TooLarge(usize::MAX))
+        );
+        assert_eq!(ChunkingMode::Lines.check_chunk_size(0), Ok(()));
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Range;
+
+use crate::domain::FileSignature;
+
+/// The result of comparing two Signatures block by block.
+///
+/// Lets users check whether two copies of a large file diverged (and where) by exchanging only
+/// their Signatures. Both Signatures must have been computed with the same chunk size and mode.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SignatureComparison {
+    pub blocks_in_first: usize,
+    pub blocks_in_second: usize,
+    /// Runs of block indexes whose hashes differ, in order.
+    pub differing_blocks: Vec<Range<usize>>,
+}
+
+impl SignatureComparison {
+    pub fn is_equal(&self) -> bool {
+        self.differing_blocks.is_empty()
+    }
+
+    /// Byte ranges of the differing blocks, given the chunk size both Signatures were computed with.
+    /// The last range may extend past the end of the shorter file.
+    pub fn differing_byte_ranges(&self, chunk_size: usize) -> Vec<Range<usize>> {
+        self.differing_blocks
+            .iter()
+            .map(|blocks| blocks.start * chunk_size..blocks.end * chunk_size)
+            .collect()
+    }
+}
+
+impl fmt::Display for SignatureComparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Blocks: {} and {}",
+            self.blocks_in_first, self.blocks_in_second
+        )?;
+        writeln!(f, "Equal: {}", if self.is_equal() { "yes" } else { "no" })?;
+        let differing_blocks: Vec<_> = self
+            .differing_blocks
+            .iter()
+            .map(|blocks| format!("{}..{}", blocks.start, blocks.end))
+            .collect();
+        write!(f, "Differing blocks: [{}]", differing_blocks.join(", "))
+    }
+}
+
+/// Compares two Signatures block by block.
+///
+/// Two blocks are considered equal if both their rolling and strong hashes are equal. Rolling
+/// hashes are only compared when both Signatures were computed with the same weak hash, and
+/// Signatures computed with different strong hashes have no equal blocks.
+/// Blocks which only exist in the longer Signature are reported as differing.
+///
+/// # Arguments
+/// * `first` - The Signature of one copy of the file.
+/// * `second` - The Signature of the other copy.
+///
+pub fn compare_signatures(first: &FileSignature, second: &FileSignature) -> SignatureComparison {
+    let blocks_in_first = first.strong_hashes.len();
+    let blocks_in_second = second.strong_hashes.len();
+
+    let mut differing_blocks: Vec<Range<usize>> = Vec::new();
+    for index in 0..blocks_in_first.max(blocks_in_second) {
+        let is_equal = index < blocks_in_first.min(blocks_in_second)
+            && first.strong_hash == second.strong_hash
+            && first.strong_hashes[index] == second.strong_hashes[index]
+            && first.strong_hash_tail(index) == second.strong_hash_tail(index)
+            && (first.weak_hash != second.weak_hash
+                || first.rolling_hashes[index] == second.rolling_hashes[index]);
+        if is_equal {
+            continue;
+        }
+        // Adjacent differing blocks are merged into a single run.
+        match differing_blocks.last_mut() {
+            Some(run) if run.end == index => run.end += 1,
+            _ => differing_blocks.push(index..index + 1),
+        }
+    }
+
+    SignatureComparison {
+        blocks_in_first,
+        blocks_in_second,
+        differing_blocks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::compute_signature;
+
+    use super::*;
+
+    #[test]
+    fn equal_files_have_equal_signatures() {
+        let test_chunk_size = 3;
+        let file = Bytes::from("ABCDEFGHI");
+
+        let comparison = compare_signatures(
+            &compute_signature(file.clone(), test_chunk_size),
+            &compute_signature(file, test_chunk_size),
+        );
+
+        assert!(comparison.is_equal());
+        assert_eq!(comparison.blocks_in_first, 3);
+    }
+
+    #[test]
+    fn differing_blocks_are_merged_into_runs() {
+        let test_chunk_size = 3;
+        let first = compute_signature(Bytes::from("ABCDEFGHIJKL"), test_chunk_size);
+        let second = compute_signature(Bytes::from("ABCxxxxxIJKLMN"), test_chunk_size);
+
+        let comparison = compare_signatures(&first, &second);
+
+        assert_eq!(comparison.differing_blocks, vec![1..3, 4..5]);
+        assert_eq!(
+            comparison.differing_byte_ranges(test_chunk_size),
+            vec![3..9, 12..15]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! What this build guarantees about the artifacts it reads.
+//!
+//! Artifacts of every version in `FORMAT_VERSIONS` are read. Within a version, fields are only
+//! ever added at the end of a structure, optional, and left out when empty, so artifacts written by
+//! older builds are read and written again byte for byte.
+//!
+//! Golden files hold that promise: for each version, known inputs and the artifacts computed for
+//! them once, committed under `tests/golden_files/v<VERSION>`. `check_golden_directory` decodes
+//! every artifact of such a directory, and fails if any is read or written differently.
+
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::Path;
+
+use bytes::Bytes;
+use eyre::{eyre, Context};
+
+use crate::domain::{
+    apply_delta, artifact_kind, ArtifactEncoding, ArtifactKind, BlockIndex, BlockRequest,
+    DeduplicatedSignature, Delta, FileSignature, Manifest, Parallelism, FORMAT_VERSION,
+    PREAMBLE_LENGTH,
+};
+
+// Inputs of a golden directory. Every other file is an artifact computed from them.
+const GOLDEN_BASIS_FILENAME: &str = "basis_file";
+const GOLDEN_UPDATED_FILENAME: &str = "updated_file";
+
+/// The format version of an artifact, if it has a preamble.
+pub fn artifact_version(bytes: &[u8]) -> Option<u16> {
+    artifact_kind(bytes)?;
+    bytes
+        .get(4..6)
+        .map(|version| u16::from_le_bytes([version[0], version[1]]))
+}
+
+/// A golden artifact which was read as expected.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GoldenArtifact {
+    pub filename: String,
+    pub kind: ArtifactKind,
+    pub version: u16,
+    /// Whether it was also written again, byte for byte.
+    pub rewritten: bool,
+}
+
+impl fmt::Display for GoldenArtifact {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of version {}",
+            self.filename,
+            self.kind.name(),
+            self.version
+        )?;
+        if self.rewritten {
+            write!(f, ", written again unchanged")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes every artifact of a golden directory, and checks each against its inputs.
+///
+/// Signatures must hash the blocks of `basis_file` (with both their strong and weak hashes),
+/// Deltas must recreate `updated_file` from it, and Block Indexes and Requests must be of
+/// `updated_file`. Artifacts of the current version must also be written again byte for byte,
+/// except for streamed Deltas, which are only written while being computed.
+///
+/// # Arguments
+/// * `directory` - Holds `basis_file`, `updated_file`, and the artifacts computed for them.
+/// * `chunk_size` - The size for each block the artifacts were computed with, in fixed mode.
+///
+pub fn check_golden_directory(
+    directory: &Path,
+    chunk_size: usize,
+) -> eyre::Result<Vec<GoldenArtifact>> {
+    let read = |filename: &str| -> eyre::Result<Bytes> {
+        Ok(fs::read(directory.join(filename))
+            .wrap_err(format!(
+                "Golden file {filename} is missing from {}",
+                directory.display()
+            ))?
+            .into())
+    };
+    let basis_file = read(GOLDEN_BASIS_FILENAME)?;
+    let updated_file = read(GOLDEN_UPDATED_FILENAME)?;
+
+    let mut filenames: Vec<String> = fs::read_dir(directory)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<eyre::Result<_>>()?;
+    filenames.retain(|filename| {
+        filename != GOLDEN_BASIS_FILENAME && filename != GOLDEN_UPDATED_FILENAME
+    });
+    filenames.sort();
+
+    let mut artifacts = Vec::new();
+    for filename in filenames {
+        let bytes = read(&filename)?;
+        let (kind, version) = artifact_kind(&bytes)
+            .zip(artifact_version(&bytes))
+            .ok_or_else(|| eyre!("Golden file {filename} is not an artifact"))?;
+        let current = version == FORMAT_VERSION;
+        let rewritten = match kind {
+            ArtifactKind::Signature | ArtifactKind::DeduplicatedSignature => {
+                let signature = FileSignature::try_from(bytes.clone())?;
+                let (strong_hashes, rolling_hashes): (Vec<_>, Vec<_>) = basis_file
+                    .chunks(chunk_size)
+                    .map(|block| {
+                        (
+                            signature.strong_hash.hash(block),
+                            signature.weak_hash.hash(block),
+                        )
+                    })
+                    .unzip();
+                if signature.strong_hashes != strong_hashes
+                    || signature.rolling_hashes != rolling_hashes
+                {
+                    return Err(eyre!(
+                        "{filename} does not hash the blocks of the basis file"
+                    ));
+                }
+                let rewritten = match kind {
+                    ArtifactKind::Signature => Bytes::try_from(signature)?,
+                    _ => Bytes::try_from(DeduplicatedSignature::from(&signature))?,
+                };
+                Some(rewritten)
+            }
+            ArtifactKind::Delta => {
+                let streamed = bytes.get(PREAMBLE_LENGTH - 1)
+                    == Some(&(ArtifactEncoding::MessagePackFrames as u8));
+                let delta = Delta::try_from(bytes.clone())?;
+                if apply_delta(basis_file.clone(), delta.clone(), chunk_size)? != updated_file {
+                    return Err(eyre!("{filename} does not recreate the updated file"));
+                }
+                (!streamed).then(|| Bytes::try_from(delta)).transpose()?
+            }
+            ArtifactKind::Manifest => {
+                let manifest = Manifest::try_from(bytes.clone())?;
+                Some(Bytes::try_from(manifest)?)
+            }
+            ArtifactKind::BlockIndex => {
+                let index = BlockIndex::try_from(bytes.clone())?;
+                let expected = BlockIndex::compute(
+                    &updated_file,
+                    index.chunk_size,
+                    index.weak_hash,
+                    &Parallelism::serial(),
+                )?;
+                if index != expected {
+                    return Err(eyre!("{filename} does not index the updated file"));
+                }
+                Some(Bytes::try_from(index)?)
+            }
+            ArtifactKind::BlockRequest => {
+                let request = BlockRequest::try_from(bytes.clone())?;
+                if request.file_size != updated_file.len() as u64 {
+                    return Err(eyre!(
+                        "{filename} does not request blocks of the updated file"
+                    ));
+                }
+                Some(Bytes::try_from(request)?)
+            }
+        };
+        let rewritten = match rewritten.filter(|_| current) {
+            Some(rewritten) if rewritten != bytes => {
+                return Err(eyre!(
+                    "{filename} is read, but not written again byte for byte"
+                ));
+            }
+            rewritten => rewritten.is_some(),
+        };
+        artifacts.push(GoldenArtifact {
+            filename,
+            kind,
+            version,
+            rewritten,
+        });
+    }
+
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{compute_delta_to_our_file, compute_signature};
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn golden_artifacts_which_do_not_match_their_inputs_fail() {
+        let directory = scratch_directory("golden");
+        let basis_file = Bytes::from("0123456789abcdef".repeat(4));
+        let updated_file = Bytes::from("0123456789ABCDEF".repeat(4));
+        fs::write(directory.join("basis_file"), &basis_file).unwrap();
+        fs::write(directory.join("updated_file"), &updated_file).unwrap();
+        let signature = compute_signature(basis_file.clone(), 8);
+        let delta = compute_delta_to_our_file(signature.clone(), updated_file, 8);
+        fs::write(
+            directory.join("signature"),
+            Bytes::try_from(signature).unwrap(),
+        )
+        .unwrap();
+        fs::write(directory.join("delta"), Bytes::try_from(delta).unwrap()).unwrap();
+
+        let artifacts = check_golden_directory(&directory, 8).unwrap();
+        assert_eq!(artifacts.len(), 2);
+        assert!(artifacts.iter().all(|artifact| artifact.rewritten));
+
+        fs::write(directory.join("updated_file"), "something else").unwrap();
+        assert!(check_golden_directory(&directory, 8).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::Write;
+
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+// Files smaller than this are matched whatever they hold: it costs little.
+const MIN_SAMPLED_SIZE: usize = 64 * 1024;
+const SAMPLE_SIZE: usize = 16 * 1024;
+const SAMPLE_COUNT: usize = 8;
+// Samples deflated to more than this fraction of their size are taken as incompressible.
+const INCOMPRESSIBLE_RATIO: f64 = 0.95;
+
+/// Whether `file` looks already compressed (or encrypted), from how much samples of it shrink when
+/// deflated.
+///
+/// Two versions of such a file rarely share blocks even if little changed in what they hold, so
+/// looking for blocks of the basis file in it burns CPU for nothing. Only a few samples spread
+/// over the file are deflated, so this is cheap even for large files.
+pub fn looks_compressed(file: &[u8]) -> bool {
+    if file.len() < MIN_SAMPLED_SIZE {
+        return false;
+    }
+    // Samples must not overlap, or deflate would shrink the bytes they share.
+    let sample_size = SAMPLE_SIZE.min(file.len() / SAMPLE_COUNT);
+    let step = (file.len() - sample_size) / (SAMPLE_COUNT - 1);
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    for sample in 0..SAMPLE_COUNT {
+        let start = sample * step;
+        // Writing into a Vec never fails.
+        encoder
+            .write_all(&file[start..start + sample_size])
+            .unwrap();
+    }
+    let deflated_size = encoder.finish().unwrap().len();
+
+    deflated_size as f64 > (sample_size * SAMPLE_COUNT) as f64 * INCOMPRESSIBLE_RATIO
+}
+
+/// The transform which would reveal the similarities of a compressed container, judging by the
+/// extension of the file at `path`, if there is one.
+pub fn container_transform(path: &str) -> Option<&'static str> {
+    // Zip archives under other names.
+    const ZIP_EXTENSIONS: [&str; 10] = [
+        "zip", "jar", "apk", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+    ];
+    let (_, extension) = path.rsplit_once('.')?;
+    let is_zip = ZIP_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str());
+
+    (cfg!(feature = "archive") && is_zip).then_some("archive")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bytes of xorshift64, which deflate can not shrink.
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_files_look_compressed() {
+        assert!(looks_compressed(&random_bytes(200_000)));
+        assert!(looks_compressed(&random_bytes(MIN_SAMPLED_SIZE)));
+        assert!(!looks_compressed(&b"Some text, repeated.\n".repeat(10_000)));
+        // Too small to be worth sampling.
+        assert!(!looks_compressed(&random_bytes(1000)));
+    }
+}
@@ -1,51 +1,366 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
 
 use bytes::Bytes;
-use color_eyre::eyre::Context;
-use color_eyre::Help;
-use rolling_hash_rust::RollingHash;
-use serde::{Deserialize, Serialize};
+use eyre::{eyre, Context};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use xxhash_rust::xxh3::Xxh3Default;
 
-use crate::domain::{calculate_strong_hash, FileSignature};
+use crate::domain::optimal::{self, stream_optimal_delta, LITERAL_RUN_COST};
+use crate::domain::streaming::read_delta_frames;
+use crate::domain::{
+    artifact_version, calculate_strong_hash, check_chunk_size, encode_artifact,
+    hashes_files_with_siphash, read_preamble, ArtifactEncoding, ArtifactKind, BasisLayout,
+    BlockSize, ChunkingMode, DeltaMetadata, FileSignature, Parallelism, TextNormalization,
+    TokenBuffer, Tokens, WeakHash,
+};
+use crate::help::Help;
+use crate::profiling::{self, Section};
+use crate::progress;
+
+// Tokens are part of the core which builds without the standard library.
+pub use crate::embedded::Token;
 
 /// Represents how to transform the basis file into the updated file, in order.
 ///
 /// The updated file can be reconstructed by reusing some of the basis file blocks
 /// (through a BlockIndex), or by writing (new) byte literals.
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone, Default)]
 pub struct Delta {
-    pub(crate) content: Vec<Token>,
+    pub header: DeltaHeader,
+    pub(crate) content: TokenBuffer,
+}
+
+impl Delta {
+    /// The tokens of the Delta, in order.
+    pub fn tokens(&self) -> Tokens<'_> {
+        self.content.iter()
+    }
+
+    /// Reads a Delta from `bytes`, as `Delta::try_from` does, doing what `unknown_tokens` says
+    /// with tokens of kinds this build does not know.
+    pub fn decode(bytes: Bytes, unknown_tokens: UnknownTokens) -> eyre::Result<Self> {
+        let delta = decode_delta(bytes)
+            .wrap_err("Could not read Delta from file provided.")
+            .suggestion(
+                "Did you provide the correct path for the Delta file?\n\
+                         It must have been generated as an output from a previous `delta` command.",
+            )?;
+        unknown_tokens.check(delta)
+    }
+}
+
+/// What reading a Delta does with tokens of kinds added after this build.
+///
+/// Tokens are skipped whole whatever their kind, so the others can still be read. But a Delta
+/// missing tokens recreates the wrong file unless they wrote nothing (hints for the reader, say),
+/// so only readers checking the recreated file against the Delta's digest should skip them.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum UnknownTokens {
+    /// Refuse the Delta, telling how many tokens were not understood.
+    #[default]
+    Deny,
+    /// Leave them out, and apply the others.
+    Skip,
+}
+
+impl UnknownTokens {
+    /// Returns `delta`, unless it had unknown tokens it must be refused for.
+    pub fn check(self, delta: Delta) -> eyre::Result<Delta> {
+        let unknown = delta.content.unknown_tokens();
+        if unknown > 0 && self == UnknownTokens::Deny {
+            return Err(eyre!(
+                "Delta has {unknown} tokens of kinds this version does not know"
+            ))
+            .suggestion("The Delta was computed by a newer version: upgrade to apply it.");
+        }
+        Ok(delta)
+    }
+}
+
+/// Information needed to apply a Delta, besides its tokens.
+///
+/// Later versions may add fields, so other crates build headers by setting those of
+/// `DeltaHeader::default()`.
+#[derive(Debug, Eq, PartialEq, Deserialize, Clone, Default)]
+#[non_exhaustive]
+pub struct DeltaHeader {
+    /// How the files were normalized before computing the Delta, if they were.
+    pub normalization: Option<TextNormalization>,
+    /// Name of the ContentTransform applied to the files before computing the Delta, if any.
+    pub transform: Option<String>,
+    /// The basis file the Delta expects, copied from the Signature.
+    #[serde(default)]
+    pub basis: Option<BasisLayout>,
+    /// The updated file, to check the recreated file against it.
+    #[serde(default)]
+    pub updated: Option<FileDigest>,
+    /// Where the Delta comes from, if it was annotated.
+    #[serde(default)]
+    pub metadata: Option<DeltaMetadata>,
+    /// Whether blocks were reused on their weak hash alone, unconfirmed.
+    #[serde(default)]
+    pub unconfirmed: bool,
+    /// Bytes the tokens write, checked as the Delta is applied.
+    #[serde(default)]
+    pub output_length: Option<u64>,
+}
+
+impl DeltaHeader {
+    // Leaves out the hashes of whole files, for headers of versions which hashed them with SipHash.
+    pub(crate) fn forget_file_hashes(&mut self) {
+        self.basis = self.basis.map(BasisLayout::without_strong_hash);
+        self.updated = None;
+        if let Some(metadata) = &mut self.metadata {
+            metadata.basis = None;
+        }
+    }
+}
+
+// Fields are written by position, so an optional field can only be left out if every field after
+// it is too. Headers without the newer fields are written exactly as before they existed.
+impl Serialize for DeltaHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let optional_fields = if self.output_length.is_some() {
+            5
+        } else if self.unconfirmed {
+            4
+        } else if self.metadata.is_some() {
+            3
+        } else if self.updated.is_some() {
+            2
+        } else if self.basis.is_some() {
+            1
+        } else {
+            0
+        };
+
+        let mut header = serializer.serialize_struct("DeltaHeader", 2 + optional_fields)?;
+        header.serialize_field("normalization", &self.normalization)?;
+        header.serialize_field("transform", &self.transform)?;
+        if optional_fields >= 1 {
+            header.serialize_field("basis", &self.basis)?;
+        }
+        if optional_fields >= 2 {
+            header.serialize_field("updated", &self.updated)?;
+        }
+        if optional_fields >= 3 {
+            header.serialize_field("metadata", &self.metadata)?;
+        }
+        if optional_fields >= 4 {
+            header.serialize_field("unconfirmed", &self.unconfirmed)?;
+        }
+        if optional_fields >= 5 {
+            header.serialize_field("output_length", &self.output_length)?;
+        }
+        header.end()
+    }
+}
+
+/// Length and strong hash of a whole file, as `calculate_strong_hash` computes it.
+///
+/// `delta` records the updated file's, so `patch` can tell whether it recreated the right file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FileDigest {
+    pub length: u64,
+    pub strong_hash: u64,
+}
+
+impl FileDigest {
+    pub fn of(content: &[u8]) -> Self {
+        Self {
+            length: content.len() as u64,
+            strong_hash: calculate_strong_hash(content),
+        }
+    }
+
+    /// Checks a recreated file has this digest, as it must unless the Delta was damaged.
+    pub fn check_recreated(&self, recreated: FileDigest) -> eyre::Result<()> {
+        if recreated != *self {
+            return Err(eyre!(
+                "Recreated file ({recreated}) does not match the updated file ({self})"
+            ))
+            .suggestion("The Delta or the Basis file may be damaged. Compute the Delta again.");
+        }
+        Ok(())
+    }
+}
+
+/// Computes the FileDigest of content seen in pieces, without keeping it.
+#[derive(Clone, Default)]
+pub struct FileDigestBuilder {
+    length: u64,
+    /// xxh3-64, as `calculate_strong_hash` computes it, which gives the same hash in pieces.
+    hasher: Xxh3Default,
+}
+
+impl FileDigestBuilder {
+    /// Bytes seen so far.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn update(&mut self, piece: &[u8]) {
+        self.length += piece.len() as u64;
+        self.hasher.update(piece);
+    }
+
+    pub fn finish(&self) -> FileDigest {
+        FileDigest {
+            length: self.length,
+            strong_hash: self.hasher.digest(),
+        }
+    }
+}
+
+impl fmt::Display for FileDigest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes, hash {:016x}", self.length, self.strong_hash)
+    }
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
-pub enum Token {
-    BlockIndex(usize),
-    // A reference to a block within the basis file.
-    ByteLiteral(u8), // A byte literal to be reconstructed directly.
+/// Tuning of the fixed mode matcher. The default finds every block which can be matched.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct MatchingOptions {
+    /// After this many consecutive literal bytes, probe the next block boundaries (aligned with
+    /// the last match) before any other position. Blocks which only match in between are missed.
+    pub resync_after: Option<usize>,
+    /// Which block is referenced when several basis blocks match.
+    pub preference: MatchPreference,
+    /// Which blocks found are referenced rather than sent as literals.
+    pub strategy: MatchStrategy,
+    /// Runs of zeros this long are sent as ZeroRuns. Not by `optimal`.
+    pub zero_runs: Option<usize>,
+}
+
+/// Which basis block is referenced when several of them have the content of our block.
+///
+/// Any of them recreates the same file, but the order in which the basis file is read differs.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum MatchPreference {
+    /// The first one after the previously matched block, so the basis file is read sequentially.
+    Closest,
+    /// The one closest to the start of the basis file.
+    First,
+    /// The last one of the basis file, checking a single candidate, as Deltas always were.
+    #[default]
+    Any,
+}
+
+impl fmt::Display for MatchPreference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchPreference::Closest => write!(f, "closest"),
+            MatchPreference::First => write!(f, "first"),
+            MatchPreference::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for MatchPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "closest" => Ok(MatchPreference::Closest),
+            "first" => Ok(MatchPreference::First),
+            "any" => Ok(MatchPreference::Any),
+            _ => Err(format!(
+                r#""{s}" is not a match preference. Expected "closest", "first" or "any""#
+            )),
+        }
+    }
+}
+
+/// How the matcher decides between referencing a block it found and sending its bytes as literals.
+///
+/// Referencing a block costs a token, and splits the literals around it in two runs. With small
+/// chunk sizes, a block surrounded by literals can cost more than its bytes.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum MatchStrategy {
+    /// Every block found is referenced. The fastest to compute.
+    #[default]
+    Greedy,
+    /// A block is only referenced if it makes the Delta smaller, looking a few blocks ahead.
+    Lazy,
+    /// The tokens making the smallest Delta, searched over a window of blocks at a time.
+    Optimal,
+}
+
+impl fmt::Display for MatchStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchStrategy::Greedy => write!(f, "greedy"),
+            MatchStrategy::Lazy => write!(f, "lazy"),
+            MatchStrategy::Optimal => write!(f, "optimal"),
+        }
+    }
+}
+
+impl FromStr for MatchStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greedy" => Ok(MatchStrategy::Greedy),
+            "lazy" => Ok(MatchStrategy::Lazy),
+            "optimal" => Ok(MatchStrategy::Optimal),
+            _ => Err(format!(
+                r#""{s}" is not a matching strategy. Expected "greedy", "lazy" or "optimal""#
+            )),
+        }
+    }
+}
+
+// How many blocks are looked up ahead of a match, to know if it pays off in lazy matching.
+const LAZY_LOOKAHEAD_BLOCKS: usize = 16;
+
+// How many block boundaries are probed when resynchronizing.
+const RESYNC_BOUNDARIES: usize = 4;
+
+/// Receives the tokens of a Delta, in order, as they are computed.
+pub trait TokenSink {
+    fn push(&mut self, token: Token) -> eyre::Result<()>;
+}
+
+impl TokenSink for Vec<Token> {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        Vec::push(self, token);
+        Ok(())
+    }
 }
 
 // We are using `rmp_serde` as a efficient binary format to save the files in.
 impl TryFrom<Delta> for Bytes {
-    type Error = color_eyre::Report;
+    type Error = eyre::Report;
 
     fn try_from(delta: Delta) -> Result<Self, Self::Error> {
-        let serialized = rmp_serde::to_vec(&delta)?;
-        Ok(serialized.into())
+        encode_artifact(ArtifactKind::Delta, &delta)
     }
 }
 
 impl TryFrom<Bytes> for Delta {
-    type Error = color_eyre::Report;
+    type Error = eyre::Report;
 
     fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
-        let delta = rmp_serde::from_slice(&bytes)
-            .wrap_err("Could not read Delta from file provided.")
-            .suggestion(
-                "Did you provide the correct path for the Delta file?\n\
-                         It must have been generated as an output from a previous `delta` command.",
-            )?;
-        Ok(delta)
+        Delta::decode(bytes, UnknownTokens::Deny)
+    }
+}
+
+// Deltas may have been written all at once, or streamed while being computed.
+fn decode_delta(mut bytes: Bytes) -> eyre::Result<Delta> {
+    let version = artifact_version(&bytes);
+    let mut delta: Delta = match read_preamble(ArtifactKind::Delta, &mut bytes)? {
+        ArtifactEncoding::MessagePack => rmp_serde::from_slice(&bytes)?,
+        ArtifactEncoding::MessagePackFrames => read_delta_frames(bytes)?,
+    };
+    if version.is_some_and(hashes_files_with_siphash) {
+        delta.header.forget_file_hashes();
     }
+    Ok(delta)
 }
 
 /// Computes a Delta from a FileSignature.
@@ -55,6 +370,9 @@ impl TryFrom<Bytes> for Delta {
 /// Note that the `chunk_size` argument must be the same as what was used when creating
 /// the FileSignature).
 ///
+/// If our file is the basis file, block for block, the Delta is a single reference to every
+/// block, found without searching our file for matches.
+///
 /// # Arguments
 /// * `signature` - The FileSignature representing the basis file.
 /// * `updated_file` - Our updated file, in bytes.
@@ -65,125 +383,646 @@ pub fn compute_delta_to_our_file(
     updated_file: Bytes,
     chunk_size: usize,
 ) -> Delta {
-    // Each of our "sliding" blocks can match to a block in the basis file.
-    // So we need to test all of the "sliding block", which means we will compare
-    // rolling_hashes and (potentially) strong_hashes.
+    let mut tokens = TokenBuffer::default();
+    if is_unchanged(&signature, &updated_file, chunk_size) {
+        match signature.strong_hashes.len() {
+            0 => {}
+            1 => tokens.push(Token::BlockIndex(0)),
+            block_count => tokens.push(Token::BlockRange(0..block_count)),
+        }
+    } else {
+        match_fixed_blocks(
+            &signature,
+            &updated_file,
+            chunk_size,
+            &MatchingOptions::default(),
+            &mut tokens,
+        )
+        .expect("Chunk size must be valid, and collecting tokens in a TokenBuffer never fails");
+    }
 
-    let our_sliding_blocks_rolling_hashes = {
-        let bytes = updated_file.clone();
+    Delta {
+        header: DeltaHeader {
+            basis: signature.basis,
+            unconfirmed: !signature.strong_hash.confirms_blocks(),
+            output_length: Some(updated_file.len() as u64),
+            ..Default::default()
+        },
+        content: tokens,
+    }
+}
 
-        if chunk_size <= updated_file.len() {
-            // We will have a rolling hash for each sliding block
-            let mut rolling_hashes = Vec::new();
+/// Whether `updated_file` has the same blocks as the basis file, in the same order.
+///
+/// Blocks are compared to the Signature in a single pass, without sliding a window over our file,
+/// so telling that nothing changed is much cheaper than matching every block.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+///
+pub fn is_unchanged(signature: &FileSignature, updated_file: &[u8], chunk_size: usize) -> bool {
+    if signature
+        .basis
+        .is_some_and(|basis| basis.length != updated_file.len() as u64)
+    {
+        return false;
+    }
+    if chunk_size == 0 || updated_file.len().div_ceil(chunk_size) != signature.strong_hashes.len() {
+        return false;
+    }
 
-            let mut windows_iter = bytes.windows(chunk_size);
-            // Windows iter is not empty here because that case is handled by the if statement above
-            let mut hasher = RollingHash::from_initial_bytes(windows_iter.next().unwrap());
-            rolling_hashes.push(hasher.get_current_hash());
+    updated_file
+        .chunks(chunk_size)
+        .zip(&signature.rolling_hashes)
+        .enumerate()
+        .all(|(index, (block, &rolling_hash))| {
+            signature.has_strong_digest(index, &signature.strong_hash.digest(block))
+                && signature.weak_hash.hash(block) == rolling_hash
+        })
+}
 
-            // we do not need windows here, just iterate one-by-one after the initial one
-            windows_iter.for_each(|window| {
-                hasher.pop_front();
-                hasher.push_back(*window.last().unwrap());
-                rolling_hashes.push(hasher.get_current_hash());
-            });
+fn match_fixed_blocks(
+    signature: &FileSignature,
+    updated_file: &Bytes,
+    chunk_size: usize,
+    options: &MatchingOptions,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    let our_sliding_blocks_rolling_hashes =
+        compute_sliding_rolling_hashes(updated_file, chunk_size, signature.weak_hash);
+    stream_fixed_delta(
+        signature,
+        updated_file,
+        chunk_size,
+        &our_sliding_blocks_rolling_hashes,
+        options,
+        tokens,
+    )
+}
 
+/// Computes the rolling hash of every `chunk_size` window of our file, one per starting byte.
+///
+/// These hashes only depend on the Signature's weak hash, so they can be computed while the rest
+/// of the Signature is still being received.
+///
+/// # Arguments
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `weak_hash` - The rolling hash used in the Signature.
+///
+pub fn compute_sliding_rolling_hashes(
+    updated_file: &[u8],
+    chunk_size: usize,
+    weak_hash: WeakHash,
+) -> Vec<u64> {
+    compute_sliding_rolling_hashes_in_parallel(
+        updated_file,
+        chunk_size,
+        weak_hash,
+        &Parallelism::serial(),
+    )
+}
+
+/// Computes the same rolling hashes as `compute_sliding_rolling_hashes`, on several threads.
+///
+/// Each thread starts rolling from the first window of its part of the file, and a window's hash
+/// only depends on its bytes, so the hashes are the same whatever the number of threads.
+///
+/// # Arguments
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `weak_hash` - The rolling hash used in the Signature.
+/// * `parallelism` - How many threads may hash windows.
+///
+pub fn compute_sliding_rolling_hashes_in_parallel(
+    updated_file: &[u8],
+    chunk_size: usize,
+    weak_hash: WeakHash,
+    parallelism: &Parallelism,
+) -> Vec<u64> {
+    profiling::span!("rolling_hashes");
+    if chunk_size > 0 && chunk_size <= updated_file.len() {
+        // We will have a rolling hash for each sliding block
+        let number_of_windows = updated_file.len() - chunk_size + 1;
+        parallelism.map_ranges(number_of_windows, |windows| {
+            let rolling_hashes =
+                profiling::measure(Section::RollingHash, windows.len() as u64, || {
+                    weak_hash.roll(updated_file, chunk_size, windows.clone())
+                });
+            progress::advance(windows.len() as u64);
             rolling_hashes
-        } else {
-            // We do not have enough bytes to construct a block
-            Vec::new()
-        }
-    };
+        })
+    } else {
+        // We do not have enough bytes to construct a block
+        Vec::new()
+    }
+}
 
-    // Map with key: RollingHash and value: index of the block with given hash.
-    // This map is used to quickly match blocks from our file and theirs with
-    // equal rolling_hash.
-    let their_rolling_hashes = {
-        let mut map = HashMap::new();
-        signature
-            .rolling_hashes
-            .iter()
-            .enumerate()
-            .for_each(|(index, hash)| {
-                map.insert(hash, index);
-            });
-        map
+/// Computes the tokens of a Delta in fixed mode, given the rolling hashes of our sliding blocks.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `our_sliding_blocks_rolling_hashes` - As computed by `compute_sliding_rolling_hashes`.
+/// * `options` - How the matcher is tuned.
+/// * `tokens` - Where the tokens are pushed to, in order.
+///
+pub fn stream_fixed_delta(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+    our_sliding_blocks_rolling_hashes: &[u64],
+    options: &MatchingOptions,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    // Offsets in our file plus `chunk_size` can then never overflow.
+    check_chunk_size(chunk_size)?;
+    profiling::span!("match");
+    if options.strategy == MatchStrategy::Optimal {
+        return stream_optimal_delta(
+            signature,
+            updated_file,
+            chunk_size,
+            our_sliding_blocks_rolling_hashes,
+            options,
+            tokens,
+        );
+    }
+
+    // Each of our "sliding" blocks can match to a block in the basis file.
+    // So we need to test all of the "sliding block", which means we will compare
+    // rolling_hashes and (potentially) strong_hashes.
+    let their_rolling_hashes = index_rolling_hashes(signature);
+
+    let our_file_size = updated_file.len();
+    // Where the last matched block ended. Blocks following it start at multiples of `chunk_size`
+    // from here, unless bytes were inserted or removed.
+    let mut aligned_to = 0;
+    let mut next_resync = options.resync_after;
+    let mut previous_block = None;
+    let short_last_block = find_short_last_block(signature, updated_file, chunk_size);
+    let block_size = BlockSize::new(chunk_size);
+    let mut zero_runs = ZeroRuns {
+        min_length: options.zero_runs,
+        none_before: 0,
     };
+    // We need to construct the delta considering ALL of our bytes:
+    // We have one rolling hash for each potential block
+    let mut index = 0;
+    let mut reported = 0;
+    // Counted here and added up once, so profiling does not slow lookups down.
+    let (mut probes, mut probe_hits) = (0, 0);
+    while index < our_file_size {
+        let our_block_starting_byte = updated_file[index];
+        if index - reported >= progress::STEP {
+            progress::advance((index - reported) as u64);
+            reported = index;
+        }
 
-    let delta_tokens = {
-        let mut tokens = Vec::new();
-
-        let our_file_size = updated_file.len();
-        // We need to construct the delta considering ALL of our bytes:
-        // We have one rolling hash for each potential block
-        let mut index = 0;
-        while index < our_file_size {
-            let our_block_starting_byte = updated_file[index];
-
-            let end_of_our_block = index + chunk_size - 1; // inclusive
-            if end_of_our_block >= our_file_size {
-                // This is part of a trailing block, which shall be sent directly
-                // as ByteLiteral
-                tokens.push(Token::ByteLiteral(our_block_starting_byte));
-                index += 1;
+        // Long runs of zeros are sent as their length, whether the basis file has them or not.
+        if let Some(length) = zero_runs.starting_at(updated_file, index) {
+            tokens.push(Token::ZeroRun(length))?;
+            index += length;
+            aligned_to = index;
+            next_resync = options.resync_after;
+            continue;
+        }
+
+        if next_resync.is_some_and(|literal_bytes| index - aligned_to >= literal_bytes) {
+            let resync_after = options.resync_after.unwrap_or_default();
+            next_resync =
+                next_resync.map(|literal_bytes| literal_bytes.saturating_add(resync_after));
+            let first_boundary =
+                aligned_to + block_size.align_up((index - aligned_to) as u64) as usize;
+            let resynced = (0..RESYNC_BOUNDARIES)
+                .map(|boundary| first_boundary + boundary * chunk_size)
+                .take_while(|&position| {
+                    position
+                        .checked_add(chunk_size)
+                        .is_some_and(|end| end <= our_file_size)
+                })
+                .find_map(|position| {
+                    let candidates =
+                        their_rolling_hashes.get(&our_sliding_blocks_rolling_hashes[position])?;
+                    let block_bytes = &updated_file[position..position + chunk_size];
+                    find_matching_block(signature, candidates, block_bytes, options, previous_block)
+                        .map(|matched_block_index| (position, matched_block_index))
+                });
+            if let Some((position, matched_block_index)) = resynced {
+                // The bytes before the boundary are not looked up at all.
+                for &byte in &updated_file[index..position] {
+                    tokens.push(Token::ByteLiteral(byte))?;
+                }
+                tokens.push(Token::BlockIndex(matched_block_index))?;
+                index = position + chunk_size;
+                aligned_to = index;
+                next_resync = options.resync_after;
+                previous_block = Some(matched_block_index);
                 continue;
             }
+        }
+
+        // Exclusive, and None when our block would go past the end of our file.
+        let end_of_our_block = index
+            .checked_add(chunk_size)
+            .filter(|&end| end <= our_file_size);
+        let Some(end_of_our_block) = end_of_our_block else {
+            // Our last bytes may be the last block of the basis file, shorter than the others.
+            if let Some((_, block_index)) =
+                short_last_block.filter(|&(position, _)| position == index)
+            {
+                tokens.push(Token::BlockIndex(block_index))?;
+                break;
+            }
+            // Otherwise, this is part of a trailing block, which shall be sent directly
+            // as ByteLiteral
+            tokens.push(Token::ByteLiteral(our_block_starting_byte))?;
+            index += 1;
+            continue;
+        };
 
-            // For each block, we will try to match it to an existing one in the basis file
-            // using the rolling_hashes.
-            let our_block_rolling_hash = our_sliding_blocks_rolling_hashes[index];
-            match their_rolling_hashes.get(&our_block_rolling_hash) {
-                Some(&matched_block_index) => {
-                    // We have matched our current block with block at `matched_block_index` in the basis file.
-                    // Note this is only a *potential* match, as it may be a collision in the rolling_hashes.
-
-                    // We only consider the block to be a true match if we match the strong_hashes as well.
-                    // As the strong_hash is computationally expensive, we only compute it when needed
-                    // (if the rolling_hashes have matched).
-                    let our_block_strong_hash = {
-                        let block_bytes = &updated_file[index..=end_of_our_block];
-                        calculate_strong_hash(block_bytes)
-                    };
-                    let their_strong_hash = signature.strong_hashes[matched_block_index];
-
-                    if our_block_strong_hash == their_strong_hash {
+        // For each block, we will try to match it to an existing one in the basis file
+        // using the rolling_hashes.
+        let our_block_rolling_hash = our_sliding_blocks_rolling_hashes[index];
+        let candidates = their_rolling_hashes.get(&our_block_rolling_hash);
+        probes += 1;
+        probe_hits += candidates.is_some() as u64;
+        match candidates {
+            Some(candidates) => {
+                // We have matched our current block with the `candidates` blocks in the basis file.
+                // Note these are only *potential* matches, as it may be a collision in the rolling_hashes.
+
+                // We only consider a block to be a true match if we match the strong_hashes as well.
+                // As the strong_hash is computationally expensive, we only compute it when needed
+                // (if the rolling_hashes have matched).
+                let block_bytes = &updated_file[index..end_of_our_block];
+                match find_matching_block(
+                    signature,
+                    candidates,
+                    block_bytes,
+                    options,
+                    previous_block,
+                ) {
+                    Some(matched_block_index)
+                        if options.strategy == MatchStrategy::Lazy
+                            && !match_pays_off(
+                                signature,
+                                updated_file,
+                                chunk_size,
+                                our_sliding_blocks_rolling_hashes,
+                                &their_rolling_hashes,
+                                options,
+                                index,
+                                matched_block_index,
+                                (aligned_to == index).then_some(previous_block).flatten(),
+                            ) =>
+                    {
+                        // A block surrounded by literals, which costs more than its bytes.
+                        tokens.push(Token::ByteLiteral(our_block_starting_byte))?;
+                        index += 1;
+                    }
+                    Some(matched_block_index) => {
                         // These blocks have matched both rolling_hashes and strong_hashes.
                         // We are confident they are the same.
-                        tokens.push(Token::BlockIndex(matched_block_index));
+                        tokens.push(Token::BlockIndex(matched_block_index))?;
                         // All this block is already accounted for, jump to the next unaccounted byte.
                         index += chunk_size;
-                    } else {
+                        aligned_to = index;
+                        next_resync = options.resync_after;
+                        previous_block = Some(matched_block_index);
+                    }
+                    None => {
                         // The rolling_hashes matched but not the strong_hashes. It was a false positive.
-                        tokens.push(Token::ByteLiteral(our_block_starting_byte));
+                        tokens.push(Token::ByteLiteral(our_block_starting_byte))?;
                         index += 1;
                         // Note that if we, mistakenly, thought that the rolling_hashes were sufficient,
                         // we would have pushed a reference to a different block, thus reconstructing
                         // a wrong file in the end! Dodged a bullet here!
                     }
                 }
-                None => {
-                    // No blocks match the rolling hash. The best we can do is to send the byte directly.
-                    tokens.push(Token::ByteLiteral(our_block_starting_byte));
-                    index += 1;
-                    // Note that we can be confident that no matching block exists at all, because equal
-                    // blocks would have equal hashes.
-                }
             }
+            None => {
+                // No blocks match the rolling hash. The best we can do is to send the byte directly.
+                tokens.push(Token::ByteLiteral(our_block_starting_byte))?;
+                index += 1;
+                // Note that we can be confident that no matching block exists at all, because equal
+                // blocks would have equal hashes.
+            }
+        }
+    }
+    profiling::count(Section::Probe, probes, probe_hits);
+
+    Ok(())
+}
+
+// The last block of the basis file, when it is shorter than `chunk_size` and our file ends with
+// it, with the position it starts at in our file. No window of our sliding blocks can match it, as
+// they are all `chunk_size` long, but reusing it saves up to `chunk_size - 1` literal bytes.
+// Signatures record its length in their BasisLayout; those which do not are never matched.
+pub(crate) fn find_short_last_block(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+) -> Option<(usize, usize)> {
+    let last_block_size = signature.basis?.last_block_size? as usize;
+    if last_block_size >= chunk_size || last_block_size > updated_file.len() {
+        return None;
+    }
+    let block_index = signature.strong_hashes.len().checked_sub(1)?;
+    let position = updated_file.len() - last_block_size;
+    let tail = &updated_file[position..];
+    let matches = signature.rolling_hashes.get(block_index)
+        == Some(&signature.weak_hash.hash(tail))
+        && signature.has_strong_digest(block_index, &signature.strong_hash.digest(tail));
+
+    matches.then_some((position, block_index))
+}
+
+// Finds the runs of zeros in our file long enough to be sent as ZeroRuns, checking each byte once.
+struct ZeroRuns {
+    min_length: Option<usize>,
+    /// Positions before this one were found to start no long enough run.
+    none_before: usize,
+}
+
+impl ZeroRuns {
+    // Length of the run of zeros starting at `index`, if it is long enough.
+    fn starting_at(&mut self, file: &[u8], index: usize) -> Option<usize> {
+        let min_length = self.min_length?;
+        if index < self.none_before || file[index] != 0 {
+            return None;
+        }
+        let length = file[index..].iter().take_while(|&&byte| byte == 0).count();
+        if length >= min_length {
+            return Some(length);
         }
+        // Runs starting later within this one are even shorter.
+        self.none_before = index + length;
+        None
+    }
+}
 
-        tokens
+// Map with key: RollingHash and value: indexes of the blocks with given hash, ascending.
+// This map is used to quickly match blocks from our file and theirs with equal rolling_hash.
+// It is only used for lookups (never iterated), so the Delta never depends on the map's
+// (randomized) ordering.
+pub(crate) fn index_rolling_hashes(signature: &FileSignature) -> HashMap<u64, Vec<usize>> {
+    let mut map: HashMap<u64, Vec<usize>> = HashMap::new();
+    signature
+        .rolling_hashes
+        .iter()
+        .enumerate()
+        .for_each(|(index, &hash)| map.entry(hash).or_default().push(index));
+    map
+}
+
+// Whether referencing `matched_block_index` at `index` makes the Delta smaller than sending its
+// bytes as literals, given the token before it (`previous_block` if it was a block ending at
+// `index`). The blocks continuing it are looked up too, as a range costs little more than a block.
+#[allow(clippy::too_many_arguments)]
+fn match_pays_off(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+    our_sliding_blocks_rolling_hashes: &[u64],
+    their_rolling_hashes: &HashMap<u64, Vec<usize>>,
+    options: &MatchingOptions,
+    index: usize,
+    matched_block_index: usize,
+    previous_block: Option<usize>,
+) -> bool {
+    let in_literal_run = index > 0 && previous_block.is_none();
+    let mut cost_as_reference = optimal::reference_cost(previous_block, matched_block_index);
+    let mut cost_as_literals = optimal::literals_cost(&updated_file[index..index + chunk_size])
+        + if in_literal_run { 0 } else { LITERAL_RUN_COST };
+
+    let mut block = matched_block_index;
+    let mut next = index + chunk_size;
+    for _ in 1..LAZY_LOOKAHEAD_BLOCKS {
+        if cost_as_reference <= cost_as_literals || next == updated_file.len() {
+            break;
+        }
+        let next_block = updated_file
+            .get(next..next + chunk_size)
+            .and_then(|block_bytes| {
+                let candidates =
+                    their_rolling_hashes.get(&our_sliding_blocks_rolling_hashes[next])?;
+                find_matching_block(signature, candidates, block_bytes, options, Some(block))
+            });
+        match next_block {
+            Some(next_block) if next_block == block + 1 => {
+                cost_as_reference += optimal::reference_cost(Some(block), next_block);
+                cost_as_literals += optimal::literals_cost(&updated_file[next..next + chunk_size]);
+                block = next_block;
+                next += chunk_size;
+            }
+            // Another block follows, which will be referenced anyway.
+            Some(_) => break,
+            None => {
+                // The literals after the blocks start a new run.
+                cost_as_reference += LITERAL_RUN_COST;
+                break;
+            }
+        }
+    }
+
+    cost_as_reference <= cost_as_literals
+}
+
+// Picks, among the basis blocks sharing the rolling hash of `block_bytes`, one which also shares
+// its strong hash, following `options.preference`.
+pub(crate) fn find_matching_block(
+    signature: &FileSignature,
+    candidates: &[usize],
+    block_bytes: &[u8],
+    options: &MatchingOptions,
+    previous_block: Option<usize>,
+) -> Option<usize> {
+    let our_block_strong_hash = profiling::measure(Section::StrongHash, 1, || {
+        signature.strong_hash.digest(block_bytes)
+    });
+    let is_match =
+        |&&candidate: &&usize| signature.has_strong_digest(candidate, &our_block_strong_hash);
+
+    let matched = match options.preference {
+        MatchPreference::Closest => {
+            let after_previous =
+                previous_block.map_or(0, |previous| candidates.partition_point(|&c| c <= previous));
+            let (before, after) = candidates.split_at(after_previous);
+            after.iter().chain(before).find(is_match).copied()
+        }
+        MatchPreference::First => candidates.iter().find(is_match).copied(),
+        MatchPreference::Any => candidates.last().filter(is_match).copied(),
     };
+    profiling::count(Section::StrongHash, 0, matched.is_some() as u64);
+    matched
+}
+
+/// Computes a Delta from a FileSignature, dividing our file into blocks with `mode`.
+///
+/// The FileSignature must have been computed with the same `mode` (and `chunk_size`).
+/// In fixed mode, panics if `check_chunk_size` refuses `chunk_size`: `stream_delta_with_mode`
+/// returns the error instead.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `mode` - How the files were divided into blocks.
+/// * `options` - How the matcher is tuned, in fixed mode.
+///
+pub fn compute_delta_with_mode(
+    signature: FileSignature,
+    updated_file: Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    options: &MatchingOptions,
+) -> Delta {
+    let mut tokens = TokenBuffer::default();
+    stream_delta_with_mode(
+        &signature,
+        &updated_file,
+        chunk_size,
+        mode,
+        options,
+        &mut tokens,
+    )
+    .expect("Chunk size must be valid, and collecting tokens in a TokenBuffer never fails");
+
+    Delta {
+        header: DeltaHeader {
+            basis: signature.basis,
+            unconfirmed: !signature.strong_hash.confirms_blocks(),
+            output_length: Some(updated_file.len() as u64),
+            ..Default::default()
+        },
+        content: tokens,
+    }
+}
+
+/// Computes a Delta in fixed mode, given the rolling hashes of our sliding blocks.
+///
+/// Same as `compute_delta_with_mode` in fixed mode, for callers which hash our file beforehand.
+/// Panics on the same chunk sizes.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `our_sliding_blocks_rolling_hashes` - As computed by `compute_sliding_rolling_hashes`.
+/// * `options` - How the matcher is tuned.
+///
+pub fn compute_fixed_delta(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+    our_sliding_blocks_rolling_hashes: &[u64],
+    options: &MatchingOptions,
+) -> Delta {
+    let mut tokens = TokenBuffer::default();
+    stream_fixed_delta(
+        signature,
+        updated_file,
+        chunk_size,
+        our_sliding_blocks_rolling_hashes,
+        options,
+        &mut tokens,
+    )
+    .expect("Chunk size must be valid, and collecting tokens in a TokenBuffer never fails");
 
     Delta {
-        content: delta_tokens,
+        header: DeltaHeader {
+            basis: signature.basis,
+            unconfirmed: !signature.strong_hash.confirms_blocks(),
+            output_length: Some(updated_file.len() as u64),
+            ..Default::default()
+        },
+        content: tokens,
+    }
+}
+
+/// Computes the tokens of a Delta, pushing each of them to `tokens` as soon as it is known.
+///
+/// This lets the Delta be written (e.g. by a `DeltaWriter`) while the updated file is still
+/// being matched, instead of collecting every token in memory first.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `mode` - How the files were divided into blocks.
+/// * `options` - How the matcher is tuned, in fixed mode.
+/// * `tokens` - Where the tokens are pushed to, in order.
+///
+pub fn stream_delta_with_mode(
+    signature: &FileSignature,
+    updated_file: &Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    options: &MatchingOptions,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    match mode {
+        ChunkingMode::Fixed => {
+            match_fixed_blocks(signature, updated_file, chunk_size, options, tokens)
+        }
+        ChunkingMode::Lines => match_lines(signature, updated_file, tokens),
+    }
+}
+
+// In lines mode, block boundaries are given by the content itself, so there is no need
+// for a sliding window: each of our lines either matches a basis line or is sent as literals.
+fn match_lines(
+    signature: &FileSignature,
+    updated_file: &Bytes,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    // Map with key: (RollingHash, StrongHash, its tail) and value: index of the first line with
+    // those hashes.
+    let their_lines = {
+        let mut map = HashMap::new();
+        signature
+            .rolling_hashes
+            .iter()
+            .zip(signature.strong_hashes.iter())
+            .enumerate()
+            .for_each(|(index, (rolling_hash, strong_hash))| {
+                let tail = signature.strong_hash_tail(index).unwrap_or_default();
+                map.entry((rolling_hash, strong_hash, tail))
+                    .or_insert(index);
+            });
+        map
+    };
+
+    for line in ChunkingMode::Lines.split(updated_file, 0) {
+        let digest = signature.strong_hash.digest(line);
+        let hashes = (&signature.weak_hash.hash(line), &digest.head, digest.tail());
+        match their_lines.get(&hashes) {
+            Some(&matched_line_index) => tokens.push(Token::BlockIndex(matched_line_index))?,
+            None => {
+                for &byte in line {
+                    tokens.push(Token::ByteLiteral(byte))?;
+                }
+            }
+        }
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::ops::Range;
+
     use bytes::Bytes;
 
-    use crate::domain::signature::compute_signature;
+    use crate::domain::signature::{
+        compute_signature, compute_signature_with_hashes, compute_signature_with_mode,
+    };
+    use crate::domain::{StrongHash, TokenRef};
 
     use super::*;
 
@@ -204,40 +1043,101 @@ mod tests {
 
         let file1_signature = compute_signature(file1, test_chunk_size);
         // We need to calculate the delta from our file `file2` to `file1` based on
-        // `file1`'s signature.
-        let delta = compute_delta_to_our_file(file1_signature, file2, test_chunk_size);
+        // `file1`'s signature. The matcher is used directly, as `compute_delta_to_our_file`
+        // does not search unchanged files.
+        let delta = compute_delta_with_mode(
+            file1_signature,
+            file2,
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+        );
 
         // Delta is all BlockIndexes.
-        for c in delta.content {
-            assert!(matches!(c, Token::BlockIndex(_)));
+        for c in &delta.content {
+            assert!(matches!(c, TokenRef::BlockIndex(_)));
         }
     }
 
     #[test]
-    fn delta_for_equal_content_is_block_indexes_plus_literals_when_there_is_leftover() {
+    fn delta_for_equal_content_references_the_leftover_block_too() {
         let test_chunk_size = 5;
         // Hello World! has 12 bytes. We will have 2 chunks of size 5
-        // and a leftover chunk of size 2. This last chunk will be sent as two ByteLiterals.
+        // and a leftover chunk of size 2. The basis file ends with the same leftover chunk, so it
+        // is referenced too.
         let basis_file = Bytes::from("Hello World!");
         let updated_file = Bytes::from("Hello World!");
 
         let signature = compute_signature(basis_file, test_chunk_size);
         // We need to calculate the delta from our `updated_file` to `basis_file` based on signature.
-        let delta = compute_delta_to_our_file(signature, updated_file, test_chunk_size);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file,
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+        );
 
-        // 2 BlockIndex (for the first two chunks).
-        let block_indexes = &delta.content[0..2];
-        for b in block_indexes {
-            assert!(matches!(b, Token::BlockIndex(_)));
-        }
+        assert_eq!(
+            delta.content.to_vec(),
+            vec![
+                Token::BlockIndex(0),
+                Token::BlockIndex(1),
+                Token::BlockIndex(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn leftover_bytes_differing_from_the_basis_file_are_sent_as_literals() {
+        let test_chunk_size = 5;
+        let signature = compute_signature(Bytes::from("Hello World!"), test_chunk_size);
+
+        // The leftover chunks differ, or are not at the end of the basis file.
+        for (updated_file, literals) in [("Hello World?", 2), ("Hello World!!", 3)] {
+            let delta = compute_delta_with_mode(
+                signature.clone(),
+                Bytes::from(updated_file),
+                test_chunk_size,
+                ChunkingMode::Fixed,
+                &MatchingOptions::default(),
+            );
 
-        // 2 ByteLiterals (for the leftover chunk).
-        let byte_literals = &delta.content[2..];
-        for b in byte_literals {
-            assert!(matches!(b, Token::ByteLiteral(_)));
+            let tokens = delta.content.to_vec();
+            assert_eq!(tokens[..2], [Token::BlockIndex(0), Token::BlockIndex(1)]);
+            assert_eq!(tokens[2..].len(), literals);
+            assert!(tokens[2..]
+                .iter()
+                .all(|token| matches!(token, Token::ByteLiteral(_))));
         }
     }
 
+    #[test]
+    fn delta_for_unchanged_file_is_a_single_range_of_every_block() {
+        let test_chunk_size = 5;
+        let basis_file = Bytes::from("Hello World!");
+
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        assert!(is_unchanged(&signature, &basis_file, test_chunk_size));
+        let delta = compute_delta_to_our_file(signature, basis_file.clone(), test_chunk_size);
+
+        // The leftover chunk is referenced too, as it is the same as the last basis block.
+        assert_eq!(delta.content, vec![Token::BlockRange(0..3)]);
+        assert_eq!(
+            crate::domain::apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            Bytes::from("Hello World!")
+        );
+    }
+
+    #[test]
+    fn files_with_a_changed_block_are_not_unchanged() {
+        let test_chunk_size = 5;
+        let signature = compute_signature(Bytes::from("Hello World!"), test_chunk_size);
+
+        assert!(!is_unchanged(&signature, b"Hello World?", test_chunk_size));
+        assert!(!is_unchanged(&signature, b"Hello World!!", test_chunk_size));
+    }
+
     #[test]
     fn delta_for_completely_different_files_has_only_literal_bytes() {
         let test_chunk_size = 3;
@@ -249,8 +1149,8 @@ mod tests {
         let signature = compute_signature(basis_file, test_chunk_size);
         let delta = compute_delta_to_our_file(signature, updated_file, test_chunk_size);
 
-        for b in delta.content {
-            assert!(matches!(b, Token::ByteLiteral(_)));
+        for b in &delta.content {
+            assert!(matches!(b, TokenRef::ByteLiteral(_)));
         }
     }
 
@@ -268,16 +1168,34 @@ mod tests {
         let byte_literals = delta
             .content
             .iter()
-            .filter(|x| matches!(x, Token::ByteLiteral(_)));
+            .filter(|x| matches!(x, TokenRef::ByteLiteral(_)));
         let block_indexes = delta
             .content
             .iter()
-            .filter(|x| matches!(x, Token::BlockIndex(_)));
+            .filter(|x| matches!(x, TokenRef::BlockIndex(_)));
 
         assert!(byte_literals.count() > 0);
         assert!(block_indexes.count() > 0);
     }
 
+    #[test]
+    fn chunk_sizes_which_could_overflow_are_errors() {
+        let signature = compute_signature(Bytes::from("Hello World!"), 5);
+
+        for chunk_size in [0, usize::MAX, usize::MAX / 2 + 1] {
+            let result = stream_delta_with_mode(
+                &signature,
+                &Bytes::from("Hello World!"),
+                chunk_size,
+                ChunkingMode::Fixed,
+                &MatchingOptions::default(),
+                &mut TokenBuffer::default(),
+            );
+
+            assert!(result.is_err(), "{chunk_size}");
+        }
+    }
+
     #[test]
     fn chunk_size_bigger_means_only_literals() {
         let test_chunk_size = 100;
@@ -292,8 +1210,253 @@ mod tests {
         let block_indexes = delta
             .content
             .iter()
-            .filter(|x| matches!(x, Token::BlockIndex(_)));
+            .filter(|x| matches!(x, TokenRef::BlockIndex(_)));
 
         assert_eq!(block_indexes.count(), 0);
     }
+
+    #[test]
+    fn lines_mode_references_unchanged_lines() {
+        let basis_file = Bytes::from("first\nsecond\nthird\n");
+        let updated_file = Bytes::from("first\nchanged\nthird\n");
+
+        let signature = compute_signature_with_mode(basis_file, 0, ChunkingMode::Lines);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file,
+            0,
+            ChunkingMode::Lines,
+            &MatchingOptions::default(),
+        );
+
+        let mut expected = vec![Token::BlockIndex(0)];
+        expected.extend(b"changed\n".iter().copied().map(Token::ByteLiteral));
+        expected.push(Token::BlockIndex(2));
+        assert_eq!(delta.content, expected);
+    }
+
+    #[test]
+    fn delta_is_deterministic_when_basis_has_repeated_blocks() {
+        let test_chunk_size = 3;
+
+        // Many blocks share the same hashes, so which one is referenced must not depend
+        // on the order of any hash map.
+        let basis_file = Bytes::from("ABCABCABCxyzABCABC");
+        let updated_file = Bytes::from("xyzABCABC-ABCABCABC");
+
+        let serialize = || -> Bytes {
+            let signature = compute_signature(basis_file.clone(), test_chunk_size);
+            compute_delta_to_our_file(signature, updated_file.clone(), test_chunk_size)
+                .try_into()
+                .unwrap()
+        };
+
+        let first = serialize();
+        for _ in 0..10 {
+            assert_eq!(serialize(), first);
+        }
+    }
+
+    #[test]
+    fn sliding_rolling_hashes_do_not_depend_on_the_number_of_threads() {
+        let test_chunk_size = 16;
+        let updated_file: Vec<u8> = (0..100_000_u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        let serial =
+            compute_sliding_rolling_hashes(&updated_file, test_chunk_size, WeakHash::Polynomial);
+        for threads in [2, 5, 16] {
+            let parallelism = Parallelism::with_threads(threads.try_into().unwrap());
+            let parallel = compute_sliding_rolling_hashes_in_parallel(
+                &updated_file,
+                test_chunk_size,
+                WeakHash::Polynomial,
+                &parallelism,
+            );
+            assert_eq!(parallel, serial);
+        }
+        assert_eq!(serial.len(), updated_file.len() - test_chunk_size + 1);
+    }
+
+    #[test]
+    fn resync_matches_block_boundaries_after_a_literal_run() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCCDDDDxBBC");
+        // "BBBB" was replaced. "xBBC" is a basis block too, but matching it (as the matcher
+        // would, byte by byte) leaves the following blocks misaligned.
+        let updated_file = Bytes::from("AAAAxxBBCCCCDDDD");
+        let options = MatchingOptions {
+            resync_after: Some(1),
+            ..Default::default()
+        };
+
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file.clone(),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &options,
+        );
+
+        let mut expected = vec![Token::BlockIndex(0)];
+        expected.extend(b"xxBB".iter().copied().map(Token::ByteLiteral));
+        expected.extend([Token::BlockIndex(2), Token::BlockIndex(3)]);
+        assert_eq!(delta.content, expected);
+        assert_eq!(
+            crate::domain::apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn match_preference_chooses_among_repeated_blocks() {
+        let test_chunk_size = 3;
+        let basis_file = Bytes::from("ABCxyzABCuvwABC");
+        let updated_file = Bytes::from("uvwABCABC");
+        let delta_with = |preference| {
+            let signature = compute_signature(basis_file.clone(), test_chunk_size);
+            let options = MatchingOptions {
+                preference,
+                ..Default::default()
+            };
+            compute_delta_with_mode(
+                signature,
+                updated_file.clone(),
+                test_chunk_size,
+                ChunkingMode::Fixed,
+                &options,
+            )
+            .content
+        };
+
+        use Token::BlockIndex;
+        assert_eq!(
+            delta_with(MatchPreference::Closest),
+            vec![BlockIndex(3), BlockIndex(4), BlockIndex(0)]
+        );
+        assert_eq!(
+            delta_with(MatchPreference::First),
+            vec![BlockIndex(3), BlockIndex(0), BlockIndex(0)]
+        );
+        assert_eq!(
+            delta_with(MatchPreference::Any),
+            vec![BlockIndex(3), BlockIndex(4), BlockIndex(4)]
+        );
+    }
+
+    #[test]
+    fn deltas_against_weak_only_signatures_are_flagged() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("ABCDEFGHIJKL");
+        let signature = compute_signature_with_hashes(
+            basis_file.clone(),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            WeakHash::default(),
+            StrongHash::Omitted,
+            &Parallelism::serial(),
+        );
+
+        let delta = compute_delta_with_mode(
+            signature,
+            Bytes::from("xxABCDEFGHIJKL"),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+        );
+
+        assert!(delta.header.unconfirmed);
+        let read_back = Delta::try_from(Bytes::try_from(delta.clone()).unwrap()).unwrap();
+        assert_eq!(read_back, delta);
+        assert_eq!(
+            crate::domain::apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            Bytes::from("xxABCDEFGHIJKL")
+        );
+    }
+
+    #[test]
+    fn long_runs_of_zeros_are_sent_as_zero_runs() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from_static(b"AAAA\0\0\0\0BBBB");
+        let updated_file = Bytes::from([b"AAAA".as_slice(), &[0; 10], b"x\0\0\0BBBB"].concat());
+        let options = MatchingOptions {
+            zero_runs: Some(8),
+            ..Default::default()
+        };
+
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file.clone(),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &options,
+        );
+
+        // Even though the basis file has a block of zeros, and the last run is too short.
+        let mut expected = vec![Token::BlockIndex(0), Token::ZeroRun(10)];
+        expected.extend(b"x\0\0\0".iter().copied().map(Token::ByteLiteral));
+        expected.push(Token::BlockIndex(2));
+        assert_eq!(delta.content, expected);
+        assert_eq!(
+            crate::domain::apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn digests_built_in_pieces_are_the_digest_of_the_whole_file() {
+        let file = b"0123456789abcdefghijklmnopqrstuvwxyz".repeat(10);
+
+        let mut builder = FileDigestBuilder::default();
+        for piece in file.chunks(7) {
+            builder.update(piece);
+        }
+
+        assert_eq!(builder.finish(), FileDigest::of(&file));
+        assert!(FileDigest::of(&file)
+            .check_recreated(FileDigest::of(&file[1..]))
+            .is_err());
+    }
+
+    #[test]
+    fn tokens_of_unknown_kinds_are_skipped_or_refused() {
+        // As a later version could write them, with a new kind of token after the existing ones.
+        #[derive(Serialize)]
+        #[serde(rename = "Token")]
+        #[allow(dead_code)]
+        enum LaterToken {
+            BlockIndex(usize),
+            ByteLiteral(u8),
+            BlockRange(Range<usize>),
+            ByteLiterals(Vec<u8>),
+            ZeroRun(usize),
+            Copy { offset: u64, length: u64 },
+        }
+        #[derive(Serialize)]
+        struct LaterDelta {
+            header: DeltaHeader,
+            content: Vec<LaterToken>,
+        }
+        let later_delta = LaterDelta {
+            header: DeltaHeader::default(),
+            content: vec![
+                LaterToken::BlockIndex(1),
+                LaterToken::Copy {
+                    offset: 4,
+                    length: 8,
+                },
+                LaterToken::ByteLiterals(b"xy".to_vec()),
+            ],
+        };
+        let bytes = encode_artifact(ArtifactKind::Delta, &later_delta).unwrap();
+
+        assert!(Delta::try_from(bytes.clone()).is_err());
+        let delta = Delta::decode(bytes, UnknownTokens::Skip).unwrap();
+        assert_eq!(
+            delta.content,
+            vec![Token::BlockIndex(1), Token::ByteLiterals(b"xy".to_vec())]
+        );
+        assert_eq!(delta.content.unknown_tokens(), 1);
+    }
 }
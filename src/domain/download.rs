@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+use bytes::{Bytes, BytesMut};
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    calculate_strong_hash, check_chunk_size, compute_fixed_delta, compute_provenance_map,
+    compute_sliding_rolling_hashes, decode_artifact, encode_artifact, ArtifactKind, BasisLayout,
+    FileSignature, MatchingOptions, Source,
+};
+use crate::help::Help;
+
+/// Where a client downloading an updated file finds each of its blocks: in its own older version
+/// of the file, or in the blocks it must request.
+///
+/// The roles of the rsync algorithm are reversed: the server publishes the Signature of the
+/// updated file once, each client computes the Delta of its own file against it, and the blocks
+/// that Delta references are those the client already has. Only the others are requested, so the
+/// server does no work per client but reading the requested blocks, as zsync does.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DownloadPlan {
+    pub chunk_size: usize,
+    /// Layout of the updated file, from its Signature.
+    pub layout: BasisLayout,
+    /// Where each block of the updated file is in the client's.
+    local_offsets: Vec<Option<u64>>,
+}
+
+/// The blocks of an updated file a client does not have, sent to the server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BlockRequest {
+    pub chunk_size: usize,
+    /// Of the updated file, so the server can tell it is asked for blocks of the right one.
+    pub file_size: u64,
+    /// Indexes of the blocks requested, in order.
+    pub missing: Vec<Range<usize>>,
+}
+
+impl DownloadPlan {
+    /// Finds the blocks of the updated file, described by `signature`, that `basis_file` has.
+    ///
+    /// # Arguments
+    /// * `signature` - The Signature of the whole updated file, published by the server.
+    /// * `basis_file` - The client's version of the file.
+    /// * `chunk_size` - The size for each block used in the Signature.
+    /// * `options` - How the matcher is tuned.
+    ///
+    pub fn compute(
+        signature: &FileSignature,
+        basis_file: &[u8],
+        chunk_size: usize,
+        options: &MatchingOptions,
+    ) -> eyre::Result<Self> {
+        check_chunk_size(chunk_size)?;
+        let Some(layout) = signature.basis else {
+            return Err(eyre!("Signature does not describe the whole updated file"))
+                .suggestion("Compute the Signature of the updated file with `signature`.");
+        };
+        if layout
+            .chunk_size()
+            .is_some_and(|signature_chunk_size| signature_chunk_size != chunk_size as u64)
+        {
+            return Err(eyre!(
+                "Signature describes {layout}, but the chunk size is {chunk_size}"
+            ))
+            .suggestion("Use the same `--chunk-size` used to compute the Signature.");
+        }
+
+        let rolling_hashes =
+            compute_sliding_rolling_hashes(basis_file, chunk_size, signature.weak_hash);
+        let delta =
+            compute_fixed_delta(signature, basis_file, chunk_size, &rolling_hashes, options);
+        // The Delta recreates our file from the updated one, so each region it copies is a region
+        // of the updated file we have.
+        let map = compute_provenance_map(&delta, chunk_size, Some(layout.length as usize));
+        let mut local_offsets = vec![None; signature.strong_hashes.len()];
+        for entry in map.entries {
+            let Source::Basis { basis_offset } = entry.source else {
+                continue;
+            };
+            let blocks =
+                basis_offset / chunk_size..(basis_offset + entry.length).div_ceil(chunk_size);
+            for block in blocks {
+                let offset = entry.output_offset + block * chunk_size - basis_offset;
+                local_offsets[block].get_or_insert(offset as u64);
+            }
+        }
+
+        Ok(Self {
+            chunk_size,
+            layout,
+            local_offsets,
+        })
+    }
+
+    /// The blocks to request from the server.
+    pub fn request(&self) -> BlockRequest {
+        let mut missing: Vec<Range<usize>> = Vec::new();
+        for (block, _) in self
+            .local_offsets
+            .iter()
+            .enumerate()
+            .filter(|(_, offset)| offset.is_none())
+        {
+            match missing.last_mut() {
+                Some(range) if range.end == block => range.end += 1,
+                _ => missing.push(block..block + 1),
+            }
+        }
+
+        BlockRequest {
+            chunk_size: self.chunk_size,
+            file_size: self.layout.length,
+            missing,
+        }
+    }
+
+    /// Bytes of the updated file the client already has.
+    pub fn local_size(&self) -> u64 {
+        self.layout.length - self.request().size()
+    }
+
+    /// Rebuilds the updated file from `basis_file` and the `blocks` the server sent for the
+    /// request, checking each block against `signature` and the whole file against its hash.
+    pub fn assemble(
+        &self,
+        signature: &FileSignature,
+        basis_file: &[u8],
+        blocks: &[u8],
+    ) -> eyre::Result<Bytes> {
+        let request = self.request();
+        if blocks.len() as u64 != request.size() {
+            return Err(eyre!(
+                "{} bytes of blocks were received, but {} were requested",
+                blocks.len(),
+                request.size()
+            ));
+        }
+
+        let mut updated_file = BytesMut::with_capacity(self.layout.length as usize);
+        let mut received = 0;
+        for (block, offset) in self.local_offsets.iter().enumerate() {
+            let length = request.block_length(block);
+            let content = match offset {
+                Some(offset) => &basis_file[*offset as usize..][..length],
+                None => {
+                    let content = &blocks[received..received + length];
+                    received += length;
+                    if signature.strong_hash.hash(content) != signature.strong_hashes[block] {
+                        return Err(eyre!("Block {block} received is not the one requested"));
+                    }
+                    content
+                }
+            };
+            updated_file.extend_from_slice(content);
+        }
+        let updated_file = updated_file.freeze();
+        if self
+            .layout
+            .strong_hash
+            .is_some_and(|strong_hash| strong_hash != calculate_strong_hash(&updated_file))
+        {
+            return Err(eyre!("Assembled file does not match the Signature"));
+        }
+
+        Ok(updated_file)
+    }
+}
+
+impl BlockRequest {
+    /// Bytes of the blocks requested.
+    pub fn size(&self) -> u64 {
+        self.missing
+            .iter()
+            .flat_map(Clone::clone)
+            .map(|block| self.block_length(block) as u64)
+            .sum()
+    }
+
+    // The last block of the file may be shorter.
+    fn block_length(&self, block: usize) -> usize {
+        let start = (block * self.chunk_size) as u64;
+        self.file_size
+            .saturating_sub(start)
+            .min(self.chunk_size as u64) as usize
+    }
+}
+
+/// The blocks of `updated_file` requested, one after the other, as the server answers a
+/// BlockRequest.
+pub fn answer_block_request(updated_file: &[u8], request: &BlockRequest) -> eyre::Result<Bytes> {
+    if request.file_size != updated_file.len() as u64 {
+        return Err(eyre!(
+            "Blocks of a file of {} bytes were requested, but it has {}",
+            request.file_size,
+            updated_file.len()
+        ))
+        .suggestion("The file changed since its Signature was published: publish it again.");
+    }
+
+    let mut blocks = BytesMut::with_capacity(request.size() as usize);
+    for range in &request.missing {
+        let start = range.start.saturating_mul(request.chunk_size);
+        let end = range
+            .end
+            .saturating_mul(request.chunk_size)
+            .min(updated_file.len());
+        let content = updated_file
+            .get(start..end)
+            .ok_or_else(|| eyre!("Blocks {range:?} are not in the file"))?;
+        blocks.extend_from_slice(content);
+    }
+
+    Ok(blocks.freeze())
+}
+
+impl TryFrom<BlockRequest> for Bytes {
+    type Error = eyre::Report;
+
+    fn try_from(request: BlockRequest) -> Result<Self, Self::Error> {
+        encode_artifact(ArtifactKind::BlockRequest, &request)
+    }
+}
+
+impl TryFrom<Bytes> for BlockRequest {
+    type Error = eyre::Report;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        let request = decode_artifact(ArtifactKind::BlockRequest, bytes)
+            .wrap_err("Could not read Block Request from file provided.")
+            .suggestion(
+                "Did you provide the correct path for the Block Request file?\n\
+                         It must have been generated as an output from a previous `request-blocks` command.",
+            )?;
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::compute_signature;
+
+    use super::*;
+
+    #[test]
+    fn only_missing_blocks_are_requested() {
+        let basis_file = b"AAAABBBBCCCCDDDD";
+        let updated_file = Bytes::from_static(b"xxAAyyBBzzCCwwDD");
+        let signature = compute_signature(updated_file.clone(), 4);
+
+        let plan =
+            DownloadPlan::compute(&signature, basis_file, 4, &MatchingOptions::default()).unwrap();
+        let request = BlockRequest::try_from(Bytes::try_from(plan.request()).unwrap()).unwrap();
+        // Blocks are "xxAA", "yyBB", "zzCC" and "wwDD": none is anywhere in our file whole.
+        assert_eq!(request.missing, vec![0..4]);
+
+        let updated_file = Bytes::from_static(b"AAAABBBBCCCCzzDDDD");
+        let signature = compute_signature(updated_file.clone(), 4);
+        let plan =
+            DownloadPlan::compute(&signature, basis_file, 4, &MatchingOptions::default()).unwrap();
+        let request = plan.request();
+        // The short last block "DD" is found at the end of our file.
+        assert_eq!(request.missing, vec![3..4]);
+        assert_eq!(plan.local_size(), 14);
+
+        let blocks = answer_block_request(&updated_file, &request).unwrap();
+        assert_eq!(blocks, "zzDD");
+        assert_eq!(
+            plan.assemble(&signature, basis_file, &blocks).unwrap(),
+            updated_file
+        );
+        assert!(plan.assemble(&signature, basis_file, b"zzDX").is_err());
+    }
+}
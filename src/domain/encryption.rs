@@ -0,0 +1,191 @@
+use std::io::{Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use age::secrecy::SecretString;
+use bytes::Bytes;
+use eyre::{eyre, Context};
+
+use crate::help::Help;
+
+// Every age file starts with this line, unlike any of our artifacts.
+const AGE_HEADER: &[u8] = b"age-encryption.org/";
+
+/// Keys to encrypt the artifacts we write, and to decrypt the ones we read, with age.
+///
+/// Encryption is authenticated: an artifact which was tampered with fails to decrypt.
+#[derive(Default)]
+pub struct ArtifactKeys {
+    /// Encrypts and decrypts, like `age --passphrase`.
+    pub passphrase: Option<SecretString>,
+    /// Public keys of who may decrypt the artifacts we write.
+    pub recipients: Vec<age::x25519::Recipient>,
+    /// Private keys tried on the artifacts we read.
+    pub identities: Vec<Box<dyn age::Identity>>,
+}
+
+impl ArtifactKeys {
+    /// Loads keys from the files and public keys given on the command line.
+    ///
+    /// # Arguments
+    /// * `passphrase_file` - File whose first line is the passphrase.
+    /// * `recipients` - age public keys (`age1...`).
+    /// * `identity_files` - age identity files, as created by `age-keygen`.
+    ///
+    pub fn load(
+        passphrase_file: Option<&Path>,
+        recipients: &[String],
+        identity_files: &[PathBuf],
+    ) -> eyre::Result<Self> {
+        let passphrase = passphrase_file
+            .map(|path| -> eyre::Result<SecretString> {
+                let content = std::fs::read_to_string(path).wrap_err(format!(
+                    r#"Could not read passphrase file "{}""#,
+                    path.display()
+                ))?;
+                let passphrase = content.lines().next().unwrap_or_default();
+                if passphrase.is_empty() {
+                    return Err(eyre!(r#"Passphrase file "{}" is empty"#, path.display()));
+                }
+                Ok(SecretString::from(passphrase.to_string()))
+            })
+            .transpose()?;
+        let recipients = recipients
+            .iter()
+            .map(|recipient| {
+                age::x25519::Recipient::from_str(recipient)
+                    .map_err(|error| eyre!(r#"Invalid recipient "{recipient}": {error}"#))
+            })
+            .collect::<eyre::Result<_>>()?;
+        let mut identities = Vec::new();
+        for path in identity_files {
+            let identity_file = age::IdentityFile::from_file(path.display().to_string()).wrap_err(
+                format!(r#"Could not read identity file "{}""#, path.display()),
+            )?;
+            identities.extend(identity_file.into_identities()?);
+        }
+
+        Ok(Self {
+            passphrase,
+            recipients,
+            identities,
+        })
+    }
+
+    /// Whether the artifacts we write are encrypted.
+    pub fn encrypts(&self) -> bool {
+        self.passphrase.is_some() || !self.recipients.is_empty()
+    }
+
+    /// Whether the artifacts we read must be encrypted.
+    pub fn decrypts(&self) -> bool {
+        self.passphrase.is_some() || !self.identities.is_empty()
+    }
+
+    /// Encrypts `artifact`, or returns it unchanged if no key to encrypt with was given.
+    pub fn encrypt(&self, artifact: Bytes) -> eyre::Result<Bytes> {
+        let encryptor = match (&self.passphrase, self.recipients.as_slice()) {
+            (None, []) => return Ok(artifact),
+            (Some(passphrase), []) => age::Encryptor::with_user_passphrase(passphrase.clone()),
+            (None, recipients) => age::Encryptor::with_recipients(
+                recipients
+                    .iter()
+                    .map(|recipient| recipient as &dyn age::Recipient),
+            )?,
+            (Some(_), _) => return Err(eyre!("A passphrase cannot be combined with recipients")),
+        };
+
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(&artifact)?;
+        writer.finish()?;
+
+        Ok(Bytes::from(encrypted))
+    }
+
+    /// Decrypts `artifact`.
+    ///
+    /// Artifacts must be encrypted exactly when we have keys to decrypt them, so an
+    /// intermediary cannot replace an encrypted artifact with a plain one.
+    pub fn decrypt(&self, artifact: Bytes) -> eyre::Result<Bytes> {
+        match (is_encrypted(&artifact), self.decrypts()) {
+            (false, false) => return Ok(artifact),
+            (false, true) => return Err(eyre!("Artifact is not encrypted")),
+            (true, false) => {
+                return Err(eyre!("Artifact is encrypted"))
+                    .suggestion("Provide `--passphrase-file` or `--identity` to decrypt it.")
+            }
+            (true, true) => {}
+        }
+
+        let decryptor = age::Decryptor::new_buffered(&artifact[..])?;
+        let mut reader = match &self.passphrase {
+            Some(passphrase) if decryptor.is_scrypt() => {
+                let identity = age::scrypt::Identity::new(passphrase.clone());
+                decryptor.decrypt(iter::once(&identity as &dyn age::Identity))?
+            }
+            _ => decryptor.decrypt(self.identities.iter().map(|identity| identity.as_ref()))?,
+        };
+        let mut decrypted = Vec::new();
+        reader
+            .read_to_end(&mut decrypted)
+            .wrap_err("Encrypted artifact was tampered with or is truncated")?;
+
+        Ok(Bytes::from(decrypted))
+    }
+}
+
+/// Tells whether `artifact` was encrypted with age.
+pub fn is_encrypted(artifact: &[u8]) -> bool {
+    artifact.starts_with(AGE_HEADER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys_for(identity: &age::x25519::Identity) -> ArtifactKeys {
+        ArtifactKeys {
+            recipients: vec![identity.to_public()],
+            identities: vec![Box::new(identity.clone())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encrypted_artifact_decrypts_with_the_right_key_only() {
+        let identity = age::x25519::Identity::generate();
+        let artifact = Bytes::from("RRSG some artifact");
+
+        let encrypted = keys_for(&identity).encrypt(artifact.clone()).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(
+            keys_for(&identity).decrypt(encrypted.clone()).unwrap(),
+            artifact
+        );
+        let other_keys = keys_for(&age::x25519::Identity::generate());
+        assert!(other_keys.decrypt(encrypted.clone()).is_err());
+        assert!(ArtifactKeys::default().decrypt(encrypted).is_err());
+    }
+
+    #[test]
+    fn tampered_or_plain_artifacts_are_refused() {
+        let identity = age::x25519::Identity::generate();
+        let artifact = Bytes::from("RRDL some artifact");
+        let mut encrypted = keys_for(&identity)
+            .encrypt(artifact.clone())
+            .unwrap()
+            .to_vec();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+
+        assert!(keys_for(&identity).decrypt(Bytes::from(encrypted)).is_err());
+        assert!(keys_for(&identity).decrypt(artifact.clone()).is_err());
+        assert_eq!(
+            ArtifactKeys::default().decrypt(artifact.clone()).unwrap(),
+            artifact
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::io_utils;
+
+/// Bytes of a memory page, and of a block on most file systems.
+pub const PAGE_SIZE: usize = 4096;
+
+/// `align_chunk_size` for the basis file at `basis_filename`, with the extents the file system
+/// reports for it. Files whose extents can not be read, on other file systems or platforms, are
+/// aligned with pages only.
+pub fn align_chunk_size_to_file(basis_filename: &Path, chunk_size: usize) -> usize {
+    let extents = File::open(basis_filename)
+        .and_then(|file| io_utils::file_extents(&file))
+        .ok();
+    align_chunk_size(chunk_size, extents.as_deref())
+}
+
+/// A chunk size close to `chunk_size`, and no larger, whose blocks line up with the extents of
+/// the basis file, or at least with its pages.
+///
+/// Blocks which start on a page are read with fewer page faults when a file is patched in place,
+/// and blocks which start on an extent can be shared by reflinks instead of copied. Chunk sizes
+/// smaller than a page are rounded down to a power of two, so each page starts a block. Larger
+/// ones are rounded down to whole pages, and down to a divisor of where every extent starts, if
+/// that does not make blocks less than half as large as asked for.
+///
+/// # Arguments
+/// * `chunk_size` - The size for each block asked for.
+/// * `extents` - Where the file system stores the basis file, as `io_utils::file_extents` tells.
+///
+pub fn align_chunk_size(chunk_size: usize, extents: Option<&[Range<u64>]>) -> usize {
+    if chunk_size < PAGE_SIZE {
+        return 1 << chunk_size.max(1).ilog2();
+    }
+
+    let page_size = PAGE_SIZE as u64;
+    let pages = (chunk_size / PAGE_SIZE) as u64;
+    let Some(extent_pages) = extents
+        .and_then(extent_alignment)
+        .filter(|alignment| alignment.is_multiple_of(page_size))
+        .map(|alignment| alignment / page_size)
+    else {
+        return (pages * page_size) as usize;
+    };
+    let aligned_pages = (1..=pages.min(extent_pages))
+        .rev()
+        .find(|divisor| extent_pages.is_multiple_of(*divisor))
+        .unwrap_or(1);
+
+    match aligned_pages * 2 >= pages {
+        true => (aligned_pages * page_size) as usize,
+        false => (pages * page_size) as usize,
+    }
+}
+
+// The largest size dividing every boundary between extents (and holes), so blocks of that size
+// never straddle one. The end of the file is not a boundary, as nothing follows it.
+fn extent_alignment(extents: &[Range<u64>]) -> Option<u64> {
+    let file_end = extents.last()?.end;
+    let alignment = extents
+        .iter()
+        .flat_map(|extent| [extent.start, extent.end])
+        .filter(|&boundary| boundary != 0 && boundary != file_end)
+        .fold(0, gcd);
+
+    (alignment != 0).then_some(alignment)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_line_up_with_pages_and_extents() {
+        const KIB: u64 = 1024;
+        // Small blocks divide pages.
+        assert_eq!(align_chunk_size(10, None), 8);
+        assert_eq!(align_chunk_size(1024, None), 1024);
+        // Large blocks are whole pages.
+        assert_eq!(align_chunk_size(10_000, None), 8192);
+
+        // Every extent boundary is a multiple of 1 MiB, so blocks of 8 pages never straddle one.
+        let extents = [0..1024 * KIB, 1024 * KIB..1036 * KIB];
+        assert_eq!(align_chunk_size(50_000, Some(&extents)), 32 * 1024);
+        // Extents of 3 pages would make blocks four times smaller: pages are enough.
+        let extents = [0..12 * KIB, 12 * KIB..1024 * KIB];
+        assert_eq!(align_chunk_size(50_000, Some(&extents)), 48 * 1024);
+        assert_eq!(align_chunk_size(13_000, Some(&extents)), 12 * 1024);
+    }
+}
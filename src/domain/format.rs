@@ -0,0 +1,197 @@
+//! The format of the files we save Signatures, Deltas and Manifests in.
+//!
+//! Every artifact starts with a preamble of fixed-width, little-endian fields:
+//! 1 - 4 bytes of magic, telling which kind of artifact it is.
+//! 2 - the format version, as an u16.
+//! 3 - the encoding of the payload, as an u8.
+//!
+//! The payload is encoded with MessagePack, in which every integer is explicitly tagged with its
+//! width and written in big-endian order, regardless of the machine writing it. Together with hashes
+//! that only depend on the bytes being hashed, this means an artifact created on any architecture
+//! can be used on any other.
+//!
+//! The payload is either a single MessagePack value, or (for Deltas written while being computed)
+//! a sequence of frames, each a little-endian u32 length followed by a MessagePack value.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use eyre::eyre;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Version of the artifact format written by this build.
+///
+/// Version 2 hashes blocks which are not valid UTF-8 by their raw bytes, as `delta` rolls them.
+/// Version 1 hashed them after a lossy UTF-8 conversion: such blocks of its Signatures never match.
+/// Version 2 also hashes whole files with xxh3-64, see `hashes_files_with_siphash`.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Versions of the artifact format read by this build, see `compatibility`.
+pub const FORMAT_VERSIONS: &[u16] = &[1, 2];
+
+/// Whether artifacts of `version` hashed whole files with SipHash, as `DefaultHasher` computes
+/// it, rather than with xxh3-64.
+///
+/// SipHash is only kept from one Rust release to the next by convention, so this build does not
+/// check such hashes: they are left out where they are optional, and artifacts which need them
+/// are refused.
+pub fn hashes_files_with_siphash(version: u16) -> bool {
+    version < 2
+}
+
+pub const PREAMBLE_LENGTH: usize = 4 + 2 + 1;
+
+/// How the payload after the preamble is encoded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArtifactEncoding {
+    /// A single MessagePack value.
+    MessagePack = 1,
+    /// Length-prefixed MessagePack frames, see `streaming`.
+    MessagePackFrames = 2,
+}
+
+impl TryFrom<u8> for ArtifactEncoding {
+    type Error = eyre::Report;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        match tag {
+            1 => Ok(ArtifactEncoding::MessagePack),
+            2 => Ok(ArtifactEncoding::MessagePackFrames),
+            tag => Err(eyre!("Payload has an unknown encoding ({tag})")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArtifactKind {
+    Signature,
+    DeduplicatedSignature,
+    Delta,
+    Manifest,
+    BlockIndex,
+    BlockRequest,
+}
+
+impl ArtifactKind {
+    pub fn magic(&self) -> &'static [u8; 4] {
+        match self {
+            ArtifactKind::Signature => b"RRSG",
+            ArtifactKind::DeduplicatedSignature => b"RRSD",
+            ArtifactKind::Delta => b"RRDL",
+            ArtifactKind::Manifest => b"RRMF",
+            ArtifactKind::BlockIndex => b"RRBI",
+            ArtifactKind::BlockRequest => b"RRBQ",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArtifactKind::Signature => "Signature",
+            ArtifactKind::DeduplicatedSignature => "Deduplicated Signature",
+            ArtifactKind::Delta => "Delta",
+            ArtifactKind::Manifest => "Manifest",
+            ArtifactKind::BlockIndex => "Block Index",
+            ArtifactKind::BlockRequest => "Block Request",
+        }
+    }
+}
+
+/// The kind of artifact `bytes` holds, according to its magic.
+pub fn artifact_kind(bytes: &[u8]) -> Option<ArtifactKind> {
+    [
+        ArtifactKind::Signature,
+        ArtifactKind::DeduplicatedSignature,
+        ArtifactKind::Delta,
+        ArtifactKind::Manifest,
+        ArtifactKind::BlockIndex,
+        ArtifactKind::BlockRequest,
+    ]
+    .into_iter()
+    .find(|kind| bytes.starts_with(kind.magic()))
+}
+
+/// Serializes `artifact`, prefixed with the preamble for `kind`.
+pub fn encode_artifact<T: Serialize>(kind: ArtifactKind, artifact: &T) -> eyre::Result<Bytes> {
+    let payload = rmp_serde::to_vec(artifact)?;
+
+    let mut encoded = BytesMut::with_capacity(PREAMBLE_LENGTH + payload.len());
+    encoded.put_slice(&preamble(kind, ArtifactEncoding::MessagePack));
+    encoded.put_slice(&payload);
+
+    Ok(encoded.freeze())
+}
+
+/// The preamble of an artifact of `kind`, whose payload is encoded with `encoding`.
+pub fn preamble(kind: ArtifactKind, encoding: ArtifactEncoding) -> [u8; PREAMBLE_LENGTH] {
+    let mut preamble = [0; PREAMBLE_LENGTH];
+    preamble[..4].copy_from_slice(kind.magic());
+    preamble[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    preamble[6] = encoding as u8;
+    preamble
+}
+
+/// Checks the preamble for `kind` and deserializes the artifact after it.
+pub fn decode_artifact<T: DeserializeOwned>(
+    kind: ArtifactKind,
+    mut bytes: Bytes,
+) -> eyre::Result<T> {
+    match read_preamble(kind, &mut bytes)? {
+        ArtifactEncoding::MessagePack => Ok(rmp_serde::from_slice(&bytes)?),
+        ArtifactEncoding::MessagePackFrames => {
+            Err(eyre!("{} can not be read from frames", kind.name()))
+        }
+    }
+}
+
+/// Checks the preamble for `kind`, leaving `bytes` at the start of the payload.
+pub fn read_preamble(kind: ArtifactKind, bytes: &mut Bytes) -> eyre::Result<ArtifactEncoding> {
+    if bytes.len() < PREAMBLE_LENGTH || &bytes[..4] != kind.magic() {
+        return Err(eyre!("File is not a {}", kind.name()));
+    }
+    bytes.advance(4);
+
+    let version = bytes.get_u16_le();
+    if !FORMAT_VERSIONS.contains(&version) {
+        return Err(eyre!(
+            "{} has format version {version}, but only versions {FORMAT_VERSIONS:?} are supported",
+            kind.name()
+        ));
+    }
+
+    bytes.get_u8().try_into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preamble_is_little_endian() {
+        let encoded = encode_artifact(ArtifactKind::Delta, &vec![1_u64]).unwrap();
+
+        assert_eq!(&encoded[..4], b"RRDL");
+        assert_eq!(&encoded[4..6], FORMAT_VERSION.to_le_bytes());
+        assert_eq!(encoded[6], ArtifactEncoding::MessagePack as u8);
+    }
+
+    #[test]
+    fn artifacts_of_another_kind_are_rejected() {
+        let encoded = encode_artifact(ArtifactKind::Delta, &vec![1_u64]).unwrap();
+
+        let decoded: eyre::Result<Vec<u64>> =
+            decode_artifact(ArtifactKind::Signature, encoded.clone());
+        assert!(decoded.is_err());
+
+        let decoded: Vec<u64> = decode_artifact(ArtifactKind::Delta, encoded).unwrap();
+        assert_eq!(decoded, vec![1]);
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let mut encoded = BytesMut::from(&encode_artifact(ArtifactKind::Delta, &0_u8).unwrap()[..]);
+        encoded[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        let decoded: eyre::Result<u8> = decode_artifact(ArtifactKind::Delta, encoded.freeze());
+
+        assert!(decoded.is_err());
+    }
+}
@@ -0,0 +1,486 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    apply_delta_with_mode, compute_delta_with_mode, compute_signature, ChunkingMode, Delta,
+    FileDigest, FileSignature, MatchingOptions,
+};
+use crate::help::Help;
+use crate::io_utils;
+
+// Generations of a chain directory. The first one is stored whole, the others as Deltas.
+const CHAIN_FILENAME: &str = "chain.json";
+
+/// A version of the backed up file, stored as a Delta against its parent.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Generation {
+    pub id: u64,
+    /// The generation the Delta is computed against. The oldest generation is stored whole.
+    pub parent: Option<u64>,
+    /// The file this generation restores.
+    pub file: FileDigest,
+    /// When it was backed up, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub created: Option<u64>,
+    /// Bytes taken by the whole file, or by the Delta and the Signature.
+    pub stored_size: u64,
+}
+
+/// Every generation of a file backed up to a chain directory, oldest first.
+///
+/// Each backup computes the Delta of the file against the Signature of the latest generation,
+/// and stores it along with the Signature of the file, for the next backup.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct GenerationChain {
+    /// The size for each block, for every generation.
+    pub chunk_size: usize,
+    pub generations: Vec<Generation>,
+}
+
+impl GenerationChain {
+    /// Reads the chain stored in `directory`.
+    pub fn read(directory: &Path) -> eyre::Result<Self> {
+        let chain = fs::read(directory.join(CHAIN_FILENAME))
+            .wrap_err(format!(
+                "Unable to read the generations of {}",
+                directory.display()
+            ))
+            .suggestion("Back up a file to this directory first.")?;
+        serde_json::from_slice(&chain).wrap_err(format!(
+            "{} is not a chain of generations",
+            directory.join(CHAIN_FILENAME).display()
+        ))
+    }
+
+    pub fn latest(&self) -> Option<&Generation> {
+        self.generations.last()
+    }
+
+    pub fn get(&self, id: u64) -> eyre::Result<&Generation> {
+        self.generations
+            .iter()
+            .find(|generation| generation.id == id)
+            .ok_or_else(|| eyre!("There is no generation {id}"))
+            .suggestion(format!(
+                "Generations go from 0 to {}.",
+                self.generations.len().saturating_sub(1)
+            ))
+    }
+
+    // Replaces the chain at once, so it always lists generations which can be restored.
+    fn write(&self, directory: &Path) -> eyre::Result<()> {
+        let chain = serde_json::to_vec_pretty(self)?;
+        let new_chain_filename = directory.join(format!("{CHAIN_FILENAME}.new"));
+        io_utils::write_to_file(&new_chain_filename, chain.into())?;
+        fs::rename(new_chain_filename, directory.join(CHAIN_FILENAME))?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for GenerationChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for generation in &self.generations {
+            match generation.parent {
+                Some(parent) => write!(f, "Generation {} (from {parent})", generation.id)?,
+                None => write!(f, "Generation {} (whole)", generation.id)?,
+            }
+            writeln!(
+                f,
+                ": {}, stored in {} bytes",
+                generation.file, generation.stored_size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn base_filename(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id}.base"))
+}
+
+fn delta_filename(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id}.delta"))
+}
+
+fn signature_filename(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id}.signature"))
+}
+
+/// Stores `file` as a new generation in the chain directory, creating the chain if needed.
+///
+/// The first generation is stored whole. The others are stored as a Delta against the latest
+/// generation, computed from its Signature only, so older generations are never read.
+///
+/// # Arguments
+/// * `directory` - The chain directory.
+/// * `file` - The content of the file to back up.
+/// * `chunk_size` - The size for each block, when the chain is created. Later generations use the
+///   chunk size of the chain.
+/// * `options` - How the matcher is tuned.
+///
+pub fn back_up_generation(
+    directory: &Path,
+    file: Bytes,
+    chunk_size: usize,
+    options: &MatchingOptions,
+) -> eyre::Result<Generation> {
+    let mut chain = match directory.join(CHAIN_FILENAME).exists() {
+        true => GenerationChain::read(directory)?,
+        false => GenerationChain {
+            chunk_size,
+            generations: Vec::new(),
+        },
+    };
+    fs::create_dir_all(directory)?;
+
+    let parent = chain.latest().map(|generation| generation.id);
+    let id = parent.map_or(0, |parent| parent + 1);
+    let signature = compute_signature(file.clone(), chain.chunk_size);
+    let signature_bytes = Bytes::try_from(signature)?;
+    let stored_size = match parent {
+        None => {
+            io_utils::write_to_file(base_filename(directory, id), file.clone())?;
+            file.len() as u64
+        }
+        Some(parent) => {
+            let parent_signature = FileSignature::try_from(io_utils::attempt_to_read_file(
+                signature_filename(directory, parent),
+            )?)
+            .wrap_err(format!("Signature of generation {parent} is unreadable"))?;
+            let mut delta = compute_delta_with_mode(
+                parent_signature.clone(),
+                file.clone(),
+                chain.chunk_size,
+                ChunkingMode::Fixed,
+                options,
+            );
+            delta.header.updated = Some(FileDigest::of(&file));
+            delta.optimize_against(&parent_signature);
+            let delta_bytes = Bytes::try_from(delta)?;
+            let delta_size = delta_bytes.len() as u64;
+            io_utils::write_to_file(delta_filename(directory, id), delta_bytes)?;
+            delta_size + signature_bytes.len() as u64
+        }
+    };
+    io_utils::write_to_file(signature_filename(directory, id), signature_bytes)?;
+
+    // The chain is written last, so an interrupted backup leaves the previous generations intact.
+    let generation = Generation {
+        id,
+        parent,
+        file: FileDigest::of(&file),
+        created: Some(seconds_since_epoch(SystemTime::now())),
+        stored_size,
+    };
+    chain.generations.push(generation);
+    chain.write(directory)?;
+
+    Ok(generation)
+}
+
+/// Restores a generation, replaying the Deltas from the whole generation it descends from.
+///
+/// Each generation restored on the way is checked against the file it was backed up from.
+///
+/// # Arguments
+/// * `directory` - The chain directory.
+/// * `id` - The generation to restore, or the latest one.
+/// * `max_output_size` - The largest generation allowed, in bytes.
+///
+pub fn restore_generation(
+    directory: &Path,
+    id: Option<u64>,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    let chain = GenerationChain::read(directory)?;
+    let target = match id {
+        Some(id) => chain.get(id)?,
+        None => chain
+            .latest()
+            .ok_or_else(|| eyre!("There are no generations to restore"))?,
+    };
+
+    restore_lineage(directory, &chain, target, max_output_size)
+}
+
+fn restore_lineage(
+    directory: &Path,
+    chain: &GenerationChain,
+    target: &Generation,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    // Walks back to the whole generation, then replays the Deltas forwards.
+    let mut lineage = vec![target];
+    while let Some(parent) = lineage.last().and_then(|generation| generation.parent) {
+        if lineage.len() > chain.generations.len() {
+            return Err(eyre!("Generations of {} form a loop", directory.display()));
+        }
+        lineage.push(chain.get(parent)?);
+    }
+    let mut file = Bytes::new();
+    for generation in lineage.into_iter().rev() {
+        file = match generation.parent {
+            None => io_utils::attempt_to_read_file(base_filename(directory, generation.id))?,
+            Some(_) => {
+                let delta = Delta::try_from(io_utils::attempt_to_read_file(delta_filename(
+                    directory,
+                    generation.id,
+                ))?)?;
+                apply_delta_with_mode(
+                    file,
+                    delta,
+                    chain.chunk_size,
+                    ChunkingMode::Fixed,
+                    max_output_size,
+                )?
+            }
+        };
+        if FileDigest::of(&file) != generation.file {
+            return Err(eyre!(
+                "Generation {} does not restore the file it was backed up from",
+                generation.id
+            ))
+            .suggestion("The chain directory was modified or corrupted since.");
+        }
+    }
+
+    Ok(file)
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Which generations `prune_generations` keeps. The latest generation is always kept.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// Keep this many of the latest generations.
+    pub keep_last: Option<usize>,
+    /// Keep the generations backed up this recently.
+    pub keep_within: Option<Duration>,
+}
+
+/// What pruning a chain of generations did, or would do.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PruneReport {
+    /// Generations which can no longer be restored.
+    pub pruned: Vec<u64>,
+    /// The oldest generation kept, now stored whole.
+    pub rebased: Option<u64>,
+    pub deleted_files: Vec<PathBuf>,
+    /// Bytes of the deleted files. The rebased generation takes some of them again.
+    pub freed_size: u64,
+    /// Whether nothing was changed.
+    pub dry_run: bool,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (prune, delete, is) = match self.dry_run {
+            true => ("Would prune", "Would delete", "would be"),
+            false => ("Pruned", "Deleted", "is"),
+        };
+        if self.pruned.is_empty() {
+            return write!(f, "No generation to prune");
+        }
+        let pruned: Vec<String> = self.pruned.iter().map(u64::to_string).collect();
+        writeln!(f, "{prune} generations {}", pruned.join(", "))?;
+        if let Some(rebased) = self.rebased {
+            writeln!(f, "Generation {rebased} {is} stored whole")?;
+        }
+        for filename in &self.deleted_files {
+            writeln!(f, "{delete} {}", filename.display())?;
+        }
+        write!(f, "{} bytes {is} freed", self.freed_size)
+    }
+}
+
+/// Deletes the generations the policy does not keep, without breaking the restore of the others.
+///
+/// Generations are kept from the oldest one the policy keeps onwards, since each is restored from
+/// the previous ones. That oldest one is restored, checked and stored whole before anything else
+/// is deleted, so an interrupted pruning leaves every generation restorable.
+///
+/// # Arguments
+/// * `directory` - The chain directory.
+/// * `policy` - Which generations to keep.
+/// * `dry_run` - Only report what would be deleted.
+/// * `max_output_size` - The largest generation allowed, in bytes.
+///
+pub fn prune_generations(
+    directory: &Path,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    max_output_size: u64,
+) -> eyre::Result<PruneReport> {
+    if policy.keep_last.is_none() && policy.keep_within.is_none() {
+        return Err(eyre!("Pruning needs a retention policy"))
+            .suggestion("Tell how many generations to keep, or for how long.");
+    }
+    let chain = GenerationChain::read(directory)?;
+    let mut report = PruneReport {
+        dry_run,
+        ..Default::default()
+    };
+    let now = seconds_since_epoch(SystemTime::now());
+    let count = chain.generations.len();
+    let kept = |index: usize, generation: &Generation| {
+        let recent = policy.keep_within.is_some_and(|within| {
+            generation
+                .created
+                .is_some_and(|created| created.saturating_add(within.as_secs()) >= now)
+        });
+        recent || index + 1 == count || policy.keep_last.is_some_and(|last| index + last >= count)
+    };
+    let Some(oldest_kept) = (0..count).find(|&index| kept(index, &chain.generations[index])) else {
+        return Ok(report);
+    };
+    if oldest_kept == 0 {
+        return Ok(report);
+    }
+
+    let (pruned, kept) = chain.generations.split_at(oldest_kept);
+    let pruned_ids: Vec<u64> = pruned.iter().map(|generation| generation.id).collect();
+    if let Some(orphan) = kept[1..].iter().find(|generation| {
+        generation
+            .parent
+            .is_some_and(|parent| pruned_ids.contains(&parent))
+    }) {
+        return Err(eyre!(
+            "Generation {} is restored from a generation which would be pruned",
+            orphan.id
+        ));
+    }
+    let rebased = kept[0];
+    report.pruned = pruned_ids;
+    report.rebased = rebased.parent.map(|_| rebased.id);
+    for generation in pruned {
+        report.deleted_files.extend([
+            base_filename(directory, generation.id),
+            delta_filename(directory, generation.id),
+            signature_filename(directory, generation.id),
+        ]);
+    }
+    if rebased.parent.is_some() {
+        report
+            .deleted_files
+            .push(delta_filename(directory, rebased.id));
+    }
+    report
+        .deleted_files
+        .retain(|filename| filename.symlink_metadata().is_ok());
+    report.freed_size = report
+        .deleted_files
+        .iter()
+        .filter_map(|filename| filename.symlink_metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    if dry_run {
+        return Ok(report);
+    }
+
+    let mut kept = kept.to_vec();
+    if rebased.parent.is_some() {
+        let file = restore_lineage(directory, &chain, &rebased, max_output_size)?;
+        kept[0].parent = None;
+        kept[0].stored_size = file.len() as u64;
+        io_utils::write_to_file(base_filename(directory, rebased.id), file)?;
+    }
+    GenerationChain {
+        chunk_size: chain.chunk_size,
+        generations: kept,
+    }
+    .write(directory)?;
+    for filename in &report.deleted_files {
+        match fs::remove_file(filename) {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                return Err(error).wrap_err(format!("Unable to delete {}", filename.display()))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::DEFAULT_MAX_OUTPUT_SIZE;
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn every_generation_is_restored_from_the_chain() {
+        let directory = scratch_directory("generations");
+        let first: String = (0..200).map(|line| format!("line {line}\n")).collect();
+        let versions: Vec<Bytes> = [
+            first.clone(),
+            first.replace("line 42\n", "line forty-two\n"),
+            first.replace("line 42\n", "line forty-two\n") + "line 200\n",
+        ]
+        .into_iter()
+        .map(Bytes::from)
+        .collect();
+
+        for version in &versions {
+            back_up_generation(&directory, version.clone(), 16, &MatchingOptions::default())
+                .unwrap();
+        }
+
+        let chain = GenerationChain::read(&directory).unwrap();
+        assert_eq!(chain.generations[2].parent, Some(1));
+        assert!(chain.generations[2].stored_size < versions[2].len() as u64);
+        for (id, version) in versions.iter().enumerate() {
+            let restored =
+                restore_generation(&directory, Some(id as u64), DEFAULT_MAX_OUTPUT_SIZE).unwrap();
+            assert_eq!(&restored, version);
+        }
+        assert!(restore_generation(&directory, Some(3), DEFAULT_MAX_OUTPUT_SIZE).is_err());
+
+        fs::write(directory.join("1.delta"), b"not a delta").unwrap();
+        assert!(restore_generation(&directory, None, DEFAULT_MAX_OUTPUT_SIZE).is_err());
+    }
+
+    #[test]
+    fn pruned_chains_still_restore_the_generations_kept() {
+        let directory = scratch_directory("generations_pruned");
+        let versions: Vec<Bytes> = (0..4)
+            .map(|version| Bytes::from(format!("version {version}\n").repeat(30)))
+            .collect();
+        for version in &versions {
+            back_up_generation(&directory, version.clone(), 16, &MatchingOptions::default())
+                .unwrap();
+        }
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+
+        let dry_run =
+            prune_generations(&directory, &policy, true, DEFAULT_MAX_OUTPUT_SIZE).unwrap();
+        assert_eq!(dry_run.pruned, vec![0, 1]);
+        assert!(directory.join("0.base").exists());
+
+        let report =
+            prune_generations(&directory, &policy, false, DEFAULT_MAX_OUTPUT_SIZE).unwrap();
+        assert_eq!(report.deleted_files, dry_run.deleted_files);
+        assert_eq!(report.rebased, Some(2));
+        assert!(!directory.join("0.base").exists());
+        for id in [2, 3] {
+            let restored =
+                restore_generation(&directory, Some(id), DEFAULT_MAX_OUTPUT_SIZE).unwrap();
+            assert_eq!(restored, versions[id as usize]);
+        }
+        assert!(restore_generation(&directory, Some(1), DEFAULT_MAX_OUTPUT_SIZE).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use std::ops::Range;
+
+use bytes::Bytes;
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    compare_signatures, compute_signature, stream_delta_with_mode, ChunkingMode, FileSignature,
+    MatchingOptions, StrongHash, StrongHashTails, Token, TokenSink, WeakHash,
+};
+
+/// Size of coarse blocks when files are large enough to use two levels of blocks: 1 MiB.
+pub const DEFAULT_COARSE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Fine blocks of some regions of the basis file, keeping their indexes in the whole file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SparseSignature {
+    /// Index, in the whole basis file, of each block in `signature`.
+    pub block_indexes: Vec<usize>,
+    pub signature: FileSignature,
+}
+
+/// Rounds `coarse_chunk_size` to a multiple of `chunk_size`, so every coarse block is made
+/// of whole fine blocks.
+pub fn align_coarse_chunk_size(coarse_chunk_size: usize, chunk_size: usize) -> usize {
+    (coarse_chunk_size / chunk_size).max(1) * chunk_size
+}
+
+/// Finds which coarse blocks of the basis file differ from the updated file, at the same position.
+///
+/// This is the first pass of two-level matching: only these regions need fine blocks.
+///
+/// # Arguments
+/// * `basis_coarse_signature` - Signature of the basis file, with coarse blocks.
+/// * `updated_coarse_signature` - Signature of the updated file, with the same coarse blocks.
+///
+pub fn changed_coarse_blocks(
+    basis_coarse_signature: &FileSignature,
+    updated_coarse_signature: &FileSignature,
+) -> Vec<Range<usize>> {
+    let basis_blocks = basis_coarse_signature.strong_hashes.len();
+    compare_signatures(basis_coarse_signature, updated_coarse_signature)
+        .differing_blocks
+        .into_iter()
+        .map(|blocks| blocks.start..blocks.end.min(basis_blocks))
+        .filter(|blocks| !blocks.is_empty())
+        .collect()
+}
+
+/// Computes the fine blocks of the basis file inside `coarse_ranges` only.
+///
+/// # Arguments
+/// * `basis_file` - The basis file.
+/// * `chunk_size` - The size of fine blocks.
+/// * `coarse_chunk_size` - The size of coarse blocks, a multiple of `chunk_size`.
+/// * `coarse_ranges` - The coarse blocks to describe, as returned by `changed_coarse_blocks`.
+///
+pub fn compute_sparse_signature(
+    basis_file: &Bytes,
+    chunk_size: usize,
+    coarse_chunk_size: usize,
+    coarse_ranges: &[Range<usize>],
+) -> SparseSignature {
+    let fine_blocks_per_coarse_block = coarse_chunk_size / chunk_size;
+    let mut sparse = SparseSignature {
+        block_indexes: Vec::new(),
+        signature: FileSignature {
+            strong_hashes: Vec::new(),
+            rolling_hashes: Vec::new(),
+            basis: None,
+            weak_hash: WeakHash::default(),
+            strong_hash: StrongHash::default(),
+            strong_hash_tails: StrongHashTails::default(),
+        },
+    };
+    for coarse_range in coarse_ranges {
+        let start = (coarse_range.start * coarse_chunk_size).min(basis_file.len());
+        let end = (coarse_range.end * coarse_chunk_size).min(basis_file.len());
+        let region = compute_signature(basis_file.slice(start..end), chunk_size);
+
+        let first_block = coarse_range.start * fine_blocks_per_coarse_block;
+        let blocks = region.strong_hashes.len();
+        sparse
+            .block_indexes
+            .extend(first_block..first_block + blocks);
+        sparse.signature.strong_hashes.extend(region.strong_hashes);
+        sparse
+            .signature
+            .strong_hash_tails
+            .extend(&region.strong_hash_tails);
+        sparse
+            .signature
+            .rolling_hashes
+            .extend(region.rolling_hashes);
+    }
+
+    sparse
+}
+
+/// Computes the tokens of a Delta using two levels of blocks.
+///
+/// Coarse blocks which did not change are referenced through their fine blocks directly, and only
+/// the changed regions of the updated file are matched, against the fine blocks in `sparse`.
+/// Content moved into a changed region from an unchanged one is sent as literals.
+/// The resulting Delta references fine blocks, and is applied as any other.
+///
+/// # Arguments
+/// * `basis_coarse_signature` - Signature of the basis file, with coarse blocks.
+/// * `updated_coarse_signature` - Signature of `updated_file`, with the same coarse blocks.
+/// * `sparse` - Fine blocks of the changed coarse blocks of the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size of fine blocks.
+/// * `coarse_chunk_size` - The size of coarse blocks, a multiple of `chunk_size`.
+/// * `tokens` - Where the tokens are pushed to, in order.
+///
+pub fn stream_hierarchical_delta(
+    basis_coarse_signature: &FileSignature,
+    updated_coarse_signature: &FileSignature,
+    sparse: &SparseSignature,
+    updated_file: &Bytes,
+    chunk_size: usize,
+    coarse_chunk_size: usize,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    if !coarse_chunk_size.is_multiple_of(chunk_size) {
+        return Err(eyre!(
+            "Coarse chunk size ({coarse_chunk_size}) is not a multiple of chunk size ({chunk_size})"
+        ));
+    }
+    let fine_blocks_per_coarse_block = coarse_chunk_size / chunk_size;
+    let differing_blocks =
+        compare_signatures(basis_coarse_signature, updated_coarse_signature).differing_blocks;
+
+    let mut sparse_tokens = SparseTokens { sparse, tokens };
+    let mut coarse_block = 0;
+    let coarse_blocks = updated_coarse_signature.strong_hashes.len();
+    for changed in differing_blocks
+        .iter()
+        .map(|blocks| blocks.start.min(coarse_blocks)..blocks.end.min(coarse_blocks))
+        .chain(std::iter::once(coarse_blocks..coarse_blocks))
+    {
+        // Unchanged coarse blocks, before this changed run.
+        for unchanged in coarse_block..changed.start {
+            let start = unchanged * coarse_chunk_size;
+            let length = coarse_chunk_size.min(updated_file.len() - start);
+            let first_block = unchanged * fine_blocks_per_coarse_block;
+            for fine_block in first_block..first_block + length.div_ceil(chunk_size) {
+                sparse_tokens.tokens.push(Token::BlockIndex(fine_block))?;
+            }
+        }
+
+        let start = changed.start * coarse_chunk_size;
+        let end = (changed.end * coarse_chunk_size).min(updated_file.len());
+        if start < end {
+            stream_delta_with_mode(
+                &sparse.signature,
+                &updated_file.slice(start..end),
+                chunk_size,
+                ChunkingMode::Fixed,
+                &MatchingOptions::default(),
+                &mut sparse_tokens,
+            )?;
+        }
+        coarse_block = changed.end;
+    }
+
+    Ok(())
+}
+
+// Translates indexes into a SparseSignature to indexes into the whole basis file.
+struct SparseTokens<'a, S: TokenSink> {
+    sparse: &'a SparseSignature,
+    tokens: &'a mut S,
+}
+
+impl<S: TokenSink> TokenSink for SparseTokens<'_, S> {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        // Consecutive fine blocks of `sparse` may not be consecutive in the whole basis file.
+        for index in token.block_indexes() {
+            self.tokens
+                .push(Token::BlockIndex(self.sparse.block_indexes[index]))?;
+        }
+        match token {
+            Token::BlockIndex(_) | Token::BlockRange(_) => Ok(()),
+            literal => self.tokens.push(literal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{apply_delta, Delta};
+
+    use super::*;
+
+    fn hierarchical_delta(basis_file: &Bytes, updated_file: &Bytes) -> (Delta, SparseSignature) {
+        let (chunk_size, coarse_chunk_size) = (4, 16);
+        let basis_coarse_signature = compute_signature(basis_file.clone(), coarse_chunk_size);
+        let updated_coarse_signature = compute_signature(updated_file.clone(), coarse_chunk_size);
+
+        let changed = changed_coarse_blocks(&basis_coarse_signature, &updated_coarse_signature);
+        let sparse = compute_sparse_signature(basis_file, chunk_size, coarse_chunk_size, &changed);
+
+        let mut delta = Delta::default();
+        stream_hierarchical_delta(
+            &basis_coarse_signature,
+            &updated_coarse_signature,
+            &sparse,
+            updated_file,
+            chunk_size,
+            coarse_chunk_size,
+            &mut delta.content,
+        )
+        .unwrap();
+        (delta, sparse)
+    }
+
+    #[test]
+    fn only_changed_regions_have_fine_blocks() {
+        let basis_file = Bytes::from("0123456789abcdef".repeat(4));
+        let mut updated_file = basis_file.to_vec();
+        updated_file[20] = b'x';
+        let updated_file = Bytes::from(updated_file);
+
+        let (delta, sparse) = hierarchical_delta(&basis_file, &updated_file);
+
+        assert_eq!(sparse.block_indexes, vec![4, 5, 6, 7]);
+        assert_eq!(apply_delta(basis_file, delta, 4).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn files_of_different_lengths_are_recreated() {
+        let basis_file = Bytes::from("0123456789abcdef".repeat(3) + "tail");
+        let updated_file = Bytes::from("0123456789abcdef".repeat(2) + "inserted" + "0123456789");
+
+        let (delta, _) = hierarchical_delta(&basis_file, &updated_file);
+
+        assert_eq!(apply_delta(basis_file, delta, 4).unwrap(), updated_file);
+    }
+}
@@ -0,0 +1,475 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use crate::domain::delta::Delta;
+use crate::domain::signature::format_size;
+use crate::domain::{compute_provenance_map, ChunkingMode, Source};
+
+// Most cells the block map of `render_html` has, so huge files still render quickly.
+const MAX_BLOCK_MAP_CELLS: usize = 4096;
+
+// How many of the largest literal regions `render_html` lists.
+const LARGEST_LITERAL_REGIONS: usize = 10;
+
+/// Largest updated file `inspect --basis` shows inline: larger ones are too long to read through.
+pub const MAX_INLINE_DIFF_SIZE: usize = 64 * 1024;
+
+// ANSI escape codes used by `DiffStyle::Colored`.
+const DIM: &str = "\x1b[2m";
+const HIGHLIGHT: &str = "\x1b[1;32m";
+const STRUCK_OUT: &str = "\x1b[9;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Counts describing the content of a Delta.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeltaSummary {
+    pub tokens: usize,
+    pub block_references: usize,
+    pub distinct_blocks: usize,
+    pub literal_bytes: usize,
+    pub zero_bytes: usize,
+}
+
+impl fmt::Display for DeltaSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tokens: {}", self.tokens)?;
+        writeln!(f, "Block references: {}", self.block_references)?;
+        writeln!(f, "Distinct blocks referenced: {}", self.distinct_blocks)?;
+        write!(f, "Literal bytes: {}", self.literal_bytes)?;
+        if self.zero_bytes > 0 {
+            write!(f, "\nZero bytes: {}", self.zero_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn summarize_delta(delta: &Delta) -> DeltaSummary {
+    let mut summary = DeltaSummary {
+        tokens: delta.content.len(),
+        ..Default::default()
+    };
+    // BlockRanges may be huge, so distinct blocks are counted by merging the ranges referenced.
+    let mut referenced = Vec::new();
+    delta.content.iter().for_each(|c| {
+        let indexes = c.block_indexes();
+        summary.block_references += indexes.len();
+        if !indexes.is_empty() {
+            referenced.push(indexes);
+        }
+        summary.literal_bytes += c.literals().len();
+        summary.zero_bytes += c.zeros();
+    });
+    referenced.sort_by_key(|indexes| indexes.start);
+    let mut counted_until = 0;
+    for indexes in referenced {
+        let start = indexes.start.max(counted_until);
+        summary.distinct_blocks += indexes.end.saturating_sub(start);
+        counted_until = counted_until.max(indexes.end);
+    }
+
+    summary
+}
+
+/// Renders a Delta computed in lines mode as a unified-diff-like view.
+///
+/// Basis lines reused in order are shown as context (` `), basis lines which were skipped
+/// are shown as removed (`-`), and everything else is shown as added (`+`).
+///
+/// # Arguments
+/// * `basis_file` - The file the Delta was computed against.
+/// * `delta` - A Delta computed with `ChunkingMode::Lines`.
+///
+pub fn render_line_diff(basis_file: &[u8], delta: &Delta) -> String {
+    let basis_lines = ChunkingMode::Lines.split(basis_file, 0);
+
+    let mut rendered = String::new();
+    let mut push_line = |prefix: char, line: &[u8]| {
+        rendered.push(prefix);
+        rendered.push_str(&String::from_utf8_lossy(line));
+        if !line.ends_with(b"\n") {
+            rendered.push('\n');
+        }
+    };
+
+    // Index of the basis line we expect to see next if nothing changed.
+    let mut next_basis_line = 0;
+    let mut literals = Vec::new();
+    for c in delta.content.iter() {
+        literals.extend_from_slice(c.literals());
+        for index in c.block_indexes() {
+            let moved = index < next_basis_line;
+            if !moved {
+                // Like in a unified diff, removed lines come before added ones.
+                basis_lines[next_basis_line..index.min(basis_lines.len())]
+                    .iter()
+                    .for_each(|removed| push_line('-', removed));
+            }
+            ChunkingMode::Lines
+                .split(&literals, 0)
+                .into_iter()
+                .for_each(|line| push_line('+', line));
+            literals.clear();
+
+            let Some(line) = basis_lines.get(index) else {
+                continue;
+            };
+            if moved {
+                // A line which was moved (or duplicated) from earlier in the basis file.
+                push_line('+', line);
+            } else {
+                push_line(' ', line);
+                next_basis_line = index + 1;
+            }
+        }
+    }
+    ChunkingMode::Lines
+        .split(&literals, 0)
+        .into_iter()
+        .for_each(|line| push_line('+', line));
+    basis_lines
+        .iter()
+        .skip(next_basis_line)
+        .for_each(|removed| push_line('-', removed));
+
+    rendered
+}
+
+/// How `render_inline_diff` marks the parts of the updated file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStyle {
+    /// For terminals: reused regions dimmed, literals in green, dropped basis bytes struck out
+    /// in red.
+    Colored,
+    /// Like `wdiff`: literals within `{+ +}`, dropped basis bytes within `[- -]`.
+    Plain,
+}
+
+/// Renders the updated file of a Delta computed in fixed mode, with what changed from the basis
+/// file marked inline.
+///
+/// Regions reused in order are shown as they are, literals as added, and the bytes of the basis
+/// file skipped over as removed. Returns None if either file is not UTF-8 text, or if the Delta
+/// references blocks the basis file does not have or has runs of zeros. Characters split between a reused block and
+/// literals may be shown as replacement characters.
+///
+/// # Arguments
+/// * `basis_file` - The file the Delta was computed against.
+/// * `delta` - A Delta computed in fixed mode.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `style` - How changes are marked.
+///
+pub fn render_inline_diff(
+    basis_file: &[u8],
+    delta: &Delta,
+    chunk_size: usize,
+    style: DiffStyle,
+) -> Option<String> {
+    std::str::from_utf8(basis_file).ok()?;
+    let map = compute_provenance_map(delta, chunk_size, Some(basis_file.len()));
+    let literals: Vec<u8> = delta
+        .tokens()
+        .flat_map(|token| token.literals())
+        .copied()
+        .collect();
+
+    let mut updated_file = Vec::new();
+    let mut rendered = Vec::new();
+    let mut push = |marks: (&str, &str), bytes: &[u8]| {
+        rendered.extend_from_slice(marks.0.as_bytes());
+        rendered.extend_from_slice(bytes);
+        rendered.extend_from_slice(marks.1.as_bytes());
+    };
+    let (reused_marks, added_marks, removed_marks) = match style {
+        DiffStyle::Colored => ((DIM, RESET), (HIGHLIGHT, RESET), (STRUCK_OUT, RESET)),
+        DiffStyle::Plain => (("", ""), ("{+", "+}"), ("[-", "-]")),
+    };
+
+    // Offset of the basis file we expect to reuse next if nothing changed.
+    let mut next_basis_offset = 0;
+    let mut next_literal = 0;
+    // Like in a unified diff, removed bytes come before added ones, so literals wait for the
+    // next reused region.
+    let mut added: &[u8] = &[];
+    for entry in &map.entries {
+        match entry.source {
+            Source::Basis { basis_offset } => {
+                let reused = basis_file.get(basis_offset..basis_offset + entry.length)?;
+                if basis_offset > next_basis_offset {
+                    push(removed_marks, &basis_file[next_basis_offset..basis_offset]);
+                }
+                if !added.is_empty() {
+                    push(added_marks, added);
+                }
+                push(reused_marks, reused);
+                updated_file.extend_from_slice(reused);
+                next_basis_offset = next_basis_offset.max(basis_offset + entry.length);
+                added = &[];
+            }
+            Source::Literal => {
+                added = &literals[next_literal..next_literal + entry.length];
+                updated_file.extend_from_slice(added);
+                next_literal += entry.length;
+            }
+            // Long runs of zeros are binary content.
+            Source::Zeros => return None,
+        }
+    }
+    if next_basis_offset < basis_file.len() {
+        push(removed_marks, &basis_file[next_basis_offset..]);
+    }
+    if !added.is_empty() {
+        push(added_marks, added);
+    }
+
+    std::str::from_utf8(&updated_file).ok()?;
+    Some(String::from_utf8_lossy(&rendered).into_owned())
+}
+
+/// Renders a self-contained HTML page showing where the updated file of a Delta comes from.
+///
+/// It has a map of the updated file, each cell colored from green (reused from the basis file) to
+/// red (sent as literals), a bar of reused and literal bytes, the summary of the Delta, and its
+/// largest literal regions, which are what made the transfer large.
+///
+/// # Arguments
+/// * `title` - Shown as the heading of the page, such as the name of the Delta file.
+/// * `delta` - A Delta computed in fixed mode.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+///
+pub fn render_html(title: &str, delta: &Delta, chunk_size: usize) -> String {
+    let basis_size = delta.header.basis.map(|basis| basis.length as usize);
+    let map = compute_provenance_map(delta, chunk_size, basis_size);
+    let summary = summarize_delta(delta);
+    let updated_size: usize = map.entries.iter().map(|entry| entry.length).sum();
+    let literal_size = summary.literal_bytes;
+    let reused_size = updated_size - literal_size - summary.zero_bytes;
+    let percent = |size: usize| match updated_size {
+        0 => 0.0,
+        _ => 100.0 * size as f64 / updated_size as f64,
+    };
+
+    let mut page = String::new();
+    page += "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n";
+    page += &format!("<title>{}</title>\n", escape_html(title));
+    page += "<style>\n\
+        body { font-family: sans-serif; margin: 2em; color: #222; }\n\
+        .bar { display: flex; height: 2em; border-radius: 4px; overflow: hidden; }\n\
+        .reused { background: #4caf50; } .literal { background: #e53935; }\n\
+        .map { display: grid; grid-template-columns: repeat(64, 1fr); gap: 1px; }\n\
+        .map span { height: 12px; }\n\
+        table { border-collapse: collapse; } td, th { padding: 2px 12px; text-align: right; }\n\
+        </style>\n</head>\n<body>\n";
+    page += &format!("<h1>{}</h1>\n", escape_html(title));
+    if let Some(basis) = delta.header.basis {
+        page += &format!("<p>Computed against {basis}.</p>\n");
+    }
+
+    page += &format!(
+        "<h2>Updated file: {}</h2>\n<div class=\"bar\">\
+         <div class=\"reused\" style=\"width: {:.2}%\" title=\"Reused\"></div>\
+         <div class=\"literal\" style=\"width: {:.2}%\" title=\"Literal\"></div></div>\n\
+         <p>{} ({:.1}%) reused from the basis file, {} ({:.1}%) sent as literals.</p>\n",
+        format_size(updated_size as u64),
+        percent(reused_size),
+        percent(literal_size),
+        format_size(reused_size as u64),
+        percent(reused_size),
+        format_size(literal_size as u64),
+        percent(literal_size),
+    );
+
+    // Each cell covers the same share of the updated file, colored by how much of it is reused.
+    let cells = updated_size
+        .div_ceil(chunk_size.max(1))
+        .min(MAX_BLOCK_MAP_CELLS);
+    page += "<h2>Block map</h2>\n<div class=\"map\">\n";
+    let mut entries = map.entries.iter().peekable();
+    for cell in 0..cells {
+        let (start, end) = (
+            cell * updated_size / cells,
+            (cell + 1) * updated_size / cells,
+        );
+        let mut reused = 0;
+        while let Some(entry) = entries.peek() {
+            let overlap = (entry.output_offset + entry.length).min(end)
+                - entry.output_offset.max(start).min(end);
+            if matches!(entry.source, Source::Basis { .. }) {
+                reused += overlap;
+            }
+            if entry.output_offset + entry.length > end {
+                break;
+            }
+            entries.next();
+        }
+        let fraction = reused as f64 / (end - start).max(1) as f64;
+        page += &format!(
+            "<span style=\"background: hsl({:.0}, 65%, 50%)\" title=\"bytes {start} to {end}: {:.0}% reused\"></span>\n",
+            120.0 * fraction,
+            100.0 * fraction
+        );
+    }
+    page += "</div>\n";
+
+    page += "<h2>Summary</h2>\n<table>\n";
+    for (name, count) in [
+        ("Tokens", summary.tokens),
+        ("Block references", summary.block_references),
+        ("Distinct blocks referenced", summary.distinct_blocks),
+        ("Literal bytes", summary.literal_bytes),
+        ("Zero bytes", summary.zero_bytes),
+    ] {
+        page += &format!("<tr><th>{name}</th><td>{count}</td></tr>\n");
+    }
+    page += "</table>\n";
+
+    let mut literal_regions: Vec<_> = map
+        .entries
+        .iter()
+        .filter(|entry| entry.source == Source::Literal)
+        .collect();
+    literal_regions.sort_by_key(|entry| std::cmp::Reverse(entry.length));
+    if !literal_regions.is_empty() {
+        page += "<h2>Largest literal regions</h2>\n<table>\n\
+                 <tr><th>Offset</th><th>Length</th><th>Share of literals</th></tr>\n";
+        for entry in literal_regions.into_iter().take(LARGEST_LITERAL_REGIONS) {
+            page += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{:.1}%</td></tr>\n",
+                entry.output_offset,
+                format_size(entry.length as u64),
+                100.0 * entry.length as f64 / literal_size as f64
+            );
+        }
+        page += "</table>\n";
+    }
+
+    page + "</body>\n</html>\n"
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::delta::Token;
+    use crate::domain::{compute_delta_with_mode, compute_signature_with_mode, MatchingOptions};
+
+    use super::*;
+
+    #[test]
+    fn summary_counts_tokens() {
+        let delta = Delta {
+            content: vec![
+                Token::BlockIndex(0),
+                Token::ByteLiteral(b'a'),
+                Token::BlockIndex(0),
+                Token::BlockIndex(3),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let summary = summarize_delta(&delta);
+
+        assert_eq!(
+            summary,
+            DeltaSummary {
+                tokens: 4,
+                block_references: 3,
+                distinct_blocks: 2,
+                literal_bytes: 1,
+                zero_bytes: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn line_diff_shows_changed_lines() {
+        let basis_file = Bytes::from("one\ntwo\nthree\nfour\n");
+        let updated_file = Bytes::from("one\n2\nthree\nfour\nfive");
+
+        let signature = compute_signature_with_mode(basis_file.clone(), 0, ChunkingMode::Lines);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file,
+            0,
+            ChunkingMode::Lines,
+            &MatchingOptions::default(),
+        );
+
+        let rendered = render_line_diff(&basis_file, &delta);
+
+        assert_eq!(rendered, " one\n-two\n+2\n three\n four\n+five\n");
+    }
+
+    #[test]
+    fn inline_diff_marks_added_and_removed_text() {
+        let basis_file = Bytes::from("the quick brown fox jumps over the lazy dog.");
+        let updated_file = Bytes::from("the quick brown cat jumps over the lazy dog.\nThe end.");
+        let signature = compute_signature_with_mode(basis_file.clone(), 4, ChunkingMode::Fixed);
+        let delta = compute_delta_with_mode(
+            signature,
+            updated_file,
+            4,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+        );
+
+        let plain = render_inline_diff(&basis_file, &delta, 4, DiffStyle::Plain).unwrap();
+        let colored = render_inline_diff(&basis_file, &delta, 4, DiffStyle::Colored).unwrap();
+
+        assert_eq!(
+            plain,
+            "the quick brown [-fox -]{+cat +}jumps over the lazy dog.{+\nThe end.+}"
+        );
+        assert!(colored.contains("\x1b[1;32mcat \x1b[0m"));
+        assert!(colored.contains("\x1b[9;31mfox \x1b[0m"));
+    }
+
+    #[test]
+    fn inline_diff_is_only_rendered_for_text() {
+        let basis_file = [0xff, 0xfe, 0xfd, 0xfc];
+        let delta = Delta {
+            content: vec![Token::BlockIndex(0)].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render_inline_diff(&basis_file, &delta, 4, DiffStyle::Plain),
+            None
+        );
+    }
+
+    #[test]
+    fn html_maps_reused_and_literal_regions() {
+        let delta = Delta {
+            content: vec![
+                Token::BlockIndex(0),
+                Token::BlockIndex(1),
+                Token::ByteLiteral(b'a'),
+                Token::ByteLiteral(b'b'),
+                Token::ByteLiteral(b'c'),
+                Token::ByteLiteral(b'd'),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let html = render_html("<delta>", &delta, 4);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h1>&lt;delta&gt;</h1>"));
+        assert!(html.contains(
+            "8 bytes (66.7%) reused from the basis file, 4 bytes (33.3%) sent as literals"
+        ));
+        // Two green cells for the reused blocks, and a red one for the literals.
+        assert_eq!(html.matches("hsl(120, 65%, 50%)").count(), 2);
+        assert_eq!(html.matches("hsl(0, 65%, 50%)").count(), 1);
+        assert!(html.contains("<tr><td>8</td><td>4 bytes</td><td>100.0%</td></tr>"));
+    }
+}
@@ -0,0 +1,450 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Context};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::help::Help;
+use crate::io_utils;
+
+// Files are compared, journaled and written in pages of this many bytes.
+const JOURNAL_PAGE_SIZE: usize = 4096;
+const JOURNAL_MAGIC: &[u8; 8] = b"RSJRNL02";
+// Journals of other versions start the same, with another number.
+const JOURNAL_MAGIC_PREFIX: &[u8; 6] = b"RSJRNL";
+
+/// Bytes of a file about to be overwritten in place, to put them back if the update is
+/// interrupted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UndoJournal {
+    /// Length of the file before the update.
+    pub length: u64,
+    /// Original bytes of the regions overwritten or truncated.
+    pub regions: Vec<JournalRegion>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct JournalRegion {
+    pub offset: u64,
+    pub original: Vec<u8>,
+}
+
+impl UndoJournal {
+    // Journals the pages of `original` which differ in `updated`, and those it truncates.
+    fn between(original: &[u8], updated: &[u8]) -> Self {
+        let common = original.len().min(updated.len());
+        let mut regions: Vec<JournalRegion> = Vec::new();
+        for start in (0..common).step_by(JOURNAL_PAGE_SIZE) {
+            let end = (start + JOURNAL_PAGE_SIZE).min(common);
+            if original[start..end] == updated[start..end] {
+                continue;
+            }
+            match regions.last_mut() {
+                Some(last) if last.offset as usize + last.original.len() == start => {
+                    last.original.extend_from_slice(&original[start..end]);
+                }
+                _ => regions.push(JournalRegion {
+                    offset: start as u64,
+                    original: original[start..end].to_vec(),
+                }),
+            }
+        }
+        if original.len() > common {
+            regions.push(JournalRegion {
+                offset: common as u64,
+                original: original[common..].to_vec(),
+            });
+        }
+
+        Self {
+            length: original.len() as u64,
+            regions,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = JOURNAL_MAGIC.to_vec();
+        // The length of the whole journal, filled in once known.
+        encoded.extend_from_slice(&0_u64.to_le_bytes());
+        encoded.extend_from_slice(&self.length.to_le_bytes());
+        encoded.extend_from_slice(&(self.regions.len() as u64).to_le_bytes());
+        for region in &self.regions {
+            encoded.extend_from_slice(&region.offset.to_le_bytes());
+            encoded.extend_from_slice(&(region.original.len() as u64).to_le_bytes());
+            encoded.extend_from_slice(&region.original);
+        }
+        let journal_length = (encoded.len() + 8) as u64;
+        encoded[JOURNAL_MAGIC.len()..JOURNAL_MAGIC.len() + 8]
+            .copy_from_slice(&journal_length.to_le_bytes());
+        // A journal damaged on disk is told apart from a complete one by its hash.
+        let hash = xxh3_64(&encoded);
+        encoded.extend_from_slice(&hash.to_le_bytes());
+        encoded
+    }
+
+    // A journal shorter than the length it starts with was cut short by a crash while it was
+    // written. Any other journal which does not match its hash was damaged afterwards.
+    fn decode(encoded: &[u8]) -> DecodedJournal {
+        let mut fields = &encoded[JOURNAL_MAGIC.len().min(encoded.len())..];
+        let Some(journal_length) = next_u64(&mut fields) else {
+            return DecodedJournal::Truncated;
+        };
+        if (encoded.len() as u64) < journal_length {
+            return DecodedJournal::Truncated;
+        }
+        if encoded.len() as u64 != journal_length {
+            return DecodedJournal::Damaged;
+        }
+        // At least the magic and the length precede the hash.
+        let (content, hash) = encoded.split_at(encoded.len() - 8);
+        if hash != xxh3_64(content).to_le_bytes() {
+            return DecodedJournal::Damaged;
+        }
+        match content
+            .get(JOURNAL_MAGIC.len() + 8..)
+            .and_then(Self::decode_fields)
+        {
+            Some(journal) => DecodedJournal::Whole(journal),
+            None => DecodedJournal::Damaged,
+        }
+    }
+
+    fn decode_fields(mut fields: &[u8]) -> Option<Self> {
+        let length = next_u64(&mut fields)?;
+        let region_count = next_u64(&mut fields)?;
+        let mut regions = Vec::new();
+        for _ in 0..region_count {
+            let offset = next_u64(&mut fields)?;
+            let region_length = next_u64(&mut fields)?;
+            let (original, rest) = fields.split_at_checked(region_length as usize)?;
+            fields = rest;
+            regions.push(JournalRegion {
+                offset,
+                original: original.to_vec(),
+            });
+        }
+
+        Some(Self { length, regions })
+    }
+}
+
+// What was found in a journal.
+#[derive(Debug, PartialEq, Eq)]
+enum DecodedJournal {
+    Whole(UndoJournal),
+    /// Cut short while it was written, so the file was not touched yet.
+    Truncated,
+    /// Written whole, but changed since.
+    Damaged,
+}
+
+fn next_u64(fields: &mut &[u8]) -> Option<u64> {
+    let (field, rest) = fields.split_at_checked(8)?;
+    *fields = rest;
+    Some(u64::from_le_bytes(field.try_into().ok()?))
+}
+
+/// Where the journal of `filename` is written, unless told otherwise.
+pub fn default_journal_filename(filename: &Path) -> PathBuf {
+    let mut journal_filename = filename.as_os_str().to_owned();
+    journal_filename.push(".rsync-journal");
+    PathBuf::from(journal_filename)
+}
+
+/// What an in-place update wrote.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct InPlaceUpdate {
+    /// Bytes of the file overwritten or appended.
+    pub bytes_written: u64,
+    /// Original bytes saved in the journal beforehand.
+    pub bytes_journaled: u64,
+}
+
+/// Replaces the content of a file with `updated`, overwriting only the pages which differ.
+///
+/// The original bytes of those pages are first saved in an undo journal, which is synced to the
+/// disk before the file is touched and removed once the file is. If the update is interrupted,
+/// e.g. by a power loss, `recover_from_journal` puts the file back as it was. The file is locked
+/// meanwhile (see `io_utils::lock_file`).
+///
+/// # Arguments
+/// * `filename` - The file to change in place.
+/// * `updated` - The content the file should have.
+/// * `journal_filename` - Where to write the undo journal. Must not exist.
+///
+pub fn rewrite_in_place(
+    filename: &Path,
+    updated: &[u8],
+    journal_filename: &Path,
+) -> eyre::Result<InPlaceUpdate> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)
+        .wrap_err(format!("Unable to open file: {}", filename.display()))?;
+    io_utils::lock_file(&file, filename)?;
+    let mut original = Vec::new();
+    file.read_to_end(&mut original)?;
+
+    let journal = UndoJournal::between(&original, updated);
+    let mut journal_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(journal_filename)
+        .wrap_err(format!(
+            "Unable to create the journal: {}",
+            journal_filename.display()
+        ))
+        .suggestion(
+            "A journal left by an interrupted update must be recovered first, with `recover`.",
+        )?;
+    journal_file.write_all(&journal.encode())?;
+    journal_file.sync_all()?;
+    sync_parent_directory(journal_filename)?;
+
+    let common = original.len().min(updated.len());
+    let mut bytes_written = 0;
+    for region in journal
+        .regions
+        .iter()
+        .filter(|region| region.offset < common as u64)
+    {
+        let start = region.offset as usize;
+        let end = start + region.original.len();
+        file.seek(SeekFrom::Start(region.offset))?;
+        file.write_all(&updated[start..end])?;
+        bytes_written += region.original.len() as u64;
+    }
+    if updated.len() > common {
+        file.seek(SeekFrom::Start(common as u64))?;
+        file.write_all(&updated[common..])?;
+        bytes_written += (updated.len() - common) as u64;
+    }
+    file.set_len(updated.len() as u64)?;
+    file.sync_all()?;
+
+    fs::remove_file(journal_filename)?;
+    Ok(InPlaceUpdate {
+        bytes_written,
+        bytes_journaled: journal
+            .regions
+            .iter()
+            .map(|region| region.original.len() as u64)
+            .sum(),
+    })
+}
+
+/// What was done with the journal of an interrupted update.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Recovery {
+    /// The file was put back as it was before the update.
+    RolledBack { bytes_restored: u64 },
+    /// The journal was not written whole, so the file was not touched yet.
+    Incomplete,
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::RolledBack { bytes_restored } => write!(
+                f,
+                "Rolled the file back to before the update ({bytes_restored} bytes restored)"
+            ),
+            Recovery::Incomplete => write!(
+                f,
+                "The update was interrupted before changing the file, which was left as it was"
+            ),
+        }
+    }
+}
+
+/// Puts a file back as it was before an interrupted `rewrite_in_place`, and removes its journal.
+///
+/// Updates interrupted after the file was written, but before the journal was removed, are rolled
+/// back too: the file is always left either as it was, or fully updated.
+///
+/// # Arguments
+/// * `filename` - The file which was being changed in place.
+/// * `journal_filename` - The journal written by `rewrite_in_place`.
+///
+pub fn recover_from_journal(filename: &Path, journal_filename: &Path) -> eyre::Result<Recovery> {
+    let encoded = fs::read(journal_filename).wrap_err(format!(
+        "Unable to read the journal: {}",
+        journal_filename.display()
+    ))?;
+    // Anything else is refused, as it is removed once recovered.
+    if !encoded.starts_with(JOURNAL_MAGIC_PREFIX) {
+        return Err(eyre!(
+            "{} is not a journal written by `patch --inplace`",
+            journal_filename.display()
+        ));
+    }
+    if !encoded.starts_with(JOURNAL_MAGIC) {
+        return Err(eyre!(
+            "{} was written by another version of rsync_rust",
+            journal_filename.display()
+        ))
+        .suggestion("Recover it with the version which wrote it.");
+    }
+    let journal = match UndoJournal::decode(&encoded) {
+        DecodedJournal::Whole(journal) => journal,
+        DecodedJournal::Truncated => {
+            fs::remove_file(journal_filename)?;
+            return Ok(Recovery::Incomplete);
+        }
+        DecodedJournal::Damaged => {
+            return Err(eyre!(
+                "{} is damaged, so the file can not be rolled back",
+                journal_filename.display()
+            ))
+            .suggestion(
+                "The journal was kept. Check the file, and remove the journal once it is restored.",
+            );
+        }
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(filename)
+        .wrap_err(format!("Unable to open file: {}", filename.display()))?;
+    io_utils::lock_file(&file, filename)?;
+    // Truncating first drops what the update appended, and restores the length regions need.
+    file.set_len(journal.length)?;
+    for region in &journal.regions {
+        file.seek(SeekFrom::Start(region.offset))?;
+        file.write_all(&region.original)?;
+    }
+    file.sync_all()?;
+
+    fs::remove_file(journal_filename)?;
+    Ok(Recovery::RolledBack {
+        bytes_restored: journal
+            .regions
+            .iter()
+            .map(|region| region.original.len() as u64)
+            .sum(),
+    })
+}
+
+// Makes the journal's directory entry durable, so it is found after a power loss.
+fn sync_parent_directory(path: &Path) -> eyre::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &[u8]) -> (PathBuf, PathBuf) {
+        let filename = scratch_directory(name).join("file");
+        fs::write(&filename, content).unwrap();
+        let journal_filename = default_journal_filename(&filename);
+        let _ = fs::remove_file(&journal_filename);
+        (filename, journal_filename)
+    }
+
+    #[test]
+    fn only_the_pages_which_differ_are_journaled() {
+        let original = vec![1; JOURNAL_PAGE_SIZE * 4];
+        let mut updated = original.clone();
+        updated[JOURNAL_PAGE_SIZE + 10] = 2;
+        updated[JOURNAL_PAGE_SIZE * 2 + 10] = 2;
+        updated.truncate(JOURNAL_PAGE_SIZE * 3 + 100);
+
+        let journal = UndoJournal::between(&original, &updated);
+
+        let regions: Vec<_> = journal
+            .regions
+            .iter()
+            .map(|region| (region.offset as usize, region.original.len()))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (JOURNAL_PAGE_SIZE, JOURNAL_PAGE_SIZE * 2),
+                (JOURNAL_PAGE_SIZE * 3 + 100, JOURNAL_PAGE_SIZE - 100)
+            ]
+        );
+        assert_eq!(
+            UndoJournal::decode(&journal.encode()),
+            DecodedJournal::Whole(journal)
+        );
+    }
+
+    #[test]
+    fn journals_cut_short_are_not_decoded() {
+        let journal = UndoJournal::between(b"original content", b"updated content, longer");
+        let encoded = journal.encode();
+
+        for length in [JOURNAL_MAGIC.len(), encoded.len() / 2, encoded.len() - 1] {
+            assert_eq!(
+                UndoJournal::decode(&encoded[..length]),
+                DecodedJournal::Truncated
+            );
+        }
+    }
+
+    #[test]
+    fn damaged_journals_are_kept() {
+        let original = b"original content".repeat(100);
+        let (filename, journal_filename) = temp_file("journal_damaged", &original);
+        let updated = b"updated content".repeat(100);
+        let mut encoded = UndoJournal::between(&original, &updated).encode();
+        encoded[100] ^= 1;
+        fs::write(&journal_filename, &encoded).unwrap();
+        fs::write(&filename, &updated).unwrap();
+
+        assert!(recover_from_journal(&filename, &journal_filename).is_err());
+
+        assert_eq!(fs::read(&journal_filename).unwrap(), encoded);
+        assert_eq!(fs::read(&filename).unwrap(), updated);
+    }
+
+    #[test]
+    fn files_are_rewritten_in_place_and_the_journal_removed() {
+        let original: Vec<u8> = (0..20_000).map(|byte| byte as u8).collect();
+        let mut updated = original.clone();
+        updated[5000..5010].copy_from_slice(b"0123456789");
+        updated.extend_from_slice(b"appended");
+        let (filename, journal_filename) = temp_file("journal_rewrite", &original);
+
+        let update = rewrite_in_place(&filename, &updated, &journal_filename).unwrap();
+
+        assert_eq!(fs::read(&filename).unwrap(), updated);
+        assert_eq!(update.bytes_written, JOURNAL_PAGE_SIZE as u64 + 8);
+        assert!(!journal_filename.exists());
+    }
+
+    #[test]
+    fn interrupted_updates_are_rolled_back() {
+        let original: Vec<u8> = (0..10_000).map(|byte| (byte % 251) as u8).collect();
+        let (filename, journal_filename) = temp_file("journal_recover", &original);
+        let updated = [b"changed".as_slice(), &original].concat();
+        let journal = UndoJournal::between(&original, &updated);
+        fs::write(&journal_filename, journal.encode()).unwrap();
+        // Crashed halfway through writing the updated file.
+        fs::write(&filename, [&updated[..7000], &original[7000..]].concat()).unwrap();
+
+        let recovery = recover_from_journal(&filename, &journal_filename).unwrap();
+
+        assert!(matches!(recovery, Recovery::RolledBack { .. }));
+        assert_eq!(fs::read(&filename).unwrap(), original);
+        assert!(!journal_filename.exists());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    artifact_version, calculate_strong_hash, decode_artifact, encode_artifact,
+    hashes_files_with_siphash, ArtifactKind,
+};
+use crate::help::Help;
+
+/// Records the content of every file in a directory tree, to verify it later.
+///
+/// Unlike a Signature, which describes the blocks of a single file, a Manifest only has
+/// a whole-file strong hash for each file, which is enough to tell whether a backup is intact.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct Manifest {
+    /// Sorted by path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ManifestEntry {
+    /// Relative to the root of the tree, with `/` as separator. Invalid UTF-8 is replaced.
+    pub path: String,
+    pub size: u64,
+    /// Permission bits of the file.
+    pub mode: u32,
+    pub strong_hash: u64,
+}
+
+impl TryFrom<Manifest> for Bytes {
+    type Error = eyre::Report;
+
+    fn try_from(manifest: Manifest) -> Result<Self, Self::Error> {
+        encode_artifact(ArtifactKind::Manifest, &manifest)
+    }
+}
+
+impl TryFrom<Bytes> for Manifest {
+    type Error = eyre::Report;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        if artifact_version(&bytes).is_some_and(hashes_files_with_siphash) {
+            return Err(eyre!(
+                "Manifest of format version 1 hashes files with SipHash, which this version does not check"
+            ))
+            .suggestion("Compute the Manifest again from a tree known to be intact.");
+        }
+        let manifest = decode_artifact(ArtifactKind::Manifest, bytes)
+            .wrap_err("Could not read Manifest from file provided.")
+            .suggestion(
+                "Did you provide the correct path for the Manifest file?\n\
+                         It must have been generated as an output from a previous `manifest` command.",
+            )?;
+        Ok(manifest)
+    }
+}
+
+/// Differences between a directory tree and its Manifest.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ManifestCheck {
+    /// Files whose size or content changed.
+    pub modified: Vec<String>,
+    /// Files whose content is intact, but whose permissions changed.
+    pub mode_changed: Vec<String>,
+    /// Files in the Manifest which are not in the tree anymore.
+    pub missing: Vec<String>,
+    /// Files in the tree which are not in the Manifest.
+    pub added: Vec<String>,
+}
+
+impl ManifestCheck {
+    pub fn is_intact(&self) -> bool {
+        self.modified.is_empty()
+            && self.mode_changed.is_empty()
+            && self.missing.is_empty()
+            && self.added.is_empty()
+    }
+}
+
+impl fmt::Display for ManifestCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_intact() {
+            return write!(f, "All files match the Manifest");
+        }
+        let lines: Vec<_> = [
+            ("modified", &self.modified),
+            ("mode changed", &self.mode_changed),
+            ("missing", &self.missing),
+            ("added", &self.added),
+        ]
+        .into_iter()
+        .flat_map(|(label, paths)| paths.iter().map(move |path| format!("{label}: {path}")))
+        .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// A regular file found under a directory, see `list_files`.
+#[derive(Debug, Clone)]
+pub struct TreeFile {
+    /// Relative to the directory, as named on disk, even when that is not valid UTF-8.
+    pub relative_path: PathBuf,
+    /// The same with `/` as separator, to be displayed and recorded. Invalid UTF-8 is replaced.
+    pub path: String,
+    pub metadata: fs::Metadata,
+}
+
+/// Lists every regular file under `root`, sorted by `path`.
+///
+/// Symbolic links and other special files are not followed nor listed. Files are found through
+/// `relative_path`, so names which are not valid UTF-8 are still read and written as they are.
+///
+/// # Arguments
+/// * `root` - The directory to list.
+///
+pub fn list_files(root: &Path) -> eyre::Result<Vec<TreeFile>> {
+    let mut files = Vec::new();
+    collect_files(root, Path::new(""), "", &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(files)
+}
+
+/// Computes a Manifest for every regular file under `root`.
+///
+/// Symbolic links and other special files are not followed nor recorded.
+///
+/// # Arguments
+/// * `root` - The directory to describe.
+///
+pub fn compute_manifest(root: &Path) -> eyre::Result<Manifest> {
+    let entries = list_files(root)?
+        .into_iter()
+        .map(|file| {
+            let content = fs::read(root.join(&file.relative_path))
+                .wrap_err(format!(r#"Could not read file "{}""#, file.path))?;
+            Ok(ManifestEntry {
+                path: file.path,
+                size: file.metadata.len(),
+                mode: file_mode(&file.metadata),
+                strong_hash: calculate_strong_hash(&content),
+            })
+        })
+        .collect::<eyre::Result<_>>()?;
+
+    Ok(Manifest { entries })
+}
+
+/// Compares the files under `root` with a Manifest computed earlier.
+///
+/// # Arguments
+/// * `root` - The directory to verify.
+/// * `manifest` - The Manifest it is expected to match.
+///
+pub fn check_manifest(root: &Path, manifest: &Manifest) -> eyre::Result<ManifestCheck> {
+    let current = compute_manifest(root)?;
+    let mut current: BTreeMap<_, _> = current
+        .entries
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+
+    let mut check = ManifestCheck::default();
+    for expected in &manifest.entries {
+        match current.remove(&expected.path) {
+            None => check.missing.push(expected.path.clone()),
+            Some(entry)
+                if entry.size != expected.size || entry.strong_hash != expected.strong_hash =>
+            {
+                check.modified.push(expected.path.clone())
+            }
+            Some(entry) if entry.mode != expected.mode => {
+                check.mode_changed.push(expected.path.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    check.added = current.into_keys().collect();
+
+    Ok(check)
+}
+
+// Relative paths are joined component by component, so they use the separator of the platform
+// (`/` is not one in `\\?\` paths on Windows).
+fn collect_files(
+    directory: &Path,
+    relative_directory: &Path,
+    prefix: &str,
+    files: &mut Vec<TreeFile>,
+) -> eyre::Result<()> {
+    let read_directory = fs::read_dir(directory).wrap_err(format!(
+        r#"Could not read directory "{}""#,
+        directory.display()
+    ))?;
+    for directory_entry in read_directory {
+        let directory_entry = directory_entry?;
+        let relative_path = relative_directory.join(directory_entry.file_name());
+        let path = format!("{prefix}{}", directory_entry.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(directory_entry.path())?;
+        if metadata.is_dir() {
+            collect_files(
+                &directory_entry.path(),
+                &relative_path,
+                &format!("{path}/"),
+                files,
+            )?;
+        } else if metadata.is_file() {
+            files.push(TreeFile {
+                relative_path,
+                path,
+                metadata,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    // Only the read-only flag is available, so it is recorded as the closest Unix mode.
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    fn create_tree(name: &str) -> PathBuf {
+        let root = scratch_directory(&format!("manifest_{name}"));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::write(root.join("a.txt"), "first file").unwrap();
+        fs::write(root.join("nested/b.txt"), "second file").unwrap();
+        root
+    }
+
+    #[test]
+    fn manifest_lists_files_sorted_by_path() {
+        let root = create_tree("sorted");
+
+        let manifest = compute_manifest(&root).unwrap();
+
+        let paths: Vec<_> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt", "nested/b.txt"]);
+        assert_eq!(manifest.entries[0].size, 10);
+        assert!(check_manifest(&root, &manifest).unwrap().is_intact());
+    }
+
+    #[test]
+    fn check_reports_every_kind_of_difference() {
+        let root = create_tree("differences");
+        let manifest = compute_manifest(&root).unwrap();
+
+        fs::write(root.join("a.txt"), "first file, changed").unwrap();
+        fs::remove_file(root.join("nested/b.txt")).unwrap();
+        fs::write(root.join("c.txt"), "third file").unwrap();
+
+        let check = check_manifest(&root, &manifest).unwrap();
+
+        assert_eq!(
+            check,
+            ManifestCheck {
+                modified: vec!["a.txt".to_string()],
+                mode_changed: vec![],
+                missing: vec!["nested/b.txt".to_string()],
+                added: vec!["c.txt".to_string()],
+            }
+        );
+    }
+}
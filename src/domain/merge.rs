@@ -0,0 +1,443 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Range;
+
+use bytes::Bytes;
+use eyre::eyre;
+
+use crate::domain::delta::{Delta, DeltaHeader, Token};
+use crate::domain::{compute_provenance_map, Source};
+use crate::help::Help;
+
+/// A region of the basis file which an updated file replaced with other bytes.
+///
+/// Empty basis ranges are insertions, and empty replacements are deletions.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Edit {
+    /// Bytes of the basis file replaced.
+    pub basis_range: Range<usize>,
+    /// What the updated file has in their place.
+    pub replacement: Bytes,
+}
+
+impl Edit {
+    // Whether both edits change the same bytes of the basis file, or bytes next to each other,
+    // which could not be merged without knowing what either side meant.
+    fn touches(&self, other: &Edit) -> bool {
+        self.basis_range.start <= other.basis_range.end
+            && other.basis_range.start <= self.basis_range.end
+    }
+}
+
+/// Edits of both files which change the same region of the basis file differently.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EditConflict {
+    /// Bytes of the basis file the left file changed.
+    pub left: Range<usize>,
+    /// Bytes of the basis file the right file changed.
+    pub right: Range<usize>,
+}
+
+/// E.g. "bytes 100..200 changed on the left, and 150..150 on the right".
+impl fmt::Display for EditConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bytes {}..{} changed on the left, and {}..{} on the right",
+            self.left.start, self.left.end, self.right.start, self.right.end
+        )
+    }
+}
+
+/// Lists the edits turning the basis file into the updated file, from a Delta in fixed mode.
+///
+/// Blocks reused in order anchor the two files to each other, and whatever lies between two
+/// anchors is an edit. Blocks reused out of order (moved, or repeated) are part of the edits.
+///
+/// # Arguments
+/// * `delta` - Delta representing the changes from the basis file to the updated file.
+/// * `updated_file` - The file the Delta recreates.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `basis_length` - Length of the basis file.
+///
+pub fn compute_edits(
+    delta: &Delta,
+    updated_file: &Bytes,
+    chunk_size: usize,
+    basis_length: usize,
+) -> Vec<Edit> {
+    let map = compute_provenance_map(delta, chunk_size, Some(basis_length));
+    let mut edits = Vec::new();
+    let mut push_edit = |basis_range: Range<usize>, updated_range: Range<usize>| {
+        if !basis_range.is_empty() || !updated_range.is_empty() {
+            edits.push(Edit {
+                basis_range,
+                replacement: updated_file.slice(updated_range),
+            });
+        }
+    };
+
+    // Where the last region reused in order ends, in the basis file and in the updated file.
+    let (mut basis_position, mut updated_position) = (0, 0);
+    for entry in &map.entries {
+        let Source::Basis { basis_offset } = entry.source else {
+            continue;
+        };
+        if basis_offset < basis_position {
+            continue;
+        }
+        push_edit(
+            basis_position..basis_offset,
+            updated_position..entry.output_offset,
+        );
+        basis_position = basis_offset + entry.length;
+        updated_position = entry.output_offset + entry.length;
+    }
+    push_edit(
+        basis_position..basis_length,
+        updated_position..updated_file.len(),
+    );
+
+    edits
+}
+
+/// Merges the edits both files made to the basis file they were last synced at.
+///
+/// Edits made on both sides identically are applied once. Returns the merged file, or every pair
+/// of edits changing the same region differently, in which case neither file should change.
+///
+/// # Arguments
+/// * `left` - The left file, the basis file with `left_edits` applied.
+/// * `left_edits` - Edits of the left file, in order, as computed by `compute_edits`.
+/// * `right_edits` - Edits of the right file, in order, as computed by `compute_edits`.
+///
+pub fn merge_edits(
+    left: &[u8],
+    left_edits: &[Edit],
+    right_edits: &[Edit],
+) -> Result<Vec<u8>, Vec<EditConflict>> {
+    let right_only: Vec<&Edit> = right_edits
+        .iter()
+        .filter(|edit| !left_edits.contains(edit))
+        .collect();
+    let conflicts: Vec<EditConflict> = left_edits
+        .iter()
+        .filter(|edit| !right_edits.contains(edit))
+        .flat_map(|left_edit| {
+            right_only
+                .iter()
+                .filter(|right_edit| left_edit.touches(right_edit))
+                .map(|right_edit| EditConflict {
+                    left: left_edit.basis_range.clone(),
+                    right: right_edit.basis_range.clone(),
+                })
+        })
+        .collect();
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    // The right edits are applied to the left file, shifted by the left edits before them.
+    let mut merged = Vec::with_capacity(left.len());
+    let mut left_position = 0;
+    let mut shift = 0_isize;
+    let mut left_edits = left_edits.iter().peekable();
+    for edit in right_only {
+        while let Some(left_edit) =
+            left_edits.next_if(|left_edit| left_edit.basis_range.end <= edit.basis_range.start)
+        {
+            shift += left_edit.replacement.len() as isize - left_edit.basis_range.len() as isize;
+        }
+        let start = edit.basis_range.start.saturating_add_signed(shift);
+        merged.extend_from_slice(&left[left_position..start]);
+        merged.extend_from_slice(&edit.replacement);
+        left_position = start + edit.basis_range.len();
+    }
+    merged.extend_from_slice(&left[left_position..]);
+
+    Ok(merged)
+}
+
+/// Blocks of the basis file which a Delta replaced, with the tokens it has in their place.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct TokenEdit {
+    blocks: Range<usize>,
+    tokens: Vec<Token>,
+}
+
+impl TokenEdit {
+    fn touches(&self, other: &TokenEdit) -> bool {
+        self.blocks.start <= other.blocks.end && other.blocks.start <= self.blocks.end
+    }
+}
+
+/// Edits of both Deltas which replace the same blocks of the basis file differently.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DeltaConflict {
+    /// Blocks of the basis file the left Delta replaced.
+    pub left: Range<usize>,
+    /// Blocks of the basis file the right Delta replaced.
+    pub right: Range<usize>,
+}
+
+/// E.g. "blocks 10..12 replaced on the left, and 11..11 on the right".
+impl fmt::Display for DeltaConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "blocks {}..{} replaced on the left, and {}..{} on the right",
+            self.left.start, self.left.end, self.right.start, self.right.end
+        )
+    }
+}
+
+/// What `merge_deltas` made of two Deltas.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaMerge {
+    /// A Delta recreating the basis file with the changes of both.
+    Merged(Box<Delta>),
+    /// Every pair of edits replacing the same blocks differently.
+    Conflicts(Vec<DeltaConflict>),
+}
+
+/// Merges two Deltas computed against the same Signature into one, holding the changes of both.
+///
+/// Like `compute_edits`, blocks referenced in order anchor each Delta to the basis file, and the
+/// tokens between two anchors replace the blocks between them. When the blocks replaced by either
+/// Delta are apart from those the other replaced, the merged Delta replaces both. Edits made by
+/// both Deltas identically are kept once. The merged Delta does not record the file it recreates,
+/// as neither Delta knows it.
+///
+/// Fails if the Deltas do not record the same basis file, or were computed with other options.
+///
+pub fn merge_deltas(left: &Delta, right: &Delta) -> eyre::Result<DeltaMerge> {
+    let basis = match (left.header.basis, right.header.basis) {
+        (Some(left_basis), Some(right_basis)) if left_basis == right_basis => left_basis,
+        (Some(_), Some(_)) => {
+            return Err(eyre!("Deltas were computed against different basis files"));
+        }
+        _ => {
+            return Err(eyre!(
+                "Deltas do not record the basis file they were computed against"
+            ))
+            .suggestion("Compute them again with a newer `delta` command.");
+        }
+    };
+    if (&left.header.normalization, &left.header.transform)
+        != (&right.header.normalization, &right.header.transform)
+    {
+        return Err(eyre!(
+            "Deltas were computed with different normalizations or transforms"
+        ));
+    }
+    let block_count = basis.block_count as usize;
+    let left_edits = token_edits(left, block_count);
+    let right_edits = token_edits(right, block_count);
+
+    let right_only: Vec<&TokenEdit> = right_edits
+        .iter()
+        .filter(|edit| !left_edits.contains(edit))
+        .collect();
+    let conflicts: Vec<DeltaConflict> = left_edits
+        .iter()
+        .filter(|edit| !right_edits.contains(edit))
+        .flat_map(|left_edit| {
+            right_only
+                .iter()
+                .filter(|right_edit| left_edit.touches(right_edit))
+                .map(|right_edit| DeltaConflict {
+                    left: left_edit.blocks.clone(),
+                    right: right_edit.blocks.clone(),
+                })
+        })
+        .collect();
+    if !conflicts.is_empty() {
+        return Ok(DeltaMerge::Conflicts(conflicts));
+    }
+
+    let mut edits: Vec<&TokenEdit> = left_edits.iter().chain(right_only).collect();
+    edits.sort_by_key(|edit| edit.blocks.start);
+    let mut merged = Delta {
+        header: DeltaHeader {
+            updated: None,
+            unconfirmed: left.header.unconfirmed || right.header.unconfirmed,
+            output_length: None,
+            ..left.header.clone()
+        },
+        ..Default::default()
+    };
+    // Blocks between the edits are referenced in order, as in both Deltas.
+    let mut next_block = 0;
+    for edit in edits {
+        if next_block < edit.blocks.start {
+            merged
+                .content
+                .push(Token::BlockRange(next_block..edit.blocks.start));
+        }
+        merged.content.extend(edit.tokens.iter().cloned());
+        next_block = edit.blocks.end;
+    }
+    if next_block < block_count {
+        merged
+            .content
+            .push(Token::BlockRange(next_block..block_count));
+    }
+
+    Ok(DeltaMerge::Merged(Box::new(merged)))
+}
+
+// Lists the blocks a Delta replaced, and the tokens it has in their place.
+fn token_edits(delta: &Delta, block_count: usize) -> Vec<TokenEdit> {
+    let mut edits = Vec::new();
+    // The block expected next if nothing changed, and the tokens since the last anchor.
+    let mut next_block = 0;
+    let mut tokens = Vec::new();
+    for token in delta.tokens() {
+        let indexes = token.block_indexes();
+        if indexes.is_empty() || indexes.start < next_block {
+            tokens.push(token.to_token());
+            continue;
+        }
+        if indexes.start > next_block || !tokens.is_empty() {
+            edits.push(TokenEdit {
+                blocks: next_block..indexes.start,
+                tokens: std::mem::take(&mut tokens),
+            });
+        }
+        next_block = indexes.end;
+    }
+    if next_block < block_count || !tokens.is_empty() {
+        edits.push(TokenEdit {
+            blocks: next_block..block_count.max(next_block),
+            tokens,
+        });
+    }
+
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{apply_delta, compute_delta_to_our_file, compute_signature};
+
+    const CHUNK_SIZE: usize = 4;
+
+    fn edits(basis_file: &Bytes, updated_file: &Bytes) -> Vec<Edit> {
+        let signature = compute_signature(basis_file.clone(), CHUNK_SIZE);
+        let delta = compute_delta_to_our_file(signature, updated_file.clone(), CHUNK_SIZE);
+        compute_edits(&delta, updated_file, CHUNK_SIZE, basis_file.len())
+    }
+
+    #[test]
+    fn edits_of_distinct_regions_are_merged() {
+        let basis_file = Bytes::from("aaaabbbbccccddddeeeeffff");
+        let left = Bytes::from("aaaaBBBBccccddddeeeeffff");
+        let right = Bytes::from("aaaabbbbccccddddeeeeffffgggg");
+
+        let left_edits = edits(&basis_file, &left);
+        let right_edits = edits(&basis_file, &right);
+
+        assert_eq!(
+            left_edits,
+            vec![Edit {
+                basis_range: 4..8,
+                replacement: Bytes::from("BBBB")
+            }]
+        );
+        let merged = Bytes::from("aaaaBBBBccccddddeeeeffffgggg");
+        assert_eq!(
+            merge_edits(&left, &left_edits, &right_edits),
+            Ok(merged.to_vec())
+        );
+        assert_eq!(
+            merge_edits(&right, &right_edits, &left_edits),
+            Ok(merged.to_vec())
+        );
+    }
+
+    #[test]
+    fn edits_of_the_same_region_conflict() {
+        let basis_file = Bytes::from("aaaabbbbccccdddd");
+        let left = Bytes::from("aaaaXXXXccccdddd");
+        let right = Bytes::from("aaaaYYYYccccdddd");
+
+        let conflicts = merge_edits(
+            &left,
+            &edits(&basis_file, &left),
+            &edits(&basis_file, &right),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            conflicts,
+            vec![EditConflict {
+                left: 4..8,
+                right: 4..8
+            }]
+        );
+    }
+
+    #[test]
+    fn identical_edits_are_applied_once() {
+        let basis_file = Bytes::from("aaaabbbbccccdddd");
+        let left = Bytes::from("aaaaXXXXccccdddd");
+        let right = Bytes::from("aaaaXXXXccccddddeeee");
+
+        let merged = merge_edits(
+            &left,
+            &edits(&basis_file, &left),
+            &edits(&basis_file, &right),
+        );
+
+        assert_eq!(merged, Ok(right.to_vec()));
+    }
+
+    fn delta(basis_file: &Bytes, updated_file: &str) -> Delta {
+        let signature = compute_signature(basis_file.clone(), CHUNK_SIZE);
+        compute_delta_to_our_file(signature, Bytes::from(updated_file.to_owned()), CHUNK_SIZE)
+    }
+
+    #[test]
+    fn deltas_replacing_distinct_blocks_are_merged() {
+        let basis_file = Bytes::from("aaaabbbbccccddddeeeeffff");
+        let left = delta(&basis_file, "aaaaBBBBccccddddeeeeffff");
+        let right = delta(&basis_file, "aaaabbbbccccddddEEffffgggg");
+
+        let DeltaMerge::Merged(merged) = merge_deltas(&left, &right).unwrap() else {
+            panic!("Deltas replacing distinct blocks should merge");
+        };
+
+        assert_eq!(merged.header.updated, None);
+        assert_eq!(
+            apply_delta(basis_file, *merged, CHUNK_SIZE).unwrap(),
+            "aaaaBBBBccccddddEEffffgggg"
+        );
+    }
+
+    #[test]
+    fn deltas_replacing_the_same_blocks_conflict() {
+        let basis_file = Bytes::from("aaaabbbbccccdddd");
+        let left = delta(&basis_file, "aaaaXXXXccccdddd");
+        let right = delta(&basis_file, "aaaabbbbYYYYdddd");
+
+        let merge = merge_deltas(&left, &right).unwrap();
+
+        // The edits are next to each other, so which comes first is not known for sure.
+        assert_eq!(
+            merge,
+            DeltaMerge::Conflicts(vec![DeltaConflict {
+                left: 1..2,
+                right: 2..3
+            }])
+        );
+    }
+
+    #[test]
+    fn deltas_against_other_basis_files_are_not_merged() {
+        let left = delta(&Bytes::from("aaaabbbb"), "aaaa");
+        let right = delta(&Bytes::from("ccccddddeeee"), "cccc");
+
+        assert!(merge_deltas(&left, &right).is_err());
+    }
+}
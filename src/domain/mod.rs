@@ -1,9 +1,146 @@
+#[cfg(feature = "compression")]
+pub use analysis::*;
+pub use annotation::*;
+#[cfg(feature = "archive")]
+pub use archive::*;
+#[cfg(feature = "json")]
+pub use audit::*;
+pub use basis_reader::*;
+pub use block_index::*;
+pub use blocks::*;
+pub use chunking::*;
+pub use compare::*;
+pub use compatibility::*;
+#[cfg(feature = "compression")]
+pub use compressibility::*;
 pub use delta::*;
+pub use download::*;
+#[cfg(feature = "encryption")]
+pub use encryption::*;
+pub use extents::*;
+pub use format::*;
+#[cfg(feature = "json")]
+pub use generations::*;
+pub use hierarchy::*;
+pub use inspect::*;
+pub use journal::*;
+pub use manifest::*;
+pub use merge::*;
+pub use normalization::*;
+pub use parallel::*;
+pub use partition::*;
 pub use patch::*;
+pub use policy::*;
+pub use provenance::*;
+pub use region::*;
+pub use resign::*;
+pub use session::*;
+pub use shared_basis::*;
 pub use signature::*;
+#[cfg(feature = "signing")]
+pub use signing::*;
+pub use similarity::*;
+pub use size_estimate::*;
+pub use streaming::*;
+pub use strong_hash::*;
+pub use token_buffer::*;
+#[cfg(feature = "compression")]
+pub use transfer::*;
+pub use transform::*;
+pub use weak_hash::*;
 
+#[cfg(feature = "compression")]
+pub mod analysis;
+// Analysis tells how large Signatures and Deltas are for several chunk sizes and compression levels
+pub mod annotation;
+// Annotation records where a Delta comes from, for humans and pipelines to trace it
+#[cfg(feature = "archive")]
+pub mod archive;
+// Archive is a transform which makes zip and tar archives easier to compare
+#[cfg(feature = "json")]
+pub mod audit;
+// Audit appends what each sync did to a log, to add up savings over time
+pub mod basis_reader;
+// BasisReader reads blocks of the basis file where they are, without reading it whole
+pub mod block_index;
+// BlockIndex keeps the rolling hashes of an updated file, to compute its Deltas against many Signatures
+pub mod blocks;
+// Blocks lists the hashes of each block of a file, for other tools to use
+pub mod chunking;
+// Chunking is how files are divided into blocks
+pub mod compare;
+// Compare tells where two files differ, using only their Signatures
+pub mod compatibility;
+// Compatibility checks artifacts written by earlier builds are still read the same way
+#[cfg(feature = "compression")]
+pub mod compressibility;
+// Compressibility tells already compressed files apart, as matching their blocks is seldom worth it
 pub mod delta;
 // Delta is the representation of a difference from `basis_file` and  `updated_file``
+pub mod download;
+// Download reverses the roles, so clients only request the blocks of a published file they lack
+#[cfg(feature = "encryption")]
+pub mod encryption;
+// Encryption protects Signatures and Deltas stored or relayed through untrusted places
+pub mod extents;
+// Extents line blocks up with where the file system stores the basis file
+pub mod format;
+// Format is how Signatures and Deltas are laid out in files
+#[cfg(feature = "json")]
+pub mod generations;
+// Generations back up successive versions of a file as a chain of Deltas
+pub mod hierarchy;
+// Hierarchy matches large files with coarse blocks first, and fine blocks only where they changed
+pub mod inspect;
+// Inspect presents the content of a Delta to humans
+pub mod journal;
+// Journal saves what an in-place update overwrites, to roll it back after a crash
+pub mod manifest;
+// Manifest records whole-file hashes of a directory tree, to verify it later
+pub mod merge;
+// Merge combines the changes two copies of a file made since they were last synced
+pub mod normalization;
+// Normalization makes text files from different platforms comparable
+pub mod optimal;
+// Optimal matching chooses the tokens making the smallest Delta
+pub mod optimize;
+// Optimize rewrites Deltas with as few tokens as possible
+pub mod parallel;
+// Parallel splits hashing across threads, without changing the artifacts
+pub mod partition;
+// Partition applies Deltas from one fixed-size partition to another, as A/B firmware updates do
 pub mod patch;
 // Patch is the process of applying a Delta to `basis_file` and constructing `recreated_file`
-pub mod signature; // Signature is the representation of `basis_file`
+pub mod policy;
+// Policy chooses how files of each kind are synced, by their path
+pub mod provenance;
+// Provenance describes where each region of `recreated_file` comes from
+pub mod region;
+// Region restricts Signatures and Deltas to the part of the basis file which changed
+pub mod resign;
+// Resign migrates Signatures to another chunk size, checking they describe the same basis file
+pub mod session;
+// Session steps programs using the library through syncs, with the options of both sides
+pub mod shared_basis;
+// SharedBasis lays out the basis files of a tree as one, so Deltas reference blocks of any of them
+pub mod signature;
+// Signature is the representation of `basis_file`
+#[cfg(feature = "signing")]
+pub mod signing;
+// Signing proves Signatures and Deltas were not tampered with on their way
+pub mod similarity;
+// Similarity tells how much of a file exists in another, without computing a Delta
+pub mod size_estimate;
+// SizeEstimate tells how large a Delta is once serialized, without serializing it
+pub mod streaming;
+// Streaming writes Deltas while they are being computed
+pub mod strong_hash;
+// StrongHash is the hash confirming a block of the basis file was found
+pub mod token_buffer;
+// TokenBuffer stores the tokens of a Delta compactly, without an allocation per token
+#[cfg(feature = "compression")]
+pub mod transfer;
+// Transfer brings a directory tree up to date with another, playing both sides of the algorithm
+pub mod transform;
+// Transform is a reversible preprocessing of files, applied before chunking
+pub mod weak_hash; // WeakHash is the rolling hash used to find blocks of the basis file
@@ -0,0 +1,160 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+/// Records how text files were normalized before computing a Delta.
+///
+/// Files edited on different platforms often differ only in line endings (and byte order marks),
+/// which makes every block different. Normalizing both files first lets them match again,
+/// and this record is what allows `patch` to give the updated file its original form back.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct TextNormalization {
+    /// Whether byte order marks were stripped from both files.
+    pub strip_bom: bool,
+    /// The updated file used CRLF line endings everywhere, which were converted to LF.
+    pub restore_crlf: bool,
+    /// The updated file started with a byte order mark, which was stripped.
+    pub restore_bom: bool,
+}
+
+/// Normalizes the basis file: CRLF line endings become LF, and the byte order mark is optionally
+/// stripped. This does not need to be reversible, as the basis file is only used as a source
+/// of blocks.
+///
+/// # Arguments
+/// * `basis_file` - The content of the basis file.
+/// * `strip_bom` - Whether a leading UTF-8 byte order mark should be removed.
+///
+pub fn normalize_basis_file(basis_file: Bytes, strip_bom: bool) -> Bytes {
+    let content = if strip_bom {
+        basis_file
+            .strip_prefix(BYTE_ORDER_MARK)
+            .unwrap_or(&basis_file)
+    } else {
+        &basis_file[..]
+    };
+
+    crlf_to_lf(content).into()
+}
+
+/// Normalizes the updated file, returning the record needed to reverse the normalization.
+///
+/// Line endings are only normalized if the file consistently uses CRLF, as a file with mixed
+/// line endings could not be restored exactly.
+///
+/// # Arguments
+/// * `updated_file` - The content of the updated file.
+/// * `strip_bom` - Whether a leading UTF-8 byte order mark should be removed.
+///
+pub fn normalize_updated_file(updated_file: Bytes, strip_bom: bool) -> (Bytes, TextNormalization) {
+    let mut normalization = TextNormalization {
+        strip_bom,
+        ..Default::default()
+    };
+
+    let mut content = &updated_file[..];
+    if strip_bom {
+        if let Some(stripped) = content.strip_prefix(BYTE_ORDER_MARK) {
+            content = stripped;
+            normalization.restore_bom = true;
+        }
+    }
+
+    let line_feeds = content.iter().filter(|&&byte| byte == b'\n').count();
+    let carriage_return_line_feeds = content.windows(2).filter(|w| w == b"\r\n").count();
+    let content = if line_feeds > 0 && line_feeds == carriage_return_line_feeds {
+        normalization.restore_crlf = true;
+        crlf_to_lf(content)
+    } else {
+        content.to_vec()
+    };
+
+    (content.into(), normalization)
+}
+
+/// Reverses `normalize_updated_file`.
+///
+/// # Arguments
+/// * `recreated_file` - The normalized content of the updated file.
+/// * `normalization` - How the updated file was normalized.
+///
+pub fn restore_normalized_file(recreated_file: Bytes, normalization: &TextNormalization) -> Bytes {
+    let mut restored = Vec::with_capacity(recreated_file.len());
+    if normalization.restore_bom {
+        restored.extend_from_slice(BYTE_ORDER_MARK);
+    }
+    if normalization.restore_crlf {
+        recreated_file.iter().for_each(|&byte| {
+            if byte == b'\n' {
+                restored.push(b'\r');
+            }
+            restored.push(byte);
+        });
+    } else {
+        restored.extend_from_slice(&recreated_file);
+    }
+
+    restored.into()
+}
+
+fn crlf_to_lf(content: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(content.len());
+    let mut bytes = content.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        // A carriage return is only dropped when it is part of a CRLF.
+        if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_file_is_restored_exactly() {
+        let updated_file = Bytes::from("\u{FEFF}first\r\nsecond\r\n");
+
+        let (normalized, normalization) = normalize_updated_file(updated_file.clone(), true);
+
+        assert_eq!(normalized, Bytes::from("first\nsecond\n"));
+        assert!(normalization.restore_crlf);
+        assert!(normalization.restore_bom);
+        assert_eq!(
+            restore_normalized_file(normalized, &normalization),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn mixed_line_endings_are_left_untouched() {
+        let updated_file = Bytes::from("first\r\nsecond\n");
+
+        let (normalized, normalization) = normalize_updated_file(updated_file.clone(), false);
+
+        assert_eq!(normalized, updated_file);
+        assert!(!normalization.restore_crlf);
+        assert_eq!(
+            restore_normalized_file(normalized, &normalization),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn basis_and_updated_files_normalize_to_the_same_content() {
+        let basis_file = Bytes::from("first\r\nsecond\r\n");
+        let updated_file = Bytes::from("first\nsecond\n");
+
+        let (normalized_updated_file, _) = normalize_updated_file(updated_file, false);
+
+        assert_eq!(
+            normalize_basis_file(basis_file, false),
+            normalized_updated_file
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::domain::delta::{
+    find_matching_block, find_short_last_block, index_rolling_hashes, stream_fixed_delta,
+    MatchStrategy, MatchingOptions, Token, TokenSink,
+};
+use crate::domain::FileSignature;
+use crate::profiling::{self, Section};
+
+// Estimated size of each token of an optimized Delta, serialized with MessagePack: a map from the
+// name of the variant to its fields.
+// "ByteLiterals" and the length of the run, without the bytes themselves.
+pub(crate) const LITERAL_RUN_COST: u64 = 15;
+// "BlockIndex", without the index itself.
+const BLOCK_REFERENCE_COST: u64 = 12;
+// Turning a reference into a BlockRange, or extending one, only changes the last index.
+const RANGE_EXTENSION_COST: u64 = 1;
+// The widest integer, with its tag.
+const MAX_INTEGER_COST: u64 = 9;
+
+// Each search for the cheapest tokens covers this many blocks of our file, but at most
+// `MAX_WINDOW_BYTES`. Blocks so large that two of them do not fit in a window always cost less
+// than their bytes, so every block found is referenced instead.
+const WINDOW_BLOCKS: usize = 64;
+const MAX_WINDOW_BYTES: usize = 256 * 1024;
+// Each search also looks past its window by this fraction of it, so the tokens kept do not favour
+// literals only because the bytes after the window were unknown.
+const LOOKAHEAD_FRACTION: usize = 4;
+
+/// Estimated cost of sending `bytes` inside a run of literals, once serialized.
+pub(crate) fn literals_cost(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .map(|&byte| if byte < 0x80 { 1 } else { 2 })
+        .sum()
+}
+
+/// Estimated cost of referencing `block`, once serialized, right after `previous_block`.
+pub(crate) fn reference_cost(previous_block: Option<usize>, block: usize) -> u64 {
+    if previous_block.is_some_and(|previous| previous + 1 == block) {
+        RANGE_EXTENSION_COST
+    } else {
+        BLOCK_REFERENCE_COST + integer_cost(block as u64)
+    }
+}
+
+// MessagePack writes integers with as few bytes as their value needs, after a tag.
+fn integer_cost(value: u64) -> u64 {
+    match value {
+        0..=0x7f => 1,
+        0x80..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => MAX_INTEGER_COST,
+    }
+}
+
+// Having referenced one block rather than another saves at most this much on the next token,
+// which may extend a range instead of starting a new reference.
+const MAX_CONTINUATION_SAVING: u64 = BLOCK_REFERENCE_COST + MAX_INTEGER_COST - RANGE_EXTENSION_COST;
+
+// The cheapest way found to cover our file up to some position, ending with a given token.
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    cost: u64,
+    /// The block referenced by the last token, or None if it was a literal.
+    block: Option<usize>,
+    /// The last block referenced, even before some literals, which matches are looked for after.
+    last_block: Option<usize>,
+    /// Offset and block of the previous step.
+    from: Option<(usize, Option<usize>)>,
+}
+
+// At each offset of a window, the cheapest step ending with a literal, and for each block, the
+// cheapest step ending with a reference to it. References which are too costly to ever make the
+// cheapest Delta are dropped.
+#[derive(Debug, Default, Clone)]
+struct Steps {
+    literal: Option<Step>,
+    references: Vec<Step>,
+}
+
+impl Steps {
+    fn get(&self, block: Option<usize>) -> Option<Step> {
+        match block {
+            Some(_) => self
+                .references
+                .iter()
+                .find(|step| step.block == block)
+                .copied(),
+            None => self.literal,
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = Step> + '_ {
+        self.literal.iter().chain(&self.references).copied()
+    }
+
+    fn relax(&mut self, step: Step) {
+        if step.block.is_none() {
+            if self.literal.is_none_or(|literal| step.cost < literal.cost) {
+                self.literal = Some(step);
+            }
+            return;
+        }
+
+        match self
+            .references
+            .iter_mut()
+            .find(|reference| reference.block == step.block)
+        {
+            Some(reference) if step.cost < reference.cost => *reference = step,
+            Some(_) => {}
+            None => self.references.push(step),
+        }
+        let cheapest = self.references.iter().map(|reference| reference.cost).min();
+        if let Some(cheapest) = cheapest {
+            self.references
+                .retain(|reference| reference.cost <= cheapest + MAX_CONTINUATION_SAVING);
+        }
+    }
+}
+
+/// Computes the tokens of a Delta in fixed mode, choosing those making the Delta smallest.
+///
+/// Referencing a block is not always cheaper than sending its bytes: each token has a cost, and a
+/// reference splits the literals around it in two runs. The cheapest tokens are searched over a
+/// window of blocks at a time, keeping at each position the cheapest way to end with a literal
+/// and with a reference to each block. Only tokens ending inside the window are pushed, the
+/// search looking a bit further so the bytes after the window are accounted for.
+/// `options.resync_after` is ignored, as every position is looked up anyway.
+///
+/// # Arguments
+/// * `signature` - The FileSignature representing the basis file.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used in the Signature.
+/// * `our_sliding_blocks_rolling_hashes` - As computed by `compute_sliding_rolling_hashes`.
+/// * `options` - How the matcher is tuned.
+/// * `tokens` - Where the tokens are pushed to, in order.
+///
+pub fn stream_optimal_delta(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+    our_sliding_blocks_rolling_hashes: &[u64],
+    options: &MatchingOptions,
+    tokens: &mut impl TokenSink,
+) -> eyre::Result<()> {
+    if 2 * chunk_size > MAX_WINDOW_BYTES {
+        let options = MatchingOptions {
+            strategy: MatchStrategy::Lazy,
+            ..*options
+        };
+        return stream_fixed_delta(
+            signature,
+            updated_file,
+            chunk_size,
+            our_sliding_blocks_rolling_hashes,
+            &options,
+            tokens,
+        );
+    }
+
+    let their_rolling_hashes = index_rolling_hashes(signature);
+    let window = (WINDOW_BLOCKS * chunk_size).min(MAX_WINDOW_BYTES);
+    let mut start = 0;
+    let mut first = Step {
+        cost: 0,
+        block: None,
+        last_block: None,
+        from: None,
+    };
+    let mut started = false;
+    while start < updated_file.len() {
+        let end = (start + window + window / LOOKAHEAD_FRACTION).min(updated_file.len());
+        // Tokens ending after this may still change once the bytes after the search are known.
+        let commit_until = if end == updated_file.len() {
+            end
+        } else {
+            start + window
+        };
+
+        let path = cheapest_tokens(
+            signature,
+            updated_file,
+            chunk_size,
+            our_sliding_blocks_rolling_hashes,
+            &their_rolling_hashes,
+            options,
+            start..end,
+            first,
+            started,
+        );
+        for (token_end, token) in path {
+            if token_end > commit_until {
+                break;
+            }
+            first.block = token.block_indexes().last();
+            first.last_block = first.block.or(first.last_block);
+            started = true;
+            tokens.push(token)?;
+            start = token_end;
+        }
+    }
+
+    Ok(())
+}
+
+// Searches the cheapest tokens covering `window`, after a token ending with `first.block`
+// (if `started`), and returns each of them with the position it ends at.
+#[allow(clippy::too_many_arguments)]
+fn cheapest_tokens(
+    signature: &FileSignature,
+    updated_file: &[u8],
+    chunk_size: usize,
+    our_sliding_blocks_rolling_hashes: &[u64],
+    their_rolling_hashes: &HashMap<u64, Vec<usize>>,
+    options: &MatchingOptions,
+    window: Range<usize>,
+    first: Step,
+    started: bool,
+) -> Vec<(usize, Token)> {
+    let length = window.len();
+    let mut steps = vec![Steps::default(); length + 1];
+    match first.block {
+        Some(_) => steps[0].references.push(first),
+        None => steps[0].literal = Some(first),
+    }
+    // Only the last window may end with the short last block of the basis file.
+    let short_last_block = (window.end == updated_file.len())
+        .then(|| find_short_last_block(signature, updated_file, chunk_size))
+        .flatten()
+        .filter(|&(position, _)| position >= window.start);
+
+    for offset in 0..length {
+        let position = window.start + offset;
+        let block_bytes = updated_file.get(position..position + chunk_size);
+        let candidates = block_bytes
+            .filter(|_| offset + chunk_size <= length)
+            .and_then(|_| their_rolling_hashes.get(&our_sliding_blocks_rolling_hashes[position]));
+        let our_strong_hash = candidates.and(block_bytes).map(|block| {
+            profiling::measure(Section::StrongHash, 1, || {
+                signature.strong_hash.digest(block)
+            })
+        });
+
+        for step in steps[offset].clone().all() {
+            let from = Some((offset, step.block));
+
+            let byte = &updated_file[position..=position];
+            // Before anything is sent, the next literal starts a run, like after a reference.
+            let starts_run = step.block.is_some() || (offset == 0 && !started);
+            let run_cost = if starts_run { LITERAL_RUN_COST } else { 0 };
+            steps[offset + 1].relax(Step {
+                cost: step.cost + run_cost + literals_cost(byte),
+                block: None,
+                last_block: step.last_block,
+                from,
+            });
+
+            if let Some((_, block)) = short_last_block.filter(|&(start, _)| start == position) {
+                steps[length].relax(Step {
+                    cost: step.cost + reference_cost(step.block, block),
+                    block: Some(block),
+                    last_block: Some(block),
+                    from,
+                });
+            }
+
+            let (Some(candidates), Some(block_bytes)) = (candidates, block_bytes) else {
+                continue;
+            };
+            // Continuing a range is cheaper than any other block with the same content.
+            let continued = step
+                .block
+                .map(|previous| previous + 1)
+                .filter(|next| candidates.binary_search(next).is_ok())
+                .filter(|&next| {
+                    our_strong_hash.is_some_and(|digest| signature.has_strong_digest(next, &digest))
+                });
+            let matched = continued.or_else(|| {
+                find_matching_block(signature, candidates, block_bytes, options, step.last_block)
+            });
+            if let Some(block) = matched {
+                steps[offset + chunk_size].relax(Step {
+                    cost: step.cost + reference_cost(step.block, block),
+                    block: Some(block),
+                    last_block: Some(block),
+                    from,
+                });
+            }
+        }
+    }
+
+    // Walk back from the cheapest way to cover the whole window.
+    let mut current = steps[length]
+        .all()
+        .min_by_key(|step| step.cost)
+        .map(|step| (length, step.block));
+    let mut path = Vec::new();
+    while let Some((offset, block)) = current {
+        let step = steps[offset]
+            .get(block)
+            .expect("Steps are only linked to existing steps");
+        let Some((from_offset, _)) = step.from else {
+            break;
+        };
+        let token = match step.block {
+            Some(block) => Token::BlockIndex(block),
+            None => Token::ByteLiteral(updated_file[window.start + from_offset]),
+        };
+        path.push((window.start + offset, token));
+        current = step.from;
+    }
+    path.reverse();
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::{
+        apply_delta, compute_delta_with_mode, compute_signature, ChunkingMode, Delta,
+    };
+
+    use super::*;
+
+    fn delta_with_strategy(
+        basis_file: &Bytes,
+        updated_file: &Bytes,
+        strategy: MatchStrategy,
+    ) -> Delta {
+        let test_chunk_size = 4;
+        let options = MatchingOptions {
+            strategy,
+            ..Default::default()
+        };
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut delta = compute_delta_with_mode(
+            signature,
+            updated_file.clone(),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &options,
+        );
+        delta.optimize();
+        delta
+    }
+
+    #[test]
+    fn isolated_small_blocks_are_sent_as_literals() {
+        let basis_file = Bytes::from("ABCD");
+        let updated_file = Bytes::from("xxxxxxABCDyyyyyy");
+
+        for strategy in [MatchStrategy::Lazy, MatchStrategy::Optimal] {
+            let delta = delta_with_strategy(&basis_file, &updated_file, strategy);
+
+            assert_eq!(
+                delta.content,
+                vec![Token::ByteLiterals(updated_file.to_vec())],
+                "{strategy}"
+            );
+        }
+        let greedy = delta_with_strategy(&basis_file, &updated_file, MatchStrategy::Greedy);
+        assert!(greedy.content.contains(&Token::BlockIndex(0)));
+    }
+
+    #[test]
+    fn a_short_last_block_continues_the_range_before_it() {
+        // Blocks of 4 bytes, and a last one of a single byte.
+        let basis_file = Bytes::from("Hello World!!");
+
+        for strategy in [MatchStrategy::Greedy, MatchStrategy::Optimal] {
+            let delta = delta_with_strategy(&basis_file, &basis_file, strategy);
+
+            assert_eq!(delta.content, vec![Token::BlockRange(0..4)], "{strategy}");
+        }
+    }
+
+    #[test]
+    fn optimal_delta_is_never_larger_and_recreates_the_file() {
+        let basis_file = Bytes::from("0123456789abcdefghijklmnopqrstuvwxyz".repeat(40));
+        let mut updated_file = basis_file.to_vec();
+        for position in (5..updated_file.len()).step_by(23) {
+            updated_file[position] = b'#';
+        }
+        updated_file.splice(100..100, b"inserted".iter().copied());
+        let updated_file = Bytes::from(updated_file);
+
+        let greedy = delta_with_strategy(&basis_file, &updated_file, MatchStrategy::Greedy);
+        let optimal = delta_with_strategy(&basis_file, &updated_file, MatchStrategy::Optimal);
+
+        let size = |delta: &Delta| Bytes::try_from(delta.clone()).unwrap().len();
+        assert!(size(&optimal) <= size(&greedy));
+        assert_eq!(apply_delta(basis_file, optimal, 4).unwrap(), updated_file);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::mem;
+
+use crate::domain::delta::{Delta, Token};
+use crate::domain::{token_size, FileSignature, TokenRef};
+
+impl Delta {
+    /// Rewrites the tokens of the Delta in canonical form, recreating the same file.
+    ///
+    /// Adjacent byte literals are merged into a single ByteLiterals, references to consecutive
+    /// blocks into a single BlockRange, adjacent runs of zeros into a single ZeroRun, and empty
+    /// runs are dropped. Runs of a single element are kept as a BlockIndex or a ByteLiteral, so an
+    /// optimized Delta is never larger.
+    pub fn optimize(&mut self) {
+        let mut optimized: Vec<Token> = Vec::new();
+        for token in mem::take(&mut self.content).iter() {
+            let indexes = token.block_indexes();
+            let literals = token.literals();
+            let zeros = token.zeros();
+            match optimized.last_mut() {
+                _ if indexes.is_empty() && literals.is_empty() && zeros == 0 => {}
+                Some(last)
+                    if !last.block_indexes().is_empty()
+                        && last.block_indexes().end == indexes.start =>
+                {
+                    *last = Token::BlockRange(last.block_indexes().start..indexes.end);
+                }
+                Some(Token::ByteLiterals(run)) if !literals.is_empty() => {
+                    run.extend_from_slice(literals)
+                }
+                Some(last @ Token::ByteLiteral(_)) if !literals.is_empty() => {
+                    let mut run = last.literals().to_vec();
+                    run.extend_from_slice(literals);
+                    *last = Token::ByteLiterals(run);
+                }
+                Some(Token::ZeroRun(run)) if zeros > 0 => *run += zeros,
+                _ => optimized.push(token.to_token()),
+            }
+        }
+
+        self.content = optimized
+            .into_iter()
+            .map(|token| match token {
+                Token::BlockRange(indexes) if indexes.len() == 1 => {
+                    Token::BlockIndex(indexes.start)
+                }
+                Token::ByteLiterals(run) if run.len() == 1 => Token::ByteLiteral(run[0]),
+                token => token,
+            })
+            .collect();
+    }
+
+    /// Like `optimize`, but also replaces runs of literals identical to a block of the basis file
+    /// with a reference to that block.
+    ///
+    /// # Arguments
+    /// * `signature` - The FileSignature the Delta was computed from.
+    ///
+    pub fn optimize_against(&mut self, signature: &FileSignature) {
+        self.optimize();
+
+        // Map with key: StrongHash and value: index of the first block with it.
+        let mut blocks = HashMap::new();
+        for (index, strong_hash) in signature.strong_hashes.iter().enumerate() {
+            blocks.entry(*strong_hash).or_insert(index);
+        }
+        self.content = self
+            .content
+            .iter()
+            .map(|token| {
+                let literals = token.literals();
+                if !literals.is_empty() {
+                    let digest = signature.strong_hash.digest(literals);
+                    if let Some(&index) = blocks.get(&digest.head) {
+                        if signature.has_strong_digest(index, &digest)
+                            && signature.rolling_hashes[index] == signature.weak_hash.hash(literals)
+                        {
+                            return Token::BlockIndex(index);
+                        }
+                    }
+                }
+                token.to_token()
+            })
+            .collect();
+
+        // Replaced runs may now be next to references to their neighbouring blocks.
+        self.optimize();
+    }
+
+    /// Like `optimize`, but also sends as literals the references which, once serialized, take
+    /// more bytes than the literals they stand for, so referencing a block never makes the Delta
+    /// larger.
+    ///
+    /// A reference between literals splits them in two runs, so with small chunk sizes it can
+    /// cost more than its bytes. Sizes are the exact ones of the serialized tokens, not the
+    /// estimates the matching strategies decide with. The Delta is left as it is if its blocks do
+    /// not fit in `updated_file`.
+    ///
+    /// # Arguments
+    /// * `updated_file` - The file the Delta recreates, as it was matched.
+    /// * `chunk_size` - Size of the blocks, except for the last block of the basis file, whose
+    ///   size is known from the Delta's header.
+    ///
+    pub fn drop_costly_references(&mut self, updated_file: &[u8], chunk_size: usize) {
+        self.optimize();
+
+        let last_block = self.header.basis.and_then(|basis| {
+            let index = basis.block_count.checked_sub(1)? as usize;
+            Some((index, basis.last_block_size? as usize))
+        });
+        let block_size = |index: usize| match last_block {
+            Some((last, size)) if index == last => size,
+            _ => chunk_size,
+        };
+
+        let tokens: Vec<Token> = self.content.iter().map(|token| token.to_token()).collect();
+        let mut minimized: Vec<Token> = Vec::with_capacity(tokens.len());
+        let mut offset = 0;
+        for (position, token) in tokens.iter().enumerate() {
+            let length = match token.block_indexes() {
+                indexes if indexes.is_empty() => token.literals().len() + token.zeros(),
+                indexes => indexes.map(block_size).sum(),
+            };
+            let Some(bytes) = updated_file.get(offset..offset + length) else {
+                return;
+            };
+            offset += length;
+            if token.zeros() > 0 {
+                minimized.push(token.clone());
+                continue;
+            }
+            if token.block_indexes().is_empty() {
+                push_literals(&mut minimized, bytes);
+                continue;
+            }
+
+            let before = minimized.last().map_or(&[][..], |last| last.literals());
+            let after = tokens
+                .get(position + 1)
+                .map_or(&[][..], |next| next.literals());
+            let as_reference =
+                literals_size(before) + token_size(&TokenRef::from(token)) + literals_size(after);
+            let as_literals = literals_size(&[before, bytes, after].concat());
+            if as_literals < as_reference {
+                push_literals(&mut minimized, bytes);
+            } else {
+                minimized.push(token.clone());
+            }
+        }
+        if offset != updated_file.len() {
+            return;
+        }
+
+        self.content = minimized.into_iter().collect();
+        self.optimize();
+    }
+}
+
+// Appends `bytes` to the run of literals `tokens` ends with, or starts one.
+fn push_literals(tokens: &mut Vec<Token>, bytes: &[u8]) {
+    match tokens.last_mut() {
+        Some(Token::ByteLiterals(run)) => run.extend_from_slice(bytes),
+        _ => tokens.push(Token::ByteLiterals(bytes.to_vec())),
+    }
+}
+
+// Bytes a run of literals takes once serialized, as `optimize` writes it.
+fn literals_size(bytes: &[u8]) -> u64 {
+    match bytes {
+        [] => 0,
+        [byte] => token_size(&TokenRef::ByteLiteral(byte)),
+        bytes => token_size(&TokenRef::ByteLiterals(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::{
+        apply_delta, compute_delta_with_mode, compute_signature, ChunkingMode, MatchingOptions,
+    };
+
+    use super::*;
+
+    fn create_byte_literals(bytes: &[u8]) -> Vec<Token> {
+        bytes.iter().copied().map(Token::ByteLiteral).collect()
+    }
+
+    #[test]
+    fn adjacent_tokens_are_merged_into_runs() {
+        let mut content = vec![Token::BlockIndex(2), Token::BlockIndex(3)];
+        content.extend(create_byte_literals(b"abc"));
+        content.extend([
+            Token::BlockIndex(0),
+            Token::BlockIndex(1),
+            Token::BlockIndex(5),
+        ]);
+        content.extend([Token::ByteLiterals(Vec::new()), Token::ByteLiteral(b'd')]);
+        content.extend([Token::ZeroRun(100), Token::ZeroRun(0), Token::ZeroRun(28)]);
+        let mut delta = Delta {
+            content: content.into(),
+            ..Default::default()
+        };
+
+        delta.optimize();
+
+        assert_eq!(
+            delta.content,
+            vec![
+                Token::BlockRange(2..4),
+                Token::ByteLiterals(b"abc".to_vec()),
+                Token::BlockRange(0..2),
+                Token::BlockIndex(5),
+                Token::ByteLiteral(b'd'),
+                Token::ZeroRun(128),
+            ]
+        );
+    }
+
+    #[test]
+    fn optimized_delta_recreates_the_same_file() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("ABCDEFGHIJKL");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut content = vec![Token::BlockIndex(0)];
+        // Literals identical to block 1, which the optimizer turns into a reference.
+        content.extend(create_byte_literals(b"EFGH"));
+        content.push(Token::BlockIndex(2));
+        content.extend(create_byte_literals(b"xy"));
+        let mut delta = Delta {
+            content: content.into(),
+            ..Default::default()
+        };
+        let expected = apply_delta(basis_file.clone(), delta.clone(), test_chunk_size).unwrap();
+
+        delta.optimize_against(&signature);
+
+        assert_eq!(
+            delta.content,
+            vec![Token::BlockRange(0..3), Token::ByteLiterals(b"xy".to_vec())]
+        );
+        assert_eq!(
+            apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn references_costing_more_than_their_bytes_are_sent_as_literals() {
+        let test_chunk_size = 2;
+        let basis_file = Bytes::from("ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcd");
+        let updated_file = Bytes::from([&b"xyzCDxyz"[..], &basis_file[..], &b"xyz"[..]].concat());
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut delta = compute_delta_with_mode(
+            signature,
+            updated_file.clone(),
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+        );
+        let greedy_size = delta.serialized_size_estimate();
+
+        delta.drop_costly_references(&updated_file, test_chunk_size);
+
+        // A lone block between literals costs more than its 2 bytes, the whole file less.
+        assert_eq!(
+            delta.content,
+            vec![
+                Token::ByteLiterals(b"xyzCDxyz".to_vec()),
+                Token::BlockRange(0..20),
+                Token::ByteLiterals(b"xyz".to_vec()),
+            ]
+        );
+        assert!(delta.serialized_size_estimate() < greedy_size);
+        assert_eq!(
+            apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            updated_file
+        );
+    }
+}
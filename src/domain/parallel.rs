@@ -0,0 +1,146 @@
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::thread;
+
+use eyre::eyre;
+
+use crate::help::Help;
+
+/// Environment variable with the number of threads to use, when `--threads` is not given.
+pub const THREADS_VARIABLE: &str = "RSYNC_RUST_THREADS";
+
+// Below this many items per thread, spawning threads costs more than it saves.
+const MIN_ITEMS_PER_THREAD: usize = 4096;
+
+/// How many threads the work of a command may be split across.
+///
+/// Work is split in contiguous parts whose results are put back in order, so artifacts are
+/// byte-identical whatever the number of threads. With a single thread, nothing is spawned.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Parallelism {
+    threads: NonZeroUsize,
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Self::serial()
+    }
+}
+
+impl Parallelism {
+    /// Runs everything on the calling thread.
+    pub fn serial() -> Self {
+        Self {
+            threads: NonZeroUsize::MIN,
+        }
+    }
+
+    pub fn with_threads(threads: NonZeroUsize) -> Self {
+        Self { threads }
+    }
+
+    /// Uses `threads` if given, else the `RSYNC_RUST_THREADS` environment variable, else every
+    /// available core.
+    pub fn from_environment(threads: Option<NonZeroUsize>) -> eyre::Result<Self> {
+        if let Some(threads) = threads {
+            return Ok(Self::with_threads(threads));
+        }
+
+        match std::env::var(THREADS_VARIABLE) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map(Self::with_threads)
+                .map_err(|_| eyre!(r#"{THREADS_VARIABLE} is "{value}", not a number of threads"#))
+                .suggestion("Set it to a positive integer, or unset it to use every core."),
+            Err(_) => Ok(Self::with_threads(
+                thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.get()
+    }
+
+    pub fn is_serial(&self) -> bool {
+        self.threads == NonZeroUsize::MIN
+    }
+
+    /// Computes the results for `0..length`, splitting it in contiguous ranges across threads.
+    ///
+    /// # Arguments
+    /// * `length` - How many results there are.
+    /// * `compute` - Computes the results for a range, in order.
+    ///
+    pub fn map_ranges<R: Send>(
+        &self,
+        length: usize,
+        compute: impl Fn(Range<usize>) -> Vec<R> + Sync,
+    ) -> Vec<R> {
+        let threads = self.threads().min(length / MIN_ITEMS_PER_THREAD).max(1);
+        if threads == 1 {
+            return compute(0..length);
+        }
+
+        let part_length = length.div_ceil(threads);
+        let compute = &compute;
+        thread::scope(|scope| {
+            let parts: Vec<_> = (0..length)
+                .step_by(part_length)
+                .map(|start| scope.spawn(move || compute(start..length.min(start + part_length))))
+                .collect();
+            parts
+                .into_iter()
+                .flat_map(|part| part.join().expect("Worker thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Runs `first` and `second` at the same time, or one after the other if serial.
+    pub fn join<A: Send, B>(
+        &self,
+        first: impl FnOnce() -> A + Send,
+        second: impl FnOnce() -> B,
+    ) -> (A, B) {
+        if self.is_serial() {
+            let first = first();
+            return (first, second());
+        }
+
+        thread::scope(|scope| {
+            let first = scope.spawn(first);
+            let second = second();
+            (first.join().expect("Worker thread panicked"), second)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_in_order_whatever_the_number_of_threads() {
+        let length = 10 * MIN_ITEMS_PER_THREAD + 3;
+        let squares = |range: Range<usize>| range.map(|i| i * i).collect::<Vec<_>>();
+
+        let serial = Parallelism::serial().map_ranges(length, squares);
+        for threads in [2, 3, 7, 64] {
+            let parallelism = Parallelism::with_threads(NonZeroUsize::new(threads).unwrap());
+            assert_eq!(parallelism.map_ranges(length, squares), serial);
+        }
+        assert_eq!(serial.len(), length);
+    }
+
+    #[test]
+    fn explicit_threads_win_over_the_environment() {
+        let threads = NonZeroUsize::new(3).unwrap();
+
+        let parallelism = Parallelism::from_environment(Some(threads)).unwrap();
+
+        assert_eq!(parallelism.threads(), 3);
+        assert!(!parallelism.is_serial());
+        assert!(Parallelism::serial().is_serial());
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use eyre::{eyre, Context};
+
+use crate::domain::{
+    apply_delta_from_reader, check_basis_reader, simulate_delta, BasisReader, Delta, FileDigest,
+    PatchOutput,
+};
+use crate::help::Help;
+
+/// An image at the start of anything which can be read and seeked, like a partition of a device.
+///
+/// Only the first `length` bytes are read, so the partition may be larger than the image.
+pub struct SeekBasis<R> {
+    inner: R,
+    length: u64,
+}
+
+impl<R: Read + Seek> SeekBasis<R> {
+    pub fn new(inner: R, length: u64) -> Self {
+        Self { inner, length }
+    }
+}
+
+impl<R: Read + Seek> BasisReader for SeekBasis<R> {
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        ranges
+            .iter()
+            .map(|range| {
+                self.inner.seek(SeekFrom::Start(range.start))?;
+                let mut block = vec![0; (range.end - range.start) as usize];
+                self.inner.read_exact(&mut block)?;
+                Ok(block)
+            })
+            .collect()
+    }
+}
+
+// Writes an image from the start of a partition, refusing to write past its end, and reports how
+// much of it was written after every write.
+struct PartitionWriter<W, F> {
+    inner: W,
+    capacity: u64,
+    image_size: u64,
+    written: u64,
+    on_progress: F,
+}
+
+impl<W: Write, F: FnMut(u64, u64)> Write for PartitionWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > self.capacity {
+            return Err(io::Error::other(format!(
+                "Image does not fit in a partition of {} bytes",
+                self.capacity
+            )));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        (self.on_progress)(self.written, self.image_size);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write, F: FnMut(u64, u64)> PatchOutput for PartitionWriter<W, F> {}
+
+/// The image written to the target partition.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PartitionUpdate {
+    /// Length and hash of the image, as written.
+    pub image: FileDigest,
+    /// Bytes the target partition holds.
+    pub capacity: u64,
+}
+
+impl fmt::Display for PartitionUpdate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Wrote an image of {} to a partition of {} bytes ({} bytes left as they were)",
+            self.image,
+            self.capacity,
+            self.capacity - self.image.length
+        )
+    }
+}
+
+/// Applies a Delta to an image at the start of a partition, writing the updated image at the
+/// start of another one, as A/B updates of firmware do.
+///
+/// The current image is hashed and checked against the Delta's basis file (if it records its
+/// hash), and the size of the updated image against the Delta and the target partition, before
+/// anything is written. The updated image is checked against the Delta's (if it records the
+/// updated file) once it is. Bytes of the target partition past the image are left as they were.
+/// Only Deltas of fixed blocks, computed without normalization or transforms, can be applied this
+/// way.
+///
+/// # Arguments
+/// * `basis` - The partition holding the current image.
+/// * `basis_length` - Length of the current image, in bytes.
+/// * `delta` - Delta representing the changes from the current image to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `output` - The partition to write the updated image to.
+/// * `capacity` - Bytes the target partition holds.
+/// * `on_progress` - Called with the bytes written so far, and the size of the updated image.
+///
+pub fn apply_delta_to_partition<R: Read + Seek, W: Write + Seek>(
+    basis: R,
+    basis_length: u64,
+    delta: &Delta,
+    chunk_size: usize,
+    mut output: W,
+    capacity: u64,
+    on_progress: impl FnMut(u64, u64),
+) -> eyre::Result<PartitionUpdate> {
+    let simulation = simulate_delta(basis_length as usize, delta, chunk_size)?;
+    if !simulation.is_valid() {
+        return Err(eyre!(
+            "Delta references blocks {:?}, which the current image does not have",
+            simulation.out_of_range_indexes
+        ))
+        .suggestion("Did you provide the image the Delta was computed against, and its length?");
+    }
+    let image_size = (simulation.bytes_from_basis + simulation.bytes_from_literals) as u64;
+    let expected = delta.header.updated;
+    if let Some(expected) = expected.filter(|expected| expected.length != image_size) {
+        return Err(eyre!(
+            "Delta recreates an image of {image_size} bytes, but was computed from one of {}",
+            expected.length
+        ));
+    }
+    if image_size > capacity {
+        return Err(eyre!(
+            "Updated image is {image_size} bytes, but the target partition only holds {capacity}"
+        ));
+    }
+
+    // Patching another image would write a wrong one to the target partition.
+    let mut basis = SeekBasis::new(basis, basis_length);
+    check_basis_reader(delta, &mut basis).wrap_err("Could not check the current image")?;
+
+    output
+        .seek(SeekFrom::Start(0))
+        .context("Could not seek to the start of the target partition")?;
+    let mut output = PartitionWriter {
+        inner: output,
+        capacity,
+        image_size,
+        written: 0,
+        on_progress,
+    };
+    // The updated image is checked against the Delta's as it is written.
+    let image = apply_delta_from_reader(&mut basis, delta, chunk_size, capacity, &mut output)
+        .suggestion("The target partition may hold a corrupt image: do not boot from it.")?;
+    output
+        .flush()
+        .context("Could not write the updated image to the target partition")?;
+
+    Ok(PartitionUpdate { image, capacity })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::domain::{compute_delta_to_our_file, compute_signature, BasisLayout};
+
+    const CHUNK_SIZE: usize = 16;
+
+    fn image_delta(basis_image: &[u8], updated_image: &[u8]) -> Delta {
+        let signature = compute_signature(Bytes::copy_from_slice(basis_image), CHUNK_SIZE);
+        let mut delta =
+            compute_delta_to_our_file(signature, Bytes::copy_from_slice(updated_image), CHUNK_SIZE);
+        delta.header.updated = Some(FileDigest::of(updated_image));
+        delta.header.basis = Some(
+            BasisLayout::fixed(basis_image.len() as u64, CHUNK_SIZE).with_strong_hash(basis_image),
+        );
+        delta
+    }
+
+    #[test]
+    fn writes_the_updated_image_at_the_start_of_the_target_partition() {
+        let basis_image: Vec<u8> = (0..200).map(|byte| byte as u8).collect();
+        let updated_image = [&basis_image[..50], b"patched", &basis_image[80..]].concat();
+        let delta = image_delta(&basis_image, &updated_image);
+        // Partitions are larger than the images they hold.
+        let basis_partition = [basis_image.as_slice(), &[0xff; 56]].concat();
+        let mut target_partition = Cursor::new(vec![0xee; 256]);
+        let mut progress = Vec::new();
+
+        let update = apply_delta_to_partition(
+            Cursor::new(basis_partition),
+            basis_image.len() as u64,
+            &delta,
+            CHUNK_SIZE,
+            &mut target_partition,
+            256,
+            |written, total| progress.push((written, total)),
+        )
+        .unwrap();
+
+        let target_partition = target_partition.into_inner();
+        assert_eq!(update.image, FileDigest::of(&updated_image));
+        assert_eq!(&target_partition[..updated_image.len()], updated_image);
+        assert!(target_partition[updated_image.len()..]
+            .iter()
+            .all(|&byte| byte == 0xee));
+        let total = updated_image.len() as u64;
+        assert_eq!(progress.last(), Some(&(total, total)));
+    }
+
+    #[test]
+    fn images_larger_than_the_target_partition_are_not_written() {
+        let basis_image = vec![1; 64];
+        let updated_image = vec![2; 100];
+        let delta = image_delta(&basis_image, &updated_image);
+        let mut target_partition = Cursor::new(vec![0; 96]);
+
+        let result = apply_delta_to_partition(
+            Cursor::new(basis_image),
+            64,
+            &delta,
+            CHUNK_SIZE,
+            &mut target_partition,
+            96,
+            |_, _| {},
+        );
+
+        assert!(result.is_err());
+        assert_eq!(target_partition.into_inner(), vec![0; 96]);
+    }
+
+    #[test]
+    fn images_recreated_from_another_basis_are_refused() {
+        let basis_image: Vec<u8> = (0..128).map(|byte| byte as u8).collect();
+        let updated_image = [&basis_image[..64], b"new tail"].concat();
+        let delta = image_delta(&basis_image, &updated_image);
+        let other_image = vec![0; 128];
+
+        let result = apply_delta_to_partition(
+            Cursor::new(other_image),
+            128,
+            &delta,
+            CHUNK_SIZE,
+            Cursor::new(vec![0; 128]),
+            128,
+            |_, _| {},
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn target_partition_is_left_as_it_was_if_the_current_image_is_another_one() {
+        let basis_image: Vec<u8> = (0..128).map(|byte| byte as u8).collect();
+        // The same blocks in another order recreate an image of the right size, but not the
+        // right one.
+        let other_image = [&basis_image[64..], &basis_image[..64]].concat();
+        let updated_image = [&basis_image[..96], b"new tail"].concat();
+        let delta = image_delta(&basis_image, &updated_image);
+        let mut target_partition = Cursor::new(vec![0xee; 128]);
+
+        let result = apply_delta_to_partition(
+            Cursor::new(other_image),
+            128,
+            &delta,
+            CHUNK_SIZE,
+            &mut target_partition,
+            128,
+            |_, _| {},
+        );
+
+        assert!(result.is_err());
+        assert_eq!(target_partition.into_inner(), vec![0xee; 128]);
+    }
+}
@@ -1,6 +1,162 @@
-use bytes::Bytes;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, IoSlice, Read, Seek, Write};
+use std::ops::Range;
 
-use crate::domain::delta::{Delta, Token};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use eyre::eyre;
+
+use crate::domain::basis_reader::read_exact_at;
+use crate::domain::delta::Delta;
+use crate::domain::{
+    check_chunk_size, normalize_basis_file, restore_normalized_file, BasisLayout, BasisReader,
+    BlockSize, ChunkingMode, FileDigest, FileDigestBuilder,
+};
+use crate::help::Help;
+use crate::{io_utils, profiling, progress};
+
+/// Largest file `apply_delta` recreates before giving up: 16 GiB.
+///
+/// A small Delta can reference the same block billions of times, so without a limit a malicious
+/// Delta could fill up memory (or the disk) while patching.
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+// Largest single read issued by `apply_delta_from_reader`, so long runs of blocks stay bounded.
+const MAX_READ_SIZE: usize = 1024 * 1024;
+
+/// Shortest run of blocks `apply_delta_from_reader` copies inside the kernel: 64 KiB.
+///
+/// Only runs at the same offset in the basis and recreated files are copied this way, as those are
+/// the ones filesystems can share instead of copying. Shorter runs are cheaper to just write.
+pub const MIN_CLONE_SIZE: u64 = 64 * 1024;
+
+/// Where `apply_delta_from_reader` writes the recreated file.
+pub trait PatchOutput: Write {
+    /// Appends `length` bytes of `basis`, starting at `offset`, without moving them through
+    /// userspace. Returns whether it could; if not, the bytes are read and written instead.
+    fn clone_from_basis(&mut self, _basis: &File, _offset: u64, _length: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Appends `length` zero bytes without writing them, leaving a hole in a sparse file. Returns
+    /// whether it could; if not, the zeros are written instead.
+    fn skip_zeros(&mut self, _length: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl PatchOutput for Vec<u8> {}
+
+impl<W: PatchOutput + ?Sized> PatchOutput for &mut W {
+    fn clone_from_basis(&mut self, basis: &File, offset: u64, length: u64) -> io::Result<bool> {
+        (**self).clone_from_basis(basis, offset, length)
+    }
+
+    fn skip_zeros(&mut self, length: u64) -> io::Result<bool> {
+        (**self).skip_zeros(length)
+    }
+}
+
+// The standard output may be a pipe, so blocks are always copied to it.
+impl PatchOutput for BufWriter<io::StdoutLock<'_>> {}
+
+impl PatchOutput for BufWriter<File> {
+    fn clone_from_basis(&mut self, basis: &File, offset: u64, length: u64) -> io::Result<bool> {
+        // Buffered bytes come before the cloned ones.
+        self.flush()?;
+        io_utils::copy_file_range(basis, offset, self.get_ref(), length)
+    }
+
+    fn skip_zeros(&mut self, length: u64) -> io::Result<bool> {
+        self.flush()?;
+        let mut file = self.get_ref();
+        let position = file.stream_position()?;
+        // Only a file ending where we write grows with a hole: other bytes would be left as is.
+        if file.metadata()?.len() != position {
+            return Ok(false);
+        }
+        file.set_len(position + length)?;
+        file.seek(io::SeekFrom::Start(position + length))?;
+        Ok(true)
+    }
+}
+
+/// A PatchOutput which also computes the FileDigest of the recreated file, as it is written.
+pub struct DigestOutput<W> {
+    inner: W,
+    digest: FileDigestBuilder,
+}
+
+impl<W: PatchOutput> DigestOutput<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            digest: FileDigestBuilder::default(),
+        }
+    }
+
+    /// The digest of everything written so far.
+    pub fn digest(&self) -> FileDigest {
+        self.digest.finish()
+    }
+}
+
+impl<W: PatchOutput> Write for DigestOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: PatchOutput> PatchOutput for DigestOutput<W> {
+    fn clone_from_basis(&mut self, basis: &File, offset: u64, length: u64) -> io::Result<bool> {
+        if !self.inner.clone_from_basis(basis, offset, length)? {
+            return Ok(false);
+        }
+        // Cloned bytes never go through `write`, so they are read again to be hashed.
+        let mut buffer = vec![0; length.min(MAX_READ_SIZE as u64) as usize];
+        let mut hashed = 0;
+        while hashed < length {
+            let piece = (length - hashed).min(buffer.len() as u64) as usize;
+            read_exact_at(basis, &mut buffer[..piece], offset + hashed)?;
+            self.digest.update(&buffer[..piece]);
+            hashed += piece as u64;
+        }
+        Ok(true)
+    }
+
+    fn skip_zeros(&mut self, length: u64) -> io::Result<bool> {
+        if !self.inner.skip_zeros(length)? {
+            return Ok(false);
+        }
+        // Skipped bytes never go through `write` either.
+        for_each_zero_piece(length, |zeros| {
+            self.digest.update(zeros);
+            Ok(())
+        })?;
+        Ok(true)
+    }
+}
+
+// Calls `f` with `length` zero bytes, a piece at a time.
+fn for_each_zero_piece(length: u64, mut f: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let zeros = [0; 4096];
+    let mut remaining = length;
+    while remaining > 0 {
+        let piece = remaining.min(zeros.len() as u64) as usize;
+        f(&zeros[..piece])?;
+        remaining -= piece as u64;
+    }
+    Ok(())
+}
 
 /// Applies a Delta to a basis file.
 ///
@@ -13,25 +169,921 @@ use crate::domain::delta::{Delta, Token};
 /// * `delta` - Delta representing the changes from the `basis_file` to the updated one.
 /// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
 ///
-pub fn apply_delta(basis_file: Bytes, delta: Delta, chunk_size: usize) -> Bytes {
-    let blocks: Vec<_> = basis_file.chunks(chunk_size).collect();
+pub fn apply_delta(basis_file: Bytes, delta: Delta, chunk_size: usize) -> eyre::Result<Bytes> {
+    apply_delta_with_mode(
+        basis_file,
+        delta,
+        chunk_size,
+        ChunkingMode::Fixed,
+        DEFAULT_MAX_OUTPUT_SIZE,
+    )
+}
+
+/// Applies a Delta to a basis file, dividing the basis file into blocks with `mode`.
+///
+/// Fails if the basis file is not the one the Delta was computed against (its length or number
+/// of blocks differ), if the Delta references a block the basis file does not have, or as soon as
+/// the recreated file would grow past `max_output_size` bytes.
+///
+/// # Arguments
+/// * `basis_file` - The file to be changed (not in-place).
+/// * `delta` - Delta representing the changes from the `basis_file` to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `mode` - How the files were divided into blocks.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+///
+pub fn apply_delta_with_mode(
+    basis_file: Bytes,
+    delta: Delta,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    profiling::span!("patch");
+    mode.check_chunk_size(chunk_size)?;
+    // The Delta references blocks of the normalized basis file, if it was computed that way.
+    let basis_file = match &delta.header.normalization {
+        Some(normalization) => normalize_basis_file(basis_file, normalization.strip_bom),
+        None => basis_file,
+    };
+    let blocks = mode.split(&basis_file, chunk_size);
+    check_basis_layout(&delta, BasisLayout::of_blocks(&blocks))?;
+    let limit = OutputLimit::of(&delta, max_output_size);
     let mut reconstructed = Vec::new();
 
-    delta.content.iter().for_each(|c| match c {
-        Token::BlockIndex(index) => {
+    for c in delta.content.iter() {
+        for index in c.block_indexes() {
             // We can reuse a block from our file. Nice!
-            reconstructed.extend(blocks.get(*index).unwrap().to_vec());
+            let block = get_block(&blocks, index)?;
+            limit.check(reconstructed.len() + block.len())?;
+            reconstructed.extend_from_slice(block);
         }
-        // This is a new byte, just write it directly.
-        Token::ByteLiteral(byte) => reconstructed.push(*byte),
+        // These are new bytes, just write them directly.
+        let literals = c.literals();
+        limit.check(reconstructed.len() + literals.len())?;
+        reconstructed.extend_from_slice(literals);
+        let length = output_size_after(reconstructed.len(), c.zeros())?;
+        limit.check(length)?;
+        reconstructed.resize(length, 0);
+    }
+    limit.check_complete(reconstructed.len())?;
+
+    let recreated = match &delta.header.normalization {
+        Some(normalization) => restore_normalized_file(reconstructed.into(), normalization),
+        None => Bytes::from(reconstructed),
+    };
+    // Restoring CRLF line endings may still make the file larger.
+    ensure_within_limit(recreated.len(), max_output_size)?;
+
+    Ok(recreated)
+}
+
+/// A recreated file made of slices of the basis file and of the literals of a Delta, in order.
+///
+/// Reading it as a `Buf` goes through the slices one after the other, so blocks reused from the
+/// basis file are never copied.
+#[derive(Debug, Clone, Default)]
+pub struct BytesRope {
+    pieces: VecDeque<Bytes>,
+    remaining: usize,
+}
+
+impl BytesRope {
+    fn push(&mut self, piece: Bytes) {
+        if !piece.is_empty() {
+            self.remaining += piece.len();
+            self.pieces.push_back(piece);
+        }
+    }
+
+    /// Number of slices left to read.
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// The FileDigest of the bytes left to read, without copying them.
+    pub fn digest(&self) -> FileDigest {
+        let mut digest = FileDigestBuilder::default();
+        for piece in &self.pieces {
+            digest.update(piece);
+        }
+        digest.finish()
+    }
+}
+
+impl From<Bytes> for BytesRope {
+    fn from(bytes: Bytes) -> Self {
+        let mut rope = Self::default();
+        rope.push(bytes);
+        rope
+    }
+}
+
+impl Buf for BytesRope {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.pieces.front().map_or(&[], |piece| piece)
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut filled = 0;
+        for (slot, piece) in dst.iter_mut().zip(&self.pieces) {
+            *slot = IoSlice::new(piece);
+            filled += 1;
+        }
+        filled
+    }
+
+    fn advance(&mut self, mut count: usize) {
+        assert!(
+            count <= self.remaining,
+            "Cannot advance {count} bytes, only {} remain",
+            self.remaining
+        );
+        self.remaining -= count;
+        while count > 0 {
+            let front = self
+                .pieces
+                .front_mut()
+                .expect("Remaining bytes are in pieces");
+            if count < front.len() {
+                front.advance(count);
+                break;
+            }
+            count -= front.len();
+            self.pieces.pop_front();
+        }
+    }
+
+    fn copy_to_bytes(&mut self, length: usize) -> Bytes {
+        match self.pieces.front_mut() {
+            // Within a single piece, a slice of it is handed out without copying.
+            Some(front) if length <= front.len() => {
+                let bytes = front.split_to(length);
+                if front.is_empty() {
+                    self.pieces.pop_front();
+                }
+                self.remaining -= length;
+                bytes
+            }
+            _ => {
+                let mut bytes = BytesMut::with_capacity(length);
+                bytes.put((&mut *self).take(length));
+                bytes.freeze()
+            }
+        }
+    }
+}
+
+/// Applies a Delta to a basis file, like `apply_delta_with_mode`, without copying the blocks it
+/// reuses.
+///
+/// The recreated file references the basis file buffer for every run of reused blocks, and only
+/// the literals of the Delta are copied. Deltas of normalized files are recreated as a single
+/// piece, as restoring their line endings rewrites the whole file anyway.
+///
+/// # Arguments
+/// * `basis_file` - The file to be changed (not in-place).
+/// * `delta` - Delta representing the changes from the `basis_file` to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `mode` - How the files were divided into blocks.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+///
+pub fn apply_delta_zero_copy(
+    basis_file: Bytes,
+    delta: Delta,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    max_output_size: u64,
+) -> eyre::Result<BytesRope> {
+    if delta.header.normalization.is_some() {
+        return apply_delta_with_mode(basis_file, delta, chunk_size, mode, max_output_size)
+            .map(BytesRope::from);
+    }
+    mode.check_chunk_size(chunk_size)?;
+    let blocks = mode.split(&basis_file, chunk_size);
+    check_basis_layout(&delta, BasisLayout::of_blocks(&blocks))?;
+    let limit = OutputLimit::of(&delta, max_output_size);
+    let mut recreated = BytesRope::default();
+    let mut recreated_size = 0;
+    // Only one of these is pending at a time: the run of blocks being reused, or the literals.
+    let mut reused = 0..0;
+    let mut literals = Vec::new();
+
+    for c in delta.content.iter() {
+        for index in c.block_indexes() {
+            let block = get_block(&blocks, index)?;
+            recreated_size += block.len();
+            limit.check(recreated_size)?;
+            if !literals.is_empty() {
+                recreated.push(std::mem::take(&mut literals).into());
+            }
+            // Blocks are slices of the basis file, so their offsets follow from their addresses.
+            let start = block.as_ptr() as usize - basis_file.as_ptr() as usize;
+            if reused.is_empty() || reused.end != start {
+                recreated.push(basis_file.slice(reused));
+                reused = start..start;
+            }
+            reused.end += block.len();
+        }
+        let new_literals = c.literals();
+        if !new_literals.is_empty() {
+            recreated_size += new_literals.len();
+            limit.check(recreated_size)?;
+            recreated.push(basis_file.slice(std::mem::replace(&mut reused, 0..0)));
+            literals.extend_from_slice(new_literals);
+        }
+        let zeros = c.zeros();
+        if zeros > 0 {
+            recreated_size = output_size_after(recreated_size, zeros)?;
+            limit.check(recreated_size)?;
+            recreated.push(basis_file.slice(std::mem::replace(&mut reused, 0..0)));
+            literals.resize(literals.len() + zeros, 0);
+        }
+    }
+    limit.check_complete(recreated_size)?;
+    recreated.push(basis_file.slice(reused));
+    recreated.push(literals.into());
+
+    Ok(recreated)
+}
+
+/// Applies a Delta to a basis file read through `basis`, writing the recreated file to `output`
+/// as it goes, and returns its FileDigest.
+///
+/// Only the blocks the Delta references are read, a batch at a time, so the basis file is never
+/// in memory as a whole. Long runs of blocks at the same offset in both files are copied inside
+/// the kernel when both `basis` and `output` are files, falling back to reading them otherwise.
+/// Runs of zeros are left as holes when `output` is a file growing as it is written.
+/// Deltas computed on normalized or transformed files are not supported, as those need the whole
+/// basis file.
+///
+/// The recreated file is hashed as it is written, and checked against the updated file the Delta
+/// records, if any, once it all is. On any error, what was written to `output` must be discarded.
+///
+/// # Arguments
+/// * `basis` - Reads blocks of the file to be changed.
+/// * `delta` - Delta representing the changes from the basis file to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+/// * `output` - Where the recreated file is written.
+///
+pub fn apply_delta_from_reader(
+    basis: &mut dyn BasisReader,
+    delta: &Delta,
+    chunk_size: usize,
+    max_output_size: u64,
+    output: &mut impl PatchOutput,
+) -> eyre::Result<FileDigest> {
+    profiling::span!("patch");
+    if delta.header.normalization.is_some() || delta.header.transform.is_some() {
+        return Err(eyre!(
+            "Deltas of normalized or transformed files need the whole Basis file in memory"
+        ))
+        .suggestion("Patch with `--basis-io memory`.");
+    }
+    check_chunk_size(chunk_size)?;
+    let basis_length = basis.len();
+    let layout = BasisLayout::fixed(basis_length, chunk_size);
+    check_basis_layout(delta, layout)?;
+    let block_count = layout.block_count as usize;
+    let block_size = BlockSize::new(chunk_size);
+    let limit = OutputLimit::of(delta, max_output_size);
+
+    if let Some(index) = delta
+        .content
+        .iter()
+        .map(|token| token.block_indexes().end)
+        .find(|&end| end > block_count)
+    {
+        return Err(eyre!(
+            "Delta references block {}, but the Basis file only has {block_count} blocks",
+            index - 1
+        ));
+    }
+
+    // Consecutive blocks are contiguous in the basis file, so each token needs a single read,
+    // unless it is too large.
+    let mut pieces = delta.content.iter().flat_map(|token| {
+        let indexes = token.block_indexes();
+        let start = basis_length.min(block_size.start_of(indexes.start as u64));
+        let end = basis_length.min(block_size.start_of(indexes.end as u64));
+        (start..end)
+            .step_by(MAX_READ_SIZE)
+            .map(move |offset| Piece::Basis(offset..end.min(offset + MAX_READ_SIZE as u64)))
+            .chain(std::iter::once(Piece::Literals(token.literals())))
+            .chain((token.zeros() > 0).then(|| Piece::Zeros(token.zeros() as u64)))
     });
 
-    Bytes::from(reconstructed)
+    let mut output = DigestOutput::new(output);
+    let mut written = 0;
+    let mut clone_supported = basis.file().is_some();
+    let mut batch = Vec::new();
+    loop {
+        // Pieces are taken until their blocks fill a batch of the size the reader asks for.
+        batch.clear();
+        let mut batch_size = 0;
+        for piece in pieces.by_ref() {
+            if let Piece::Basis(range) = &piece {
+                batch_size += range.end - range.start;
+            }
+            batch.push(piece);
+            if batch_size >= basis.batch_size() {
+                break;
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        // Runs of blocks at the same offset in both files are cloned, without reading them.
+        let mut offset = written;
+        let cloned: Vec<_> = batch
+            .iter()
+            .map(|piece| {
+                let clone = matches!(piece, Piece::Basis(range)
+                    if clone_supported
+                        && range.start == offset
+                        && range.end - range.start >= MIN_CLONE_SIZE);
+                // ZeroRuns too long to be written fail below, once they are reached.
+                offset = offset.saturating_add(piece.len());
+                clone
+            })
+            .collect();
+        let reads: Vec<_> = batch
+            .iter()
+            .zip(&cloned)
+            .filter_map(|(piece, &clone)| match piece {
+                Piece::Basis(range) if !clone => Some(range.clone()),
+                _ => None,
+            })
+            .collect();
+        limit.check((written + batch_size) as usize)?;
+        let mut blocks = basis.read_ranges(&reads)?.into_iter();
+        for (piece, &clone) in batch.iter().zip(&cloned) {
+            let block;
+            let bytes = match piece {
+                Piece::Basis(range) if clone => {
+                    let file = basis.file().expect("Only runs of files are cloned");
+                    let length = range.end - range.start;
+                    if clone_supported && output.clone_from_basis(file, range.start, length)? {
+                        written += length;
+                        progress::advance(length);
+                        continue;
+                    }
+                    // Not supported here, so the rest of the file is read and written instead.
+                    clone_supported = false;
+                    block = basis.read_ranges(std::slice::from_ref(range))?.remove(0);
+                    &block
+                }
+                Piece::Basis(_) => {
+                    block = blocks.next().expect("A read was issued for every block");
+                    &block
+                }
+                Piece::Literals(literals) => *literals,
+                Piece::Zeros(length) => {
+                    let end = written.checked_add(*length).ok_or_else(output_overflow)?;
+                    limit.check(end as usize)?;
+                    if !output.skip_zeros(*length)? {
+                        io::copy(&mut io::repeat(0).take(*length), &mut output)?;
+                    }
+                    written += length;
+                    progress::advance(*length);
+                    continue;
+                }
+            };
+            limit.check(written as usize + bytes.len())?;
+            output.write_all(bytes)?;
+            written += bytes.len() as u64;
+            progress::advance(bytes.len() as u64);
+        }
+    }
+    limit.check_complete(written as usize)?;
+    let recreated = output.digest();
+    if let Some(expected) = delta.header.updated {
+        expected.check_recreated(recreated)?;
+    }
+
+    Ok(recreated)
+}
+
+// A part of the recreated file, as `apply_delta_from_reader` writes it.
+enum Piece<'a> {
+    /// Bytes of the basis file.
+    Basis(Range<u64>),
+    /// Bytes of the Delta.
+    Literals(&'a [u8]),
+    /// A run of zero bytes.
+    Zeros(u64),
+}
+
+impl Piece<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Basis(range) => range.end - range.start,
+            Piece::Literals(literals) => literals.len() as u64,
+            Piece::Zeros(length) => *length,
+        }
+    }
+}
+
+/// Applies a Delta to a basis file which can only be read once, in order (e.g. from a pipe),
+/// writing the recreated file to `output` as it goes, and returns its FileDigest.
+///
+/// Only the last `window_size` bytes of blocks read are kept, so the Delta may only reference
+/// blocks after those, or among them. Deltas of files where content moved further back fail
+/// when they reference a block already dropped, and so do Deltas of normalized or transformed
+/// files. The whole basis file is read before checking it is the one the Delta expects, and the
+/// recreated file, hashed as it is written, is checked against the updated file the Delta records
+/// once it all is, so on any error, what was written to `output` must be discarded.
+///
+/// # Arguments
+/// * `basis` - The file to be changed, read from start to end at most once.
+/// * `delta` - Delta representing the changes from the basis file to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `window_size` - Bytes of basis blocks kept after they were read. At least one block is.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+/// * `output` - Where the recreated file is written.
+///
+pub fn apply_delta_from_sequential_reader(
+    basis: impl Read,
+    delta: &Delta,
+    chunk_size: usize,
+    window_size: u64,
+    max_output_size: u64,
+    output: &mut impl Write,
+) -> eyre::Result<FileDigest> {
+    if delta.header.normalization.is_some() || delta.header.transform.is_some() {
+        return Err(eyre!(
+            "Deltas of normalized or transformed files need the whole Basis file in memory"
+        ));
+    }
+    check_chunk_size(chunk_size)?;
+    let mut window = BlockWindow {
+        basis,
+        chunk_size,
+        capacity: (window_size / chunk_size as u64).max(1) as usize,
+        blocks: VecDeque::new(),
+        first_index: 0,
+        length: 0,
+        last_block_size: None,
+    };
+
+    let limit = OutputLimit::of(delta, max_output_size);
+    // Only the running hash is kept, so the recreated file is checked without holding it.
+    let mut digest = FileDigestBuilder::default();
+    for token in delta.content.iter() {
+        for index in token.block_indexes() {
+            let block = window.block(index)?;
+            limit.check(digest.length() as usize + block.len())?;
+            output.write_all(block)?;
+            digest.update(block);
+        }
+        let literals = token.literals();
+        limit.check(digest.length() as usize + literals.len())?;
+        output.write_all(literals)?;
+        digest.update(literals);
+        let zeros = token.zeros();
+        limit.check(output_size_after(digest.length() as usize, zeros)?)?;
+        for_each_zero_piece(zeros as u64, |piece| {
+            digest.update(piece);
+            output.write_all(piece)
+        })?;
+    }
+    output.flush()?;
+    check_basis_layout(delta, window.finish()?)?;
+    limit.check_complete(digest.length() as usize)?;
+    let recreated = digest.finish();
+    if let Some(expected) = delta.header.updated {
+        expected.check_recreated(recreated)?;
+    }
+
+    Ok(recreated)
+}
+
+// The last blocks read from a basis file which is read once, in order.
+struct BlockWindow<R> {
+    basis: R,
+    chunk_size: usize,
+    /// Blocks kept at most.
+    capacity: usize,
+    blocks: VecDeque<Vec<u8>>,
+    /// Index of the oldest block kept.
+    first_index: usize,
+    /// Bytes read so far.
+    length: u64,
+    /// Size of the last block read, the only one which may be short.
+    last_block_size: Option<u64>,
+}
+
+impl<R: Read> BlockWindow<R> {
+    fn block(&mut self, index: usize) -> eyre::Result<&[u8]> {
+        if index < self.first_index {
+            return Err(eyre!(
+                "Delta references block {index}, but only blocks from {} on are still kept, as \
+                 the Basis file can only be read once",
+                self.first_index
+            ))
+            .suggestion("Patch from a regular file, or keep more blocks with a larger window.");
+        }
+        while self.first_index + self.blocks.len() <= index {
+            let read_blocks = self.first_index + self.blocks.len();
+            let Some(block) = self.read_block()? else {
+                return Err(eyre!(
+                    "Delta references block {index}, but the Basis file only has {read_blocks} \
+                     blocks"
+                ));
+            };
+            self.blocks.push_back(block);
+            if self.blocks.len() > self.capacity {
+                self.blocks.pop_front();
+                self.first_index += 1;
+            }
+        }
+
+        Ok(&self.blocks[index - self.first_index])
+    }
+
+    fn read_block(&mut self) -> eyre::Result<Option<Vec<u8>>> {
+        let mut block = Vec::with_capacity(self.chunk_size);
+        (&mut self.basis)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            return Ok(None);
+        }
+        self.length += block.len() as u64;
+        self.last_block_size = Some(block.len() as u64);
+        Ok(Some(block))
+    }
+
+    // Reads the rest of the basis file, to tell how it was laid out.
+    fn finish(mut self) -> eyre::Result<BasisLayout> {
+        let mut block_count = self.first_index + self.blocks.len();
+        self.blocks.clear();
+        while self.read_block()?.is_some() {
+            block_count += 1;
+        }
+
+        Ok(BasisLayout {
+            length: self.length,
+            block_count: block_count as u64,
+            last_block_size: self.last_block_size,
+            strong_hash: None,
+        })
+    }
+}
+
+/// Applies a Delta to a basis file, reconstructing only a window of the updated file.
+///
+/// Equivalent to `apply_delta(basis_file, delta, chunk_size).slice(range)`, but only the bytes
+/// inside `range` are ever copied. If `range` goes past the end of the updated file, the result is
+/// truncated to the bytes that do exist.
+///
+/// # Arguments
+/// * `basis_file` - The file to be changed (not in-place).
+/// * `delta` - Delta representing the changes from the `basis_file` to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `range` - The byte window of the updated file to reconstruct.
+/// * `max_output_size` - The largest recreated window allowed, in bytes.
+///
+pub fn apply_delta_range(
+    basis_file: Bytes,
+    delta: Delta,
+    chunk_size: usize,
+    range: Range<usize>,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    if delta.header.normalization.is_some() {
+        // Offsets only make sense after the normalization is reversed.
+        let recreated = apply_delta_with_mode(
+            basis_file,
+            delta,
+            chunk_size,
+            ChunkingMode::Fixed,
+            max_output_size,
+        )?;
+        let end = range.end.min(recreated.len());
+        return Ok(recreated.slice(range.start.min(end)..end));
+    }
+
+    check_chunk_size(chunk_size)?;
+    let blocks: Vec<_> = basis_file.chunks(chunk_size).collect();
+    check_basis_layout(&delta, BasisLayout::of_blocks(&blocks))?;
+    let mut reconstructed = Vec::with_capacity(range.len());
+
+    // Offset (in the updated file) of the token we are currently looking at.
+    let mut offset = 0;
+    for c in delta.content.iter() {
+        for index in c.block_indexes() {
+            if offset >= range.end {
+                // Everything after this point is outside the window.
+                break;
+            }
+            let block = get_block(&blocks, index)?;
+            offset = copy_overlap(&mut reconstructed, block, offset, &range, max_output_size)?;
+        }
+        if offset >= range.end {
+            break;
+        }
+        offset = copy_overlap(
+            &mut reconstructed,
+            c.literals(),
+            offset,
+            &range,
+            max_output_size,
+        )?;
+        let zeros = offset..output_size_after(offset, c.zeros())?;
+        let overlap = range.start.max(zeros.start)..range.end.min(zeros.end);
+        if !overlap.is_empty() {
+            ensure_within_limit(reconstructed.len() + overlap.len(), max_output_size)?;
+            reconstructed.resize(reconstructed.len() + overlap.len(), 0);
+        }
+        offset = zeros.end;
+    }
+
+    Ok(Bytes::from(reconstructed))
+}
+
+// Copies the part of `content`, found at `offset` of the updated file, which overlaps `range`.
+// Returns the offset right after `content`.
+fn copy_overlap(
+    reconstructed: &mut Vec<u8>,
+    content: &[u8],
+    offset: usize,
+    range: &Range<usize>,
+    max_output_size: u64,
+) -> eyre::Result<usize> {
+    let content_range = offset..offset + content.len();
+    let start = range.start.max(content_range.start);
+    let end = range.end.min(content_range.end);
+    if start < end {
+        ensure_within_limit(reconstructed.len() + end - start, max_output_size)?;
+        reconstructed.extend_from_slice(&content[start - offset..end - offset]);
+    }
+
+    Ok(content_range.end)
+}
+
+// Deltas computed from a whole Signature know the length and blocks of the basis file they expect.
+fn check_basis_layout(delta: &Delta, actual: BasisLayout) -> eyre::Result<()> {
+    let Some(expected) = delta.header.basis else {
+        return Ok(());
+    };
+
+    if expected.length != actual.length {
+        return Err(eyre!(
+            "Basis file is {actual}, but the Delta was computed against {expected}"
+        ))
+        .suggestion("Did you provide the same Basis file used to compute the Signature?");
+    }
+    let same_last_block =
+        expected.last_block_size.is_none() || expected.last_block_size == actual.last_block_size;
+    if expected.block_count != actual.block_count || !same_last_block {
+        return Err(eyre!(
+            "Basis file divided with these options is {actual}, but the Delta was computed \
+             against {expected}"
+        ))
+        .suggestion("Use the same `--chunk-size` and `--mode` used to compute the Signature.");
+    }
+
+    Ok(())
+}
+
+/// Checks `basis_file` is the one the Delta was computed against, comparing its strong hash with
+/// the one the Signature recorded.
+///
+/// A basis file of the right length and blocks, but different content, would otherwise be patched
+/// into a wrong file. Deltas computed from Signatures which do not record the hash (older ones,
+/// or those of a region) are accepted as they are.
+pub fn check_basis_file(delta: &Delta, basis_file: &Bytes) -> eyre::Result<()> {
+    let Some(expected) = delta.header.basis.and_then(|basis| basis.strong_hash) else {
+        return Ok(());
+    };
+    // The Signature was computed on the normalized basis file, if the Delta was computed that way.
+    let basis_file = match &delta.header.normalization {
+        Some(normalization) => normalize_basis_file(basis_file.clone(), normalization.strip_bom),
+        None => basis_file.clone(),
+    };
+
+    check_basis_hash(expected, FileDigest::of(&basis_file))
+}
+
+/// Same as `check_basis_file`, reading the whole basis file through `basis`.
+pub fn check_basis_reader(delta: &Delta, basis: &mut dyn BasisReader) -> eyre::Result<()> {
+    let Some(expected) = delta.header.basis.and_then(|basis| basis.strong_hash) else {
+        return Ok(());
+    };
+    let length = basis.len();
+    let mut digest = FileDigestBuilder::default();
+    for start in (0..length).step_by(MAX_READ_SIZE) {
+        let end = length.min(start + MAX_READ_SIZE as u64);
+        // A single range is read, not the indexes from `start` to `end`.
+        #[allow(clippy::single_range_in_vec_init)]
+        let ranges = [start..end];
+        for piece in basis.read_ranges(&ranges)? {
+            digest.update(&piece);
+        }
+    }
+
+    check_basis_hash(expected, digest.finish())
+}
+
+fn check_basis_hash(expected: u64, actual: FileDigest) -> eyre::Result<()> {
+    if actual.strong_hash != expected {
+        return Err(eyre!(
+            "Basis file ({actual}) is not the one the Delta was computed against \
+             (hash {expected:016x})"
+        ))
+        .suggestion("Did you provide the same Basis file used to compute the Signature?");
+    }
+
+    Ok(())
+}
+
+fn get_block<'a>(blocks: &[&'a [u8]], index: usize) -> eyre::Result<&'a [u8]> {
+    blocks.get(index).copied().ok_or_else(|| {
+        eyre!(
+            "Delta references block {index}, but the Basis file only has {} blocks",
+            blocks.len()
+        )
+    })
+}
+
+fn ensure_within_limit(output_size: usize, max_output_size: u64) -> eyre::Result<()> {
+    if output_size as u64 > max_output_size {
+        return Err(eyre!(
+            "Recreated file is larger than the maximum output size ({max_output_size} bytes)"
+        ))
+        .suggestion("If the Delta is trusted, raise the limit with `--max-output-size`");
+    }
+
+    Ok(())
+}
+
+// Bytes of the recreated file once `added` more are written, failing on ZeroRuns too long to be
+// counted, before any limit is checked, rather than wrapping around to a small size.
+fn output_size_after(written: usize, added: usize) -> eyre::Result<usize> {
+    written.checked_add(added).ok_or_else(output_overflow)
+}
+
+fn output_overflow() -> eyre::Report {
+    eyre!("Delta writes more bytes than a file can hold")
+        .suggestion("The Delta is damaged. Compute it again.")
+}
+
+// How many bytes applying a Delta may write: no more than the maximum output size, and exactly
+// the output length its header records, if it does. Damaged Deltas writing too much fail as soon
+// as they do, and those writing too little once every token was applied, instead of silently
+// recreating a truncated file.
+struct OutputLimit {
+    max_output_size: u64,
+    output_length: Option<u64>,
+}
+
+impl OutputLimit {
+    fn of(delta: &Delta, max_output_size: u64) -> Self {
+        Self {
+            max_output_size,
+            output_length: delta.header.output_length,
+        }
+    }
+
+    // Fails if writing up to `output_size` bytes is not allowed.
+    fn check(&self, output_size: usize) -> eyre::Result<()> {
+        ensure_within_limit(output_size, self.max_output_size)?;
+        match self.output_length {
+            Some(output_length) if output_size as u64 > output_length => Err(eyre!(
+                "Delta writes more than the {output_length} bytes its header records"
+            ))
+            .suggestion("The Delta is damaged. Compute it again."),
+            _ => Ok(()),
+        }
+    }
+
+    // Fails unless `output_size` bytes, written by every token, are what the header records.
+    fn check_complete(&self, output_size: usize) -> eyre::Result<()> {
+        match self.output_length {
+            Some(output_length) if output_size as u64 != output_length => Err(eyre!(
+                "Delta wrote {output_size} bytes, but its header records {output_length}"
+            ))
+            .suggestion("The Delta is damaged or was cut short. Compute it again."),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Describes what applying a Delta would do, without reconstructing anything.
+///
+/// Useful as a safety check before applying deltas from untrusted sources.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PatchSimulation {
+    /// Bytes that would be copied from blocks of the basis file.
+    pub bytes_from_basis: usize,
+    /// Bytes that would be written directly, from ByteLiterals and ZeroRuns.
+    pub bytes_from_literals: usize,
+    /// Byte ranges of the basis file which are referenced at least once, in order.
+    pub reused_regions: Vec<Range<usize>>,
+    /// Block indexes referenced by the Delta which do not exist in the basis file.
+    pub out_of_range_indexes: Vec<usize>,
+}
+
+impl PatchSimulation {
+    pub fn is_valid(&self) -> bool {
+        self.out_of_range_indexes.is_empty()
+    }
+}
+
+impl fmt::Display for PatchSimulation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let total = self.bytes_from_basis + self.bytes_from_literals;
+        writeln!(f, "Bytes in recreated file: {total}")?;
+        writeln!(f, "Bytes reused from basis file: {}", self.bytes_from_basis)?;
+        writeln!(f, "Bytes from literals: {}", self.bytes_from_literals)?;
+        writeln!(f, "Reused basis regions: {}", self.reused_regions.len())?;
+        for region in &self.reused_regions {
+            writeln!(f, "    [{}..{})", region.start, region.end)?;
+        }
+        if self.is_valid() {
+            write!(f, "All block indexes are within the basis file.")
+        } else {
+            write!(
+                f,
+                "Out of range block indexes: {:?}",
+                self.out_of_range_indexes
+            )
+        }
+    }
+}
+
+/// Simulates applying a Delta to a basis file of a given size.
+///
+/// Walks the Delta and reports where each byte of the recreated file would come from,
+/// without reading the basis file or writing any output. Fails if the Delta writes more bytes
+/// than can be counted.
+///
+/// # Arguments
+/// * `basis_file_size` - The size in bytes of the file the Delta would be applied to.
+/// * `delta` - Delta representing the changes from the basis file to the updated one.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+///
+pub fn simulate_delta(
+    basis_file_size: usize,
+    delta: &Delta,
+    chunk_size: usize,
+) -> eyre::Result<PatchSimulation> {
+    // Without valid blocks, every index is out of range.
+    let number_of_blocks = match check_chunk_size(chunk_size) {
+        Ok(()) => basis_file_size.div_ceil(chunk_size),
+        Err(_) => 0,
+    };
+    let block_range = |index: usize| {
+        let start = index * chunk_size;
+        start..basis_file_size.min(start.saturating_add(chunk_size))
+    };
+
+    let mut simulation = PatchSimulation::default();
+    let mut reused_blocks = BTreeSet::new();
+    let mut out_of_range_blocks = BTreeSet::new();
+    for c in delta.content.iter() {
+        let indexes = c.block_indexes();
+        for index in indexes.start..indexes.end.min(number_of_blocks) {
+            simulation.bytes_from_basis =
+                output_size_after(simulation.bytes_from_basis, block_range(index).len())?;
+            reused_blocks.insert(index);
+        }
+        // A BlockRange may be huge, so only its first out of range index is reported.
+        if indexes.end > number_of_blocks {
+            out_of_range_blocks.insert(indexes.start.max(number_of_blocks));
+        }
+        let written = output_size_after(c.literals().len(), c.zeros())?;
+        simulation.bytes_from_literals =
+            output_size_after(simulation.bytes_from_literals, written)?;
+    }
+    // The size of the recreated file is their sum.
+    output_size_after(simulation.bytes_from_basis, simulation.bytes_from_literals)?;
+
+    // Adjacent blocks are merged into a single region, so the report stays readable.
+    for index in reused_blocks {
+        let range = block_range(index);
+        match simulation.reused_regions.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => simulation.reused_regions.push(range),
+        }
+    }
+    simulation.out_of_range_indexes = out_of_range_blocks.into_iter().collect();
+
+    Ok(simulation)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::delta::{Delta, Token};
+    use crate::domain::delta::{compute_delta_to_our_file, Delta, Token};
+    use crate::domain::{compute_signature, normalize_updated_file, PositionedBasis};
+    use crate::test_utils::scratch_directory;
 
     use super::*;
 
@@ -47,11 +1099,14 @@ mod tests {
             let mut content = Vec::new();
             content.extend(create_byte_literals(b"abc"));
             content.extend(create_byte_literals(b"def"));
-            Delta { content }
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
         };
 
         let empty_file = Bytes::new();
-        let reconstructed = apply_delta(empty_file, delta, test_chunk_size);
+        let reconstructed = apply_delta(empty_file, delta, test_chunk_size).unwrap();
 
         assert_eq!(reconstructed, Bytes::from("abcdef"));
     }
@@ -67,10 +1122,12 @@ mod tests {
                 Token::BlockIndex(2),
                 Token::BlockIndex(1),
                 Token::BlockIndex(0),
-            ],
+            ]
+            .into(),
+            ..Default::default()
         };
 
-        let reconstructed = apply_delta(basis_file, delta, test_chunk_size);
+        let reconstructed = apply_delta(basis_file, delta, test_chunk_size).unwrap();
 
         assert_eq!(reconstructed, Bytes::from("block2 block3 block2 block1 "));
     }
@@ -86,11 +1143,509 @@ mod tests {
             content.extend(create_byte_literals(b"abc"));
             content.push(Token::BlockIndex(0));
             content.extend(create_byte_literals(b"abc"));
-            Delta { content }
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
         };
 
-        let reconstructed = apply_delta(basis_file, delta, test_chunk_size);
+        let reconstructed = apply_delta(basis_file, delta, test_chunk_size).unwrap();
 
         assert_eq!(reconstructed, Bytes::from("abcblock1 abc"));
     }
+
+    #[test]
+    fn output_larger_than_the_limit_is_an_error() {
+        let test_chunk_size = 7;
+
+        let basis_file = Bytes::from("block1 ");
+        let delta = Delta {
+            content: vec![Token::BlockIndex(0); 3].into(),
+            ..Default::default()
+        };
+
+        let apply_with_limit = |max_output_size| {
+            apply_delta_with_mode(
+                basis_file.clone(),
+                delta.clone(),
+                test_chunk_size,
+                ChunkingMode::Fixed,
+                max_output_size,
+            )
+        };
+
+        assert!(apply_with_limit(20).is_err());
+        assert_eq!(apply_with_limit(21).unwrap().len(), 21);
+        assert!(apply_delta_range(
+            basis_file.clone(),
+            delta.clone(),
+            test_chunk_size,
+            0..21,
+            10
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn out_of_range_block_index_is_an_error() {
+        let basis_file = Bytes::from("block1 ");
+        let delta = Delta {
+            content: vec![Token::BlockIndex(1)].into(),
+            ..Default::default()
+        };
+
+        assert!(apply_delta(basis_file, delta, 7).is_err());
+    }
+
+    #[test]
+    fn basis_file_or_chunk_size_other_than_the_signatures_is_an_error() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("ABCDEFGHIJKL");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let delta = compute_delta_to_our_file(signature, Bytes::from("EFGHxABCD"), test_chunk_size);
+
+        let other_basis_file = Bytes::from("ABCDEFGHIJK");
+        assert!(apply_delta(other_basis_file, delta.clone(), test_chunk_size).is_err());
+        assert!(apply_delta(basis_file.clone(), delta.clone(), 3).is_err());
+        assert_eq!(
+            apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            Bytes::from("EFGHxABCD")
+        );
+    }
+
+    #[test]
+    fn simulation_reports_reused_regions_and_literals() {
+        let test_chunk_size = 7;
+
+        // 3 blocks: "block1 ", "block2 ", "end".
+        let basis_file_size = "block1 block2 end".len();
+        let delta = {
+            let mut content = create_byte_literals(b"abc");
+            content.push(Token::BlockIndex(0));
+            content.push(Token::BlockIndex(1));
+            content.push(Token::BlockIndex(2));
+            content.push(Token::BlockIndex(0));
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
+        };
+
+        let simulation = simulate_delta(basis_file_size, &delta, test_chunk_size).unwrap();
+
+        assert_eq!(simulation.bytes_from_literals, 3);
+        assert_eq!(simulation.bytes_from_basis, 7 + 7 + 3 + 7);
+        assert_eq!(simulation.reused_regions, vec![0..basis_file_size]);
+        assert!(simulation.is_valid());
+    }
+
+    #[test]
+    fn simulation_detects_out_of_range_block_indexes() {
+        let test_chunk_size = 7;
+
+        let basis_file_size = "block1 block2 ".len();
+        let delta = Delta {
+            content: vec![
+                Token::BlockIndex(1),
+                Token::BlockIndex(5),
+                Token::BlockIndex(2),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let simulation = simulate_delta(basis_file_size, &delta, test_chunk_size).unwrap();
+
+        assert_eq!(simulation.reused_regions, vec![7..14]);
+        assert_eq!(simulation.out_of_range_indexes, vec![2, 5]);
+        assert!(!simulation.is_valid());
+    }
+
+    #[test]
+    fn range_reconstruction_matches_slice_of_full_reconstruction() {
+        let test_chunk_size = 7;
+
+        let basis_file = Bytes::from("block1 block2 ");
+        let delta = {
+            let mut content = create_byte_literals(b"abc");
+            content.push(Token::BlockIndex(1));
+            content.extend(create_byte_literals(b"def"));
+            content.push(Token::BlockIndex(0));
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
+        };
+
+        let full = apply_delta(basis_file.clone(), delta.clone(), test_chunk_size).unwrap();
+        for range in [0..3, 2..5, 4..12, 9..20, 0..full.len(), 30..40] {
+            let partial = apply_delta_range(
+                basis_file.clone(),
+                delta.clone(),
+                test_chunk_size,
+                range.clone(),
+                DEFAULT_MAX_OUTPUT_SIZE,
+            )
+            .unwrap();
+
+            let expected = full.slice(range.start.min(full.len())..range.end.min(full.len()));
+            assert_eq!(partial, expected);
+        }
+    }
+
+    #[test]
+    fn normalized_delta_restores_original_line_endings() {
+        let test_chunk_size = 4;
+
+        let basis_file = Bytes::from("line\r\nline\r\n");
+        let (updated_file, normalization) =
+            normalize_updated_file(Bytes::from("line\r\nline\r\nnew\r\n"), false);
+        let signature = compute_signature(
+            normalize_basis_file(basis_file.clone(), false),
+            test_chunk_size,
+        );
+        let mut delta = compute_delta_to_our_file(signature, updated_file, test_chunk_size);
+        delta.header.normalization = Some(normalization);
+
+        let reconstructed = apply_delta(basis_file, delta, test_chunk_size).unwrap();
+
+        assert_eq!(reconstructed, Bytes::from("line\r\nline\r\nnew\r\n"));
+    }
+
+    #[test]
+    fn applying_from_a_reader_matches_applying_in_memory() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCCDD");
+        let updated_file = Bytes::from("CCCCDDxAAAABBBBCCCC");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut delta =
+            compute_delta_to_our_file(signature.clone(), updated_file.clone(), test_chunk_size);
+        delta.optimize_against(&signature);
+
+        let mut recreated = Vec::new();
+        let digest = apply_delta_from_reader(
+            &mut basis_file.clone(),
+            &delta,
+            test_chunk_size,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut recreated,
+        )
+        .unwrap();
+
+        assert_eq!(recreated, updated_file);
+        assert_eq!(digest, FileDigest::of(&updated_file));
+        assert_eq!(
+            apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn streamed_files_are_checked_against_the_updated_file() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCCDD");
+        let updated_file = Bytes::from("CCCCDDxAAAABBBB");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut delta = compute_delta_to_our_file(signature, updated_file.clone(), test_chunk_size);
+        delta.header.updated = Some(FileDigest::of(&updated_file));
+        assert!(apply_delta_from_reader(
+            &mut basis_file.clone(),
+            &delta,
+            test_chunk_size,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut Vec::new(),
+        )
+        .is_ok());
+
+        // A damaged Delta, recreating another file than the one it was computed from.
+        delta.header.updated = Some(FileDigest::of(b"CCCCDDyAAAABBBB"));
+        assert!(apply_delta_from_reader(
+            &mut basis_file.clone(),
+            &delta,
+            test_chunk_size,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut Vec::new(),
+        )
+        .is_err());
+        assert!(apply_delta_from_sequential_reader(
+            &basis_file[..],
+            &delta,
+            test_chunk_size,
+            u64::MAX,
+            u64::MAX,
+            &mut Vec::new(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sequential_basis_only_keeps_a_window_of_blocks() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCCDDDDEEEE");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let in_order = Bytes::from("AAAAxBBBBDDDDEEEEEE");
+        let delta = compute_delta_to_our_file(signature.clone(), in_order.clone(), test_chunk_size);
+
+        let mut recreated = Vec::new();
+        let digest = apply_delta_from_sequential_reader(
+            &basis_file[..],
+            &delta,
+            test_chunk_size,
+            0,
+            u64::MAX,
+            &mut recreated,
+        )
+        .unwrap();
+        assert_eq!(recreated, in_order);
+        assert_eq!(digest, FileDigest::of(&in_order));
+
+        let moved_back = Bytes::from("DDDDAAAA");
+        let delta = compute_delta_to_our_file(signature, moved_back.clone(), test_chunk_size);
+        let apply_with_window = |window_size| {
+            apply_delta_from_sequential_reader(
+                &basis_file[..],
+                &delta,
+                test_chunk_size,
+                window_size,
+                u64::MAX,
+                &mut Vec::new(),
+            )
+        };
+        assert!(apply_with_window(8).is_err());
+        assert_eq!(
+            apply_with_window(16).unwrap().length,
+            moved_back.len() as u64
+        );
+        let error = apply_delta_from_sequential_reader(
+            &basis_file[..12],
+            &delta,
+            test_chunk_size,
+            16,
+            u64::MAX,
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{error}").contains("only has 3 blocks"));
+    }
+
+    #[test]
+    fn runs_at_the_same_offset_are_copied_between_files() {
+        let test_chunk_size = 1024;
+        let basis_file = Bytes::from((0..=255).cycle().take(200_000).collect::<Vec<u8>>());
+        let mut updated_file = basis_file[..150_000].to_vec();
+        updated_file.extend_from_slice(b"a few new bytes");
+        let updated_file = Bytes::from(updated_file);
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let mut delta =
+            compute_delta_to_our_file(signature.clone(), updated_file.clone(), test_chunk_size);
+        delta.optimize_against(&signature);
+
+        let directory = scratch_directory("patch_clone");
+        let basis_path = directory.join("basis");
+        let recreated_path = directory.join("recreated");
+        std::fs::write(&basis_path, &basis_file).unwrap();
+        let mut basis = PositionedBasis::open(&basis_path).unwrap();
+        let mut output = BufWriter::new(File::create(&recreated_path).unwrap());
+        let digest = apply_delta_from_reader(
+            &mut basis,
+            &delta,
+            test_chunk_size,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut output,
+        )
+        .unwrap();
+        output.flush().unwrap();
+
+        // Cloned blocks are hashed too.
+        assert_eq!(digest, FileDigest::of(&updated_file));
+        assert_eq!(std::fs::read(&recreated_path).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn zero_runs_are_recreated_by_every_patch() {
+        let basis_file = Bytes::from("AAAA");
+        let delta = Delta {
+            content: vec![
+                Token::BlockIndex(0),
+                Token::ZeroRun(100_000),
+                Token::ByteLiteral(b'x'),
+                Token::ZeroRun(5),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let updated_file = [b"AAAA".as_slice(), &[0; 100_000], b"x", &[0; 5]].concat();
+
+        let recreated = apply_delta(basis_file.clone(), delta.clone(), 4).unwrap();
+        assert_eq!(recreated, updated_file);
+        let mut rope = apply_delta_zero_copy(
+            basis_file.clone(),
+            delta.clone(),
+            4,
+            ChunkingMode::Fixed,
+            DEFAULT_MAX_OUTPUT_SIZE,
+        )
+        .unwrap();
+        assert_eq!(rope.copy_to_bytes(rope.remaining()), updated_file);
+        let window = apply_delta_range(
+            basis_file.clone(),
+            delta.clone(),
+            4,
+            99_990..100_010,
+            DEFAULT_MAX_OUTPUT_SIZE,
+        )
+        .unwrap();
+        assert_eq!(window, updated_file[99_990..100_010]);
+
+        // Zeros are left as holes in the recreated file, and hashed all the same.
+        let recreated_path = scratch_directory("patch_zeros").join("recreated");
+        let mut output = BufWriter::new(File::create(&recreated_path).unwrap());
+        let digest = apply_delta_from_reader(
+            &mut basis_file.clone(),
+            &delta,
+            4,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut output,
+        )
+        .unwrap();
+        output.flush().unwrap();
+        assert_eq!(digest, FileDigest::of(&updated_file));
+        assert_eq!(std::fs::read(&recreated_path).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn zero_runs_too_long_to_count_are_refused() {
+        let basis_file = Bytes::from("AAAA");
+        let delta = Delta {
+            content: vec![Token::BlockIndex(0), Token::ZeroRun(usize::MAX)].into(),
+            ..Default::default()
+        };
+        let refused = |result: eyre::Result<()>| {
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains("more bytes than a file can hold"), "{error}");
+        };
+
+        // No limit would be reached if the sizes wrapped around.
+        refused(
+            apply_delta_with_mode(
+                basis_file.clone(),
+                delta.clone(),
+                4,
+                ChunkingMode::Fixed,
+                u64::MAX,
+            )
+            .map(drop),
+        );
+        refused(
+            apply_delta_zero_copy(
+                basis_file.clone(),
+                delta.clone(),
+                4,
+                ChunkingMode::Fixed,
+                u64::MAX,
+            )
+            .map(drop),
+        );
+        refused(apply_delta_range(basis_file.clone(), delta.clone(), 4, 8..16, u64::MAX).map(drop));
+        refused(
+            apply_delta_from_reader(
+                &mut basis_file.clone(),
+                &delta,
+                4,
+                u64::MAX,
+                &mut Vec::new(),
+            )
+            .map(drop),
+        );
+        refused(simulate_delta(basis_file.len(), &delta, 4).map(drop));
+    }
+
+    #[test]
+    fn deltas_not_writing_their_output_length_are_refused() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCC");
+        let updated_file = Bytes::from("AAAAxyCCCC");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let delta = compute_delta_to_our_file(signature, updated_file.clone(), test_chunk_size);
+        assert_eq!(delta.header.output_length, Some(10));
+
+        let mut truncated = delta.clone();
+        truncated.header.output_length = Some(12);
+        let mut extended = delta.clone();
+        extended.header.output_length = Some(8);
+        for damaged in [truncated, extended] {
+            assert!(apply_delta(basis_file.clone(), damaged.clone(), test_chunk_size).is_err());
+            assert!(apply_delta_zero_copy(
+                basis_file.clone(),
+                damaged.clone(),
+                test_chunk_size,
+                ChunkingMode::Fixed,
+                DEFAULT_MAX_OUTPUT_SIZE,
+            )
+            .is_err());
+            let mut output = Vec::new();
+            assert!(apply_delta_from_sequential_reader(
+                &basis_file[..],
+                &damaged,
+                test_chunk_size,
+                u64::MAX,
+                u64::MAX,
+                &mut output,
+            )
+            .is_err());
+        }
+        // Writing more fails as soon as the recreated file grows past it.
+        let mut output = Vec::new();
+        let mut extended = delta.clone();
+        extended.header.output_length = Some(4);
+        assert!(apply_delta_from_reader(
+            &mut basis_file.clone(),
+            &extended,
+            test_chunk_size,
+            DEFAULT_MAX_OUTPUT_SIZE,
+            &mut output,
+        )
+        .is_err());
+        assert!(output.len() <= 4);
+
+        assert_eq!(
+            apply_delta(basis_file, delta, test_chunk_size).unwrap(),
+            updated_file
+        );
+    }
+
+    #[test]
+    fn zero_copy_apply_references_the_basis_file() {
+        let test_chunk_size = 4;
+        let basis_file = Bytes::from("AAAABBBBCCCCDD");
+        let updated_file = Bytes::from("AAAABBBBxyCCCC");
+        let signature = compute_signature(basis_file.clone(), test_chunk_size);
+        let delta = compute_delta_to_our_file(signature, updated_file.clone(), test_chunk_size);
+
+        let mut recreated = apply_delta_zero_copy(
+            basis_file.clone(),
+            delta,
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            DEFAULT_MAX_OUTPUT_SIZE,
+        )
+        .unwrap();
+
+        // Consecutive blocks are a single slice, and literals are another.
+        assert_eq!(recreated.piece_count(), 3);
+        assert_eq!(recreated.chunk().as_ptr(), basis_file.as_ptr());
+        assert_eq!(recreated.copy_to_bytes(recreated.remaining()), updated_file);
+    }
+
+    #[test]
+    fn rope_reads_across_pieces() {
+        let mut rope = BytesRope::from(Bytes::from("abc"));
+        rope.push(Bytes::from("defg"));
+
+        rope.advance(2);
+        assert_eq!(rope.copy_to_bytes(1), Bytes::from("c"));
+        assert_eq!(rope.piece_count(), 1);
+        assert_eq!(rope.copy_to_bytes(4), Bytes::from("defg"));
+        assert!(!rope.has_remaining());
+    }
 }
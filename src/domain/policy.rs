@@ -0,0 +1,178 @@
+use std::str::FromStr;
+
+use eyre::eyre;
+
+use crate::domain::{check_chunk_size, ChunkingMode};
+use crate::help::Help;
+
+/// How the files whose path matches `pattern` are synced, overriding the options given for the
+/// whole run. Settings left out keep those options.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FilePolicy {
+    /// A glob: `*` and `?` match within a single component, and patterns without a `/` only match
+    /// the name of the file, in any directory.
+    pub pattern: String,
+    pub chunk_size: Option<usize>,
+    pub mode: Option<ChunkingMode>,
+    /// Whether the file is deflated if sent whole.
+    pub compress: Option<bool>,
+}
+
+/// Policies for files of different kinds, e.g. large blocks for disk images and lines for CSV
+/// files, which are applied automatically in directory mode.
+///
+/// Each policy is a line: a pattern, then `chunk-size=N`, `mode=fixed|lines` or
+/// `compress=yes|no` settings, separated by spaces. Empty lines and lines starting with `#` are
+/// skipped. For each file, the first policy whose pattern matches applies.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct FilePolicies {
+    policies: Vec<FilePolicy>,
+}
+
+impl FilePolicies {
+    /// The policy applying to the file at `path`, relative to the root of the tree with `/` as
+    /// separator, if any.
+    pub fn for_path(&self, path: &str) -> Option<&FilePolicy> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.policies
+            .iter()
+            .find(|policy| match policy.pattern.contains('/') {
+                true => matches_glob(policy.pattern.as_bytes(), path.as_bytes()),
+                false => matches_glob(policy.pattern.as_bytes(), name.as_bytes()),
+            })
+    }
+
+    /// Whether any policy chooses how files are divided into blocks.
+    pub fn set_chunking(&self) -> bool {
+        self.policies
+            .iter()
+            .any(|policy| policy.chunk_size.is_some() || policy.mode.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Adds the policies of `other` after these, so these are tried first.
+    pub fn extend(&mut self, other: FilePolicies) {
+        self.policies.extend(other.policies);
+    }
+}
+
+impl FromStr for FilePolicies {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policies = s
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+            .map(|(number, line)| {
+                parse_policy(line).map_err(|error| {
+                    eyre!(
+                        r#"Line {} of the policies is "{line}": {error}"#,
+                        number + 1
+                    )
+                })
+            })
+            .collect::<eyre::Result<Vec<_>>>()
+            .suggestion(
+                "Write a pattern, then `chunk-size=N`, `mode=fixed|lines` or `compress=yes|no`.",
+            )?;
+
+        Ok(Self { policies })
+    }
+}
+
+fn parse_policy(line: &str) -> Result<FilePolicy, String> {
+    let mut words = line.split_whitespace();
+    let pattern = words.next().ok_or("no pattern")?;
+    let mut policy = FilePolicy {
+        pattern: pattern.to_string(),
+        chunk_size: None,
+        mode: None,
+        compress: None,
+    };
+    for setting in words {
+        match setting.split_once('=') {
+            Some(("chunk-size", value)) => {
+                let chunk_size = value
+                    .parse()
+                    .map_err(|_| format!(r#""{value}" is not a valid chunk size"#))?;
+                check_chunk_size(chunk_size).map_err(|error| error.to_string())?;
+                policy.chunk_size = Some(chunk_size);
+            }
+            Some(("mode", value)) => policy.mode = Some(value.parse()?),
+            Some(("compress", "yes")) => policy.compress = Some(true),
+            Some(("compress", "no")) => policy.compress = Some(false),
+            _ => return Err(format!(r#""{setting}" is not a setting"#)),
+        }
+    }
+
+    Ok(policy)
+}
+
+// Whether `text` matches the glob `pattern`, where `*` matches any bytes but `/`, and `?` a single
+// one. A failed match after a `*` is retried with the `*` matching one more byte.
+fn matches_glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position after the last `*`, and the text it was last tried from.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(b'?') if text[t] != b'/' => (p, t) = (p + 1, t + 1),
+            Some(&byte) if byte != b'?' && byte == text[t] => (p, t) = (p + 1, t + 1),
+            _ => match backtrack {
+                Some((after_star, from)) if text[from] != b'/' => {
+                    backtrack = Some((after_star, from + 1));
+                    (p, t) = (after_star, from + 1);
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_matching_policy_applies() {
+        let policies: FilePolicies = "
+            # Disk images change in place, in large regions.
+            *.iso chunk-size=1048576 compress=no
+            data/*.csv mode=lines
+            *.csv chunk-size=64
+        "
+        .parse()
+        .unwrap();
+
+        let iso = policies.for_path("images/debian.iso").unwrap();
+        assert_eq!(iso.chunk_size, Some(1_048_576));
+        assert_eq!(iso.compress, Some(false));
+        assert_eq!(
+            policies.for_path("data/sales.csv").unwrap().mode,
+            Some(ChunkingMode::Lines)
+        );
+        // `*` does not match across directories.
+        assert_eq!(
+            policies.for_path("data/2024/sales.csv").unwrap().chunk_size,
+            Some(64)
+        );
+        assert_eq!(policies.for_path("debian.iso.sig"), None);
+    }
+
+    #[test]
+    fn policies_with_unknown_settings_are_refused() {
+        assert!("*.tar chunking=cdc".parse::<FilePolicies>().is_err());
+        assert!("*.iso chunk-size=0".parse::<FilePolicies>().is_err());
+        assert!("*.csv mode=rows".parse::<FilePolicies>().is_err());
+    }
+}
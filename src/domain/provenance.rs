@@ -0,0 +1,205 @@
+#[cfg(any(feature = "csv", feature = "json"))]
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::domain::delta::Delta;
+
+/// Where a region of the recreated file comes from.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Copy)]
+#[serde(tag = "source", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Source {
+    /// Copied from the basis file, starting at `basis_offset`.
+    Basis { basis_offset: usize },
+    /// Sent directly as byte literals.
+    Literal,
+    /// Written as a run of zero bytes.
+    Zeros,
+}
+
+/// A contiguous region of the recreated file and its origin.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Copy)]
+pub struct ProvenanceEntry {
+    pub output_offset: usize,
+    pub length: usize,
+    #[serde(flatten)]
+    pub source: Source,
+}
+
+/// Per-block map of where each part of the recreated file comes from.
+///
+/// Every referenced block has its own entry, while consecutive byte literals are
+/// merged into a single `Literal` entry, and consecutive zero runs into a single `Zeros` entry.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Default)]
+pub struct ProvenanceMap {
+    pub entries: Vec<ProvenanceEntry>,
+}
+
+impl ProvenanceMap {
+    /// Serializes the map as CSV, with one row per entry.
+    /// The `basis_offset` column is `LITERAL` for literal entries, and `ZEROS` for zero runs.
+    #[cfg(feature = "csv")]
+    pub fn to_csv(&self) -> eyre::Result<Bytes> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(["output_offset", "length", "basis_offset"])?;
+        for entry in &self.entries {
+            let basis_offset = match entry.source {
+                Source::Basis { basis_offset } => basis_offset.to_string(),
+                Source::Literal => String::from("LITERAL"),
+                Source::Zeros => String::from("ZEROS"),
+            };
+            writer.write_record([
+                &entry.output_offset.to_string(),
+                &entry.length.to_string(),
+                &basis_offset,
+            ])?;
+        }
+
+        Ok(writer.into_inner()?.into())
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> eyre::Result<Bytes> {
+        let serialized = serde_json::to_vec_pretty(&self.entries)?;
+        Ok(serialized.into())
+    }
+}
+
+/// Computes the provenance map of the file a Delta reconstructs.
+///
+/// # Arguments
+/// * `delta` - The Delta to describe.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `basis_file_size` - Size of the basis file, if known. It is only needed to know the length
+///   of a trailing block smaller than `chunk_size`.
+///
+pub fn compute_provenance_map(
+    delta: &Delta,
+    chunk_size: usize,
+    basis_file_size: Option<usize>,
+) -> ProvenanceMap {
+    let block_length = |basis_offset: usize| match basis_file_size {
+        Some(size) => size.saturating_sub(basis_offset).min(chunk_size),
+        None => chunk_size,
+    };
+
+    let mut map = ProvenanceMap::default();
+    let mut output_offset = 0;
+    delta.content.iter().for_each(|c| {
+        let indexes = c.block_indexes();
+        if !indexes.is_empty() {
+            // Consecutive blocks of a BlockRange are a single region of the basis file.
+            let basis_offset = indexes.start * chunk_size;
+            let last_block_offset = (indexes.end - 1) * chunk_size;
+            let length = last_block_offset - basis_offset + block_length(last_block_offset);
+            map.entries.push(ProvenanceEntry {
+                output_offset,
+                length,
+                source: Source::Basis { basis_offset },
+            });
+            output_offset += length;
+        }
+
+        let literals = c.literals().len();
+        if literals > 0 {
+            match map.entries.last_mut() {
+                Some(last) if last.source == Source::Literal => last.length += literals,
+                _ => map.entries.push(ProvenanceEntry {
+                    output_offset,
+                    length: literals,
+                    source: Source::Literal,
+                }),
+            }
+            output_offset += literals;
+        }
+
+        let zeros = c.zeros();
+        if zeros > 0 {
+            match map.entries.last_mut() {
+                Some(last) if last.source == Source::Zeros => last.length += zeros,
+                _ => map.entries.push(ProvenanceEntry {
+                    output_offset,
+                    length: zeros,
+                    source: Source::Zeros,
+                }),
+            }
+            output_offset += zeros;
+        }
+    });
+
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::delta::Token;
+
+    use super::*;
+
+    fn create_byte_literals(bytes: &[u8]) -> Vec<Token> {
+        bytes.iter().copied().map(Token::ByteLiteral).collect()
+    }
+
+    #[test]
+    fn literal_runs_are_merged_and_blocks_are_kept_separate() {
+        let test_chunk_size = 4;
+
+        let delta = {
+            let mut content = create_byte_literals(b"ab");
+            content.push(Token::BlockIndex(3));
+            content.push(Token::BlockIndex(4));
+            content.extend(create_byte_literals(b"cde"));
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
+        };
+
+        // The basis file has 18 bytes, so block 4 is only 2 bytes long.
+        let map = compute_provenance_map(&delta, test_chunk_size, Some(18));
+
+        let expected = vec![
+            ProvenanceEntry {
+                output_offset: 0,
+                length: 2,
+                source: Source::Literal,
+            },
+            ProvenanceEntry {
+                output_offset: 2,
+                length: 4,
+                source: Source::Basis { basis_offset: 12 },
+            },
+            ProvenanceEntry {
+                output_offset: 6,
+                length: 2,
+                source: Source::Basis { basis_offset: 16 },
+            },
+            ProvenanceEntry {
+                output_offset: 8,
+                length: 3,
+                source: Source::Literal,
+            },
+        ];
+        assert_eq!(map.entries, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "csv")]
+    fn csv_marks_literal_entries() {
+        let delta = {
+            let mut content = vec![Token::BlockIndex(1)];
+            content.extend(create_byte_literals(b"xy"));
+            Delta {
+                content: content.into(),
+                ..Default::default()
+            }
+        };
+
+        let map = compute_provenance_map(&delta, 3, None);
+        let csv = map.to_csv().unwrap();
+
+        assert_eq!(
+            csv,
+            Bytes::from("output_offset,length,basis_offset\n0,3,3\n3,2,LITERAL\n")
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use bytes::Bytes;
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    apply_delta_with_mode, check_chunk_size, compute_delta_with_mode, compute_signature,
+    BasisLayout, ChunkingMode, Delta, FileSignature, MatchingOptions,
+};
+use crate::help::Help;
+use crate::io_utils;
+
+/// Blocks of a region of the basis file, to update that region without the rest of the file.
+///
+/// Regions start and end on block boundaries, so their blocks are the same as in the Signature of
+/// the whole file. Their layout does not record the strong hash of the region, which Signatures
+/// of the whole file cannot tell.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RegionSignature {
+    /// Bytes of the basis file covered by the blocks.
+    pub range: Range<u64>,
+    /// Blocks of the region, indexed from its start.
+    pub signature: FileSignature,
+}
+
+/// A Delta recreating a region of the basis file, which may change its length.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RegionDelta {
+    /// Bytes of the basis file replaced by the recreated region.
+    pub range: Range<u64>,
+    /// Computed against the blocks of the region only.
+    pub delta: Delta,
+}
+
+// Widens `range` to whole blocks, without going past the end of the file.
+fn align_region(range: &Range<u64>, chunk_size: usize, file_length: u64) -> Range<u64> {
+    let chunk_size = chunk_size as u64;
+    let end = range.end.min(file_length).div_ceil(chunk_size) * chunk_size;
+    let start = (range.start / chunk_size * chunk_size).min(end);
+    start..end.min(file_length)
+}
+
+impl FileSignature {
+    /// Extracts the blocks covering `range` of the basis file, in fixed mode.
+    ///
+    /// The range is widened to whole blocks. Only Signatures of a whole basis file know where it
+    /// ends, so others are refused.
+    ///
+    /// # Arguments
+    /// * `range` - The bytes of the basis file which may have changed.
+    /// * `chunk_size` - The size for each block used in the Signature.
+    ///
+    pub fn region(&self, range: Range<u64>, chunk_size: usize) -> eyre::Result<RegionSignature> {
+        check_chunk_size(chunk_size)?;
+        let layout = self
+            .basis
+            .ok_or_else(|| eyre!("Signature does not tell how long the basis file is"))
+            .suggestion("Compute the Signature of the whole basis file again.")?;
+        let range = align_region(&range, chunk_size, layout.length);
+        let blocks = (range.start / chunk_size as u64) as usize
+            ..(range.end.div_ceil(chunk_size as u64)) as usize;
+        if blocks.end > self.strong_hashes.len() {
+            return Err(eyre!(
+                "Signature has {} blocks, but the region ends at block {}",
+                self.strong_hashes.len(),
+                blocks.end
+            ))
+            .suggestion("Use the same `--chunk-size` used to compute the Signature.");
+        }
+
+        Ok(RegionSignature {
+            signature: FileSignature {
+                basis: Some(BasisLayout::fixed(range.end - range.start, chunk_size)),
+                ..self.blocks(blocks)
+            },
+            range,
+        })
+    }
+}
+
+/// Computes the blocks covering `range` of `basis_file` only, in fixed mode.
+///
+/// The result is the same as extracting the region from the Signature of the whole file.
+///
+/// # Arguments
+/// * `basis_file` - The basis file. Only the region is hashed.
+/// * `range` - The bytes of the basis file which may have changed. Widened to whole blocks.
+/// * `chunk_size` - The size for each block.
+///
+pub fn compute_region_signature(
+    basis_file: &Bytes,
+    range: Range<u64>,
+    chunk_size: usize,
+) -> eyre::Result<RegionSignature> {
+    check_chunk_size(chunk_size)?;
+    let range = align_region(&range, chunk_size, basis_file.len() as u64);
+    let signature = FileSignature {
+        basis: Some(BasisLayout::fixed(range.end - range.start, chunk_size)),
+        ..compute_signature(
+            basis_file.slice(range.start as usize..range.end as usize),
+            chunk_size,
+        )
+    };
+
+    Ok(RegionSignature { range, signature })
+}
+
+/// Computes a Delta recreating `updated_region` from the blocks of a region of the basis file.
+///
+/// # Arguments
+/// * `region` - The blocks of the region of the basis file, as it is now.
+/// * `updated_region` - What the region should contain instead. It may be of another length.
+/// * `chunk_size` - The size for each block used in the RegionSignature.
+/// * `options` - How the matcher is tuned.
+///
+pub fn compute_region_delta(
+    region: &RegionSignature,
+    updated_region: Bytes,
+    chunk_size: usize,
+    options: &MatchingOptions,
+) -> RegionDelta {
+    RegionDelta {
+        range: region.range.clone(),
+        delta: compute_delta_with_mode(
+            region.signature.clone(),
+            updated_region,
+            chunk_size,
+            ChunkingMode::Fixed,
+            options,
+        ),
+    }
+}
+
+/// Applies a RegionDelta to a basis file, keeping the bytes around the region as they are.
+///
+/// # Arguments
+/// * `basis_file` - The file to be changed (not in-place).
+/// * `region_delta` - The Delta of the region, and where the region is.
+/// * `chunk_size` - The size for each block used in the RegionSignature.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+///
+pub fn apply_region_delta(
+    basis_file: Bytes,
+    region_delta: RegionDelta,
+    chunk_size: usize,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    let range = region_in_file(&region_delta.range, basis_file.len() as u64)?;
+    let region = apply_delta_with_mode(
+        basis_file.slice(range.clone()),
+        region_delta.delta,
+        chunk_size,
+        ChunkingMode::Fixed,
+        max_output_size,
+    )?;
+
+    let mut recreated = Vec::with_capacity(basis_file.len() - range.len() + region.len());
+    recreated.extend_from_slice(&basis_file[..range.start]);
+    recreated.extend_from_slice(&region);
+    recreated.extend_from_slice(&basis_file[range.end..]);
+    if recreated.len() as u64 > max_output_size {
+        return Err(eyre!(
+            "Recreated file is larger than the maximum output size ({max_output_size} bytes)"
+        ));
+    }
+
+    Ok(recreated.into())
+}
+
+/// Applies a RegionDelta to the basis file on disk, reading and writing only what it must.
+///
+/// A region recreated with its previous length is written over itself, leaving the rest of the
+/// file untouched. Otherwise, the bytes after the region are moved too. The file is locked
+/// meanwhile (see `io_utils::lock_file`), as another process writing it would corrupt it.
+///
+/// # Arguments
+/// * `basis_filename` - The file to change in place.
+/// * `region_delta` - The Delta of the region, and where the region is.
+/// * `chunk_size` - The size for each block used in the RegionSignature.
+/// * `max_output_size` - The largest recreated region allowed, in bytes.
+///
+pub fn apply_region_delta_in_place(
+    basis_filename: &Path,
+    region_delta: RegionDelta,
+    chunk_size: usize,
+    max_output_size: u64,
+) -> eyre::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(basis_filename)?;
+    io_utils::lock_file(&file, basis_filename)?;
+    let file_length = file.metadata()?.len();
+    let range = region_in_file(&region_delta.range, file_length)?;
+
+    let mut region = vec![0; range.len()];
+    file.seek(SeekFrom::Start(range.start as u64))?;
+    file.read_exact(&mut region)?;
+    let recreated = apply_delta_with_mode(
+        region.into(),
+        region_delta.delta,
+        chunk_size,
+        ChunkingMode::Fixed,
+        max_output_size,
+    )?;
+
+    let mut after = Vec::new();
+    if recreated.len() != range.len() {
+        file.read_to_end(&mut after)?;
+    }
+    file.seek(SeekFrom::Start(range.start as u64))?;
+    file.write_all(&recreated)?;
+    file.write_all(&after)?;
+    if recreated.len() < range.len() {
+        file.set_len(file_length - (range.len() - recreated.len()) as u64)?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+fn region_in_file(range: &Range<u64>, file_length: u64) -> eyre::Result<Range<usize>> {
+    if range.start > range.end || range.end > file_length {
+        return Err(eyre!(
+            "Region {}..{} is not inside the basis file of {file_length} bytes",
+            range.start,
+            range.end
+        ))
+        .suggestion("Did you provide the same Basis file used to compute the RegionSignature?");
+    }
+    Ok(range.start as usize..range.end as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::compute_signature;
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn region_of_a_signature_is_the_signature_of_the_region() {
+        let basis_file = Bytes::from("0123456789abcdefghij".repeat(10));
+        let signature = compute_signature(basis_file.clone(), 8);
+
+        let extracted = signature.region(20..50, 8).unwrap();
+        let computed = compute_region_signature(&basis_file, 20..50, 8).unwrap();
+
+        assert_eq!(extracted.range, 16..56);
+        assert_eq!(extracted, computed);
+        assert_eq!(computed.signature.basis.unwrap().strong_hash, None);
+        let last = signature.region(190..1000, 8).unwrap();
+        assert_eq!(last.range, 184..200);
+    }
+
+    #[test]
+    fn region_deltas_only_change_their_region() {
+        let basis_file = Bytes::from("segment0segment1segment2segment3");
+        let region = compute_region_signature(&basis_file, 8..16, 8).unwrap();
+        let region_delta = compute_region_delta(
+            &region,
+            Bytes::from("segment1, longer"),
+            8,
+            &MatchingOptions::default(),
+        );
+
+        let recreated =
+            apply_region_delta(basis_file.clone(), region_delta.clone(), 8, u64::MAX).unwrap();
+        assert_eq!(recreated, "segment0segment1, longersegment2segment3");
+
+        let basis_filename = scratch_directory("region_in_place").join("basis_file");
+        std::fs::write(&basis_filename, &basis_file).unwrap();
+        apply_region_delta_in_place(&basis_filename, region_delta, 8, u64::MAX).unwrap();
+        assert_eq!(std::fs::read(basis_filename).unwrap(), recreated);
+    }
+}
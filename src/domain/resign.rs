@@ -0,0 +1,126 @@
+use std::io::Read;
+
+use eyre::eyre;
+
+use crate::domain::signature::READ_BUFFER_SIZE;
+use crate::domain::{check_chunk_size, ChunkingMode, FileSignature, SignatureBuilder};
+use crate::help::Help;
+
+/// A Signature recomputed with another chunk size, see `resign_from_reader`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Resigned {
+    pub signature: FileSignature,
+    /// Chunk size of the new Signature.
+    pub chunk_size: usize,
+    /// Chunk size of the old Signature, if its layout tells it.
+    pub old_chunk_size: Option<usize>,
+}
+
+impl Resigned {
+    /// Whether Deltas computed against the old Signature can not be applied with the new chunk
+    /// size. Their block indexes are those of the old blocks.
+    pub fn breaks_cached_deltas(&self) -> bool {
+        self.old_chunk_size != Some(self.chunk_size)
+    }
+}
+
+/// Recomputes the Signature of a basis file with blocks of `chunk_size`, in fixed mode, while
+/// reading it once.
+///
+/// When the layout of the old Signature tells which chunk size it used, the basis file is checked
+/// against the old Signature on the way, so a Signature is never migrated to another file.
+/// Otherwise, only its length is checked.
+///
+/// # Arguments
+/// * `old_signature` - The Signature to migrate.
+/// * `reader` - Where the content of the basis file is read from, e.g. an already open file.
+/// * `chunk_size` - The size for each block of the new Signature.
+///
+pub fn resign_from_reader(
+    old_signature: &FileSignature,
+    mut reader: impl Read,
+    chunk_size: usize,
+) -> eyre::Result<Resigned> {
+    check_chunk_size(chunk_size)?;
+    let old_chunk_size = old_signature
+        .basis
+        .and_then(|layout| layout.chunk_size())
+        .map(|old_chunk_size| old_chunk_size as usize);
+
+    // The new Signature keeps the weak and strong hashes of the old one.
+    let builder_of = |chunk_size| {
+        SignatureBuilder::new(chunk_size, ChunkingMode::Fixed)
+            .with_weak_hash(old_signature.weak_hash)
+            .with_strong_hash(old_signature.strong_hash)
+    };
+    let mut builder = builder_of(chunk_size);
+    let mut old_builder = old_chunk_size.map(builder_of);
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        builder.update(&buffer[..read]);
+        if let Some(old_builder) = &mut old_builder {
+            old_builder.update(&buffer[..read]);
+        }
+    }
+    let signature = builder.finish();
+
+    let length = signature.basis.map(|basis| basis.length);
+    let old_length = old_signature.basis.map(|basis| basis.length);
+    let matches = match old_builder {
+        Some(old_builder) => {
+            let recomputed = old_builder.finish();
+            recomputed.strong_hashes == old_signature.strong_hashes
+                && recomputed.rolling_hashes == old_signature.rolling_hashes
+        }
+        None => old_length.is_none() || old_length == length,
+    };
+    if !matches {
+        return Err(eyre!(
+            "Basis file is not the one the old Signature was computed from"
+        ))
+        .suggestion("Did you provide the same Basis file used to compute the Signature?");
+    }
+
+    Ok(Resigned {
+        signature,
+        chunk_size,
+        old_chunk_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::compute_signature;
+
+    use super::*;
+
+    #[test]
+    fn resigned_signature_is_the_signature_with_the_new_chunk_size() {
+        let basis_file = Bytes::from("0123456789abcdef".repeat(100));
+        let old_signature = compute_signature(basis_file.clone(), 10);
+
+        let resigned = resign_from_reader(&old_signature, &basis_file[..], 64).unwrap();
+
+        assert_eq!(resigned.signature, compute_signature(basis_file, 64));
+        assert_eq!(resigned.old_chunk_size, Some(10));
+        assert!(resigned.breaks_cached_deltas());
+    }
+
+    #[test]
+    fn another_basis_file_is_refused() {
+        let old_signature = compute_signature(Bytes::from("0123456789abcdef".repeat(100)), 10);
+        let other_file = "fedcba9876543210".repeat(100);
+
+        let resigned = resign_from_reader(&old_signature, other_file.as_bytes(), 64);
+
+        assert!(resigned.is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use eyre::eyre;
+
+use crate::domain::{
+    apply_delta_with_mode, compute_fixed_delta, compute_signature_with_hashes,
+    compute_sliding_rolling_hashes_in_parallel, read_streamed_delta, stream_delta_with_mode,
+    ChunkingMode, Delta, DeltaHeader, DeltaWriter, FileDigest, FileSignature, MatchStrategy,
+    MatchingOptions, Parallelism, StrongHash, WeakHash, DEFAULT_MAX_OUTPUT_SIZE,
+};
+use crate::help::Help;
+
+/// How both sides of a sync divide files into blocks, hash and match them.
+///
+/// Starts from the defaults of the commands, changed with the `with_` methods, e.g.
+/// `SyncOptions::default().with_chunk_size(4096)`. See `examples/` for whole syncs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncOptions {
+    chunk_size: usize,
+    mode: ChunkingMode,
+    weak_hash: WeakHash,
+    strong_hash: StrongHash,
+    matching: MatchingOptions,
+    parallelism: Parallelism,
+    max_output_size: u64,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 10,
+            mode: ChunkingMode::Fixed,
+            weak_hash: WeakHash::default(),
+            strong_hash: StrongHash::default(),
+            matching: MatchingOptions::default(),
+            parallelism: Parallelism::serial(),
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+        }
+    }
+}
+
+impl SyncOptions {
+    /// Divides files into blocks of `chunk_size` bytes, or lines of at most that many.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: ChunkingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Computes the rolling hashes of the blocks with `weak_hash`, rather than the polynomial one.
+    pub fn with_weak_hash(mut self, weak_hash: WeakHash) -> Self {
+        self.weak_hash = weak_hash;
+        self
+    }
+
+    /// Computes the strong hashes of the blocks with `strong_hash`, rather than xxh3.
+    pub fn with_strong_hash(mut self, strong_hash: StrongHash) -> Self {
+        self.strong_hash = strong_hash;
+        self
+    }
+
+    /// Tunes the matcher computing Deltas, in fixed mode.
+    pub fn with_matching(mut self, matching: MatchingOptions) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Hashes files on the threads of `parallelism`, rather than on the calling one.
+    pub fn with_parallelism(mut self, parallelism: Parallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Refuses Deltas recreating files larger than `max_output_size` bytes.
+    pub fn with_max_output_size(mut self, max_output_size: u64) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn mode(&self) -> ChunkingMode {
+        self.mode
+    }
+
+    pub fn parallelism(&self) -> &Parallelism {
+        &self.parallelism
+    }
+
+    pub fn max_output_size(&self) -> u64 {
+        self.max_output_size
+    }
+
+    /// Computes the Signature of `basis_file`, which the side with the updated file needs.
+    pub fn signature(&self, basis_file: Bytes) -> eyre::Result<FileSignature> {
+        self.mode.check_chunk_size(self.chunk_size)?;
+        Ok(compute_signature_with_hashes(
+            basis_file,
+            self.chunk_size,
+            self.mode,
+            self.weak_hash,
+            self.strong_hash,
+            &self.parallelism,
+        ))
+    }
+
+    /// Computes the Delta recreating `updated_file` from the basis file `signature` describes.
+    ///
+    /// Fails if the Signature was computed with another chunk size.
+    pub fn compute_delta(
+        &self,
+        signature: &FileSignature,
+        updated_file: &Bytes,
+    ) -> eyre::Result<Delta> {
+        self.check_signature(signature)?;
+        let mut delta = match self.mode {
+            ChunkingMode::Fixed => {
+                let rolling_hashes = compute_sliding_rolling_hashes_in_parallel(
+                    updated_file,
+                    self.chunk_size,
+                    signature.weak_hash,
+                    &self.parallelism,
+                );
+                let mut delta = compute_fixed_delta(
+                    signature,
+                    updated_file,
+                    self.chunk_size,
+                    &rolling_hashes,
+                    &self.matching,
+                );
+                delta.header.updated = Some(FileDigest::of(updated_file));
+                delta
+            }
+            ChunkingMode::Lines => {
+                let mut delta = Delta {
+                    header: self.delta_header(signature, updated_file),
+                    ..Default::default()
+                };
+                stream_delta_with_mode(
+                    signature,
+                    updated_file,
+                    self.chunk_size,
+                    self.mode,
+                    &self.matching,
+                    &mut delta.content,
+                )?;
+                delta
+            }
+        };
+        delta.optimize_against(signature);
+        if self.matching.strategy != MatchStrategy::Greedy && self.mode == ChunkingMode::Fixed {
+            delta.drop_costly_references(updated_file, self.chunk_size);
+        }
+
+        Ok(delta)
+    }
+
+    /// Writes the Delta recreating `updated_file` to `writer` while it is being computed, as a
+    /// streamed Delta, and returns the writer. Unlike `compute_delta`, tokens are not merged.
+    pub fn stream_delta<W: Write>(
+        &self,
+        signature: &FileSignature,
+        updated_file: &Bytes,
+        writer: W,
+    ) -> eyre::Result<W> {
+        self.check_signature(signature)?;
+        let mut writer = DeltaWriter::new(writer, &self.delta_header(signature, updated_file))?;
+        stream_delta_with_mode(
+            signature,
+            updated_file,
+            self.chunk_size,
+            self.mode,
+            &self.matching,
+            &mut writer,
+        )?;
+        writer.finish()
+    }
+
+    fn check_signature(&self, signature: &FileSignature) -> eyre::Result<()> {
+        self.mode.check_chunk_size(self.chunk_size)?;
+        if let Some(layout) = signature.basis.filter(|_| self.mode == ChunkingMode::Fixed) {
+            if layout
+                .chunk_size()
+                .is_some_and(|chunk_size| chunk_size != self.chunk_size as u64)
+            {
+                return Err(eyre!(
+                    "Signature describes {layout}, but the chunk size is {}",
+                    self.chunk_size
+                ))
+                .suggestion("Use the same chunk size on both sides of the sync.");
+            }
+        }
+        Ok(())
+    }
+
+    fn delta_header(&self, signature: &FileSignature, updated_file: &[u8]) -> DeltaHeader {
+        DeltaHeader {
+            basis: signature.basis,
+            updated: Some(FileDigest::of(updated_file)),
+            unconfirmed: !signature.strong_hash.confirms_blocks(),
+            output_length: Some(updated_file.len() as u64),
+            ..Default::default()
+        }
+    }
+}
+
+/// The side of syncs which has the basis file: it hands out the Signature of the basis file,
+/// and applies the Deltas computed against it.
+///
+/// Once a Delta was applied, `advance` makes the recreated file the basis file of the next sync.
+#[derive(Debug, Clone)]
+pub struct SyncSession {
+    options: SyncOptions,
+    basis_file: Bytes,
+    signature: FileSignature,
+}
+
+impl SyncSession {
+    /// Starts syncing `basis_file`, computing its Signature with `options`.
+    pub fn new(basis_file: Bytes, options: SyncOptions) -> eyre::Result<Self> {
+        let signature = options.signature(basis_file.clone())?;
+        Ok(Self {
+            options,
+            basis_file,
+            signature,
+        })
+    }
+
+    pub fn options(&self) -> &SyncOptions {
+        &self.options
+    }
+
+    pub fn basis_file(&self) -> &Bytes {
+        &self.basis_file
+    }
+
+    /// The Signature of the basis file, to send to the side with the updated file.
+    pub fn signature(&self) -> &FileSignature {
+        &self.signature
+    }
+
+    /// Recreates the updated file from the basis file and `delta`, checking it is the file the
+    /// Delta was computed for when it records it.
+    pub fn apply(&self, delta: Delta) -> eyre::Result<Bytes> {
+        let expected = delta.header.updated;
+        let recreated = apply_delta_with_mode(
+            self.basis_file.clone(),
+            delta,
+            self.options.chunk_size,
+            self.options.mode,
+            self.options.max_output_size,
+        )?;
+        if let Some(expected) = expected {
+            expected.check_recreated(FileDigest::of(&recreated))?;
+        }
+
+        Ok(recreated)
+    }
+
+    /// Reads a streamed Delta from `reader`, as `SyncOptions::stream_delta` writes it, and
+    /// recreates the updated file from it.
+    pub fn apply_streamed(&self, reader: &mut impl Read) -> eyre::Result<Bytes> {
+        let delta = read_streamed_delta(reader, self.options.max_output_size)?;
+        self.apply(delta)
+    }
+
+    /// Makes `recreated` the basis file of the next sync, computing its Signature.
+    pub fn advance(&mut self, recreated: Bytes) -> eyre::Result<()> {
+        self.signature = self.options.signature(recreated.clone())?;
+        self.basis_file = recreated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_recreate_the_updated_file() {
+        let options = SyncOptions::default().with_chunk_size(4);
+        let mut session = SyncSession::new(Bytes::from("AAAABBBBCCCC"), options).unwrap();
+
+        let updated_file = Bytes::from("AAAAxyCCCCBBBB");
+        let delta = options
+            .compute_delta(session.signature(), &updated_file)
+            .unwrap();
+        let recreated = session.apply(delta).unwrap();
+        assert_eq!(recreated, updated_file);
+        session.advance(recreated).unwrap();
+
+        let updated_file = Bytes::from("AAAAxyCCCCBBBBzz");
+        let streamed = options
+            .stream_delta(session.signature(), &updated_file, Vec::new())
+            .unwrap();
+        assert_eq!(
+            session.apply_streamed(&mut streamed.as_slice()).unwrap(),
+            updated_file
+        );
+
+        let other_options = options.with_chunk_size(8);
+        assert!(other_options
+            .compute_delta(session.signature(), &updated_file)
+            .is_err());
+    }
+
+    #[test]
+    fn deltas_recreating_another_file_are_refused() {
+        for mode in [ChunkingMode::Fixed, ChunkingMode::Lines] {
+            let options = SyncOptions::default().with_chunk_size(4).with_mode(mode);
+            let session = SyncSession::new(Bytes::from("AAAA\nBBBB\nCCCC\n"), options).unwrap();
+            let delta = |updated_file: &'static str| {
+                options
+                    .compute_delta(session.signature(), &Bytes::from(updated_file))
+                    .unwrap()
+            };
+
+            // The tokens of a Delta of the updated file once modified, under its header.
+            let modified = Delta {
+                header: delta("AAAA\nxy\nCCCC\n").header,
+                ..delta("AAAA\nxz\nCCCC\n")
+            };
+
+            assert!(session.apply(modified).is_err(), "{mode}");
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::domain::{
+    compute_signature_with_mode, list_files, BasisLayout, BasisReader, ChunkingMode, FileSignature,
+};
+use crate::io_utils;
+
+/// The basis files of a directory tree seen as a single basis file, so the Delta of any updated
+/// file can reference blocks of every one of them, and content moved from a file to another
+/// (renamed modules, split files) is still matched.
+///
+/// Each file starts at the block after the last one of the previous file, so the index of a
+/// block tells both the file it is in and where (see `locate`), and tokens need nothing else to
+/// identify it. The bytes between the end of a file and the next block read as zeros.
+///
+/// Files are kept as they were read, so Deltas can still be applied while the tree is updated.
+#[derive(Debug, Clone)]
+pub struct SharedBasis {
+    files: Vec<SharedFile>,
+    chunk_size: usize,
+}
+
+/// A basis file of a SharedBasis.
+#[derive(Debug, Clone)]
+pub struct SharedFile {
+    /// Relative to the root of the tree, with `/` as separator.
+    pub path: String,
+    /// Index of its first block among the blocks of every file.
+    pub first_block: usize,
+    pub block_count: usize,
+    /// As it was read.
+    content: Bytes,
+}
+
+impl SharedBasis {
+    /// Reads every file under `root`, in the order `list_files` lists them.
+    ///
+    /// Every file is loaded into memory, so the tree must fit in it.
+    pub fn read(root: &Path, chunk_size: usize) -> eyre::Result<Self> {
+        let files = list_files(root)?
+            .into_iter()
+            .map(|entry| {
+                let content = io_utils::attempt_to_read_file(root.join(&entry.relative_path))?;
+                Ok((entry.path, content))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        Ok(Self::from_files(files, chunk_size))
+    }
+
+    /// Lays out `files`, given by path and content, one after the other.
+    pub fn from_files(files: Vec<(String, Bytes)>, chunk_size: usize) -> Self {
+        let mut first_block = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, content)| {
+                let block_count = content.len().div_ceil(chunk_size);
+                let file = SharedFile {
+                    path,
+                    first_block,
+                    block_count,
+                    content,
+                };
+                first_block += block_count;
+                file
+            })
+            .collect();
+
+        Self { files, chunk_size }
+    }
+
+    pub fn files(&self) -> &[SharedFile] {
+        &self.files
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.files
+            .last()
+            .map_or(0, |file| file.first_block + file.block_count)
+    }
+
+    /// The file holding the block at `index`, and the index of the block within that file.
+    pub fn locate(&self, index: usize) -> Option<(&SharedFile, usize)> {
+        // Empty files start at the same block as the next one, and are never returned.
+        let position = self
+            .files
+            .partition_point(|file| file.first_block <= index)
+            .checked_sub(1)?;
+        let file = &self.files[position];
+
+        (index < file.first_block + file.block_count).then(|| (file, index - file.first_block))
+    }
+
+    /// The Signature of the blocks of every file, in order, as if they were a single file of
+    /// whole blocks.
+    ///
+    /// The last block of each file is hashed on its own bytes, so it is only matched where
+    /// another file has the same bytes followed by the zeros it is padded with.
+    pub fn signature(&self) -> FileSignature {
+        let mut signature =
+            compute_signature_with_mode(Bytes::new(), self.chunk_size, ChunkingMode::Fixed);
+        for file in &self.files {
+            let file_signature = compute_signature_with_mode(
+                file.content.clone(),
+                self.chunk_size,
+                ChunkingMode::Fixed,
+            );
+            signature.strong_hashes.extend(file_signature.strong_hashes);
+            signature
+                .rolling_hashes
+                .extend(file_signature.rolling_hashes);
+            signature
+                .strong_hash_tails
+                .extend(&file_signature.strong_hash_tails);
+        }
+        signature.basis = Some(BasisLayout::fixed(self.len(), self.chunk_size));
+
+        signature
+    }
+
+    // Bytes of `range`, which may go over several files.
+    fn read_range(&self, range: Range<usize>) -> Vec<u8> {
+        let mut bytes = vec![0; range.len()];
+        let mut offset = range.start;
+        while offset < range.end {
+            let Some((file, _)) = self.locate(offset / self.chunk_size) else {
+                break;
+            };
+            let start = file.first_block * self.chunk_size;
+            let end = range.end.min(start + file.block_count * self.chunk_size);
+            let content = file.content.get(offset - start..).unwrap_or_default();
+            let copied = content.len().min(end - offset);
+            bytes[offset - range.start..][..copied].copy_from_slice(&content[..copied]);
+            offset = end;
+        }
+
+        bytes
+    }
+}
+
+impl BasisReader for SharedBasis {
+    fn len(&self) -> u64 {
+        (self.block_count() * self.chunk_size) as u64
+    }
+
+    fn read_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        Ok(ranges
+            .iter()
+            .map(|range| self.read_range(range.start as usize..range.end as usize))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_located_in_their_file() {
+        let basis = SharedBasis::from_files(
+            vec![
+                (String::from("a"), Bytes::from("ABCDEF")),
+                (String::from("empty"), Bytes::new()),
+                (String::from("b"), Bytes::from("GHIJ")),
+            ],
+            4,
+        );
+
+        assert_eq!(basis.block_count(), 3);
+        let located = |index| {
+            basis
+                .locate(index)
+                .map(|(file, block)| (file.path.as_str(), block))
+        };
+        assert_eq!(located(1), Some(("a", 1)));
+        assert_eq!(located(2), Some(("b", 0)));
+        assert_eq!(located(3), None);
+        // The last block of "a" is padded with zeros up to the first block of "b".
+        // A single range is read, not the indexes 3 to 10.
+        #[allow(clippy::single_range_in_vec_init)]
+        let ranges = [3..10];
+        assert_eq!(
+            basis.clone().read_ranges(&ranges).unwrap(),
+            vec![b"DEF\0\0GH".to_vec()]
+        );
+    }
+}
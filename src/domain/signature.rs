@@ -1,46 +1,279 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::ops::Range;
 
 use bytes::Bytes;
-use color_eyre::eyre::Context;
-use color_eyre::Help;
-use rolling_hash_rust::RollingHash;
-use serde::{Deserialize, Serialize};
+use eyre::{eyre, Context};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use xxhash_rust::xxh3::Xxh3Default;
+
+use crate::domain::{
+    artifact_kind, artifact_version, decode_artifact, encode_artifact, hashes_files_with_siphash,
+    ArtifactKind, ChunkingMode, Parallelism, StrongDigest, StrongHash, StrongHashTails, WeakHash,
+};
+use crate::help::Help;
+use crate::{profiling, progress};
 
 type StrongHashType = u64;
 type RollingHashType = u64;
 
+// Bytes requested from a reader at a time by `compute_signature_from_reader`.
+pub(crate) const READ_BUFFER_SIZE: usize = 64 * 1024;
+
 /// Represents the contents of a File
 ///
 /// A file is divided into blocks of `chunk_size` bytes.
 /// For each block, we represent it with two hashes.
 /// The rolling hash is fast to compute, but weak.
 /// The strong hash is a more computationally expensive, but stronger hash.
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct FileSignature {
     // We will generally be accessing `rolling_hashes` together, so it's better if they are
     // closely packed. (As opposed to a single Vec<(strong_hash, rolling_hash)>.
     // SoA vs AoS: https://en.wikipedia.org/wiki/AoS_and_SoA
+    /// The first 64 bits of each block's strong hash, which blocks are looked up by.
     pub strong_hashes: Vec<StrongHashType>,
     pub rolling_hashes: Vec<RollingHashType>,
+    /// What the whole basis file looked like, if this is all of it.
+    #[serde(default)]
+    pub basis: Option<BasisLayout>,
+    /// Which rolling hash `rolling_hashes` are.
+    #[serde(default)]
+    pub weak_hash: WeakHash,
+    /// Which hash `strong_hashes` are, and how wide.
+    #[serde(default = "StrongHash::unrecorded")]
+    pub strong_hash: StrongHash,
+    /// The rest of each strong hash wider than 64 bits.
+    #[serde(default)]
+    pub strong_hash_tails: StrongHashTails,
+}
+
+// Fields are written by position, so Signatures without a basis layout, or with the rolling and
+// strong hashes they had before those could be chosen, are written exactly as before those were
+// recorded.
+impl Serialize for FileSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let optional_fields = optional_signature_fields(
+            self.basis,
+            self.weak_hash,
+            self.strong_hash,
+            &self.strong_hash_tails,
+        );
+        let mut signature = serializer.serialize_struct("FileSignature", 2 + optional_fields)?;
+        signature.serialize_field("strong_hashes", &self.strong_hashes)?;
+        signature.serialize_field("rolling_hashes", &self.rolling_hashes)?;
+        if optional_fields >= 1 {
+            signature.serialize_field("basis", &self.basis)?;
+        }
+        if optional_fields >= 2 {
+            signature.serialize_field("weak_hash", &self.weak_hash)?;
+        }
+        if optional_fields >= 3 {
+            signature.serialize_field("strong_hash", &self.strong_hash)?;
+        }
+        if optional_fields >= 4 {
+            signature.serialize_field("strong_hash_tails", &self.strong_hash_tails)?;
+        }
+        signature.end()
+    }
+}
+
+// How many of the trailing fields of a Signature are written.
+fn optional_signature_fields(
+    basis: Option<BasisLayout>,
+    weak_hash: WeakHash,
+    strong_hash: StrongHash,
+    strong_hash_tails: &StrongHashTails,
+) -> usize {
+    if !strong_hash_tails.is_empty() {
+        4
+    } else if strong_hash != StrongHash::unrecorded() {
+        3
+    } else if weak_hash != WeakHash::default() {
+        2
+    } else if basis.is_some() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Length and blocks of a basis file, to check it is the one a Delta was computed against.
+///
+/// They are carried from the Signature to the Delta, so a Delta applied to a different basis file,
+/// or with a different chunk size, is refused instead of recreating the wrong file.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub struct BasisLayout {
+    pub length: u64,
+    pub block_count: u64,
+    /// None without blocks, or if recorded before it was.
+    #[serde(default)]
+    pub last_block_size: Option<u64>,
+    /// Of the whole file, None if recorded before it was.
+    #[serde(default)]
+    pub strong_hash: Option<u64>,
+}
+
+// Fields are written by position, so layouts without the last block size or the strong hash are
+// written exactly as before they were recorded.
+impl Serialize for BasisLayout {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match (self.last_block_size, self.strong_hash) {
+            (_, Some(_)) => 4,
+            (Some(_), None) => 3,
+            (None, None) => 2,
+        };
+        let mut layout = serializer.serialize_struct("BasisLayout", fields)?;
+        layout.serialize_field("length", &self.length)?;
+        layout.serialize_field("block_count", &self.block_count)?;
+        if self.strong_hash.is_some() {
+            layout.serialize_field("last_block_size", &self.last_block_size)?;
+            layout.serialize_field("strong_hash", &self.strong_hash)?;
+        } else if let Some(last_block_size) = self.last_block_size {
+            layout.serialize_field("last_block_size", &last_block_size)?;
+        }
+        layout.end()
+    }
+}
+
+impl BasisLayout {
+    /// Layout of a file divided into `blocks`, in any mode.
+    pub fn of_blocks(blocks: &[&[u8]]) -> Self {
+        Self {
+            length: blocks.iter().map(|block| block.len() as u64).sum(),
+            block_count: blocks.len() as u64,
+            last_block_size: blocks.last().map(|block| block.len() as u64),
+            strong_hash: None,
+        }
+    }
+
+    /// Layout of a file of `length` bytes divided into fixed blocks of `chunk_size` bytes.
+    pub fn fixed(length: u64, chunk_size: usize) -> Self {
+        let block_count = length.div_ceil(chunk_size as u64);
+        Self {
+            length,
+            block_count,
+            last_block_size: (block_count > 0)
+                .then(|| length - (block_count - 1) * chunk_size as u64),
+            strong_hash: None,
+        }
+    }
+
+    /// The same layout, recording the strong hash of `file`, the whole file it describes.
+    pub fn with_strong_hash(self, file: &[u8]) -> Self {
+        Self {
+            strong_hash: Some(calculate_strong_hash(file)),
+            ..self
+        }
+    }
+
+    /// The same layout, as if the strong hash of the file had not been recorded.
+    pub fn without_strong_hash(self) -> Self {
+        Self {
+            strong_hash: None,
+            ..self
+        }
+    }
+
+    /// The chunk size of the blocks, assuming they are fixed, when the layout tells it.
+    ///
+    /// A single block only tells the chunk size is at least its length. Without the size of
+    /// the last block, the chunk size is only known when a single one gives this many blocks.
+    pub fn chunk_size(&self) -> Option<u64> {
+        if self.block_count < 2 {
+            return None;
+        }
+        let full_blocks = self.block_count - 1;
+        match self.last_block_size {
+            Some(last_block_size) => {
+                let chunk_size = self.length.checked_sub(last_block_size)? / full_blocks;
+                let fits = chunk_size * full_blocks + last_block_size == self.length
+                    && (1..=chunk_size).contains(&last_block_size);
+                fits.then_some(chunk_size)
+            }
+            None => {
+                let smallest = self.length.div_ceil(self.block_count);
+                let largest = (self.length - 1) / full_blocks;
+                (smallest == largest).then_some(smallest)
+            }
+        }
+    }
+}
+
+/// E.g. "a 1.2 GiB file in 1,258,292 blocks of 1 KiB".
+impl fmt::Display for BasisLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a {} file in {} block{}",
+            format_size(self.length),
+            format_count(self.block_count),
+            if self.block_count == 1 { "" } else { "s" }
+        )?;
+        let Some(chunk_size) = self.chunk_size() else {
+            return Ok(());
+        };
+        write!(f, " of {}", format_size(chunk_size))?;
+        match self.last_block_size {
+            Some(last_block_size) if last_block_size != chunk_size => {
+                write!(f, ", the last one of {}", format_size(last_block_size))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Sizes of a KiB and above are rounded to a tenth of their unit, and whole ones shown without it.
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let size = format!("{size:.1}");
+    let size = size.strip_suffix(".0").unwrap_or(&size);
+    format!("{size} {}", UNITS[unit])
+}
+
+// Groups digits by thousands: 1200000 becomes "1,200,000".
+pub(crate) fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+    for (position, digit) in digits.chars().enumerate() {
+        if position > 0 && (digits.len() - position).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
 }
 
 // We are using `rmp_serde` as a efficient binary format to save the files in.
 // TODO: we can experiment with a custom made binary format and optimizations (the paper has some suggestions).
 impl TryFrom<FileSignature> for Bytes {
-    type Error = color_eyre::Report;
+    type Error = eyre::Report;
 
     fn try_from(signature: FileSignature) -> Result<Self, Self::Error> {
-        let serialized = rmp_serde::to_vec(&signature)?;
-        Ok(serialized.into())
+        encode_artifact(ArtifactKind::Signature, &signature)
     }
 }
 
 impl TryFrom<Bytes> for FileSignature {
-    type Error = color_eyre::Report;
+    type Error = eyre::Report;
 
     fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
-        let file_signature = rmp_serde::from_slice(&bytes)
+        let file_signature = decode_signature(bytes)
             .wrap_err("Could not read FileSignature from file provided.")
             .suggestion(
                 "Did you provide the correct path for the Signature file?\n\
@@ -50,6 +283,217 @@ impl TryFrom<Bytes> for FileSignature {
     }
 }
 
+// Signatures may have been saved deduplicated, but are always used with one entry per block.
+fn decode_signature(bytes: Bytes) -> eyre::Result<FileSignature> {
+    let version = artifact_version(&bytes);
+    let mut signature: FileSignature = match artifact_kind(&bytes) {
+        Some(ArtifactKind::DeduplicatedSignature) => {
+            let signature: DeduplicatedSignature =
+                decode_artifact(ArtifactKind::DeduplicatedSignature, bytes)?;
+            signature.try_into()?
+        }
+        _ => decode_artifact(ArtifactKind::Signature, bytes)?,
+    };
+    let tail_width = signature.strong_hash.tail_width();
+    if !signature
+        .strong_hash_tails
+        .fit(signature.strong_hashes.len(), tail_width)
+    {
+        return Err(eyre!(
+            "Signature does not have the whole {} hash of each of its blocks",
+            signature.strong_hash
+        ));
+    }
+    // Version 1 hashed blocks with the low half of xxh3-128 where later versions use xxh3-64, so
+    // none of its blocks would match.
+    if version == Some(1) && signature.strong_hash == StrongHash::Xxh3 {
+        return Err(eyre!(
+            "Signature of format version 1 has xxh3 hashes this version does not compute"
+        ))
+        .suggestion("Compute the Signature again.");
+    }
+    if version.is_some_and(hashes_files_with_siphash) {
+        signature.basis = signature.basis.map(BasisLayout::without_strong_hash);
+    }
+
+    Ok(signature)
+}
+
+impl FileSignature {
+    /// The hashes of the blocks in `blocks` alone, without the basis layout.
+    ///
+    /// Panics if the Signature does not have all of them.
+    pub fn blocks(&self, blocks: Range<usize>) -> FileSignature {
+        FileSignature {
+            strong_hashes: self.strong_hashes[blocks.clone()].to_vec(),
+            rolling_hashes: self.rolling_hashes[blocks.clone()].to_vec(),
+            basis: None,
+            weak_hash: self.weak_hash,
+            strong_hash: self.strong_hash,
+            strong_hash_tails: self
+                .strong_hash_tails
+                .blocks(blocks, self.strong_hash.tail_width())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the block at `index` has the strong hash `digest`, all of its bits.
+    pub fn has_strong_digest(&self, index: usize, digest: &StrongDigest) -> bool {
+        self.strong_hashes.get(index) == Some(&digest.head)
+            && self.strong_hash_tail(index) == Some(digest.tail())
+    }
+
+    /// The bits of the strong hash of the block at `index` after its first 64.
+    pub fn strong_hash_tail(&self, index: usize) -> Option<&[u8]> {
+        self.strong_hash_tails
+            .get(index, self.strong_hash.tail_width())
+    }
+
+    // Appends the hashes of a block.
+    fn push_block_hashes(&mut self, digest: &StrongDigest, rolling_hash: RollingHashType) {
+        self.strong_hashes.push(digest.head);
+        self.strong_hash_tails.push(digest.tail());
+        self.rolling_hashes.push(rolling_hash);
+    }
+}
+
+/// A FileSignature where each distinct block is stored only once.
+///
+/// Large files often have many identical blocks (zero pages, repeated headers). Here, each distinct
+/// pair of hashes is stored once, together with the indexes of every block that has it, which makes
+/// the saved Signature smaller. It is converted back to a FileSignature when read, so blocks keep
+/// their indexes and Deltas are the same.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct DeduplicatedSignature {
+    pub strong_hashes: Vec<StrongHashType>,
+    pub rolling_hashes: Vec<RollingHashType>,
+    /// Indexes of the blocks with each pair of hashes, in order.
+    pub block_indexes: Vec<Vec<usize>>,
+    /// Same as in the FileSignature.
+    #[serde(default)]
+    pub basis: Option<BasisLayout>,
+    /// Same as in the FileSignature.
+    #[serde(default)]
+    pub weak_hash: WeakHash,
+    /// Same as in the FileSignature.
+    #[serde(default = "StrongHash::unrecorded")]
+    pub strong_hash: StrongHash,
+    /// Of each pair of hashes, as in the FileSignature.
+    #[serde(default)]
+    pub strong_hash_tails: StrongHashTails,
+}
+
+// Written by position, as FileSignatures are.
+impl Serialize for DeduplicatedSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let optional_fields = optional_signature_fields(
+            self.basis,
+            self.weak_hash,
+            self.strong_hash,
+            &self.strong_hash_tails,
+        );
+        let mut signature =
+            serializer.serialize_struct("DeduplicatedSignature", 3 + optional_fields)?;
+        signature.serialize_field("strong_hashes", &self.strong_hashes)?;
+        signature.serialize_field("rolling_hashes", &self.rolling_hashes)?;
+        signature.serialize_field("block_indexes", &self.block_indexes)?;
+        if optional_fields >= 1 {
+            signature.serialize_field("basis", &self.basis)?;
+        }
+        if optional_fields >= 2 {
+            signature.serialize_field("weak_hash", &self.weak_hash)?;
+        }
+        if optional_fields >= 3 {
+            signature.serialize_field("strong_hash", &self.strong_hash)?;
+        }
+        if optional_fields >= 4 {
+            signature.serialize_field("strong_hash_tails", &self.strong_hash_tails)?;
+        }
+        signature.end()
+    }
+}
+
+impl From<&FileSignature> for DeduplicatedSignature {
+    fn from(signature: &FileSignature) -> Self {
+        let mut deduplicated = DeduplicatedSignature {
+            basis: signature.basis,
+            weak_hash: signature.weak_hash,
+            strong_hash: signature.strong_hash,
+            ..Default::default()
+        };
+        // Map with key: (RollingHash, StrongHash, its tail) and value: its position in
+        // `deduplicated`.
+        let mut positions = HashMap::new();
+        for (index, hashes) in signature
+            .rolling_hashes
+            .iter()
+            .zip(signature.strong_hashes.iter())
+            .enumerate()
+        {
+            let tail = signature.strong_hash_tail(index).unwrap_or_default();
+            let position = *positions.entry((hashes, tail)).or_insert_with(|| {
+                deduplicated.rolling_hashes.push(*hashes.0);
+                deduplicated.strong_hashes.push(*hashes.1);
+                deduplicated.strong_hash_tails.push(tail);
+                deduplicated.block_indexes.push(Vec::new());
+                deduplicated.block_indexes.len() - 1
+            });
+            deduplicated.block_indexes[position].push(index);
+        }
+
+        deduplicated
+    }
+}
+
+impl TryFrom<DeduplicatedSignature> for FileSignature {
+    type Error = eyre::Report;
+
+    fn try_from(deduplicated: DeduplicatedSignature) -> Result<Self, Self::Error> {
+        let number_of_blocks = deduplicated.block_indexes.iter().map(Vec::len).sum();
+        let tail_width = deduplicated.strong_hash.tail_width();
+        let mut hashes = vec![None; number_of_blocks];
+        for (position, indexes) in deduplicated.block_indexes.iter().enumerate() {
+            let pair = deduplicated
+                .rolling_hashes
+                .get(position)
+                .zip(deduplicated.strong_hashes.get(position))
+                .zip(deduplicated.strong_hash_tails.get(position, tail_width))
+                .ok_or_else(|| eyre!("Deduplicated Signature has indexes without hashes"))?;
+            for &index in indexes {
+                match hashes.get_mut(index) {
+                    Some(slot @ None) => *slot = Some(pair),
+                    _ => return Err(eyre!("Deduplicated Signature has invalid block indexes")),
+                }
+            }
+        }
+
+        // Every slot is filled: there are as many slots as indexes, and no index was repeated.
+        let mut signature = FileSignature {
+            strong_hashes: Vec::with_capacity(number_of_blocks),
+            rolling_hashes: Vec::with_capacity(number_of_blocks),
+            basis: deduplicated.basis,
+            weak_hash: deduplicated.weak_hash,
+            strong_hash: deduplicated.strong_hash,
+            strong_hash_tails: StrongHashTails::default(),
+        };
+        for ((&rolling_hash, &strong_hash), tail) in hashes.into_iter().flatten() {
+            signature.rolling_hashes.push(rolling_hash);
+            signature.strong_hashes.push(strong_hash);
+            signature.strong_hash_tails.push(tail);
+        }
+
+        Ok(signature)
+    }
+}
+
+impl TryFrom<DeduplicatedSignature> for Bytes {
+    type Error = eyre::Report;
+
+    fn try_from(signature: DeduplicatedSignature) -> Result<Self, Self::Error> {
+        encode_artifact(ArtifactKind::DeduplicatedSignature, &signature)
+    }
+}
+
 /// Computes a FileSignature for the content of a file.
 ///
 /// The file is split into equally-sized blocks (or possibly a smaller last block)
@@ -60,33 +504,316 @@ impl TryFrom<Bytes> for FileSignature {
 /// * `chunk_size` - The size for each block.
 ///
 pub fn compute_signature(basis_file: Bytes, chunk_size: usize) -> FileSignature {
-    let blocks = basis_file.chunks(chunk_size);
-    let strong_hashes = blocks.map(calculate_strong_hash).collect();
-
-    let mut rolling_hashes = Vec::new();
-    let blocks = basis_file.chunks(chunk_size);
-    blocks.for_each(|block| {
-        let hasher = RollingHash::from_initial_bytes(String::from_utf8_lossy(block).as_bytes());
-        let hash = hasher.get_current_hash();
-        rolling_hashes.push(hash);
+    compute_signature_with_mode(basis_file, chunk_size, ChunkingMode::Fixed)
+}
+
+/// Computes a FileSignature for the content of a file, dividing it into blocks with `mode`.
+///
+/// # Arguments
+/// * `basis_file` - A Bytes structure which holds the content of the file.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How the file is divided into blocks.
+///
+pub fn compute_signature_with_mode(
+    basis_file: Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+) -> FileSignature {
+    let mut builder = SignatureBuilder::new(chunk_size, mode);
+    builder.update(&basis_file);
+    builder.finish()
+}
+
+/// Computes a FileSignature while reading the file, in a single pass, without holding it in
+/// memory. Works with readers which cannot seek, like pipes.
+///
+/// # Arguments
+/// * `reader` - Where the content of the file is read from.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How the file is divided into blocks.
+///
+pub fn compute_signature_from_reader(
+    mut reader: impl Read,
+    chunk_size: usize,
+    mode: ChunkingMode,
+) -> io::Result<FileSignature> {
+    let mut builder = SignatureBuilder::new(chunk_size, mode);
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => builder.update(&buffer[..read]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+/// Computes a FileSignature from the content of a file given in pieces, as they arrive.
+///
+/// Both hashes of a block are computed as soon as the block is complete, so the content is only
+/// seen once, and only the block being read is kept.
+pub struct SignatureBuilder {
+    chunk_size: usize,
+    mode: ChunkingMode,
+    /// Bytes of the block being read, until it is complete.
+    pending: Vec<u8>,
+    length: u64,
+    /// Bytes of the last block hashed.
+    last_block_size: u64,
+    /// Strong hash of all the bytes so far, which gives the same hash in pieces.
+    hasher: Xxh3Default,
+    /// Hashes of the complete blocks so far.
+    signature: FileSignature,
+}
+
+impl SignatureBuilder {
+    pub fn new(chunk_size: usize, mode: ChunkingMode) -> Self {
+        assert!(
+            chunk_size > 0 || mode == ChunkingMode::Lines,
+            "Fixed blocks must not be empty"
+        );
+        Self {
+            chunk_size,
+            mode,
+            pending: Vec::new(),
+            length: 0,
+            last_block_size: 0,
+            hasher: Xxh3Default::new(),
+            signature: FileSignature {
+                strong_hashes: Vec::new(),
+                rolling_hashes: Vec::new(),
+                basis: None,
+                weak_hash: WeakHash::default(),
+                strong_hash: StrongHash::default(),
+                strong_hash_tails: StrongHashTails::default(),
+            },
+        }
+    }
+
+    /// Computes the rolling hashes of the blocks with `weak_hash`, rather than the polynomial one.
+    pub fn with_weak_hash(mut self, weak_hash: WeakHash) -> Self {
+        self.signature.weak_hash = weak_hash;
+        self
+    }
+
+    /// Computes the strong hashes of the blocks with `strong_hash`, rather than xxh3.
+    pub fn with_strong_hash(mut self, strong_hash: StrongHash) -> Self {
+        self.signature.strong_hash = strong_hash;
+        self
+    }
+
+    /// Hashes the blocks completed by `bytes`, which follow the bytes given before.
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+        self.hasher.update(bytes);
+        while !bytes.is_empty() {
+            let block_end = match self.mode {
+                ChunkingMode::Fixed => Some(self.chunk_size - self.pending.len())
+                    .filter(|&missing| missing <= bytes.len()),
+                ChunkingMode::Lines => bytes
+                    .iter()
+                    .position(|&byte| byte == b'\n')
+                    .map(|newline| newline + 1),
+            };
+            let Some(block_end) = block_end else {
+                self.pending.extend_from_slice(bytes);
+                return;
+            };
+
+            let (block, rest) = bytes.split_at(block_end);
+            if self.pending.is_empty() {
+                // The block is hashed where it is, without copying it.
+                self.push_block(block);
+            } else {
+                let mut pending = mem::take(&mut self.pending);
+                pending.extend_from_slice(block);
+                self.push_block(&pending);
+                pending.clear();
+                self.pending = pending;
+            }
+            bytes = rest;
+        }
+    }
+
+    /// Hashes the last block, even if it is shorter than the others, and returns the Signature.
+    pub fn finish(mut self) -> FileSignature {
+        if !self.pending.is_empty() {
+            let pending = mem::take(&mut self.pending);
+            self.push_block(&pending);
+        }
+        let block_count = self.signature.strong_hashes.len() as u64;
+        self.signature.basis = Some(BasisLayout {
+            length: self.length,
+            block_count,
+            last_block_size: (block_count > 0).then_some(self.last_block_size),
+            strong_hash: Some(self.hasher.digest()),
+        });
+
+        self.signature
+    }
+
+    fn push_block(&mut self, block: &[u8]) {
+        progress::advance(block.len() as u64);
+        self.last_block_size = block.len() as u64;
+        let digest = self.signature.strong_hash.digest(block);
+        let rolling_hash = self.signature.weak_hash.hash(block);
+        self.signature.push_block_hashes(&digest, rolling_hash);
+    }
+}
+
+/// Computes a FileSignature like `compute_signature_with_mode`, hashing blocks on several threads.
+///
+/// The FileSignature is the same whatever the number of threads.
+///
+/// # Arguments
+/// * `basis_file` - A Bytes structure which holds the content of the file.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How the file is divided into blocks.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn compute_signature_in_parallel(
+    basis_file: Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    parallelism: &Parallelism,
+) -> FileSignature {
+    compute_signature_with_hashes(
+        basis_file,
+        chunk_size,
+        mode,
+        WeakHash::default(),
+        StrongHash::default(),
+        parallelism,
+    )
+}
+
+/// Computes a FileSignature like `compute_signature_in_parallel`, with `weak_hash` as the rolling
+/// hash of the blocks, and `strong_hash` as their strong hash.
+///
+/// # Arguments
+/// * `basis_file` - A Bytes structure which holds the content of the file.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How the file is divided into blocks.
+/// * `weak_hash` - Which rolling hash the blocks get.
+/// * `strong_hash` - Which strong hash the blocks get.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn compute_signature_with_hashes(
+    basis_file: Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    weak_hash: WeakHash,
+    strong_hash: StrongHash,
+    parallelism: &Parallelism,
+) -> FileSignature {
+    profiling::span!("signature");
+    if parallelism.is_serial() {
+        let mut builder = SignatureBuilder::new(chunk_size, mode)
+            .with_weak_hash(weak_hash)
+            .with_strong_hash(strong_hash);
+        builder.update(&basis_file);
+        return builder.finish();
+    }
+    let blocks = mode.split(&basis_file, chunk_size);
+    let hashes = parallelism.map_ranges(blocks.len(), |range| {
+        blocks[range]
+            .iter()
+            .inspect(|b| progress::advance(b.len() as u64))
+            .map(|b| (strong_hash.digest(b), weak_hash.hash(b)))
+            .collect()
+    });
+
+    signature_of_blocks(&basis_file, &blocks, hashes, weak_hash, strong_hash)
+}
+
+/// Computes the FileSignature of a file in fixed mode, reusing the rolling hashes of its sliding
+/// blocks computed to match it against another Signature.
+///
+/// The result is the same as `compute_signature_in_parallel`. Only the strong hashes are
+/// computed, except for a last shorter block.
+///
+/// # Arguments
+/// * `file` - The file, as it was matched.
+/// * `chunk_size` - The size for each block.
+/// * `sliding_rolling_hashes` - As computed by `compute_sliding_rolling_hashes_in_parallel`.
+/// * `weak_hash` - The rolling hash `sliding_rolling_hashes` were computed with.
+/// * `strong_hash` - Which strong hash the blocks get.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn compute_signature_from_sliding_hashes(
+    file: &[u8],
+    chunk_size: usize,
+    sliding_rolling_hashes: &[u64],
+    weak_hash: WeakHash,
+    strong_hash: StrongHash,
+    parallelism: &Parallelism,
+) -> FileSignature {
+    let blocks = ChunkingMode::Fixed.split(file, chunk_size);
+    let hashes = parallelism.map_ranges(blocks.len(), |range| {
+        range
+            .map(|index| {
+                let block = blocks[index];
+                let rolling_hash = match sliding_rolling_hashes.get(index * chunk_size) {
+                    Some(&hash) => hash,
+                    _ => weak_hash.hash(block),
+                };
+                (strong_hash.digest(block), rolling_hash)
+            })
+            .collect()
     });
 
-    FileSignature {
-        strong_hashes,
-        rolling_hashes,
+    signature_of_blocks(file, &blocks, hashes, weak_hash, strong_hash)
+}
+
+// The Signature of `file`, divided into `blocks`, given the hashes of each block in order.
+fn signature_of_blocks(
+    file: &[u8],
+    blocks: &[&[u8]],
+    hashes: Vec<(StrongDigest, RollingHashType)>,
+    weak_hash: WeakHash,
+    strong_hash: StrongHash,
+) -> FileSignature {
+    let mut signature = FileSignature {
+        strong_hashes: Vec::with_capacity(blocks.len()),
+        rolling_hashes: Vec::with_capacity(blocks.len()),
+        basis: Some(BasisLayout::of_blocks(blocks).with_strong_hash(file)),
+        weak_hash,
+        strong_hash,
+        strong_hash_tails: StrongHashTails::default(),
+    };
+    for (digest, rolling_hash) in &hashes {
+        signature.push_block_hashes(digest, *rolling_hash);
     }
+
+    signature
+}
+
+/// Computes the rolling hash of a whole block.
+///
+/// The bytes are hashed as they are, as `delta` does while sliding over the updated file, so blocks
+/// which are not valid UTF-8 match too. Signatures hash them so since format version 2.
+///
+/// # Arguments
+/// * `content` - Bytes to hash.
+///
+pub fn calculate_rolling_hash(content: &[u8]) -> RollingHashType {
+    WeakHash::Polynomial.hash(content)
 }
 
 /// Computes a strong hash for a slice of bytes.
 ///
+/// This is the hash of whole files (in digests, layouts, manifests and block indexes): xxh3-64,
+/// which is the same for the same content whichever build computes it. Artifacts of format
+/// version 1 hashed whole files with SipHash instead, see `hashes_files_with_siphash`.
+///
 /// # Arguments
 /// * `content` - Bytes to hash.
 ///
 pub fn calculate_strong_hash(content: &[u8]) -> StrongHashType {
-    let mut s = DefaultHasher::new();
-    content.hash(&mut s);
-
-    s.finish()
+    StrongHash::Xxh3.hash(content)
 }
 
 #[cfg(test)]
@@ -127,6 +854,124 @@ mod tests {
         assert_ne!(file1_signature, file2_signature);
     }
 
+    #[test]
+    fn deduplicated_signature_reads_back_with_every_block() {
+        let test_chunk_size = 4;
+
+        let file = Bytes::from("ABCDABCDEFGHABCD");
+        let signature = compute_signature(file, test_chunk_size);
+
+        let deduplicated = DeduplicatedSignature::from(&signature);
+        assert_eq!(deduplicated.block_indexes, vec![vec![0, 1, 3], vec![2]]);
+
+        let bytes = Bytes::try_from(deduplicated).unwrap();
+        assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+    }
+
+    #[test]
+    fn weak_hash_is_read_back_from_the_signature() {
+        let file = Bytes::from("ABCDABCDEFGHABCD");
+        let signature = compute_signature_with_hashes(
+            file.clone(),
+            4,
+            ChunkingMode::Fixed,
+            WeakHash::Gear,
+            StrongHash::default(),
+            &Parallelism::serial(),
+        );
+        assert_eq!(signature.rolling_hashes[2], WeakHash::Gear.hash(b"EFGH"));
+
+        let bytes = Bytes::try_from(signature.clone()).unwrap();
+        assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+        let without_basis = FileSignature {
+            basis: None,
+            ..signature.clone()
+        };
+        let bytes = Bytes::try_from(DeduplicatedSignature::from(&without_basis)).unwrap();
+        assert_eq!(FileSignature::try_from(bytes).unwrap(), without_basis);
+
+        // Polynomial and SipHash hashes are written as before they could be chosen.
+        let mut builder =
+            SignatureBuilder::new(4, ChunkingMode::Fixed).with_strong_hash(StrongHash::SipHash);
+        builder.update(&file);
+        let unrecorded = builder.finish();
+        let serialized = rmp_serde::to_vec(&unrecorded).unwrap();
+        let fields = (
+            &unrecorded.strong_hashes,
+            &unrecorded.rolling_hashes,
+            unrecorded.basis,
+        );
+        assert_eq!(serialized, rmp_serde::to_vec(&fields).unwrap());
+    }
+
+    #[test]
+    fn strong_hash_is_read_back_from_the_signature() {
+        let file = Bytes::from("ABCDABCDEFGHABCD");
+        let signature = compute_signature(file.clone(), 4);
+        assert_eq!(signature.strong_hash, StrongHash::Xxh3);
+        assert_eq!(signature.strong_hashes[2], StrongHash::Xxh3.hash(b"EFGH"));
+
+        let bytes = Bytes::try_from(signature.clone()).unwrap();
+        assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+        let bytes = Bytes::try_from(DeduplicatedSignature::from(&signature)).unwrap();
+        assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+
+        // Signatures written before it was recorded hashed their blocks with SipHash.
+        let fields = (&signature.strong_hashes, &signature.rolling_hashes);
+        let serialized = rmp_serde::to_vec(&fields).unwrap();
+        let read_back: FileSignature = rmp_serde::from_slice(&serialized).unwrap();
+        assert_eq!(read_back.strong_hash, StrongHash::SipHash);
+
+        // Version 1 hashed blocks with the low half of xxh3-128 instead.
+        let mut version_1 = Bytes::try_from(signature).unwrap().to_vec();
+        version_1[4..6].copy_from_slice(&1_u16.to_le_bytes());
+        assert!(FileSignature::try_from(Bytes::from(version_1)).is_err());
+    }
+
+    #[test]
+    fn wide_strong_hashes_are_read_back_from_the_signature() {
+        let file = Bytes::from("ABCDABCDEFGHABCD");
+        let signature_with = |strong_hash| {
+            let mut builder =
+                SignatureBuilder::new(4, ChunkingMode::Fixed).with_strong_hash(strong_hash);
+            builder.update(&file);
+            builder.finish()
+        };
+
+        for strong_hash in [StrongHash::Xxh128, StrongHash::Blake3] {
+            let signature = signature_with(strong_hash);
+            assert!(signature.has_strong_digest(2, &strong_hash.digest(b"EFGH")));
+            assert!(!signature.has_strong_digest(2, &strong_hash.digest(b"ABCD")));
+
+            let bytes = Bytes::try_from(signature.clone()).unwrap();
+            assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+            let deduplicated = DeduplicatedSignature::from(&signature);
+            assert_eq!(deduplicated.block_indexes, vec![vec![0, 1, 3], vec![2]]);
+            let bytes = Bytes::try_from(deduplicated).unwrap();
+            assert_eq!(FileSignature::try_from(bytes).unwrap(), signature);
+        }
+
+        // Without the rest of each hash, blocks could not be confirmed.
+        let truncated = FileSignature {
+            strong_hash_tails: StrongHashTails::default(),
+            ..signature_with(StrongHash::Blake3)
+        };
+        let bytes = Bytes::try_from(truncated).unwrap();
+        assert!(FileSignature::try_from(bytes).is_err());
+    }
+
+    #[test]
+    fn deduplicated_signature_with_repeated_indexes_is_an_error() {
+        let deduplicated = DeduplicatedSignature {
+            strong_hashes: vec![1, 2],
+            rolling_hashes: vec![1, 2],
+            block_indexes: vec![vec![0], vec![0]],
+            ..Default::default()
+        };
+
+        assert!(FileSignature::try_from(deduplicated).is_err());
+    }
+
     #[test]
     fn chunk_size_too_big_means_only_one_block() {
         let test_chunk_size = 100;
@@ -138,4 +983,96 @@ mod tests {
         assert_eq!(file_signature.rolling_hashes.len(), 1);
         assert_eq!(file_signature.strong_hashes.len(), 1);
     }
+
+    #[test]
+    fn signatures_computed_from_pieces_are_the_same() {
+        let basis_file = Bytes::from("first line\nsecond\n\nlast without newline");
+
+        for mode in [ChunkingMode::Fixed, ChunkingMode::Lines] {
+            let mut builder = SignatureBuilder::new(4, mode);
+            for piece in basis_file.chunks(3) {
+                builder.update(piece);
+            }
+            let expected = compute_signature_in_parallel(
+                basis_file.clone(),
+                4,
+                mode,
+                &Parallelism::with_threads(2.try_into().unwrap()),
+            );
+            assert_eq!(builder.finish(), expected);
+        }
+    }
+
+    #[test]
+    fn signatures_can_be_computed_from_a_reader() {
+        let basis_file: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+
+        let signature = compute_signature_from_reader(&basis_file[..], 1000, ChunkingMode::Fixed);
+
+        assert_eq!(
+            signature.unwrap(),
+            compute_signature(basis_file.into(), 1000)
+        );
+    }
+
+    #[test]
+    fn signature_from_sliding_hashes_is_the_signature() {
+        // Blocks which are not valid UTF-8 reuse their sliding hashes too.
+        let mut file = "0123456789abcdef".repeat(10).into_bytes();
+        file[37] = 0xff;
+        let chunk_size = 16;
+        for weak_hash in [WeakHash::Polynomial, WeakHash::Adler32, WeakHash::Gear] {
+            let sliding_rolling_hashes = crate::domain::compute_sliding_rolling_hashes_in_parallel(
+                &file,
+                chunk_size,
+                weak_hash,
+                &Parallelism::serial(),
+            );
+
+            let signature = compute_signature_from_sliding_hashes(
+                &file,
+                chunk_size,
+                &sliding_rolling_hashes,
+                weak_hash,
+                StrongHash::SipHash,
+                &Parallelism::serial(),
+            );
+
+            let expected = compute_signature_with_hashes(
+                file.clone().into(),
+                chunk_size,
+                ChunkingMode::Fixed,
+                weak_hash,
+                StrongHash::SipHash,
+                &Parallelism::serial(),
+            );
+            assert_eq!(signature, expected, "{weak_hash}");
+        }
+    }
+
+    #[test]
+    fn layout_describes_the_blocks_of_the_basis_file() {
+        let signature = compute_signature(Bytes::from(vec![0; 1_500_000]), 1024);
+        let layout = signature.basis.unwrap();
+
+        assert_eq!(layout.last_block_size, Some(1_500_000 % 1024));
+        assert_eq!(layout.chunk_size(), Some(1024));
+        assert_eq!(
+            layout.to_string(),
+            "a 1.4 MiB file in 1,465 blocks of 1 KiB, the last one of 864 bytes"
+        );
+        assert_eq!(
+            layout.strong_hash,
+            Some(calculate_strong_hash(&[0; 1_500_000]))
+        );
+        let without_last_block = BasisLayout {
+            last_block_size: None,
+            strong_hash: None,
+            ..layout
+        };
+        let serialized = rmp_serde::to_vec(&without_last_block).unwrap();
+        assert_eq!(serialized, rmp_serde::to_vec(&(1_500_000, 1465)).unwrap());
+        let deserialized: BasisLayout = rmp_serde::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, without_last_block);
+    }
 }
@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey, SIGNATURE_LENGTH};
+use eyre::{eyre, Context};
+
+use crate::help::Help;
+
+// Signed artifacts start with this magic, followed by the ed25519 signature of the rest.
+const SIGNED_MAGIC: &[u8; 4] = b"RRSN";
+
+/// Keys to sign the artifacts we write, and to verify the ones we read, with ed25519.
+///
+/// Signing happens last, after encryption, so mirrors can verify artifacts they cannot read.
+#[derive(Default)]
+pub struct ArtifactSigner {
+    /// Signs the artifacts we write.
+    pub signing_key: Option<SigningKey>,
+    /// Public keys of who we accept artifacts from.
+    pub verifying_keys: Vec<VerifyingKey>,
+    /// Refuse artifacts which are not signed by one of `verifying_keys`.
+    pub require_signed: bool,
+}
+
+impl ArtifactSigner {
+    /// Loads keys from files written by `generate_signing_key`.
+    ///
+    /// # Arguments
+    /// * `signing_key_file` - File with the private key to sign with.
+    /// * `verifying_key_files` - Files with the public keys to verify with.
+    /// * `require_signed` - Whether unsigned artifacts are refused.
+    ///
+    pub fn load(
+        signing_key_file: Option<&Path>,
+        verifying_key_files: &[impl AsRef<Path>],
+        require_signed: bool,
+    ) -> eyre::Result<Self> {
+        let signing_key = signing_key_file
+            .map(|path| read_key(path).map(|bytes| SigningKey::from_bytes(&bytes)))
+            .transpose()?;
+        let verifying_keys = verifying_key_files
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                VerifyingKey::from_bytes(&read_key(path)?).wrap_err(format!(
+                    r#"Verifying key file "{}" does not hold an ed25519 public key"#,
+                    path.display()
+                ))
+            })
+            .collect::<eyre::Result<_>>()?;
+        if require_signed && verifying_key_files.is_empty() {
+            return Err(eyre!(
+                "Cannot require signed artifacts without a verifying key"
+            ));
+        }
+
+        Ok(Self {
+            signing_key,
+            verifying_keys,
+            require_signed,
+        })
+    }
+
+    /// Signs `artifact`, or returns it unchanged if no key to sign with was given.
+    pub fn sign(&self, artifact: Bytes) -> Bytes {
+        let Some(signing_key) = &self.signing_key else {
+            return artifact;
+        };
+
+        let signature = signing_key.sign(&artifact);
+        let mut signed =
+            BytesMut::with_capacity(SIGNED_MAGIC.len() + SIGNATURE_LENGTH + artifact.len());
+        signed.put_slice(SIGNED_MAGIC);
+        signed.put_slice(&signature.to_bytes());
+        signed.put_slice(&artifact);
+        signed.freeze()
+    }
+
+    /// Verifies `artifact` and returns it without its signature.
+    ///
+    /// Signed artifacts must verify with one of `verifying_keys`, if any was given.
+    /// Unsigned artifacts are only refused if `require_signed` is set.
+    pub fn verify(&self, artifact: Bytes) -> eyre::Result<Bytes> {
+        if !is_signed(&artifact) {
+            return if self.require_signed {
+                Err(eyre!("Artifact is not signed"))
+                    .suggestion("Sign it with `--signing-key`, or drop `--require-signed`.")
+            } else {
+                Ok(artifact)
+            };
+        }
+
+        let header_length = SIGNED_MAGIC.len() + SIGNATURE_LENGTH;
+        let signature_bytes: [u8; SIGNATURE_LENGTH] = artifact
+            .get(SIGNED_MAGIC.len()..header_length)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| eyre!("Signed artifact is truncated"))?;
+        let signature = ed25519_dalek::Signature::from_bytes(&signature_bytes);
+        let content = artifact.slice(header_length..);
+
+        if !self.verifying_keys.is_empty()
+            && !self
+                .verifying_keys
+                .iter()
+                .any(|key| key.verify_strict(&content, &signature).is_ok())
+        {
+            return Err(eyre!(
+                "Artifact was tampered with, or signed by a key which is not trusted"
+            ));
+        }
+
+        Ok(content)
+    }
+}
+
+/// Tells whether `artifact` was signed by `ArtifactSigner::sign`.
+pub fn is_signed(artifact: &[u8]) -> bool {
+    artifact.starts_with(SIGNED_MAGIC)
+}
+
+/// Generates a new ed25519 key pair, and writes each key in hexadecimal to its file.
+///
+/// # Arguments
+/// * `signing_key_file` - Where the private key is written. Keep it secret.
+/// * `verifying_key_file` - Where the public key is written, to give to whoever verifies.
+///
+pub fn generate_signing_key(
+    signing_key_file: &Path,
+    verifying_key_file: &Path,
+) -> eyre::Result<()> {
+    let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+    write_key(signing_key_file, &signing_key.to_bytes())?;
+    write_key(verifying_key_file, signing_key.verifying_key().as_bytes())
+}
+
+fn write_key(path: &Path, key: &[u8; 32]) -> eyre::Result<()> {
+    let hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+    std::fs::write(path, hex + "\n")
+        .wrap_err(format!(r#"Could not write key file "{}""#, path.display()))
+}
+
+fn read_key(path: &Path) -> eyre::Result<[u8; 32]> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err(format!(r#"Could not read key file "{}""#, path.display()))?;
+    let hex = content.trim();
+    let invalid = || {
+        eyre!(
+            r#"Key file "{}" does not hold 32 bytes in hexadecimal"#,
+            path.display()
+        )
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer_for(signing_key: &SigningKey, require_signed: bool) -> ArtifactSigner {
+        ArtifactSigner {
+            signing_key: Some(signing_key.clone()),
+            verifying_keys: vec![signing_key.verifying_key()],
+            require_signed,
+        }
+    }
+
+    #[test]
+    fn signed_artifact_verifies_with_the_right_key_only() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let artifact = Bytes::from("RRDL some artifact");
+
+        let signed = signer_for(&signing_key, true).sign(artifact.clone());
+
+        assert!(is_signed(&signed));
+        assert_eq!(
+            signer_for(&signing_key, true)
+                .verify(signed.clone())
+                .unwrap(),
+            artifact
+        );
+        let other_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        assert!(signer_for(&other_key, false).verify(signed).is_err());
+    }
+
+    #[test]
+    fn tampered_or_unsigned_artifacts_are_refused_when_required() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let artifact = Bytes::from("RRDL some artifact");
+        let mut tampered = signer_for(&signing_key, true)
+            .sign(artifact.clone())
+            .to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+
+        assert!(signer_for(&signing_key, false)
+            .verify(Bytes::from(tampered))
+            .is_err());
+        assert!(signer_for(&signing_key, true)
+            .verify(artifact.clone())
+            .is_err());
+        assert_eq!(
+            signer_for(&signing_key, false)
+                .verify(artifact.clone())
+                .unwrap(),
+            artifact
+        );
+    }
+}
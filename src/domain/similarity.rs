@@ -0,0 +1,277 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Range;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::domain::{
+    compute_signature_in_parallel, compute_sliding_rolling_hashes_in_parallel,
+    stream_delta_with_mode, stream_fixed_delta, ChunkingMode, MatchingOptions, Parallelism, Token,
+    TokenSink,
+};
+
+/// About how many coarse blocks the updated file is split in, when scoring candidate basis files.
+pub const SCORING_BLOCKS: usize = 256;
+
+/// How much of the updated file already exists in the basis file, at block granularity.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Similarity {
+    /// Size of the updated file.
+    pub updated_size: usize,
+    /// Parts of the updated file found in the basis file, in order.
+    pub shared_regions: Vec<Range<usize>>,
+}
+
+/// A part of the updated file, and how many of its bytes were found in the basis file.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone, Copy)]
+pub struct HeatMapBucket {
+    pub offset: usize,
+    pub length: usize,
+    pub shared_bytes: usize,
+}
+
+impl Similarity {
+    pub fn shared_bytes(&self) -> usize {
+        self.shared_regions.iter().map(|region| region.len()).sum()
+    }
+
+    /// Fraction of the updated file found in the basis file, between 0 and 1.
+    /// An empty updated file is entirely found.
+    pub fn fraction(&self) -> f64 {
+        if self.updated_size == 0 {
+            return 1.0;
+        }
+        self.shared_bytes() as f64 / self.updated_size as f64
+    }
+
+    /// Splits the updated file into `buckets` parts of (almost) equal length, and tells how many
+    /// bytes of each were found in the basis file.
+    pub fn heat_map(&self, buckets: usize) -> Vec<HeatMapBucket> {
+        let bucket_length = self.updated_size.div_ceil(buckets.max(1)).max(1);
+        let mut regions = self.shared_regions.iter().peekable();
+
+        (0..self.updated_size)
+            .step_by(bucket_length)
+            .map(|offset| {
+                let bucket = offset..self.updated_size.min(offset + bucket_length);
+                let mut shared_bytes = 0;
+                // Regions are in order, so only the ones overlapping this bucket are looked at.
+                while let Some(region) = regions.peek() {
+                    shared_bytes += region
+                        .end
+                        .min(bucket.end)
+                        .saturating_sub(region.start.max(bucket.start));
+                    if region.end > bucket.end {
+                        break;
+                    }
+                    regions.next();
+                }
+                HeatMapBucket {
+                    offset,
+                    length: bucket.len(),
+                    shared_bytes,
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "json")]
+    pub fn heat_map_to_json(&self, buckets: usize) -> eyre::Result<Bytes> {
+        let serialized = serde_json::to_vec_pretty(&self.heat_map(buckets))?;
+        Ok(serialized.into())
+    }
+}
+
+impl fmt::Display for Similarity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Bytes found in basis file: {} of {} ({:.2}%)",
+            self.shared_bytes(),
+            self.updated_size,
+            self.fraction() * 100.0
+        )?;
+        write!(f, "Shared regions: {}", self.shared_regions.len())
+    }
+}
+
+// Follows the tokens of a Delta without keeping them, only recording which of our bytes
+// come from the basis file.
+struct SharedRegions<'a> {
+    basis_block_lengths: &'a [usize],
+    offset: usize,
+    regions: Vec<Range<usize>>,
+}
+
+impl TokenSink for SharedRegions<'_> {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        for index in token.block_indexes() {
+            let end = self.offset + self.basis_block_lengths[index];
+            match self.regions.last_mut() {
+                Some(last) if last.end == self.offset => last.end = end,
+                _ => self.regions.push(self.offset..end),
+            }
+            self.offset = end;
+        }
+        self.offset += token.literals().len() + token.zeros();
+
+        Ok(())
+    }
+}
+
+/// Computes how much of `updated_file` exists in `basis_file`, as the matcher of `delta` sees it,
+/// without building a Delta.
+///
+/// # Arguments
+/// * `basis_file` - The candidate basis file.
+/// * `updated_file` - The file which would be sent.
+/// * `chunk_size` - The size for each block.
+/// * `mode` - How files are divided into blocks.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn compute_similarity(
+    basis_file: &Bytes,
+    updated_file: &Bytes,
+    chunk_size: usize,
+    mode: ChunkingMode,
+    parallelism: &Parallelism,
+) -> Similarity {
+    let signature =
+        compute_signature_in_parallel(basis_file.clone(), chunk_size, mode, parallelism);
+    let basis_block_lengths: Vec<_> = mode
+        .split(basis_file, chunk_size)
+        .iter()
+        .map(|block| block.len())
+        .collect();
+    let mut shared = SharedRegions {
+        basis_block_lengths: &basis_block_lengths,
+        offset: 0,
+        regions: Vec::new(),
+    };
+
+    let options = MatchingOptions::default();
+    match mode {
+        ChunkingMode::Fixed => {
+            let our_rolling_hashes = compute_sliding_rolling_hashes_in_parallel(
+                updated_file,
+                chunk_size,
+                signature.weak_hash,
+                parallelism,
+            );
+            stream_fixed_delta(
+                &signature,
+                updated_file,
+                chunk_size,
+                &our_rolling_hashes,
+                &options,
+                &mut shared,
+            )
+        }
+        ChunkingMode::Lines => stream_delta_with_mode(
+            &signature,
+            updated_file,
+            chunk_size,
+            mode,
+            &options,
+            &mut shared,
+        ),
+    }
+    .expect("Recording shared regions never fails");
+
+    Similarity {
+        updated_size: updated_file.len(),
+        shared_regions: shared.regions,
+    }
+}
+
+/// Scores how much of `updated_file` each candidate basis file holds, between 0 and 1.
+///
+/// Candidates are compared with coarse blocks (the updated file is split in about
+/// `SCORING_BLOCKS` of them), which is much quicker than computing a Delta against each one,
+/// and enough to tell which candidate is closest.
+///
+/// # Arguments
+/// * `candidates` - The candidate basis files.
+/// * `updated_file` - The file which would be sent.
+/// * `chunk_size` - The size of the blocks of the real Delta. Coarse blocks are never smaller.
+/// * `parallelism` - How many threads may hash blocks.
+///
+pub fn score_basis_candidates(
+    candidates: &[Bytes],
+    updated_file: &Bytes,
+    chunk_size: usize,
+    parallelism: &Parallelism,
+) -> Vec<f64> {
+    let coarse_chunk_size = (updated_file.len() / SCORING_BLOCKS).max(chunk_size).max(1);
+
+    candidates
+        .iter()
+        .map(|candidate| {
+            compute_similarity(
+                candidate,
+                updated_file,
+                coarse_chunk_size,
+                ChunkingMode::Fixed,
+                parallelism,
+            )
+            .fraction()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_counts_the_bytes_found_in_the_basis_file() {
+        let basis_file = Bytes::from("AAAABBBBCCCC");
+        let updated_file = Bytes::from("AAAAxxxxCCCCyy");
+
+        let similarity = compute_similarity(
+            &basis_file,
+            &updated_file,
+            4,
+            ChunkingMode::Fixed,
+            &Parallelism::serial(),
+        );
+
+        assert_eq!(similarity.shared_regions, vec![0..4, 8..12]);
+        assert_eq!(similarity.shared_bytes(), 8);
+        assert_eq!(similarity.updated_size, 14);
+    }
+
+    #[test]
+    fn heat_map_splits_shared_regions_across_buckets() {
+        let similarity = Similarity {
+            updated_size: 10,
+            shared_regions: vec![0..3, 4..9],
+        };
+
+        let heat_map = similarity.heat_map(2);
+
+        let shared: Vec<_> = heat_map.iter().map(|bucket| bucket.shared_bytes).collect();
+        assert_eq!(shared, vec![4, 4]);
+        assert_eq!(heat_map[1].offset, 5);
+        assert_eq!(heat_map[1].length, 5);
+    }
+
+    #[test]
+    fn the_closest_candidate_has_the_best_score() {
+        let content = "0123456789".repeat(SCORING_BLOCKS);
+        let updated_file = Bytes::from(content.clone());
+        let unrelated = Bytes::from("abcdefghij".repeat(SCORING_BLOCKS));
+        let close = Bytes::from(format!("{}xyz", &content[..content.len() - 10]));
+
+        let scores = score_basis_candidates(
+            &[unrelated, close],
+            &updated_file,
+            10,
+            &Parallelism::serial(),
+        );
+
+        assert_eq!(scores[0], 0.0);
+        assert!(scores[1] > 0.9);
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use crate::domain::{Delta, FileSignature, Token, TokenRef, TokenSink, PREAMBLE_LENGTH};
+
+/// Matching was stopped because the Delta was going to be larger than allowed.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct DeltaTooLarge {
+    /// Bytes the Delta would take with the tokens pushed so far.
+    pub estimate: u64,
+    /// Largest Delta allowed, in bytes.
+    pub limit: u64,
+}
+
+impl fmt::Display for DeltaTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Delta takes at least {} bytes, more than the limit of {} bytes",
+            self.estimate, self.limit
+        )
+    }
+}
+
+impl std::error::Error for DeltaTooLarge {}
+
+impl Delta {
+    /// Bytes this Delta takes once serialized as a single MessagePack artifact, without
+    /// serializing its tokens.
+    pub fn serialized_size_estimate(&self) -> u64 {
+        // The header is a handful of optional fields, so it is cheaper to just serialize it.
+        let header = rmp_serde::to_vec(&self.header).map_or(0, |header| header.len() as u64);
+        let tokens: u64 = self.tokens().map(|token| token_size(&token)).sum();
+
+        PREAMBLE_LENGTH as u64 + 1 + header + array_header_size(self.content.len()) + tokens
+    }
+}
+
+impl FileSignature {
+    /// Bytes this Signature takes once serialized as a single MessagePack artifact, without
+    /// serializing its hashes.
+    pub fn serialized_size_estimate(&self) -> u64 {
+        // The other fields are a handful (the tails of wide strong hashes, a single string of
+        // bytes), so it is cheaper to serialize them without the hashes, whose arrays then take a
+        // byte each.
+        let without_hashes = FileSignature {
+            strong_hashes: Vec::new(),
+            rolling_hashes: Vec::new(),
+            basis: self.basis,
+            weak_hash: self.weak_hash,
+            strong_hash: self.strong_hash,
+            strong_hash_tails: self.strong_hash_tails.clone(),
+        };
+        let fields = rmp_serde::to_vec(&without_hashes).map_or(0, |fields| fields.len() as u64);
+        let hashes = |hashes: &[u64]| {
+            array_header_size(hashes.len())
+                + hashes.iter().map(|&hash| uint_size(hash)).sum::<u64>()
+        };
+
+        PREAMBLE_LENGTH as u64 + fields - 2
+            + hashes(&self.strong_hashes)
+            + hashes(&self.rolling_hashes)
+    }
+}
+
+/// Counts the bytes the tokens pushed to `inner` take once serialized, as they are pushed.
+///
+/// With a limit, pushing a token fails with DeltaTooLarge as soon as the Delta is larger than
+/// it, so matching stops early instead of computing a Delta which will not be used.
+pub struct DeltaSizeEstimator<S> {
+    inner: S,
+    limit: Option<u64>,
+    tokens: usize,
+    /// Bytes of the tokens pushed so far.
+    estimate: u64,
+}
+
+impl<S: TokenSink> DeltaSizeEstimator<S> {
+    pub fn new(inner: S, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            limit,
+            tokens: 0,
+            estimate: 0,
+        }
+    }
+
+    /// Bytes the tokens pushed so far take, with the length of their sequence.
+    pub fn estimate(&self) -> u64 {
+        array_header_size(self.tokens) + self.estimate
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: TokenSink> TokenSink for DeltaSizeEstimator<S> {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        self.tokens += 1;
+        self.estimate += token_size(&TokenRef::from(&token));
+        if let Some(limit) = self.limit.filter(|&limit| self.estimate() > limit) {
+            return Err(DeltaTooLarge {
+                estimate: self.estimate(),
+                limit,
+            }
+            .into());
+        }
+
+        self.inner.push(token)
+    }
+}
+
+/// Bytes a token takes once serialized with MessagePack.
+///
+/// Tokens are maps from the name of their variant to their value, so even a single literal takes
+/// over ten bytes.
+pub fn token_size(token: &TokenRef) -> u64 {
+    let (variant, value) = match token {
+        TokenRef::BlockIndex(index) => ("BlockIndex", uint_size(*index as u64)),
+        TokenRef::ByteLiteral(byte) => ("ByteLiteral", uint_size(**byte as u64)),
+        TokenRef::BlockRange(indexes) => (
+            "BlockRange",
+            1 + uint_size(indexes.start as u64) + uint_size(indexes.end as u64),
+        ),
+        TokenRef::ByteLiterals(bytes) => (
+            "ByteLiterals",
+            array_header_size(bytes.len())
+                + bytes
+                    .iter()
+                    .map(|&byte| uint_size(byte as u64))
+                    .sum::<u64>(),
+        ),
+        TokenRef::ZeroRun(length) => ("ZeroRun", uint_size(*length as u64)),
+    };
+
+    // A map of one entry, and the name of the variant as a short string.
+    1 + 1 + variant.len() as u64 + value
+}
+
+fn uint_size(value: u64) -> u64 {
+    match value {
+        0..=0x7f => 1,
+        0x80..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+fn array_header_size(length: usize) -> u64 {
+    match length {
+        0..=15 => 1,
+        16..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::domain::{
+        compute_delta_to_our_file, compute_signature, stream_delta_with_mode, ChunkingMode,
+        MatchingOptions, SignatureBuilder, StrongHash, TokenBuffer, WeakHash,
+    };
+
+    use super::*;
+
+    #[test]
+    fn estimate_is_the_serialized_size() {
+        let content: Vec<Token> = vec![
+            Token::BlockIndex(3),
+            Token::BlockIndex(300),
+            Token::ByteLiteral(5),
+            Token::ByteLiteral(200),
+            Token::BlockRange(1..70_000),
+            Token::ByteLiterals((0..=255).collect()),
+            Token::ZeroRun(1 << 20),
+        ]
+        .into_iter()
+        .cycle()
+        .take(20)
+        .collect();
+        let delta = Delta {
+            content: content.into(),
+            ..Default::default()
+        };
+
+        let estimate = delta.serialized_size_estimate();
+
+        let serialized: Bytes = delta.try_into().unwrap();
+        assert_eq!(estimate, serialized.len() as u64);
+    }
+
+    #[test]
+    fn signature_estimate_is_the_serialized_size() {
+        let basis_file = Bytes::from("0123456789abcdef".repeat(64));
+
+        let mut builder = SignatureBuilder::new(4, ChunkingMode::Fixed)
+            .with_weak_hash(WeakHash::Gear)
+            .with_strong_hash(StrongHash::Blake3);
+        builder.update(&basis_file);
+        for signature in [
+            compute_signature(basis_file.clone(), 4),
+            compute_signature(Bytes::new(), 4),
+            builder.finish(),
+        ] {
+            let estimate = signature.serialized_size_estimate();
+
+            let serialized: Bytes = signature.try_into().unwrap();
+            assert_eq!(estimate, serialized.len() as u64);
+        }
+    }
+
+    #[test]
+    fn matching_stops_once_the_limit_is_exceeded() {
+        let test_chunk_size = 4;
+        let signature = compute_signature(Bytes::from("AAAABBBB"), test_chunk_size);
+        let updated_file = Bytes::from("completely different content");
+
+        let mut tokens = DeltaSizeEstimator::new(TokenBuffer::default(), Some(100));
+        let error = stream_delta_with_mode(
+            &signature,
+            &updated_file,
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+            &mut tokens,
+        )
+        .unwrap_err();
+
+        let too_large = error.downcast_ref::<DeltaTooLarge>().unwrap();
+        assert!(too_large.estimate > 100);
+        assert!(tokens.into_inner().len() < updated_file.len());
+        let full = compute_delta_to_our_file(signature, updated_file, test_chunk_size);
+        assert!(full.serialized_size_estimate() > 100);
+    }
+}
@@ -0,0 +1,273 @@
+//! Deltas written while they are being computed.
+//!
+//! A streamed Delta has the usual preamble (with the `MessagePackFrames` encoding), followed by:
+//! 1 - a frame with the DeltaHeader.
+//! 2 - any number of frames, each with a batch of tokens.
+//! 3 - an empty frame (a length of 0), marking the end of the Delta.
+//!
+//! Each frame is a little-endian u32 length followed by that many bytes of MessagePack.
+//! As the end is explicitly marked, a Delta whose writing was interrupted is detected when read.
+//! The same frames are used for every message of the network mode.
+
+use std::io::{ErrorKind, Read, Write};
+
+use bytes::{Buf, Bytes};
+use eyre::eyre;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::domain::delta::{Delta, DeltaHeader, Token, TokenSink, UnknownTokens};
+use crate::domain::token_buffer::TokenBuffer;
+use crate::domain::{
+    artifact_version, hashes_files_with_siphash, preamble, read_preamble, ArtifactEncoding,
+    ArtifactKind, PREAMBLE_LENGTH,
+};
+
+// Tokens are batched so that the length prefixes are a small fraction of the Delta.
+const TOKENS_PER_FRAME: usize = 4096;
+
+// Frames are much smaller than this, so a larger length can only come from a corrupted input.
+// Refusing it avoids allocating that much memory before finding out.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Writes a Delta to `writer` as its tokens are pushed, holding at most one frame in memory.
+///
+/// `finish` must be called once every token was pushed, or the Delta will be incomplete.
+pub struct DeltaWriter<W: Write> {
+    writer: W,
+    pending: TokenBuffer,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Writes the preamble and the header, so tokens can be pushed right away.
+    pub fn new(mut writer: W, header: &DeltaHeader) -> eyre::Result<Self> {
+        writer.write_all(&preamble(
+            ArtifactKind::Delta,
+            ArtifactEncoding::MessagePackFrames,
+        ))?;
+        write_frame(&mut writer, header)?;
+
+        Ok(Self {
+            writer,
+            pending: TokenBuffer::default(),
+        })
+    }
+
+    /// Writes the remaining tokens and the end of the Delta, returning the writer.
+    pub fn finish(mut self) -> eyre::Result<W> {
+        self.write_pending()?;
+        write_end_frame(&mut self.writer)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_pending(&mut self) -> eyre::Result<()> {
+        if !self.pending.is_empty() {
+            write_frame(&mut self.writer, &self.pending)?;
+            self.pending.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> TokenSink for DeltaWriter<W> {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        self.pending.push(token);
+        if self.pending.len() == TOKENS_PER_FRAME {
+            self.write_pending()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the frames of a streamed Delta, after its preamble.
+pub(crate) fn read_delta_frames(mut payload: Bytes) -> eyre::Result<Delta> {
+    let header: DeltaHeader =
+        read_frame(&mut payload)?.ok_or_else(|| eyre!("Streamed Delta has no header"))?;
+
+    let mut content = TokenBuffer::default();
+    while let Some(tokens) = read_frame::<TokenBuffer>(&mut payload)? {
+        content.append(&tokens);
+    }
+
+    Ok(Delta { header, content })
+}
+
+/// Reads a streamed Delta from `reader`, stopping right after its last frame.
+///
+/// Unlike reading a Delta from Bytes, this does not need the end of the input, so it works
+/// on connections which stay open after the Delta was sent.
+///
+/// # Arguments
+/// * `reader` - Where the Delta is read from.
+/// * `max_tokens` - Deltas with more tokens are rejected without being kept in memory. The rest
+///   of their frames is still read, so `reader` is left right after the Delta either way.
+///
+pub fn read_streamed_delta(reader: &mut impl Read, max_tokens: u64) -> eyre::Result<Delta> {
+    let mut preamble = [0; PREAMBLE_LENGTH];
+    reader.read_exact(&mut preamble)?;
+    let encoding = read_preamble(ArtifactKind::Delta, &mut Bytes::copy_from_slice(&preamble))?;
+    if encoding != ArtifactEncoding::MessagePackFrames {
+        return Err(eyre!("Delta was not streamed"));
+    }
+
+    let mut header: DeltaHeader =
+        read_frame_from(reader)?.ok_or_else(|| eyre!("Streamed Delta has no header"))?;
+    if artifact_version(&preamble).is_some_and(hashes_files_with_siphash) {
+        header.forget_file_hashes();
+    }
+    let mut content = TokenBuffer::default();
+    while let Some(tokens) = read_frame_from::<TokenBuffer>(reader)? {
+        content.append(&tokens);
+        if content.len() as u64 > max_tokens {
+            while read_frame_from::<TokenBuffer>(reader)?.is_some() {}
+            return Err(eyre!("Streamed Delta has more than {max_tokens} tokens"));
+        }
+    }
+
+    UnknownTokens::Deny.check(Delta { header, content })
+}
+
+/// Writes `value` as a single frame.
+pub fn write_frame<T: Serialize>(writer: &mut impl Write, value: &T) -> eyre::Result<()> {
+    let frame = rmp_serde::to_vec(value)?;
+    writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+    writer.write_all(&frame)?;
+
+    Ok(())
+}
+
+// Returns None for the empty frame which ends the Delta.
+fn read_frame<T: DeserializeOwned>(payload: &mut Bytes) -> eyre::Result<Option<T>> {
+    if payload.len() < 4 {
+        return Err(eyre!("Streamed Delta is truncated"));
+    }
+    let length = payload.get_u32_le() as usize;
+    if length == 0 {
+        return Ok(None);
+    }
+    if payload.len() < length {
+        return Err(eyre!("Streamed Delta is truncated"));
+    }
+    let frame = payload.split_to(length);
+
+    Ok(Some(rmp_serde::from_slice(&frame)?))
+}
+
+/// Writes the empty frame, which marks the end of a sequence of frames.
+pub fn write_end_frame(writer: &mut impl Write) -> eyre::Result<()> {
+    writer.write_all(&0_u32.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Reads a single frame from `reader`, or None if it was the empty frame.
+pub fn read_frame_from<T: DeserializeOwned>(reader: &mut impl Read) -> eyre::Result<Option<T>> {
+    match read_raw_frame_from(reader)? {
+        Some(frame) => Ok(Some(rmp_serde::from_slice(&frame)?)),
+        None => Ok(None),
+    }
+}
+
+/// Writes `bytes` as a single frame, as they are. They must not be empty, as that ends a
+/// sequence of frames.
+pub fn write_raw_frame(writer: &mut impl Write, bytes: &[u8]) -> eyre::Result<()> {
+    if bytes.is_empty() || bytes.len() > MAX_FRAME_LENGTH {
+        return Err(eyre!("Raw frames hold 1 to {MAX_FRAME_LENGTH} bytes"));
+    }
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+
+    Ok(())
+}
+
+/// Reads the bytes of a single frame from `reader`, or None if it was the empty frame.
+pub fn read_raw_frame_from(reader: &mut impl Read) -> eyre::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).map_err(truncated_on_eof)?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length == 0 {
+        return Ok(None);
+    }
+    if length > MAX_FRAME_LENGTH {
+        return Err(eyre!(
+            "Frame of {length} bytes is larger than the limit of {MAX_FRAME_LENGTH} bytes"
+        ));
+    }
+    let mut frame = vec![0; length];
+    reader.read_exact(&mut frame).map_err(truncated_on_eof)?;
+
+    Ok(Some(frame))
+}
+
+fn truncated_on_eof(error: std::io::Error) -> eyre::Report {
+    if error.kind() == ErrorKind::UnexpectedEof {
+        eyre!("Input ended in the middle of a frame")
+    } else {
+        error.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{compute_delta_to_our_file, compute_signature, stream_delta_with_mode};
+    use crate::domain::{ChunkingMode, MatchingOptions, TextNormalization};
+
+    use super::*;
+
+    #[test]
+    fn streamed_delta_reads_back_as_the_computed_one() {
+        let test_chunk_size = 3;
+        let basis_file = Bytes::from("ABCDEFGHI".repeat(2000));
+        let updated_file = Bytes::from("xABCDEFGHI".repeat(2000));
+        let signature = compute_signature(basis_file, test_chunk_size);
+        let header = DeltaHeader {
+            normalization: Some(TextNormalization::default()),
+            ..Default::default()
+        };
+
+        let mut writer = DeltaWriter::new(Vec::new(), &header).unwrap();
+        stream_delta_with_mode(
+            &signature,
+            &updated_file,
+            test_chunk_size,
+            ChunkingMode::Fixed,
+            &MatchingOptions::default(),
+            &mut writer,
+        )
+        .unwrap();
+        let streamed = Bytes::from(writer.finish().unwrap());
+
+        let mut expected = compute_delta_to_our_file(signature, updated_file, test_chunk_size);
+        expected.header = header;
+        assert!(expected.content.len() > TOKENS_PER_FRAME);
+        assert_eq!(Delta::try_from(streamed).unwrap(), expected);
+    }
+
+    #[test]
+    fn streamed_delta_is_read_from_a_reader_without_consuming_what_follows() {
+        let mut writer = DeltaWriter::new(Vec::new(), &DeltaHeader::default()).unwrap();
+        writer.push(Token::BlockIndex(3)).unwrap();
+        let mut streamed = writer.finish().unwrap();
+        streamed.extend_from_slice(b"next message");
+
+        let mut reader = &streamed[..];
+        let delta = read_streamed_delta(&mut reader, u64::MAX).unwrap();
+
+        assert_eq!(delta.content, vec![Token::BlockIndex(3)]);
+        assert_eq!(reader, b"next message");
+    }
+
+    #[test]
+    fn unfinished_streamed_delta_is_an_error() {
+        let mut writer = DeltaWriter::new(Vec::new(), &DeltaHeader::default()).unwrap();
+        writer.push(Token::ByteLiteral(b'a')).unwrap();
+        writer.write_pending().unwrap();
+        let unfinished = Bytes::from(writer.writer);
+
+        assert!(Delta::try_from(unfinished).is_err());
+    }
+}
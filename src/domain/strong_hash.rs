@@ -0,0 +1,283 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fmt::Formatter;
+use std::hash::Hasher;
+use std::ops::Range;
+use std::str::FromStr;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::embedded;
+
+/// The hash confirming that a block of the updated file, found by its weak hash, is the block of
+/// the basis file it seems to be.
+///
+/// It is recorded in the Signature, so `delta` hashes the updated file's blocks the same way
+/// whichever build computed the Signature, and so is its width. Hashes wider than 64 bits make a
+/// collision between blocks unlikely even across petabytes of them, at the cost of larger
+/// Signatures.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum StrongHash {
+    /// `DefaultHasher`, the one Signatures had before it could be chosen. Its output is only kept
+    /// from one Rust release to the next by convention, and it is slower.
+    SipHash,
+    /// xxh3-64: several times faster, and specified independently of Rust.
+    #[default]
+    Xxh3,
+    /// The whole 128 bits of xxh3-128.
+    Xxh128,
+    /// The 256 bits of BLAKE3, a cryptographic hash: slower, but hard to collide on purpose.
+    Blake3,
+    /// No strong hash: blocks are reused on their weak hash alone.
+    Omitted,
+}
+
+/// Bytes of the widest strong hash.
+pub const MAX_STRONG_HASH_WIDTH: usize = 32;
+
+impl StrongHash {
+    /// The strong hash of Signatures written before it was recorded.
+    pub fn unrecorded() -> Self {
+        StrongHash::SipHash
+    }
+
+    /// Bytes of each block's strong hash.
+    pub fn width(&self) -> usize {
+        match self {
+            StrongHash::Omitted => 0,
+            StrongHash::SipHash | StrongHash::Xxh3 => 8,
+            StrongHash::Xxh128 => 16,
+            StrongHash::Blake3 => 32,
+        }
+    }
+
+    /// Bytes of each block's strong hash after its first 64 bits.
+    pub fn tail_width(&self) -> usize {
+        self.width().saturating_sub(8)
+    }
+
+    /// Whether blocks found by their weak hash are confirmed before they are reused.
+    pub fn confirms_blocks(&self) -> bool {
+        *self != StrongHash::Omitted
+    }
+
+    /// The first 64 bits of the strong hash of a block, which Signatures keep apart to look
+    /// blocks up by. For 64-bit hashes, that is the whole hash.
+    ///
+    /// Without a strong hash, every block has the same one, zero, so blocks whose weak hashes
+    /// match always look the same.
+    pub fn hash(&self, block: &[u8]) -> u64 {
+        match self {
+            StrongHash::Omitted => 0,
+            StrongHash::SipHash => {
+                // `DefaultHasher::new()` always starts from the same keys. The bytes are fed
+                // directly: hashing the slice would also hash its length as a native-endian
+                // `usize`, giving different hashes on 32-bit or big-endian machines.
+                let mut hasher = DefaultHasher::new();
+                hasher.write(block);
+                hasher.finish()
+            }
+            StrongHash::Xxh3 => embedded::strong_hash(block),
+            // The low half of the 128 bits, which is not xxh3-64.
+            StrongHash::Xxh128 => xxh3_128(block) as u64,
+            StrongHash::Blake3 => self.digest(block).head,
+        }
+    }
+
+    /// The whole strong hash of a block.
+    pub fn digest(&self, block: &[u8]) -> StrongDigest {
+        let mut bytes = [0; MAX_STRONG_HASH_WIDTH];
+        match self {
+            StrongHash::Omitted => {}
+            StrongHash::SipHash | StrongHash::Xxh3 => {
+                bytes[..8].copy_from_slice(&self.hash(block).to_le_bytes())
+            }
+            StrongHash::Xxh128 => bytes[..16].copy_from_slice(&xxh3_128(block).to_le_bytes()),
+            StrongHash::Blake3 => bytes.copy_from_slice(blake3::hash(block).as_bytes()),
+        }
+        let head = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+
+        StrongDigest {
+            head,
+            bytes,
+            width: self.width(),
+        }
+    }
+}
+
+/// The whole strong hash of a block, of the width of the StrongHash which computed it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StrongDigest {
+    /// Its first 64 bits, as `StrongHash::hash` computes them.
+    pub head: u64,
+    bytes: [u8; MAX_STRONG_HASH_WIDTH],
+    /// How many of `bytes` the hash has.
+    width: usize,
+}
+
+impl StrongDigest {
+    /// The bytes of the hash after its first 64 bits, empty for 64-bit hashes.
+    pub fn tail(&self) -> &[u8] {
+        self.bytes.get(8..self.width).unwrap_or_default()
+    }
+}
+
+/// The tails of the strong hashes of consecutive blocks, one after the other.
+///
+/// Each tail has the same width, so the tail of a block is found from its index. They are written
+/// as a single string of bytes, which takes no more room than the tails themselves.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct StrongHashTails {
+    bytes: Vec<u8>,
+}
+
+impl StrongHashTails {
+    /// The tail of the block at `index`, if there is one of `width` bytes.
+    pub fn get(&self, index: usize, width: usize) -> Option<&[u8]> {
+        self.bytes.get(index * width..(index + 1) * width)
+    }
+
+    /// The tails of the blocks in `blocks`, if every one has `width` bytes.
+    pub fn blocks(&self, blocks: Range<usize>, width: usize) -> Option<Self> {
+        let bytes = self.bytes.get(blocks.start * width..blocks.end * width)?;
+        Some(Self {
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn push(&mut self, tail: &[u8]) {
+        self.bytes.extend_from_slice(tail);
+    }
+
+    pub fn extend(&mut self, tails: &StrongHashTails) {
+        self.push(&tails.bytes);
+    }
+
+    /// Whether these are the tails of `block_count` blocks, of `width` bytes each.
+    pub fn fit(&self, block_count: usize, width: usize) -> bool {
+        self.bytes.len() == block_count * width
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl Serialize for StrongHashTails {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.bytes)
+    }
+}
+
+impl<'de> Deserialize<'de> for StrongHashTails {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TailsVisitor;
+
+        impl<'de> Visitor<'de> for TailsVisitor {
+            type Value = StrongHashTails;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "the bytes of strong hash tails")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                Ok(StrongHashTails {
+                    bytes: bytes.to_vec(),
+                })
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(StrongHashTails { bytes })
+            }
+        }
+
+        deserializer.deserialize_byte_buf(TailsVisitor)
+    }
+}
+
+impl fmt::Display for StrongHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StrongHash::SipHash => write!(f, "siphash"),
+            StrongHash::Xxh3 => write!(f, "xxh3"),
+            StrongHash::Xxh128 => write!(f, "xxh128"),
+            StrongHash::Blake3 => write!(f, "blake3"),
+            StrongHash::Omitted => write!(f, "none"),
+        }
+    }
+}
+
+impl FromStr for StrongHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "siphash" => Ok(StrongHash::SipHash),
+            "xxh3" => Ok(StrongHash::Xxh3),
+            "xxh128" => Ok(StrongHash::Xxh128),
+            "blake3" => Ok(StrongHash::Blake3),
+            "none" => Ok(StrongHash::Omitted),
+            _ => Err(format!(
+                r#""{s}" is not a strong hash. Expected "siphash", "xxh3", "xxh128", "blake3" or "none""#
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [StrongHash; 5] = [
+        StrongHash::SipHash,
+        StrongHash::Xxh3,
+        StrongHash::Xxh128,
+        StrongHash::Blake3,
+        StrongHash::Omitted,
+    ];
+
+    #[test]
+    fn strong_hashes_are_parsed_from_their_names() {
+        for strong_hash in ALL {
+            assert_eq!(strong_hash.to_string().parse(), Ok(strong_hash));
+        }
+        assert!("md5".parse::<StrongHash>().is_err());
+    }
+
+    #[test]
+    fn strong_hashes_do_not_depend_on_the_build() {
+        // The published xxh3-64 of the empty input.
+        assert_eq!(StrongHash::Xxh3.hash(b""), 0x2d06_8005_38d3_94c2);
+    }
+
+    #[test]
+    fn digests_start_with_the_hash() {
+        for strong_hash in ALL.into_iter().filter(StrongHash::confirms_blocks) {
+            let digest = strong_hash.digest(b"block");
+
+            assert_eq!(digest.head, strong_hash.hash(b"block"));
+            assert_eq!(digest.tail().len(), strong_hash.tail_width());
+            assert_ne!(digest, strong_hash.digest(b"other"));
+        }
+        // The published xxh3-128 of the empty input, low half first.
+        let digest = StrongHash::Xxh128.digest(b"");
+        assert_eq!(digest.head, 0x6001_c324_468d_497f);
+        assert_eq!(digest.tail(), 0x99aa_06d3_0147_98d8_u64.to_le_bytes());
+    }
+
+    #[test]
+    fn tails_are_written_as_bytes() {
+        let mut tails = StrongHashTails::default();
+        tails.push(&[1, 2]);
+        tails.push(&[3, 255]);
+
+        let serialized = rmp_serde::to_vec(&tails).unwrap();
+        assert_eq!(serialized, [0xc4, 4, 1, 2, 3, 255]);
+        let read_back: StrongHashTails = rmp_serde::from_slice(&serialized).unwrap();
+        assert_eq!(read_back, tails);
+        assert_eq!(tails.get(1, 2), Some([3, 255].as_slice()));
+        assert_eq!(tails.get(2, 2), None);
+    }
+}
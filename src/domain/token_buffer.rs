@@ -0,0 +1,476 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Range;
+
+use serde::de::{EnumAccess, Error, IgnoredAny, SeqAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::domain::{Token, TokenSink};
+
+/// A token stored in a TokenBuffer, borrowing its literals from it.
+///
+/// Serializes exactly like the Token it stands for.
+#[derive(Debug, Eq, PartialEq, Serialize, Clone)]
+#[serde(rename = "Token")]
+pub enum TokenRef<'a> {
+    BlockIndex(usize),
+    ByteLiteral(&'a u8),
+    BlockRange(Range<usize>),
+    ByteLiterals(&'a [u8]),
+    ZeroRun(usize),
+}
+
+impl<'a> TokenRef<'a> {
+    /// Indexes of the basis file blocks this token references, in order. Empty for literals.
+    ///
+    /// Panics for a BlockIndex of `usize::MAX`, which no basis file has and decoding refuses.
+    pub fn block_indexes(&self) -> Range<usize> {
+        match self {
+            TokenRef::BlockIndex(index) => {
+                *index
+                    ..index
+                        .checked_add(1)
+                        .expect("Block indexes are below usize::MAX")
+            }
+            TokenRef::BlockRange(indexes) => indexes.clone(),
+            TokenRef::ByteLiteral(_) | TokenRef::ByteLiterals(_) | TokenRef::ZeroRun(_) => 0..0,
+        }
+    }
+
+    /// Bytes this token writes directly. Empty for block references.
+    pub fn literals(&self) -> &'a [u8] {
+        match self {
+            TokenRef::ByteLiteral(byte) => std::slice::from_ref(*byte),
+            TokenRef::ByteLiterals(bytes) => bytes,
+            TokenRef::BlockIndex(_) | TokenRef::BlockRange(_) | TokenRef::ZeroRun(_) => &[],
+        }
+    }
+
+    /// Zero bytes this token writes. None but for a ZeroRun.
+    pub fn zeros(&self) -> usize {
+        match self {
+            TokenRef::ZeroRun(length) => *length,
+            _ => 0,
+        }
+    }
+
+    pub fn to_token(&self) -> Token {
+        match self {
+            TokenRef::BlockIndex(index) => Token::BlockIndex(*index),
+            TokenRef::ByteLiteral(byte) => Token::ByteLiteral(**byte),
+            TokenRef::BlockRange(indexes) => Token::BlockRange(indexes.clone()),
+            TokenRef::ByteLiterals(bytes) => Token::ByteLiterals(bytes.to_vec()),
+            TokenRef::ZeroRun(length) => Token::ZeroRun(*length),
+        }
+    }
+}
+
+impl<'a> From<&'a Token> for TokenRef<'a> {
+    fn from(token: &'a Token) -> Self {
+        match token {
+            Token::BlockIndex(index) => TokenRef::BlockIndex(*index),
+            Token::ByteLiteral(byte) => TokenRef::ByteLiteral(byte),
+            Token::BlockRange(indexes) => TokenRef::BlockRange(indexes.clone()),
+            Token::ByteLiterals(bytes) => TokenRef::ByteLiterals(bytes),
+            Token::ZeroRun(length) => TokenRef::ZeroRun(*length),
+        }
+    }
+}
+
+impl PartialEq<Token> for TokenRef<'_> {
+    fn eq(&self, other: &Token) -> bool {
+        self.block_indexes() == other.block_indexes()
+            && self.literals() == other.literals()
+            && self.zeros() == other.zeros()
+            && matches!(
+                (self, other),
+                (TokenRef::BlockIndex(_), Token::BlockIndex(_))
+                    | (TokenRef::ByteLiteral(_), Token::ByteLiteral(_))
+                    | (TokenRef::BlockRange(_), Token::BlockRange(_))
+                    | (TokenRef::ByteLiterals(_), Token::ByteLiterals(_))
+                    | (TokenRef::ZeroRun(_), Token::ZeroRun(_))
+            )
+    }
+}
+
+// How a TokenBuffer stores its tokens. Literals live in the pool of the buffer.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Op {
+    BlockIndex(usize),
+    /// Start and end of the references to consecutive blocks.
+    BlockRange(usize, usize),
+    /// A ByteLiteral token for each byte of the pool in this range.
+    ByteLiteralRun(usize, usize),
+    /// A single ByteLiterals token, with the bytes of the pool in this range.
+    ByteLiterals(usize, usize),
+    ZeroRun(usize),
+}
+
+/// The tokens of a Delta, in order, stored without an allocation per token.
+///
+/// Matchers emit a ByteLiteral for each new byte, so tokens are kept as a flat list of references
+/// to blocks and to runs of literals, with every literal byte in a single pool. A run of
+/// ByteLiteral tokens takes a byte each, instead of a whole Token.
+#[derive(Default, Clone)]
+pub struct TokenBuffer {
+    ops: Vec<Op>,
+    literals: Vec<u8>,
+    /// Number of tokens, as runs of literals hold many.
+    len: usize,
+    /// Tokens of kinds this build does not know, left out when decoding the buffer.
+    unknown: usize,
+}
+
+impl TokenBuffer {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Tokens of kinds added after this build, which were left out when decoding the buffer.
+    pub fn unknown_tokens(&self) -> usize {
+        self.unknown
+    }
+
+    #[inline]
+    pub fn push(&mut self, token: Token) {
+        let start = self.literals.len();
+        match token {
+            Token::BlockIndex(index) => self.ops.push(Op::BlockIndex(index)),
+            Token::BlockRange(indexes) => self.ops.push(Op::BlockRange(indexes.start, indexes.end)),
+            Token::ByteLiteral(byte) => {
+                self.literals.push(byte);
+                match self.ops.last_mut() {
+                    Some(Op::ByteLiteralRun(_, end)) if *end == start => *end += 1,
+                    _ => self.ops.push(Op::ByteLiteralRun(start, start + 1)),
+                }
+            }
+            Token::ByteLiterals(bytes) => self.push_byte_literals(&bytes),
+            Token::ZeroRun(length) => self.ops.push(Op::ZeroRun(length)),
+        }
+        self.len += 1;
+    }
+
+    // Like pushing a ByteLiterals token, without building its Vec first.
+    fn push_byte_literals(&mut self, bytes: &[u8]) {
+        let start = self.literals.len();
+        self.literals.extend_from_slice(bytes);
+        self.ops.push(Op::ByteLiterals(start, self.literals.len()));
+    }
+
+    /// Removes every token, keeping the memory for the next ones.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.literals.clear();
+        self.len = 0;
+        self.unknown = 0;
+    }
+
+    /// Pushes every token of `other`, counting the unknown tokens it left out.
+    pub fn append(&mut self, other: &TokenBuffer) {
+        self.extend(other);
+        self.unknown += other.unknown;
+    }
+
+    pub fn iter(&self) -> Tokens<'_> {
+        Tokens {
+            ops: self.ops.iter(),
+            literals: &self.literals,
+            run: 0..0,
+            remaining: self.len,
+        }
+    }
+
+    pub fn contains(&self, token: &Token) -> bool {
+        self.iter().any(|candidate| candidate == *token)
+    }
+
+    pub fn to_vec(&self) -> Vec<Token> {
+        self.iter().map(|token| token.to_token()).collect()
+    }
+}
+
+/// Iterates over the tokens of a TokenBuffer, in order.
+pub struct Tokens<'a> {
+    ops: std::slice::Iter<'a, Op>,
+    literals: &'a [u8],
+    /// Bytes of the current run of ByteLiteral tokens not yet returned.
+    run: Range<usize>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = TokenRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.run.is_empty() {
+            let token = match *self.ops.next()? {
+                Op::BlockIndex(index) => TokenRef::BlockIndex(index),
+                Op::BlockRange(start, end) => TokenRef::BlockRange(start..end),
+                Op::ByteLiterals(start, end) => TokenRef::ByteLiterals(&self.literals[start..end]),
+                Op::ZeroRun(length) => TokenRef::ZeroRun(length),
+                Op::ByteLiteralRun(start, end) => {
+                    self.run = start..end;
+                    return self.next();
+                }
+            };
+            self.remaining -= 1;
+            return Some(token);
+        }
+        let byte = &self.literals[self.run.start];
+        self.run.start += 1;
+        self.remaining -= 1;
+        Some(TokenRef::ByteLiteral(byte))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Tokens<'_> {}
+
+impl<'a> IntoIterator for &'a TokenBuffer {
+    type Item = TokenRef<'a>;
+    type IntoIter = Tokens<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl FromIterator<Token> for TokenBuffer {
+    fn from_iter<I: IntoIterator<Item = Token>>(tokens: I) -> Self {
+        let mut buffer = Self::default();
+        buffer.extend(tokens);
+        buffer
+    }
+}
+
+impl Extend<Token> for TokenBuffer {
+    fn extend<I: IntoIterator<Item = Token>>(&mut self, tokens: I) {
+        tokens.into_iter().for_each(|token| self.push(token));
+    }
+}
+
+impl<'a> Extend<TokenRef<'a>> for TokenBuffer {
+    fn extend<I: IntoIterator<Item = TokenRef<'a>>>(&mut self, tokens: I) {
+        for token in tokens {
+            match token {
+                TokenRef::ByteLiterals(bytes) => {
+                    self.push_byte_literals(bytes);
+                    self.len += 1;
+                }
+                token => self.push(token.to_token()),
+            }
+        }
+    }
+}
+
+impl From<Vec<Token>> for TokenBuffer {
+    fn from(tokens: Vec<Token>) -> Self {
+        tokens.into_iter().collect()
+    }
+}
+
+impl TokenSink for TokenBuffer {
+    fn push(&mut self, token: Token) -> eyre::Result<()> {
+        TokenBuffer::push(self, token);
+        Ok(())
+    }
+}
+
+// Buffers holding the same tokens are equal, however their literals were pushed.
+impl PartialEq for TokenBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for TokenBuffer {}
+
+impl PartialEq<Vec<Token>> for TokenBuffer {
+    fn eq(&self, other: &Vec<Token>) -> bool {
+        self.len == other.len() && self.iter().zip(other).all(|(ours, theirs)| ours == *theirs)
+    }
+}
+
+impl fmt::Debug for TokenBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// Laid out as a sequence of Tokens, so artifacts are the same as before tokens were buffered.
+impl Serialize for TokenBuffer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TokenBuffer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TokenBufferVisitor;
+
+        impl<'de> Visitor<'de> for TokenBufferVisitor {
+            type Value = TokenBuffer;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                write!(f, "a sequence of tokens")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut buffer = TokenBuffer::default();
+                while let Some(token) = seq.next_element::<DecodedToken>()? {
+                    match token {
+                        DecodedToken::Known(token) => buffer.push(token),
+                        DecodedToken::Unknown => buffer.unknown += 1,
+                    }
+                }
+                Ok(buffer)
+            }
+        }
+
+        deserializer.deserialize_seq(TokenBufferVisitor)
+    }
+}
+
+// A Token as decoded from an artifact, which may be of a kind added after this build.
+//
+// Each token is a MessagePack value, keyed by the index (or name) of its kind, and values tell their
+// own length: tokens of unknown kinds are skipped whole, leaving the next ones readable.
+enum DecodedToken {
+    Known(Token),
+    Unknown,
+}
+
+// Kinds of tokens in the order they were added, which gives their index. New kinds go last.
+const TOKEN_KINDS: &[&str] = &[
+    "BlockIndex",
+    "ByteLiteral",
+    "BlockRange",
+    "ByteLiterals",
+    "ZeroRun",
+];
+
+struct TokenKind(Option<usize>);
+
+impl<'de> Deserialize<'de> for TokenKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TokenKindVisitor;
+
+        impl<'de> Visitor<'de> for TokenKindVisitor {
+            type Value = TokenKind;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                write!(f, "the index or name of a kind of token")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, index: u64) -> Result<Self::Value, E> {
+                Ok(TokenKind(
+                    (index < TOKEN_KINDS.len() as u64).then_some(index as usize),
+                ))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                Ok(TokenKind(TOKEN_KINDS.iter().position(|kind| *kind == name)))
+            }
+        }
+
+        deserializer.deserialize_identifier(TokenKindVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for DecodedToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DecodedTokenVisitor;
+
+        impl<'de> Visitor<'de> for DecodedTokenVisitor {
+            type Value = DecodedToken;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                write!(f, "a token")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+                let (TokenKind(kind), content) = data.variant()?;
+                let token = match kind {
+                    Some(0) => {
+                        let index: usize = content.newtype_variant()?;
+                        // Every block it could reference ends before usize::MAX.
+                        if index.checked_add(1).is_none() {
+                            return Err(A::Error::custom(format!(
+                                "Block index {index} is past every block"
+                            )));
+                        }
+                        Token::BlockIndex(index)
+                    }
+                    Some(1) => Token::ByteLiteral(content.newtype_variant()?),
+                    Some(2) => Token::BlockRange(content.newtype_variant()?),
+                    Some(3) => Token::ByteLiterals(content.newtype_variant()?),
+                    Some(4) => Token::ZeroRun(content.newtype_variant()?),
+                    _ => {
+                        content.newtype_variant::<IgnoredAny>()?;
+                        return Ok(DecodedToken::Unknown);
+                    }
+                };
+                Ok(DecodedToken::Known(token))
+            }
+        }
+
+        deserializer.deserialize_enum("Token", TOKEN_KINDS, DecodedTokenVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_literals_are_stored_in_a_single_run() {
+        let tokens = vec![
+            Token::ByteLiteral(b'a'),
+            Token::ByteLiteral(b'b'),
+            Token::BlockRange(2..4),
+            Token::ByteLiterals(b"cd".to_vec()),
+            Token::ByteLiteral(b'e'),
+        ];
+
+        let buffer = TokenBuffer::from(tokens.clone());
+
+        assert_eq!(buffer.ops.len(), 4);
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.to_vec(), tokens);
+    }
+
+    #[test]
+    fn buffers_serialize_like_vectors_of_tokens() {
+        let tokens = vec![
+            Token::BlockIndex(7),
+            Token::ByteLiteral(b'x'),
+            Token::ByteLiteral(b'y'),
+            Token::ByteLiterals(b"zz".to_vec()),
+            Token::BlockRange(1..3),
+            Token::ZeroRun(4096),
+        ];
+        let buffer = TokenBuffer::from(tokens.clone());
+
+        let serialized = rmp_serde::to_vec(&buffer).unwrap();
+
+        assert_eq!(serialized, rmp_serde::to_vec(&tokens).unwrap());
+        let deserialized: TokenBuffer = rmp_serde::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, buffer);
+    }
+
+    #[test]
+    fn block_indexes_past_every_block_are_refused() {
+        let serialized = rmp_serde::to_vec(&vec![Token::BlockIndex(usize::MAX)]).unwrap();
+
+        let deserialized: Result<TokenBuffer, _> = rmp_serde::from_slice(&serialized);
+
+        assert!(deserialized.is_err());
+    }
+}
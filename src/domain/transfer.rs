@@ -0,0 +1,639 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+use bytes::Bytes;
+use eyre::{eyre, Context};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    apply_delta_from_reader, apply_delta_with_mode, calculate_strong_hash, check_chunk_size,
+    compute_delta_with_mode, compute_fixed_delta, compute_signature_with_mode,
+    compute_sliding_rolling_hashes, list_files, looks_compressed, ChunkingMode, Delta,
+    FilePolicies, FileSignature, MatchingOptions, SharedBasis, DEFAULT_MAX_OUTPUT_SIZE,
+};
+use crate::help::Help;
+use crate::io_utils;
+
+/// How a file was brought up to date.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TransferMethod {
+    /// A Signature went one way, and a Delta the other way.
+    #[default]
+    Delta,
+    /// The Signature and the Delta would have been larger than the file, so it was sent instead.
+    WholeFile,
+    /// Same as WholeFile, with the file deflated.
+    CompressedWholeFile,
+}
+
+impl fmt::Display for TransferMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferMethod::Delta => write!(f, "Delta"),
+            TransferMethod::WholeFile => write!(f, "whole file"),
+            TransferMethod::CompressedWholeFile => write!(f, "compressed whole file"),
+        }
+    }
+}
+
+/// How `transfer_directory` brings files up to date.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferOptions {
+    /// The size for each block.
+    pub chunk_size: usize,
+    /// How files are divided into blocks.
+    pub mode: ChunkingMode,
+    /// How the matcher is tuned, in fixed mode.
+    pub matching: MatchingOptions,
+    /// Whether files are deflated when sent whole.
+    pub compress_whole_files: bool,
+    /// Whether Deltas reference blocks of every basis file, in fixed mode.
+    pub share_blocks: bool,
+    /// Whether blocks are looked for even in files which look already compressed.
+    pub match_compressed: bool,
+    /// Other settings for some of the files, by path.
+    pub policies: FilePolicies,
+}
+
+/// What was exchanged to bring a single file up to date.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileTransfer {
+    /// Relative to both roots, with `/` as separator.
+    pub path: String,
+    /// Size of the sender's file, which is what sending it directly would cost.
+    pub file_size: u64,
+    pub signature_size: u64,
+    pub delta_size: u64,
+    /// Whether the Delta or the file itself was sent.
+    pub method: TransferMethod,
+    /// Bytes actually sent: the Signature and the Delta, or the (compressed) file.
+    pub sent_size: u64,
+    /// Whether it was sent whole without matching, as it looked compressed.
+    pub looked_compressed: bool,
+}
+
+/// What was exchanged to bring a whole directory tree up to date.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TransferReport {
+    /// Sorted by path.
+    pub files: Vec<FileTransfer>,
+    /// The Signature of every basis file, when blocks were shared.
+    pub shared_signature_size: u64,
+}
+
+impl TransferReport {
+    /// Size of every file, which is what sending them directly would cost.
+    pub fn total_file_size(&self) -> u64 {
+        self.files.iter().map(|file| file.file_size).sum()
+    }
+
+    /// Size of every Signature and Delta, which is what the rsync algorithm costs.
+    pub fn total_size_using_rsync(&self) -> u64 {
+        self.shared_signature_size
+            + self
+                .files
+                .iter()
+                .map(|file| file.signature_size + file.delta_size)
+                .sum::<u64>()
+    }
+
+    /// Bytes actually sent, with files sent whole when their Delta was not worth it.
+    pub fn total_sent_size(&self) -> u64 {
+        self.shared_signature_size + self.files.iter().map(|file| file.sent_size).sum::<u64>()
+    }
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            writeln!(
+                f,
+                "{}: {} bytes, Signature {} bytes, Delta {} bytes, sent as {} ({} bytes)",
+                file.path,
+                file.file_size,
+                file.signature_size,
+                file.delta_size,
+                file.method,
+                file.sent_size
+            )?;
+        }
+        if self.shared_signature_size > 0 {
+            writeln!(
+                f,
+                "Signature of every basis file: {} bytes",
+                self.shared_signature_size
+            )?;
+        }
+        let sent_size = self.total_sent_size();
+        let compression_ratio = self.total_file_size() as f64 / sent_size.max(1) as f64;
+        let whole_files = self
+            .files
+            .iter()
+            .filter(|file| file.method != TransferMethod::Delta)
+            .count();
+        write!(
+            f,
+            "Transferred {} files: {} bytes sent as {} bytes, {} of them whole \
+             (compression ratio {:.2})",
+            self.files.len(),
+            self.total_file_size(),
+            sent_size,
+            whole_files,
+            compression_ratio
+        )
+    }
+}
+
+/// Brings every file of the receiver's tree up to date with the sender's, playing both roles.
+///
+/// For each file of the sender, the receiver computes the Signature of its own version (empty
+/// if it has none), the sender computes the Delta, and the receiver applies it. Both artifacts
+/// are encoded as they would be sent, and the recreated file is checked against the sender's
+/// before it is written. Files only the receiver has are left alone.
+///
+/// With `share_blocks`, the receiver instead sends a single Signature of all its files, laid out
+/// as a SharedBasis, and each Delta may reference blocks of any of them: content moved between
+/// files is matched too. Deltas are applied to the files as they were before the transfer.
+///
+/// Files which look already compressed (see `looks_compressed`) are sent whole without looking
+/// for blocks, unless `match_compressed` is set or they did not change. With `share_blocks`, they
+/// are still matched, as they may have been moved whole from another file.
+///
+/// # Arguments
+/// * `sender_root` - The directory with the updated files.
+/// * `receiver_root` - The directory with the basis files, updated in place.
+/// * `options` - How files are synced, unless their FilePolicy says otherwise.
+///
+pub fn transfer_directory(
+    sender_root: &Path,
+    receiver_root: &Path,
+    options: &TransferOptions,
+) -> eyre::Result<TransferReport> {
+    if options.share_blocks {
+        return transfer_directory_sharing_blocks(sender_root, receiver_root, options);
+    }
+    let mut report = TransferReport::default();
+    for entry in list_files(sender_root)? {
+        let policy = options.policies.for_path(&entry.path);
+        let chunk_size = policy
+            .and_then(|policy| policy.chunk_size)
+            .unwrap_or(options.chunk_size);
+        let mode = policy
+            .and_then(|policy| policy.mode)
+            .unwrap_or(options.mode);
+        let compress_whole_files = policy
+            .and_then(|policy| policy.compress)
+            .unwrap_or(options.compress_whole_files);
+        let basis_filename = receiver_root.join(&entry.relative_path);
+        let basis_file = match fs::read(&basis_filename) {
+            Ok(content) => Bytes::from(content),
+            Err(error) if error.kind() == ErrorKind::NotFound => Bytes::new(),
+            Err(error) => {
+                return Err(error).wrap_err(format!(r#"Could not read file "{}""#, entry.path))
+            }
+        };
+        let updated_file = io_utils::attempt_to_read_file(sender_root.join(&entry.relative_path))?;
+        let file_size = updated_file.len() as u64;
+        let strong_hash = calculate_strong_hash(&updated_file);
+        // The receiver would tell an unchanged file from the strong hash of its whole content.
+        if !options.match_compressed
+            && updated_file != basis_file
+            && looks_compressed(&updated_file)
+        {
+            check_and_write_recreated(&basis_filename, &entry.path, updated_file, strong_hash)?;
+            report.files.push(FileTransfer {
+                path: entry.path,
+                file_size,
+                signature_size: 0,
+                delta_size: 0,
+                // Deflating it again would not make it smaller either.
+                method: TransferMethod::WholeFile,
+                sent_size: file_size,
+                looked_compressed: true,
+            });
+            continue;
+        }
+
+        let signature = compute_signature_with_mode(basis_file.clone(), chunk_size, mode);
+        let signature_bytes = Bytes::try_from(signature.clone())?;
+        let mut delta = compute_delta_with_mode(
+            signature.clone(),
+            updated_file.clone(),
+            chunk_size,
+            mode,
+            &options.matching,
+        );
+        delta.optimize_against(&signature);
+        let delta_bytes = Bytes::try_from(delta)?;
+
+        let signature_size = signature_bytes.len() as u64;
+        let delta_size = delta_bytes.len() as u64;
+        // Both sides are played here, so the file is sent whole before anything else is sent.
+        let (method, sent_size, recreated) = if signature_size + delta_size > file_size {
+            let (method, encoded) = encode_whole_file(&updated_file, compress_whole_files)?;
+            let sent_size = encoded.len() as u64;
+            let recreated = decode_whole_file(encoded, method, DEFAULT_MAX_OUTPUT_SIZE);
+            (method, sent_size, recreated)
+        } else {
+            let recreated = apply_delta_with_mode(
+                basis_file,
+                Delta::try_from(delta_bytes)?,
+                chunk_size,
+                mode,
+                DEFAULT_MAX_OUTPUT_SIZE,
+            );
+            (
+                TransferMethod::Delta,
+                signature_size + delta_size,
+                recreated,
+            )
+        };
+        let recreated =
+            recreated.wrap_err(format!(r#"Could not recreate file "{}""#, entry.path))?;
+        check_and_write_recreated(&basis_filename, &entry.path, recreated, strong_hash)?;
+        report.files.push(FileTransfer {
+            path: entry.path,
+            file_size,
+            signature_size,
+            delta_size,
+            method,
+            sent_size,
+            looked_compressed: false,
+        });
+    }
+
+    Ok(report)
+}
+
+// Like `transfer_directory`, with a single Signature of every basis file.
+fn transfer_directory_sharing_blocks(
+    sender_root: &Path,
+    receiver_root: &Path,
+    options: &TransferOptions,
+) -> eyre::Result<TransferReport> {
+    let chunk_size = options.chunk_size;
+    if options.mode != ChunkingMode::Fixed {
+        return Err(eyre!("Blocks are only shared between files in fixed mode"));
+    }
+    if options.policies.set_chunking() {
+        return Err(eyre!(
+            "Blocks shared between files all have the same size, so policies can not set it"
+        ))
+        .suggestion("Only set `compress` in the policies, or do not share blocks.");
+    }
+    check_chunk_size(chunk_size)?;
+    let mut shared_basis = SharedBasis::read(receiver_root, chunk_size)?;
+    let signature = shared_basis.signature();
+    let signature_bytes = Bytes::try_from(signature.clone())?;
+    let signature = FileSignature::try_from(signature_bytes.clone())?;
+    let mut report = TransferReport {
+        shared_signature_size: signature_bytes.len() as u64,
+        ..Default::default()
+    };
+
+    for entry in list_files(sender_root)? {
+        let basis_filename = receiver_root.join(&entry.relative_path);
+        let updated_file = io_utils::attempt_to_read_file(sender_root.join(&entry.relative_path))?;
+        let file_size = updated_file.len() as u64;
+        let strong_hash = calculate_strong_hash(&updated_file);
+
+        let rolling_hashes =
+            compute_sliding_rolling_hashes(&updated_file, chunk_size, signature.weak_hash);
+        let mut delta = compute_fixed_delta(
+            &signature,
+            &updated_file,
+            chunk_size,
+            &rolling_hashes,
+            &options.matching,
+        );
+        delta.optimize_against(&signature);
+        delta.drop_costly_references(&updated_file, chunk_size);
+        let delta_bytes = Bytes::try_from(delta)?;
+        let delta_size = delta_bytes.len() as u64;
+
+        // The Signature was sent already, so only the Delta is weighed against the file.
+        let (method, sent_size, recreated) = if delta_size > file_size {
+            let compress = options
+                .policies
+                .for_path(&entry.path)
+                .and_then(|policy| policy.compress)
+                .unwrap_or(options.compress_whole_files);
+            let (method, encoded) = encode_whole_file(&updated_file, compress)?;
+            let sent_size = encoded.len() as u64;
+            let recreated = decode_whole_file(encoded, method, DEFAULT_MAX_OUTPUT_SIZE);
+            (method, sent_size, recreated)
+        } else {
+            let mut recreated = Vec::new();
+            let recreated = apply_delta_from_reader(
+                &mut shared_basis,
+                &Delta::try_from(delta_bytes)?,
+                chunk_size,
+                DEFAULT_MAX_OUTPUT_SIZE,
+                &mut recreated,
+            )
+            .map(|_| Bytes::from(recreated));
+            (TransferMethod::Delta, delta_size, recreated)
+        };
+        let recreated =
+            recreated.wrap_err(format!(r#"Could not recreate file "{}""#, entry.path))?;
+        check_and_write_recreated(&basis_filename, &entry.path, recreated, strong_hash)?;
+        report.files.push(FileTransfer {
+            path: entry.path,
+            file_size,
+            signature_size: 0,
+            delta_size,
+            method,
+            sent_size,
+            looked_compressed: false,
+        });
+    }
+
+    Ok(report)
+}
+
+// Writes the recreated file over the basis file, once it is checked to be the sender's.
+fn check_and_write_recreated(
+    basis_filename: &Path,
+    path: &str,
+    recreated: Bytes,
+    strong_hash: u64,
+) -> eyre::Result<()> {
+    if calculate_strong_hash(&recreated) != strong_hash {
+        return Err(eyre!(
+            r#"Recreated file "{path}" does not match the sender's file"#
+        ));
+    }
+
+    if let Some(parent) = basis_filename.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    io_utils::write_to_file(basis_filename, recreated)?;
+    Ok(())
+}
+
+/// Encodes `file` to be sent instead of a Delta, deflated if `compress` is set.
+pub fn encode_whole_file(file: &Bytes, compress: bool) -> eyre::Result<(TransferMethod, Bytes)> {
+    if !compress {
+        return Ok((TransferMethod::WholeFile, file.clone()));
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(file)?;
+
+    Ok((
+        TransferMethod::CompressedWholeFile,
+        encoder.finish()?.into(),
+    ))
+}
+
+/// Recovers a file sent whole with `method`, refusing files larger than `max_output_size`.
+pub fn decode_whole_file(
+    encoded: Bytes,
+    method: TransferMethod,
+    max_output_size: u64,
+) -> eyre::Result<Bytes> {
+    let file = match method {
+        TransferMethod::Delta => return Err(eyre!("A Delta is not a whole file")),
+        TransferMethod::WholeFile => encoded,
+        TransferMethod::CompressedWholeFile => {
+            // One byte more than allowed is enough to tell the file is too large.
+            let mut file = Vec::new();
+            DeflateDecoder::new(&encoded[..])
+                .take(max_output_size.saturating_add(1))
+                .read_to_end(&mut file)
+                .wrap_err("Could not decompress the whole file")?;
+            file.into()
+        }
+    };
+    if file.len() as u64 > max_output_size {
+        return Err(eyre!(
+            "Recreated file is larger than the maximum output size ({max_output_size} bytes)"
+        ));
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::domain::{compute_manifest, StrongHash};
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    fn create_tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = scratch_directory(&format!("transfer_{name}"));
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn transfer_makes_the_receiver_match_the_sender() {
+        let sender = create_tree(
+            "sender",
+            &[
+                ("same.txt", "unchanged content"),
+                ("nested/changed.txt", "new content"),
+                ("nested/added.txt", "only the sender has this"),
+            ],
+        );
+        let receiver = create_tree(
+            "receiver",
+            &[
+                ("same.txt", "unchanged content"),
+                ("nested/changed.txt", "old content"),
+                ("extra.txt", "only the receiver has this"),
+            ],
+        );
+
+        let report = transfer_directory(
+            &sender,
+            &receiver,
+            &TransferOptions {
+                chunk_size: 4,
+                mode: ChunkingMode::Fixed,
+                matching: MatchingOptions::default(),
+                compress_whole_files: false,
+                share_blocks: false,
+                match_compressed: false,
+                policies: FilePolicies::default(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.files.len(), 3);
+        let sender_manifest = compute_manifest(&sender).unwrap();
+        let check = crate::domain::check_manifest(&receiver, &sender_manifest).unwrap();
+        assert!(check.modified.is_empty() && check.missing.is_empty());
+        assert_eq!(check.added, vec!["extra.txt".to_string()]);
+    }
+
+    #[test]
+    fn files_are_sent_whole_when_their_delta_is_larger() {
+        let updated = "completely rewritten ".repeat(50);
+        let sender = create_tree(
+            "whole_sender",
+            &[
+                ("rewritten.txt", &updated),
+                ("same.txt", &"same ".repeat(50)),
+            ],
+        );
+        let receiver = create_tree(
+            "whole_receiver",
+            &[
+                ("rewritten.txt", "old content"),
+                ("same.txt", &"same ".repeat(50)),
+            ],
+        );
+
+        let report = transfer_directory(
+            &sender,
+            &receiver,
+            &TransferOptions {
+                chunk_size: 64,
+                mode: ChunkingMode::Fixed,
+                matching: MatchingOptions::default(),
+                compress_whole_files: true,
+                share_blocks: false,
+                match_compressed: false,
+                policies: FilePolicies::default(),
+            },
+        )
+        .unwrap();
+
+        let methods: Vec<_> = report.files.iter().map(|file| file.method).collect();
+        assert_eq!(
+            methods,
+            vec![TransferMethod::CompressedWholeFile, TransferMethod::Delta]
+        );
+        assert!(report.files[0].sent_size < updated.len() as u64);
+        assert_eq!(
+            fs::read(receiver.join("rewritten.txt")).unwrap(),
+            updated.as_bytes()
+        );
+    }
+
+    #[test]
+    fn content_moved_between_files_is_referenced() {
+        let module = "fn helper() -> u32 { 42 }\n".repeat(20);
+        let sender = create_tree(
+            "shared_sender",
+            &[("main.rs", "fn main() {}\n"), ("moved/helper.rs", &module)],
+        );
+        let receiver = create_tree(
+            "shared_receiver",
+            &[("main.rs", &format!("fn main() {{}}\n{module}"))],
+        );
+
+        let report = transfer_directory(
+            &sender,
+            &receiver,
+            &TransferOptions {
+                chunk_size: 16,
+                mode: ChunkingMode::Fixed,
+                matching: MatchingOptions::default(),
+                compress_whole_files: false,
+                share_blocks: true,
+                match_compressed: false,
+                policies: FilePolicies::default(),
+            },
+        )
+        .unwrap();
+
+        // The module only exists in the receiver's main.rs, but is not sent again.
+        let moved = &report.files[1];
+        assert_eq!(moved.path, "moved/helper.rs");
+        assert_eq!(moved.method, TransferMethod::Delta);
+        assert!(moved.delta_size < module.len() as u64 / 4);
+        assert_eq!(
+            fs::read(receiver.join("moved/helper.rs")).unwrap(),
+            module.as_bytes()
+        );
+        assert_eq!(
+            fs::read(receiver.join("main.rs")).unwrap(),
+            b"fn main() {}\n"
+        );
+    }
+
+    #[test]
+    fn policies_choose_how_matching_files_are_synced() {
+        // Rows long enough for the Signature of their lines to be much smaller than them, all in
+        // less than a block of 4096 bytes.
+        let rows = |changed: usize| -> String {
+            (0..30)
+                .map(|row| match row == changed {
+                    true => format!("row {row},changed\n"),
+                    false => format!("row {row},{}\n", "value ".repeat(16)),
+                })
+                .collect()
+        };
+        let sender = create_tree("policy_sender", &[("table.csv", &rows(15))]);
+        let receiver = create_tree("policy_receiver", &[("table.csv", &rows(30))]);
+        let options = TransferOptions {
+            chunk_size: 4096,
+            mode: ChunkingMode::Fixed,
+            matching: MatchingOptions::default(),
+            compress_whole_files: false,
+            share_blocks: false,
+            match_compressed: false,
+            policies: "*.csv mode=lines".parse().unwrap(),
+        };
+
+        let report = transfer_directory(&sender, &receiver, &options).unwrap();
+
+        // A single block of 4096 bytes would have been sent whole.
+        assert_eq!(report.files[0].method, TransferMethod::Delta);
+        assert_eq!(
+            fs::read(receiver.join("table.csv")).unwrap(),
+            rows(15).as_bytes()
+        );
+    }
+
+    #[test]
+    fn compressed_files_are_sent_whole_without_matching() {
+        // Hashes of consecutive numbers, which deflate can not shrink.
+        let noise = |seed: u64| -> Vec<u8> {
+            (seed..seed + 20_000)
+                .flat_map(|number| StrongHash::Xxh3.hash(&number.to_le_bytes()).to_le_bytes())
+                .collect()
+        };
+        let sender = create_tree("compressed_sender", &[]);
+        let receiver = create_tree("compressed_receiver", &[]);
+        fs::create_dir_all(&sender).unwrap();
+        fs::create_dir_all(&receiver).unwrap();
+        fs::write(sender.join("video.mp4"), noise(1)).unwrap();
+        fs::write(receiver.join("video.mp4"), noise(0)).unwrap();
+        let options = |match_compressed| TransferOptions {
+            chunk_size: 1024,
+            mode: ChunkingMode::Fixed,
+            matching: MatchingOptions::default(),
+            compress_whole_files: true,
+            share_blocks: false,
+            match_compressed,
+            policies: FilePolicies::default(),
+        };
+
+        // Most blocks only moved, and would have been found by matching.
+        let matched = transfer_directory(&sender, &receiver, &options(true)).unwrap();
+        assert_eq!(matched.files[0].method, TransferMethod::Delta);
+        fs::write(receiver.join("video.mp4"), noise(0)).unwrap();
+        let report = transfer_directory(&sender, &receiver, &options(false)).unwrap();
+
+        let file = &report.files[0];
+        assert!(file.looked_compressed);
+        assert_eq!(file.method, TransferMethod::WholeFile);
+        assert_eq!(file.signature_size, 0);
+        assert_eq!(fs::read(receiver.join("video.mp4")).unwrap(), noise(1));
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use eyre::eyre;
+
+#[cfg(feature = "archive")]
+use crate::domain::ArchiveTransform;
+use crate::help::Help;
+
+/// A reversible transformation of a file's content, applied before dividing it into blocks.
+///
+/// Some formats hide their similarities (e.g. compressed containers), so two versions of a file
+/// share almost no blocks. A transform can turn them into a representation which does, as long as
+/// `decode` gives back the original content exactly.
+pub trait ContentTransform: Send + Sync {
+    /// Name used to select the transform, which is also recorded in the Delta.
+    fn name(&self) -> &str;
+
+    /// Applied to the basis file (before computing its Signature and before patching)
+    /// and to the updated file (before computing the Delta).
+    fn encode(&self, content: Bytes) -> eyre::Result<Bytes>;
+
+    /// Applied to the recreated file after patching. Must reverse `encode`.
+    fn decode(&self, content: Bytes) -> eyre::Result<Bytes>;
+}
+
+/// The set of transforms that can be selected by name.
+#[derive(Default)]
+pub struct TransformRegistry {
+    transforms: BTreeMap<String, Box<dyn ContentTransform>>,
+}
+
+impl TransformRegistry {
+    /// A registry with every transform this crate provides, as far as its features enable them.
+    #[cfg_attr(not(feature = "archive"), allow(unused_mut))]
+    pub fn with_builtin_transforms() -> Self {
+        let mut registry = Self::default();
+        #[cfg(feature = "archive")]
+        registry.register(Box::new(ArchiveTransform));
+        registry
+    }
+
+    /// Registers a transform, replacing any other with the same name.
+    pub fn register(&mut self, transform: Box<dyn ContentTransform>) {
+        self.transforms
+            .insert(transform.name().to_string(), transform);
+    }
+
+    pub fn get(&self, name: &str) -> eyre::Result<&dyn ContentTransform> {
+        match self.transforms.get(name) {
+            Some(transform) => Ok(transform.as_ref()),
+            None => Err(eyre!(r#"There is no transform named "{name}""#)).suggestion(format!(
+                "Available transforms: [{}]",
+                self.names().collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.transforms.keys().map(String::as_str)
+    }
+
+    /// Encodes `content` with the transform named `name`, or returns it unchanged if there is none.
+    pub fn encode(&self, name: Option<&str>, content: Bytes) -> eyre::Result<Bytes> {
+        match name {
+            Some(name) => self.get(name)?.encode(content),
+            None => Ok(content),
+        }
+    }
+
+    /// Decodes `content` with the transform named `name`, or returns it unchanged if there is none.
+    pub fn decode(&self, name: Option<&str>, content: Bytes) -> eyre::Result<Bytes> {
+        match name {
+            Some(name) => self.get(name)?.decode(content),
+            None => Ok(content),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{apply_delta, compute_delta_to_our_file, compute_signature};
+
+    use super::*;
+
+    // Stores files backwards, so their tail becomes their head.
+    struct Reverse;
+
+    impl ContentTransform for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn encode(&self, content: Bytes) -> eyre::Result<Bytes> {
+            Ok(content.iter().rev().copied().collect::<Vec<_>>().into())
+        }
+
+        fn decode(&self, content: Bytes) -> eyre::Result<Bytes> {
+            self.encode(content)
+        }
+    }
+
+    #[test]
+    fn registered_transforms_are_applied_around_the_algorithm() {
+        let test_chunk_size = 3;
+        let mut registry = TransformRegistry::default();
+        registry.register(Box::new(Reverse));
+        let transform = Some("reverse");
+
+        let basis_file = Bytes::from("ABCDEFGH");
+        let updated_file = Bytes::from("xABCDEFGH");
+
+        let encoded_basis_file = registry.encode(transform, basis_file).unwrap();
+        let signature = compute_signature(encoded_basis_file.clone(), test_chunk_size);
+        let encoded_updated_file = registry.encode(transform, updated_file.clone()).unwrap();
+        let delta = compute_delta_to_our_file(signature, encoded_updated_file, test_chunk_size);
+        let recreated = apply_delta(encoded_basis_file, delta, test_chunk_size).unwrap();
+
+        assert_eq!(registry.decode(transform, recreated).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn unknown_transforms_are_an_error() {
+        let registry = TransformRegistry::default();
+
+        assert!(registry.get("reverse").is_err());
+        assert!(registry.encode(None, Bytes::from("ABC")).is_ok());
+    }
+}
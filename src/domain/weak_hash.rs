@@ -0,0 +1,203 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Range;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::embedded::{self, RollingHasher};
+
+/// The rolling hash of the blocks of a Signature, which `delta` computes for every window of the
+/// updated file to find blocks the basis file has.
+///
+/// Blocks whose weak hashes match are only reused once their strong hashes match too, so any of
+/// them finds the same blocks: the choice trades how often strong hashes are computed for
+/// nothing against how fast windows roll. A Gear hash only keeps the last 64 bytes of a block, so
+/// larger blocks which end with the same bytes collide. It is recorded in the Signature.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum WeakHash {
+    /// A polynomial hash modulo a prime, the one Signatures always had: rarely collides.
+    #[default]
+    Polynomial,
+    /// Two 16-bit sums of the bytes, as rsync computes them: cheaper to roll, but blocks with the
+    /// same bytes in a slightly different order collide.
+    Adler32,
+    /// As content-defined chunkers roll: cheapest, but blocks ending alike collide.
+    Gear,
+}
+
+impl WeakHash {
+    /// The weak hash of a whole block.
+    pub fn hash(&self, block: &[u8]) -> u64 {
+        match self {
+            WeakHash::Polynomial => embedded::rolling_hash(block),
+            WeakHash::Adler32 => AdlerSums::of(block).hash(),
+            WeakHash::Gear => block.iter().fold(0, |hash, &byte| gear_push(hash, byte)),
+        }
+    }
+
+    /// The weak hashes of the windows of `window_size` bytes of `file` starting at each offset of
+    /// `starts`, rolling each one from the previous window.
+    ///
+    /// Every window must be inside `file`. A window's hash only depends on its bytes, so it is
+    /// the same as `hash` of the window, wherever `starts` begins.
+    pub fn roll(&self, file: &[u8], window_size: usize, starts: Range<usize>) -> Vec<u64> {
+        let mut hashes = Vec::with_capacity(starts.len());
+        if starts.is_empty() {
+            return hashes;
+        }
+        let first_window = &file[starts.start..starts.start + window_size];
+        // The byte leaving the window, and the one entering it, at each step.
+        let steps = file[starts.start..starts.end - 1]
+            .iter()
+            .zip(&file[starts.start + window_size..starts.end + window_size - 1]);
+
+        match self {
+            WeakHash::Polynomial => {
+                let mut hasher = RollingHasher::new(first_window);
+                hashes.push(hasher.hash());
+                for (&leaving, &entering) in steps {
+                    hasher.roll(leaving, entering);
+                    hashes.push(hasher.hash());
+                }
+            }
+            WeakHash::Adler32 => {
+                let mut sums = AdlerSums::of(first_window);
+                hashes.push(sums.hash());
+                for (&leaving, &entering) in steps {
+                    sums.roll(leaving, entering, window_size);
+                    hashes.push(sums.hash());
+                }
+            }
+            WeakHash::Gear => {
+                let mut hash = WeakHash::Gear.hash(first_window);
+                hashes.push(hash);
+                // Bytes further than 64 from the end of the window were already shifted out.
+                let shift = u32::try_from(window_size).ok().filter(|&shift| shift < 64);
+                for (&leaving, &entering) in steps {
+                    let leaving = shift.map_or(0, |shift| GEAR[leaving as usize] << shift);
+                    hash = gear_push(hash, entering).wrapping_sub(leaving);
+                    hashes.push(hash);
+                }
+            }
+        }
+
+        hashes
+    }
+}
+
+impl fmt::Display for WeakHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WeakHash::Polynomial => write!(f, "polynomial"),
+            WeakHash::Adler32 => write!(f, "adler32"),
+            WeakHash::Gear => write!(f, "gear"),
+        }
+    }
+}
+
+impl FromStr for WeakHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "polynomial" => Ok(WeakHash::Polynomial),
+            "adler32" => Ok(WeakHash::Adler32),
+            "gear" => Ok(WeakHash::Gear),
+            _ => Err(format!(
+                r#""{s}" is not a weak hash. Expected "polynomial", "adler32" or "gear""#
+            )),
+        }
+    }
+}
+
+// The sums of rsync's weak checksum: of the bytes, and of the first sum after each byte.
+struct AdlerSums {
+    bytes: u32,
+    prefixes: u32,
+}
+
+impl AdlerSums {
+    fn of(block: &[u8]) -> Self {
+        let mut sums = Self {
+            bytes: 0,
+            prefixes: 0,
+        };
+        for &byte in block {
+            sums.bytes = sums.bytes.wrapping_add(byte as u32);
+            sums.prefixes = sums.prefixes.wrapping_add(sums.bytes);
+        }
+        sums
+    }
+
+    fn roll(&mut self, leaving: u8, entering: u8, window_size: usize) {
+        self.bytes = self
+            .bytes
+            .wrapping_sub(leaving as u32)
+            .wrapping_add(entering as u32);
+        self.prefixes = self
+            .prefixes
+            .wrapping_sub((window_size as u32).wrapping_mul(leaving as u32))
+            .wrapping_add(self.bytes);
+    }
+
+    fn hash(&self) -> u64 {
+        ((self.bytes & 0xffff) | (self.prefixes << 16)) as u64
+    }
+}
+
+fn gear_push(hash: u64, byte: u8) -> u64 {
+    (hash << 1).wrapping_add(GEAR[byte as usize])
+}
+
+// A random number for each byte, the same on every build (splitmix64 from a fixed seed).
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5259_4e43_5255_5354;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut mixed = state;
+        mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = mixed ^ (mixed >> 31);
+        index += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [WeakHash; 3] = [WeakHash::Polynomial, WeakHash::Adler32, WeakHash::Gear];
+
+    #[test]
+    fn rolled_hashes_are_the_hashes_of_each_window() {
+        let file: Vec<u8> = (0..300u32).map(|byte| (byte * 7 % 251) as u8).collect();
+        for weak_hash in ALL {
+            // Windows shorter and longer than the 64 bytes a Gear hash keeps.
+            for window_size in [1, 16, 64, 100] {
+                let starts = 3..file.len() - window_size + 1;
+                let expected: Vec<_> = starts
+                    .clone()
+                    .map(|start| weak_hash.hash(&file[start..start + window_size]))
+                    .collect();
+
+                assert_eq!(
+                    weak_hash.roll(&file, window_size, starts),
+                    expected,
+                    "{weak_hash} windows of {window_size} bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn weak_hashes_are_parsed_from_their_names() {
+        for weak_hash in ALL {
+            assert_eq!(weak_hash.to_string().parse(), Ok(weak_hash));
+        }
+        assert!("crc32".parse::<WeakHash>().is_err());
+    }
+}
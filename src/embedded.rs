@@ -0,0 +1,413 @@
+//! The hashing and token-matching core of the algorithm, which only needs `alloc`.
+//!
+//! With the crate's default features disabled, this is all the crate builds, without the standard
+//! library: enough to apply Deltas on embedded devices (e.g. to update their firmware), or to
+//! hash and match blocks there. Reading and writing artifacts and files is left to the caller.
+//!
+//! The library hashes blocks with the functions below, and tokens are those of a Delta, so either
+//! side of a sync can run on the device while the other one uses the whole library, with
+//! Signatures of the default (polynomial) weak hash and of the default (xxh3) strong hash.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Formatter;
+use core::ops::Range;
+
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_64;
+
+// Zero bytes written at once when applying a ZeroRun.
+static ZEROS: [u8; 4096] = [0; 4096];
+
+// The rolling hash of a window is the value of its bytes (each plus one) as the digits of a number
+// in this base, modulo `ROLLING_MODULUS`, the first byte being the most significant.
+const ROLLING_BASE: u64 = 257;
+const ROLLING_MODULUS: u64 = 1_000_000_007;
+
+/// A step of recreating the updated file: copying blocks of the basis file, or writing bytes.
+///
+/// Later versions may add kinds of tokens, after the existing ones so these keep their encoding.
+/// Matches on a Token need a wildcard arm, and Deltas with tokens of unknown kinds are refused,
+/// unless read with `UnknownTokens::Skip`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum Token {
+    /// A reference to a block within the basis file.
+    BlockIndex(usize),
+    /// A byte literal to be reconstructed directly.
+    ByteLiteral(u8),
+    /// References to consecutive blocks within the basis file, as merged by `Delta::optimize`.
+    BlockRange(Range<usize>),
+    /// Consecutive byte literals, as merged by `Delta::optimize`.
+    ByteLiterals(Vec<u8>),
+    /// This many zero bytes, for matchers asked to find long runs of them.
+    ZeroRun(usize),
+}
+
+impl Token {
+    /// Indexes of the basis file blocks this token references, in order. Empty for literals.
+    ///
+    /// Panics for a BlockIndex of `usize::MAX`, which no basis file has and decoding refuses.
+    pub fn block_indexes(&self) -> Range<usize> {
+        match self {
+            Token::BlockIndex(index) => {
+                *index
+                    ..index
+                        .checked_add(1)
+                        .expect("Block indexes are below usize::MAX")
+            }
+            Token::BlockRange(indexes) => indexes.clone(),
+            Token::ByteLiteral(_) | Token::ByteLiterals(_) | Token::ZeroRun(_) => 0..0,
+        }
+    }
+
+    /// Bytes this token writes directly. Empty for block references.
+    pub fn literals(&self) -> &[u8] {
+        match self {
+            Token::ByteLiteral(byte) => core::slice::from_ref(byte),
+            Token::ByteLiterals(bytes) => bytes,
+            Token::BlockIndex(_) | Token::BlockRange(_) | Token::ZeroRun(_) => &[],
+        }
+    }
+
+    /// Zero bytes this token writes. None but for a ZeroRun.
+    pub fn zeros(&self) -> usize {
+        match self {
+            Token::ZeroRun(length) => *length,
+            _ => 0,
+        }
+    }
+}
+
+/// Rolling hash of a window of bytes, updated in constant time as the window slides by a byte.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct RollingHasher {
+    hash: u64,
+    /// What the first byte of the window was multiplied by.
+    first_byte_weight: u64,
+}
+
+impl RollingHasher {
+    pub fn new(window: &[u8]) -> Self {
+        let mut hash = 0;
+        let mut first_byte_weight = 1;
+        for (position, &byte) in window.iter().enumerate() {
+            hash = (hash * ROLLING_BASE + byte as u64 + 1) % ROLLING_MODULUS;
+            if position > 0 {
+                first_byte_weight = first_byte_weight * ROLLING_BASE % ROLLING_MODULUS;
+            }
+        }
+        Self {
+            hash,
+            first_byte_weight,
+        }
+    }
+
+    /// Slides the window by a byte: `removed` was its first byte, and `added` follows its last.
+    pub fn roll(&mut self, removed: u8, added: u8) {
+        let removed = (removed as u64 + 1) * self.first_byte_weight % ROLLING_MODULUS;
+        let hash = (self.hash + ROLLING_MODULUS - removed) % ROLLING_MODULUS;
+        self.hash = (hash * ROLLING_BASE + added as u64 + 1) % ROLLING_MODULUS;
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// Computes the rolling hash of a block, the same as `calculate_rolling_hash`.
+pub fn rolling_hash(block: &[u8]) -> u64 {
+    RollingHasher::new(block).hash()
+}
+
+/// Computes the strong hash of a block, the same as `StrongHash::Xxh3` (the default): xxh3-64,
+/// which is specified independently of the Rust toolchain.
+pub fn strong_hash(block: &[u8]) -> u64 {
+    xxh3_64(block)
+}
+
+/// Hashes the blocks of a file, returning their strong and rolling hashes, in order, as a
+/// FileSignature holds them. The last block is shorter if the file does not divide evenly.
+///
+/// # Arguments
+/// * `file` - The content of the file.
+/// * `chunk_size` - The size for each block. Must not be zero.
+///
+pub fn hash_blocks(file: &[u8], chunk_size: usize) -> (Vec<u64>, Vec<u64>) {
+    assert!(chunk_size > 0, "Fixed blocks must not be empty");
+    file.chunks(chunk_size)
+        .map(|block| (strong_hash(block), rolling_hash(block)))
+        .unzip()
+}
+
+/// Matches our file against the blocks of a basis file, given their hashes, and returns the
+/// tokens recreating our file from the basis file.
+///
+/// Windows of `chunk_size` bytes are looked up at every offset, as the library's matcher does with
+/// the default options, but a basis block found several times is always referenced by its first
+/// copy. Either recreates the same file.
+///
+/// # Arguments
+/// * `strong_hashes` - Strong hashes of the basis file blocks, as `hash_blocks` returns them.
+/// * `rolling_hashes` - Rolling hashes of the basis file blocks, as `hash_blocks` returns them.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `chunk_size` - The size for each block used to hash the basis file.
+///
+pub fn match_blocks(
+    strong_hashes: &[u64],
+    rolling_hashes: &[u64],
+    updated_file: &[u8],
+    chunk_size: usize,
+) -> Vec<Token> {
+    assert!(chunk_size > 0, "Fixed blocks must not be empty");
+    // Indexes of the blocks with each rolling hash, ascending.
+    let mut blocks_by_rolling_hash: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+    for (index, &hash) in rolling_hashes.iter().enumerate() {
+        blocks_by_rolling_hash.entry(hash).or_default().push(index);
+    }
+
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    let mut window = RollingHasher::new(updated_file.get(..chunk_size).unwrap_or_default());
+    while index + chunk_size <= updated_file.len() {
+        let block = &updated_file[index..index + chunk_size];
+        // Rolling hashes may collide, so only blocks with the same strong hash match.
+        let matched = blocks_by_rolling_hash
+            .get(&window.hash())
+            .and_then(|candidates| {
+                let hash = strong_hash(block);
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|&candidate| strong_hashes.get(candidate) == Some(&hash))
+            });
+        match matched {
+            Some(block_index) => {
+                tokens.push(Token::BlockIndex(block_index));
+                index += chunk_size;
+                if let Some(next_block) = updated_file.get(index..index + chunk_size) {
+                    window = RollingHasher::new(next_block);
+                }
+            }
+            None => {
+                tokens.push(Token::ByteLiteral(updated_file[index]));
+                if let Some(&added) = updated_file.get(index + chunk_size) {
+                    window.roll(updated_file[index], added);
+                }
+                index += 1;
+            }
+        }
+    }
+    // Bytes after the last whole window are sent as they are.
+    tokens.extend(
+        updated_file[index..]
+            .iter()
+            .map(|&byte| Token::ByteLiteral(byte)),
+    );
+
+    tokens
+}
+
+/// Why tokens could not be applied to a basis file.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ApplyError<E> {
+    /// A token references a block the basis file does not have.
+    MissingBlock { index: usize, block_count: usize },
+    /// The recreated file could not be written.
+    Output(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ApplyError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::MissingBlock { index, block_count } => write!(
+                f,
+                "Delta references block {index}, but the Basis file only has {block_count} blocks"
+            ),
+            ApplyError::Output(error) => write!(f, "Could not write the recreated file: {error}"),
+        }
+    }
+}
+
+/// Applies tokens to a basis file, and returns the size of the recreated file.
+///
+/// The recreated file is given to `write` a piece at a time, in order, so it can go straight to
+/// its destination (e.g. the inactive partition of a device) without being held in memory. Runs
+/// of consecutive blocks are given at once, and runs of zeros a few KiB at a time.
+///
+/// # Arguments
+/// * `basis_file` - The file to be changed (not in-place).
+/// * `tokens` - The tokens of a Delta, in order.
+/// * `chunk_size` - The size for each block used in the Signature, and in the Delta.
+/// * `write` - Takes each piece of the recreated file.
+///
+pub fn apply_tokens<E>(
+    basis_file: &[u8],
+    tokens: &[Token],
+    chunk_size: usize,
+    mut write: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<u64, ApplyError<E>> {
+    assert!(chunk_size > 0, "Fixed blocks must not be empty");
+    let block_count = basis_file.len().div_ceil(chunk_size);
+    let mut written = 0;
+    for token in tokens {
+        let indexes = match token {
+            Token::BlockIndex(usize::MAX) => {
+                return Err(ApplyError::MissingBlock {
+                    index: usize::MAX,
+                    block_count,
+                })
+            }
+            token => token.block_indexes(),
+        };
+        if indexes.end > block_count {
+            return Err(ApplyError::MissingBlock {
+                index: indexes.end - 1,
+                block_count,
+            });
+        }
+        // Consecutive blocks are contiguous in the basis file.
+        let start = indexes.start * chunk_size;
+        let end = basis_file.len().min(indexes.end * chunk_size);
+        for piece in [&basis_file[start.min(end)..end], token.literals()] {
+            if !piece.is_empty() {
+                write(piece).map_err(ApplyError::Output)?;
+                written += piece.len() as u64;
+            }
+        }
+        let mut zeros = token.zeros();
+        while zeros > 0 {
+            let piece = &ZEROS[..zeros.min(ZEROS.len())];
+            write(piece).map_err(ApplyError::Output)?;
+            written += piece.len() as u64;
+            zeros -= piece.len();
+        }
+    }
+
+    Ok(written)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::domain::{
+        apply_delta, compute_delta_to_our_file, compute_signature, compute_sliding_rolling_hashes,
+        ChunkingMode, SignatureBuilder, WeakHash,
+    };
+
+    // Every byte value, so hashes of bytes which are not valid UTF-8 are compared too.
+    fn sample_file(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index * 7 % 256) as u8).collect()
+    }
+
+    fn recreate(basis_file: &[u8], tokens: &[Token], chunk_size: usize) -> Vec<u8> {
+        let mut recreated = Vec::new();
+        apply_tokens(basis_file, tokens, chunk_size, |piece| {
+            recreated.extend_from_slice(piece);
+            Ok::<(), Infallible>(())
+        })
+        .unwrap();
+        recreated
+    }
+
+    #[test]
+    fn hashes_are_the_ones_of_signatures() {
+        let file = sample_file(1000);
+
+        let (strong_hashes, rolling_hashes) = hash_blocks(&file, 24);
+        let mut builder = SignatureBuilder::new(24, ChunkingMode::Fixed);
+        builder.update(&file);
+        let signature = builder.finish();
+
+        assert_eq!(strong_hashes, signature.strong_hashes);
+        assert_eq!(rolling_hashes, signature.rolling_hashes);
+    }
+
+    #[test]
+    fn rolled_hashes_are_the_ones_of_sliding_windows() {
+        let file = sample_file(300);
+
+        let mut window = RollingHasher::new(&file[..16]);
+        let mut rolled = vec![window.hash()];
+        for index in 16..file.len() {
+            window.roll(file[index - 16], file[index]);
+            rolled.push(window.hash());
+        }
+
+        assert_eq!(
+            rolled,
+            compute_sliding_rolling_hashes(&file, 16, WeakHash::Polynomial)
+        );
+    }
+
+    #[test]
+    fn matched_tokens_recreate_the_updated_file() {
+        let basis_file = sample_file(2000);
+        let updated_file = [
+            &basis_file[..100],
+            b"inserted bytes",
+            &basis_file[110..1500],
+            &basis_file[1530..],
+            &basis_file[..64],
+        ]
+        .concat();
+        let (strong_hashes, rolling_hashes) = hash_blocks(&basis_file, 32);
+
+        let tokens = match_blocks(&strong_hashes, &rolling_hashes, &updated_file, 32);
+
+        assert!(tokens.contains(&Token::BlockIndex(1)));
+        assert_eq!(recreate(&basis_file, &tokens, 32), updated_file);
+    }
+
+    #[test]
+    fn applies_deltas_as_the_library_does() {
+        let basis_file = Bytes::from(sample_file(500));
+        let updated_file = Bytes::from([b"new".as_slice(), &basis_file[40..], b"end"].concat());
+        let signature = compute_signature(basis_file.clone(), 16);
+        let delta = compute_delta_to_our_file(signature, updated_file.clone(), 16);
+
+        let recreated = recreate(&basis_file, &delta.content.to_vec(), 16);
+
+        assert_eq!(recreated, updated_file);
+        assert_eq!(apply_delta(basis_file, delta, 16).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn zero_runs_are_written_as_zeros() {
+        let tokens = [
+            Token::BlockIndex(1),
+            Token::ZeroRun(10_000),
+            Token::ByteLiteral(b'a'),
+        ];
+
+        let recreated = recreate(b"01234567", &tokens, 4);
+
+        assert_eq!(recreated, [b"4567".as_slice(), &[0; 10_000], b"a"].concat());
+    }
+
+    #[test]
+    fn missing_blocks_are_reported() {
+        let tokens = [Token::ByteLiteral(b'a'), Token::BlockRange(1..4)];
+
+        let result = apply_tokens(b"0123456789", &tokens, 4, |_| Ok::<(), Infallible>(()));
+
+        assert_eq!(
+            result,
+            Err(ApplyError::MissingBlock {
+                index: 3,
+                block_count: 3
+            })
+        );
+
+        let tokens = [Token::BlockIndex(usize::MAX)];
+        let result = apply_tokens(b"0123456789", &tokens, 4, |_| Ok::<(), Infallible>(()));
+        assert!(matches!(result, Err(ApplyError::MissingBlock { .. })));
+    }
+}
@@ -0,0 +1,49 @@
+//! Suggestions attached to errors, telling users how to get past them.
+//!
+//! The command-line program shows them below its errors, through `color_eyre`. Without the `cli`
+//! feature they are dropped, so programs embedding the library do not depend on `color_eyre`.
+
+#[cfg(feature = "cli")]
+pub use color_eyre::Help;
+
+#[cfg(not(feature = "cli"))]
+pub use fallback::Help;
+
+#[cfg(not(feature = "cli"))]
+mod fallback {
+    use std::fmt::Display;
+
+    /// Takes the place of `color_eyre::Help`, dropping the suggestions.
+    pub trait Help<T, E> {
+        type Return;
+
+        fn suggestion<D>(self, suggestion: D) -> Self::Return
+        where
+            D: Display + Send + Sync + 'static;
+    }
+
+    impl<T, E> Help<T, E> for Result<T, E>
+    where
+        E: Into<eyre::Report>,
+    {
+        type Return = eyre::Result<T>;
+
+        fn suggestion<D>(self, _suggestion: D) -> Self::Return
+        where
+            D: Display + Send + Sync + 'static,
+        {
+            self.map_err(Into::into)
+        }
+    }
+
+    impl Help<(), eyre::Report> for eyre::Report {
+        type Return = eyre::Report;
+
+        fn suggestion<D>(self, _suggestion: D) -> Self::Return
+        where
+            D: Display + Send + Sync + 'static,
+        {
+            self
+        }
+    }
+}
@@ -1,26 +1,371 @@
-use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::fmt::Formatter;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::{fmt, fs};
 
-use bytes::Bytes;
-use color_eyre::eyre::Context;
-use color_eyre::Help;
+use bytes::{Buf, Bytes};
+use eyre::{eyre, Context};
 
-pub fn attempt_to_read_file<P: AsRef<Path>>(
-    path: P,
-) -> color_eyre::Result<Bytes, color_eyre::Report> {
+use crate::help::Help;
+
+/// Path standing for the standard input or output, for commands used in pipelines.
+pub const STDIO_PATH: &str = "-";
+
+pub fn is_stdio<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new(STDIO_PATH)
+}
+
+/// Files which are not regular files, which not every command can read.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SpecialFile {
+    Directory,
+    Pipe,
+    Socket,
+    /// Terminals, and devices like `/dev/null`.
+    CharacterDevice,
+    BlockDevice,
+    Other,
+}
+
+impl SpecialFile {
+    /// What kind of special file `metadata` describes, None for a regular file.
+    pub fn of(metadata: &fs::Metadata) -> Option<Self> {
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            return None;
+        }
+        if file_type.is_dir() {
+            return Some(SpecialFile::Directory);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return Some(SpecialFile::Pipe);
+            }
+            if file_type.is_socket() {
+                return Some(SpecialFile::Socket);
+            }
+            if file_type.is_char_device() {
+                return Some(SpecialFile::CharacterDevice);
+            }
+            if file_type.is_block_device() {
+                return Some(SpecialFile::BlockDevice);
+            }
+        }
+        Some(SpecialFile::Other)
+    }
+}
+
+impl fmt::Display for SpecialFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecialFile::Directory => write!(f, "a directory"),
+            SpecialFile::Pipe => write!(f, "a pipe"),
+            SpecialFile::Socket => write!(f, "a socket"),
+            SpecialFile::CharacterDevice => write!(f, "a character device"),
+            SpecialFile::BlockDevice => write!(f, "a block device"),
+            SpecialFile::Other => write!(f, "not a regular file"),
+        }
+    }
+}
+
+/// Reads a whole file. Pipes and devices (e.g. `/dev/stdin`) are read to their end, while
+/// directories and sockets, which can not be read as files, are refused with a targeted error.
+pub fn attempt_to_read_file<P: AsRef<Path>>(path: P) -> eyre::Result<Bytes, eyre::Report> {
+    // Links are followed, so `/dev/stdin` is the pipe or terminal it stands for.
+    let special_file = fs::metadata(&path)
+        .ok()
+        .and_then(|metadata| SpecialFile::of(&metadata));
+    match special_file {
+        Some(SpecialFile::Directory) => {
+            return Err(eyre!(r#""{}" is a directory"#, path.as_ref().display()))
+                .suggestion("Use `transfer` or `manifest` to work on directory trees.");
+        }
+        Some(SpecialFile::Socket) => {
+            return Err(eyre!(
+                r#""{}" is a socket, which can not be read as a file"#,
+                path.as_ref().display()
+            ))
+            .suggestion("Pipe what it sends instead, e.g. `nc -U <socket> | rsync_rust ... -`.");
+        }
+        _ => {}
+    }
     match fs::read(&path) {
         Ok(bytes) => Ok(bytes.into()),
-        Err(error) => Err(color_eyre::Report::new(error))
+        Err(error) => Err(eyre::Report::new(error))
             .context(format!(r#"Path provided: "{}""#, path.as_ref().display()))
             .suggestion("Are you sure the path provided is correct? Note that it should be a relative path."),
     }
 }
 
-pub fn write_to_file<P: AsRef<Path>>(path: P, content: Bytes) -> color_eyre::Result<()> {
+/// Refuses anything but a regular file, for commands reading a file out of order or relying on
+/// its length, which pipes and devices do not allow. Files which do not exist are left to the
+/// caller.
+pub fn ensure_regular_file<P: AsRef<Path>>(path: P) -> eyre::Result<()> {
+    let special_file = fs::metadata(&path)
+        .ok()
+        .and_then(|metadata| SpecialFile::of(&metadata));
+    match special_file {
+        None => Ok(()),
+        Some(special_file @ (SpecialFile::Pipe | SpecialFile::CharacterDevice)) => Err(eyre!(
+            r#""{}" is {special_file}, which can only be read once, from start to end"#,
+            path.as_ref().display()
+        )),
+        Some(special_file) => Err(eyre!(
+            r#""{}" is {special_file}, not a regular file"#,
+            path.as_ref().display()
+        )),
+    }
+}
+
+/// Reads a whole file, or the standard input if `path` is `-`.
+pub fn attempt_to_read_file_or_stdin<P: AsRef<Path>>(path: P) -> eyre::Result<Bytes> {
+    if !is_stdio(&path) {
+        return attempt_to_read_file(path);
+    }
+    let mut content = Vec::new();
+    io::stdin()
+        .lock()
+        .read_to_end(&mut content)
+        .context("Error while reading the standard input")?;
+
+    Ok(content.into())
+}
+
+pub fn write_to_file<P: AsRef<Path>>(path: P, content: Bytes) -> eyre::Result<()> {
     let mut file = File::create(path)?;
     file.write_all(&content)?;
 
     Ok(())
 }
+
+/// Writes content made of several slices, like a `BytesRope`, without joining them first.
+pub fn write_buf_to_file<P: AsRef<Path>>(path: P, content: impl Buf) -> eyre::Result<()> {
+    write_buf(File::create(path)?, content)
+}
+
+/// Same as `write_buf_to_file`, writing to the standard output if `path` is `-`, and holding
+/// an advisory lock on the file while writing it if `lock` is set (see `create_locked_file`).
+pub fn write_buf_to_file_or_stdout<P: AsRef<Path>>(
+    path: P,
+    content: impl Buf,
+    lock: bool,
+) -> eyre::Result<()> {
+    match (is_stdio(&path), lock) {
+        (true, _) => write_buf(io::stdout().lock(), content),
+        (false, true) => write_buf_to_locked_file(path, content),
+        (false, false) => write_buf_to_file(path, content),
+    }
+}
+
+/// Same as `write_buf_to_file`, holding an advisory lock on the file while writing it.
+pub fn write_buf_to_locked_file<P: AsRef<Path>>(path: P, content: impl Buf) -> eyre::Result<()> {
+    write_buf(create_locked_file(path)?, content)
+}
+
+/// Creates or truncates a file like `File::create`, but only once it holds an advisory lock on
+/// it (`flock` on Unix, `LockFileEx` on Windows). So two processes writing the same file fail
+/// instead of interleaving their writes.
+///
+/// The lock is released when the file is closed. It only keeps out processes taking it too.
+pub fn create_locked_file<P: AsRef<Path>>(path: P) -> eyre::Result<File> {
+    let path = path.as_ref();
+    // Truncating before holding the lock would destroy what the holder is writing.
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    lock_file(&file, path)?;
+    file.set_len(0)?;
+
+    Ok(file)
+}
+
+/// Takes an advisory lock on an open file, failing right away if another process holds it.
+pub fn lock_file(file: &File, path: &Path) -> eyre::Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(eyre!(
+            "{} is locked by another process writing to it",
+            path.display()
+        ))
+        .suggestion("Wait for the other process to finish, then try again."),
+        Err(TryLockError::Error(error)) => Err(eyre::Report::new(error))
+            .context(format!("Unable to lock file: {}", path.display()))
+            .suggestion("Use `--no-lock` on file systems which do not support locks."),
+    }
+}
+
+fn write_buf(output: impl Write, mut content: impl Buf) -> eyre::Result<()> {
+    // Small slices are written together.
+    let mut output = BufWriter::new(output);
+    while content.has_remaining() {
+        let chunk = content.chunk();
+        output.write_all(chunk)?;
+        let written = chunk.len();
+        content.advance(written);
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Appends `length` bytes of `input`, starting at `offset`, to `output` without moving them
+/// through userspace. Filesystems supporting it (btrfs, XFS) share the blocks instead of copying.
+///
+/// Returns whether the range was copied: `false` if the kernel or filesystem does not support it,
+/// and nothing was written, so the caller can copy the bytes itself.
+#[cfg(target_os = "linux")]
+pub fn copy_file_range(input: &File, offset: u64, output: &File, length: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut input_offset = offset as libc::loff_t;
+    let mut remaining = length;
+    while remaining > 0 {
+        // SAFETY: both descriptors stay open for the call, and the output is written at (and
+        // advances) its own position.
+        let copied = unsafe {
+            libc::copy_file_range(
+                input.as_raw_fd(),
+                &mut input_offset,
+                output.as_raw_fd(),
+                std::ptr::null_mut(),
+                remaining as usize,
+                0,
+            )
+        };
+        if copied < 0 {
+            let error = io::Error::last_os_error();
+            let unsupported = matches!(
+                error.raw_os_error(),
+                Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM)
+            );
+            return match unsupported && remaining == length {
+                true => Ok(false),
+                false => Err(error),
+            };
+        }
+        if copied == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= copied as u64;
+    }
+
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_file_range(
+    _input: &File,
+    _offset: u64,
+    _output: &File,
+    _length: u64,
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Where the file system stores `file`: the ranges of the file mapped to an extent, in order.
+/// Holes are left out.
+///
+/// Fails if the file system does not tell, e.g. tmpfs, which has no extents.
+#[cfg(target_os = "linux")]
+pub fn file_extents(file: &File) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    // From linux/fs.h and linux/fiemap.h, which libc does not bind.
+    const FS_IOC_FIEMAP: u32 = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const EXTENTS_PER_CALL: usize = 64;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; EXTENTS_PER_CALL],
+    }
+
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut fiemap = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: EXTENTS_PER_CALL as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+        };
+        // SAFETY: the kernel writes at most `extent_count` extents, which `fiemap` has room for.
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mapped = &fiemap.extents[..(fiemap.mapped_extents as usize).min(EXTENTS_PER_CALL)];
+        let Some(last) = mapped.last() else {
+            break;
+        };
+        extents.extend(
+            mapped
+                .iter()
+                .map(|extent| extent.logical..extent.logical + extent.length),
+        );
+        if last.flags & FIEMAP_EXTENT_LAST != 0 {
+            break;
+        }
+        start = last.logical + last.length;
+    }
+
+    Ok(extents)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn file_extents(_file: &File) -> io::Result<Vec<Range<u64>>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_read_or_refused_with_their_kind() {
+        let directory = scratch_directory("special_files");
+        let socket_path = directory.join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+        let error = attempt_to_read_file(&socket_path).unwrap_err();
+        assert!(format!("{error}").contains("is a socket"));
+        let error = attempt_to_read_file(&directory).unwrap_err();
+        assert!(format!("{error}").contains("is a directory"));
+
+        assert_eq!(attempt_to_read_file("/dev/null").unwrap(), Bytes::new());
+        let error = ensure_regular_file("/dev/null").unwrap_err();
+        assert!(format!("{error}").contains("is a character device"));
+        assert!(ensure_regular_file(directory.join("missing")).is_ok());
+    }
+}
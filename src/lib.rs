@@ -1,3 +1,31 @@
+// Without the `std` feature, only the core of the algorithm is built, for embedded targets.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod allocation;
+#[cfg(feature = "cli")]
+pub mod commands;
+#[cfg(feature = "std")]
 pub mod domain;
+pub mod embedded;
+#[cfg(feature = "std")]
+pub mod help;
+#[cfg(feature = "std")]
 pub mod io_utils;
+#[cfg(feature = "cli")]
+pub mod network;
+#[cfg(feature = "std")]
+pub mod profiling;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod protocol;
+#[cfg(feature = "cli")]
+pub mod selftest;
+// Also built for the unit tests of the library without `cli`, which write in scratch directories.
+#[cfg(any(test, feature = "cli"))]
 pub mod test_utils;
+#[cfg(feature = "std")]
+pub mod timings;
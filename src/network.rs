@@ -0,0 +1,1926 @@
+//! Synchronizing a file over a connection, instead of exchanging Signature and Delta files.
+//!
+//! The receiver (`serve`) has the basis file, and the sender (`push`) has the updated file:
+//! 1 - The sender sends a SyncRequest, telling how files are divided into blocks.
+//! 2 - The receiver sends the Signature of its basis file, in batches of blocks.
+//! 3 - The sender sends the Delta, in the streamed format, while it is being computed.
+//! 4 - The receiver applies it, replaces its basis file and answers with a SyncOutcome.
+//!
+//! A sender estimating a sync (`push --estimate`) computes the Delta as usual, but only sends a
+//! SyncEstimate of what it would have sent in step 3. The receiver answers with that estimate, and
+//! leaves its basis file untouched.
+//!
+//! Every message is a frame, as described in `domain::streaming`.
+//!
+//! For large files, the sender asks for coarse blocks too, and step 2 happens in two passes:
+//! the receiver sends the Signature with coarse blocks, the sender answers with the coarse blocks
+//! which changed, and the receiver sends fine blocks for those only (see `domain::hierarchy`).
+//!
+//! When the sender asks for it, the Delta is followed by a frame telling whether it was cut short
+//! because, with the Signature, it grew larger than the file. If it was, the whole file (deflated
+//! if asked) follows in raw frames, and the receiver uses it instead of applying the Delta. A
+//! receiver whose Signature alone is larger than the file sends an empty one, so the sender
+//! sends the whole file right away.
+//!
+//! The steps are pipelined on the sender: it hashes its file while the Signature is still
+//! arriving, and each Delta token is sent as soon as it is known. So the time to sync approaches
+//! the slowest of reading, hashing and sending, instead of their sum.
+//!
+//! A receiver serves several senders at once with a bounded pool of workers (`serve_connections`).
+//! Syncs read the basis file as it was when they started, and the receiver checks that its basis
+//! file did not change meanwhile before writing it, failing the sync if it did, unless told to do
+//! otherwise (see `ConflictPolicy`).
+//!
+//! A sync may be resumed when it names a `transfer`: if the connection breaks after the Signature
+//! was sent, the receiver keeps what it received since, and the sender what it sent. The sender
+//! then connects again with a request to `resume`, and the receiver answers with how many bytes it
+//! kept, so only the rest is sent. When the receiver kept nothing, or its basis file changed since,
+//! it answers None, and the sync starts over from step 2 on the same connection.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::{BufRead, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use eyre::{eyre, Context};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::transfer::{decode_whole_file, encode_whole_file, TransferMethod};
+use crate::domain::{
+    align_coarse_chunk_size, apply_delta_with_mode, changed_coarse_blocks, compute_signature,
+    compute_signature_in_parallel, compute_signature_with_mode,
+    compute_sliding_rolling_hashes_in_parallel, compute_sparse_signature, read_frame_from,
+    read_raw_frame_from, read_streamed_delta, stream_delta_with_mode, stream_fixed_delta,
+    stream_hierarchical_delta, write_end_frame, write_frame, write_raw_frame, ChunkingMode,
+    DeltaHeader, DeltaSizeEstimator, DeltaTooLarge, DeltaWriter, FileDigest, FileSignature,
+    MatchingOptions, Parallelism, SparseSignature, StrongHash, StrongHashTails, SyncOptions,
+    WeakHash, DEFAULT_COARSE_CHUNK_SIZE, DEFAULT_MAX_OUTPUT_SIZE,
+};
+use crate::io_utils;
+
+// Signatures are sent in batches, so the sender can start receiving them early.
+const BLOCKS_PER_FRAME: usize = 4096;
+
+// Files with fewer coarse blocks than this are matched with fine blocks only.
+const MIN_COARSE_BLOCKS: usize = 8;
+
+// Bytes of each frame when the whole file is sent instead of a Delta.
+const WHOLE_FILE_FRAME_SIZE: usize = 1024 * 1024;
+
+/// First message of a sync, sent by the sender.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SyncRequest {
+    pub chunk_size: usize,
+    pub mode: ChunkingMode,
+    /// Coarse blocks to find the changed regions with first.
+    pub coarse_chunk_size: Option<usize>,
+    /// Whether the whole file may be sent instead of a large Delta.
+    #[serde(default)]
+    pub whole_file_fallback: bool,
+    /// Whether the whole file is deflated when it is sent.
+    #[serde(default)]
+    pub compress_whole_file: bool,
+    /// Size of the updated file, to tell if a Signature is worth sending.
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    /// Stop once the sender knows what it would send, and send only that.
+    #[serde(default)]
+    pub estimate_only: bool,
+    /// Names the sync across connections, so it can be resumed. Picked at random by the sender.
+    #[serde(default)]
+    pub transfer: Option<u64>,
+    /// Resume the sync `transfer`, rather than starting it.
+    #[serde(default)]
+    pub resume: bool,
+}
+
+impl SyncRequest {
+    /// Creates a request for `updated_file`, using coarse blocks if the file is large enough.
+    ///
+    /// # Arguments
+    /// * `updated_file` - Our updated file, in bytes.
+    /// * `chunk_size` - The size of (fine) blocks.
+    /// * `mode` - How files are divided into blocks. Coarse blocks are only used in `fixed` mode.
+    ///
+    pub fn for_file(updated_file: &Bytes, chunk_size: usize, mode: ChunkingMode) -> Self {
+        let coarse_chunk_size = align_coarse_chunk_size(DEFAULT_COARSE_CHUNK_SIZE, chunk_size);
+        let worth_it = mode == ChunkingMode::Fixed
+            && coarse_chunk_size > chunk_size
+            && updated_file.len() >= MIN_COARSE_BLOCKS * coarse_chunk_size;
+
+        Self {
+            chunk_size,
+            mode,
+            coarse_chunk_size: worth_it.then_some(coarse_chunk_size),
+            whole_file_fallback: true,
+            compress_whole_file: false,
+            file_size: Some(updated_file.len() as u64),
+            estimate_only: false,
+            transfer: None,
+            resume: false,
+        }
+    }
+}
+
+// Syncs over a connection take the blocks and threads of SyncOptions. The protocol fixes the
+// hashes and the tuning of the matcher.
+impl SyncOptions {
+    /// The SyncRequest sent to push `updated_file`, as `SyncRequest::for_file` creates it.
+    pub fn sync_request(&self, updated_file: &Bytes) -> SyncRequest {
+        SyncRequest::for_file(updated_file, self.chunk_size(), self.mode())
+    }
+
+    /// Syncs `updated_file` to the receiver at the other end of `connection`, as `push_file`.
+    pub fn push<C: Read + Write>(
+        &self,
+        connection: &mut C,
+        updated_file: &Bytes,
+    ) -> eyre::Result<SyncOutcome> {
+        let request = self.sync_request(updated_file);
+        push_file(connection, updated_file, request, self.parallelism())
+    }
+
+    /// Handles a single sync of `basis_filename` on the receiving side, as `serve_connection`.
+    pub fn serve<C: Read + Write>(
+        &self,
+        connection: &mut C,
+        basis_filename: &Path,
+    ) -> eyre::Result<SyncOutcome> {
+        serve_connection(connection, basis_filename, self.max_output_size())
+    }
+}
+
+/// What a sync would send, measured by the sender without sending it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SyncEstimate {
+    /// Bytes of the Signature the receiver sent, which were already exchanged.
+    pub signature_size: u64,
+    /// Bytes the sender would send: the Delta, then the whole file if the Delta grew too large.
+    pub sent_size: u64,
+    /// How the file would be brought up to date.
+    pub method: TransferMethod,
+}
+
+impl fmt::Display for SyncEstimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sync would send {} bytes (as {}), after receiving a Signature of {} bytes",
+            self.sent_size, self.method, self.signature_size
+        )
+    }
+}
+
+/// How many times, and how patiently, a sender tries to sync before giving up.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Wait before the first retry. It doubles on each retry after that.
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Waits are picked at random between half and all of the exponential backoff, so senders
+    // which failed together do not all retry at the same time.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=backoff - half)
+    }
+}
+
+/// Bytes sent and received through the connections it meters, across reconnections.
+#[derive(Debug, Default)]
+pub struct TrafficMeter {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl TrafficMeter {
+    /// `connection`, counting the bytes through it in this meter.
+    pub fn meter<C: Read + Write>(&self, connection: C) -> MeteredConnection<'_, C> {
+        MeteredConnection {
+            connection,
+            meter: self,
+        }
+    }
+
+    /// Bytes sent and received so far.
+    pub fn total(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed) + self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// A connection counting the bytes through it in a TrafficMeter.
+pub struct MeteredConnection<'a, C> {
+    connection: C,
+    meter: &'a TrafficMeter,
+}
+
+impl<C: Read> Read for MeteredConnection<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.connection.read(buf)?;
+        self.meter
+            .received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<C: Write> Write for MeteredConnection<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.connection.write(buf)?;
+        self.meter.sent.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+/// Limits protecting a receiver from slow, greedy or malicious senders.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ServeOptions {
+    /// Syncs handled at the same time. Further connections wait for a free worker.
+    pub workers: usize,
+    /// Syncs from a single IP address at the same time. Further connections are closed.
+    pub connections_per_client: usize,
+    /// Longest a sender may take to send or receive anything, before its sync is abandoned.
+    pub timeout: Option<Duration>,
+    /// The largest recreated file allowed, in bytes.
+    pub max_output_size: u64,
+    /// What to do when the basis file changed during a sync.
+    pub on_conflict: ConflictPolicy,
+    /// Hold an advisory lock on the basis file while writing it.
+    pub lock_basis_file: bool,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            connections_per_client: 2,
+            timeout: Some(Duration::from_secs(30)),
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+            on_conflict: ConflictPolicy::default(),
+            lock_basis_file: true,
+        }
+    }
+}
+
+/// What a receiver does when its basis file changed since its Signature was sent, e.g. because
+/// it was edited, or updated by another sync.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum ConflictPolicy {
+    /// The sync fails, and the basis file is left as it is now.
+    #[default]
+    Abort,
+    /// The basis file is replaced anyway, losing what changed meanwhile. It is not even checked.
+    Overwrite,
+    /// The recreated file is written next to the basis file, under a suffixed name.
+    KeepBoth,
+    /// Ask on the terminal which of the above to do, for each conflict.
+    Prompt,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::Abort => write!(f, "abort"),
+            ConflictPolicy::Overwrite => write!(f, "overwrite"),
+            ConflictPolicy::KeepBoth => write!(f, "keep-both"),
+            ConflictPolicy::Prompt => write!(f, "prompt"),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(ConflictPolicy::Abort),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "prompt" => Ok(ConflictPolicy::Prompt),
+            _ => Err(format!(
+                r#""{s}" is not a conflict policy. Expected "abort", "overwrite", "keep-both" or "prompt""#
+            )),
+        }
+    }
+}
+
+/// A connection accepted by a receiver.
+pub trait IncomingConnection: Read + Write + Send {
+    /// Limits how long a single read or write may wait for the sender.
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    /// Who the sender is, for per-client limits. None if senders cannot be told apart.
+    fn client(&self) -> Option<IpAddr>;
+}
+
+impl IncomingConnection for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+
+    fn client(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|address| address.ip())
+    }
+}
+
+#[cfg(unix)]
+impl IncomingConnection for std::os::unix::net::UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+
+    // Local senders are only limited by the number of workers.
+    fn client(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Last message of a sync, sent by the receiver.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum SyncOutcome {
+    /// The basis file was replaced by the recreated file, from a Delta or from the whole file.
+    Updated {
+        recreated_size: u64,
+        #[serde(default)]
+        method: TransferMethod,
+    },
+    /// The basis file changed during the sync, so the recreated file was written next to it.
+    KeptBoth {
+        recreated_size: u64,
+        method: TransferMethod,
+        filename: PathBuf,
+    },
+    /// The Delta could not be applied, and the basis file is untouched.
+    Failed { reason: String },
+    /// The sender only estimated the sync, and the basis file is untouched.
+    Estimated(SyncEstimate),
+}
+
+/// Handles a single sync on the receiving side.
+///
+/// A basis file which does not exist yet is treated as empty, so the first sync creates it. A
+/// single sync can not be resumed, as nothing is kept for the next one.
+///
+/// # Arguments
+/// * `connection` - The connection to the sender.
+/// * `basis_filename` - The file to update.
+/// * `max_output_size` - The largest recreated file allowed, in bytes.
+///
+pub fn serve_connection<C: Read + Write>(
+    connection: &mut C,
+    basis_filename: &Path,
+    max_output_size: u64,
+) -> eyre::Result<SyncOutcome> {
+    let options = ServeOptions {
+        max_output_size,
+        ..Default::default()
+    };
+    let partial_uploads = PartialUploads::default();
+    serve_sync(
+        connection,
+        basis_filename,
+        &options,
+        &RwLock::new(()),
+        &partial_uploads,
+    )
+}
+
+/// Handles syncs from `incoming` connections with a pool of workers, until `incoming` ends.
+///
+/// What was received of syncs whose connection broke is kept for as many syncs as there are
+/// workers (each at most `max_output_size` bytes), so their senders can resume them.
+///
+/// # Arguments
+/// * `incoming` - The accepted connections, e.g. from `TcpListener::incoming`.
+/// * `basis_filename` - The file to update.
+/// * `options` - The limits applied to senders.
+/// * `report` - Called with the result of each sync, from the worker which handled it.
+///
+pub fn serve_connections<C: IncomingConnection>(
+    incoming: impl Iterator<Item = std::io::Result<C>>,
+    basis_filename: &Path,
+    options: &ServeOptions,
+    report: impl Fn(eyre::Result<SyncOutcome>) + Sync,
+) {
+    let basis_lock = RwLock::new(());
+    let partial_uploads = PartialUploads::default();
+    let active_syncs: Mutex<HashMap<IpAddr, usize>> = Mutex::default();
+    // At most one waiting connection per worker, so an accepted connection never waits long.
+    let (queue, waiting) = mpsc::sync_channel::<(C, ClientSync)>(options.workers);
+    let waiting = Mutex::new(waiting);
+
+    thread::scope(|scope| {
+        for _ in 0..options.workers.max(1) {
+            scope.spawn(|| loop {
+                let next = waiting
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+                let Ok((mut connection, client_sync)) = next else {
+                    break;
+                };
+                let result = connection
+                    .set_timeout(options.timeout)
+                    .map_err(eyre::Report::from)
+                    .and_then(|_| {
+                        serve_sync(
+                            &mut connection,
+                            basis_filename,
+                            options,
+                            &basis_lock,
+                            &partial_uploads,
+                        )
+                    });
+                drop(client_sync);
+                report(result);
+            });
+        }
+
+        for connection in incoming {
+            let connection = match connection {
+                Ok(connection) => connection,
+                Err(error) => {
+                    report(Err(error.into()));
+                    continue;
+                }
+            };
+            // The address is only read once: the connection may not know it anymore later.
+            let client = connection.client();
+            if let Some(client) = client {
+                let mut active_syncs = active_syncs.lock().unwrap_or_else(PoisonError::into_inner);
+                let syncs = active_syncs.entry(client).or_default();
+                if *syncs >= options.connections_per_client {
+                    report(Err(eyre!(
+                        "Too many syncs from {client} at once, closed its connection"
+                    )));
+                    continue;
+                }
+                *syncs += 1;
+            }
+            let client_sync = ClientSync {
+                client,
+                active_syncs: &active_syncs,
+            };
+            if queue.send((connection, client_sync)).is_err() {
+                break;
+            }
+        }
+        // Workers stop once every queued connection was handled.
+        drop(queue);
+    });
+}
+
+// A sync counted against its client until it is dropped, even by a worker which panicked.
+struct ClientSync<'a> {
+    client: Option<IpAddr>,
+    active_syncs: &'a Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Drop for ClientSync<'_> {
+    fn drop(&mut self) {
+        let Some(client) = self.client else {
+            return;
+        };
+        let mut active_syncs = self
+            .active_syncs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(syncs) = active_syncs.get_mut(&client) {
+            *syncs = syncs.saturating_sub(1);
+            if *syncs == 0 {
+                active_syncs.remove(&client);
+            }
+        }
+    }
+}
+
+// The lock only guards the basis file on disk, so a sync never reads a partially written one.
+// It holds no data, so a sync which panicked while holding it does not poison it.
+fn serve_sync<C: Read + Write>(
+    connection: &mut C,
+    basis_filename: &Path,
+    options: &ServeOptions,
+    basis_lock: &RwLock<()>,
+    partial_uploads: &PartialUploads,
+) -> eyre::Result<SyncOutcome> {
+    let max_output_size = options.max_output_size;
+    let request: SyncRequest = read_frame_from(connection)?
+        .ok_or_else(|| eyre!("Sender closed the sync before sending a request"))?;
+    request
+        .mode
+        .check_chunk_size(request.chunk_size)
+        .wrap_err("Sender asked for blocks which can not be used")?;
+
+    let basis_file = {
+        let _reading = basis_lock.read().unwrap_or_else(PoisonError::into_inner);
+        read_basis_file(basis_filename)?
+    };
+    let basis_digest = basis_file.as_ref().map(|content| FileDigest::of(content));
+    let basis_file = basis_file.unwrap_or_default();
+    // Kept uploads are those of the sync as first requested.
+    let resumed = request.resume;
+    let request = SyncRequest {
+        resume: false,
+        ..request
+    };
+    let received = match resumed {
+        true => {
+            let received = take_partial_upload(partial_uploads, request, basis_digest);
+            let answer = received.as_ref().map(|received| received.len() as u64);
+            write_frame(connection, &answer)?;
+            connection.flush()?;
+            received
+        }
+        false => None,
+    };
+    let received = match received {
+        Some(received) => received,
+        None => {
+            send_basis_signature(connection, &basis_file, request)?;
+            if request.estimate_only {
+                let estimate = read_frame_from(connection)?
+                    .ok_or_else(|| eyre!("Sender closed the sync before sending its estimate"))?;
+                let outcome = SyncOutcome::Estimated(estimate);
+                write_frame(connection, &outcome)?;
+                connection.flush()?;
+                return Ok(outcome);
+            }
+            Vec::new()
+        }
+    };
+
+    let mut upload = UploadReader {
+        connection: &mut *connection,
+        received,
+        replayed: 0,
+        keep: request.transfer.is_some(),
+        max_kept: max_output_size,
+        broke: false,
+    };
+    // Every token recreates at least one byte, so larger Deltas are rejected as they arrive.
+    let delta =
+        read_streamed_delta(&mut upload, max_output_size).context("Error while receiving Delta");
+    // What follows the Delta is read even if it was rejected, so the sender can get our answer.
+    let whole_file = match request.whole_file_fallback {
+        true => receive_whole_file(&mut upload, request, max_output_size)
+            .context("Error while receiving the whole file"),
+        false => Ok(None),
+    };
+    if upload.broke {
+        let error = delta
+            .and(whole_file)
+            .err()
+            .unwrap_or_else(|| eyre!("Sender closed the sync before its end"));
+        let UploadReader { received, keep, .. } = upload;
+        return match (request.transfer, keep) {
+            (Some(transfer), true) => {
+                let kept = received.len();
+                let partial_upload = PartialUpload {
+                    request,
+                    basis_digest,
+                    received,
+                };
+                keep_partial_upload(partial_uploads, transfer, partial_upload, options.workers);
+                Err(error.wrap_err(format!(
+                    "Connection to the sender broke, {kept} bytes were kept to resume the sync"
+                )))
+            }
+            _ => Err(error.wrap_err("Connection to the sender broke")),
+        };
+    }
+
+    let outcome = delta
+        .and_then(|delta| match whole_file? {
+            Some(whole_file) => Ok(whole_file),
+            None => apply_delta_with_mode(
+                basis_file,
+                delta,
+                request.chunk_size,
+                request.mode,
+                max_output_size,
+            )
+            .map(|recreated| (recreated, TransferMethod::Delta)),
+        })
+        .and_then(|(recreated, method)| {
+            let recreated_size = recreated.len() as u64;
+            let _writing = basis_lock.write().unwrap_or_else(PoisonError::into_inner);
+            let destination = resolve_conflict(basis_filename, basis_digest, options.on_conflict)?;
+            match options.lock_basis_file {
+                true => io_utils::write_buf_to_locked_file(&destination, recreated)?,
+                false => io_utils::write_to_file(&destination, recreated)?,
+            }
+            Ok((recreated_size, method, destination))
+        });
+    let outcome = match outcome {
+        Ok((recreated_size, method, destination)) if destination == basis_filename => {
+            SyncOutcome::Updated {
+                recreated_size,
+                method,
+            }
+        }
+        Ok((recreated_size, method, filename)) => SyncOutcome::KeptBoth {
+            recreated_size,
+            method,
+            filename,
+        },
+        Err(error) => SyncOutcome::Failed {
+            reason: format!("{error:#}"),
+        },
+    };
+    write_frame(connection, &outcome)?;
+    connection.flush()?;
+
+    Ok(outcome)
+}
+
+// Sends the Signature of the basis file as `request` asks for it: whole, or with coarse blocks
+// first and then fine blocks of the coarse blocks which changed.
+fn send_basis_signature<C: Read + Write>(
+    connection: &mut C,
+    basis_file: &Bytes,
+    request: SyncRequest,
+) -> eyre::Result<()> {
+    match request.coarse_chunk_size {
+        Some(coarse_chunk_size) => {
+            if request.mode != ChunkingMode::Fixed
+                || !coarse_chunk_size.is_multiple_of(request.chunk_size)
+            {
+                return Err(eyre!(
+                    "Sender asked for coarse blocks of {coarse_chunk_size} bytes, which do not \
+                     fit blocks of {} bytes in {} mode",
+                    request.chunk_size,
+                    request.mode
+                ));
+            }
+            let coarse_signature = compute_signature(basis_file.clone(), coarse_chunk_size);
+            send_signature(connection, &coarse_signature)?;
+
+            let changed: Vec<Range<usize>> = read_frame_from(connection)?
+                .ok_or_else(|| eyre!("Sender did not tell which coarse blocks changed"))?;
+            let sparse = compute_sparse_signature(
+                basis_file,
+                request.chunk_size,
+                coarse_chunk_size,
+                &changed,
+            );
+            send_sparse_signature(connection, &sparse)?;
+        }
+        None => {
+            let mut signature =
+                compute_signature_with_mode(basis_file.clone(), request.chunk_size, request.mode);
+            // Without a single block to match, the sender sends the whole file at once.
+            let larger_than_file = request
+                .file_size
+                .is_some_and(|file_size| signature.serialized_size_estimate() >= file_size);
+            if request.whole_file_fallback && larger_than_file {
+                signature = FileSignature {
+                    strong_hashes: Vec::new(),
+                    rolling_hashes: Vec::new(),
+                    basis: None,
+                    weak_hash: WeakHash::default(),
+                    strong_hash: StrongHash::default(),
+                    strong_hash_tails: StrongHashTails::default(),
+                };
+            }
+            send_signature(connection, &signature)?;
+        }
+    }
+
+    Ok(())
+}
+
+// What a receiver kept of the syncs whose connection broke, by transfer, the oldest first.
+type PartialUploads = Mutex<VecDeque<(u64, PartialUpload)>>;
+
+// What a receiver received of a sync after sending its Signature, to resume it.
+struct PartialUpload {
+    request: SyncRequest,
+    basis_digest: Option<FileDigest>,
+    received: Vec<u8>,
+}
+
+// Keeps what was received of the sync `transfer`, dropping the oldest partial uploads beyond
+// `capacity`.
+fn keep_partial_upload(
+    partial_uploads: &PartialUploads,
+    transfer: u64,
+    partial_upload: PartialUpload,
+    capacity: usize,
+) {
+    let mut partial_uploads = partial_uploads
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    partial_uploads.retain(|(kept, _)| *kept != transfer);
+    while partial_uploads.len() >= capacity.max(1) {
+        partial_uploads.pop_front();
+    }
+    partial_uploads.push_back((transfer, partial_upload));
+}
+
+// What was received of the sync `request` resumes, if it was kept, for the same request, and the
+// basis file is still the one its Signature was computed from.
+fn take_partial_upload(
+    partial_uploads: &PartialUploads,
+    request: SyncRequest,
+    basis_digest: Option<FileDigest>,
+) -> Option<Vec<u8>> {
+    let mut partial_uploads = partial_uploads
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let position = partial_uploads
+        .iter()
+        .position(|(transfer, _)| Some(*transfer) == request.transfer)?;
+    let (_, partial_upload) = partial_uploads.remove(position)?;
+    (partial_upload.request == request && partial_upload.basis_digest == basis_digest)
+        .then_some(partial_upload.received)
+}
+
+// Reads what the sender sends after the Signature: first what was received of it before the sync
+// was resumed, then the connection. Keeps what it reads if the sync may be resumed, up to
+// `max_kept` bytes, and tells whether the connection broke.
+struct UploadReader<'a, C> {
+    connection: &'a mut C,
+    received: Vec<u8>,
+    replayed: usize,
+    keep: bool,
+    max_kept: u64,
+    broke: bool,
+}
+
+impl<C: Read> Read for UploadReader<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(rest) = self
+            .received
+            .get(self.replayed..)
+            .filter(|rest| !rest.is_empty())
+        {
+            let length = rest.len().min(buf.len());
+            buf[..length].copy_from_slice(&rest[..length]);
+            self.replayed += length;
+            return Ok(length);
+        }
+
+        let read = self.connection.read(buf);
+        match read {
+            Ok(0) if !buf.is_empty() => self.broke = true,
+            Ok(length) if self.keep => {
+                if (self.received.len() + length) as u64 > self.max_kept {
+                    self.keep = false;
+                    self.received = Vec::new();
+                    self.replayed = 0;
+                } else {
+                    self.received.extend_from_slice(&buf[..length]);
+                    self.replayed += length;
+                }
+            }
+            Err(ref error) if error.kind() != ErrorKind::Interrupted => self.broke = true,
+            _ => {}
+        }
+        read
+    }
+}
+
+// None when the basis file does not exist yet, which is not the same as an empty one.
+fn read_basis_file(basis_filename: &Path) -> eyre::Result<Option<Bytes>> {
+    match std::fs::read(basis_filename) {
+        Ok(content) => Ok(Some(Bytes::from(content))),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).wrap_err("Error while reading Basis file"),
+    }
+}
+
+// Prompts from several workers are asked one at a time.
+static PROMPT: Mutex<()> = Mutex::new(());
+
+// Tells where the recreated file is written: over the basis file if it did not change since it
+// was read (as `basis_digest`), or as `policy` tells otherwise. Must be called with the basis file
+// locked for writing, so another sync can not change it in between.
+fn resolve_conflict(
+    basis_filename: &Path,
+    basis_digest: Option<FileDigest>,
+    policy: ConflictPolicy,
+) -> eyre::Result<PathBuf> {
+    if policy == ConflictPolicy::Overwrite {
+        return Ok(basis_filename.to_path_buf());
+    }
+    let current_digest = read_basis_file(basis_filename)?.map(|content| FileDigest::of(&content));
+    if current_digest == basis_digest {
+        return Ok(basis_filename.to_path_buf());
+    }
+
+    let policy = match policy {
+        ConflictPolicy::Prompt => {
+            let _prompting = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+            prompt_for_conflict(
+                basis_filename,
+                &mut std::io::stdin().lock(),
+                &mut std::io::stderr(),
+            )?
+        }
+        policy => policy,
+    };
+    match policy {
+        ConflictPolicy::Overwrite => Ok(basis_filename.to_path_buf()),
+        ConflictPolicy::KeepBoth => Ok(conflict_filename(basis_filename)),
+        ConflictPolicy::Abort | ConflictPolicy::Prompt => Err(eyre!(
+            "Basis file changed since its Signature was sent, so it was left as it is"
+        )),
+    }
+}
+
+/// Asks which of `abort`, `overwrite` and `keep-both` to do about a basis file which changed
+/// during a sync, until a valid answer is given. Without an answer, the sync is aborted.
+///
+/// # Arguments
+/// * `basis_filename` - The file which changed.
+/// * `input` - Where the answer is read from, usually the terminal.
+/// * `output` - Where the question is written to, usually the terminal.
+///
+pub fn prompt_for_conflict(
+    basis_filename: &Path,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::io::Result<ConflictPolicy> {
+    loop {
+        write!(
+            output,
+            "{} changed during a sync. [a]bort, [o]verwrite or [k]eep both? ",
+            basis_filename.display()
+        )?;
+        output.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Ok(ConflictPolicy::Abort);
+        }
+        match answer.trim() {
+            "a" | "abort" => return Ok(ConflictPolicy::Abort),
+            "o" | "overwrite" => return Ok(ConflictPolicy::Overwrite),
+            "k" | "keep-both" => return Ok(ConflictPolicy::KeepBoth),
+            _ => continue,
+        }
+    }
+}
+
+// `file.txt` becomes `file.txt.conflict-1`, or `-2`, `-3`... if that one is taken.
+fn conflict_filename(basis_filename: &Path) -> PathBuf {
+    (1..)
+        .map(|attempt| {
+            let mut filename = basis_filename.as_os_str().to_owned();
+            filename.push(format!(".conflict-{attempt}"));
+            PathBuf::from(filename)
+        })
+        .find(|filename| !filename.exists())
+        .expect("Some suffix is always free")
+}
+
+/// Syncs `updated_file` to the receiver at the other end of `connection`.
+///
+/// # Arguments
+/// * `connection` - The connection to the receiver.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `request` - How files are divided into blocks.
+/// * `parallelism` - How many threads hash our file. With more than one, hashing starts
+///   while the Signature is still being received.
+///
+pub fn push_file<C: Read + Write>(
+    connection: &mut C,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    parallelism: &Parallelism,
+) -> eyre::Result<SyncOutcome> {
+    push_attempt(connection, updated_file, request, parallelism, &mut None)
+}
+
+/// Syncs `updated_file`, connecting again when an attempt fails, and resuming the sync.
+///
+/// What was sent after the Signature is kept in memory between attempts, so a sync whose
+/// connection broke while the Delta (or the whole file) was sent is resumed where the receiver
+/// stopped receiving it, if the receiver kept it too (see `serve_connections`). Otherwise, it
+/// starts over, which is always safe: a failed attempt never changes the receiver's file. Only
+/// connection errors are retried: a receiver answering `SyncOutcome::Failed` will not change its
+/// mind.
+///
+/// # Arguments
+/// * `connect` - Opens a new connection to the receiver.
+/// * `updated_file` - Our updated file, in bytes.
+/// * `request` - How files are divided into blocks. Its `transfer` is picked here.
+/// * `parallelism` - How many threads hash our file.
+/// * `policy` - How many times to retry, and how long to wait in between.
+/// * `on_retry` - Called with the error of each failed attempt, and the wait before the next one.
+///
+pub fn push_file_with_retries<C: Read + Write>(
+    mut connect: impl FnMut() -> std::io::Result<C>,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    parallelism: &Parallelism,
+    policy: &RetryPolicy,
+    mut on_retry: impl FnMut(&eyre::Report, Duration),
+) -> eyre::Result<SyncOutcome> {
+    // Estimates send nothing worth resuming.
+    let request = SyncRequest {
+        transfer: (policy.retries > 0 && !request.estimate_only).then(rand::random),
+        resume: false,
+        ..request
+    };
+    let mut sent = None;
+    let mut retry = 0;
+    loop {
+        let attempt = connect()
+            .wrap_err("Unable to connect to the receiver")
+            .and_then(|mut connection| {
+                push_attempt(
+                    &mut connection,
+                    updated_file,
+                    request,
+                    parallelism,
+                    &mut sent,
+                )
+            });
+        match attempt {
+            Ok(outcome) => return Ok(outcome),
+            Err(error) if retry < policy.retries => {
+                let backoff = policy.backoff(retry);
+                on_retry(&error, backoff);
+                thread::sleep(backoff);
+                retry += 1;
+            }
+            Err(error) => {
+                return Err(error.wrap_err(format!("Sync failed after {} attempts", retry + 1)))
+            }
+        }
+    }
+}
+
+// Resumes the sync if what was sent of it is in `sent`, or starts it otherwise, keeping there
+// what is sent after the Signature if the sync names a transfer.
+fn push_attempt<C: Read + Write>(
+    connection: &mut C,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    parallelism: &Parallelism,
+    sent: &mut Option<Vec<u8>>,
+) -> eyre::Result<SyncOutcome> {
+    let resumed = match sent {
+        Some(upload) => {
+            let resume = SyncRequest {
+                resume: true,
+                ..request
+            };
+            write_frame(connection, &resume)?;
+            connection.flush()?;
+            let received: Option<u64> = read_frame_from(connection)?
+                .ok_or_else(|| eyre!("Receiver closed the sync without telling what it kept"))?;
+            match received {
+                Some(received) => {
+                    let rest = usize::try_from(received)
+                        .ok()
+                        .and_then(|received| upload.get(received..))
+                        .ok_or_else(|| {
+                            eyre!("Receiver kept {received} bytes of the sync, more than were sent")
+                        })?;
+                    connection.write_all(rest)?;
+                    connection.flush()?;
+                    true
+                }
+                // The receiver starts over, sending its Signature again.
+                None => false,
+            }
+        }
+        None => {
+            write_frame(connection, &request)?;
+            connection.flush()?;
+            false
+        }
+    };
+
+    if !resumed {
+        *sent = None;
+        match request.coarse_chunk_size {
+            Some(coarse_chunk_size) => push_hierarchical_delta(
+                connection,
+                updated_file,
+                request,
+                coarse_chunk_size,
+                parallelism,
+                sent,
+            )?,
+            None => push_delta(connection, updated_file, request, parallelism, sent)?,
+        }
+    }
+
+    read_frame_from(connection)?.ok_or_else(|| eyre!("Receiver closed the sync without answering"))
+}
+
+fn push_delta<C: Read + Write>(
+    connection: &mut C,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    parallelism: &Parallelism,
+    sent: &mut Option<Vec<u8>>,
+) -> eyre::Result<()> {
+    // Our rolling hashes do not depend on the Signature (the protocol always uses the polynomial
+    // weak hash), so they are computed while it arrives.
+    let (our_sliding_blocks_rolling_hashes, signature) = parallelism.join(
+        || match request.mode {
+            ChunkingMode::Fixed => compute_sliding_rolling_hashes_in_parallel(
+                updated_file,
+                request.chunk_size,
+                WeakHash::Polynomial,
+                parallelism,
+            ),
+            ChunkingMode::Lines => Vec::new(),
+        },
+        || receive_signature(connection),
+    );
+    let signature = signature.context("Error while receiving Signature")?;
+
+    let header = DeltaHeader {
+        output_length: Some(updated_file.len() as u64),
+        ..Default::default()
+    };
+    let mut upload = UploadWriter::new(connection, request);
+    let writer = DeltaWriter::new(delta_output(&mut upload, request), &header)?;
+    let signature_size = signature.serialized_size_estimate();
+    let limit = whole_file_limit(request, updated_file, signature_size);
+    let mut tokens = DeltaSizeEstimator::new(writer, limit);
+    let matched = match request.mode {
+        ChunkingMode::Fixed => stream_fixed_delta(
+            &signature,
+            updated_file,
+            request.chunk_size,
+            &our_sliding_blocks_rolling_hashes,
+            &MatchingOptions::default(),
+            &mut tokens,
+        ),
+        ChunkingMode::Lines => stream_delta_with_mode(
+            &signature,
+            updated_file,
+            request.chunk_size,
+            request.mode,
+            &MatchingOptions::default(),
+            &mut tokens,
+        ),
+    };
+    let delta_size = tokens.estimate();
+    let too_large = finish_delta(tokens, matched)?;
+    match request.estimate_only {
+        true => send_estimate(
+            &mut upload,
+            updated_file,
+            request,
+            signature_size,
+            delta_size,
+            too_large,
+        )?,
+        false => send_whole_file_if_needed(&mut upload, updated_file, request, too_large)?,
+    }
+    upload.finish(sent)
+}
+
+fn push_hierarchical_delta<C: Read + Write>(
+    connection: &mut C,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    coarse_chunk_size: usize,
+    parallelism: &Parallelism,
+    sent: &mut Option<Vec<u8>>,
+) -> eyre::Result<()> {
+    let (updated_coarse_signature, basis_coarse_signature) = parallelism.join(
+        || {
+            compute_signature_in_parallel(
+                updated_file.clone(),
+                coarse_chunk_size,
+                ChunkingMode::Fixed,
+                parallelism,
+            )
+        },
+        || receive_signature(connection),
+    );
+    let basis_coarse_signature =
+        basis_coarse_signature.context("Error while receiving coarse Signature")?;
+
+    let changed = changed_coarse_blocks(&basis_coarse_signature, &updated_coarse_signature);
+    write_frame(connection, &changed)?;
+    connection.flush()?;
+    let sparse = receive_sparse_signature(connection).context("Error while receiving Signature")?;
+
+    let header = DeltaHeader {
+        output_length: Some(updated_file.len() as u64),
+        ..Default::default()
+    };
+    let mut upload = UploadWriter::new(connection, request);
+    let writer = DeltaWriter::new(delta_output(&mut upload, request), &header)?;
+    let signature_size = basis_coarse_signature.serialized_size_estimate()
+        + sparse.signature.serialized_size_estimate();
+    let limit = whole_file_limit(request, updated_file, signature_size);
+    let mut tokens = DeltaSizeEstimator::new(writer, limit);
+    let matched = stream_hierarchical_delta(
+        &basis_coarse_signature,
+        &updated_coarse_signature,
+        &sparse,
+        updated_file,
+        request.chunk_size,
+        coarse_chunk_size,
+        &mut tokens,
+    );
+    let delta_size = tokens.estimate();
+    let too_large = finish_delta(tokens, matched)?;
+    match request.estimate_only {
+        true => send_estimate(
+            &mut upload,
+            updated_file,
+            request,
+            signature_size,
+            delta_size,
+            too_large,
+        )?,
+        false => send_whole_file_if_needed(&mut upload, updated_file, request, too_large)?,
+    }
+    upload.finish(sent)
+}
+
+// Writes what the sender sends after the Signature to the receiver, keeping it too if the sync
+// names a transfer. Once the connection fails, what follows is still kept, for the next attempt to
+// send, and the error is only returned by `finish`.
+struct UploadWriter<'a, C> {
+    connection: &'a mut C,
+    sent: Option<Vec<u8>>,
+    error: Option<std::io::Error>,
+}
+
+impl<'a, C: Write> UploadWriter<'a, C> {
+    fn new(connection: &'a mut C, request: SyncRequest) -> Self {
+        Self {
+            connection,
+            sent: request.transfer.map(|_| Vec::new()),
+            error: None,
+        }
+    }
+
+    // Gives what was sent to `sent`, once it is all written.
+    fn finish(self, sent: &mut Option<Vec<u8>>) -> eyre::Result<()> {
+        *sent = self.sent;
+        match self.error {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<C: Write> Write for UploadWriter<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(sent) = &mut self.sent else {
+            return self.connection.write(buf);
+        };
+        sent.extend_from_slice(buf);
+        if self.error.is_none() {
+            self.error = self.connection.write_all(buf).err();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.sent.is_none() {
+            return self.connection.flush();
+        }
+        if self.error.is_none() {
+            self.error = self.connection.flush().err();
+        }
+        Ok(())
+    }
+}
+
+// Where the Delta is written: to the receiver, or nowhere when the sync is only estimated.
+fn delta_output<'a>(connection: &'a mut impl Write, request: SyncRequest) -> Box<dyn Write + 'a> {
+    match request.estimate_only {
+        true => Box::new(std::io::sink()),
+        false => Box::new(BufWriter::new(connection)),
+    }
+}
+
+// Once the Signature and the Delta take more bytes than the file, sending the file is cheaper.
+// The whole file is only deflated once it is sent, so it is compared uncompressed.
+fn whole_file_limit(
+    request: SyncRequest,
+    updated_file: &Bytes,
+    signature_size: u64,
+) -> Option<u64> {
+    request
+        .whole_file_fallback
+        .then_some((updated_file.len() as u64).saturating_sub(signature_size))
+}
+
+// Ends the Delta, even if matching stopped because it was too large, and tells whether it did.
+fn finish_delta<W: Write>(
+    tokens: DeltaSizeEstimator<DeltaWriter<W>>,
+    matched: eyre::Result<()>,
+) -> eyre::Result<bool> {
+    let too_large = match matched {
+        Ok(()) => false,
+        Err(error) if error.downcast_ref::<DeltaTooLarge>().is_some() => true,
+        Err(error) => return Err(error),
+    };
+    tokens.into_inner().finish()?.flush()?;
+
+    Ok(too_large)
+}
+
+fn send_whole_file_if_needed(
+    connection: &mut impl Write,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    too_large: bool,
+) -> eyre::Result<()> {
+    if !request.whole_file_fallback {
+        return Ok(());
+    }
+    write_frame(connection, &too_large)?;
+    if too_large {
+        let (_, whole_file) = encode_whole_file(updated_file, request.compress_whole_file)?;
+        for frame in whole_file.chunks(WHOLE_FILE_FRAME_SIZE) {
+            write_raw_frame(connection, frame)?;
+        }
+        write_end_frame(connection)?;
+    }
+    connection.flush()?;
+
+    Ok(())
+}
+
+// Tells the receiver what the sync would have sent, instead of sending it. The whole file is
+// encoded to measure it, as deflating it may save more or less than expected.
+fn send_estimate(
+    connection: &mut impl Write,
+    updated_file: &Bytes,
+    request: SyncRequest,
+    signature_size: u64,
+    delta_size: u64,
+    too_large: bool,
+) -> eyre::Result<()> {
+    let (method, sent_size) = match too_large {
+        // The Delta is still sent up to where it grew too large.
+        true => {
+            let (method, whole_file) =
+                encode_whole_file(updated_file, request.compress_whole_file)?;
+            (method, delta_size + whole_file.len() as u64)
+        }
+        false => (TransferMethod::Delta, delta_size),
+    };
+    let estimate = SyncEstimate {
+        signature_size,
+        sent_size,
+        method,
+    };
+    write_frame(connection, &estimate)?;
+    connection.flush()?;
+
+    Ok(())
+}
+
+// Returns the whole file if the sender sent it instead of (the rest of) the Delta.
+fn receive_whole_file(
+    connection: &mut impl Read,
+    request: SyncRequest,
+    max_output_size: u64,
+) -> eyre::Result<Option<(Bytes, TransferMethod)>> {
+    let too_large: bool = read_frame_from(connection)?
+        .ok_or_else(|| eyre!("Sender did not tell whether the Delta was complete"))?;
+    if !too_large {
+        return Ok(None);
+    }
+
+    // A file over the limit is still read to its end, but not kept. Compressed files are checked
+    // again once decompressed.
+    let mut whole_file = Vec::new();
+    let mut too_large_to_keep = false;
+    while let Some(frame) = read_raw_frame_from(connection)? {
+        too_large_to_keep |= (whole_file.len() + frame.len()) as u64 > max_output_size;
+        if !too_large_to_keep {
+            whole_file.extend_from_slice(&frame);
+        }
+    }
+    if too_large_to_keep {
+        return Err(eyre!(
+            "Recreated file is larger than the maximum output size ({max_output_size} bytes)"
+        ));
+    }
+
+    let method = match request.compress_whole_file {
+        true => TransferMethod::CompressedWholeFile,
+        false => TransferMethod::WholeFile,
+    };
+    let whole_file = decode_whole_file(whole_file.into(), method, max_output_size)?;
+
+    Ok(Some((whole_file, method)))
+}
+
+fn send_signature(connection: &mut impl Write, signature: &FileSignature) -> eyre::Result<()> {
+    let mut writer = BufWriter::new(connection);
+    let block_count = signature.strong_hashes.len();
+    for start in (0..block_count).step_by(BLOCKS_PER_FRAME) {
+        // Each batch records the strong hash, so the sender hashes its blocks the same way.
+        let batch = signature.blocks(start..(start + BLOCKS_PER_FRAME).min(block_count));
+        write_frame(&mut writer, &batch)?;
+    }
+    write_end_frame(&mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn receive_signature(connection: &mut impl Read) -> eyre::Result<FileSignature> {
+    let mut signature = FileSignature {
+        strong_hashes: Vec::new(),
+        rolling_hashes: Vec::new(),
+        basis: None,
+        weak_hash: WeakHash::default(),
+        strong_hash: StrongHash::default(),
+        strong_hash_tails: StrongHashTails::default(),
+    };
+    while let Some(batch) = read_frame_from::<FileSignature>(connection)? {
+        signature.strong_hash = batch.strong_hash;
+        signature.strong_hashes.extend(batch.strong_hashes);
+        signature.strong_hash_tails.extend(&batch.strong_hash_tails);
+        signature.rolling_hashes.extend(batch.rolling_hashes);
+    }
+
+    Ok(signature)
+}
+
+fn send_sparse_signature(
+    connection: &mut impl Write,
+    sparse: &SparseSignature,
+) -> eyre::Result<()> {
+    let mut writer = BufWriter::new(connection);
+    let block_count = sparse.block_indexes.len();
+    for start in (0..block_count).step_by(BLOCKS_PER_FRAME) {
+        let blocks = start..(start + BLOCKS_PER_FRAME).min(block_count);
+        let batch = SparseSignature {
+            block_indexes: sparse.block_indexes[blocks.clone()].to_vec(),
+            signature: sparse.signature.blocks(blocks),
+        };
+        write_frame(&mut writer, &batch)?;
+    }
+    write_end_frame(&mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn receive_sparse_signature(connection: &mut impl Read) -> eyre::Result<SparseSignature> {
+    let mut sparse = SparseSignature {
+        block_indexes: Vec::new(),
+        signature: FileSignature {
+            strong_hashes: Vec::new(),
+            rolling_hashes: Vec::new(),
+            basis: None,
+            weak_hash: WeakHash::default(),
+            strong_hash: StrongHash::default(),
+            strong_hash_tails: StrongHashTails::default(),
+        },
+    };
+    while let Some(batch) = read_frame_from::<SparseSignature>(connection)? {
+        sparse.signature.strong_hash = batch.signature.strong_hash;
+        sparse
+            .signature
+            .strong_hash_tails
+            .extend(&batch.signature.strong_hash_tails);
+        sparse.block_indexes.extend(batch.block_indexes);
+        sparse
+            .signature
+            .strong_hashes
+            .extend(batch.signature.strong_hashes);
+        sparse
+            .signature
+            .rolling_hashes
+            .extend(batch.signature.rolling_hashes);
+    }
+
+    Ok(sparse)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    fn sync(
+        basis_filename: PathBuf,
+        updated_file: Bytes,
+        max_output_size: u64,
+        coarse_chunk_size: Option<usize>,
+    ) -> SyncOutcome {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            serve_connection(&mut connection, &basis_filename, max_output_size).unwrap()
+        });
+
+        let mut connection = TcpStream::connect(address).unwrap();
+        let request = SyncRequest {
+            chunk_size: 32,
+            mode: ChunkingMode::Fixed,
+            coarse_chunk_size,
+            whole_file_fallback: true,
+            compress_whole_file: false,
+            file_size: Some(updated_file.len() as u64),
+            estimate_only: false,
+            transfer: None,
+            resume: false,
+        };
+        let outcome = push_file(
+            &mut connection,
+            &updated_file,
+            request,
+            &Parallelism::serial(),
+        )
+        .unwrap();
+
+        assert_eq!(receiver.join().unwrap(), outcome);
+        outcome
+    }
+
+    #[test]
+    fn push_updates_the_basis_file() {
+        let basis_filename = scratch_directory("network_push").join("basis_file");
+        let lines: Vec<_> = (0..1000).map(|line| format!("{line:031}\n")).collect();
+        std::fs::write(&basis_filename, lines.concat()).unwrap();
+        let updated_file =
+            Bytes::from(lines[..500].concat() + "new line\n" + &lines[500..].concat());
+
+        let outcome = sync(basis_filename.clone(), updated_file.clone(), u64::MAX, None);
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::Updated {
+                recreated_size: updated_file.len() as u64,
+                method: TransferMethod::Delta,
+            }
+        );
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn failed_sync_leaves_the_basis_file_untouched() {
+        let basis_filename = scratch_directory("network_failed").join("basis_file");
+        std::fs::write(&basis_filename, "basis").unwrap();
+
+        let outcome = sync(basis_filename.clone(), Bytes::from("too large"), 4, None);
+
+        assert!(matches!(outcome, SyncOutcome::Failed { .. }));
+        assert_eq!(std::fs::read(basis_filename).unwrap(), b"basis");
+    }
+
+    #[test]
+    fn whole_file_is_sent_instead_of_a_larger_delta() {
+        let basis_filename = scratch_directory("network_whole_file").join("basis_file");
+        std::fs::write(&basis_filename, "block1 block2 ".repeat(100)).unwrap();
+        // Nothing matches, so the Delta would be all literals, many times larger than the file.
+        let updated_file = Bytes::from("unrelated content ".repeat(100));
+
+        let outcome = sync(basis_filename.clone(), updated_file.clone(), u64::MAX, None);
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::Updated {
+                recreated_size: updated_file.len() as u64,
+                method: TransferMethod::WholeFile,
+            }
+        );
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compressed_file_is_sent_when_the_signature_is_larger() {
+        let basis_filename = scratch_directory("network_compressed").join("basis_file");
+        std::fs::write(&basis_filename, "block1 block2 ".repeat(100)).unwrap();
+        let updated_file = Bytes::from("block2 block1 ".repeat(100));
+
+        let (mut sender, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        let receiving_basis_filename = basis_filename.clone();
+        let receiver = thread::spawn(move || {
+            serve_connection(&mut receiver, &receiving_basis_filename, u64::MAX).unwrap()
+        });
+        // Blocks of 4 bytes take more than 4 bytes each in the Signature.
+        let mut request = SyncRequest::for_file(&updated_file, 4, ChunkingMode::Fixed);
+        request.compress_whole_file = true;
+        let outcome =
+            push_file(&mut sender, &updated_file, request, &Parallelism::serial()).unwrap();
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::Updated {
+                recreated_size: updated_file.len() as u64,
+                method: TransferMethod::CompressedWholeFile,
+            }
+        );
+        assert_eq!(receiver.join().unwrap(), outcome);
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn estimate_leaves_the_basis_file_untouched() {
+        let basis_filename = scratch_directory("network_estimate").join("basis_file");
+        let basis_file = "block1 block2 block3 ".repeat(1000);
+        std::fs::write(&basis_filename, &basis_file).unwrap();
+        let similar_file = Bytes::from(basis_file.replacen("block2", "blockX", 3));
+        let unrelated_file = Bytes::from("unrelated content ".repeat(1000));
+
+        for (updated_file, method) in [
+            (similar_file, TransferMethod::Delta),
+            (unrelated_file, TransferMethod::WholeFile),
+        ] {
+            let (mut sender, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+            let receiving_basis_filename = basis_filename.clone();
+            let receiver = thread::spawn(move || {
+                serve_connection(&mut receiver, &receiving_basis_filename, u64::MAX).unwrap()
+            });
+            let mut request = SyncRequest::for_file(&updated_file, 32, ChunkingMode::Fixed);
+            request.estimate_only = true;
+            let outcome =
+                push_file(&mut sender, &updated_file, request, &Parallelism::serial()).unwrap();
+
+            let SyncOutcome::Estimated(estimate) = outcome.clone() else {
+                panic!("Expected an estimate, got {outcome:?}");
+            };
+            assert_eq!(estimate.method, method);
+            assert!(estimate.signature_size > 0 && estimate.sent_size > 0);
+            assert_eq!(receiver.join().unwrap(), outcome);
+            assert_eq!(
+                std::fs::read_to_string(&basis_filename).unwrap(),
+                basis_file
+            );
+        }
+    }
+
+    #[test]
+    fn push_with_coarse_blocks_updates_the_basis_file() {
+        let basis_filename = scratch_directory("network_coarse").join("basis_file");
+        let basis_file = "block1 block2 block3 ".repeat(1000);
+        std::fs::write(&basis_filename, &basis_file).unwrap();
+        let updated_file = Bytes::from(basis_file.replacen("block2", "blockX", 3));
+
+        let outcome = sync(
+            basis_filename.clone(),
+            updated_file.clone(),
+            u64::MAX,
+            Some(64),
+        );
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::Updated {
+                recreated_size: updated_file.len() as u64,
+                method: TransferMethod::Delta,
+            }
+        );
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn push_works_over_a_unix_socket() {
+        let basis_filename = scratch_directory("network_unix").join("basis_file");
+        std::fs::write(&basis_filename, "block1 block2 ".repeat(100)).unwrap();
+        let updated_file = Bytes::from("block2 block1 ".repeat(100));
+
+        let (mut sender, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        let receiving_basis_filename = basis_filename.clone();
+        let receiver = thread::spawn(move || {
+            serve_connection(&mut receiver, &receiving_basis_filename, u64::MAX).unwrap()
+        });
+        let request = SyncRequest::for_file(&updated_file, 7, ChunkingMode::Fixed);
+        let outcome =
+            push_file(&mut sender, &updated_file, request, &Parallelism::serial()).unwrap();
+
+        assert_eq!(receiver.join().unwrap(), outcome);
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn push_is_retried_until_the_receiver_is_reachable() {
+        let basis_filename = scratch_directory("network_retries").join("basis_file");
+        std::fs::write(&basis_filename, "basis").unwrap();
+        let updated_file = Bytes::from("updated");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiving_basis_filename = basis_filename.clone();
+        let receiver = thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            serve_connection(&mut connection, &receiving_basis_filename, u64::MAX).unwrap()
+        });
+
+        let mut failures_left = 2;
+        let connect = || {
+            if failures_left > 0 {
+                failures_left -= 1;
+                return Err(ErrorKind::ConnectionRefused.into());
+            }
+            TcpStream::connect(address)
+        };
+        let policy = RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut retries = 0;
+        let request = SyncRequest::for_file(&updated_file, 4, ChunkingMode::Fixed);
+        let outcome = push_file_with_retries(
+            connect,
+            &updated_file,
+            request,
+            &Parallelism::serial(),
+            &policy,
+            |_, _| retries += 1,
+        )
+        .unwrap();
+
+        assert_eq!(retries, 2);
+        assert_eq!(receiver.join().unwrap(), outcome);
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    // A connection which breaks once `limit` bytes were written to it, counting them in `written`.
+    struct BreakingConnection<'a> {
+        stream: TcpStream,
+        limit: u64,
+        written: &'a AtomicU64,
+    }
+
+    impl Read for BreakingConnection<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.stream.read(buf)
+        }
+    }
+
+    impl Write for BreakingConnection<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let allowed = self.limit - self.written.load(Ordering::Relaxed);
+            if allowed == 0 {
+                self.stream.shutdown(std::net::Shutdown::Both)?;
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let length = buf.len().min(allowed as usize);
+            let length = self.stream.write(&buf[..length])?;
+            self.written.fetch_add(length as u64, Ordering::Relaxed);
+            Ok(length)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    #[test]
+    fn push_resumes_where_the_receiver_stopped_receiving() {
+        // Random bytes are all sent as literals, so the Delta is larger than the file.
+        let updated_file: Bytes = (0..64 * 1024).map(|_| rand::random::<u8>()).collect();
+        let request = SyncRequest {
+            whole_file_fallback: false,
+            ..SyncRequest::for_file(&updated_file, 64, ChunkingMode::Fixed)
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // What the sync writes when it does not break.
+        let basis_filename = scratch_directory("network_unbroken").join("basis_file");
+        std::fs::write(&basis_filename, "basis").unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            serve_connection(&mut connection, &basis_filename, u64::MAX).unwrap()
+        });
+        let unbroken = AtomicU64::new(0);
+        let mut connection = BreakingConnection {
+            stream: TcpStream::connect(address).unwrap(),
+            limit: u64::MAX,
+            written: &unbroken,
+        };
+        push_file(
+            &mut connection,
+            &updated_file,
+            request,
+            &Parallelism::serial(),
+        )
+        .unwrap();
+        receiver.join().unwrap();
+        let unbroken = unbroken.into_inner();
+
+        let basis_filename = scratch_directory("network_resume").join("basis_file");
+        std::fs::write(&basis_filename, "basis").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            workers: 1,
+            ..Default::default()
+        };
+        let results = Mutex::new(Vec::new());
+        // Bytes written by each attempt. The first one breaks halfway through the sync.
+        let written = [AtomicU64::new(0), AtomicU64::new(0)];
+        let limits = [unbroken / 2, u64::MAX];
+        let outcome = thread::scope(|scope| {
+            scope.spawn(|| {
+                serve_connections(
+                    listener.incoming().take(2),
+                    &basis_filename,
+                    &options,
+                    |result| results.lock().unwrap().push(result),
+                )
+            });
+
+            let mut attempt = 0;
+            let connect = || {
+                let connection = BreakingConnection {
+                    stream: TcpStream::connect(address)?,
+                    limit: limits[attempt],
+                    written: &written[attempt],
+                };
+                attempt += 1;
+                Ok(connection)
+            };
+            let policy = RetryPolicy {
+                retries: 1,
+                initial_backoff: Duration::ZERO,
+                ..Default::default()
+            };
+            push_file_with_retries(
+                connect,
+                &updated_file,
+                request,
+                &Parallelism::serial(),
+                &policy,
+                |_, _| {},
+            )
+            .unwrap()
+        });
+
+        assert!(matches!(outcome, SyncOutcome::Updated { .. }));
+        assert_eq!(std::fs::read(&basis_filename).unwrap(), updated_file);
+        let results = results.into_inner().unwrap();
+        let broken = results[0].as_ref().unwrap_err();
+        assert!(format!("{broken:#}").contains("kept to resume the sync"));
+        assert_eq!(results[1].as_ref().unwrap(), &outcome);
+        // Starting over would have sent the whole Delta again.
+        let resent = written[1].load(Ordering::Relaxed);
+        assert!(
+            resent < unbroken * 3 / 4,
+            "{resent} of {unbroken} bytes resent"
+        );
+    }
+
+    #[test]
+    fn server_handles_several_senders_and_limits_each_client() {
+        let basis_filename = scratch_directory("network_pool").join("basis_file");
+        std::fs::write(&basis_filename, "block1 block2 ".repeat(100)).unwrap();
+        let updated_file = Bytes::from("block2 block1 ".repeat(100));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // Every connection comes from the same client, which may have 3 syncs at once.
+        let idle = TcpStream::connect(address).unwrap();
+        let senders: Vec<_> = (0..2)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut rejected = TcpStream::connect(address).unwrap();
+
+        let options = ServeOptions {
+            workers: 2,
+            connections_per_client: 3,
+            timeout: Some(Duration::from_secs(5)),
+            max_output_size: u64::MAX,
+            // Both senders update the same file, so whichever finishes last finds it changed.
+            on_conflict: ConflictPolicy::Overwrite,
+            ..Default::default()
+        };
+        let results = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                serve_connections(
+                    listener.incoming().take(4),
+                    &basis_filename,
+                    &options,
+                    |result| results.lock().unwrap().push(result),
+                )
+            });
+
+            assert_eq!(rejected.read(&mut [0]).unwrap(), 0);
+            // The idle connection holds a worker, but the senders are still served by the other.
+            let request = SyncRequest::for_file(&updated_file, 7, ChunkingMode::Fixed);
+            let threads = Parallelism::with_threads(NonZeroUsize::new(2).unwrap());
+            let pushes: Vec<_> = senders
+                .into_iter()
+                .map(|mut sender| {
+                    let updated_file = &updated_file;
+                    scope.spawn(move || {
+                        push_file(&mut sender, updated_file, request, &threads).unwrap()
+                    })
+                })
+                .collect();
+            for push in pushes {
+                assert!(matches!(push.join().unwrap(), SyncOutcome::Updated { .. }));
+            }
+            drop(idle);
+        });
+
+        let results = results.into_inner().unwrap();
+        let updated = results
+            .iter()
+            .filter(|result| matches!(result, Ok(SyncOutcome::Updated { .. })))
+            .count();
+        assert_eq!((results.len(), updated), (4, 2));
+        assert_eq!(std::fs::read(basis_filename).unwrap(), updated_file);
+    }
+
+    #[test]
+    fn syncs_of_a_client_are_released_even_by_a_worker_which_panicked() {
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let active_syncs = Mutex::new(HashMap::from([(client, 2)]));
+        let client_sync = || ClientSync {
+            client: Some(client),
+            active_syncs: &active_syncs,
+        };
+
+        drop(client_sync());
+        assert_eq!(active_syncs.lock().unwrap()[&client], 1);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _client_sync = client_sync();
+            panic!("Sync failed");
+        }));
+        assert!(panicked.is_err());
+        assert!(active_syncs.lock().unwrap().is_empty());
+        // Releasing a sync which is not counted anymore changes nothing.
+        drop(client_sync());
+        assert!(active_syncs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn changed_basis_file_is_handled_as_the_policy_tells() {
+        let basis_filename = scratch_directory("conflicting_basis").join("basis_file");
+        std::fs::write(&basis_filename, "as it was signed").unwrap();
+        let basis_digest = Some(FileDigest::of(b"as it was signed"));
+        let resolve = |policy| resolve_conflict(&basis_filename, basis_digest, policy);
+
+        assert_eq!(resolve(ConflictPolicy::Abort).unwrap(), basis_filename);
+        std::fs::write(&basis_filename, "edited meanwhile").unwrap();
+        assert!(resolve(ConflictPolicy::Abort).is_err());
+        assert!(resolve(ConflictPolicy::default()).is_err());
+        assert_eq!(resolve(ConflictPolicy::Overwrite).unwrap(), basis_filename);
+        let kept = resolve(ConflictPolicy::KeepBoth).unwrap();
+        assert_eq!(kept.file_name().unwrap(), "basis_file.conflict-1");
+
+        let mut answers = "x\nkeep-both\n".as_bytes();
+        let mut questions = Vec::new();
+        let answer = prompt_for_conflict(&basis_filename, &mut answers, &mut questions).unwrap();
+        assert_eq!(answer, ConflictPolicy::KeepBoth);
+        assert_eq!(
+            String::from_utf8(questions)
+                .unwrap()
+                .matches("changed")
+                .count(),
+            2
+        );
+    }
+}
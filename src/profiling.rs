@@ -0,0 +1,216 @@
+//! Counts how often the hot loops of the algorithm run, and how long they take, for users tuning
+//! the chunk size or the hashes of large workloads.
+//!
+//! With the `profiling` feature, rolling the weak hash over windows, looking rolling hashes up in
+//! the Signature and confirming candidates with the strong hash are counted and timed (summed
+//! over threads), and the coarse steps of a command enter tracing spans, which subscribers such as
+//! `tracing-flame` turn into flamegraphs. Without it, every function here does nothing, so the
+//! loops cost the same as without instrumentation.
+
+use std::fmt;
+use std::fmt::Formatter;
+#[cfg(feature = "profiling")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+#[cfg(feature = "profiling")]
+use std::time::Instant;
+
+/// Whether this build profiles the hot loops.
+pub const ENABLED: bool = cfg!(feature = "profiling");
+
+const SECTION_COUNT: usize = 3;
+
+#[cfg(feature = "profiling")]
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "profiling")]
+static CALLS: [AtomicU64; SECTION_COUNT] = [ZERO; SECTION_COUNT];
+#[cfg(feature = "profiling")]
+static HITS: [AtomicU64; SECTION_COUNT] = [ZERO; SECTION_COUNT];
+#[cfg(feature = "profiling")]
+static NANOS: [AtomicU64; SECTION_COUNT] = [ZERO; SECTION_COUNT];
+
+/// A hot loop of the algorithm, profiled on its own.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Section {
+    /// Rolling the weak hash over the windows of our file. Calls are windows.
+    RollingHash,
+    /// Looking the rolling hash of a window up in the Signature. Hits found candidates. Too quick
+    /// to be timed one by one: they take most of the match phase the strong hashes do not.
+    Probe,
+    /// Hashing a window whose rolling hash was found. Hits matched a candidate.
+    StrongHash,
+}
+
+impl Section {
+    const ALL: [Section; SECTION_COUNT] =
+        [Section::RollingHash, Section::Probe, Section::StrongHash];
+
+    fn is_timed(self) -> bool {
+        self != Section::Probe
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::RollingHash => write!(f, "rolling hash"),
+            Section::Probe => write!(f, "probe"),
+            Section::StrongHash => write!(f, "strong hash"),
+        }
+    }
+}
+
+/// What a single Section did, since the process started or the last `reset`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SectionProfile {
+    pub section: Section,
+    pub calls: u64,
+    pub hits: u64,
+    pub time: Duration,
+}
+
+/// What every Section did, as `--profile-report` prints it.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ProfileReport {
+    pub sections: Vec<SectionProfile>,
+}
+
+/// Counts `calls` more runs of `section`, of which `hits` found what they looked for.
+#[inline(always)]
+pub fn count(section: Section, calls: u64, hits: u64) {
+    #[cfg(feature = "profiling")]
+    {
+        CALLS[section as usize].fetch_add(calls, Ordering::Relaxed);
+        HITS[section as usize].fetch_add(hits, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "profiling"))]
+    let _ = (section, calls, hits);
+}
+
+/// Runs `f`, which makes `calls` runs of `section`, and adds the time it took to it.
+#[inline(always)]
+pub fn measure<T>(section: Section, calls: u64, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "profiling")]
+    {
+        let start = Instant::now();
+        let result = f();
+        NANOS[section as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        count(section, calls, 0);
+        result
+    }
+    #[cfg(not(feature = "profiling"))]
+    {
+        let _ = (section, calls);
+        f()
+    }
+}
+
+/// What every Section did since the process started or the last `reset`. All zero without the
+/// `profiling` feature.
+pub fn report() -> ProfileReport {
+    let sections = Section::ALL
+        .into_iter()
+        .map(|section| {
+            #[cfg(feature = "profiling")]
+            let (calls, hits, nanos) = (
+                CALLS[section as usize].load(Ordering::Relaxed),
+                HITS[section as usize].load(Ordering::Relaxed),
+                NANOS[section as usize].load(Ordering::Relaxed),
+            );
+            #[cfg(not(feature = "profiling"))]
+            let (calls, hits, nanos) = (0, 0, 0);
+            SectionProfile {
+                section,
+                calls,
+                hits,
+                time: Duration::from_nanos(nanos),
+            }
+        })
+        .collect();
+
+    ProfileReport { sections }
+}
+
+/// Sets every count and time back to zero.
+pub fn reset() {
+    #[cfg(feature = "profiling")]
+    for counters in [&CALLS, &HITS, &NANOS] {
+        counters
+            .iter()
+            .for_each(|counter| counter.store(0, Ordering::Relaxed));
+    }
+}
+
+// Enters a tracing span named `$name` until the end of the enclosing scope, with the `profiling`
+// feature. Only meant for coarse steps: entering a span costs more than most hot loops.
+macro_rules! span {
+    ($name:literal) => {
+        #[cfg(feature = "profiling")]
+        let _span = tracing::trace_span!($name).entered();
+    };
+}
+pub(crate) use span;
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<14} {:>14} {:>14} {:>12} {:>10}",
+            "Section", "Calls", "Hits", "Time", "Per call"
+        )?;
+        for profile in &self.sections {
+            let (time, per_call) = match profile.section.is_timed() {
+                true => (
+                    format!("{:.3?}", profile.time),
+                    format!(
+                        "{:.1?}",
+                        Duration::from_nanos(
+                            (profile.time.as_nanos() / profile.calls.max(1) as u128) as u64
+                        )
+                    ),
+                ),
+                false => (String::from("-"), String::from("-")),
+            };
+            let hits = match profile.section {
+                Section::RollingHash => String::from("-"),
+                _ => profile.hits.to_string(),
+            };
+            write!(
+                f,
+                "\n{:<14} {:>14} {:>14} {:>12} {:>10}",
+                profile.section.to_string(),
+                profile.calls,
+                hits,
+                time,
+                per_call
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lists_every_section() {
+        let value = measure(Section::StrongHash, 2, || 42);
+        count(Section::StrongHash, 0, 1);
+        let report = report();
+
+        assert_eq!(value, 42);
+        assert_eq!(report.sections.len(), SECTION_COUNT);
+        let strong_hash = report.sections[Section::StrongHash as usize];
+        assert_eq!(strong_hash.section, Section::StrongHash);
+        if ENABLED {
+            // Other tests may hash blocks meanwhile.
+            assert!(strong_hash.calls >= 2 && strong_hash.hits >= 1);
+        }
+        let lines: Vec<_> = report.to_string().lines().map(String::from).collect();
+        assert_eq!(lines.len(), SECTION_COUNT + 1);
+        assert!(lines[2].starts_with("probe"));
+        assert!(lines[2].ends_with('-'));
+    }
+}
@@ -0,0 +1,152 @@
+//! Reports how far a command got, for front-ends to render progress without parsing human text.
+//!
+//! Once enabled, an event is written to the standard error as a line of JSON whenever a phase
+//! starts or ends, and every interval in between. Phases are started with `Timings::measure_bytes`, and
+//! the loops doing the work tell how many bytes they got through with `advance`, which costs a
+//! single relaxed load while reporting is disabled.
+
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::timings::Phase;
+
+/// Bytes loops which go through them one at a time get through before telling `advance`.
+pub const STEP: usize = 1 << 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BYTES_DONE: AtomicU64 = AtomicU64::new(0);
+static CURRENT_PHASE: Mutex<Option<CurrentPhase>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+struct CurrentPhase {
+    phase: Phase,
+    bytes_total: u64,
+    started: Instant,
+}
+
+/// How far the current phase of a command got, as written on the standard error.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct ProgressEvent {
+    pub phase: String,
+    pub bytes_done: u64,
+    /// Zero when the total is not known in advance.
+    pub bytes_total: u64,
+    /// Bytes per second since the phase started.
+    pub rate: u64,
+}
+
+// Written by hand, as every field is a number but the phase, whose names need no escaping.
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            r#"{{"phase":"{}","bytes_done":{},"bytes_total":{},"rate":{}}}"#,
+            self.phase, self.bytes_done, self.bytes_total, self.rate
+        )
+    }
+}
+
+impl ProgressEvent {
+    fn of(current: &CurrentPhase, bytes_done: u64) -> Self {
+        let elapsed = current.started.elapsed().as_secs_f64();
+        Self {
+            phase: current.phase.to_string(),
+            bytes_done,
+            bytes_total: current.bytes_total,
+            rate: match elapsed > 0.0 {
+                true => (bytes_done as f64 / elapsed) as u64,
+                false => 0,
+            },
+        }
+    }
+}
+
+/// Starts reporting progress on the standard error, every `interval` while a phase runs.
+pub fn enable(interval: Duration) {
+    if ENABLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // The thread ends with the process.
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Some(event) = current_event() {
+            emit(&event);
+        }
+    });
+}
+
+/// Starts reporting progress through `bytes_total` bytes of `phase`.
+pub fn begin(phase: Phase, bytes_total: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let current = CurrentPhase {
+        phase,
+        bytes_total,
+        started: Instant::now(),
+    };
+    BYTES_DONE.store(0, Ordering::Relaxed);
+    *CURRENT_PHASE.lock().unwrap() = Some(current);
+    emit(&ProgressEvent::of(&current, 0));
+}
+
+/// Tells `bytes` more bytes of the current phase were done. May be called from several threads.
+pub fn advance(bytes: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        BYTES_DONE.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Ends the current phase, reporting it as done.
+pub fn end() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(current) = CURRENT_PHASE.lock().unwrap().take() else {
+        return;
+    };
+    // Not every loop reports its bytes, so a phase which ended got through all of them.
+    let bytes_done = BYTES_DONE.load(Ordering::Relaxed).max(current.bytes_total);
+    emit(&ProgressEvent::of(&current, bytes_done));
+}
+
+fn current_event() -> Option<ProgressEvent> {
+    let current = (*CURRENT_PHASE.lock().unwrap())?;
+    Some(ProgressEvent::of(
+        &current,
+        BYTES_DONE.load(Ordering::Relaxed),
+    ))
+}
+
+fn emit(event: &ProgressEvent) {
+    // Progress is best effort: a closed standard error does not stop the command.
+    let _ = writeln!(std::io::stderr().lock(), "{event}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_single_json_lines_with_a_rate() {
+        let current = CurrentPhase {
+            phase: Phase::Hash,
+            bytes_total: 4096,
+            started: Instant::now() - Duration::from_secs(2),
+        };
+
+        let event = ProgressEvent::of(&current, 1024);
+        let line = event.to_string();
+
+        assert!(!line.contains('\n'));
+        assert!(line.starts_with(r#"{"phase":"hash","bytes_done":1024,"bytes_total":4096,"rate":"#));
+        assert!((500..=512).contains(&event.rate), "{}", event.rate);
+    }
+}
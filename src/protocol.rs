@@ -0,0 +1,270 @@
+//! Messages to fetch blocks of a file over a connection, the building block of download modes
+//! where the client finds the blocks it has and asks the server for the others.
+//!
+//! The server has the file and its Signature, which it published (see `domain::download`):
+//! 1 - The client sends a FetchBlocks message, selecting blocks by index or by strong hash.
+//! 2 - For each selector, in order, the server answers with a BlockReply: either the run of blocks
+//!     it selects, followed by their bytes in raw frames, or the selector it could not find.
+//! 3 - The server ends the answer with the empty frame.
+//!
+//! Every message is a frame, as described in `domain::streaming`. Each run carries the xxh3 hash of
+//! its bytes, so blocks damaged on the way are refused as they arrive, before the client checks
+//! them against the Signature.
+
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use bytes::{Bytes, BytesMut};
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{
+    read_frame_from, read_raw_frame_from, write_end_frame, write_frame, write_raw_frame,
+    BlockRequest, FileSignature, StrongHash,
+};
+
+// Bytes of each raw frame of a run of blocks.
+const BLOCK_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Blocks of the server's file, either by their indexes or by the strong hash the Signature has
+/// for them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum BlockSelector {
+    Indexes(Range<usize>),
+    /// The first block whose strong hash is this one.
+    StrongHash(u64),
+}
+
+/// First message of a fetch, sent by the client.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FetchBlocks {
+    /// Of the file whose Signature the client has, so a server with another version refuses.
+    pub file_size: u64,
+    pub blocks: Vec<BlockSelector>,
+}
+
+/// The server's answer to a single BlockSelector.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum BlockReply {
+    Run {
+        blocks: Range<usize>,
+        length: u64,
+        checksum: u64, // xxh3 of the bytes of the blocks, which follow in raw frames.
+    },
+    NotFound(BlockSelector),
+}
+
+/// Blocks received for a BlockSelector.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReceivedBlocks {
+    pub blocks: Range<usize>,
+    pub content: Bytes,
+}
+
+impl From<&BlockRequest> for FetchBlocks {
+    fn from(request: &BlockRequest) -> Self {
+        Self {
+            file_size: request.file_size,
+            blocks: request
+                .missing
+                .iter()
+                .cloned()
+                .map(BlockSelector::Indexes)
+                .collect(),
+        }
+    }
+}
+
+/// Answers the FetchBlocks read from `connection` with blocks of `file`, whose Signature is
+/// `signature`, and returns how many bytes of blocks were sent.
+pub fn serve_fetch<C: Read + Write>(
+    connection: &mut C,
+    file: &[u8],
+    signature: &FileSignature,
+    chunk_size: usize,
+) -> eyre::Result<u64> {
+    let request: FetchBlocks =
+        read_frame_from(connection)?.ok_or_else(|| eyre!("Client sent no FetchBlocks"))?;
+    if request.file_size != file.len() as u64 {
+        return Err(eyre!(
+            "Client fetches blocks of a file of {} bytes, but it has {}",
+            request.file_size,
+            file.len()
+        ));
+    }
+
+    let block_count = file.len().div_ceil(chunk_size);
+    let mut sent = 0;
+    for selector in request.blocks {
+        let blocks = match &selector {
+            BlockSelector::Indexes(blocks) => Some(blocks.clone()),
+            BlockSelector::StrongHash(strong_hash) => signature
+                .strong_hashes
+                .iter()
+                .position(|hash| hash == strong_hash)
+                .map(|block| block..block + 1),
+        };
+        let Some(blocks) = blocks.filter(|blocks| !blocks.is_empty() && blocks.end <= block_count)
+        else {
+            write_frame(connection, &BlockReply::NotFound(selector))?;
+            continue;
+        };
+        let content = &file[blocks.start * chunk_size..(blocks.end * chunk_size).min(file.len())];
+        write_frame(
+            connection,
+            &BlockReply::Run {
+                blocks,
+                length: content.len() as u64,
+                checksum: StrongHash::Xxh3.hash(content),
+            },
+        )?;
+        for frame in content.chunks(BLOCK_FRAME_SIZE) {
+            write_raw_frame(connection, frame)?;
+        }
+        sent += content.len() as u64;
+    }
+    write_end_frame(connection)?;
+    connection.flush()?;
+
+    Ok(sent)
+}
+
+/// Sends `request` over `connection`, and returns the blocks received for each selector, in
+/// order. Fails if any selector was not found, or any run does not match its checksum.
+pub fn fetch_blocks<C: Read + Write>(
+    connection: &mut C,
+    request: &FetchBlocks,
+) -> eyre::Result<Vec<ReceivedBlocks>> {
+    write_frame(connection, request)?;
+    connection.flush()?;
+
+    let mut received = Vec::with_capacity(request.blocks.len());
+    while let Some(reply) = read_frame_from::<BlockReply>(connection)? {
+        let (blocks, length, checksum) = match reply {
+            BlockReply::Run {
+                blocks,
+                length,
+                checksum,
+            } => (blocks, length, checksum),
+            BlockReply::NotFound(selector) => {
+                return Err(eyre!("Server does not have the blocks {selector:?}"))
+            }
+        };
+        let mut content = BytesMut::with_capacity(length as usize);
+        while (content.len() as u64) < length {
+            let frame = read_raw_frame_from(connection)?
+                .ok_or_else(|| eyre!("Blocks {blocks:?} were cut short"))?;
+            content.extend_from_slice(&frame);
+        }
+        if content.len() as u64 != length || StrongHash::Xxh3.hash(&content) != checksum {
+            return Err(eyre!("Blocks {blocks:?} were damaged on the way"));
+        }
+        received.push(ReceivedBlocks {
+            blocks,
+            content: content.freeze(),
+        });
+    }
+    if received.len() != request.blocks.len() {
+        return Err(eyre!(
+            "Server answered {} of {} selectors",
+            received.len(),
+            request.blocks.len()
+        ));
+    }
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::domain::compute_signature;
+
+    use super::*;
+
+    // A connection whose answer was written beforehand: what the client writes is kept apart.
+    struct Replay {
+        answer: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.answer.read(buf)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Plays the server for `request`, then the client reading its answer, as altered by `alter`.
+    fn fetch(
+        file: &[u8],
+        request: &FetchBlocks,
+        alter: impl FnOnce(&mut Vec<u8>),
+    ) -> eyre::Result<Vec<ReceivedBlocks>> {
+        let signature = compute_signature(Bytes::copy_from_slice(file), 4);
+        let mut request_bytes = Vec::new();
+        write_frame(&mut request_bytes, request)?;
+        let mut server = Replay {
+            answer: Cursor::new(request_bytes),
+            written: Vec::new(),
+        };
+        serve_fetch(&mut server, file, &signature, 4)?;
+
+        let mut answer = server.written;
+        alter(&mut answer);
+        let mut client = Replay {
+            answer: Cursor::new(answer),
+            written: Vec::new(),
+        };
+        fetch_blocks(&mut client, request)
+    }
+
+    #[test]
+    fn blocks_are_fetched_by_index_and_by_hash() {
+        let file = b"AAAABBBBCCCCDD";
+        let request = FetchBlocks {
+            file_size: file.len() as u64,
+            blocks: vec![
+                BlockSelector::Indexes(2..4),
+                BlockSelector::StrongHash(StrongHash::Xxh3.hash(b"BBBB")),
+            ],
+        };
+
+        let received = fetch(file, &request, |_| {}).unwrap();
+
+        assert_eq!(received[0].blocks, 2..4);
+        assert_eq!(received[0].content, "CCCCDD");
+        assert_eq!(received[1].blocks, 1..2);
+        assert_eq!(received[1].content, "BBBB");
+    }
+
+    #[test]
+    fn damaged_or_missing_blocks_are_refused() {
+        let file = b"AAAABBBBCCCCDD";
+        let request = FetchBlocks {
+            file_size: file.len() as u64,
+            blocks: vec![BlockSelector::Indexes(0..2)],
+        };
+        let damage = |answer: &mut Vec<u8>| {
+            let last_content_byte = answer.len() - 5;
+            answer[last_content_byte] ^= 1;
+        };
+        assert!(fetch(file, &request, damage).is_err());
+
+        let request = FetchBlocks {
+            file_size: file.len() as u64,
+            blocks: vec![BlockSelector::Indexes(3..5)],
+        };
+        assert!(fetch(file, &request, |_| {}).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Runs the whole pipeline on generated files, to check the installed binary works on this host.
+
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::Path;
+
+use eyre::eyre;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::commands::{
+    delta, patch, signature, ArtifactProtection, BlockOptions, DeltaOptions, PatchOptions,
+    SignatureOptions,
+};
+use crate::domain::basis_reader::BasisIo;
+use crate::domain::chunking::ChunkingMode;
+
+/// Chunk sizes every file pair is synced with, in fixed mode. It is also synced in lines mode.
+pub const SELFTEST_CHUNK_SIZES: [usize; 3] = [16, 256, 4096];
+
+/// How blocks are computed and read in a single case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestCase {
+    /// Which generated file pair is synced.
+    pub pair: &'static str,
+    pub blocks: BlockOptions,
+    /// How `patch` reads the basis file.
+    pub basis_io: BasisIo,
+}
+
+impl fmt::Display for SelfTestCase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.blocks.mode {
+            ChunkingMode::Fixed => write!(
+                f,
+                "{}, blocks of {} bytes",
+                self.pair, self.blocks.chunk_size
+            )?,
+            ChunkingMode::Lines => write!(f, "{}, lines", self.pair)?,
+        }
+        write!(f, ", {} basis IO", self.basis_io)
+    }
+}
+
+/// Whether each case recreated its updated file.
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    pub results: Vec<(SelfTestCase, eyre::Result<()>)>,
+}
+
+impl SelfTestReport {
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (case, result) in &self.results {
+            match result {
+                Ok(()) => writeln!(f, "ok      {case}")?,
+                Err(error) => writeln!(f, "FAILED  {case}: {error:#}")?,
+            }
+        }
+        write!(
+            f,
+            "{} of {} cases passed",
+            self.results.len() - self.failures(),
+            self.results.len()
+        )
+    }
+}
+
+// Pairs of basis and updated files, generated from `seed`: random bytes, edited text, and the
+// edge cases of empty and identical files.
+fn generate_pairs(seed: u64) -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+    let mut random = StdRng::seed_from_u64(seed);
+    let random_basis: Vec<u8> = (0..64 * 1024).map(|_| random.gen()).collect();
+    let mut random_updated = random_basis.clone();
+    for _ in 0..8 {
+        let offset = random.gen_range(0..random_updated.len());
+        match random.gen_range(0..3) {
+            0 => {
+                let inserted: Vec<u8> = (0..random.gen_range(1..512))
+                    .map(|_| random.gen())
+                    .collect();
+                random_updated.splice(offset..offset, inserted);
+            }
+            1 => {
+                let end = random_updated.len().min(offset + random.gen_range(1..512));
+                random_updated.drain(offset..end);
+            }
+            _ => random_updated[offset] ^= 0xff,
+        }
+    }
+
+    let text_basis: String = (0..2000)
+        .map(|line| format!("line {line}: the quick brown fox jumps over the lazy dog\n"))
+        .collect();
+    let text_updated = text_basis
+        .replace("line 100:", "line one hundred:")
+        .replace("line 1500: the quick", "line 1500: the slow")
+        + "a line at the end\n";
+
+    vec![
+        ("random bytes", random_basis, random_updated),
+        (
+            "edited text",
+            text_basis.clone().into(),
+            text_updated.into(),
+        ),
+        ("empty basis", Vec::new(), text_basis.clone().into()),
+        ("empty updated", text_basis.clone().into(), Vec::new()),
+        ("identical", text_basis.clone().into(), text_basis.into()),
+    ]
+}
+
+// Every way blocks are computed and read. Lines can only be applied in memory.
+fn basis_ios(mode: ChunkingMode) -> Vec<BasisIo> {
+    match mode {
+        ChunkingMode::Lines => vec![BasisIo::Memory],
+        ChunkingMode::Fixed => {
+            let mut basis_ios = vec![BasisIo::Memory, BasisIo::Positioned];
+            if cfg!(all(target_os = "linux", feature = "io-uring")) {
+                basis_ios.push(BasisIo::IoUring);
+            }
+            basis_ios
+        }
+    }
+}
+
+/// Syncs generated file pairs with `signature`, `delta` and `patch`, for every chunk size, chunking
+/// mode and way of reading the basis file, and checks each recreated file.
+///
+/// # Arguments
+/// * `directory` - An empty directory for the generated files and artifacts.
+/// * `seed` - Generates the random files, to reproduce a failure.
+///
+pub fn run_selftest(directory: &Path, seed: u64) -> eyre::Result<SelfTestReport> {
+    fs::create_dir_all(directory)?;
+
+    let mut report = SelfTestReport::default();
+    for (pair, basis_file, updated_file) in generate_pairs(seed) {
+        fs::write(directory.join("basis"), &basis_file)?;
+        fs::write(directory.join("updated"), &updated_file)?;
+        let fixed = SELFTEST_CHUNK_SIZES.map(|chunk_size| BlockOptions {
+            chunk_size,
+            mode: ChunkingMode::Fixed,
+        });
+        let lines = BlockOptions {
+            mode: ChunkingMode::Lines,
+            ..Default::default()
+        };
+        for blocks in fixed.into_iter().chain([lines]) {
+            for basis_io in basis_ios(blocks.mode) {
+                let case = SelfTestCase {
+                    pair,
+                    blocks,
+                    basis_io,
+                };
+                let result = run_case(directory, &case, &updated_file);
+                report.results.push((case, result));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// Syncs the `basis` and `updated` files of the directory, as the command line does.
+fn run_case(directory: &Path, case: &SelfTestCase, updated_file: &[u8]) -> eyre::Result<()> {
+    let SelfTestCase {
+        blocks, basis_io, ..
+    } = *case;
+    let protection = ArtifactProtection::default();
+    let recreated_filename = directory.join("recreated");
+    let _ = fs::remove_file(&recreated_filename);
+
+    signature(
+        &directory.join("basis"),
+        &directory.join("signature"),
+        &SignatureOptions {
+            blocks,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    delta(
+        &directory.join("signature"),
+        &directory.join("updated"),
+        &directory.join("delta"),
+        &DeltaOptions {
+            blocks,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    patch(
+        &directory.join("basis"),
+        &directory.join("delta"),
+        &recreated_filename,
+        &PatchOptions {
+            blocks,
+            basis_io,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    if fs::read(&recreated_filename)? != updated_file {
+        return Err(eyre!("Recreated file differs from the updated file"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::scratch_directory;
+
+    use super::*;
+
+    #[test]
+    fn every_selftest_case_passes() {
+        let directory = scratch_directory("selftest");
+
+        let report = run_selftest(&directory, 7).unwrap();
+
+        assert_eq!(report.failures(), 0, "{report}");
+        assert!(report.results.len() >= 5 * (SELFTEST_CHUNK_SIZES.len() * 2 + 1));
+    }
+}
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use nanoid::nanoid;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
 #[derive(Debug)]
 struct NotDirectoryError;
 
@@ -62,6 +67,15 @@ impl TryFrom<PathBuf> for TestCase {
     }
 }
 
+/// An empty directory under the system's temporary directory for a test to write its files in.
+/// It is named after `name` with a random suffix, so tests running at the same time never share
+/// one.
+pub fn scratch_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("rsync_rust_{name}_{}", nanoid!(5)));
+    fs::create_dir_all(&directory).expect("Scratch directory should be created");
+    directory
+}
+
 // This populates `target/release/rsync_rust` which is used in the commands below
 pub fn create_release_mode_target() {
     Command::new("cargo")
@@ -119,3 +133,342 @@ pub fn run_patch_command(
         .wait()
         .expect("failed to wait on child");
 }
+
+/// A way of damaging a serialized Signature or Delta, as a faulty disk or connection would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    BitFlip { offset: usize, bit: u8 },
+    Truncation { length: usize },
+    DuplicatedSection { start: usize, end: usize }, // Written again right after itself.
+}
+
+impl Corruption {
+    /// The artifact, damaged.
+    pub fn apply(&self, artifact: &[u8]) -> Vec<u8> {
+        let mut corrupted = artifact.to_vec();
+        match *self {
+            Corruption::BitFlip { offset, bit } => corrupted[offset] ^= 1 << bit,
+            Corruption::Truncation { length } => corrupted.truncate(length),
+            Corruption::DuplicatedSection { start, end } => {
+                corrupted.splice(end..end, artifact[start..end].to_vec());
+            }
+        }
+        corrupted
+    }
+}
+
+/// Corruptions of each kind for an artifact of `length` bytes: flips of every bit, truncations
+/// to every shorter length, and duplications of sections of a few sizes, at every offset.
+///
+/// # Arguments
+/// * `length` - The length of the serialized artifact.
+/// * `stride` - Only offsets (and lengths) which are multiples of it are corrupted, to keep the
+///   number of corruptions manageable for larger artifacts.
+///
+pub fn corruptions(length: usize, stride: usize) -> Vec<Corruption> {
+    let offsets = (0..length).step_by(stride);
+    let bit_flips = offsets
+        .clone()
+        .flat_map(|offset| (0..8).map(move |bit| Corruption::BitFlip { offset, bit }));
+    let truncations = offsets
+        .clone()
+        .map(|length| Corruption::Truncation { length });
+    let duplications = offsets.flat_map(|start| {
+        [1, 8, length / 4]
+            .into_iter()
+            .filter(move |size| *size > 0 && start + size <= length)
+            .map(move |size| Corruption::DuplicatedSection {
+                start,
+                end: start + size,
+            })
+    });
+
+    bit_flips.chain(truncations).chain(duplications).collect()
+}
+
+/// Whether using a corrupted artifact was rejected, or still gave the right output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionOutcome {
+    Rejected,
+    Unaffected, // The damaged part did not matter, as with a flip in a hash which never matches.
+}
+
+/// Runs `use_artifact` on a corrupted artifact, and panics if it panicked or silently gave a wrong
+/// output instead of returning an error.
+///
+/// # Arguments
+/// * `corruption` - How the artifact was damaged, for the panic message.
+/// * `expected` - The output `use_artifact` gives with the undamaged artifact.
+/// * `use_artifact` - Uses the corrupted artifact, and returns its output (such as the recreated file).
+///
+pub fn assert_corruption_is_detected(
+    corruption: Corruption,
+    expected: &[u8],
+    use_artifact: impl FnOnce() -> eyre::Result<Vec<u8>>,
+) -> CorruptionOutcome {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(use_artifact)) {
+        Err(_) => panic!("{corruption:?} made it panic instead of returning an error"),
+        Ok(Err(_)) => CorruptionOutcome::Rejected,
+        Ok(Ok(output)) if output == expected => CorruptionOutcome::Unaffected,
+        Ok(Ok(_)) => panic!("{corruption:?} was not detected, and gave a wrong output"),
+    }
+}
+
+/// A kind of data files are synced for in real use, to generate inputs resembling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Source files, a few of which get small edits: a constant changed, a function added or removed.
+    SourceTree {
+        files: usize,
+        functions_per_file: usize,
+        edited_files: usize,
+    },
+    /// A log which is appended to, and whose oldest lines are sometimes rotated out.
+    AppendMostlyLog {
+        lines: usize,
+        appended_lines: usize,
+        rotated_lines: usize,
+    },
+    /// An executable whose sections move when one of them grows, patching the addresses inside.
+    RelocatedBinary {
+        sections: usize,
+        section_size: usize,
+        grown_sections: usize,
+    },
+}
+
+/// Workloads of about a megabyte each, as benchmarked.
+pub const REALISTIC_WORKLOADS: [(&str, Workload); 3] = [
+    (
+        "source tree",
+        Workload::SourceTree {
+            files: 200,
+            functions_per_file: 20,
+            edited_files: 10,
+        },
+    ),
+    (
+        "append-mostly log",
+        Workload::AppendMostlyLog {
+            lines: 12_000,
+            appended_lines: 500,
+            rotated_lines: 300,
+        },
+    ),
+    (
+        "relocated binary",
+        Workload::RelocatedBinary {
+            sections: 16,
+            section_size: 64 * 1024,
+            grown_sections: 2,
+        },
+    ),
+];
+
+/// A file of a generated workload, before and after it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkloadFile {
+    pub path: PathBuf,
+    // Relative to the root of the workload.
+    pub basis_file: Vec<u8>,
+    pub updated_file: Vec<u8>,
+}
+
+impl Workload {
+    /// The files of the workload, the same for the same `seed`. Only SourceTree has several.
+    pub fn generate(&self, seed: u64) -> Vec<WorkloadFile> {
+        let mut random = StdRng::seed_from_u64(seed);
+        match *self {
+            Workload::SourceTree {
+                files,
+                functions_per_file,
+                edited_files,
+            } => generate_source_tree(&mut random, files, functions_per_file, edited_files),
+            Workload::AppendMostlyLog {
+                lines,
+                appended_lines,
+                rotated_lines,
+            } => vec![generate_log(
+                &mut random,
+                lines,
+                appended_lines,
+                rotated_lines,
+            )],
+            Workload::RelocatedBinary {
+                sections,
+                section_size,
+                grown_sections,
+            } => vec![generate_binary(
+                &mut random,
+                sections,
+                section_size,
+                grown_sections,
+            )],
+        }
+    }
+}
+
+/// Writes the basis and updated files of a workload under `basis_directory` and
+/// `updated_directory`, keeping their relative paths.
+pub fn write_workload(
+    files: &[WorkloadFile],
+    basis_directory: &Path,
+    updated_directory: &Path,
+) -> std::io::Result<()> {
+    for file in files {
+        for (directory, content) in [
+            (basis_directory, &file.basis_file),
+            (updated_directory, &file.updated_file),
+        ] {
+            let path = directory.join(&file.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+        }
+    }
+    Ok(())
+}
+
+const IDENTIFIERS: [&str; 12] = [
+    "buffer", "count", "offset", "length", "index", "block", "token", "state", "reader", "writer",
+    "cache", "limit",
+];
+
+fn generate_function(random: &mut StdRng, name: &str) -> String {
+    let argument = IDENTIFIERS[random.gen_range(0..IDENTIFIERS.len())];
+    let mut function = format!("pub fn {name}({argument}: u64) -> u64 {{\n");
+    for line in 0..random.gen_range(2..8) {
+        let variable = IDENTIFIERS[random.gen_range(0..IDENTIFIERS.len())];
+        function += &format!(
+            "    let {variable}_{line} = {argument} * {} + {};\n",
+            random.gen_range(1..100),
+            random.gen_range(0..1000)
+        );
+    }
+    function + &format!("    {argument}\n}}\n\n")
+}
+
+fn generate_source_tree(
+    random: &mut StdRng,
+    files: usize,
+    functions_per_file: usize,
+    edited_files: usize,
+) -> Vec<WorkloadFile> {
+    let mut tree = Vec::new();
+    for file in 0..files {
+        let mut functions: Vec<String> = (0..functions_per_file)
+            .map(|function| generate_function(random, &format!("function_{file}_{function}")))
+            .collect();
+        let basis_file = functions.concat().into_bytes();
+        if file < edited_files && !functions.is_empty() {
+            let edited = random.gen_range(0..functions.len());
+            match random.gen_range(0..3) {
+                0 => functions[edited] = functions[edited].replacen(" + ", " - ", 1),
+                1 => {
+                    let added = generate_function(random, &format!("added_{file}_{edited}"));
+                    functions.insert(edited, added);
+                }
+                _ => drop(functions.remove(edited)),
+            }
+        }
+        tree.push(WorkloadFile {
+            path: PathBuf::from(format!("src/module_{}/file_{file}.rs", file % 8)),
+            basis_file,
+            updated_file: functions.concat().into_bytes(),
+        });
+    }
+    tree
+}
+
+fn generate_log(
+    random: &mut StdRng,
+    lines: usize,
+    appended_lines: usize,
+    rotated_lines: usize,
+) -> WorkloadFile {
+    const LEVELS: [&str; 4] = ["DEBUG", "INFO", "INFO", "WARN"];
+    let mut log_line = |line: usize| {
+        format!(
+            "2024-03-01T{:02}:{:02}:{:02}.{:03}Z {:5} worker[{}]: request {} handled in {}ms\n",
+            line / 3600 % 24,
+            line / 60 % 60,
+            line % 60,
+            random.gen_range(0..1000),
+            LEVELS[random.gen_range(0..LEVELS.len())],
+            random.gen_range(1..16),
+            random.gen::<u32>(),
+            random.gen_range(1..2000)
+        )
+    };
+    let all_lines: Vec<String> = (0..lines + appended_lines).map(&mut log_line).collect();
+
+    WorkloadFile {
+        path: PathBuf::from("service.log"),
+        basis_file: all_lines[..lines].concat().into_bytes(),
+        updated_file: all_lines[rotated_lines.min(lines)..].concat().into_bytes(),
+    }
+}
+
+// Sections are code-like: short instruction patterns repeated with varying operands, and an
+// absolute address every KiB, which moves with the section.
+fn generate_binary(
+    random: &mut StdRng,
+    sections: usize,
+    section_size: usize,
+    grown_sections: usize,
+) -> WorkloadFile {
+    let patterns: Vec<[u8; 4]> = (0..32).map(|_| random.gen()).collect();
+    let contents: Vec<Vec<u8>> = (0..sections)
+        .map(|_| {
+            (0..section_size)
+                .map(|offset| {
+                    patterns[offset / 16 % patterns.len()][offset % 4] ^ random.gen_range(0..4)
+                })
+                .collect()
+        })
+        .collect();
+    let grown: Vec<usize> = (0..grown_sections)
+        .map(|_| random.gen_range(0..sections.max(1)))
+        .collect();
+    let growth: Vec<Vec<u8>> = (0..sections)
+        .map(|section| match grown.contains(&section) {
+            true => (0..random.gen_range(16..256))
+                .map(|_| random.gen())
+                .collect(),
+            false => Vec::new(),
+        })
+        .collect();
+
+    let link = |grow: bool| {
+        let header_size = 4 * sections;
+        let mut offsets = Vec::new();
+        let mut offset = header_size;
+        for (content, growth) in contents.iter().zip(&growth) {
+            offsets.push(offset);
+            offset += content.len() + if grow { growth.len() } else { 0 };
+        }
+        let mut binary: Vec<u8> = offsets
+            .iter()
+            .flat_map(|offset| (*offset as u32).to_le_bytes())
+            .collect();
+        for ((content, growth), offset) in contents.iter().zip(&growth).zip(&offsets) {
+            let mut section = content.clone();
+            for address in (0..section.len().saturating_sub(4)).step_by(1024) {
+                let relocated = (*offset + address) as u32;
+                section[address..address + 4].copy_from_slice(&relocated.to_le_bytes());
+            }
+            if grow {
+                section.extend(growth);
+            }
+            binary.extend(section);
+        }
+        binary
+    };
+
+    WorkloadFile {
+        path: PathBuf::from("application.bin"),
+        basis_file: link(false),
+        updated_file: link(true),
+    }
+}
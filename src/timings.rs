@@ -0,0 +1,149 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "cli")]
+use cpu_time::ProcessTime;
+
+use crate::progress;
+
+/// A part of a command whose time is measured on its own.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Phase {
+    /// Reading the input files, and transforming or normalizing them.
+    Read,
+    /// Hashing the blocks of a file.
+    Hash,
+    /// Looking our blocks up in the Signature. Streamed Deltas are also written meanwhile.
+    Match,
+    /// Encoding artifacts before writing them, or decoding them after reading, with encryption.
+    Serialize,
+    /// Recreating the updated file from the basis file and the Delta.
+    Apply,
+    /// Writing the output files.
+    Write,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Read => write!(f, "read"),
+            Phase::Hash => write!(f, "hash"),
+            Phase::Match => write!(f, "match"),
+            Phase::Serialize => write!(f, "serialize"),
+            Phase::Apply => write!(f, "apply"),
+            Phase::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Time spent in a single phase of a command.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    /// Time elapsed on the clock.
+    pub wall: Duration,
+    /// Time spent computing, by every thread of the process.
+    pub cpu: Duration,
+}
+
+/// Time spent in each phase of a command, in the order they first ran.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Timings {
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Timings {
+    /// Runs `f`, and adds the time it took to `phase`.
+    pub fn measure<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let wall_start = Instant::now();
+        let cpu_elapsed = start_cpu_clock();
+        let result = f();
+        let wall = wall_start.elapsed();
+        let cpu = cpu_elapsed();
+
+        match self.phases.iter_mut().find(|timing| timing.phase == phase) {
+            Some(timing) => {
+                timing.wall += wall;
+                timing.cpu += cpu;
+            }
+            None => self.phases.push(PhaseTiming { phase, wall, cpu }),
+        }
+        result
+    }
+
+    /// Like `measure`, also reporting progress through `bytes_total` bytes of `phase`.
+    pub fn measure_bytes<T>(&mut self, phase: Phase, bytes_total: u64, f: impl FnOnce() -> T) -> T {
+        progress::begin(phase, bytes_total);
+        let result = self.measure(phase, f);
+        progress::end();
+        result
+    }
+
+    pub fn total_wall(&self) -> Duration {
+        self.phases.iter().map(|timing| timing.wall).sum()
+    }
+
+    pub fn total_cpu(&self) -> Duration {
+        self.phases.iter().map(|timing| timing.cpu).sum()
+    }
+}
+
+// Returns a function telling the CPU time spent since this call. CPU time is not available
+// everywhere, nor without the `cli` feature, in which case it is reported as zero.
+#[cfg(feature = "cli")]
+fn start_cpu_clock() -> impl FnOnce() -> Duration {
+    let start = ProcessTime::try_now().ok();
+    move || {
+        start
+            .and_then(|start| start.try_elapsed().ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(not(feature = "cli"))]
+fn start_cpu_clock() -> impl FnOnce() -> Duration {
+    || Duration::ZERO
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10} {:>12} {:>12}", "Phase", "Wall", "CPU")?;
+        for timing in &self.phases {
+            writeln!(
+                f,
+                "{:<10} {:>12.3?} {:>12.3?}",
+                timing.phase.to_string(),
+                timing.wall,
+                timing.cpu
+            )?;
+        }
+        write!(
+            f,
+            "{:<10} {:>12.3?} {:>12.3?}",
+            "total",
+            self.total_wall(),
+            self.total_cpu()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_phases_are_added_up_in_first_run_order() {
+        let mut timings = Timings::default();
+
+        timings.measure(Phase::Read, || std::thread::sleep(Duration::from_millis(2)));
+        let value = timings.measure(Phase::Match, || 42);
+        timings.measure(Phase::Read, || std::thread::sleep(Duration::from_millis(2)));
+
+        assert_eq!(value, 42);
+        let phases: Vec<_> = timings.phases.iter().map(|timing| timing.phase).collect();
+        assert_eq!(phases, vec![Phase::Read, Phase::Match]);
+        assert!(timings.phases[0].wall >= Duration::from_millis(4));
+        assert!(timings.total_wall() >= timings.phases[0].wall);
+    }
+}
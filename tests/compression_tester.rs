@@ -4,6 +4,9 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::{fmt, fs};
 
+use bytes::Bytes;
+
+use rsync_rust::domain::{apply_delta, compute_delta_to_our_file, compute_signature};
 use rsync_rust::io_utils;
 use rsync_rust::test_utils::*;
 
@@ -116,6 +119,37 @@ fn compute_linux_compression() {
     }
 }
 
+// Unlike random bytes, realistic workloads share most of their content before and after a change,
+// so the Delta of each file should be a fraction of it.
+#[test]
+fn realistic_workloads_compress() {
+    let chunk_size = 256;
+    for (name, workload) in REALISTIC_WORKLOADS {
+        let files = workload.generate(42);
+        let mut updated_size = 0;
+        let mut delta_size = 0;
+        for file in files {
+            let basis_file = Bytes::from(file.basis_file);
+            let updated_file = Bytes::from(file.updated_file);
+            let signature = compute_signature(basis_file.clone(), chunk_size);
+            let mut delta =
+                compute_delta_to_our_file(signature.clone(), updated_file.clone(), chunk_size);
+            // As `delta` does, so literals are stored in runs.
+            delta.optimize_against(&signature);
+            delta_size += Bytes::try_from(delta.clone()).unwrap().len();
+            updated_size += updated_file.len();
+
+            assert_eq!(
+                apply_delta(basis_file, delta, chunk_size).unwrap(),
+                updated_file
+            );
+        }
+
+        println!("{name}: {delta_size} bytes of Delta for {updated_size} bytes");
+        assert!(delta_size * 4 < updated_size, "{name} did not compress");
+    }
+}
+
 #[test]
 #[ignore]
 fn test_linux_compression() {
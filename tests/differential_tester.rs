@@ -0,0 +1,239 @@
+//! Differential tests against librsync
+//!
+//! `rdiff` (the command line tool of librsync) implements the same algorithm with its own format,
+//! so artifacts can not be applied across implementations. Instead, the same random file pairs are
+//! synced by both, and each implementation must recreate the updated file from its own artifacts.
+//! Any divergence (one failing, or recreating something else) is a bug in one of them.
+//!
+//! Pairs which diverged are recorded as TestCases in `tests/differential_corpus`, together with a
+//! `divergence` file describing what happened, and are synced again before new pairs are generated.
+//!
+//! The test is ignored by default, as it needs `rdiff` on the PATH:
+//! `cargo test --test differential_tester -- --ignored`. `DIFFERENTIAL_CASES` (default 50) tells how
+//! many random pairs are generated, and `DIFFERENTIAL_SEED` reproduces a run.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use rsync_rust::commands::{
+    delta, patch, signature, ArtifactProtection, BlockOptions, DeltaOptions, PatchOptions,
+    SignatureOptions,
+};
+use rsync_rust::domain::chunking::ChunkingMode;
+use rsync_rust::test_utils::*;
+
+const CORPUS_DIRECTORY: &str = "tests/differential_corpus";
+
+// Block sizes both implementations sync each pair with.
+const BLOCK_SIZES: [usize; 3] = [16, 512, 2048];
+
+fn rdiff_is_available() -> bool {
+    Command::new("rdiff")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+fn rdiff(arguments: &[&Path], block_size: Option<usize>) -> Result<(), String> {
+    let mut command = Command::new("rdiff");
+    if let Some(block_size) = block_size {
+        command.args(["--block-size", &block_size.to_string()]);
+    }
+    let output = command
+        .args(arguments)
+        .output()
+        .map_err(|error| error.to_string())?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
+
+// Recreates the updated file of the case with `rdiff`, from its own signature and delta.
+fn sync_with_rdiff(case: &TestCase, block_size: usize) -> Result<Vec<u8>, String> {
+    let directory = &case.directory_path;
+    let (signature, delta, recreated) = (
+        directory.join("rdiff_signature"),
+        directory.join("rdiff_delta"),
+        directory.join("rdiff_recreated"),
+    );
+    rdiff(
+        &[Path::new("signature"), &case.basis_file, &signature],
+        Some(block_size),
+    )?;
+    rdiff(
+        &[Path::new("delta"), &signature, &case.updated_file, &delta],
+        None,
+    )?;
+    rdiff(
+        &[Path::new("patch"), &case.basis_file, &delta, &recreated],
+        None,
+    )?;
+    fs::read(recreated).map_err(|error| error.to_string())
+}
+
+// Recreates the updated file of the case with our commands, as the command line would.
+fn sync_with_rsync_rust(case: &TestCase, chunk_size: usize) -> eyre::Result<Vec<u8>> {
+    let directory = &case.directory_path;
+    let (signature_filename, delta_filename, recreated) = (
+        directory.join("signature"),
+        directory.join("delta"),
+        directory.join("recreated"),
+    );
+    let blocks = BlockOptions {
+        chunk_size,
+        mode: ChunkingMode::Fixed,
+    };
+    let protection = ArtifactProtection::default();
+    signature(
+        &case.basis_file,
+        &signature_filename,
+        &SignatureOptions {
+            blocks,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    delta(
+        &signature_filename,
+        &case.updated_file,
+        &delta_filename,
+        &DeltaOptions {
+            blocks,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    patch(
+        &case.basis_file,
+        &delta_filename,
+        &recreated,
+        &PatchOptions {
+            blocks,
+            ..Default::default()
+        },
+        &protection,
+    )?;
+    Ok(fs::read(recreated)?)
+}
+
+// Syncs the case with both implementations, and describes how they diverged, if they did.
+fn find_divergence(case: &TestCase) -> Option<String> {
+    let updated = fs::read(&case.updated_file).unwrap();
+    for block_size in BLOCK_SIZES {
+        let ours = sync_with_rsync_rust(case, block_size);
+        let reference = sync_with_rdiff(case, block_size);
+        let divergence = match (ours, reference) {
+            (Ok(ours), Ok(reference)) if ours == updated && reference == updated => continue,
+            (Ok(ours), Ok(_)) if ours != updated => "rsync_rust recreated a different file".into(),
+            (Ok(_), Ok(_)) => "rdiff recreated a different file".into(),
+            (Err(error), Ok(_)) => format!("rsync_rust failed: {error:?}"),
+            (Ok(_), Err(error)) => format!("rdiff failed: {error}"),
+            (Err(ours), Err(reference)) => {
+                format!("Both failed.\nrsync_rust: {ours:?}\nrdiff: {reference}")
+            }
+        };
+        return Some(format!("Block size {block_size}: {divergence}"));
+    }
+    None
+}
+
+// A basis file, and an updated file made from it with a few random edits.
+fn generate_pair(random: &mut StdRng) -> (Vec<u8>, Vec<u8>) {
+    let length = random.gen_range(0..64 * 1024);
+    let basis: Vec<u8> = match random.gen_bool(0.5) {
+        true => (0..length).map(|_| random.gen()).collect(),
+        // Few distinct bytes make for many repeated blocks.
+        false => (0..length)
+            .map(|_| b"ab\n"[random.gen_range(0..3)])
+            .collect(),
+    };
+    let mut updated = basis.clone();
+    for _ in 0..random.gen_range(0..16) {
+        let offset = random.gen_range(0..=updated.len());
+        match random.gen_range(0..3) {
+            0 => {
+                let inserted: Vec<u8> = (0..random.gen_range(1..1024))
+                    .map(|_| random.gen())
+                    .collect();
+                updated.splice(offset..offset, inserted);
+            }
+            1 => {
+                let end = updated.len().min(offset + random.gen_range(1..1024));
+                updated.drain(offset..end);
+            }
+            _ => {
+                // Moves a section elsewhere, which both implementations should find.
+                let end = updated.len().min(offset + random.gen_range(1..4096));
+                let section: Vec<u8> = updated.drain(offset..end).collect();
+                let destination = random.gen_range(0..=updated.len());
+                updated.splice(destination..destination, section);
+            }
+        }
+    }
+    (basis, updated)
+}
+
+fn write_case(directory: PathBuf, basis: &[u8], updated: &[u8]) -> TestCase {
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("basis_file"), basis).unwrap();
+    fs::write(directory.join("updated_file"), updated).unwrap();
+    TestCase::try_from(directory).unwrap()
+}
+
+fn environment_variable(name: &str) -> Option<u64> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} should be a number"))
+    })
+}
+
+#[test]
+#[ignore]
+// Needs `rdiff`, which is not installed in CI.
+fn both_implementations_recreate_the_same_files() {
+    if !rdiff_is_available() {
+        println!("rdiff was not found on the PATH, nothing to compare against");
+        return;
+    }
+    let mut divergences = Vec::new();
+
+    // Cases which diverged before come first, until they are fixed and removed.
+    if let Ok(entries) = fs::read_dir(CORPUS_DIRECTORY) {
+        for entry in entries {
+            let Ok(case) = TestCase::try_from(entry.unwrap().path()) else {
+                continue;
+            };
+            if let Some(divergence) = find_divergence(&case) {
+                divergences.push(format!("{}: {divergence}", case.directory_path.display()));
+            }
+        }
+    }
+
+    let seed = environment_variable("DIFFERENTIAL_SEED").unwrap_or_else(rand::random);
+    let cases = environment_variable("DIFFERENTIAL_CASES").unwrap_or(50);
+    let mut random = StdRng::seed_from_u64(seed);
+    let scratch = scratch_directory("differential");
+    for index in 0..cases {
+        let (basis, updated) = generate_pair(&mut random);
+        let case = write_case(scratch.clone(), &basis, &updated);
+        let Some(divergence) = find_divergence(&case) else {
+            continue;
+        };
+        let recorded = Path::new(CORPUS_DIRECTORY).join(format!("{seed}_{index}"));
+        write_case(recorded.clone(), &basis, &updated);
+        fs::write(recorded.join("divergence"), &divergence).unwrap();
+        divergences.push(format!("{}: {divergence}", recorded.display()));
+    }
+
+    assert!(
+        divergences.is_empty(),
+        "Implementations diverged (seed {seed}):\n{}",
+        divergences.join("\n")
+    );
+}
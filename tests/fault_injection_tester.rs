@@ -0,0 +1,156 @@
+//! Tests for corrupted artifacts
+//!
+//! Every Signature and Delta written for a pair of files is corrupted in every way `corruptions`
+//! lists (bit flips, truncations and duplicated sections), and then used as the command line would.
+//! Each corruption must either be rejected with an error, or not matter at all: `delta` and `patch`
+//! must never panic, nor silently recreate a different file.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rsync_rust::commands::{
+    delta, patch, signature, ArtifactProtection, BlockOptions, DeltaOptions, PatchOptions,
+    SignatureOptions,
+};
+use rsync_rust::domain::basis_reader::BasisIo;
+use rsync_rust::domain::chunking::ChunkingMode;
+use rsync_rust::test_utils::*;
+
+const CHUNK_SIZE: usize = 16;
+
+const BLOCKS: BlockOptions = BlockOptions {
+    chunk_size: CHUNK_SIZE,
+    mode: ChunkingMode::Fixed,
+};
+
+// A directory with a `basis` and an `updated` file, which share most of their blocks.
+fn prepare_directory(name: &str) -> PathBuf {
+    let directory = scratch_directory(&format!("fault_injection_{name}"));
+
+    let basis: String = (0..12)
+        .map(|line| format!("line {line}: some text\n"))
+        .collect();
+    let updated = basis.replace("line 3:", "line three:") + "one more line\n";
+    fs::write(directory.join("basis"), basis).unwrap();
+    fs::write(directory.join("updated"), updated).unwrap();
+
+    directory
+}
+
+fn write_signature(directory: &Path, dedup: bool) -> PathBuf {
+    let signature_filename = directory.join("signature");
+    let options = SignatureOptions {
+        blocks: BLOCKS,
+        dedup,
+        ..Default::default()
+    };
+    signature(
+        &directory.join("basis"),
+        &signature_filename,
+        &options,
+        &ArtifactProtection::default(),
+    )
+    .unwrap();
+    signature_filename
+}
+
+fn delta_then_patch(directory: &Path, signature_filename: &Path) -> eyre::Result<Vec<u8>> {
+    let delta_filename = directory.join("delta_from_corrupted");
+    let options = DeltaOptions {
+        blocks: BLOCKS,
+        ..Default::default()
+    };
+    delta(
+        signature_filename,
+        &directory.join("updated"),
+        &delta_filename,
+        &options,
+        &ArtifactProtection::default(),
+    )?;
+    apply(directory, &delta_filename, BasisIo::Memory)
+}
+
+fn apply(directory: &Path, delta_filename: &Path, basis_io: BasisIo) -> eyre::Result<Vec<u8>> {
+    let recreated_filename = directory.join("recreated");
+    let _ = fs::remove_file(&recreated_filename);
+    let options = PatchOptions {
+        blocks: BLOCKS,
+        basis_io,
+        ..Default::default()
+    };
+    patch(
+        &directory.join("basis"),
+        delta_filename,
+        &recreated_filename,
+        &options,
+        &ArtifactProtection::default(),
+    )?;
+    Ok(fs::read(recreated_filename)?)
+}
+
+// Uses each corruption of `artifact` through `use_artifact`, and returns how many were rejected.
+fn inject_faults(
+    artifact: &Path,
+    expected: &[u8],
+    use_artifact: impl Fn(&Path) -> eyre::Result<Vec<u8>>,
+) -> usize {
+    let serialized = fs::read(artifact).unwrap();
+    let corrupted_filename = artifact.with_extension("corrupted");
+
+    let mut rejected = 0;
+    for corruption in corruptions(serialized.len(), 1) {
+        fs::write(&corrupted_filename, corruption.apply(&serialized)).unwrap();
+        let outcome = assert_corruption_is_detected(corruption, expected, || {
+            use_artifact(&corrupted_filename)
+        });
+        if outcome == CorruptionOutcome::Rejected {
+            rejected += 1;
+        }
+    }
+    rejected
+}
+
+#[test]
+fn corrupted_signatures_are_rejected_or_harmless() {
+    for dedup in [false, true] {
+        let directory = prepare_directory(&format!("signature_{dedup}"));
+        let signature_filename = write_signature(&directory, dedup);
+        let updated = fs::read(directory.join("updated")).unwrap();
+
+        let rejected = inject_faults(&signature_filename, &updated, |corrupted| {
+            delta_then_patch(&directory, corrupted)
+        });
+
+        assert!(rejected > 0);
+    }
+}
+
+#[test]
+fn corrupted_deltas_are_rejected_or_harmless() {
+    for stream in [false, true] {
+        let directory = prepare_directory(&format!("delta_{stream}"));
+        let signature_filename = write_signature(&directory, false);
+        let delta_filename = directory.join("delta");
+        let options = DeltaOptions {
+            blocks: BLOCKS,
+            stream,
+            ..Default::default()
+        };
+        delta(
+            &signature_filename,
+            &directory.join("updated"),
+            &delta_filename,
+            &options,
+            &ArtifactProtection::default(),
+        )
+        .unwrap();
+        let updated = fs::read(directory.join("updated")).unwrap();
+
+        for basis_io in [BasisIo::Memory, BasisIo::Positioned] {
+            let rejected = inject_faults(&delta_filename, &updated, |corrupted| {
+                apply(&directory, corrupted, basis_io)
+            });
+
+            assert!(rejected > 0);
+        }
+    }
+}
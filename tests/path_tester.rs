@@ -0,0 +1,134 @@
+//! Tests for file names and paths
+//!
+//! Paths are given to the commands as they are, without going through UTF-8 strings, so file
+//! names which are not valid UTF-8 (on Unix) and paths longer than 260 characters (on Windows,
+//! with or without the `\\?\` prefix) work like any other.
+use std::fs;
+use std::path::Path;
+
+use rsync_rust::commands::{
+    delta, patch, signature, ArtifactProtection, DeltaOptions, PatchOptions, SignatureOptions,
+};
+use rsync_rust::domain::transfer::{transfer_directory, TransferOptions};
+use rsync_rust::domain::{ChunkingMode, FilePolicies, MatchingOptions};
+use rsync_rust::test_utils::scratch_directory;
+
+// Runs `signature`, `delta` and `patch` on files named after `name`, in `directory`.
+fn assert_commands_recreate_the_file(directory: &Path, name: &Path) {
+    let basis_file = "the quick brown fox jumps over the lazy dog\n".repeat(20);
+    let updated_file = basis_file.replace("fox", "cat");
+    let named = |suffix: &str| {
+        let mut filename = name.as_os_str().to_owned();
+        filename.push(suffix);
+        directory.join(filename)
+    };
+    fs::write(named(".basis"), &basis_file).unwrap();
+    fs::write(named(".updated"), &updated_file).unwrap();
+    let protection = ArtifactProtection::default();
+
+    signature(
+        &named(".basis"),
+        &named(".signature"),
+        &SignatureOptions::default(),
+        &protection,
+    )
+    .unwrap();
+    delta(
+        &named(".signature"),
+        &named(".updated"),
+        &named(".delta"),
+        &DeltaOptions::default(),
+        &protection,
+    )
+    .unwrap();
+    patch(
+        &named(".basis"),
+        &named(".delta"),
+        &named(".recreated"),
+        &PatchOptions::default(),
+        &protection,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read(named(".recreated")).unwrap(),
+        updated_file.as_bytes()
+    );
+}
+
+// Transfers a tree with a single file at `relative_path` into an empty directory.
+fn assert_tree_is_transferred(sender: &Path, receiver: &Path, relative_path: &Path) {
+    let sender_file = sender.join(relative_path);
+    fs::create_dir_all(sender_file.parent().unwrap()).unwrap();
+    fs::write(&sender_file, "file content\n".repeat(20)).unwrap();
+
+    let report = transfer_directory(
+        sender,
+        receiver,
+        &TransferOptions {
+            chunk_size: 16,
+            mode: ChunkingMode::Fixed,
+            matching: MatchingOptions::default(),
+            compress_whole_files: false,
+            share_blocks: false,
+            match_compressed: false,
+            policies: FilePolicies::default(),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(
+        fs::read(receiver.join(relative_path)).unwrap(),
+        fs::read(sender_file).unwrap()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_file_names_are_used_as_they_are() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let root = scratch_directory("paths_non_utf8");
+    let name = Path::new(OsStr::from_bytes(b"caf\xe9"));
+
+    assert_commands_recreate_the_file(&root, name);
+    assert_tree_is_transferred(
+        &root.join("sender"),
+        &root.join("receiver"),
+        &Path::new("nested").join(name),
+    );
+}
+
+#[cfg(windows)]
+#[test]
+fn long_paths_are_used_as_they_are() {
+    let root = scratch_directory("paths_long");
+    let deep_directory = (0..12).fold(root.clone(), |directory, level| {
+        directory.join(format!("directory_level_{level:02}_with_a_long_name"))
+    });
+    assert!(deep_directory.as_os_str().len() > 260);
+    fs::create_dir_all(&deep_directory).unwrap();
+
+    assert_commands_recreate_the_file(&deep_directory, Path::new("file"));
+    let relative_path = deep_directory.strip_prefix(&root).unwrap().join("file");
+    assert_tree_is_transferred(&root.join("sender"), &root.join("receiver"), &relative_path);
+}
+
+#[cfg(windows)]
+#[test]
+fn verbatim_paths_are_used_as_they_are() {
+    let root = scratch_directory("paths_verbatim");
+    let mut verbatim_root = std::ffi::OsString::from(r"\\?\");
+    verbatim_root.push(root.as_os_str());
+    let verbatim_root = std::path::PathBuf::from(verbatim_root);
+
+    assert_commands_recreate_the_file(&verbatim_root, Path::new("file"));
+    // `/` is not a separator in verbatim paths, so nested files must be joined by components.
+    assert_tree_is_transferred(
+        &verbatim_root.join("sender"),
+        &verbatim_root.join("receiver"),
+        &Path::new("nested").join("file"),
+    );
+}
@@ -0,0 +1,212 @@
+//! Tests for the artifact format
+//!
+//! `tests/golden_files/v1` holds a `basis_file` and an `updated_file`, together with
+//! the `signature` and `delta` created for them (with a chunk size of 8), and the later revisions of
+//! both: with the basis layout and header, deduplicated, and streamed.
+//! Each version in `FORMAT_VERSIONS` has such a directory. The files of `v2` are not valid UTF-8,
+//! and its updated file shares blocks with the basis file at other offsets. Its Deltas also
+//! record every field of the header, and use every kind of token.
+//! These artifacts were created once and committed, so these tests fail if a change (or another
+//! machine, with a different endianness or pointer width) reads or writes them differently, or
+//! decodes other hashes or tokens from them.
+use bytes::Bytes;
+
+use rsync_rust::domain::{
+    apply_delta, artifact_version, check_golden_directory, read_streamed_delta, Delta, FileDigest,
+    FileSignature, Token, FORMAT_VERSION, FORMAT_VERSIONS, PREAMBLE_LENGTH,
+};
+use rsync_rust::embedded::rolling_hash;
+
+const GOLDEN_CHUNK_SIZE: usize = 8;
+
+// Literals of the golden updated file, between the runs of blocks it shares with the basis file.
+const GOLDEN_LITERALS: [&[u8]; 3] = [
+    b"cat.\nPac",
+    b"jugs.\nSphinx of black quartz, judge my vow.\nHo",
+    b"p!\n",
+];
+
+fn golden_file(name: &str) -> Bytes {
+    golden_file_of_version(1, name)
+}
+
+fn golden_file_of_version(version: u16, name: &str) -> Bytes {
+    std::fs::read(format!("tests/golden_files/v{version}/{name}"))
+        .expect("Golden files should be committed")
+        .into()
+}
+
+// Artifacts of older versions are written again with the current version, and the same payload.
+fn assert_written_unchanged(rewritten: Bytes, serialized: &Bytes) {
+    assert_eq!(artifact_version(&rewritten), Some(FORMAT_VERSION));
+    assert_eq!(rewritten[PREAMBLE_LENGTH..], serialized[PREAMBLE_LENGTH..]);
+}
+
+#[test]
+fn golden_signature_is_read_and_written_unchanged() {
+    let serialized = golden_file("signature");
+
+    let signature = FileSignature::try_from(serialized.clone()).unwrap();
+
+    let basis_file = golden_file("basis_file");
+    let strong_hashes: Vec<_> = basis_file
+        .chunks(GOLDEN_CHUNK_SIZE)
+        .map(|block| signature.strong_hash.hash(block))
+        .collect();
+    assert_eq!(signature.strong_hashes, strong_hashes);
+    assert_eq!(
+        signature.rolling_hashes,
+        [
+            399013635, 929971380, 98448571, 328573877, 835200706, 881650025, 828621749, 705648341,
+            357368496, 983642216, 410535312, 625371620, 148544546, 291206595, 728126514, 7472286,
+        ]
+    );
+    assert_written_unchanged(Bytes::try_from(signature).unwrap(), &serialized);
+}
+
+#[test]
+fn golden_signature_hashes_blocks_which_are_not_utf8_by_their_bytes() {
+    let serialized = golden_file_of_version(2, "signature");
+
+    let signature = FileSignature::try_from(serialized.clone()).unwrap();
+
+    let basis_file = golden_file_of_version(2, "basis_file");
+    assert!(std::str::from_utf8(&basis_file).is_err());
+    let rolling_hashes: Vec<_> = basis_file
+        .chunks(GOLDEN_CHUNK_SIZE)
+        .map(rolling_hash)
+        .collect();
+    assert_eq!(signature.rolling_hashes, rolling_hashes);
+    assert_eq!(
+        signature.rolling_hashes,
+        [
+            970902767, 636645695, 573160100, 141731995, 286887012, 13831906, 303898455, 970902767,
+            500740915, 517030419, 169338295, 769423222, 786452955,
+        ]
+    );
+    assert_eq!(Bytes::try_from(signature.clone()).unwrap(), serialized);
+    // The blocks which are not UTF-8 are found at other offsets of the updated file.
+    let delta = Delta::try_from(golden_file_of_version(2, "delta")).unwrap();
+    let tokens = tokens_of(&delta);
+    assert!(tokens.contains(&Token::BlockIndex(5)) && tokens.contains(&Token::BlockIndex(6)));
+    assert_eq!(
+        apply_delta(basis_file, delta, GOLDEN_CHUNK_SIZE).unwrap(),
+        golden_file_of_version(2, "updated_file")
+    );
+}
+
+#[test]
+fn golden_delta_is_read_and_written_unchanged() {
+    let serialized = golden_file("delta");
+
+    let delta = Delta::try_from(serialized.clone()).unwrap();
+
+    let expected: Vec<Token> = [
+        (0..5).map(Token::BlockIndex).collect(),
+        byte_literals(GOLDEN_LITERALS[0]),
+        (6..10).map(Token::BlockIndex).collect(),
+        byte_literals(GOLDEN_LITERALS[1]),
+        (11..15).map(Token::BlockIndex).collect(),
+        byte_literals(GOLDEN_LITERALS[2]),
+    ]
+    .concat();
+    assert_eq!(tokens_of(&delta), expected);
+    let streamed = read_streamed_delta(&mut &golden_file("streamed_delta")[..], u64::MAX).unwrap();
+    assert_eq!(tokens_of(&streamed), expected);
+    assert_written_unchanged(Bytes::try_from(delta.clone()).unwrap(), &serialized);
+    assert_eq!(
+        apply_delta(golden_file("basis_file"), delta, GOLDEN_CHUNK_SIZE).unwrap(),
+        golden_file("updated_file")
+    );
+}
+
+#[test]
+fn golden_optimized_delta_keeps_its_merged_tokens() {
+    let delta = Delta::try_from(golden_file("delta_with_header")).unwrap();
+
+    // Version 1 hashed the basis file with SipHash, which is not checked anymore.
+    let basis = delta.header.basis.unwrap();
+    assert_eq!((basis.length, basis.block_count), (123, 16));
+    assert_eq!(basis.strong_hash, None);
+
+    assert_eq!(
+        tokens_of(&delta),
+        vec![
+            Token::BlockRange(0..5),
+            Token::ByteLiterals(GOLDEN_LITERALS[0].to_vec()),
+            Token::BlockRange(6..10),
+            Token::ByteLiterals(GOLDEN_LITERALS[1].to_vec()),
+            Token::BlockRange(11..16),
+        ]
+    );
+}
+
+#[test]
+fn golden_delta_header_keeps_every_field() {
+    let basis_file = golden_file_of_version(2, "basis_file");
+    let updated_file = golden_file_of_version(2, "updated_file");
+
+    let delta = Delta::try_from(golden_file_of_version(2, "delta_with_header")).unwrap();
+
+    let header = &delta.header;
+    let basis = header.basis.unwrap();
+    assert_eq!(basis.length, basis_file.len() as u64);
+    assert_eq!(basis.block_count, 13);
+    assert_eq!(basis.last_block_size, Some(5));
+    // Files are hashed with xxh3-64, the same on every build.
+    assert_eq!(basis.strong_hash, Some(16548766775252392383));
+    assert_eq!(
+        header.updated,
+        Some(FileDigest {
+            length: 134,
+            strong_hash: 4062275882667123771,
+        })
+    );
+    assert_eq!(header.updated, Some(FileDigest::of(&updated_file)));
+    assert_eq!(header.output_length, Some(updated_file.len() as u64));
+    assert!(!header.unconfirmed);
+    let metadata = header.metadata.as_ref().unwrap();
+    assert_eq!(metadata.creator.as_deref(), Some("golden"));
+    assert_eq!(metadata.created_at, Some(1_700_000_000));
+    assert_eq!(metadata.basis, Some(FileDigest::of(&basis_file)));
+    assert_eq!(metadata.tool_version.as_deref(), Some("0.1.0"));
+    assert_eq!(metadata.properties["commit"], "0123abc");
+    assert_eq!(
+        tokens_of(&delta),
+        vec![
+            Token::BlockIndex(7),
+            Token::BlockRange(1..3),
+            Token::ByteLiterals(b"changed.\n".to_vec()),
+            Token::BlockRange(5..7),
+            Token::ZeroRun(40),
+            Token::BlockRange(7..13),
+        ]
+    );
+
+    let unconfirmed = Delta::try_from(golden_file_of_version(2, "unconfirmed_delta")).unwrap();
+    assert!(unconfirmed.header.unconfirmed);
+    assert_eq!(
+        apply_delta(basis_file, unconfirmed, GOLDEN_CHUNK_SIZE).unwrap(),
+        updated_file
+    );
+}
+
+fn tokens_of(delta: &Delta) -> Vec<Token> {
+    delta.tokens().map(|token| token.to_token()).collect()
+}
+
+fn byte_literals(bytes: &[u8]) -> Vec<Token> {
+    bytes.iter().map(|&byte| Token::ByteLiteral(byte)).collect()
+}
+
+#[test]
+fn golden_artifacts_of_every_supported_version_are_read() {
+    for version in FORMAT_VERSIONS {
+        let directory = std::path::PathBuf::from(format!("tests/golden_files/v{version}"));
+
+        let artifacts = check_golden_directory(&directory, GOLDEN_CHUNK_SIZE)
+            .unwrap_or_else(|error| panic!("Golden files of version {version}: {error:?}"));
+
+        assert!(artifacts.len() >= 6, "{artifacts:?}");
+    }
+}